@@ -0,0 +1,345 @@
+//! Resolution of recipient addresses to the values (usually destinations) of mappings.
+//!
+//! A mapping is registered with one or more address patterns. Each pattern is one of the following:
+//!
+//! * an exact address like `user@example.com` or `user+alerts@example.com`,
+//! * a domain wildcard like `*@example.com`, that matches every address of the given domain,
+//! * the catch-all `*`, that matches every address.
+//!
+//! Domains are compared case-insensitively, while local parts are compared case-sensitively (as required by RFC 5321).
+//! A subaddress tag (everything after the first `+` of the local part) is ignored, unless a pattern with exactly this
+//! tag was registered. If several patterns match an address, the most specific one wins:
+//!
+//! 1. exact address including the subaddress tag,
+//! 2. exact address without the subaddress tag,
+//! 3. domain wildcard,
+//! 4. catch-all.
+
+use std::collections::HashMap;
+
+use crate::Error;
+
+pub(crate) struct AddressMatcher<T> {
+    values: Vec<T>,
+    exact: HashMap<String, usize>,
+    domains: HashMap<String, usize>,
+    catch_all: Option<usize>,
+}
+
+/// A parsed address pattern.
+#[derive(Debug, PartialEq, Eq)]
+enum Pattern {
+    Exact(String),
+    Domain(String),
+    CatchAll,
+}
+
+impl<T> AddressMatcher<T> {
+    pub(crate) fn new() -> Self {
+        AddressMatcher {
+            values: Vec::new(),
+            exact: HashMap::new(),
+            domains: HashMap::new(),
+            catch_all: None,
+        }
+    }
+
+    /// Registers `value` for all given patterns.
+    ///
+    /// Returns an error, if one of the patterns is invalid or was already registered before. In this case the matcher
+    /// is not changed.
+    pub(crate) fn insert<'p>(
+        &mut self,
+        patterns: impl IntoIterator<Item = &'p str>,
+        value: T,
+    ) -> Result<(), Error> {
+        let mut parsed = Vec::new();
+        for pattern in patterns {
+            let pattern = Pattern::parse(pattern)?;
+            let duplicate = match &pattern {
+                Pattern::Exact(addr) => self.exact.contains_key(addr),
+                Pattern::Domain(domain) => self.domains.contains_key(domain),
+                Pattern::CatchAll => self.catch_all.is_some(),
+            } || parsed.contains(&pattern);
+            if duplicate {
+                return Err(Error::config(format!(
+                    "The address pattern '{}' is used by multiple mappings.",
+                    pattern
+                )));
+            }
+            parsed.push(pattern);
+        }
+
+        let index = self.values.len();
+        self.values.push(value);
+        for pattern in parsed {
+            match pattern {
+                Pattern::Exact(addr) => {
+                    self.exact.insert(addr, index);
+                }
+                Pattern::Domain(domain) => {
+                    self.domains.insert(domain, index);
+                }
+                Pattern::CatchAll => self.catch_all = Some(index),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the value of the most specific pattern matching `address` or None, if no pattern matches.
+    #[cfg(test)]
+    pub(crate) fn get(&self, address: &str) -> Option<&T> {
+        self.get_with_pattern(address).map(|(_, value)| value)
+    }
+
+    /// Like `get()`, but also returns the pattern, that matched.
+    pub(crate) fn get_with_pattern(&self, address: &str) -> Option<(String, &T)> {
+        let (local, domain) = split_address(address)?;
+        let domain = domain.to_lowercase();
+
+        let exact = format!("{}@{}", local, domain);
+        if let Some(index) = self.exact.get(&exact) {
+            return Some((exact, &self.values[*index]));
+        }
+        let (base, tag) = split_tag(local);
+        if tag.is_some() {
+            let untagged = format!("{}@{}", base, domain);
+            if let Some(index) = self.exact.get(&untagged) {
+                return Some((untagged, &self.values[*index]));
+            }
+        }
+        if let Some(index) = self.domains.get(&domain) {
+            return Some((format!("*@{}", domain), &self.values[*index]));
+        }
+        self.catch_all
+            .map(|index| ("*".to_string(), &self.values[index]))
+    }
+
+    /// Returns all registered values.
+    pub(crate) fn values(&self) -> impl Iterator<Item = &T> {
+        self.values.iter()
+    }
+
+    /// Returns the number of registered values.
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.values.len()
+    }
+}
+
+impl<T> Default for AddressMatcher<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pattern {
+    fn parse(pattern: &str) -> Result<Self, Error> {
+        if pattern == "*" {
+            return Ok(Pattern::CatchAll);
+        }
+        let (local, domain) = split_address(pattern).ok_or_else(|| {
+            Error::config(format!(
+                "Invalid address pattern '{}' (expected '*', '*@<domain>' or '<local>@<domain>').",
+                pattern
+            ))
+        })?;
+        let domain = domain.to_lowercase();
+        if local == "*" {
+            Ok(Pattern::Domain(domain))
+        } else if local.contains('*') {
+            Err(Error::config(format!(
+                "Invalid address pattern '{}': Wildcards are only allowed as the complete local part.",
+                pattern
+            )))
+        } else {
+            Ok(Pattern::Exact(format!("{}@{}", local, domain)))
+        }
+    }
+}
+
+impl std::fmt::Display for Pattern {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Pattern::Exact(addr) => write!(f, "{}", addr),
+            Pattern::Domain(domain) => write!(f, "*@{}", domain),
+            Pattern::CatchAll => write!(f, "*"),
+        }
+    }
+}
+
+/// Splits an address into its local part and its domain at the last '@'.
+/// Returns None, if one of them would be empty.
+fn split_address(address: &str) -> Option<(&str, &str)> {
+    let (local, domain) = address.rsplit_once('@')?;
+    if local.is_empty() || domain.is_empty() {
+        None
+    } else {
+        Some((local, domain))
+    }
+}
+
+/// Splits a local part into the base and the subaddress tag.
+/// A leading '+' does not start a tag, because the base would be empty.
+fn split_tag(local: &str) -> (&str, Option<&str>) {
+    match local.split_once('+') {
+        Some((base, tag)) if !base.is_empty() => (base, Some(tag)),
+        _ => (local, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn local_part() -> impl Strategy<Value = String> {
+        "[a-z0-9][a-z0-9._-]{0,15}"
+    }
+    fn domain() -> impl Strategy<Value = String> {
+        "[a-z0-9][a-z0-9-]{0,11}\\.[a-z]{2,6}"
+    }
+    fn tag() -> impl Strategy<Value = String> {
+        "[a-zA-Z0-9]{0,8}"
+    }
+
+    /// Changes the case of some characters of `s` depending on `mask`.
+    fn mix_case(s: &str, mask: u64) -> String {
+        s.chars()
+            .enumerate()
+            .map(|(i, c)| {
+                if mask & (1 << (i % 64)) != 0 {
+                    c.to_ascii_uppercase()
+                } else {
+                    c
+                }
+            })
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn domains_are_case_insensitive(local in local_part(), domain in domain(), mask in any::<u64>()) {
+            let mut matcher = AddressMatcher::new();
+            matcher.insert([format!("{}@{}", local, mix_case(&domain, !mask)).as_str()], 1).unwrap();
+
+            prop_assert_eq!(matcher.get(&format!("{}@{}", local, mix_case(&domain, mask))), Some(&1));
+        }
+
+        #[test]
+        fn local_parts_are_case_sensitive(local in "[a-z][a-z0-9]{0,15}", domain in domain()) {
+            let mut matcher = AddressMatcher::new();
+            matcher.insert([format!("{}@{}", local, domain).as_str()], 1).unwrap();
+
+            prop_assert_eq!(matcher.get(&format!("{}@{}", local.to_uppercase(), domain)), None);
+        }
+
+        #[test]
+        fn subaddress_tags_are_ignored(local in local_part(), domain in domain(), tag in tag()) {
+            let mut matcher = AddressMatcher::new();
+            matcher.insert([format!("{}@{}", local, domain).as_str()], 1).unwrap();
+
+            prop_assert_eq!(matcher.get(&format!("{}+{}@{}", local, tag, domain)), Some(&1));
+        }
+
+        #[test]
+        fn tagged_patterns_take_precedence(local in local_part(), domain in domain(), tag in tag(), other in tag()) {
+            prop_assume!(tag != other);
+            let mut matcher = AddressMatcher::new();
+            matcher.insert([format!("{}@{}", local, domain).as_str()], 1).unwrap();
+            matcher.insert([format!("{}+{}@{}", local, tag, domain).as_str()], 2).unwrap();
+
+            prop_assert_eq!(matcher.get(&format!("{}+{}@{}", local, tag, domain)), Some(&2));
+            prop_assert_eq!(matcher.get(&format!("{}+{}@{}", local, other, domain)), Some(&1));
+            prop_assert_eq!(matcher.get(&format!("{}@{}", local, domain)), Some(&1));
+        }
+
+        #[test]
+        fn more_specific_patterns_take_precedence(
+            local in local_part(),
+            other_local in local_part(),
+            domain in domain(),
+            other_domain in domain(),
+        ) {
+            prop_assume!(local != other_local && domain != other_domain);
+            let mut matcher = AddressMatcher::new();
+            // The insertion order must not matter:
+            matcher.insert(["*"], 3).unwrap();
+            matcher.insert([format!("*@{}", domain).as_str()], 2).unwrap();
+            matcher.insert([format!("{}@{}", local, domain).as_str()], 1).unwrap();
+
+            prop_assert_eq!(matcher.get(&format!("{}@{}", local, domain)), Some(&1));
+            prop_assert_eq!(matcher.get(&format!("{}@{}", other_local, domain)), Some(&2));
+            prop_assert_eq!(matcher.get(&format!("{}@{}", local, other_domain)), Some(&3));
+        }
+
+        #[test]
+        fn aliases_resolve_to_the_same_value(
+            locals in prop::collection::hash_set(local_part(), 1..8),
+            domain in domain(),
+        ) {
+            let addrs: Vec<String> = locals.iter().map(|l| format!("{}@{}", l, domain)).collect();
+            let mut matcher = AddressMatcher::new();
+            matcher.insert(addrs.iter().map(String::as_str), 1).unwrap();
+
+            for addr in addrs.iter() {
+                prop_assert_eq!(matcher.get(addr), Some(&1));
+            }
+            prop_assert_eq!(matcher.len(), 1);
+        }
+
+        #[test]
+        fn duplicate_patterns_are_rejected(local in local_part(), domain in domain(), mask in any::<u64>()) {
+            let mut matcher = AddressMatcher::new();
+            matcher.insert([format!("{}@{}", local, domain).as_str()], 1).unwrap();
+
+            let duplicate = format!("{}@{}", local, mix_case(&domain, mask));
+            prop_assert!(matcher.insert([duplicate.as_str()], 2).is_err());
+            prop_assert_eq!(matcher.get(&duplicate), Some(&1));
+        }
+
+        #[test]
+        fn unmatched_addresses_resolve_to_none(local in local_part(), domain in domain(), other_domain in domain()) {
+            prop_assume!(domain != other_domain);
+            let mut matcher = AddressMatcher::new();
+            matcher.insert([format!("*@{}", domain).as_str()], 1).unwrap();
+
+            prop_assert_eq!(matcher.get(&format!("{}@{}", local, other_domain)), None);
+        }
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let mut matcher = AddressMatcher::new();
+        for pattern in ["", "user", "@example.com", "user@", "us*er@example.com"] {
+            assert!(
+                matcher.insert([pattern], 1).is_err(),
+                "Accepted invalid pattern '{}'.",
+                pattern
+            );
+        }
+        assert_eq!(matcher.len(), 0);
+    }
+
+    #[test]
+    fn matched_pattern_is_reported() {
+        let mut matcher = AddressMatcher::new();
+        matcher.insert(["user@example.com"], 1).unwrap();
+        matcher.insert(["*@example.org"], 2).unwrap();
+        matcher.insert(["*"], 3).unwrap();
+
+        assert_eq!(
+            matcher.get_with_pattern("user+tag@EXAMPLE.com"),
+            Some(("user@example.com".to_string(), &1))
+        );
+        assert_eq!(
+            matcher.get_with_pattern("other@example.org"),
+            Some(("*@example.org".to_string(), &2))
+        );
+        assert_eq!(
+            matcher.get_with_pattern("other@example.net"),
+            Some(("*".to_string(), &3))
+        );
+    }
+}
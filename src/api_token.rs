@@ -0,0 +1,214 @@
+//! Tokens, that authenticate clients of the control socket and restrict them to the commands of their scopes.
+
+use std::fs::File;
+use std::io::Read;
+use std::str::FromStr;
+
+use crate::Error;
+
+/// The number of random bytes in generated tokens.
+const TOKEN_BYTES: usize = 32;
+
+/// A group of commands, that a token may be allowed to use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Scope {
+    /// Reading the state and the metrics of the server.
+    Metrics,
+    /// Inspecting the mappings.
+    Mappings,
+    /// Inspecting the delivery queue and pausing or resuming the listeners, that fill it.
+    Queue,
+    /// Following the senders, recipients and subjects of accepted emails and the outcomes of their deliveries.
+    Tail,
+    /// Blocking the writes to the state files for a backup.
+    Backup,
+    /// Listing and rotating the passwords and tokens of destinations.
+    Secrets,
+}
+
+impl Scope {
+    pub(crate) const ALL: [Scope; 6] = [
+        Scope::Metrics,
+        Scope::Mappings,
+        Scope::Queue,
+        Scope::Tail,
+        Scope::Backup,
+        Scope::Secrets,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Scope::Metrics => "metrics",
+            Scope::Mappings => "mappings",
+            Scope::Queue => "queue",
+            Scope::Tail => "tail",
+            Scope::Backup => "backup",
+            Scope::Secrets => "secrets",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .iter()
+            .find(|scope| scope.name() == s)
+            .copied()
+            .ok_or_else(|| {
+                Error::config(format!(
+                    "Unknown scope '{}' (expected metrics, mappings, queue, tail, backup or secrets).",
+                    s
+                ))
+            })
+    }
+}
+
+/// A section 'api_tokens.<name>' of the config file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ApiToken {
+    pub(crate) name: String,
+    secret: String,
+    pub(crate) scopes: Vec<Scope>,
+}
+
+impl ApiToken {
+    pub(crate) fn new(
+        name: impl Into<String>,
+        secret: impl Into<String>,
+        scopes: Vec<Scope>,
+    ) -> Self {
+        ApiToken {
+            name: name.into(),
+            secret: secret.into(),
+            scopes,
+        }
+    }
+
+    /// Returns true, if `secret` is the secret of this token. The comparison takes the same time for all secrets of
+    /// the same length, so the secret can't be guessed byte by byte.
+    pub(crate) fn matches(&self, secret: &str) -> bool {
+        self.secret.len() == secret.len()
+            && self
+                .secret
+                .bytes()
+                .zip(secret.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+/// Loads the tokens from the section 'api_tokens'.
+pub(crate) fn load_tokens(
+    section: &toml::map::Map<String, toml::Value>,
+) -> Result<Vec<ApiToken>, Error> {
+    let mut tokens = vec![];
+    for (name, token_section) in section.iter() {
+        let token_section = token_section.as_table().ok_or_else(|| {
+            Error::config(format!(
+                "Section 'api_tokens.{}' has wrong type (expected table).",
+                name
+            ))
+        })?;
+        let secret = token_section
+            .get("token")
+            .ok_or_else(|| {
+                Error::config(format!("Missing field 'token' for API token '{}'.", name))
+            })?
+            .as_str()
+            .filter(|secret| !secret.is_empty())
+            .ok_or_else(|| {
+                Error::config(format!(
+                    "Field 'token' for API token '{}' has wrong type (expected non-empty string).",
+                    name
+                ))
+            })?;
+        let scopes = token_section
+            .get("scopes")
+            .ok_or_else(|| {
+                Error::config(format!("Missing field 'scopes' for API token '{}'.", name))
+            })?
+            .as_array()
+            .ok_or_else(|| {
+                Error::config(format!(
+                    "Field 'scopes' for API token '{}' has wrong type (expected array).",
+                    name
+                ))
+            })?
+            .iter()
+            .map(|scope| {
+                scope
+                    .as_str()
+                    .ok_or_else(|| {
+                        Error::config(format!(
+                            "Field 'scopes' for API token '{}' contains a value with wrong type (expected string).",
+                            name
+                        ))
+                    })?
+                    .parse()
+            })
+            .collect::<Result<_, _>>()?;
+        if tokens.iter().any(|token: &ApiToken| token.matches(secret)) {
+            return Err(Error::config(format!(
+                "API token '{}' has the same token as another one.",
+                name
+            )));
+        }
+        tokens.push(ApiToken::new(name, secret, scopes));
+    }
+    Ok(tokens)
+}
+
+/// Returns a new random token secret.
+pub(crate) fn generate_secret() -> Result<String, Error> {
+    let mut bytes = [0; TOKEN_BYTES];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(base64::encode_config(bytes, base64::URL_SAFE_NO_PAD))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_tokens() {
+        let section = toml::from_str(
+            r#"
+[monitoring]
+token = "s3cr3t"
+scopes = [ "metrics" ]
+
+[automation]
+token = "0th3r"
+scopes = [ "mappings", "queue" ]
+"#,
+        )
+        .unwrap();
+        let tokens = load_tokens(&section).unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                ApiToken::new("automation", "0th3r", vec![Scope::Mappings, Scope::Queue]),
+                ApiToken::new("monitoring", "s3cr3t", vec![Scope::Metrics]),
+            ]
+        );
+        assert!(tokens[1].matches("s3cr3t"));
+        assert!(!tokens[1].matches("s3cr3"));
+        assert!(!tokens[1].matches("s3cr3T"));
+
+        let section = toml::from_str("[a]\ntoken = \"x\"\nscopes = [ \"admin\" ]").unwrap();
+        assert!(load_tokens(&section).is_err());
+        let section =
+            toml::from_str("[a]\ntoken = \"x\"\nscopes = []\n[b]\ntoken = \"x\"\nscopes = []")
+                .unwrap();
+        assert!(load_tokens(&section).is_err());
+    }
+
+    #[test]
+    fn test_generate_secret() {
+        let secret = generate_secret().unwrap();
+        assert_eq!(secret.len(), 43);
+        assert_ne!(secret, generate_secret().unwrap());
+    }
+}
@@ -0,0 +1,388 @@
+use log::debug;
+use ring::{digest, hmac};
+
+use std::fs;
+use std::io::{ErrorKind, Read, Write};
+use std::path::PathBuf;
+
+use crate::Error;
+
+/// The header, that replaces the body of an attachment, which was moved to the store. Its value is the SHA-256 hash of
+/// the body and a MAC of the hash, so headers of received emails can't refer to attachments of other emails.
+const STORED_HEADER: &str = "X-Kutsche-Stored";
+/// The name of the file in the store, that contains the key of the MACs.
+const KEY_FILE: &str = "key";
+
+/// A directory, that stores large attachments of emails once, named by the SHA-256 hash of their content.
+///
+/// File destinations, that use the store, replace the (still encoded) body of every attachment of at least `min_size`
+/// bytes with a header, that refers to the stored body. An attachment, that is received multiple times or delivered
+/// to multiple destinations, is only stored once. Emails, that are read back from the destinations (e.g. to export
+/// or replay them), are restored byte by byte.
+pub(crate) struct AttachmentStore {
+    dir: PathBuf,
+    min_size: usize,
+    key: hmac::Key,
+}
+
+impl AttachmentStore {
+    /// Opens the store in `dir`, creating the directory and the key of the MACs, if they don't exist.
+    pub(crate) fn open(dir: impl Into<PathBuf>, min_size: usize) -> Result<Self, Error> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        let key_path = dir.join(KEY_FILE);
+        let key = match fs::read(&key_path) {
+            Ok(key) => key,
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let mut key = [0; 32];
+                fs::File::open("/dev/urandom")?.read_exact(&mut key)?;
+                let mut file = fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&key_path)?;
+                file.write_all(&key)?;
+                key.to_vec()
+            }
+            Err(e) => return Err(e.into()),
+        };
+        Ok(AttachmentStore {
+            dir,
+            min_size,
+            key: hmac::Key::new(hmac::HMAC_SHA256, &key),
+        })
+    }
+
+    /// Returns the path of the content with the given hash.
+    fn path(&self, hash: &str) -> PathBuf {
+        self.dir.join(&hash[..2]).join(hash)
+    }
+
+    fn mac(&self, hash: &str) -> String {
+        hex(hmac::sign(&self.key, hash.as_bytes()).as_ref())
+    }
+
+    /// Stores `content`, unless it is stored already, and returns its hash.
+    fn store(&self, content: &[u8]) -> Result<String, Error> {
+        let hash = hex(digest::digest(&digest::SHA256, content).as_ref());
+        let path = self.path(&hash);
+        if path.is_file() {
+            debug!("Attachment {} is stored already.", hash);
+            return Ok(hash);
+        }
+        fs::create_dir_all(self.dir.join(&hash[..2]))?;
+        // Write to a temporary file first, so readers never see partial content:
+        let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp_path, content)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(hash)
+    }
+
+    /// Moves the bodies of the large attachments of an email to the store and returns the email with references to
+    /// them.
+    pub(crate) fn externalize(&self, raw: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut out = Vec::with_capacity(raw.len());
+        self.externalize_part(raw, false, &mut out)?;
+        Ok(out)
+    }
+
+    fn externalize_part(&self, part: &[u8], nested: bool, out: &mut Vec<u8>) -> Result<(), Error> {
+        let (headers, body) = match split_headers(part) {
+            Some(split) => split,
+            None => {
+                out.extend_from_slice(part);
+                return Ok(());
+            }
+        };
+        let content_type = header_value(headers, "content-type").unwrap_or_default();
+        if let Some(boundary) = boundary(&content_type) {
+            out.extend_from_slice(headers);
+            return self.externalize_multipart(body, &boundary, out);
+        }
+        let disposition = header_value(headers, "content-disposition").unwrap_or_default();
+        let is_attachment = starts_with_ignore_case(&disposition, "attachment")
+            || !(content_type.is_empty()
+                || starts_with_ignore_case(&content_type, "text/")
+                || starts_with_ignore_case(&content_type, "message/"));
+        // The body of a single part email is its text, even if it is no text:
+        if !nested || !is_attachment || body.len() < self.min_size {
+            out.extend_from_slice(part);
+            return Ok(());
+        }
+        let hash = self.store(body)?;
+        let line_break: &[u8] = if headers.ends_with(b"\r\n\r\n") {
+            b"\r\n"
+        } else {
+            b"\n"
+        };
+        out.extend_from_slice(&headers[..headers.len() - line_break.len()]);
+        out.extend_from_slice(
+            format!("{}: {}; {}", STORED_HEADER, hash, self.mac(&hash)).as_bytes(),
+        );
+        out.extend_from_slice(line_break);
+        out.extend_from_slice(line_break);
+        Ok(())
+    }
+
+    fn externalize_multipart(
+        &self,
+        body: &[u8],
+        boundary: &str,
+        out: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let delimiter = format!("--{}", boundary);
+        // The start of the current part, if a delimiter line was found:
+        let mut part_start = None;
+        let mut pos = 0;
+        while pos < body.len() {
+            let line_end = body[pos..]
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(body.len(), |i| pos + i + 1);
+            let line = trim_line_end(&body[pos..line_end]);
+            let is_close = line == format!("{}--", delimiter).as_bytes();
+            if is_close || line == delimiter.as_bytes() {
+                match part_start {
+                    // The line break before a delimiter belongs to the delimiter:
+                    Some(start) => {
+                        let part_end = pos - line_break_before(&body[start..pos]);
+                        self.externalize_part(&body[start..part_end], true, out)?;
+                        out.extend_from_slice(&body[part_end..line_end]);
+                    }
+                    None => out.extend_from_slice(&body[..line_end]),
+                }
+                if is_close {
+                    // The epilogue is kept as it is:
+                    out.extend_from_slice(&body[line_end..]);
+                    return Ok(());
+                }
+                part_start = Some(line_end);
+            }
+            pos = line_end;
+        }
+        match part_start {
+            Some(start) => self.externalize_part(&body[start..], true, out),
+            None => {
+                out.extend_from_slice(body);
+                Ok(())
+            }
+        }
+    }
+
+    /// Returns the hash, that a header line refers to, if it is a reference with a valid MAC.
+    fn reference<'a>(&self, line: &'a [u8], prefix: &str) -> Option<&'a str> {
+        line.strip_prefix(prefix.as_bytes())
+            .and_then(|value| std::str::from_utf8(value).ok())
+            .and_then(|value| value.split_once("; "))
+            .filter(|(hash, mac)| hash.len() == 64 && *mac == self.mac(hash))
+            .map(|(hash, _)| hash)
+    }
+
+    /// Returns the hashes of the stored attachments, that an email refers to.
+    pub(crate) fn references(&self, raw: &[u8]) -> Vec<String> {
+        let prefix = format!("{}: ", STORED_HEADER);
+        raw.split(|b| *b == b'\n')
+            .filter_map(|line| self.reference(trim_line_end(line), &prefix))
+            .map(String::from)
+            .collect()
+    }
+
+    /// Deletes the stored attachment with the given hash. Returns false, if it was not stored.
+    pub(crate) fn remove(&self, hash: &str) -> Result<bool, Error> {
+        match fs::remove_file(self.path(hash)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Replaces the references to stored attachments in an email with their content.
+    pub(crate) fn restore(&self, raw: &[u8]) -> Result<Vec<u8>, Error> {
+        let prefix = format!("{}: ", STORED_HEADER);
+        let mut out = Vec::with_capacity(raw.len());
+        let mut pos = 0;
+        while pos < raw.len() {
+            let line_end = raw[pos..]
+                .iter()
+                .position(|b| *b == b'\n')
+                .map_or(raw.len(), |i| pos + i + 1);
+            let reference = self.reference(trim_line_end(&raw[pos..line_end]), &prefix);
+            match reference {
+                // The reference is the last header of its part, so the empty line follows:
+                Some(hash)
+                    if raw[line_end..].starts_with(b"\r\n")
+                        || raw[line_end..].starts_with(b"\n") =>
+                {
+                    let blank_end = line_end + if raw[line_end] == b'\r' { 2 } else { 1 };
+                    out.extend_from_slice(&raw[line_end..blank_end]);
+                    out.extend_from_slice(&fs::read(self.path(hash)).map_err(|e| {
+                        Error::SysIo(std::io::Error::new(
+                            e.kind(),
+                            format!("Could not read stored attachment {}: {}", hash, e),
+                        ))
+                    })?);
+                    pos = blank_end;
+                }
+                _ => {
+                    out.extend_from_slice(&raw[pos..line_end]);
+                    pos = line_end;
+                }
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Splits a part into its headers (including the empty line after them) and its body. Returns None, if there is no
+/// empty line.
+fn split_headers(part: &[u8]) -> Option<(&[u8], &[u8])> {
+    if part.starts_with(b"\r\n") {
+        return Some(part.split_at(2));
+    }
+    if part.starts_with(b"\n") {
+        return Some(part.split_at(1));
+    }
+    let end = part
+        .windows(4)
+        .position(|window| window == b"\r\n\r\n")
+        .map(|i| i + 4)
+        .or_else(|| {
+            part.windows(2)
+                .position(|window| window == b"\n\n")
+                .map(|i| i + 2)
+        })?;
+    Some(part.split_at(end))
+}
+
+/// Returns the unfolded value of the first header with the given name.
+fn header_value(headers: &[u8], name: &str) -> Option<String> {
+    let headers = String::from_utf8_lossy(headers);
+    let mut lines = headers.lines().peekable();
+    while let Some(line) = lines.next() {
+        let value = match line.split_once(':') {
+            Some((header, value)) if header.trim().eq_ignore_ascii_case(name) => value,
+            _ => continue,
+        };
+        let mut value = value.trim().to_string();
+        while let Some(continuation) = lines.next_if(|line| line.starts_with([' ', '\t'])) {
+            value.push(' ');
+            value.push_str(continuation.trim());
+        }
+        return Some(value);
+    }
+    None
+}
+
+/// Returns the boundary of a multipart content type.
+fn boundary(content_type: &str) -> Option<String> {
+    if !starts_with_ignore_case(content_type, "multipart/") {
+        return None;
+    }
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        name.trim()
+            .eq_ignore_ascii_case("boundary")
+            .then(|| value.trim().trim_matches('"').to_string())
+    })
+}
+
+fn starts_with_ignore_case(s: &str, prefix: &str) -> bool {
+    s.get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+}
+
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Returns the length of the line break at the end of `text`.
+fn line_break_before(text: &[u8]) -> usize {
+    if text.ends_with(b"\r\n") {
+        2
+    } else if text.ends_with(b"\n") {
+        1
+    } else {
+        0
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_EMAIL: &[u8] = b"Message-ID: <report@example.com>\r\n\
+Subject: Report\r\n\
+Content-Type: multipart/mixed; boundary=\"outer\"\r\n\
+\r\n\
+Preamble\r\n\
+--outer\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+See the attachment.\r\n\
+--outer\r\n\
+Content-Type: application/pdf; name=\"report.pdf\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+Content-Disposition: attachment;\r\n filename=\"report.pdf\"\r\n\
+\r\n\
+JVBERi0xLjQKJcfsj6IKNSAwIG9iago8PC9MZW5ndGggNiAwIFIvRmlsdGVyIC9GbGF0ZURlY29k\r\n\
+ZT4+CnN0cmVhbQp4nCtUMlAwAEJDM1MFQyMDBUsjC0tzBQtzA3MzU6AGAA\r\n\
+--outer--\r\n\
+Epilogue\r\n";
+
+    #[test]
+    fn test_externalize() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = AttachmentStore::open(dir.path(), 100).unwrap();
+        let externalized = store.externalize(TEST_EMAIL).unwrap();
+        let text = String::from_utf8(externalized.clone()).unwrap();
+        assert!(text.contains("See the attachment."));
+        assert!(!text.contains("JVBERi0xLjQK"));
+        assert!(text.contains("filename=\"report.pdf\"\r\nX-Kutsche-Stored: "));
+        assert!(text.ends_with("\r\n\r\n--outer--\r\nEpilogue\r\n"));
+        assert_eq!(store.restore(&externalized).unwrap(), TEST_EMAIL);
+
+        // The same attachment is only stored once:
+        store.externalize(TEST_EMAIL).unwrap();
+        let stored: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().is_dir())
+            .collect();
+        assert_eq!(stored.len(), 1);
+        let references = store.references(&externalized);
+        assert_eq!(references.len(), 1);
+        assert!(store.remove(&references[0]).unwrap());
+        assert!(!store.remove(&references[0]).unwrap());
+
+        // Small attachments are kept:
+        let store = AttachmentStore::open(dir.path(), 1000).unwrap();
+        assert_eq!(store.externalize(TEST_EMAIL).unwrap(), TEST_EMAIL);
+    }
+
+    #[test]
+    fn test_forged_reference() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = AttachmentStore::open(dir.path(), 100).unwrap();
+        let externalized = store.externalize(TEST_EMAIL).unwrap();
+        let text = String::from_utf8(externalized).unwrap();
+        let hash = text
+            .split("X-Kutsche-Stored: ")
+            .nth(1)
+            .unwrap()
+            .split(';')
+            .next()
+            .unwrap();
+
+        // A received email can't refer to the attachment without the key:
+        let forged = format!(
+            "Message-ID: <forged@example.com>\r\nContent-Type: multipart/mixed; boundary=b\r\n\r\n\
+--b\r\nContent-Type: application/pdf\r\nX-Kutsche-Stored: {}; 00\r\n\r\n\r\n--b--\r\n",
+            hash
+        );
+        assert_eq!(store.restore(forged.as_bytes()).unwrap(), forged.as_bytes());
+        assert!(store.references(forged.as_bytes()).is_empty());
+    }
+}
@@ -0,0 +1,675 @@
+use fs2::FileExt;
+use log::error;
+use ring::digest;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair, UnparsedPublicKey};
+use serde_json::{json, Value};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::bounce::Bounce;
+use crate::smtp_server::ClientIdentity;
+use crate::state_lock;
+use crate::Error;
+
+/// The file in the state directory, that contains the key pair, which signs the audit log, in PKCS#8 format.
+pub(crate) const SIGNING_KEY_FILE: &str = "audit_log.key";
+/// The file in the state directory, that contains the base64 encoded public key, which verifies the signatures of the
+/// audit log.
+pub(crate) const PUBLIC_KEY_FILE: &str = "audit_log.pub";
+
+/// An append-only log of events concerning received emails, e.g. to prove later, that an email was forwarded.
+///
+/// Every event is written as one line of JSON with at least the fields "time" (seconds since the unix epoch) and
+/// "event".
+///
+/// A log with a signing key is hash-chained: Every entry has the field "prev" with the hex encoded SHA-256 hash of the
+/// line before it (or null for the first line). Entries with the event "signed" additionally have the field
+/// "signature" with an Ed25519 signature of their "prev" value, so all entries before a signature can be shown to be
+/// unchanged, as long as the key is kept secret.
+pub(crate) struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+    signing_key: Option<Ed25519KeyPair>,
+}
+
+impl AuditLog {
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            file: Mutex::new(open_appending(path)?),
+            signing_key: None,
+        })
+    }
+
+    /// Opens a hash-chained log, that is signed with the key in `state_dir`. The key is created, if it does not exist.
+    pub(crate) fn open_signed(path: &Path, state_dir: &Path) -> Result<Self, Error> {
+        let mut log = AuditLog::open(path)?;
+        log.signing_key = Some(load_signing_key(state_dir)?);
+        Ok(log)
+    }
+
+    /// Signs the entries recorded since the last signature, if there are any.
+    pub(crate) fn sign(&self) -> Result<(), Error> {
+        match self.signing_key {
+            Some(ref key) => self.append(
+                "signed",
+                json!({ "public_key": base64::encode(key.public_key().as_ref()) }),
+                true,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    /// Signs the log every `interval` forever.
+    pub(crate) async fn run_signer(self: Arc<Self>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        // The first tick completes immediately:
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.sign() {
+                error!("Could not sign audit log: {}", e);
+            }
+        }
+    }
+
+    /// Records a successful delivery for the given mapping together with the reference reported by the destination.
+    pub(crate) fn delivered(
+        &self,
+        message_id: &str,
+        mapping: &str,
+        reference: Option<&str>,
+    ) -> Result<(), Error> {
+        self.record(
+            "delivered",
+            json!({
+                "message_id": message_id,
+                "mapping": mapping,
+                "reference": reference,
+            }),
+        )
+    }
+
+    /// Records an accepted email together with what is known about the client, that sent it, and the envelope
+    /// recipients, that don't appear in its To and Cc headers.
+    pub(crate) fn received(
+        &self,
+        message_id: &str,
+        client: &ClientIdentity,
+        hidden_recipients: &[String],
+    ) -> Result<(), Error> {
+        self.record(
+            "received",
+            json!({
+                "message_id": message_id,
+                "peer": client.peer.map(|peer| peer.to_string()),
+                "helo": client.helo,
+                "software": client.software,
+                "tls": client.tls.as_ref().map(|tls| json!({
+                    "protocol": tls.protocol,
+                    "cipher_suite": tls.cipher_suite,
+                    "server_name": tls.server_name,
+                })),
+                "listener": client.listener.map(|listener| listener.to_string()),
+                "listener_tls": client.listener_tls.name(),
+                "hidden_recipients": hidden_recipients,
+            }),
+        )
+    }
+
+    /// Records a received bounce together with the mappings, that the bounced email was delivered to before.
+    pub(crate) fn bounced(
+        &self,
+        message_id: &str,
+        bounce: &Bounce,
+        mappings: &[String],
+    ) -> Result<(), Error> {
+        let mut fields = bounce.to_json();
+        fields["message_id"] = json!(message_id);
+        fields["mappings"] = json!(mappings);
+        self.record("bounced", fields)
+    }
+
+    /// Returns the names of the mappings, that the email with the given Message-ID was delivered to, in the order of
+    /// the deliveries.
+    pub(crate) fn delivered_mappings(&self, message_id: &str) -> Result<Vec<String>, Error> {
+        let mut mappings = vec![];
+        for line in BufReader::new(File::open(&self.path)?).lines() {
+            let event: Value = match serde_json::from_str(&line?) {
+                Ok(event) => event,
+                // Skip lines, that were cut off, e.g. by a full disk:
+                Err(_) => continue,
+            };
+            if event["event"] == "delivered" && event["message_id"] == message_id {
+                if let Some(mapping) = event["mapping"].as_str() {
+                    mappings.push(mapping.to_string());
+                }
+            }
+        }
+        Ok(mappings)
+    }
+
+    /// Replaces every value, that contains the email address `address` or equals one of the Message-IDs, with
+    /// "[forgotten]" and records the number of changed entries in a "forgotten" event. Returns that number.
+    ///
+    /// The hash chain is recomputed from the first changed entry on and the signatures after it are renewed, so the
+    /// log still verifies afterwards. A hash-chained log can only be changed this way with its signing key. The
+    /// "forgotten" event contains the hashes of the last line before and after the change, so copies of the old log
+    /// can be told apart from tampered ones.
+    pub(crate) fn forget(&self, address: &str, message_ids: &[String]) -> Result<usize, Error> {
+        let address = address.to_lowercase();
+        let (changed, old_head, new_head) = {
+            let _writing = state_lock::writing();
+            let mut file = self.file.lock().expect("Audit log is poisoned.");
+            self.lock_current(&mut file)?;
+            let res = (|| {
+                let mut content = vec![];
+                file.seek(SeekFrom::Start(0))?;
+                file.read_to_end(&mut content)?;
+                let mut lines = vec![];
+                let mut changed = 0;
+                let mut last_hash: Option<String> = None;
+                let mut old_head: Option<String> = None;
+                // The hashes of the lines after a changed line change too:
+                let mut rechain = false;
+                for line in content
+                    .split(|b| *b == b'\n')
+                    .filter(|line| !line.is_empty())
+                {
+                    old_head = Some(line_hash(line));
+                    let mut line = line.to_vec();
+                    if let Ok(mut event) = serde_json::from_slice::<Value>(&line) {
+                        let forgotten = forget_values(&mut event, &address, message_ids);
+                        if forgotten {
+                            changed += 1;
+                        }
+                        let chained = rechain && event.get("prev").is_some();
+                        if chained {
+                            let key = self.signing_key.as_ref().ok_or_else(|| {
+                                Error::config(format!(
+                                    "The audit log {} is hash-chained, but no signing key is configured.",
+                                    self.path.display()
+                                ))
+                            })?;
+                            event["prev"] = json!(last_hash);
+                            if event["event"] == "signed" {
+                                let prev = last_hash.as_deref().unwrap_or_default();
+                                event["signature"] =
+                                    json!(base64::encode(key.sign(prev.as_bytes())));
+                                event["public_key"] =
+                                    json!(base64::encode(key.public_key().as_ref()));
+                            }
+                        }
+                        if forgotten || chained {
+                            line = event.to_string().into_bytes();
+                            rechain = true;
+                        }
+                    }
+                    last_hash = Some(line_hash(&line));
+                    lines.push(line);
+                }
+                if changed > 0 {
+                    // The log is replaced by a complete copy, so a crash never leaves a truncated log behind:
+                    let mut tmp_path = self.path.clone().into_os_string();
+                    tmp_path.push(".tmp");
+                    let mut tmp = File::create(&tmp_path)?;
+                    tmp.set_permissions(file.metadata()?.permissions())?;
+                    for line in lines {
+                        tmp.write_all(&line)?;
+                        tmp.write_all(b"\n")?;
+                    }
+                    tmp.sync_all()?;
+                    fs::rename(&tmp_path, &self.path)?;
+                    if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty())
+                    {
+                        File::open(dir)?.sync_all()?;
+                    }
+                    // Dropping the old file releases its lock, so other processes find the new one:
+                    *file = open_appending(&self.path)?;
+                }
+                Ok::<_, Error>((changed, old_head, last_hash))
+            })();
+            file.unlock()?;
+            res?
+        };
+        if changed > 0 {
+            self.record(
+                "forgotten",
+                json!({ "entries": changed, "old_head": old_head, "new_head": new_head }),
+            )?;
+        }
+        Ok(changed)
+    }
+
+    /// Appends an event with the given fields to the log.
+    fn record(&self, event: &str, fields: Value) -> Result<(), Error> {
+        self.append(event, fields, false)
+    }
+
+    /// Locks the log exclusively. If it was replaced by `forget()` in another process, it is opened again before.
+    fn lock_current(&self, file: &mut File) -> Result<(), Error> {
+        loop {
+            file.lock_exclusive()?;
+            if file.metadata()?.ino() == fs::metadata(&self.path)?.ino() {
+                return Ok(());
+            }
+            *file = open_appending(&self.path)?;
+        }
+    }
+
+    /// Appends an event, that is chained to the last line and signed, if `sign` is true, for logs with a signing key.
+    /// A signature is skipped, if the last line is a signature already.
+    fn append(&self, event: &str, mut fields: Value, sign: bool) -> Result<(), Error> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        fields["time"] = json!(time);
+        fields["event"] = json!(event);
+
+        let _writing = state_lock::writing();
+        let mut file = self.file.lock().expect("Audit log is poisoned.");
+        // Other processes (e.g. an import) may append to the log as well, so the last line is read under a lock:
+        self.lock_current(&mut file)?;
+        let res = (|| {
+            if let Some(ref key) = self.signing_key {
+                let last = last_line(&mut file)?;
+                if sign {
+                    let signed = last
+                        .as_deref()
+                        .and_then(|line| serde_json::from_slice::<Value>(line).ok())
+                        .is_some_and(|last| last["event"] == "signed");
+                    if last.is_none() || signed {
+                        return Ok(());
+                    }
+                }
+                let prev = last.as_deref().map(line_hash);
+                if sign {
+                    let prev = prev.as_deref().unwrap_or_default();
+                    fields["signature"] = json!(base64::encode(key.sign(prev.as_bytes())));
+                }
+                fields["prev"] = json!(prev);
+            }
+            let mut line = fields.to_string();
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+            file.flush()
+        })();
+        file.unlock()?;
+        Ok(res?)
+    }
+}
+
+/// The result of checking the hash chain and the signatures of an audit log.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Verification {
+    /// The number of lines of the log.
+    pub(crate) entries: usize,
+    /// The number of the first line, that is part of the hash chain. Lines before it were written without signing
+    /// key, so only the last one of them is protected by the chain.
+    pub(crate) chain_start: Option<usize>,
+    /// The number and time of the line with the last valid signature, which covers all lines before it.
+    pub(crate) last_signature: Option<(usize, u64)>,
+    /// The number of the first line, that does not match the chain or the signatures, together with the reason.
+    pub(crate) tampered: Option<(usize, String)>,
+}
+
+/// Checks the hash chain and the signatures of the audit log at `path`. Signatures are verified with `public_key`.
+/// Without it, the key of the first signature in the log is trusted.
+pub(crate) fn verify(path: &Path, public_key: Option<&[u8]>) -> Result<Verification, Error> {
+    let mut verification = Verification::default();
+    let mut trusted_key = public_key.map(<[u8]>::to_vec);
+    let mut last_hash: Option<String> = None;
+    for (i, line) in BufReader::new(File::open(path)?).split(b'\n').enumerate() {
+        let line = line?;
+        let number = i + 1;
+        verification.entries = number;
+        let mut tampered = |reason: &str| {
+            verification.tampered = Some((number, reason.to_string()));
+        };
+        let event: Value = match serde_json::from_slice(&line) {
+            Ok(event) => event,
+            Err(_) => {
+                tampered("The entry is not valid JSON.");
+                break;
+            }
+        };
+        match event.get("prev") {
+            Some(prev) => {
+                if prev.as_str() != last_hash.as_deref() {
+                    tampered("The hash of the previous entry does not match.");
+                    break;
+                }
+                verification.chain_start.get_or_insert(number);
+            }
+            None if verification.chain_start.is_some() => {
+                tampered("The entry is not part of the hash chain.");
+                break;
+            }
+            None => {}
+        }
+        if event["event"] == "signed" && verification.chain_start.is_some() {
+            let entry_key = event["public_key"]
+                .as_str()
+                .and_then(|key| base64::decode(key).ok());
+            let key = trusted_key.get_or_insert_with(|| entry_key.clone().unwrap_or_default());
+            if entry_key.as_ref() != Some(key) {
+                tampered("The entry is signed with another key.");
+                break;
+            }
+            let valid = event["signature"]
+                .as_str()
+                .and_then(|signature| base64::decode(signature).ok())
+                .is_some_and(|signature| {
+                    UnparsedPublicKey::new(&signature::ED25519, &key)
+                        .verify(
+                            event["prev"].as_str().unwrap_or_default().as_bytes(),
+                            &signature,
+                        )
+                        .is_ok()
+                });
+            if !valid {
+                tampered("The signature is invalid.");
+                break;
+            }
+            verification.last_signature = Some((number, event["time"].as_u64().unwrap_or(0)));
+        }
+        last_hash = Some(line_hash(&line));
+    }
+    Ok(verification)
+}
+
+/// Opens the log at `path` for reading and appending and creates it, if it doesn't exist.
+fn open_appending(path: &Path) -> Result<File, Error> {
+    Ok(OpenOptions::new()
+        .create(true)
+        .append(true)
+        .read(true)
+        .open(path)?)
+}
+
+/// Loads the signing key from `state_dir` or creates it together with the file containing the public key.
+fn load_signing_key(state_dir: &Path) -> Result<Ed25519KeyPair, Error> {
+    let path = state_dir.join(SIGNING_KEY_FILE);
+    let pkcs8 = match fs::read(&path) {
+        Ok(pkcs8) => pkcs8,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                .map_err(|_| Error::config("Could not generate key for audit log."))?;
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .mode(0o600)
+                .open(&path)?
+                .write_all(pkcs8.as_ref())?;
+            pkcs8.as_ref().to_vec()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let key = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| {
+        Error::config(format!(
+            "File {} does not contain an Ed25519 key.",
+            path.display()
+        ))
+    })?;
+    let public_key = base64::encode(key.public_key().as_ref()) + "\n";
+    let public_key_path = state_dir.join(PUBLIC_KEY_FILE);
+    if fs::read_to_string(&public_key_path).ok().as_deref() != Some(public_key.as_str()) {
+        fs::write(&public_key_path, public_key)?;
+    }
+    Ok(key)
+}
+
+/// Returns the last line of `file` without the line break, or None, if the file is empty.
+fn last_line(file: &mut File) -> std::io::Result<Option<Vec<u8>>> {
+    let mut pos = file.seek(SeekFrom::End(0))?;
+    let mut tail = vec![];
+    while pos > 0 {
+        let start = pos.saturating_sub(4096);
+        let mut chunk = vec![0; (pos - start) as usize];
+        file.seek(SeekFrom::Start(start))?;
+        file.read_exact(&mut chunk)?;
+        chunk.extend_from_slice(&tail);
+        tail = chunk;
+        pos = start;
+        let body = tail.strip_suffix(b"\n").unwrap_or(&tail);
+        if let Some(i) = body.iter().rposition(|b| *b == b'\n') {
+            return Ok(Some(body[i + 1..].to_vec()));
+        }
+    }
+    if tail.is_empty() {
+        return Ok(None);
+    }
+    let body = tail.strip_suffix(b"\n").unwrap_or(&tail);
+    Ok(Some(body.to_vec()))
+}
+
+/// Replaces the strings in `value`, that contain the lowercase `address` or equal one of the Message-IDs, with
+/// "[forgotten]". Returns true, if any string was replaced.
+fn forget_values(value: &mut Value, address: &str, message_ids: &[String]) -> bool {
+    match value {
+        Value::String(s)
+            if contains_address(s, address) || message_ids.iter().any(|id| id == s) =>
+        {
+            *s = "[forgotten]".to_string();
+            true
+        }
+        // All values are visited, not only the ones up to the first match:
+        Value::Array(values) => {
+            values
+                .iter_mut()
+                .map(|value| forget_values(value, address, message_ids))
+                .filter(|forgotten| *forgotten)
+                .count()
+                > 0
+        }
+        Value::Object(fields) => {
+            fields
+                .values_mut()
+                .map(|value| forget_values(value, address, message_ids))
+                .filter(|forgotten| *forgotten)
+                .count()
+                > 0
+        }
+        _ => false,
+    }
+}
+
+/// Returns true, if `s` contains the lowercase `address` as a whole address, e.g. "Jane <jane@example.org>", but not
+/// "bob.jane@example.org".
+fn contains_address(s: &str, address: &str) -> bool {
+    let is_address_char = |c: char| c.is_alphanumeric() || "!#$%&'*+-/=?^_`{|}~.@".contains(c);
+    let s = s.to_lowercase();
+    s.match_indices(address).any(|(start, _)| {
+        let before = s[..start].chars().next_back();
+        let after = s[start + address.len()..].chars().next();
+        !before.is_some_and(is_address_char) && !after.is_some_and(is_address_char)
+    })
+}
+
+/// Returns the hex encoded SHA-256 hash of a line of the log.
+fn line_hash(line: &[u8]) -> String {
+    digest::digest(&digest::SHA256, line)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_append_events() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(&path).unwrap();
+        log.delivered("a@example.com", "matrix", Some("$event"))
+            .unwrap();
+        drop(log);
+        AuditLog::open(&path)
+            .unwrap()
+            .delivered("b@example.com", "files", None)
+            .unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let events: Vec<Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0]["event"], "delivered");
+        assert_eq!(events[0]["reference"], "$event");
+        assert_eq!(events[1]["message_id"], "b@example.com");
+        assert!(events[1]["reference"].is_null());
+    }
+
+    #[test]
+    fn test_delivered_mappings() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = AuditLog::open(&dir.path().join("audit.log")).unwrap();
+        log.delivered("a@example.com", "relay", None).unwrap();
+        log.delivered("b@example.com", "files", None).unwrap();
+        log.delivered("a@example.com", "matrix", Some("$event"))
+            .unwrap();
+
+        assert_eq!(
+            log.delivered_mappings("a@example.com").unwrap(),
+            vec!["relay", "matrix"]
+        );
+        assert!(log.delivered_mappings("c@example.com").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_received() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let client = ClientIdentity {
+            peer: Some("192.0.2.1:4711".parse().unwrap()),
+            helo: Some("client.example".to_string()),
+            ..ClientIdentity::default()
+        };
+        AuditLog::open(&path)
+            .unwrap()
+            .received("a@example.com", &client, &["bcc@example.org".to_string()])
+            .unwrap();
+
+        let event: Value = serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(event["event"], "received");
+        assert_eq!(event["peer"], "192.0.2.1:4711");
+        assert_eq!(event["helo"], "client.example");
+        assert!(event["tls"].is_null());
+        assert_eq!(event["hidden_recipients"], json!(["bcc@example.org"]));
+    }
+
+    #[test]
+    fn test_signed_log() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        // Entries written before signing was enabled are kept:
+        AuditLog::open(&path)
+            .unwrap()
+            .delivered("old@example.com", "files", None)
+            .unwrap();
+        let log = AuditLog::open_signed(&path, dir.path()).unwrap();
+        log.delivered("a@example.com", "files", None).unwrap();
+        // Another process appends to the same log:
+        AuditLog::open_signed(&path, dir.path())
+            .unwrap()
+            .delivered("b@example.com", "matrix", Some("$event"))
+            .unwrap();
+        log.sign().unwrap();
+        // Without new entries, there is nothing to sign:
+        log.sign().unwrap();
+        log.delivered("c@example.com", "files", None).unwrap();
+
+        let public_key = base64::decode(
+            fs::read_to_string(dir.path().join(PUBLIC_KEY_FILE))
+                .unwrap()
+                .trim(),
+        )
+        .unwrap();
+        let verification = verify(&path, Some(&public_key)).unwrap();
+        assert_eq!(verification.entries, 5);
+        assert_eq!(verification.chain_start, Some(2));
+        assert_eq!(verification.last_signature.map(|(line, _)| line), Some(4));
+        assert_eq!(verification.tampered, None);
+        assert!(verify(&path, Some(&[0; 32])).unwrap().tampered.is_some());
+
+        let content = fs::read_to_string(&path).unwrap();
+        fs::write(&path, content.replacen("\"matrix\"", "\"relay\"", 1)).unwrap();
+        let verification = verify(&path, Some(&public_key)).unwrap();
+        assert_eq!(verification.tampered.unwrap().0, 4);
+        assert_eq!(verification.last_signature, None);
+    }
+
+    #[test]
+    fn test_forget() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let client = ClientIdentity::default();
+        let log = AuditLog::open_signed(&path, dir.path()).unwrap();
+        log.received("a@example.com", &client, &["Jane@example.org".to_string()])
+            .unwrap();
+        log.delivered("a@example.com", "files", None).unwrap();
+        log.sign().unwrap();
+        log.delivered("b@example.com", "files", None).unwrap();
+        // Only whole addresses are forgotten, but regardless of their case:
+        let hidden = [
+            "bob.jane@example.org".to_string(),
+            "John <JANE@example.org>".to_string(),
+        ];
+        log.received("c@example.com", &client, &hidden).unwrap();
+        let old_head = line_hash(
+            fs::read_to_string(&path)
+                .unwrap()
+                .lines()
+                .last()
+                .unwrap()
+                .as_bytes(),
+        );
+
+        // Another process, that has the log open, appends to the new file:
+        let other = AuditLog::open_signed(&path, dir.path()).unwrap();
+
+        let forgotten = ["a@example.com".to_string()];
+        assert_eq!(log.forget("jane@example.org", &forgotten).unwrap(), 3);
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(!content.contains("a@example.com"));
+        assert!(!content.contains("Jane@example.org"));
+        assert!(content.contains("b@example.com"));
+        let events: Vec<Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(events[0]["hidden_recipients"], json!(["[forgotten]"]));
+        assert_eq!(
+            events[4]["hidden_recipients"],
+            json!(["bob.jane@example.org", "[forgotten]"])
+        );
+        assert_eq!(events[5]["event"], "forgotten");
+        assert_eq!(events[5]["entries"], 3);
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(events[5]["old_head"], old_head);
+        assert_eq!(events[5]["new_head"], line_hash(lines[4].as_bytes()));
+        let verification = verify(&path, None).unwrap();
+        assert_eq!(verification.entries, 6);
+        assert_eq!(verification.last_signature.map(|(line, _)| line), Some(3));
+        assert_eq!(verification.tampered, None);
+        other.delivered("d@example.com", "files", None).unwrap();
+        assert!(fs::read_to_string(&path).unwrap().contains("d@example.com"));
+        assert_eq!(verify(&path, None).unwrap().tampered, None);
+        assert!(!dir.path().join("audit.log.tmp").exists());
+
+        assert_eq!(log.forget("jane@example.org", &forgotten).unwrap(), 0);
+        // Without the signing key, the hash chain can't be renewed:
+        let unsigned = AuditLog::open(&path).unwrap();
+        assert!(unsigned.forget("b@example.com", &[]).is_err());
+    }
+}
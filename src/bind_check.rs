@@ -0,0 +1,75 @@
+use log::warn;
+use tokio::{net::lookup_host, time::interval};
+
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// Detects when hostnames in `bind_addresses` resolve to other addresses than at startup, e.g. because of dynamic DNS.
+///
+/// The listeners are not rebound, because after dropping privileges we usually cannot bind to the SMTP ports anymore.
+/// Instead a warning tells the admin to restart the server.
+pub(crate) struct BindAddressCheck {
+    hosts: Vec<(String, Vec<SocketAddr>)>,
+    interval: Duration,
+}
+
+impl BindAddressCheck {
+    pub(crate) fn new(hosts: Vec<(String, Vec<SocketAddr>)>, interval: Duration) -> Self {
+        BindAddressCheck { hosts, interval }
+    }
+
+    /// Resolves the hostnames periodically forever.
+    pub(crate) async fn run(mut self) {
+        let mut ticker = interval(self.interval);
+        // The first tick completes immediately, but we just resolved the hostnames during startup:
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            self.check().await;
+        }
+    }
+
+    async fn check(&mut self) {
+        for (host, bound) in self.hosts.iter_mut() {
+            let resolved: Vec<SocketAddr> = match lookup_host(host.as_str()).await {
+                Ok(addrs) => addrs.collect(),
+                Err(e) => {
+                    warn!("Could not resolve bind address {}: {}", host, e);
+                    continue;
+                }
+            };
+            let (added, removed) = drift(bound, &resolved);
+            if added.is_empty() && removed.is_empty() {
+                continue;
+            }
+            warn!(
+                "Bind address {} now resolves to {:?} (new: {:?}, gone: {:?}). Restart the server to listen on the new addresses.",
+                host, resolved, added, removed
+            );
+            // Only warn again, if the addresses change once more:
+            *bound = resolved;
+        }
+    }
+}
+
+/// Returns the addresses, that are only in `new`, and the addresses, that are only in `old`.
+fn drift(old: &[SocketAddr], new: &[SocketAddr]) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+    let added = new.iter().filter(|a| !old.contains(a)).copied().collect();
+    let removed = old.iter().filter(|a| !new.contains(a)).copied().collect();
+    (added, removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_drift() {
+        let a: SocketAddr = "192.0.2.1:25".parse().unwrap();
+        let b: SocketAddr = "192.0.2.2:25".parse().unwrap();
+        let c: SocketAddr = "[2001:db8::1]:25".parse().unwrap();
+
+        assert_eq!(drift(&[a, b], &[b, a]), (vec![], vec![]));
+        assert_eq!(drift(&[a, b], &[b, c]), (vec![c], vec![a]));
+    }
+}
@@ -0,0 +1,323 @@
+//! Parsing of delivery status notifications (bounces) in the format of RFC 3464.
+//!
+//! A DSN is a `multipart/report` message with `report-type=delivery-status`. Its `message/delivery-status` part holds
+//! one block of fields for the message and one block for each recipient, e.g.:
+//!
+//! ```text
+//! Reporting-MTA: dns; mx.example.org
+//!
+//! Final-Recipient: rfc822; user@example.org
+//! Action: failed
+//! Status: 5.1.1
+//! Diagnostic-Code: smtp; 550 5.1.1 User unknown
+//! ```
+//!
+//! The original message (or only its headers) may follow in a `message/rfc822` or `text/rfc822-headers` part, which
+//! is used to find the Message-ID of the bounced email.
+
+use serde_json::{json, Value};
+
+/// Whether retrying the delivery may succeed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum FailureKind {
+    /// A permanent failure (status 5.x.x), e.g. an unknown recipient.
+    Hard,
+    /// A temporary failure (status 4.x.x), e.g. a full mailbox. The reporting server may still retry.
+    Soft,
+}
+
+impl FailureKind {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            FailureKind::Hard => "hard",
+            FailureKind::Soft => "soft",
+        }
+    }
+}
+
+/// A recipient, for whom the delivery failed or was delayed.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct FailedRecipient {
+    pub(crate) recipient: String,
+    /// The enhanced status code (RFC 3463), e.g. "5.1.1".
+    pub(crate) status: String,
+    pub(crate) kind: FailureKind,
+    /// The reply of the server, that rejected the email, if the report contains it.
+    pub(crate) diagnostic: Option<String>,
+}
+
+/// A parsed delivery status notification.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Bounce {
+    /// The Message-ID of the email, that could not be delivered, without angle brackets.
+    pub(crate) original_message_id: Option<String>,
+    pub(crate) recipients: Vec<FailedRecipient>,
+}
+
+impl Bounce {
+    /// Parses the raw email as DSN. Returns None, if it is no DSN or reports no failed or delayed recipient.
+    pub(crate) fn parse(raw: &[u8]) -> Option<Bounce> {
+        let raw = String::from_utf8_lossy(raw);
+        let (headers, body) = split_part(&raw);
+        let content_type = field(&fields(headers), "content-type")?;
+        if !media_type(&content_type).eq_ignore_ascii_case("multipart/report") {
+            return None;
+        }
+        let boundary = parameter(&content_type, "boundary")?;
+
+        let mut bounce = Bounce {
+            original_message_id: None,
+            recipients: vec![],
+        };
+        for part in split_multipart(body, &boundary) {
+            let (headers, body) = split_part(part);
+            let part_type = field(&fields(headers), "content-type").unwrap_or_default();
+            match media_type(&part_type).to_ascii_lowercase().as_str() {
+                "message/delivery-status" => {
+                    bounce.recipients.extend(
+                        blocks(body)
+                            .iter()
+                            .filter_map(|block| FailedRecipient::parse(block)),
+                    );
+                }
+                "message/rfc822" | "text/rfc822-headers" => {
+                    let (original_headers, _) = split_part(body);
+                    bounce.original_message_id = field(&fields(original_headers), "message-id")
+                        .map(|id| id.trim_start_matches('<').trim_end_matches('>').to_string());
+                }
+                _ => {}
+            }
+        }
+
+        if bounce.recipients.is_empty() {
+            None
+        } else {
+            Some(bounce)
+        }
+    }
+
+    /// Returns `Hard`, if the delivery to any recipient failed permanently, and `Soft` otherwise.
+    pub(crate) fn kind(&self) -> FailureKind {
+        if self
+            .recipients
+            .iter()
+            .any(|recipient| recipient.kind == FailureKind::Hard)
+        {
+            FailureKind::Hard
+        } else {
+            FailureKind::Soft
+        }
+    }
+
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "original_message_id": self.original_message_id,
+            "kind": self.kind().name(),
+            "recipients": self.recipients.iter().map(|recipient| json!({
+                "recipient": recipient.recipient,
+                "status": recipient.status,
+                "kind": recipient.kind.name(),
+                "diagnostic": recipient.diagnostic,
+            })).collect::<Vec<_>>(),
+        })
+    }
+}
+
+impl FailedRecipient {
+    /// Parses a per-recipient block of a delivery-status part. Returns None for blocks without recipient and for
+    /// successful deliveries.
+    fn parse(block: &[(String, String)]) -> Option<Self> {
+        let recipient =
+            field(block, "final-recipient").or_else(|| field(block, "original-recipient"))?;
+        // The address is preceded by its type, e.g. "rfc822; user@example.org":
+        let recipient = match recipient.split_once(';') {
+            Some((_, address)) => address.trim().to_string(),
+            None => recipient,
+        };
+        let action = field(block, "action")
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let status = field(block, "status").unwrap_or_default();
+        let kind = match (status.chars().next(), action.as_str()) {
+            (Some('5'), _) => FailureKind::Hard,
+            (Some('4'), _) => FailureKind::Soft,
+            (_, "failed") => FailureKind::Hard,
+            (_, "delayed") => FailureKind::Soft,
+            _ => return None,
+        };
+        Some(FailedRecipient {
+            recipient,
+            status,
+            kind,
+            diagnostic: field(block, "diagnostic-code").map(|diagnostic| {
+                match diagnostic.split_once(';') {
+                    Some((_, reply)) => reply.trim().to_string(),
+                    None => diagnostic,
+                }
+            }),
+        })
+    }
+}
+
+/// Splits a message or body part into its headers and its body.
+fn split_part(part: &str) -> (&str, &str) {
+    let crlf = part.find("\r\n\r\n").map(|i| (i, 4));
+    let lf = part.find("\n\n").map(|i| (i, 2));
+    let separator = match (crlf, lf) {
+        (Some(crlf), Some(lf)) => Some(if crlf.0 < lf.0 { crlf } else { lf }),
+        (crlf, lf) => crlf.or(lf),
+    };
+    match separator {
+        Some((i, len)) => (&part[..i], &part[i + len..]),
+        None => (part, ""),
+    }
+}
+
+/// Returns the body parts of a multipart body.
+fn split_multipart<'a>(body: &'a str, boundary: &str) -> Vec<&'a str> {
+    let delimiter = format!("--{}", boundary);
+    let close_delimiter = format!("{}--", delimiter);
+    let mut parts = vec![];
+    let mut start = None;
+    let mut offset = 0;
+    for line in body.split_inclusive('\n') {
+        if line.trim_end().starts_with(&delimiter) {
+            if let Some(start) = start {
+                parts.push(&body[start..offset]);
+            }
+            if line.trim_end() == close_delimiter {
+                return parts;
+            }
+            start = Some(offset + line.len());
+        }
+        offset += line.len();
+    }
+    parts
+}
+
+/// Parses header fields (or fields of a delivery-status block) and unfolds continuation lines. Names are returned in
+/// lower case.
+fn fields(headers: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = vec![];
+    for line in headers.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    fields
+}
+
+/// Returns the blocks of fields of a delivery-status part, which are separated by empty lines.
+fn blocks(body: &str) -> Vec<Vec<(String, String)>> {
+    body.replace("\r\n", "\n")
+        .split("\n\n")
+        .map(fields)
+        .filter(|block| !block.is_empty())
+        .collect()
+}
+
+fn field(fields: &[(String, String)], name: &str) -> Option<String> {
+    fields
+        .iter()
+        .find(|(field_name, _)| field_name == name)
+        .map(|(_, value)| value.clone())
+}
+
+/// Returns the media type of a Content-Type value without parameters.
+fn media_type(content_type: &str) -> &str {
+    content_type.split(';').next().unwrap_or_default().trim()
+}
+
+/// Returns the value of a parameter of a Content-Type value.
+fn parameter(content_type: &str, name: &str) -> Option<String> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (param_name, value) = param.split_once('=')?;
+        if param_name.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim().trim_matches('"').to_string())
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DSN: &[u8] = b"From: MAILER-DAEMON@mx.example.org\r\n\
+To: bounces@example.com\r\n\
+Message-ID: <dsn-1@mx.example.org>\r\n\
+Subject: Undelivered Mail Returned to Sender\r\n\
+Content-Type: multipart/report; report-type=delivery-status;\r\n\
+\tboundary=\"BOUNDARY\"\r\n\
+\r\n\
+--BOUNDARY\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Your message could not be delivered.\r\n\
+--BOUNDARY\r\n\
+Content-Type: message/delivery-status\r\n\
+\r\n\
+Reporting-MTA: dns; mx.example.org\r\n\
+\r\n\
+Final-Recipient: rfc822; unknown@example.org\r\n\
+Action: failed\r\n\
+Status: 5.1.1\r\n\
+Diagnostic-Code: smtp; 550 5.1.1 User unknown\r\n\
+\r\n\
+Final-Recipient: rfc822; full@example.org\r\n\
+Action: delayed\r\n\
+Status: 4.2.2\r\n\
+\r\n\
+Final-Recipient: rfc822; ok@example.org\r\n\
+Action: delivered\r\n\
+Status: 2.0.0\r\n\
+--BOUNDARY\r\n\
+Content-Type: text/rfc822-headers\r\n\
+\r\n\
+From: alerts@example.com\r\n\
+Message-ID: <forwarded@example.com>\r\n\
+--BOUNDARY--\r\n";
+
+    #[test]
+    fn test_parse_dsn() {
+        let bounce = Bounce::parse(DSN).unwrap();
+        assert_eq!(
+            bounce.original_message_id.as_deref(),
+            Some("forwarded@example.com")
+        );
+        assert_eq!(
+            bounce.recipients,
+            vec![
+                FailedRecipient {
+                    recipient: "unknown@example.org".to_string(),
+                    status: "5.1.1".to_string(),
+                    kind: FailureKind::Hard,
+                    diagnostic: Some("550 5.1.1 User unknown".to_string()),
+                },
+                FailedRecipient {
+                    recipient: "full@example.org".to_string(),
+                    status: "4.2.2".to_string(),
+                    kind: FailureKind::Soft,
+                    diagnostic: None,
+                },
+            ]
+        );
+        assert_eq!(bounce.kind(), FailureKind::Hard);
+        assert_eq!(bounce.to_json()["kind"], "hard");
+    }
+
+    #[test]
+    fn test_no_dsn() {
+        assert!(Bounce::parse(b"Message-ID: <a@example.com>\r\n\r\nHello.\r\n").is_none());
+        assert!(Bounce::parse(
+            b"Content-Type: multipart/mixed; boundary=b\r\n\r\n--b\r\n\r\nHello.\r\n--b--\r\n"
+        )
+        .is_none());
+    }
+}
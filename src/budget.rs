@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Limits the total size of the message buffers of all SMTP sessions.
+///
+/// Sessions reserve memory for every chunk of DATA they buffer and release it when they end. If a reservation would
+/// exceed the budget, it fails and the session rejects the email with a temporary error, protecting small hosts from
+/// running out of memory, when many large emails arrive at the same time.
+pub(crate) struct MemoryBudget {
+    limit: usize,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub(crate) fn new(limit: usize) -> Self {
+        MemoryBudget {
+            limit,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// Reserves `bytes` bytes. Returns false without reserving anything, if this would exceed the budget.
+    pub(crate) fn try_reserve(&self, bytes: usize) -> bool {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |used| {
+                used.checked_add(bytes).filter(|new| *new <= self.limit)
+            })
+            .is_ok()
+    }
+
+    /// Releases `bytes` previously reserved bytes.
+    pub(crate) fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::AcqRel);
+    }
+
+    /// Returns true, if no memory is left for new emails.
+    pub(crate) fn is_exhausted(&self) -> bool {
+        self.used.load(Ordering::Acquire) >= self.limit
+    }
+
+    pub(crate) fn limit(&self) -> usize {
+        self.limit
+    }
+
+    pub(crate) fn used(&self) -> usize {
+        self.used.load(Ordering::Acquire)
+    }
+}
+
+/// Memory reserved from a `MemoryBudget`, that is released when the reservation is dropped.
+pub(crate) struct Reservation {
+    budget: Arc<MemoryBudget>,
+    bytes: usize,
+}
+
+impl Reservation {
+    pub(crate) fn new(budget: Arc<MemoryBudget>) -> Self {
+        Reservation { budget, bytes: 0 }
+    }
+
+    /// Reserves `bytes` additional bytes. Returns false, if this would exceed the budget.
+    pub(crate) fn try_grow(&mut self, bytes: usize) -> bool {
+        if self.budget.try_reserve(bytes) {
+            self.bytes += bytes;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns true, if growing by `bytes` bytes would exceed the budget even if no other reservation existed.
+    pub(crate) fn exceeds_limit(&self, bytes: usize) -> bool {
+        self.bytes.saturating_add(bytes) > self.budget.limit()
+    }
+
+    pub(crate) fn budget(&self) -> &MemoryBudget {
+        &self.budget
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.budget.release(self.bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reservations() {
+        let budget = MemoryBudget::new(100);
+        assert!(budget.try_reserve(60));
+        assert!(!budget.try_reserve(41));
+        assert_eq!(budget.used(), 60);
+        assert!(budget.try_reserve(40));
+        assert!(budget.is_exhausted());
+
+        budget.release(100);
+        assert!(!budget.is_exhausted());
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn test_reservation_release_on_drop() {
+        let budget = Arc::new(MemoryBudget::new(100));
+        let mut reservation = Reservation::new(budget.clone());
+        assert!(reservation.try_grow(70));
+        assert!(!reservation.try_grow(31));
+        assert!(reservation.exceeds_limit(31));
+        assert!(!reservation.exceeds_limit(30));
+        assert_eq!(budget.used(), 70);
+
+        drop(reservation);
+        assert_eq!(budget.used(), 0);
+    }
+}
@@ -0,0 +1,400 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::{MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Component, Path, PathBuf};
+use std::process::ExitCode;
+
+use super::ctl;
+use crate::config::Config;
+use crate::Error;
+
+/// The size of the headers and the data blocks of a tar archive.
+const BLOCK: usize = 512;
+
+/// Writes the state directories of the server and of all tenants to a new tar archive at `archive`.
+///
+/// If the server is running, its writes to the state files are blocked through the control socket (with the `freeze`
+/// command, optionally after authenticating with `token`) until the archive is complete, so it holds a consistent
+/// state.
+pub(crate) fn backup(config: &Config, archive: &str, token: Option<&str>) -> ExitCode {
+    let result = freeze(config, token).and_then(|frozen| {
+        if frozen.is_none() {
+            println!("The server is not running, so the state is copied without freezing it.");
+        }
+        let count = write_archive(&state_dirs(config)?, Path::new(archive))?;
+        // The server resumes writing, when the connection is closed:
+        drop(frozen);
+        Ok(count)
+    });
+    match result {
+        Ok(count) => {
+            println!("Wrote {} files to {}.", count, archive);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error while writing backup: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Restores the state directories of the server and of all tenants from the tar archive at `archive`.
+///
+/// The files of the archive replace existing files with the same names. The server must not be running.
+pub(crate) fn restore(config: &Config, archive: &str) -> ExitCode {
+    let result = (|| {
+        if let Some(ref path) = config.control_socket {
+            if UnixStream::connect(path).is_ok() {
+                return Err(Error::config(
+                    "The server is running. Stop it before restoring its state.",
+                ));
+            }
+        }
+        extract(
+            &state_dirs(config)?,
+            &mut BufReader::new(File::open(archive)?),
+        )
+    })();
+    match result {
+        Ok(count) => {
+            println!("Restored {} files from {}.", count, archive);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error while restoring backup: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns the configured state directories together with their names in the archive.
+fn state_dirs(config: &Config) -> Result<Vec<(String, PathBuf)>, Error> {
+    let mut dirs = vec![];
+    if let Some(ref state_dir) = config.state_dir {
+        dirs.push(("state".to_string(), state_dir.clone()));
+    }
+    for tenant in config.tenants.iter() {
+        if let Some(ref state_dir) = tenant.state_dir {
+            if tenant.name.contains('/') {
+                return Err(Error::config(format!(
+                    "The state directory of tenant '{}' can't be archived, because its name contains '/'.",
+                    tenant.name
+                )));
+            }
+            dirs.push((format!("tenants/{}", tenant.name), state_dir.clone()));
+        }
+    }
+    if dirs.is_empty() {
+        return Err(Error::config("No state directory is configured."));
+    }
+    Ok(dirs)
+}
+
+/// Blocks the writes of the running server to its state files and returns the connection, that keeps them blocked, or
+/// None, if the server is not running.
+fn freeze(config: &Config, token: Option<&str>) -> Result<Option<UnixStream>, Error> {
+    let path = match config.control_socket {
+        Some(ref path) => path,
+        None => return Ok(None),
+    };
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok(None)
+        }
+        Err(e) => return Err(e.into()),
+    };
+    if ctl::exchange(stream.try_clone()?, "freeze", token, &mut io::sink())? {
+        Ok(Some(stream))
+    } else {
+        Err(Error::config(
+            "The server refused to freeze its state files.",
+        ))
+    }
+}
+
+/// Writes all regular files and directories below `dirs` to a new tar archive and returns the number of files.
+fn write_archive(dirs: &[(String, PathBuf)], archive: &Path) -> Result<usize, Error> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(archive)?;
+    let res = write_entries(dirs, &archive.canonicalize()?, BufWriter::new(file));
+    if res.is_err() {
+        // Don't leave a partial archive, that could be mistaken for a complete one:
+        let _ = fs::remove_file(archive);
+    }
+    res
+}
+
+/// Writes the entries of `write_archive` to `out`. The file `archive` itself is skipped, since it may be created within
+/// a state directory.
+fn write_entries(
+    dirs: &[(String, PathBuf)],
+    archive: &Path,
+    mut out: BufWriter<File>,
+) -> Result<usize, Error> {
+    let mut count = 0;
+    for (name, dir) in dirs {
+        let mut pending = vec![(name.clone(), dir.clone())];
+        while let Some((name, path)) = pending.pop() {
+            let metadata = fs::symlink_metadata(&path)?;
+            if metadata.is_dir() {
+                write_header(&mut out, &format!("{}/", name), &metadata, 0, b'5')?;
+                let mut entries = fs::read_dir(&path)?
+                    .map(|entry| entry.map(|entry| entry.file_name()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                // Pop the entries in alphabetical order:
+                entries.sort_unstable_by(|a, b| b.cmp(a));
+                for entry in entries {
+                    let entry_name = entry.to_str().ok_or_else(|| {
+                        Error::config(format!(
+                            "File name in {} is not valid UTF-8.",
+                            path.display()
+                        ))
+                    })?;
+                    pending.push((format!("{}/{}", name, entry_name), path.join(&entry)));
+                }
+            } else if metadata.is_file() && path.canonicalize()? != archive {
+                let content = fs::read(&path)?;
+                write_header(&mut out, &name, &metadata, content.len() as u64, b'0')?;
+                out.write_all(&content)?;
+                out.write_all(&[0; BLOCK][..(BLOCK - content.len() % BLOCK) % BLOCK])?;
+                count += 1;
+            }
+            // Sockets (e.g. the control socket) and links are not part of the state.
+        }
+    }
+    out.write_all(&[0; 2 * BLOCK])?;
+    out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+    Ok(count)
+}
+
+/// Writes the ustar header of an entry.
+fn write_header(
+    out: &mut impl Write,
+    name: &str,
+    metadata: &fs::Metadata,
+    size: u64,
+    kind: u8,
+) -> Result<(), Error> {
+    let mut header = [0; BLOCK];
+    let (prefix, name) = match name.len() {
+        0..=100 => ("", name),
+        _ => name
+            .char_indices()
+            .filter(|(i, c)| *c == '/' && *i <= 155 && name.len() - i - 1 <= 100)
+            .map(|(i, _)| (&name[..i], &name[i + 1..]))
+            .next()
+            .ok_or_else(|| Error::config(format!("Path '{}' is too long to archive.", name)))?,
+    };
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], u64::from(metadata.mode() & 0o7777));
+    write_octal(&mut header[108..116], u64::from(metadata.uid()));
+    write_octal(&mut header[116..124], u64::from(metadata.gid()));
+    write_octal(&mut header[124..136], size);
+    write_octal(&mut header[136..148], metadata.mtime().max(0) as u64);
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    out.write_all(&header)?;
+    Ok(())
+}
+
+/// Writes `value` as zero padded octal number, that is terminated by a null byte, to `field`.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:0width$o}\0", value, width = field.len() - 1);
+    field.copy_from_slice(&digits.as_bytes()[digits.len() - field.len()..]);
+}
+
+/// Reads an octal number field of a tar header.
+fn read_octal(field: &[u8]) -> Result<u64, Error> {
+    let digits = String::from_utf8_lossy(field);
+    let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+    if digits.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(digits, 8)
+        .map_err(|_| Error::config(format!("Invalid number '{}' in archive.", digits)))
+}
+
+/// Extracts the entries of a tar archive, that was written by `write_archive`, into `dirs` and returns the number of
+/// files.
+fn extract(dirs: &[(String, PathBuf)], archive: &mut impl Read) -> Result<usize, Error> {
+    let mut count = 0;
+    loop {
+        let mut header = [0; BLOCK];
+        archive.read_exact(&mut header)?;
+        if header.iter().all(|b| *b == 0) {
+            return Ok(count);
+        }
+        let mut unsigned = header;
+        unsigned[148..156].copy_from_slice(b"        ");
+        let checksum: u64 = unsigned.iter().map(|b| u64::from(*b)).sum();
+        if read_octal(&header[148..156])? != checksum {
+            return Err(Error::config("The archive is damaged (wrong checksum)."));
+        }
+        let field = |range: std::ops::Range<usize>| {
+            let field = &header[range];
+            let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+        let name = match field(345..500) {
+            prefix if prefix.is_empty() => field(0..100),
+            prefix => format!("{}/{}", prefix, field(0..100)),
+        };
+        let mode = read_octal(&header[100..108])? as u32;
+        let size = read_octal(&header[124..136])? as usize;
+        let mut content = vec![0; size + (BLOCK - size % BLOCK) % BLOCK];
+        archive.read_exact(&mut content)?;
+        content.truncate(size);
+
+        let path = target(dirs, name.trim_end_matches('/'))?;
+        match header[156] {
+            b'5' => fs::create_dir_all(&path)?,
+            b'0' | b'\0' => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                // Replace the file atomically, so a failed restore does not leave a truncated file:
+                let tmp_path = path.with_extension("restore");
+                OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .mode(0o600)
+                    .open(&tmp_path)?
+                    .write_all(&content)?;
+                fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode))?;
+                fs::rename(&tmp_path, &path)?;
+                count += 1;
+            }
+            kind => {
+                return Err(Error::config(format!(
+                    "Unsupported entry type '{}' of '{}' in archive.",
+                    kind as char, name
+                )))
+            }
+        }
+    }
+}
+
+/// Returns the path, that an entry of the archive is restored to.
+fn target(dirs: &[(String, PathBuf)], name: &str) -> Result<PathBuf, Error> {
+    for (dir_name, dir) in dirs {
+        let rest = match name.strip_prefix(dir_name.as_str()) {
+            Some("") => return Ok(dir.clone()),
+            Some(rest) => match rest.strip_prefix('/') {
+                Some(rest) => rest,
+                None => continue,
+            },
+            None => continue,
+        };
+        // Entries must not escape the state directory:
+        if !Path::new(rest)
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            return Err(Error::config(format!(
+                "Invalid path '{}' in archive.",
+                name
+            )));
+        }
+        return Ok(dir.join(rest));
+    }
+    Err(Error::config(format!(
+        "'{}' in archive does not belong to a configured state directory.",
+        name
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let state_dir = dir.path().join("state");
+        let tenant_dir = dir.path().join("acme");
+        fs::create_dir_all(state_dir.join("sessions")).unwrap();
+        fs::create_dir(&tenant_dir).unwrap();
+        fs::write(state_dir.join("stats.json"), "{}").unwrap();
+        fs::write(
+            state_dir.join("sessions").join("bot.json"),
+            vec![b'x'; 1000],
+        )
+        .unwrap();
+        fs::write(tenant_dir.join(format!("{}.jsonl", "t".repeat(90))), "").unwrap();
+        fs::set_permissions(
+            state_dir.join("stats.json"),
+            fs::Permissions::from_mode(0o600),
+        )
+        .unwrap();
+        let dirs = vec![
+            ("state".to_string(), state_dir.clone()),
+            ("tenants/acme".to_string(), tenant_dir.clone()),
+        ];
+        // The archive is not archived itself:
+        let archive = state_dir.join("backup.tar");
+        assert_eq!(write_archive(&dirs, &archive).unwrap(), 3);
+        assert!(write_archive(&dirs, &archive).is_err());
+
+        let restored = tempfile::tempdir().unwrap();
+        let restored_dirs = vec![
+            ("state".to_string(), restored.path().join("state")),
+            ("tenants/acme".to_string(), restored.path().join("acme")),
+        ];
+        let content = fs::read(&archive).unwrap();
+        assert_eq!(extract(&restored_dirs, &mut &content[..]).unwrap(), 3);
+        let restored_stats = restored.path().join("state").join("stats.json");
+        assert_eq!(fs::read_to_string(&restored_stats).unwrap(), "{}");
+        assert_eq!(
+            fs::metadata(&restored_stats).unwrap().permissions().mode() & 0o777,
+            0o600
+        );
+        assert_eq!(
+            fs::read(restored.path().join("state/sessions/bot.json")).unwrap(),
+            vec![b'x'; 1000]
+        );
+        assert!(restored
+            .path()
+            .join("acme")
+            .join(format!("{}.jsonl", "t".repeat(90)))
+            .is_file());
+        assert!(!restored.path().join("state/backup.tar").exists());
+
+        // Only the state directories of the config are restored:
+        assert!(extract(&restored_dirs[..1], &mut &content[..]).is_err());
+        let mut damaged = content.clone();
+        damaged[0] = b'x';
+        assert!(extract(&restored_dirs, &mut &damaged[..]).is_err());
+    }
+
+    #[test]
+    fn test_target() {
+        let dirs = vec![("state".to_string(), PathBuf::from("/var/lib/kutsche"))];
+        assert_eq!(
+            target(&dirs, "state/stats.json").unwrap(),
+            Path::new("/var/lib/kutsche/stats.json")
+        );
+        assert_eq!(
+            target(&dirs, "state").unwrap(),
+            Path::new("/var/lib/kutsche")
+        );
+        assert!(target(&dirs, "state/../etc/passwd").is_err());
+        assert!(target(&dirs, "statefile").is_err());
+        assert!(target(&dirs, "/etc/passwd").is_err());
+    }
+}
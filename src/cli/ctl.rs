@@ -0,0 +1,120 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::process::ExitCode;
+
+use crate::config::Config;
+use crate::Error;
+
+/// Sends `command` to the control socket of the running server and prints the answer. If a token is given, the client
+/// authenticates with it first.
+///
+/// The answer to `tail` does not end, so the events of the mail feed are printed until the server closes the
+/// connection or the program is interrupted.
+pub(crate) fn run(config: &Config, command: &str, token: Option<&str>) -> ExitCode {
+    let path = match config.control_socket {
+        Some(ref path) => path,
+        None => {
+            eprintln!("The config file does not define a control_socket.");
+            return ExitCode::FAILURE;
+        }
+    };
+    let result = UnixStream::connect(path)
+        .map_err(Error::from)
+        .and_then(|stream| exchange(stream, command, token, &mut std::io::stdout()));
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!(
+                "Error while using the control socket {}: {}",
+                path.display(),
+                e
+            );
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Performs the exchange on an open connection, writes the answer (without the final "OK") to `out` and returns
+/// whether the command succeeded.
+pub(super) fn exchange(
+    stream: UnixStream,
+    command: &str,
+    token: Option<&str>,
+    out: &mut impl Write,
+) -> Result<bool, Error> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    let mut send = |line: &str| -> Result<(), Error> {
+        writer.write_all(line.as_bytes())?;
+        writer.write_all(b"\n")?;
+        Ok(writer.flush()?)
+    };
+    if let Some(token) = token {
+        send(&format!("auth {}", token))?;
+        if !print_answer(&mut reader, out)? {
+            return Ok(false);
+        }
+    }
+    send(command)?;
+    let ok = print_answer(&mut reader, out)?;
+    if ok && command.trim() == "tail" {
+        for line in reader.lines() {
+            writeln!(out, "{}", line?)?;
+            out.flush()?;
+        }
+    }
+    Ok(ok)
+}
+
+/// Prints the lines of one answer and returns true, if it ended with "OK".
+fn print_answer(reader: &mut impl BufRead, out: &mut impl Write) -> Result<bool, Error> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(Error::config(
+                "The server closed the control connection unexpectedly.",
+            ));
+        }
+        let line = line.trim_end();
+        if line == "OK" {
+            return Ok(true);
+        }
+        if let Some(error) = line.strip_prefix("ERR ") {
+            eprintln!("Error: {}", error);
+            return Ok(false);
+        }
+        writeln!(out, "{}", line)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Read;
+    use std::thread;
+
+    #[test]
+    fn test_exchange() {
+        let (client, mut server) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || {
+            let mut received = String::new();
+            server
+                .write_all(b"OK\nOK\n2024-03-01\tdelivered\ta@example.com\tfiles\n")
+                .unwrap();
+            server.shutdown(std::net::Shutdown::Write).unwrap();
+            server.read_to_string(&mut received).unwrap();
+            received
+        });
+
+        let mut out = vec![];
+        assert!(exchange(client, "tail", Some("s3cr3t"), &mut out).unwrap());
+        assert_eq!(out, b"2024-03-01\tdelivered\ta@example.com\tfiles\n");
+        assert_eq!(server.join().unwrap(), "auth s3cr3t\ntail\n");
+
+        let (client, mut server) = UnixStream::pair().unwrap();
+        server.write_all(b"ERR missing scope queue\n").unwrap();
+        assert!(!exchange(client, "queue", None, &mut vec![]).unwrap());
+    }
+}
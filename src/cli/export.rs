@@ -0,0 +1,229 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::UNIX_EPOCH;
+
+use super::replay::read_stored;
+use crate::config::Config;
+use crate::maildest::write_mbox_entry;
+use crate::mapping::Mapping;
+use crate::Error;
+
+/// The suffix of the files, in which file destinations store the envelopes of emails.
+pub(crate) const ENVELOPE_SUFFIX: &str = ".envelope.json";
+/// The suffix of the (empty) files, that put single stored emails under legal hold.
+pub(crate) const HOLD_SUFFIX: &str = ".hold";
+
+/// The time of reception, the envelope sender and the path of a stored email.
+pub(crate) type StoredMessage = (u64, String, PathBuf);
+
+/// Writes all emails stored by the file destination of the mapping `mapping_name` (in files named like their message
+/// ID or in a maildir) to a new mbox file at `output`.
+pub(crate) fn run(config: &Config, mapping_name: &str, output: &str) -> ExitCode {
+    match export(config, mapping_name, Path::new(output)) {
+        Ok(count) => {
+            eprintln!("Exported {} emails to {}.", count, output);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error while exporting mapping '{}': {}", mapping_name, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns the number of exported emails.
+fn export(config: &Config, mapping_name: &str, output: &Path) -> Result<usize, Error> {
+    let mapping = config
+        .mapping(mapping_name)
+        .ok_or_else(|| Error::config(format!("There is no mapping '{}'.", mapping_name)))?;
+    let mut messages = mapping_messages(mapping)?.ok_or_else(|| {
+        Error::config(format!(
+            "The destination of mapping '{}' does not store emails in files.",
+            mapping_name
+        ))
+    })?;
+
+    // Older emails come first, like in a mailbox:
+    messages.sort();
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(output)?;
+    let mut writer = BufWriter::new(file);
+    for (received_at, sender, path) in messages.iter() {
+        let raw = read_stored(config, path)?;
+        write_mbox_entry(&mut writer, sender, *received_at, &raw)?;
+    }
+    writer.flush()?;
+    Ok(messages.len())
+}
+
+/// Returns the time of reception, the envelope sender and the path of every email stored by the destination of
+/// `mapping` or None, if the destination does not store emails in files.
+pub(crate) fn mapping_messages(mapping: &Mapping) -> Result<Option<Vec<StoredMessage>>, Error> {
+    let dir = match mapping.destination.storage_path() {
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+    if mapping.destination.is_mbox() {
+        return Err(Error::config(format!(
+            "The destination of mapping '{}' stores emails in the mbox file {} already.",
+            mapping.name,
+            dir.display()
+        )));
+    }
+    if mapping.destination.is_maildir() {
+        let mut messages = stored_messages(&dir.join("new"), dir)?;
+        messages.extend(stored_messages(&dir.join("cur"), dir)?);
+        Ok(Some(messages))
+    } else {
+        stored_messages(dir, dir).map(Some)
+    }
+}
+
+/// Returns the path of the file with the given suffix, that belongs to the stored email at `path`, in the directory
+/// `dir` of the file destination, e.g. the file with its envelope.
+pub(crate) fn sidecar_path(dir: &Path, path: &Path, suffix: &str) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // MUAs append flags to the names of emails in the cur directory of a maildir, e.g. ":2,S":
+    let unique_name = if path.parent() == Some(dir) {
+        file_name.as_str()
+    } else {
+        file_name.split(':').next().unwrap_or_default()
+    };
+    dir.join(format!("{}{}", unique_name, suffix))
+}
+
+/// Returns the time of reception, the envelope sender and the path of every email in `dir`, whose envelope files are
+/// in `envelope_dir`. Without an envelope file, the modification time of the email file is used.
+fn stored_messages(dir: &Path, envelope_dir: &Path) -> Result<Vec<StoredMessage>, Error> {
+    let mut messages = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        if !path.is_file()
+            || file_name.ends_with(ENVELOPE_SUFFIX)
+            || file_name.ends_with(HOLD_SUFFIX)
+        {
+            continue;
+        }
+        let envelope_path = sidecar_path(envelope_dir, &path, ENVELOPE_SUFFIX);
+        let envelope: Option<serde_json::Value> = File::open(envelope_path)
+            .ok()
+            .and_then(|file| serde_json::from_reader(file).ok());
+        let received_at = match envelope.as_ref().and_then(|e| e["received_at"].as_u64()) {
+            Some(received_at) => received_at,
+            None => fs::metadata(&path)?
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        };
+        let sender = envelope
+            .as_ref()
+            .and_then(|e| e["mail_from"].as_str())
+            .filter(|sender| !sender.is_empty())
+            .unwrap_or("MAILER-DAEMON")
+            .to_string();
+        messages.push((received_at, sender, path));
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maildest::{FileDestination, FileFormat};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn test_export() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = dir.path().join("stored");
+        fs::create_dir(&stored).unwrap();
+        fs::write(
+            stored.join("b@example.com"),
+            "b@example.com\n\nMessage-ID: <b@example.com>\r\n\r\nSecond\r\n",
+        )
+        .unwrap();
+        fs::write(
+            stored.join("b@example.com.envelope.json"),
+            r#"{"mail_from":"cron@example.org","received_at":2000000000}"#,
+        )
+        .unwrap();
+        fs::write(
+            stored.join("a@example.com"),
+            "a@example.com\n\nMessage-ID: <a@example.com>\r\n\r\nFirst\r\n",
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config
+            .dest_map
+            .insert(
+                ["*@example.com"],
+                Arc::new(Mapping::new(
+                    "files",
+                    Box::new(FileDestination::new(&stored).unwrap()),
+                )),
+            )
+            .unwrap();
+
+        let output = dir.path().join("export.mbox");
+        assert_eq!(export(&config, "files", &output).unwrap(), 2);
+        let mbox = fs::read_to_string(&output).unwrap();
+        assert!(mbox.starts_with("From MAILER-DAEMON "));
+        assert!(mbox.contains("\nFirst\n\nFrom cron@example.org Wed May 18 03:33:20 2033\n"));
+        assert!(mbox.ends_with("\nSecond\n\n"));
+
+        // Existing files are not overwritten:
+        assert!(export(&config, "files", &output).is_err());
+        assert!(export(&config, "unknown", &dir.path().join("other.mbox")).is_err());
+    }
+
+    #[test]
+    fn test_export_maildir() {
+        let dir = tempfile::tempdir().unwrap();
+        let maildir = dir.path().join("maildir");
+        fs::create_dir(&maildir).unwrap();
+        let destination = FileDestination::with_format(
+            &maildir,
+            FileFormat::Maildir {
+                hostname: "mx".to_string(),
+            },
+        )
+        .unwrap();
+        fs::write(
+            maildir.join("cur/1646128800.M1P1Q1.mx:2,S"),
+            "Message-ID: <a@example.com>\r\n\r\nRead\r\n",
+        )
+        .unwrap();
+        fs::write(
+            maildir.join("1646128800.M1P1Q1.mx.envelope.json"),
+            r#"{"mail_from":"cron@example.org","received_at":1646128800}"#,
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config
+            .dest_map
+            .insert(
+                ["*@example.com"],
+                Arc::new(Mapping::new("maildir", Box::new(destination))),
+            )
+            .unwrap();
+
+        let output = dir.path().join("export.mbox");
+        assert_eq!(export(&config, "maildir", &output).unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(&output).unwrap(),
+            "From cron@example.org Tue Mar  1 10:00:00 2022\nMessage-ID: <a@example.com>\n\nRead\n\n"
+        );
+    }
+}
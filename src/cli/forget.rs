@@ -0,0 +1,469 @@
+use fs2::FileExt;
+use serde_json::Value;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use super::export::{mapping_messages, sidecar_path, ENVELOPE_SUFFIX, HOLD_SUFFIX};
+use super::import::split_mbox;
+use super::replay::read_stored;
+use crate::config::Config;
+use crate::email::Email;
+use crate::ha::Spool;
+use crate::mapping::Mapping;
+use crate::Error;
+
+/// Deletes the emails from or to `address`, that are stored by file destinations or queued in the spool, removes what
+/// other destinations keep about them, redacts the address in the audit log and prints a report of all changes.
+/// Attachments in the attachment store, that only the deleted emails referred to, are deleted as well.
+///
+/// Emails under legal hold are kept. The server should be stopped, while the files it appends to are rewritten.
+pub(crate) async fn run(config: &Config, address: &str) -> ExitCode {
+    match forget(config, address).await {
+        Ok(report) => {
+            if report.is_empty() {
+                eprintln!("Nothing is stored about {}.", address);
+            }
+            for line in report.iter() {
+                println!("{}", line);
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error while forgetting {}: {}", address, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns the report with one line for every deleted or kept item.
+async fn forget(config: &Config, address: &str) -> Result<Vec<String>, Error> {
+    if !address.contains('@') {
+        return Err(Error::config(format!(
+            "'{}' is not an email address.",
+            address
+        )));
+    }
+    let mut mappings: Vec<&Arc<Mapping>> = config.mappings().collect();
+    // A mapping may be used for several addresses:
+    mappings.sort_by(|a, b| a.name.cmp(&b.name));
+    mappings.dedup_by(|a, b| Arc::ptr_eq(a, b));
+
+    let mut report = vec![];
+    let mut message_ids = vec![];
+    let mut attachments = vec![];
+    for mapping in mappings.iter() {
+        if mapping.destination.is_mbox() {
+            forget_in_mbox(mapping, address, &mut message_ids, &mut report)?;
+        } else {
+            forget_in_files(
+                config,
+                mapping,
+                address,
+                &mut message_ids,
+                &mut attachments,
+                &mut report,
+            )?;
+        }
+    }
+    forget_attachments(config, &mappings, attachments, &mut report)?;
+    if let (Some(node), Some(state_dir)) = (&config.ha_node, &config.state_dir) {
+        let spool = Spool::open(state_dir, node.as_str())?;
+        for job in spool.load(|name| config.mapping(name).cloned())? {
+            let mut email = match Email::parse(&job.raw) {
+                Ok(email) => email,
+                Err(_) => continue,
+            };
+            email.envelope = job.envelope.clone();
+            if let (true, Some(id)) = (email.involves(address), job.spool_id.as_deref()) {
+                spool.remove(id)?;
+                report.push(format!(
+                    "Deleted queued delivery {} (mapping '{}')",
+                    id, job.mapping.name
+                ));
+                message_ids.push(email.message_id.clone());
+            }
+        }
+    }
+    message_ids.sort();
+    message_ids.dedup();
+
+    for mapping in mappings.iter() {
+        for destination in std::iter::once(&mapping.destination).chain(mapping.fallback.iter()) {
+            for removed in destination.forget(address, &message_ids).await? {
+                report.push(format!("Removed {} (mapping '{}')", removed, mapping.name));
+            }
+        }
+    }
+    if let (Some(audit_log), Some(path)) = (config.open_audit_log()?, &config.audit_log) {
+        let entries = audit_log.forget(address, &message_ids)?;
+        if entries > 0 {
+            report.push(format!(
+                "Redacted {} entries of audit log {}",
+                entries,
+                path.display()
+            ));
+        }
+    }
+    Ok(report)
+}
+
+/// Deletes the emails involving `address`, that the file destination of `mapping` stores in separate files, together
+/// with their envelopes. The hashes of the stored attachments, that the deleted emails referred to, are added to
+/// `attachments`.
+fn forget_in_files(
+    config: &Config,
+    mapping: &Mapping,
+    address: &str,
+    message_ids: &mut Vec<String>,
+    attachments: &mut Vec<String>,
+    report: &mut Vec<String>,
+) -> Result<(), Error> {
+    let dir = match mapping.destination.storage_path() {
+        Some(dir) => dir,
+        None => return Ok(()),
+    };
+    for (_, _, path) in mapping_messages(mapping)?.unwrap_or_default() {
+        let envelope_path = sidecar_path(dir, &path, ENVELOPE_SUFFIX);
+        let envelope: Option<Value> = File::open(&envelope_path)
+            .ok()
+            .and_then(|file| serde_json::from_reader(file).ok());
+        let raw = read_stored(config, &path)?;
+        let email = Email::parse(&raw).ok();
+        let involved = envelope
+            .as_ref()
+            .is_some_and(|envelope| envelope_involves(envelope, address))
+            || email.as_ref().is_some_and(|email| email.involves(address));
+        if !involved {
+            continue;
+        }
+        if mapping.legal_hold || sidecar_path(dir, &path, HOLD_SUFFIX).exists() {
+            report.push(format!(
+                "Kept {} under legal hold (mapping '{}')",
+                path.display(),
+                mapping.name
+            ));
+            continue;
+        }
+        if let Some(ref store) = config.attachment_store {
+            attachments.extend(store.references(&fs::read(&path)?));
+        }
+        fs::remove_file(&path)?;
+        match fs::remove_file(&envelope_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        report.push(format!(
+            "Deleted {} (mapping '{}')",
+            path.display(),
+            mapping.name
+        ));
+        message_ids.extend(email.map(|email| email.message_id));
+    }
+    Ok(())
+}
+
+/// Deletes the stored attachments with the hashes `attachments`, that no email stored by a file destination refers to
+/// anymore.
+fn forget_attachments(
+    config: &Config,
+    mappings: &[&Arc<Mapping>],
+    mut attachments: Vec<String>,
+    report: &mut Vec<String>,
+) -> Result<(), Error> {
+    let store = match config.attachment_store {
+        Some(ref store) if !attachments.is_empty() => store,
+        _ => return Ok(()),
+    };
+    attachments.sort();
+    attachments.dedup();
+    for mapping in mappings
+        .iter()
+        .filter(|mapping| !mapping.destination.is_mbox())
+    {
+        for (_, _, path) in mapping_messages(mapping)?.unwrap_or_default() {
+            let referenced = store.references(&fs::read(&path)?);
+            attachments.retain(|hash| !referenced.contains(hash));
+        }
+    }
+    for hash in attachments {
+        if store.remove(&hash)? {
+            report.push(format!("Deleted stored attachment {}", hash));
+        }
+    }
+    Ok(())
+}
+
+/// Rewrites the mbox file of the destination of `mapping` without the emails involving `address`. The file is locked
+/// like for appending emails.
+fn forget_in_mbox(
+    mapping: &Mapping,
+    address: &str,
+    message_ids: &mut Vec<String>,
+    report: &mut Vec<String>,
+) -> Result<(), Error> {
+    let path = match mapping.destination.storage_path() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    file.lock_exclusive()?;
+    let res = (|| -> Result<Vec<String>, Error> {
+        let mut content = vec![];
+        file.read_to_end(&mut content)?;
+        let mut kept = Vec::with_capacity(content.len());
+        let mut forgotten = vec![];
+        for entry in mbox_entries(&content) {
+            let sender = entry
+                .split(|b| *b == b'\n')
+                .next()
+                .and_then(|line| line.split(|b| *b == b' ').nth(1))
+                .map(String::from_utf8_lossy)
+                .unwrap_or_default();
+            let message = split_mbox(entry).pop().unwrap_or_default();
+            match Email::parse(&message) {
+                Ok(email) if email.involves(address) || sender.eq_ignore_ascii_case(address) => {
+                    forgotten.push(email.message_id)
+                }
+                _ => kept.extend_from_slice(entry),
+            }
+        }
+        if !forgotten.is_empty() && !mapping.legal_hold {
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&kept)?;
+            file.sync_all()?;
+        }
+        Ok(forgotten)
+    })();
+    let _ = FileExt::unlock(&file);
+    let forgotten = res?;
+    if forgotten.is_empty() {
+        return Ok(());
+    }
+    if mapping.legal_hold {
+        report.push(format!(
+            "Kept {} emails in {} under legal hold (mapping '{}')",
+            forgotten.len(),
+            path.display(),
+            mapping.name
+        ));
+    } else {
+        report.push(format!(
+            "Deleted {} emails from {} (mapping '{}')",
+            forgotten.len(),
+            path.display(),
+            mapping.name
+        ));
+        message_ids.extend(forgotten);
+    }
+    Ok(())
+}
+
+/// Returns true, if `address` is the sender or a recipient in the envelope, that a file destination stored as JSON.
+fn envelope_involves(envelope: &Value, address: &str) -> bool {
+    std::iter::once(&envelope["mail_from"])
+        .chain(envelope["rcpt_to"].as_array().into_iter().flatten())
+        .filter_map(Value::as_str)
+        .any(|other| other.eq_ignore_ascii_case(address))
+}
+
+/// Splits the content of an mbox file into the unchanged entries, each starting with its "From " line. Anything before
+/// the first entry is returned as an entry of its own.
+fn mbox_entries(content: &[u8]) -> Vec<&[u8]> {
+    let mut entries = vec![];
+    let mut start = 0;
+    let mut pos = 0;
+    for line in content.split_inclusive(|b| *b == b'\n') {
+        if line.starts_with(b"From ") && pos > start {
+            entries.push(&content[start..pos]);
+            start = pos;
+        }
+        pos += line.len();
+    }
+    if pos > start {
+        entries.push(&content[start..pos]);
+    }
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachment_store::AttachmentStore;
+    use crate::maildest::{write_mbox_entry, FileDestination, FileFormat};
+
+    #[tokio::test]
+    async fn test_forget() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = dir.path().join("stored");
+        fs::create_dir(&stored).unwrap();
+        fs::write(
+            stored.join("a@example.com"),
+            "a@example.com\n\nFrom: Jane <Jane@example.org>\r\nMessage-ID: <a@example.com>\r\n\r\nHi\r\n",
+        )
+        .unwrap();
+        fs::write(
+            stored.join("a@example.com.envelope.json"),
+            r#"{"mail_from":"bounces@example.org","received_at":1646128800}"#,
+        )
+        .unwrap();
+        // Only the envelope names the address:
+        fs::write(
+            stored.join("b@example.com"),
+            "b@example.com\n\nFrom: list@example.org\r\nMessage-ID: <b@example.com>\r\n\r\nHi\r\n",
+        )
+        .unwrap();
+        fs::write(
+            stored.join("b@example.com.envelope.json"),
+            r#"{"mail_from":"list@example.org","rcpt_to":["jane@example.org"]}"#,
+        )
+        .unwrap();
+        fs::write(
+            stored.join("c@example.com"),
+            "c@example.com\n\nFrom: cron@example.org\r\nTo: jane@example.org\r\nMessage-ID: <c@example.com>\r\n\r\nHi\r\n",
+        )
+        .unwrap();
+        fs::write(stored.join("c@example.com.hold"), "").unwrap();
+        fs::write(
+            stored.join("d@example.com"),
+            "d@example.com\n\nFrom: cron@example.org\r\nMessage-ID: <d@example.com>\r\n\r\nHi\r\n",
+        )
+        .unwrap();
+
+        let mbox_path = dir.path().join("archive.mbox");
+        let mut mbox = vec![];
+        write_mbox_entry(
+            &mut mbox,
+            "jane@example.org",
+            1646128800,
+            b"From: jane@example.org\r\nMessage-ID: <e@example.com>\r\n\r\nFrom now on\r\n",
+        )
+        .unwrap();
+        let other = b"From: cron@example.org\r\nMessage-ID: <f@example.com>\r\n\r\nHi\r\n";
+        let mut kept = vec![];
+        write_mbox_entry(&mut kept, "cron@example.org", 1646128800, other).unwrap();
+        mbox.extend_from_slice(&kept);
+        fs::write(&mbox_path, &mbox).unwrap();
+
+        let audit_path = dir.path().join("audit.log");
+        let mut config = Config::default();
+        config.audit_log = Some(audit_path.clone());
+        let audit_log = config.open_audit_log().unwrap().unwrap();
+        audit_log.delivered("a@example.com", "files", None).unwrap();
+        audit_log.delivered("d@example.com", "files", None).unwrap();
+        config
+            .dest_map
+            .insert(
+                ["*@example.com"],
+                Arc::new(Mapping::new(
+                    "files",
+                    Box::new(FileDestination::new(&stored).unwrap()),
+                )),
+            )
+            .unwrap();
+        config
+            .dest_map
+            .insert(
+                ["archive@example.com"],
+                Arc::new(Mapping::new(
+                    "archive",
+                    Box::new(FileDestination::with_format(&mbox_path, FileFormat::Mbox).unwrap()),
+                )),
+            )
+            .unwrap();
+
+        let report = forget(&config, "jane@example.org").await.unwrap();
+        assert_eq!(report.len(), 5);
+        assert!(report[0].starts_with("Deleted 1 emails from "));
+        assert!(report[4].starts_with("Redacted 1 entries of audit log "));
+        assert!(!stored.join("a@example.com").exists());
+        assert!(!stored.join("a@example.com.envelope.json").exists());
+        assert!(!stored.join("b@example.com").exists());
+        assert!(stored.join("c@example.com").exists());
+        assert!(stored.join("d@example.com").exists());
+        assert_eq!(fs::read(&mbox_path).unwrap(), kept);
+        let audit = fs::read_to_string(&audit_path).unwrap();
+        assert!(!audit.contains("a@example.com"));
+        assert!(audit.contains("d@example.com"));
+
+        assert!(forget(&config, "jane@example.org")
+            .await
+            .unwrap()
+            .iter()
+            .all(|line| line.starts_with("Kept ")));
+        assert!(forget(&config, "jane").await.is_err());
+    }
+
+    /// Returns an email from `from` with the given attachments.
+    fn with_attachments(message_id: &str, from: &str, attachments: &[&str]) -> Vec<u8> {
+        let mut raw = format!(
+            "From: {}\r\nMessage-ID: <{}>\r\nContent-Type: multipart/mixed; boundary=b\r\n\r\n\
+--b\r\nContent-Type: text/plain\r\n\r\nHi\r\n",
+            from, message_id
+        );
+        for attachment in attachments {
+            raw.push_str(&format!(
+                "--b\r\nContent-Type: application/octet-stream\r\n\r\n{}\r\n",
+                attachment.repeat(20)
+            ));
+        }
+        raw.push_str("--b--\r\n");
+        raw.into_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_forget_attachments() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = dir.path().join("stored");
+        fs::create_dir(&stored).unwrap();
+        let store = Arc::new(AttachmentStore::open(dir.path().join("attachments"), 100).unwrap());
+        let mut config = Config::default();
+        config.attachment_store = Some(store.clone());
+        config
+            .dest_map
+            .insert(
+                ["*@example.com"],
+                Arc::new(Mapping::new(
+                    "files",
+                    Box::new(FileDestination::new(&stored).unwrap()),
+                )),
+            )
+            .unwrap();
+        let mut hashes = vec![];
+        for (id, from, attachments) in [
+            (
+                "a@example.com",
+                "jane@example.org",
+                &["only Jane", "shared"][..],
+            ),
+            ("b@example.com", "cron@example.org", &["shared"][..]),
+        ] {
+            let externalized = store
+                .externalize(&with_attachments(id, from, attachments))
+                .unwrap();
+            hashes.push(store.references(&externalized));
+            let mut content = format!("{}\n\n", id).into_bytes();
+            content.extend(externalized);
+            fs::write(stored.join(id), content).unwrap();
+        }
+
+        let report = forget(&config, "jane@example.org").await.unwrap();
+        assert_eq!(
+            report[1..],
+            [format!("Deleted stored attachment {}", hashes[0][0])]
+        );
+        assert_eq!(hashes[0][1], hashes[1][0]);
+        let restored = read_stored(&config, &stored.join("b@example.com")).unwrap();
+        assert_eq!(
+            restored,
+            with_attachments("b@example.com", "cron@example.org", &["shared"])
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use log::{error, warn};
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use crate::audit::AuditLog;
+use crate::config::Config;
+use crate::email::Email;
+use crate::mapping::Mapping;
+use crate::Error;
+
+/// Delivers all messages of an mbox file or a maildir, e.g. an old mail drop, that is migrated to kutsche.
+///
+/// A message is delivered to the mapping named `mapping_name`, if one is given, and otherwise to the mappings of
+/// `recipients` or, without explicit recipients, of the addresses in its To and Cc headers.
+pub(crate) async fn run(
+    config: &Config,
+    source: &str,
+    mapping_name: Option<&str>,
+    recipients: Vec<String>,
+) -> ExitCode {
+    let mapping = match mapping_name {
+        Some(name) => match config.mapping(name) {
+            Some(mapping) => Some(mapping),
+            None => {
+                eprintln!("There is no mapping '{}'.", name);
+                return ExitCode::FAILURE;
+            }
+        },
+        None => None,
+    };
+    let messages = match read_messages(Path::new(source)) {
+        Ok(messages) => messages,
+        Err(e) => {
+            eprintln!("Error while reading messages from {}: {}", source, &e);
+            error!("Could not read messages from {}: {}", source, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let audit_log = match config.open_audit_log() {
+        Ok(audit_log) => audit_log,
+        Err(e) => {
+            eprintln!("Error while opening audit log: {}", &e);
+            error!("Could not open audit log: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut imported = 0;
+    for (i, raw) in messages.iter().enumerate() {
+        let email = match Email::parse(raw) {
+            Ok(email) => email,
+            Err(e) => {
+                eprintln!(
+                    "Error while parsing message {} of {}: {}",
+                    i + 1,
+                    source,
+                    &e
+                );
+                error!("Could not parse message {} of {}: {}", i + 1, source, e);
+                continue;
+            }
+        };
+        let delivered = match mapping {
+            Some(mapping) => deliver(&email, mapping, audit_log.as_ref()).await,
+            None => {
+                let recipients = if recipients.is_empty() {
+                    email.header_recipients()
+                } else {
+                    recipients.clone()
+                };
+                route(config, &email, &recipients, audit_log.as_ref()).await
+            }
+        };
+        if delivered {
+            imported += 1;
+        }
+    }
+
+    println!("Imported {} of {} messages.", imported, messages.len());
+    if imported == messages.len() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+/// Delivers the email to the mappings of all recipients. Returns true, if all deliveries succeeded.
+async fn route(
+    config: &Config,
+    email: &Email<'_>,
+    recipients: &[String],
+    audit_log: Option<&AuditLog>,
+) -> bool {
+    let mut delivered = !recipients.is_empty();
+    for recipient in recipients.iter() {
+        match config.route_email(recipient, email) {
+            Some((_, mapping)) => delivered &= deliver(email, mapping, audit_log).await,
+            None => {
+                warn!("No destination mapping for {}.", recipient);
+                println!("{}: {}: no mapping", email.message_id, recipient);
+                delivered = false;
+            }
+        }
+    }
+    delivered
+}
+
+/// Delivers the email to a mapping. Returns true, if the delivery succeeded.
+async fn deliver(email: &Email<'_>, mapping: &Mapping, audit_log: Option<&AuditLog>) -> bool {
+    match mapping.deliver(email, audit_log).await {
+        Ok(()) => {
+            println!(
+                "{}: delivered to mapping '{}'",
+                email.message_id, mapping.name
+            );
+            true
+        }
+        Err(e) => {
+            eprintln!("Error while importing email {}: {}", email.message_id, &e);
+            error!("Could not import email {}: {}", email.message_id, e);
+            false
+        }
+    }
+}
+
+/// Reads the messages of a maildir, if `source` is a directory, or of an mbox file otherwise. The messages are returned
+/// with CRLF line endings, like they are received over SMTP.
+fn read_messages(source: &Path) -> Result<Vec<Vec<u8>>, Error> {
+    if source.is_dir() {
+        let mut paths = vec![];
+        for subdir in ["cur", "new"] {
+            let dir = source.join(subdir);
+            if !dir.is_dir() {
+                return Err(Error::SysIo(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!(
+                        "{} is not a maildir (missing {}).",
+                        source.display(),
+                        subdir
+                    ),
+                )));
+            }
+            for entry in fs::read_dir(dir)? {
+                paths.push(entry?.path());
+            }
+        }
+        // The file names of a maildir start with the time of delivery:
+        paths.sort_by_key(|path: &PathBuf| path.file_name().map(|name| name.to_os_string()));
+        paths
+            .iter()
+            .filter(|path| path.is_file())
+            .map(|path| Ok(to_crlf(&fs::read(path)?)))
+            .collect()
+    } else {
+        Ok(split_mbox(&fs::read(source)?))
+    }
+}
+
+/// Splits the content of an mbox file into messages and removes the quoting of "From " lines of the mboxrd format.
+pub(super) fn split_mbox(content: &[u8]) -> Vec<Vec<u8>> {
+    let mut messages: Vec<Vec<u8>> = vec![];
+    for line in content.split_inclusive(|b| *b == b'\n') {
+        if line.starts_with(b"From ") {
+            messages.push(vec![]);
+            continue;
+        }
+        let message = match messages.last_mut() {
+            Some(message) => message,
+            // Ignore anything before the first message:
+            None => continue,
+        };
+        let line = line.strip_suffix(b"\n").unwrap_or(line);
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let quotes = line.iter().take_while(|b| **b == b'>').count();
+        if quotes > 0 && line[quotes..].starts_with(b"From ") {
+            message.extend_from_slice(&line[1..]);
+        } else {
+            message.extend_from_slice(line);
+        }
+        message.extend_from_slice(b"\r\n");
+    }
+    // Remove the empty line, that separates a message from the next one:
+    for message in messages.iter_mut() {
+        if message.ends_with(b"\r\n\r\n") {
+            message.truncate(message.len() - 2);
+        }
+    }
+    messages
+}
+
+/// Returns `raw` with all line endings converted to CRLF.
+fn to_crlf(raw: &[u8]) -> Vec<u8> {
+    let mut converted = Vec::with_capacity(raw.len());
+    for (i, b) in raw.iter().enumerate() {
+        if *b == b'\n' && (i == 0 || raw[i - 1] != b'\r') {
+            converted.push(b'\r');
+        }
+        converted.push(*b);
+    }
+    converted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maildest::FileDestination;
+
+    use std::sync::Arc;
+
+    const MBOX: &[u8] = b"From sender@example.com Tue Mar  1 10:00:00 2022\n\
+To: root@example.com\n\
+Message-ID: <first@example.com>\n\
+\n\
+>From here on\n\
+\n\
+From MAILER-DAEMON Tue Mar  1 11:00:00 2022\n\
+To: other@example.org\n\
+Message-ID: <second@example.com>\n\
+\n\
+Second\n\
+\n";
+
+    #[test]
+    fn test_split_mbox() {
+        assert_eq!(
+            split_mbox(MBOX),
+            vec![
+                b"To: root@example.com\r\nMessage-ID: <first@example.com>\r\n\r\nFrom here on\r\n"
+                    .to_vec(),
+                b"To: other@example.org\r\nMessage-ID: <second@example.com>\r\n\r\nSecond\r\n"
+                    .to_vec(),
+            ]
+        );
+        assert!(split_mbox(b"").is_empty());
+    }
+
+    #[test]
+    fn test_read_maildir() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(read_messages(dir.path()).is_err());
+        fs::create_dir(dir.path().join("cur")).unwrap();
+        fs::create_dir(dir.path().join("new")).unwrap();
+        fs::write(dir.path().join("new/1646132400.2.host"), "Subject: B\n").unwrap();
+        fs::write(
+            dir.path().join("cur/1646128800.1.host:2,S"),
+            "Subject: A\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            read_messages(dir.path()).unwrap(),
+            vec![b"Subject: A\r\n".to_vec(), b"Subject: B\r\n".to_vec()]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_import() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = dir.path().join("stored");
+        fs::create_dir(&stored).unwrap();
+        let mbox = dir.path().join("old.mbox");
+        fs::write(&mbox, MBOX).unwrap();
+        let mut config = Config::default();
+        config
+            .dest_map
+            .insert(
+                ["*@example.com"],
+                Arc::new(Mapping::new(
+                    "files",
+                    Box::new(FileDestination::new(&stored).unwrap()),
+                )),
+            )
+            .unwrap();
+
+        // The second message has no mapping:
+        let source = mbox.to_str().unwrap();
+        run(&config, source, None, vec![]).await;
+        assert!(stored.join("first@example.com").is_file());
+        assert!(!stored.join("second@example.com").is_file());
+
+        fs::remove_file(stored.join("first@example.com")).unwrap();
+        run(&config, source, Some("files"), vec![]).await;
+        assert!(stored.join("first@example.com").is_file());
+        assert!(stored.join("second@example.com").is_file());
+    }
+}
@@ -0,0 +1,248 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::process::ExitCode;
+
+use crate::config::migration::{format_string, CURRENT_VERSION};
+
+/// The values of a starter config. Values, that are not given on the command line, are asked for interactively.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct InitOptions {
+    pub(crate) listen: Option<String>,
+    pub(crate) address: Option<String>,
+    pub(crate) mail_dir: Option<String>,
+    pub(crate) matrix_address: Option<String>,
+    pub(crate) matrix_homeserver: Option<String>,
+    pub(crate) matrix_room_id: Option<String>,
+    pub(crate) matrix_username: Option<String>,
+    pub(crate) matrix_password: Option<String>,
+}
+
+/// The answers, from which the config is generated.
+struct Answers {
+    listen: String,
+    address: String,
+    mail_dir: String,
+    matrix: Option<MatrixAnswers>,
+}
+
+struct MatrixAnswers {
+    address: String,
+    homeserver: String,
+    room_id: String,
+    username: String,
+    password: String,
+}
+
+/// Writes a commented starter config to `output`. The file is only readable by its owner, because it may contain the
+/// Matrix password. Existing files are not overwritten.
+pub(crate) fn run(output: &str, options: InitOptions) -> ExitCode {
+    let stdin = io::stdin();
+    let answers = match ask_all(options, &mut stdin.lock(), &mut io::stderr()) {
+        Ok(answers) => answers,
+        Err(e) => {
+            eprintln!("Error while reading answers: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(output)
+        .and_then(|mut file| file.write_all(generate(&answers).as_bytes()));
+    match written {
+        Ok(()) => {
+            eprintln!("Wrote config file {}.", output);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error while writing config file {}: {}", output, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Asks for the values, that are missing in `options`. At the end of the input, defaults are used for the remaining
+/// values, so `kutsche init < /dev/null` generates a config without asking.
+fn ask_all(
+    options: InitOptions,
+    input: &mut impl BufRead,
+    prompt: &mut impl Write,
+) -> io::Result<Answers> {
+    let mut answer = |value: Option<String>, question: &str, default: &str| match value {
+        Some(value) => Ok(value),
+        None => ask(input, prompt, question, default),
+    };
+
+    let listen = answer(options.listen, "Address to listen on", "127.0.0.1:25")?;
+    let address = answer(
+        options.address,
+        "Address (or pattern) of emails to store in files",
+        "*",
+    )?;
+    let mail_dir = answer(
+        options.mail_dir,
+        "Directory to store emails in",
+        "/var/mail/kutsche",
+    )?;
+    let homeserver = answer(
+        options.matrix_homeserver,
+        "Matrix homeserver for notifications (empty to skip)",
+        "",
+    )?;
+    let matrix = if homeserver.is_empty() {
+        None
+    } else {
+        Some(MatrixAnswers {
+            address: answer(
+                options.matrix_address,
+                "Address (or pattern) of emails to send to Matrix",
+                "alerts@localhost",
+            )?,
+            homeserver,
+            room_id: answer(options.matrix_room_id, "Matrix room ID", "")?,
+            username: answer(options.matrix_username, "Matrix username", "")?,
+            password: answer(options.matrix_password, "Matrix password", "")?,
+        })
+    };
+
+    Ok(Answers {
+        listen,
+        address,
+        mail_dir,
+        matrix,
+    })
+}
+
+/// Asks a single question. An empty answer or the end of the input selects the default.
+fn ask(
+    input: &mut impl BufRead,
+    prompt: &mut impl Write,
+    question: &str,
+    default: &str,
+) -> io::Result<String> {
+    if default.is_empty() {
+        write!(prompt, "{}: ", question)?;
+    } else {
+        write!(prompt, "{} [{}]: ", question, default)?;
+    }
+    prompt.flush()?;
+
+    let mut answer = String::new();
+    input.read_line(&mut answer)?;
+    let answer = answer.trim();
+    Ok(if answer.is_empty() { default } else { answer }.to_string())
+}
+
+fn generate(answers: &Answers) -> String {
+    let mut config = format!(
+        r#"# Generated by `kutsche init`. The example config in the source distribution
+# describes all parameters.
+
+# The version of the config format.
+config_version = {version}
+# The directory, where emails for mappings without destination are stored.
+default_path = {mail_dir}
+
+# An address the server binds to to receive emails.
+[[listeners]]
+address = {listen}
+
+# Emails for these addresses are stored in files.
+[mappings.files]
+address = {address}
+destination = "files"
+
+[destinations.files]
+type = "file"
+path = {mail_dir}
+"#,
+        version = CURRENT_VERSION,
+        mail_dir = format_string(&answers.mail_dir),
+        listen = format_string(&answers.listen),
+        address = format_string(&answers.address),
+    );
+    if let Some(ref matrix) = answers.matrix {
+        config.push_str(&format!(
+            r#"
+# Emails for these addresses are sent to a Matrix room.
+[mappings.matrix]
+address = {address}
+destination = "matrix"
+
+[destinations.matrix]
+type = "matrix"
+homeserver = {homeserver}
+room_id = {room_id}
+username = {username}
+password = {password}
+# After the first login the session can be stored and reused:
+# session_file = "/var/lib/kutsche/matrix-session.json"
+"#,
+            address = format_string(&matrix.address),
+            homeserver = format_string(&matrix.homeserver),
+            room_id = format_string(&matrix.room_id),
+            username = format_string(&matrix.username),
+            password = format_string(&matrix.password),
+        ));
+    }
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{migration, schema};
+
+    fn parse(config: &str) -> toml::map::Map<String, toml::Value> {
+        toml::from_str(config).unwrap()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let answers = ask_all(InitOptions::default(), &mut &b""[..], &mut io::sink()).unwrap();
+        assert!(answers.matrix.is_none());
+
+        let config = parse(&generate(&answers));
+        assert_eq!(migration::version(&config).unwrap(), CURRENT_VERSION);
+        assert!(schema::unknown_fields(&config).is_empty());
+        assert_eq!(
+            config["listeners"][0]["address"].as_str(),
+            Some("127.0.0.1:25")
+        );
+        assert_eq!(config["mappings"]["files"]["address"].as_str(), Some("*"));
+        assert!(config.get("destinations").unwrap().get("matrix").is_none());
+    }
+
+    #[test]
+    fn test_matrix_answers() {
+        let options = InitOptions {
+            matrix_password: Some("se\"cret".to_string()),
+            ..InitOptions::default()
+        };
+        let mut input =
+            &b"0.0.0.0:25\n\n/srv/mail\nmatrix.example.com\n\n!room:example.com\nkutsche\n"[..];
+        let answers = ask_all(options, &mut input, &mut io::sink()).unwrap();
+
+        let config = parse(&generate(&answers));
+        assert!(schema::unknown_fields(&config).is_empty());
+        assert_eq!(
+            config["listeners"][0]["address"].as_str(),
+            Some("0.0.0.0:25")
+        );
+        assert_eq!(
+            config["destinations"]["files"]["path"].as_str(),
+            Some("/srv/mail")
+        );
+        let matrix = &config["destinations"]["matrix"];
+        assert_eq!(matrix["homeserver"].as_str(), Some("matrix.example.com"));
+        assert_eq!(matrix["room_id"].as_str(), Some("!room:example.com"));
+        assert_eq!(matrix["password"].as_str(), Some("se\"cret"));
+        assert_eq!(
+            config["mappings"]["matrix"]["address"].as_str(),
+            Some("alerts@localhost")
+        );
+    }
+}
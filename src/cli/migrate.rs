@@ -0,0 +1,64 @@
+use std::fs;
+use std::process::ExitCode;
+
+use crate::config::migration::{self, Layout, CURRENT_VERSION};
+use crate::Error;
+
+/// Rewrites the config file at `config_path` in the current format. Comments on their own lines are kept.
+///
+/// The result is written to `output` (which may be `config_path` itself) or to stdout, if no output path is given.
+pub(crate) fn run(config_path: &str, output: Option<&str>) -> ExitCode {
+    match migrate(config_path, output) {
+        Ok(Some(version)) => {
+            eprintln!(
+                "Migrated {} from format version {} to {}.",
+                config_path, version, CURRENT_VERSION
+            );
+            ExitCode::SUCCESS
+        }
+        Ok(None) => {
+            eprintln!(
+                "{} already uses format version {}.",
+                config_path, CURRENT_VERSION
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error while migrating config file {}: {}", config_path, &e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns the version of the original config or None, if it did not need to be migrated.
+fn migrate(config_path: &str, output: Option<&str>) -> Result<Option<i64>, Error> {
+    let source = fs::read_to_string(config_path)?;
+    let config: toml::map::Map<String, toml::Value> = toml::from_str(&source)
+        .map_err(|e| Error::config(format!("Could not parse config file: {}", e)))?;
+    let version = migration::version(&config)?;
+    if version >= CURRENT_VERSION {
+        return Ok(None);
+    }
+
+    let config = migration::upgrade(config)?;
+    let rendered = migration::render(&config, &Layout::parse(&source));
+    // Never write a config, that would be read differently:
+    let reparsed: toml::map::Map<String, toml::Value> = toml::from_str(&rendered)
+        .map_err(|e| Error::config(format!("Could not parse migrated config: {}", e)))?;
+    if reparsed != config {
+        return Err(Error::config(
+            "The migrated config does not match the original config.".to_string(),
+        ));
+    }
+
+    match output {
+        Some(path) => {
+            let permissions = fs::metadata(config_path)?.permissions();
+            fs::write(path, rendered)?;
+            // The config contains passwords, so the new file should be as protected as the old one:
+            fs::set_permissions(path, permissions)?;
+        }
+        None => print!("{}", rendered),
+    }
+    Ok(Some(version))
+}
@@ -0,0 +1,537 @@
+//! Parsing of the command line and the commands, that don't run the server.
+
+use crate::api_token::Scope;
+use crate::Error;
+use init::InitOptions;
+
+pub(crate) mod backup;
+pub(crate) mod ctl;
+pub(crate) mod export;
+pub(crate) mod forget;
+pub(crate) mod import;
+pub(crate) mod init;
+pub(crate) mod migrate;
+pub(crate) mod registration;
+pub(crate) mod replay;
+pub(crate) mod route;
+pub(crate) mod search;
+pub(crate) mod token;
+pub(crate) mod verify;
+
+const DEFAULT_CONFIG_PATH: &str = "/etc/kutsche.config";
+
+/// What the program should do.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Command {
+    /// Run the SMTP server. This is the default. With `--dry-startup`, the server starts up completely, but stops
+    /// before accepting connections.
+    Serve { dry_startup: bool },
+    /// Deliver a stored message again. The target is a path or a message ID.
+    Replay {
+        target: String,
+        recipients: Vec<String>,
+    },
+    /// Generate a starter config and write it to the given path or the config path.
+    Init {
+        output: Option<String>,
+        options: InitOptions,
+    },
+    /// Rewrite the config file in the current format and write it to the given path or stdout.
+    MigrateConfig { output: Option<String> },
+    /// Explain which mapping and destination an email for the address would be delivered to.
+    Route {
+        address: String,
+        from: Option<String>,
+    },
+    /// Print a config section for a new API token with the given name and scopes.
+    CreateToken { name: String, scopes: Vec<Scope> },
+    /// Print a Matrix application service registration with new tokens for the given sender, and the fields of the
+    /// Matrix destination, that use it.
+    CreateRegistration {
+        sender: String,
+        id: Option<String>,
+        user_prefix: Option<String>,
+    },
+    /// Write all emails stored by the file destination of a mapping to a new mbox file.
+    Export { mapping: String, output: String },
+    /// Deliver all messages of an mbox file or a maildir to the given mapping or to the mappings of their recipients.
+    Import {
+        source: String,
+        mapping: Option<String>,
+        recipients: Vec<String>,
+    },
+    /// Print the emails stored by file destinations, that contain all words of the query.
+    Search {
+        query: String,
+        mapping: Option<String>,
+    },
+    /// Send a command to the control socket of the running server, optionally after authenticating with a token.
+    Ctl {
+        command: String,
+        token: Option<String>,
+    },
+    /// Check the hash chain and the signatures of the audit log, optionally against a trusted base64 encoded public
+    /// key.
+    VerifyAuditLog { public_key: Option<String> },
+    /// Write the state directories to a tar archive, while the writes of the running server are blocked through the
+    /// control socket, optionally after authenticating with a token.
+    Backup {
+        archive: String,
+        token: Option<String>,
+    },
+    /// Restore the state directories from a tar archive, that was written by `backup`.
+    Restore { archive: String },
+    /// Delete the stored emails, index entries and audit log references involving the address.
+    Forget { address: String },
+}
+
+/// The parsed command line.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Args {
+    /// The path given with `-c`/`--config-file`.
+    pub(crate) config_path: Option<String>,
+    pub(crate) command: Command,
+}
+
+impl Args {
+    /// Parses the arguments without the name of the program.
+    ///
+    /// The option `-c`/`--config-file <path>` may appear anywhere. The first other argument selects the command.
+    pub(crate) fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let mut config_path = None;
+        let mut dry_startup = false;
+        let mut positional = vec![];
+        let mut options = vec![];
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-c" | "--config-file" => {
+                    config_path = Some(args.next().ok_or_else(|| {
+                        Error::config(format!("Missing argument: {} <config-path>", arg))
+                    })?);
+                }
+                "--dry-startup" => dry_startup = true,
+                _ if arg.starts_with("--") => {
+                    // All other options take a value:
+                    let value = args
+                        .next()
+                        .ok_or_else(|| Error::config(format!("Missing value for {}.", arg)))?;
+                    options.push((arg, value));
+                }
+                _ => positional.push(arg),
+            }
+        }
+
+        let mut positional = positional.into_iter();
+        let command = match positional.next().as_deref() {
+            None | Some("serve") => Command::Serve { dry_startup },
+            Some("replay") => Command::Replay {
+                target: positional.next().ok_or_else(|| {
+                    Error::config("Missing argument: replay <path-or-message-id>")
+                })?,
+                recipients: take_option(&mut options, "--to"),
+            },
+            Some("init") => Command::Init {
+                output: take_option(&mut options, "--output").pop(),
+                options: InitOptions {
+                    listen: take_option(&mut options, "--listen").pop(),
+                    address: take_option(&mut options, "--address").pop(),
+                    mail_dir: take_option(&mut options, "--mail-dir").pop(),
+                    matrix_address: take_option(&mut options, "--matrix-address").pop(),
+                    matrix_homeserver: take_option(&mut options, "--matrix-homeserver").pop(),
+                    matrix_room_id: take_option(&mut options, "--matrix-room-id").pop(),
+                    matrix_username: take_option(&mut options, "--matrix-username").pop(),
+                    matrix_password: take_option(&mut options, "--matrix-password").pop(),
+                },
+            },
+            Some("migrate-config") => Command::MigrateConfig {
+                output: take_option(&mut options, "--output").pop(),
+            },
+            Some("route") => Command::Route {
+                address: positional
+                    .next()
+                    .ok_or_else(|| Error::config("Missing argument: route <address>"))?,
+                from: take_option(&mut options, "--from").pop(),
+            },
+            Some("create-token") => Command::CreateToken {
+                name: positional
+                    .next()
+                    .ok_or_else(|| Error::config("Missing argument: create-token <name>"))?,
+                scopes: take_option(&mut options, "--scope")
+                    .iter()
+                    .map(|scope| scope.parse())
+                    .collect::<Result<_, _>>()?,
+            },
+            Some("create-registration") => Command::CreateRegistration {
+                sender: positional.next().ok_or_else(|| {
+                    Error::config("Missing argument: create-registration <sender-user-id>")
+                })?,
+                id: take_option(&mut options, "--id").pop(),
+                user_prefix: take_option(&mut options, "--user-prefix").pop(),
+            },
+            Some("export") => {
+                if let Some(format) = take_option(&mut options, "--format").pop() {
+                    if format != "mbox" {
+                        return Err(Error::config(format!(
+                            "Unknown export format '{}' (expected mbox).",
+                            format
+                        )));
+                    }
+                }
+                Command::Export {
+                    mapping: positional.next().ok_or_else(|| {
+                        Error::config("Missing argument: export <mapping> <output>")
+                    })?,
+                    output: positional.next().ok_or_else(|| {
+                        Error::config("Missing argument: export <mapping> <output>")
+                    })?,
+                }
+            }
+            Some("import") => Command::Import {
+                source: positional
+                    .next()
+                    .ok_or_else(|| Error::config("Missing argument: import <mbox-or-maildir>"))?,
+                mapping: take_option(&mut options, "--mapping").pop(),
+                recipients: take_option(&mut options, "--to"),
+            },
+            Some("search") => Command::Search {
+                query: positional
+                    .next()
+                    .ok_or_else(|| Error::config("Missing argument: search <query>"))?,
+                mapping: take_option(&mut options, "--mapping").pop(),
+            },
+            Some("ctl") => {
+                let command: Vec<String> = positional.by_ref().collect();
+                if command.is_empty() {
+                    return Err(Error::config("Missing argument: ctl <command>"));
+                }
+                Command::Ctl {
+                    command: command.join(" "),
+                    token: take_option(&mut options, "--token").pop(),
+                }
+            }
+            Some("verify-audit-log") => Command::VerifyAuditLog {
+                public_key: take_option(&mut options, "--public-key").pop(),
+            },
+            Some("backup") => Command::Backup {
+                archive: positional
+                    .next()
+                    .ok_or_else(|| Error::config("Missing argument: backup <archive>"))?,
+                token: take_option(&mut options, "--token").pop(),
+            },
+            Some("restore") => Command::Restore {
+                archive: positional
+                    .next()
+                    .ok_or_else(|| Error::config("Missing argument: restore <archive>"))?,
+            },
+            Some("forget") => Command::Forget {
+                address: positional
+                    .next()
+                    .ok_or_else(|| Error::config("Missing argument: forget <email-address>"))?,
+            },
+            Some(other) => return Err(Error::config(format!("Unknown command '{}'.", other))),
+        };
+        if let Some(arg) = positional.next() {
+            return Err(Error::config(format!("Unexpected argument '{}'.", arg)));
+        }
+        if let Some((option, _)) = options.first() {
+            return Err(Error::config(format!("Unknown option '{}'.", option)));
+        }
+        if dry_startup && !matches!(command, Command::Serve { .. }) {
+            return Err(Error::config("Unknown option '--dry-startup'."));
+        }
+
+        Ok(Args {
+            config_path,
+            command,
+        })
+    }
+
+    /// Returns the path of the config file, which is the default path, if none was given.
+    pub(crate) fn config_path(&self) -> &str {
+        self.config_path.as_deref().unwrap_or(DEFAULT_CONFIG_PATH)
+    }
+}
+
+/// Removes all occurences of `name` from `options` and returns their values.
+fn take_option(options: &mut Vec<(String, String)>, name: &str) -> Vec<String> {
+    let mut values = vec![];
+    options.retain(|(option, value)| {
+        if option == name {
+            values.push(value.clone());
+            false
+        } else {
+            true
+        }
+    });
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Args, Error> {
+        Args::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn test_default_command() {
+        assert_eq!(
+            parse(&[]).unwrap(),
+            Args {
+                config_path: None,
+                command: Command::Serve { dry_startup: false }
+            }
+        );
+        assert_eq!(
+            parse(&["serve", "--dry-startup"]).unwrap().command,
+            Command::Serve { dry_startup: true }
+        );
+        assert!(parse(&["route", "a@example.com", "--dry-startup"]).is_err());
+        assert_eq!(parse(&[]).unwrap().config_path(), DEFAULT_CONFIG_PATH);
+        assert_eq!(
+            parse(&["-c", "/tmp/kutsche.toml"]).unwrap().config_path(),
+            "/tmp/kutsche.toml"
+        );
+        assert!(parse(&["-c"]).is_err());
+    }
+
+    #[test]
+    fn test_replay() {
+        assert_eq!(
+            parse(&[
+                "replay",
+                "abc@example.com",
+                "--to",
+                "a@example.com",
+                "-c",
+                "k.toml"
+            ])
+            .unwrap(),
+            Args {
+                config_path: Some("k.toml".to_string()),
+                command: Command::Replay {
+                    target: "abc@example.com".to_string(),
+                    recipients: vec!["a@example.com".to_string()],
+                }
+            }
+        );
+        assert!(parse(&["replay"]).is_err());
+        assert!(parse(&["replay", "x", "--unknown", "y"]).is_err());
+        assert!(parse(&["frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn test_init() {
+        assert_eq!(
+            parse(&["init", "--listen", "[::1]:25", "--output", "k.toml"])
+                .unwrap()
+                .command,
+            Command::Init {
+                output: Some("k.toml".to_string()),
+                options: InitOptions {
+                    listen: Some("[::1]:25".to_string()),
+                    ..InitOptions::default()
+                },
+            }
+        );
+        assert!(parse(&["init", "--matrix-server", "x"]).is_err());
+    }
+
+    #[test]
+    fn test_migrate_config() {
+        assert_eq!(
+            parse(&["migrate-config", "--output", "new.toml"])
+                .unwrap()
+                .command,
+            Command::MigrateConfig {
+                output: Some("new.toml".to_string())
+            }
+        );
+        assert_eq!(
+            parse(&["migrate-config"]).unwrap().command,
+            Command::MigrateConfig { output: None }
+        );
+    }
+
+    #[test]
+    fn test_route() {
+        assert_eq!(
+            parse(&["route", "root@example.com", "--from", "cron@example.org"])
+                .unwrap()
+                .command,
+            Command::Route {
+                address: "root@example.com".to_string(),
+                from: Some("cron@example.org".to_string()),
+            }
+        );
+        assert_eq!(
+            parse(&["route", "root@example.com"]).unwrap().command,
+            Command::Route {
+                address: "root@example.com".to_string(),
+                from: None,
+            }
+        );
+        assert!(parse(&["route"]).is_err());
+    }
+
+    #[test]
+    fn test_export() {
+        let export = Command::Export {
+            mapping: "alerts".to_string(),
+            output: "alerts.mbox".to_string(),
+        };
+        assert_eq!(
+            parse(&["export", "--format", "mbox", "alerts", "alerts.mbox"])
+                .unwrap()
+                .command,
+            export
+        );
+        assert_eq!(
+            parse(&["export", "alerts", "alerts.mbox"]).unwrap().command,
+            export
+        );
+        assert!(parse(&["export", "--format", "maildir", "alerts", "alerts.mbox"]).is_err());
+        assert!(parse(&["export", "alerts"]).is_err());
+    }
+
+    #[test]
+    fn test_import() {
+        assert_eq!(
+            parse(&["import", "old.mbox", "--mapping", "alerts"])
+                .unwrap()
+                .command,
+            Command::Import {
+                source: "old.mbox".to_string(),
+                mapping: Some("alerts".to_string()),
+                recipients: vec![],
+            }
+        );
+        assert_eq!(
+            parse(&["import", "Maildir", "--to", "root@example.com"])
+                .unwrap()
+                .command,
+            Command::Import {
+                source: "Maildir".to_string(),
+                mapping: None,
+                recipients: vec!["root@example.com".to_string()],
+            }
+        );
+        assert!(parse(&["import"]).is_err());
+    }
+
+    #[test]
+    fn test_search() {
+        assert_eq!(
+            parse(&["search", "invoice 2024", "--mapping", "files"])
+                .unwrap()
+                .command,
+            Command::Search {
+                query: "invoice 2024".to_string(),
+                mapping: Some("files".to_string()),
+            }
+        );
+        assert!(parse(&["search"]).is_err());
+    }
+
+    #[test]
+    fn test_ctl() {
+        assert_eq!(
+            parse(&["ctl", "pause", "127.0.0.1:25", "--token", "s3cr3t"])
+                .unwrap()
+                .command,
+            Command::Ctl {
+                command: "pause 127.0.0.1:25".to_string(),
+                token: Some("s3cr3t".to_string()),
+            }
+        );
+        assert!(parse(&["ctl"]).is_err());
+    }
+
+    #[test]
+    fn test_verify_audit_log() {
+        assert_eq!(
+            parse(&["verify-audit-log", "--public-key", "AAAA"])
+                .unwrap()
+                .command,
+            Command::VerifyAuditLog {
+                public_key: Some("AAAA".to_string()),
+            }
+        );
+        assert!(parse(&["verify-audit-log", "audit.log"]).is_err());
+    }
+
+    #[test]
+    fn test_create_token() {
+        assert_eq!(
+            parse(&[
+                "create-token",
+                "ci",
+                "--scope",
+                "metrics",
+                "--scope",
+                "queue"
+            ])
+            .unwrap()
+            .command,
+            Command::CreateToken {
+                name: "ci".to_string(),
+                scopes: vec![Scope::Metrics, Scope::Queue],
+            }
+        );
+        assert!(parse(&["create-token"]).is_err());
+        assert!(parse(&["create-token", "ci", "--scope", "admin"]).is_err());
+    }
+
+    #[test]
+    fn test_create_registration() {
+        assert_eq!(
+            parse(&[
+                "create-registration",
+                "@kutsche:example.org",
+                "--user-prefix",
+                "mail_"
+            ])
+            .unwrap()
+            .command,
+            Command::CreateRegistration {
+                sender: "@kutsche:example.org".to_string(),
+                id: None,
+                user_prefix: Some("mail_".to_string()),
+            }
+        );
+        assert!(parse(&["create-registration"]).is_err());
+    }
+
+    #[test]
+    fn test_backup_restore() {
+        assert_eq!(
+            parse(&["backup", "state.tar", "--token", "s3cr3t"])
+                .unwrap()
+                .command,
+            Command::Backup {
+                archive: "state.tar".to_string(),
+                token: Some("s3cr3t".to_string()),
+            }
+        );
+        assert_eq!(
+            parse(&["restore", "state.tar"]).unwrap().command,
+            Command::Restore {
+                archive: "state.tar".to_string(),
+            }
+        );
+        assert!(parse(&["backup"]).is_err());
+        assert!(parse(&["restore", "state.tar", "--token", "s3cr3t"]).is_err());
+    }
+
+    #[test]
+    fn test_forget() {
+        assert_eq!(
+            parse(&["forget", "jane@example.org"]).unwrap().command,
+            Command::Forget {
+                address: "jane@example.org".to_string(),
+            }
+        );
+        assert!(parse(&["forget"]).is_err());
+    }
+}
@@ -0,0 +1,66 @@
+use ruma::UserId;
+
+use std::process::ExitCode;
+
+use crate::api_token::generate_secret;
+use crate::maildest::{registration, DEFAULT_USER_PREFIX};
+use crate::Error;
+
+/// The ID of the application service, if none is given.
+const DEFAULT_ID: &str = "kutsche";
+
+/// Prints the registration of a Matrix application service with new random tokens, followed by the fields of a Matrix
+/// destination, that uses it, as comments.
+///
+/// The registration is added to the config of the homeserver and the fields to the config file by the administrator.
+pub(crate) fn run(sender: &str, id: Option<&str>, user_prefix: Option<&str>) -> ExitCode {
+    match output(
+        sender,
+        id.unwrap_or(DEFAULT_ID),
+        user_prefix.unwrap_or(DEFAULT_USER_PREFIX),
+    ) {
+        Ok(output) => {
+            print!("{}", output);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Could not create registration: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn output(sender: &str, id: &str, user_prefix: &str) -> Result<String, Error> {
+    let sender = UserId::parse(sender)
+        .map_err(|e| Error::config(format!("Could not parse Matrix user id: {}", e)))?;
+    let as_token = generate_secret()?;
+    let hs_token = generate_secret()?;
+    Ok(format!(
+        "{}\n# Fields of the Matrix destination:\n\
+# appservice_token = \"{}\"\n\
+# appservice_sender = \"{}\"\n\
+# appservice_user_prefix = \"{}\"\n",
+        registration(id, &as_token, &hs_token, &sender, user_prefix),
+        as_token,
+        sender,
+        user_prefix
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output() {
+        let output = output("@kutsche:example.org", DEFAULT_ID, DEFAULT_USER_PREFIX).unwrap();
+        let as_token = output
+            .lines()
+            .find_map(|line| line.strip_prefix("as_token: '"))
+            .unwrap()
+            .trim_end_matches('\'');
+        assert!(output.contains(&format!("# appservice_token = \"{}\"\n", as_token)));
+        assert!(output.contains("# appservice_sender = \"@kutsche:example.org\"\n"));
+        assert!(super::output("kutsche", DEFAULT_ID, DEFAULT_USER_PREFIX).is_err());
+    }
+}
@@ -0,0 +1,136 @@
+use log::warn;
+
+use std::fs;
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::config::Config;
+use crate::email::Email;
+use crate::report::report_error;
+use crate::Error;
+
+/// Delivers a stored message again to the mappings of its recipients, bypassing SMTP.
+///
+/// `target` is either the path of a stored message or a message ID, that is looked up in the directories of the file
+/// destinations. Without explicit recipients, the addresses in the To and Cc headers are used.
+pub(crate) async fn run(config: &Config, target: &str, recipients: Vec<String>) -> ExitCode {
+    let raw = match load_message(config, target) {
+        Ok(raw) => raw,
+        Err(e) => {
+            report_error!("Could not load message {}: {}", target, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let email = match Email::parse(&raw) {
+        Ok(email) => email,
+        Err(e) => {
+            report_error!("Could not parse message {}: {}", target, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let recipients = if recipients.is_empty() {
+        email.header_recipients()
+    } else {
+        recipients
+    };
+    let audit_log = match config.open_audit_log() {
+        Ok(audit_log) => audit_log,
+        Err(e) => {
+            report_error!("Could not open audit log: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut failed = false;
+    for recipient in recipients.iter() {
+        let mapping = match config.route_email(recipient, &email) {
+            Some((_, mapping)) => mapping,
+            None => {
+                warn!("No destination mapping for {}.", recipient);
+                println!("{}: no mapping", recipient);
+                failed = true;
+                continue;
+            }
+        };
+        match mapping.deliver(&email, audit_log.as_ref()).await {
+            Ok(()) => println!("{}: delivered to mapping '{}'", recipient, mapping.name),
+            Err(e) => {
+                report_error!("Could not replay email to {}: {}", recipient, e);
+                failed = true;
+            }
+        }
+    }
+
+    if failed || recipients.is_empty() {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Reads the raw message from the given path or from the file destination, that stored the message with the given ID.
+/// Emails in maildirs can only be replayed by their path, emails in mbox files not at all.
+fn load_message(config: &Config, target: &str) -> Result<Vec<u8>, Error> {
+    let path = Path::new(target);
+    let path = if path.is_file() {
+        path.to_path_buf()
+    } else {
+        config
+            .mappings()
+            .filter(|mapping| !mapping.destination.is_maildir() && !mapping.destination.is_mbox())
+            .filter_map(|mapping| mapping.destination.storage_path())
+            .map(|dir| dir.join(target))
+            .find(|path| path.is_file())
+            .ok_or_else(|| {
+                Error::SysIo(std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    "No such file and no stored message with this ID.",
+                ))
+            })?
+    };
+
+    read_stored(config, &path)
+}
+
+/// Reads an email stored by a file destination without the line with the message ID and with the attachments, that
+/// were moved to the attachment store.
+pub(super) fn read_stored(config: &Config, path: &Path) -> Result<Vec<u8>, Error> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let raw = strip_id_line(fs::read(path)?, &file_name);
+    match config.attachment_store {
+        Some(ref store) => store.restore(&raw),
+        None => Ok(raw),
+    }
+}
+
+/// Removes the line with the message ID, that file destinations write before the message.
+fn strip_id_line(mut content: Vec<u8>, message_id: &str) -> Vec<u8> {
+    let prefix = format!("{}\n\n", message_id);
+    if content.starts_with(prefix.as_bytes()) {
+        content.drain(..prefix.len());
+    }
+    content
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strip_id_line() {
+        assert_eq!(
+            strip_id_line(
+                b"id@example.com\n\nSubject: Hi\r\n".to_vec(),
+                "id@example.com"
+            ),
+            b"Subject: Hi\r\n"
+        );
+        assert_eq!(
+            strip_id_line(b"Subject: Hi\r\n".to_vec(), "id@example.com"),
+            b"Subject: Hi\r\n"
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use std::process::ExitCode;
+
+use crate::config::Config;
+
+/// Prints how an email for `address` would be routed without delivering anything.
+///
+/// The sender is only shown, because routing does not depend on it yet.
+pub(crate) fn run(config: &Config, address: &str, from: Option<&str>) -> ExitCode {
+    match explain(config, address, from) {
+        Some(explanation) => {
+            print!("{}", explanation);
+            ExitCode::SUCCESS
+        }
+        None => {
+            println!("{}: no mapping", address);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns a description of the routing decision for `address` or None, if no mapping matches.
+fn explain(config: &Config, address: &str, from: Option<&str>) -> Option<String> {
+    let (pattern, mapping) = config.route(address)?;
+    let mut explanation = format!("Recipient:   {}\n", address);
+    if let Some(from) = from {
+        explanation.push_str(&format!("Sender:      {}\n", from));
+    }
+    explanation.push_str(&format!("Matched:     {}\n", pattern));
+    explanation.push_str(&format!(
+        "Mapping:     {} (priority {}, {} delivery)\n",
+        mapping.name,
+        mapping.priority,
+        if mapping.synchronous_delivery {
+            "synchronous"
+        } else {
+            "queued"
+        }
+    ));
+    explanation.push_str(&format!(
+        "Destination: {}\n",
+        mapping.destination.describe()
+    ));
+    Some(explanation)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maildest::FileDestination;
+    use crate::mapping::Mapping;
+
+    use std::sync::Arc;
+
+    #[test]
+    fn test_explain() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = Config::default();
+        let mut mapping = Mapping::new(
+            "alerts",
+            Box::new(FileDestination::new(dir.path()).unwrap()),
+        );
+        mapping.priority = 3;
+        config
+            .dest_map
+            .insert(["*@example.com"], Arc::new(mapping))
+            .unwrap();
+
+        let explanation = explain(&config, "root@example.com", Some("cron@host")).unwrap();
+        assert!(explanation.contains("Sender:      cron@host\n"));
+        assert!(explanation.contains("Matched:     *@example.com\n"));
+        assert!(explanation.contains("Mapping:     alerts (priority 3, queued delivery)\n"));
+        assert!(explanation.contains(&format!("files in {}", dir.path().display())));
+        assert!(explain(&config, "root@example.org", None).is_none());
+    }
+}
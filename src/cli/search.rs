@@ -0,0 +1,667 @@
+use mail_parser::{Addr, HeaderValue, Message};
+use serde_json::{json, Value};
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::UNIX_EPOCH;
+
+use super::export::mapping_messages;
+use super::import::split_mbox;
+use super::replay::read_stored;
+use crate::config::Config;
+use crate::email::{parse_asctime, rfc5322_date};
+use crate::mapping::Mapping;
+use crate::state_lock;
+use crate::Error;
+
+/// The file in the state directory, that contains the search index.
+const INDEX_FILE: &str = "search_index.json";
+
+/// An email, that matched a search.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Hit {
+    mapping: String,
+    received_at: u64,
+    sender: String,
+    subject: String,
+    path: PathBuf,
+}
+
+/// Prints the emails stored by file destinations, whose subject, senders or text body contain all words of `query`.
+///
+/// Only the mapping `mapping_name` is searched, if given. Otherwise all mappings with file destinations are searched.
+/// The words of the emails are kept in an index in the state directory (see `SearchIndex`), so only the emails, that
+/// were stored or changed since the last search, are read and parsed. Without a state directory, all emails are read
+/// on each search. Files, that can't be read, are skipped with a warning.
+pub(crate) fn run(config: &Config, query: &str, mapping_name: Option<&str>) -> ExitCode {
+    let mut skipped = vec![];
+    let res = search(config, query, mapping_name, &mut skipped);
+    for skipped in skipped.iter() {
+        eprintln!("Skipped {}", skipped);
+    }
+    match res {
+        Ok(hits) if hits.is_empty() => {
+            eprintln!("No emails match '{}'.", query);
+            ExitCode::FAILURE
+        }
+        Ok(hits) => {
+            for hit in hits.iter() {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    hit.mapping,
+                    rfc5322_date(hit.received_at),
+                    hit.sender,
+                    hit.subject,
+                    hit.path.display()
+                );
+            }
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Error while searching for '{}': {}", query, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Returns the matching emails, the most recent first. The files, that could not be read, are added to `skipped`
+/// together with the reason.
+fn search(
+    config: &Config,
+    query: &str,
+    mapping_name: Option<&str>,
+    skipped: &mut Vec<String>,
+) -> Result<Vec<Hit>, Error> {
+    let words: Vec<String> = query.split_whitespace().map(str::to_lowercase).collect();
+    if words.is_empty() {
+        return Err(Error::config("The search query is empty."));
+    }
+    let mappings = match mapping_name {
+        Some(name) => vec![config
+            .mapping(name)
+            .ok_or_else(|| Error::config(format!("There is no mapping '{}'.", name)))?],
+        None => config.mappings().collect(),
+    };
+
+    let index_path = config.state_dir.as_ref().map(|dir| dir.join(INDEX_FILE));
+    let mut index = match index_path {
+        Some(ref path) => SearchIndex::load(path).unwrap_or_else(|e| {
+            skipped.push(format!("search index {}: {}", path.display(), e));
+            SearchIndex::default()
+        }),
+        None => SearchIndex::default(),
+    };
+    let mut seen = HashSet::new();
+    let mut stamp = |path: &Path, skipped: &mut Vec<String>| match file_stamp(path) {
+        Ok(stamp) => {
+            seen.insert(path.to_path_buf());
+            Some(stamp)
+        }
+        Err(e) => {
+            if e.kind() != ErrorKind::NotFound {
+                skipped.push(format!("{}: {}", path.display(), e));
+            }
+            None
+        }
+    };
+    for mapping in mappings.iter() {
+        if mapping.destination.is_mbox() {
+            let path = match mapping.destination.storage_path() {
+                Some(path) => path,
+                None => continue,
+            };
+            let stamp = match stamp(path, skipped) {
+                Some(stamp) if !index.is_current(path, stamp) => stamp,
+                _ => continue,
+            };
+            index.remove(path);
+            for (received_at, sender, raw) in mbox_messages(path, skipped) {
+                index.add(stamp, mapping, received_at, sender, &raw, path);
+            }
+            continue;
+        }
+        let messages = match mapping_messages(mapping)? {
+            Some(messages) => messages,
+            None if mapping_name.is_some() => {
+                return Err(Error::config(format!(
+                    "The destination of mapping '{}' does not store emails in files.",
+                    mapping.name
+                )))
+            }
+            None => continue,
+        };
+        for (received_at, sender, path) in messages {
+            let stamp = match stamp(&path, skipped) {
+                Some(stamp) if !index.is_current(&path, stamp) => stamp,
+                _ => continue,
+            };
+            index.remove(&path);
+            match read_stored(config, &path) {
+                Ok(raw) => index.add(stamp, mapping, received_at, sender, &raw, &path),
+                Err(e) => skipped.push(format!("{}: {}", path.display(), e)),
+            }
+        }
+    }
+    // Emails, that were deleted, are removed from the index, but only for the searched mappings:
+    let searched: HashSet<&str> = mappings
+        .iter()
+        .map(|mapping| mapping.name.as_str())
+        .collect();
+    index.retain(|hit| seen.contains(&hit.path) || !searched.contains(hit.mapping.as_str()));
+
+    let mut hits: Vec<Hit> = index
+        .matching(&words)
+        .into_iter()
+        .filter(|hit| searched.contains(hit.mapping.as_str()))
+        .collect();
+    hits.sort_by_key(|hit| Reverse(hit.received_at));
+    if let (Some(path), true) = (index_path, index.changed) {
+        index.save(&path)?;
+    }
+    Ok(hits)
+}
+
+/// Returns the modification time in nanoseconds since the unix epoch and the size of the file at `path`, which tell
+/// whether it changed since it was indexed.
+fn file_stamp(path: &Path) -> std::io::Result<(u64, u64)> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as u64);
+    Ok((modified, metadata.len()))
+}
+
+/// An email in the search index.
+struct Document {
+    /// The modification time and size of the file, when it was indexed (see `file_stamp()`).
+    stamp: (u64, u64),
+    hit: Hit,
+}
+
+/// An inverted index of the words of the stored emails, that is kept in the state directory between searches.
+///
+/// The words are the whitespace separated parts of the lower-case subject, senders and text body (see
+/// `searchable_text()`). A query word matches every indexed word, that contains it, so the index finds the same
+/// emails as reading all of them. An email is read again, when its file changed; all emails of an mbox file are read
+/// again, when it changed. Removed documents are only dropped from the lists of words, when the index is saved.
+#[derive(Default)]
+struct SearchIndex {
+    documents: Vec<Option<Document>>,
+    /// The IDs of the documents (their positions in `documents`), that contain a word.
+    words: BTreeMap<String, Vec<usize>>,
+    /// The IDs of the documents stored in a file.
+    by_path: HashMap<PathBuf, Vec<usize>>,
+    /// Whether the index changed since it was loaded, so it has to be saved.
+    changed: bool,
+}
+
+impl SearchIndex {
+    /// Reads the index from the file at `path`. A missing file is read as an empty index.
+    fn load(path: &Path) -> Result<Self, Error> {
+        let content = match fs::read(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(SearchIndex::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let damaged = || Error::config("The search index is damaged and is rebuilt.");
+        let content: Value = serde_json::from_slice(&content).map_err(|_| damaged())?;
+        let mut index = SearchIndex::default();
+        for document in content["documents"].as_array().ok_or_else(damaged)? {
+            let stamp = (
+                document["modified"].as_u64().ok_or_else(damaged)?,
+                document["len"].as_u64().ok_or_else(damaged)?,
+            );
+            let hit = Hit {
+                mapping: document["mapping"]
+                    .as_str()
+                    .ok_or_else(damaged)?
+                    .to_string(),
+                received_at: document["received_at"].as_u64().ok_or_else(damaged)?,
+                sender: document["sender"].as_str().ok_or_else(damaged)?.to_string(),
+                subject: document["subject"]
+                    .as_str()
+                    .ok_or_else(damaged)?
+                    .to_string(),
+                path: PathBuf::from(document["path"].as_str().ok_or_else(damaged)?),
+            };
+            index.insert(stamp, hit);
+        }
+        for (word, ids) in content["words"].as_object().ok_or_else(damaged)? {
+            let ids = ids
+                .as_array()
+                .ok_or_else(damaged)?
+                .iter()
+                .map(|id| id.as_u64().map(|id| id as usize))
+                .collect::<Option<Vec<_>>>()
+                .filter(|ids| ids.iter().all(|id| *id < index.documents.len()))
+                .ok_or_else(damaged)?;
+            index.words.insert(word.clone(), ids);
+        }
+        Ok(index)
+    }
+
+    /// Writes the index to the file at `path` without the removed documents.
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        // The remaining documents get new, consecutive IDs:
+        let mut new_ids = vec![None; self.documents.len()];
+        let mut documents = vec![];
+        for (id, document) in self.documents.iter().enumerate() {
+            if let Some(document) = document {
+                new_ids[id] = Some(documents.len());
+                documents.push(json!({
+                    "path": document.hit.path.to_string_lossy(),
+                    "modified": document.stamp.0,
+                    "len": document.stamp.1,
+                    "mapping": document.hit.mapping,
+                    "received_at": document.hit.received_at,
+                    "sender": document.hit.sender,
+                    "subject": document.hit.subject,
+                }));
+            }
+        }
+        let words: serde_json::Map<String, Value> = self
+            .words
+            .iter()
+            .filter_map(|(word, ids)| {
+                let ids: Vec<usize> = ids.iter().filter_map(|id| new_ids[*id]).collect();
+                (!ids.is_empty()).then(|| (word.clone(), json!(ids)))
+            })
+            .collect();
+        let content = json!({ "documents": documents, "words": words });
+        let _writing = state_lock::writing();
+        let mut tmp_path = path.to_path_buf().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, content.to_string())?;
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    /// Returns true, if all documents of the file at `path` were indexed, when it had the modification time and size
+    /// `stamp`.
+    fn is_current(&self, path: &Path, stamp: (u64, u64)) -> bool {
+        self.by_path.get(path).is_some_and(|ids| {
+            ids.iter().all(
+                |id| matches!(self.documents[*id], Some(ref document) if document.stamp == stamp),
+            )
+        })
+    }
+
+    fn insert(&mut self, stamp: (u64, u64), hit: Hit) -> usize {
+        let id = self.documents.len();
+        self.by_path.entry(hit.path.clone()).or_default().push(id);
+        self.documents.push(Some(Document { stamp, hit }));
+        id
+    }
+
+    /// Adds the email `raw`, that `mapping` stored in the file at `path`. Emails, that can't be parsed, are added
+    /// without words, so they are not parsed again, until the file changes.
+    fn add(
+        &mut self,
+        stamp: (u64, u64),
+        mapping: &Mapping,
+        received_at: u64,
+        sender: String,
+        raw: &[u8],
+        path: &Path,
+    ) {
+        let message = Message::parse(raw);
+        let words: BTreeSet<String> = message
+            .as_ref()
+            .map(|message| {
+                searchable_text(message, &sender)
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default();
+        let subject = message
+            .as_ref()
+            .and_then(|message| message.get_subject())
+            .unwrap_or_default()
+            .to_string();
+        let id = self.insert(
+            stamp,
+            Hit {
+                mapping: mapping.name.clone(),
+                received_at,
+                sender,
+                subject,
+                path: path.to_path_buf(),
+            },
+        );
+        for word in words {
+            self.words.entry(word).or_default().push(id);
+        }
+        self.changed = true;
+    }
+
+    /// Removes the documents of the file at `path`.
+    fn remove(&mut self, path: &Path) {
+        for id in self.by_path.remove(path).unwrap_or_default() {
+            self.documents[id] = None;
+            self.changed = true;
+        }
+    }
+
+    /// Removes the documents, for which `f` returns false.
+    fn retain(&mut self, mut f: impl FnMut(&Hit) -> bool) {
+        let removed: Vec<PathBuf> = self
+            .by_path
+            .keys()
+            .filter(|path| {
+                self.by_path[*path].iter().any(
+                    |id| matches!(self.documents[*id], Some(ref document) if !f(&document.hit)),
+                )
+            })
+            .cloned()
+            .collect();
+        for path in removed {
+            self.remove(&path);
+        }
+    }
+
+    /// Returns the documents, that contain all `words`.
+    fn matching(&self, words: &[String]) -> Vec<Hit> {
+        let mut matching: Option<BTreeSet<usize>> = None;
+        for word in words {
+            let ids: BTreeSet<usize> = self
+                .words
+                .iter()
+                .filter(|(indexed, _)| indexed.contains(word.as_str()))
+                .flat_map(|(_, ids)| ids.iter().copied())
+                .collect();
+            matching = Some(match matching {
+                Some(matching) => matching.intersection(&ids).copied().collect(),
+                None => ids,
+            });
+        }
+        matching
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|id| {
+                self.documents[id]
+                    .as_ref()
+                    .map(|document| document.hit.clone())
+            })
+            .collect()
+    }
+}
+
+/// Returns the emails in the mbox file at `path` together with the time and the sender of their "From " lines. The
+/// file is added to `skipped`, if it can't be read.
+fn mbox_messages(path: &Path, skipped: &mut Vec<String>) -> Vec<(u64, String, Vec<u8>)> {
+    let content = match fs::read(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return vec![],
+        Err(e) => {
+            skipped.push(format!("{}: {}", path.display(), e));
+            return vec![];
+        }
+    };
+    content
+        .split(|b| *b == b'\n')
+        .filter(|line| line.starts_with(b"From "))
+        .map(|line| String::from_utf8_lossy(&line[b"From ".len()..]).into_owned())
+        .zip(split_mbox(&content))
+        .map(|(from_line, message)| {
+            let (sender, date) = from_line.trim_end().split_once(' ').unwrap_or_default();
+            (
+                parse_asctime(date).unwrap_or(0),
+                sender.to_string(),
+                message,
+            )
+        })
+        .collect()
+}
+
+/// Returns the subject, the senders (envelope sender and From header) and the text body of `message` in lower case.
+fn searchable_text(message: &Message<'_>, envelope_sender: &str) -> String {
+    let mut text = String::from(envelope_sender);
+    text.push('\n');
+    text.push_str(message.get_subject().unwrap_or_default());
+    let mut push_addr = |addr: &Addr<'_>| {
+        for part in [&addr.name, &addr.address].into_iter().flatten() {
+            text.push('\n');
+            text.push_str(part);
+        }
+    };
+    match message.get_from() {
+        HeaderValue::Address(addr) => push_addr(addr),
+        HeaderValue::AddressList(list) => list.iter().for_each(&mut push_addr),
+        _ => {}
+    }
+    for part in message.get_text_bodies() {
+        text.push('\n');
+        text.push_str(part.get_text_contents());
+    }
+    text.to_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attachment_store::AttachmentStore;
+    use crate::maildest::{write_mbox_entry, FileDestination, FileFormat};
+
+    use std::sync::Arc;
+
+    #[test]
+    fn test_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = dir.path().join("stored");
+        fs::create_dir(&stored).unwrap();
+        fs::write(
+            stored.join("a@example.com"),
+            "a@example.com\n\nFrom: Billing <billing@example.org>\r\nSubject: Invoice 2024-03\r\n\
+Message-ID: <a@example.com>\r\n\r\nPlease pay.\r\n",
+        )
+        .unwrap();
+        fs::write(
+            stored.join("a@example.com.envelope.json"),
+            r#"{"mail_from":"bounces@example.org","received_at":1646128800}"#,
+        )
+        .unwrap();
+        fs::write(
+            stored.join("b@example.com"),
+            "b@example.com\n\nFrom: cron@example.org\r\nSubject: Backup done\r\n\
+Message-ID: <b@example.com>\r\n\r\nThe invoice archive was saved.\r\n",
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config
+            .dest_map
+            .insert(
+                ["*@example.com"],
+                Arc::new(Mapping::new(
+                    "files",
+                    Box::new(FileDestination::new(&stored).unwrap()),
+                )),
+            )
+            .unwrap();
+
+        let mut skipped = vec![];
+        // Words are matched case-insensitively in the subject, the senders and the body:
+        let hits = search(&config, "INVOICE 2024", None, &mut skipped).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].subject, "Invoice 2024-03");
+        assert_eq!(hits[0].sender, "bounces@example.org");
+        assert_eq!(
+            search(&config, "invoice", Some("files"), &mut skipped)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            search(&config, "billing pay", None, &mut skipped)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(search(&config, "invoice 2023", None, &mut skipped)
+            .unwrap()
+            .is_empty());
+
+        assert!(search(&config, " ", None, &mut skipped).is_err());
+        assert!(search(&config, "invoice", Some("unknown"), &mut skipped).is_err());
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn test_search_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let stored = dir.path().join("stored");
+        fs::create_dir(&stored).unwrap();
+        let email = |subject: &str| {
+            format!(
+                "From: billing@example.org\r\nSubject: {}\r\n\r\nPlease pay.\r\n",
+                subject
+            )
+        };
+        fs::write(stored.join("a@example.com"), email("Invoice 2024-03")).unwrap();
+        fs::write(stored.join("b@example.com"), email("Invoice 2024-04")).unwrap();
+        let mut config = Config::default();
+        config.state_dir = Some(dir.path().to_path_buf());
+        config
+            .dest_map
+            .insert(
+                ["*@example.com"],
+                Arc::new(Mapping::new(
+                    "files",
+                    Box::new(FileDestination::new(&stored).unwrap()),
+                )),
+            )
+            .unwrap();
+
+        let mut skipped = vec![];
+        assert_eq!(
+            search(&config, "invoice", None, &mut skipped)
+                .unwrap()
+                .len(),
+            2
+        );
+        let index_path = dir.path().join(INDEX_FILE);
+        let index = SearchIndex::load(&index_path).unwrap();
+        assert_eq!(index.documents.len(), 2);
+        assert_eq!(index.words["please"].len(), 2);
+
+        // Unchanged emails are not read again:
+        let path = stored.join("a@example.com");
+        let modified = fs::metadata(&path).unwrap().modified().unwrap();
+        fs::write(&path, email("Receipt 2024-03")).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(modified)
+            .unwrap();
+        assert_eq!(
+            search(&config, "invoice", None, &mut skipped)
+                .unwrap()
+                .len(),
+            2
+        );
+
+        // Changed emails are indexed again and deleted ones are removed:
+        fs::write(&path, email("Receipt for 2024-03")).unwrap();
+        fs::remove_file(stored.join("b@example.com")).unwrap();
+        let hits = search(&config, "receipt", None, &mut skipped).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].subject, "Receipt for 2024-03");
+        assert!(search(&config, "invoice", None, &mut skipped)
+            .unwrap()
+            .is_empty());
+        assert_eq!(SearchIndex::load(&index_path).unwrap().documents.len(), 1);
+        assert!(skipped.is_empty());
+
+        // A damaged index is rebuilt:
+        fs::write(&index_path, "{").unwrap();
+        assert_eq!(
+            search(&config, "receipt", None, &mut skipped)
+                .unwrap()
+                .len(),
+            1
+        );
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(SearchIndex::load(&index_path).unwrap().documents.len(), 1);
+    }
+
+    #[test]
+    fn test_search_mbox_and_unreadable() {
+        let dir = tempfile::tempdir().unwrap();
+        let mbox_path = dir.path().join("archive.mbox");
+        let mut mbox = vec![];
+        write_mbox_entry(
+            &mut mbox,
+            "billing@example.org",
+            1646128800,
+            b"Subject: Invoice 2024-03\r\nMessage-ID: <a@example.com>\r\n\r\nFrom now on, please pay.\r\n",
+        )
+        .unwrap();
+        write_mbox_entry(
+            &mut mbox,
+            "cron@example.org",
+            1646132400,
+            b"Subject: Backup done\r\nMessage-ID: <b@example.com>\r\n\r\nThe invoice archive was saved.\r\n",
+        )
+        .unwrap();
+        fs::write(&mbox_path, mbox).unwrap();
+
+        // The stored attachment of this email is missing:
+        let stored = dir.path().join("stored");
+        fs::create_dir(&stored).unwrap();
+        let store = Arc::new(AttachmentStore::open(dir.path().join("attachments"), 10).unwrap());
+        let externalized = store
+            .externalize(
+                b"Subject: Invoice\r\nContent-Type: multipart/mixed; boundary=b\r\n\r\n--b\r\n\
+Content-Type: application/pdf\r\n\r\nJVBERi0xLjQKJcfsj6IK\r\n--b--\r\n",
+            )
+            .unwrap();
+        for hash in store.references(&externalized) {
+            store.remove(&hash).unwrap();
+        }
+        fs::write(stored.join("c@example.com"), externalized).unwrap();
+
+        let mut config = Config::default();
+        config.attachment_store = Some(store);
+        config
+            .dest_map
+            .insert(
+                ["archive@example.com"],
+                Arc::new(Mapping::new(
+                    "archive",
+                    Box::new(FileDestination::with_format(&mbox_path, FileFormat::Mbox).unwrap()),
+                )),
+            )
+            .unwrap();
+        config
+            .dest_map
+            .insert(
+                ["*@example.com"],
+                Arc::new(Mapping::new(
+                    "files",
+                    Box::new(FileDestination::new(&stored).unwrap()),
+                )),
+            )
+            .unwrap();
+
+        let mut skipped = vec![];
+        let hits = search(&config, "invoice", None, &mut skipped).unwrap();
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].subject, "Backup done");
+        assert_eq!(hits[0].received_at, 1646132400);
+        assert_eq!(hits[1].sender, "billing@example.org");
+        assert_eq!(hits[1].path, mbox_path);
+        assert_eq!(skipped.len(), 1);
+        assert!(skipped[0].contains("c@example.com"));
+
+        let mut skipped = vec![];
+        let hits = search(&config, "from now", Some("archive"), &mut skipped).unwrap();
+        assert_eq!(hits.len(), 1);
+        assert!(skipped.is_empty());
+    }
+}
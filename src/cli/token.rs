@@ -0,0 +1,49 @@
+use std::process::ExitCode;
+
+use crate::api_token::{generate_secret, Scope};
+use crate::config::migration::format_key;
+
+/// Prints a config section for a new API token with a random secret and the given scopes.
+///
+/// The config file is not changed, so the section can be reviewed and added by the administrator.
+pub(crate) fn run(name: &str, scopes: &[Scope]) -> ExitCode {
+    match generate_secret() {
+        Ok(secret) => {
+            print!("{}", section(name, &secret, scopes));
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("Could not generate token: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn section(name: &str, secret: &str, scopes: &[Scope]) -> String {
+    let scopes: Vec<String> = scopes
+        .iter()
+        .map(|scope| format!("\"{}\"", scope.name()))
+        .collect();
+    format!(
+        "[api_tokens.{}]\ntoken = \"{}\"\nscopes = [ {} ]\n",
+        format_key(name),
+        secret,
+        scopes.join(", ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_section() {
+        let section = section("ci bot", "abc", &[Scope::Metrics, Scope::Queue]);
+        assert_eq!(
+            section,
+            "[api_tokens.\"ci bot\"]\ntoken = \"abc\"\nscopes = [ \"metrics\", \"queue\" ]\n"
+        );
+        let parsed: toml::Value = toml::from_str(&section).unwrap();
+        assert!(parsed["api_tokens"]["ci bot"].is_table());
+    }
+}
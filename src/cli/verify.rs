@@ -0,0 +1,122 @@
+use std::fs;
+use std::process::ExitCode;
+
+use crate::audit::{self, Verification, PUBLIC_KEY_FILE};
+use crate::config::Config;
+use crate::email::rfc3339_date;
+use crate::Error;
+
+/// Checks the hash chain and the signatures of the configured audit log and prints the result.
+///
+/// The signatures are verified with `public_key` (base64 encoded) or else with the public key in the state directory.
+/// Only if neither is available, the key of the first signature in the log is trusted.
+pub(crate) fn run(config: &Config, public_key: Option<&str>) -> ExitCode {
+    match verify(config, public_key) {
+        Ok((verification, trusted)) => {
+            print!("{}", describe(&verification, trusted));
+            let signed = verification.entries == 0 || verification.last_signature.is_some();
+            if verification.tampered.is_none() && signed {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::FAILURE
+            }
+        }
+        Err(e) => {
+            eprintln!("Error while verifying audit log: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Verifies the audit log and returns the result together with whether a trusted key was used.
+fn verify(config: &Config, public_key: Option<&str>) -> Result<(Verification, bool), Error> {
+    let path = config
+        .audit_log
+        .as_ref()
+        .ok_or_else(|| Error::config("No audit log is configured."))?;
+    let public_key = match public_key {
+        Some(key) => Some(key.to_string()),
+        None => config
+            .state_dir
+            .as_ref()
+            .and_then(|dir| fs::read_to_string(dir.join(PUBLIC_KEY_FILE)).ok()),
+    };
+    let public_key = public_key
+        .map(|key| {
+            base64::decode(key.trim())
+                .map_err(|_| Error::config("The public key is not valid base64."))
+        })
+        .transpose()?;
+    let verification = audit::verify(path, public_key.as_deref())?;
+    Ok((verification, public_key.is_some()))
+}
+
+/// Returns a human readable report of a verification.
+fn describe(verification: &Verification, trusted: bool) -> String {
+    let mut report = format!("Entries:        {}\n", verification.entries);
+    match verification.chain_start {
+        Some(1) => report.push_str("Hash chain:     all entries\n"),
+        Some(start) => report.push_str(&format!(
+            "Hash chain:     from entry {} (earlier entries are not protected)\n",
+            start
+        )),
+        None => report.push_str("Hash chain:     none (signing is not enabled)\n"),
+    }
+    match verification.last_signature {
+        Some((line, time)) => {
+            report.push_str(&format!(
+                "Last signature: entry {} at {}\n",
+                line,
+                rfc3339_date(time)
+            ));
+            let unsigned = verification.entries - line;
+            if unsigned > 0 && verification.tampered.is_none() {
+                report.push_str(&format!(
+                    "Unsigned:       {} entries after the last signature\n",
+                    unsigned
+                ));
+            }
+        }
+        None => report.push_str("Last signature: none\n"),
+    }
+    if !trusted && verification.last_signature.is_some() {
+        report.push_str(
+            "Warning:        no trusted public key was given, the key in the log was trusted\n",
+        );
+    }
+    match verification.tampered {
+        Some((line, ref reason)) => report.push_str(&format!(
+            "Result:         TAMPERED at entry {}: {}\n",
+            line, reason
+        )),
+        None => report.push_str("Result:         intact\n"),
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_describe() {
+        let verification = Verification {
+            entries: 7,
+            chain_start: Some(1),
+            last_signature: Some((5, 0)),
+            tampered: None,
+        };
+        let report = describe(&verification, false);
+        assert!(report.contains("Last signature: entry 5 at 1970-01-01T00:00:00Z\n"));
+        assert!(report.contains("Unsigned:       2 entries after the last signature\n"));
+        assert!(report.contains("Warning:"));
+        assert!(report.ends_with("Result:         intact\n"));
+
+        let verification = Verification {
+            tampered: Some((3, "The signature is invalid.".to_string())),
+            ..verification
+        };
+        assert!(describe(&verification, true)
+            .ends_with("Result:         TAMPERED at entry 3: The signature is invalid.\n"));
+    }
+}
@@ -0,0 +1,672 @@
+//! Upgrading of config files, that were written for older versions of the config format.
+
+use std::collections::HashMap;
+
+use crate::Error;
+
+type Table = toml::map::Map<String, toml::Value>;
+
+/// The version of the config format, that is read by this version of the server.
+pub(crate) const CURRENT_VERSION: i64 = 2;
+
+/// The fields of destinations, that were part of the mapping sections in version 1: The type of the destination, the
+/// name of the field in the mapping section and its name in the destination section.
+const LEGACY_DESTINATION_FIELDS: &[(&str, &str, &str)] = &[
+    ("matrix", "matrix_homeserver", "homeserver"),
+    ("matrix", "matrix_username", "username"),
+    ("matrix", "matrix_password", "password"),
+    ("matrix", "matrix_access_token", "access_token"),
+    ("matrix", "matrix_session_file", "session_file"),
+    ("matrix", "matrix_room_id", "room_id"),
+    ("matrix", "locale", "locale"),
+    ("matrix", "severity_rules", "severity_rules"),
+    ("relay", "relay_host", "host"),
+    ("relay", "relay_port", "port"),
+    ("relay", "relay_recipient", "recipient"),
+    ("relay", "relay_sender", "sender"),
+    ("relay", "relay_helo_name", "helo_name"),
+    ("relay", "relay_source_address", "source_address"),
+    ("relay", "relay_starttls", "starttls"),
+    ("relay", "relay_username", "username"),
+    ("relay", "relay_password", "password"),
+    ("relay", "relay_pool_size", "pool_size"),
+    ("relay", "relay_pool_idle_timeout", "pool_idle_timeout"),
+    ("relay", "relay_pool_max_lifetime", "pool_max_lifetime"),
+    ("file", "dest_path", "path"),
+];
+
+/// Returns the version of the format of the given config. Configs without a 'config_version' field have version 1.
+///
+/// Configs of a newer version than `CURRENT_VERSION` are refused, because the server would misread them.
+pub(crate) fn version(config: &Table) -> Result<i64, Error> {
+    let version = match config.get("config_version") {
+        Some(version) => version.as_integer().filter(|v| *v >= 1).ok_or_else(|| {
+            Error::config(
+                "Value of field 'config_version' has wrong type (expected positive integer)."
+                    .to_string(),
+            )
+        })?,
+        None => 1,
+    };
+    if version > CURRENT_VERSION {
+        return Err(Error::config(format!(
+            "The config file has format version {}, but this version of kutsche only supports format versions up to {}. Please update kutsche.",
+            version, CURRENT_VERSION
+        )));
+    }
+    Ok(version)
+}
+
+/// Returns warnings about the fields of a config, that are only supported for compatibility with older versions of
+/// the config format.
+pub(crate) fn deprecated_fields(config: &Table) -> Result<Vec<String>, Error> {
+    if version(config)? >= CURRENT_VERSION {
+        return Ok(vec![]);
+    }
+
+    let mut warnings = vec![format!(
+        "The config file has the deprecated format version 1 (no 'config_version' field). Convert it to version {} with `kutsche migrate-config`.",
+        CURRENT_VERSION
+    )];
+    if config.contains_key("bind_addresses") {
+        warnings.push(
+            "Field 'bind_addresses' is deprecated, use '[[listeners]]' sections instead."
+                .to_string(),
+        );
+    }
+    if let Some(toml::Value::Table(mappings)) = config.get("mappings") {
+        for (mapping_name, mapping) in mappings.iter() {
+            let mapping = match mapping.as_table() {
+                Some(mapping) => mapping,
+                None => continue,
+            };
+            let dest_type = match legacy_destination_type(mapping) {
+                Some(dest_type) => dest_type,
+                None => continue,
+            };
+            for (_, legacy_field, field) in LEGACY_DESTINATION_FIELDS
+                .iter()
+                .filter(|(field_type, _, _)| *field_type == dest_type)
+            {
+                if mapping.contains_key(*legacy_field) {
+                    warnings.push(format!(
+                        "Field 'mappings.{}.{}' is deprecated, use '{}' in a '[destinations.<name>]' section of type '{}' instead.",
+                        format_key(mapping_name), legacy_field, field, dest_type
+                    ));
+                }
+            }
+        }
+    }
+    Ok(warnings)
+}
+
+/// Converts a config to the current format. Configs, that already have the current format, are returned unchanged.
+///
+/// Version 2 differs from version 1 as follows:
+/// - Every entry of 'bind_addresses' becomes a table in the array 'listeners'.
+/// - The fields of the destination of a mapping move to the section 'destinations.<mapping name>', which has a
+///   'type' field, and lose their prefix (e.g. 'relay_host' becomes 'host'). The mapping refers to it by its field
+///   'destination'.
+pub(crate) fn upgrade(mut config: Table) -> Result<Table, Error> {
+    if version(&config)? >= CURRENT_VERSION {
+        return Ok(config);
+    }
+
+    if let Some(addrs) = config.remove("bind_addresses") {
+        let listeners = match addrs {
+            toml::Value::Array(addrs) => addrs
+                .into_iter()
+                .map(|addr| {
+                    let mut listener = Table::new();
+                    listener.insert("address".to_string(), addr);
+                    toml::Value::Table(listener)
+                })
+                .collect(),
+            _ => {
+                return Err(Error::config(
+                    "Field 'bind_addresses' has wrong type (should be of type Array).".to_string(),
+                ))
+            }
+        };
+        config.insert("listeners".to_string(), toml::Value::Array(listeners));
+    }
+
+    let mut destinations = Table::new();
+    if let Some(toml::Value::Table(mappings)) = config.get_mut("mappings") {
+        for (mapping_name, mapping) in mappings.iter_mut() {
+            // Sections with a wrong type are reported when the config is loaded.
+            if let Some(mapping) = mapping.as_table_mut() {
+                if let Some(destination) = split_destination(mapping) {
+                    mapping.insert(
+                        "destination".to_string(),
+                        toml::Value::String(mapping_name.clone()),
+                    );
+                    destinations.insert(mapping_name.clone(), toml::Value::Table(destination));
+                }
+            }
+        }
+    }
+    if !destinations.is_empty() {
+        config.insert("destinations".to_string(), toml::Value::Table(destinations));
+    }
+
+    config.insert(
+        "config_version".to_string(),
+        toml::Value::Integer(CURRENT_VERSION),
+    );
+    Ok(config)
+}
+
+/// Returns the type of the destination, that a mapping section of version 1 describes, in the same way the server did.
+fn legacy_destination_type(mapping: &Table) -> Option<&'static str> {
+    if mapping.contains_key("matrix_homeserver") {
+        Some("matrix")
+    } else if mapping.contains_key("relay_host") || mapping.contains_key("relay_recipient") {
+        Some("relay")
+    } else if mapping.contains_key("dest_path") {
+        Some("file")
+    } else {
+        None
+    }
+}
+
+/// Removes the fields of the destination from a mapping section of version 1 and returns them as destination section.
+fn split_destination(mapping: &mut Table) -> Option<Table> {
+    let dest_type = legacy_destination_type(mapping)?;
+    let mut destination = Table::new();
+    destination.insert(
+        "type".to_string(),
+        toml::Value::String(dest_type.to_string()),
+    );
+    for (_, legacy_field, field) in LEGACY_DESTINATION_FIELDS
+        .iter()
+        .filter(|(field_type, _, _)| *field_type == dest_type)
+    {
+        if let Some(value) = mapping.remove(*legacy_field) {
+            destination.insert(field.to_string(), value);
+        }
+    }
+    Some(destination)
+}
+
+/// Returns the name, that a field of a destination section had in the mapping section in version 1.
+fn legacy_field(dest_type: &str, field: &str) -> Option<&'static str> {
+    LEGACY_DESTINATION_FIELDS
+        .iter()
+        .find(|(field_type, _, new_field)| *field_type == dest_type && *new_field == field)
+        .map(|(_, legacy_field, _)| *legacy_field)
+}
+
+/// The comments of a config file and the positions of its fields, so they can be kept when the config is rewritten.
+#[derive(Default)]
+pub(crate) struct Layout {
+    /// The lines of comments before a field or section header, indexed by the path of the section and the name of the
+    /// field. The comments before a section header have an empty field name.
+    comments: HashMap<(String, String), Vec<String>>,
+    /// The line numbers of fields and section headers, indexed like `comments`.
+    positions: HashMap<(String, String), usize>,
+}
+
+impl Layout {
+    /// Collects the comments of a config file. Only comments on their own lines are kept.
+    pub(crate) fn parse(source: &str) -> Self {
+        let mut layout = Layout::default();
+        let mut section = String::new();
+        let mut pending: Vec<String> = vec![];
+        for (line_number, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.starts_with('#') {
+                pending.push(line.to_string());
+                continue;
+            }
+            if line.is_empty() {
+                // Keep blank lines between blocks of comments:
+                if !pending.is_empty() {
+                    pending.push(String::new());
+                }
+                continue;
+            }
+            let field = if line.starts_with('[') {
+                section = parse_header(line);
+                String::new()
+            } else {
+                match line
+                    .split_once('=')
+                    .and_then(|(key, _)| parse_key(key.trim()))
+                {
+                    Some(key) => key,
+                    // Continuation of a value spanning multiple lines:
+                    None => continue,
+                }
+            };
+            let index = (section.clone(), field);
+            layout.positions.entry(index.clone()).or_insert(line_number);
+            while pending.last().is_some_and(String::is_empty) {
+                pending.pop();
+            }
+            if !pending.is_empty() {
+                layout.comments.insert(index, std::mem::take(&mut pending));
+            }
+        }
+        layout
+    }
+
+    /// Returns the comments and position of the first of the given fields, that was present in the original file.
+    fn lookup(&self, candidates: &[(String, String)]) -> (Option<&[String]>, Option<usize>) {
+        for index in candidates {
+            if let Some(position) = self.positions.get(index) {
+                return (self.comments.get(index).map(Vec::as_slice), Some(*position));
+            }
+        }
+        (None, None)
+    }
+}
+
+/// Writes a config in the current format as TOML. The comments in `layout` are written before the fields and sections,
+/// that correspond to the commented fields and sections of the original file.
+pub(crate) fn render(config: &Table, layout: &Layout) -> String {
+    let mut out = String::new();
+    let root = |field: &str| vec![(String::new(), field.to_string())];
+
+    out.push_str("# The version of the config format.\n");
+    out.push_str(&format!("config_version = {}\n", CURRENT_VERSION));
+    let fields: Vec<(&String, &toml::Value)> = config
+        .iter()
+        .filter(|(field, value)| {
+            field.as_str() != "config_version" && !value.is_table() && !is_array_of_tables(value)
+        })
+        .collect();
+    write_fields(&mut out, layout, fields, &|field| root(field));
+
+    if let Some(toml::Value::Array(listeners)) = config.get("listeners") {
+        let (comments, _) = layout.lookup(&[
+            (String::new(), "bind_addresses".to_string()),
+            (String::new(), "listeners".to_string()),
+        ]);
+        out.push('\n');
+        write_comments(&mut out, comments);
+        for listener in listeners {
+            if let toml::Value::Table(listener) = listener {
+                out.push_str("[[listeners]]\n");
+                write_fields(&mut out, layout, listener.iter().collect(), &|field| {
+                    vec![("listeners".to_string(), field.to_string())]
+                });
+            }
+        }
+    }
+
+    for (name, value) in sorted_by_position(
+        layout,
+        config
+            .iter()
+            .filter(|(name, value)| {
+                value.is_table() && name.as_str() != "mappings" && name.as_str() != "destinations"
+            })
+            .collect(),
+        &|name| vec![(name.to_string(), String::new())],
+    ) {
+        if let toml::Value::Table(section) = value {
+            write_section(&mut out, layout, &[name.as_str()], section, &|field| {
+                vec![(name.to_string(), field.to_string())]
+            });
+        }
+    }
+
+    let empty = Table::new();
+    let mappings = config
+        .get("mappings")
+        .and_then(toml::Value::as_table)
+        .unwrap_or(&empty);
+    let destinations = config
+        .get("destinations")
+        .and_then(toml::Value::as_table)
+        .unwrap_or(&empty);
+    let mut written_destinations = vec![];
+    let (comments, _) = layout.lookup(&[("mappings".to_string(), String::new())]);
+    if comments.is_some() {
+        out.push('\n');
+        write_comments(&mut out, comments);
+    }
+    for (mapping_name, mapping) in sorted_by_position(layout, mappings.iter().collect(), &|name| {
+        vec![(format!("mappings.{}", name), String::new())]
+    }) {
+        let mapping = match mapping {
+            toml::Value::Table(mapping) => mapping,
+            _ => continue,
+        };
+        let section = format!("mappings.{}", mapping_name);
+        write_section(
+            &mut out,
+            layout,
+            &["mappings", mapping_name.as_str()],
+            mapping,
+            &|field| vec![(section.clone(), field.to_string())],
+        );
+        // Write the destinations next to the (first) mapping using them:
+        let mut dest_names: Vec<&str> = match mapping.get("destination") {
+            Some(toml::Value::String(dest_name)) => vec![dest_name],
+            Some(toml::Value::Array(dest_names)) => {
+                dest_names.iter().filter_map(toml::Value::as_str).collect()
+            }
+            _ => vec![],
+        };
+        dest_names.extend(
+            mapping
+                .get("fallback_destination")
+                .and_then(toml::Value::as_str),
+        );
+        if let Some(toml::Value::Array(rules)) = mapping.get("rules") {
+            dest_names.extend(
+                rules
+                    .iter()
+                    .filter_map(|rule| rule.get("destination"))
+                    .filter_map(toml::Value::as_str),
+            );
+        }
+        for dest_name in dest_names {
+            if let Some(toml::Value::Table(destination)) = destinations.get(dest_name) {
+                if !written_destinations.contains(&dest_name) {
+                    write_destination(&mut out, layout, dest_name, destination);
+                    written_destinations.push(dest_name);
+                }
+            }
+        }
+    }
+    for (dest_name, destination) in destinations.iter() {
+        if let toml::Value::Table(destination) = destination {
+            if !written_destinations.contains(&dest_name.as_str()) {
+                write_destination(&mut out, layout, dest_name, destination);
+            }
+        }
+    }
+
+    out
+}
+
+fn write_destination(out: &mut String, layout: &Layout, dest_name: &str, destination: &Table) {
+    let dest_type = destination
+        .get("type")
+        .and_then(toml::Value::as_str)
+        .unwrap_or_default();
+    let section = format!("destinations.{}", dest_name);
+    // Destinations created by `upgrade()` are named like the mapping, whose fields they got:
+    let legacy_section = format!("mappings.{}", dest_name);
+    write_header(out, layout, &["destinations", dest_name]);
+    // The type comes first, because it determines the meaning of the other fields:
+    if let Some(value) = destination.get("type") {
+        out.push_str(&format!(
+            "type = {}
+",
+            format_value(value)
+        ));
+    }
+    write_fields(
+        out,
+        layout,
+        destination
+            .iter()
+            .filter(|(field, _)| field.as_str() != "type")
+            .collect(),
+        &|field| {
+            let mut candidates = vec![(section.clone(), field.to_string())];
+            if let Some(legacy_field) = legacy_field(dest_type, field) {
+                candidates.push((legacy_section.clone(), legacy_field.to_string()));
+            }
+            candidates
+        },
+    );
+}
+
+fn write_section(
+    out: &mut String,
+    layout: &Layout,
+    path: &[&str],
+    section: &Table,
+    candidates: &dyn Fn(&str) -> Vec<(String, String)>,
+) {
+    write_header(out, layout, path);
+    write_fields(out, layout, section.iter().collect(), candidates);
+}
+
+fn write_header(out: &mut String, layout: &Layout, path: &[&str]) {
+    let (comments, _) = layout.lookup(&[(path.join("."), String::new())]);
+    out.push('\n');
+    write_comments(out, comments);
+    let header: Vec<String> = path.iter().map(|part| format_key(part)).collect();
+    out.push_str(&format!("[{}]\n", header.join(".")));
+}
+
+fn write_fields(
+    out: &mut String,
+    layout: &Layout,
+    fields: Vec<(&String, &toml::Value)>,
+    candidates: &dyn Fn(&str) -> Vec<(String, String)>,
+) {
+    for (field, value) in sorted_by_position(layout, fields, candidates) {
+        let (comments, _) = layout.lookup(&candidates(field));
+        write_comments(out, comments);
+        out.push_str(&format!(
+            "{} = {}\n",
+            format_key(field),
+            format_value(value)
+        ));
+    }
+}
+
+fn write_comments(out: &mut String, comments: Option<&[String]>) {
+    for line in comments.unwrap_or_default() {
+        out.push_str(line);
+        out.push('\n');
+    }
+}
+
+/// Sorts fields by their position in the original file. New fields are sorted to the end.
+fn sorted_by_position<'a>(
+    layout: &Layout,
+    mut fields: Vec<(&'a String, &'a toml::Value)>,
+    candidates: &dyn Fn(&str) -> Vec<(String, String)>,
+) -> Vec<(&'a String, &'a toml::Value)> {
+    fields.sort_by_key(|(field, _)| layout.lookup(&candidates(field)).1.unwrap_or(usize::MAX));
+    fields
+}
+
+fn is_array_of_tables(value: &toml::Value) -> bool {
+    matches!(value, toml::Value::Array(values) if !values.is_empty() && values.iter().all(toml::Value::is_table))
+}
+
+/// Returns the path of a section header like `[mappings."some.name"]` with the quotes removed.
+fn parse_header(line: &str) -> String {
+    let header = line
+        .trim_start_matches('[')
+        .split(']')
+        .next()
+        .unwrap_or_default();
+    header
+        .split('.')
+        .map(|part| part.trim().trim_matches('"'))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Returns the key, if `key` is a bare or quoted key.
+fn parse_key(key: &str) -> Option<String> {
+    if key.len() >= 2 && key.starts_with('"') && key.ends_with('"') {
+        Some(key[1..key.len() - 1].to_string())
+    } else if is_bare_key(key) {
+        Some(key.to_string())
+    } else {
+        None
+    }
+}
+
+fn is_bare_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+pub(crate) fn format_key(key: &str) -> String {
+    if is_bare_key(key) {
+        key.to_string()
+    } else {
+        format_string(key)
+    }
+}
+
+pub(crate) fn format_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04X}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn format_value(value: &toml::Value) -> String {
+    match value {
+        toml::Value::String(s) => format_string(s),
+        toml::Value::Integer(i) => i.to_string(),
+        toml::Value::Float(f) => format!("{:?}", f),
+        toml::Value::Boolean(b) => b.to_string(),
+        toml::Value::Datetime(d) => d.to_string(),
+        toml::Value::Array(values) if values.is_empty() => "[]".to_string(),
+        toml::Value::Array(values) if is_array_of_tables(value) => {
+            // Put every table on its own line:
+            let lines: Vec<String> = values
+                .iter()
+                .map(|value| format!("    {},\n", format_value(value)))
+                .collect();
+            format!("[\n{}]", lines.concat())
+        }
+        toml::Value::Array(values) => {
+            let values: Vec<String> = values.iter().map(format_value).collect();
+            format!("[ {} ]", values.join(", "))
+        }
+        toml::Value::Table(table) if table.is_empty() => "{}".to_string(),
+        toml::Value::Table(table) => {
+            let fields: Vec<String> = table
+                .iter()
+                .map(|(key, value)| format!("{} = {}", format_key(key), format_value(value)))
+                .collect();
+            format!("{{ {} }}", fields.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEGACY_CONFIG: &str = r#"
+# Where to listen.
+bind_addresses = [ "127.0.0.1:25", "[::1]:25" ]
+default_path = "/var/mail/"
+
+[certificates]
+"example.com" = { cert_file = "/etc/cert.pem", private_key_file = "/etc/key.pem" }
+
+[mappings]
+
+[mappings.alerts]
+address = "alerts@example.com"
+priority = 10
+# The room for alerts.
+matrix_homeserver = "matrix.example.com"
+matrix_room_id = "!room:example.com"
+severity_rules = [
+    { pattern = "(?i)down", severity = "critical" },
+]
+
+[mappings.forward]
+address = "forward@example.com"
+relay_recipient = "someone@example.net"
+relay_port = 587
+
+[mappings."default"]
+address = "*"
+"#;
+
+    fn parse(source: &str) -> Table {
+        toml::from_str(source).unwrap()
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let config = upgrade(parse(LEGACY_CONFIG)).unwrap();
+
+        assert_eq!(version(&config).unwrap(), CURRENT_VERSION);
+        assert!(!config.contains_key("bind_addresses"));
+        assert_eq!(config["listeners"][1]["address"].as_str(), Some("[::1]:25"));
+
+        let alerts = &config["mappings"]["alerts"];
+        assert_eq!(alerts["destination"].as_str(), Some("alerts"));
+        assert_eq!(alerts["priority"].as_integer(), Some(10));
+        assert!(alerts.get("matrix_homeserver").is_none());
+        let destination = &config["destinations"]["alerts"];
+        assert_eq!(destination["type"].as_str(), Some("matrix"));
+        assert_eq!(destination["room_id"].as_str(), Some("!room:example.com"));
+        assert!(destination["severity_rules"].is_array());
+
+        let forward = &config["destinations"]["forward"];
+        assert_eq!(forward["type"].as_str(), Some("relay"));
+        assert_eq!(forward["port"].as_integer(), Some(587));
+
+        // Mappings using the default path keep having no destination:
+        assert!(config["mappings"]["default"].get("destination").is_none());
+        assert!(config["destinations"].get("default").is_none());
+
+        // Current configs are not changed:
+        assert_eq!(upgrade(config.clone()).unwrap(), config);
+    }
+
+    #[test]
+    fn test_versions() {
+        let legacy = parse(LEGACY_CONFIG);
+        let warnings = deprecated_fields(&legacy).unwrap();
+        assert!(warnings
+            .iter()
+            .any(|w| w.starts_with("Field 'bind_addresses' is deprecated")));
+        assert!(warnings
+            .iter()
+            .any(|w| w.starts_with("Field 'mappings.forward.relay_port' is deprecated")));
+        assert!(deprecated_fields(&upgrade(legacy).unwrap())
+            .unwrap()
+            .is_empty());
+
+        assert!(version(&parse("config_version = 3")).is_err());
+        assert!(version(&parse("config_version = \"2\"")).is_err());
+    }
+
+    #[test]
+    fn test_render_keeps_values_and_comments() {
+        let config = upgrade(parse(LEGACY_CONFIG)).unwrap();
+        let rendered = render(&config, &Layout::parse(LEGACY_CONFIG));
+
+        assert_eq!(parse(&rendered), config);
+        assert!(rendered.contains("# Where to listen.\n[[listeners]]\n"));
+        assert!(rendered.contains("# The room for alerts.\nhomeserver = \"matrix.example.com\"\n"));
+        // Every destination follows its mapping:
+        let mapping = rendered.find("[mappings.forward]").unwrap();
+        let destination = rendered.find("[destinations.forward]").unwrap();
+        assert!(mapping < destination);
+        assert!(rendered.find("[mappings.default]").unwrap() > destination);
+    }
+
+    #[test]
+    fn test_example_config() {
+        let source = include_str!("../../examples/config.toml");
+        let config = parse(source);
+        assert_eq!(version(&config).unwrap(), CURRENT_VERSION);
+        assert_eq!(parse(&render(&config, &Layout::parse(source))), config);
+    }
+}
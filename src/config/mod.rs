@@ -0,0 +1,2670 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use ipnet::IpNet;
+use regex::Regex;
+use ruma::{RoomId, UserId};
+use rustls::{
+    server::{ClientHello, ResolvesServerCert, ServerConfig},
+    sign::CertifiedKey,
+    Certificate, PrivateKey,
+};
+use rustls_pemfile::{read_all, read_one, Item};
+use users::{get_group_by_name, get_user_by_name, Group, User};
+
+use crate::address_matcher::AddressMatcher;
+use crate::api_token::{load_tokens, ApiToken};
+use crate::attachment_store::AttachmentStore;
+use crate::audit::AuditLog;
+use crate::dns::{DnsConfig, SharedResolver};
+use crate::email::Email;
+use crate::logging::LoggingConfig;
+use crate::maildest::{
+    AmqpDestination, DiscordDestination, EmailDestination, FanOutDestination, FileDestination,
+    FileFormat, LmtpAddress, LmtpDestination, MatrixDestBuilder, MqttDestination, NatsDestination,
+    PoolConfig, PostgresDestination, RedisDestination, RelayDestination, RoomCreation, RoomIndex,
+    RoomPer, RoutingRule, RuleDestination, StartTls, WebhookDestination, DEFAULT_USER_PREFIX,
+};
+use crate::mapping::{HeaderCondition, Mapping};
+use crate::metrics::StatsdConfig;
+use crate::proxy::{is_onion, Proxy};
+use crate::redaction::{Masking, Redactor};
+use crate::redis::RedisClient;
+use crate::secret::{Secret, SecretStore};
+use crate::self_test::SelfTestConfig;
+use crate::severity::SeverityClassifier;
+use crate::shared_state::SharedState;
+use crate::smtp_server::{DelayedDelivery, Greeting, LoopAction, LoopCheck, SocketOptions};
+use crate::tenant::Tenant;
+use crate::thread_index::ThreadIndex;
+use crate::Error;
+
+/// A table of a TOML config file.
+type Table = toml::map::Map<String, toml::Value>;
+
+pub(crate) mod migration;
+pub(crate) mod schema;
+mod summary;
+
+/// The default number of Received headers, that an email may have, if a listener checks for loops.
+const DEFAULT_MAX_HOPS: usize = 50;
+/// The default size of the smallest attachments, that are moved to the attachment store.
+const DEFAULT_MIN_ATTACHMENT_SIZE: usize = 64 * 1024;
+/// The default size of the largest attachments, that Matrix destinations upload.
+const DEFAULT_MAX_UPLOAD_SIZE: u64 = 10 * 1024 * 1024;
+/// The environment variable, that may contain the whole config instead of a config file, so containers can be
+/// configured without mounting a file.
+pub(crate) const CONFIG_ENV: &str = "KUTSCHE_CONFIG";
+/// The prefix of the keys of the shared state, if none is configured.
+const DEFAULT_SHARED_STATE_PREFIX: &str = "kutsche:";
+
+pub(crate) struct Config {
+    pub(crate) effective_user: Option<User>,
+    pub(crate) effective_group: Option<Group>,
+    pub(crate) local_addrs: Vec<SocketAddr>,
+    pub(crate) bind_retry: Duration,
+    /// The entries of `bind_addresses`, that are hostnames, and the addresses they resolved to.
+    pub(crate) bind_hosts: Vec<(String, Vec<SocketAddr>)>,
+    /// The entries of `local_addrs`, whose listeners are exposed as (Tor) hidden services.
+    pub(crate) hidden_service_addrs: Vec<SocketAddr>,
+    /// The entries of `local_addrs`, whose listeners check the Received headers of emails for relay loops.
+    pub(crate) loop_checks: Vec<(SocketAddr, LoopCheck)>,
+    /// The entries of `local_addrs`, whose listeners change the banner, delay the greeting or restrict the advertised
+    /// ESMTP keywords.
+    pub(crate) greetings: Vec<(SocketAddr, Greeting)>,
+    /// The entries of `local_addrs`, whose listeners offer clients to delay the delivery of their emails.
+    pub(crate) delayed_deliveries: Vec<(SocketAddr, DelayedDelivery)>,
+    /// The entries of `local_addrs`, whose listeners only accept connections from the given networks.
+    pub(crate) allow_from: Vec<(SocketAddr, Vec<IpNet>)>,
+    /// The entries of `local_addrs`, whose listeners change the options of their sockets.
+    pub(crate) socket_options: Vec<(SocketAddr, SocketOptions)>,
+    /// The entries of `local_addrs`, whose listeners are for testing, and the names of the mappings, that all their
+    /// emails are delivered to.
+    pub(crate) test_listeners: Vec<(SocketAddr, String)>,
+    pub(crate) bind_recheck: Duration,
+    pub(crate) hostname: String,
+    default_path: Option<PathBuf>,
+    pub(crate) state_dir: Option<PathBuf>,
+    pub(crate) control_socket: Option<PathBuf>,
+    /// The tokens, that clients of the control socket authenticate with. If empty, no authentication is required.
+    pub(crate) api_tokens: Vec<ApiToken>,
+    /// The passwords and tokens of the destinations, that the control socket can rotate.
+    pub(crate) secrets: SecretStore,
+    pub(crate) audit_log: Option<PathBuf>,
+    /// The time between two signatures of the audit log. If set, the audit log is hash-chained and signed with a key
+    /// in the state directory.
+    pub(crate) audit_log_sign_interval: Option<Duration>,
+    /// The name of this server, if it shares the state directory with another one in active/passive mode.
+    pub(crate) ha_node: Option<String>,
+    /// The time, after which the passive server takes over, if the active one stops renewing its lease.
+    pub(crate) ha_lease: Duration,
+    /// The store, that file destinations with `store_attachments` move large attachments to.
+    pub(crate) attachment_store: Option<Arc<AttachmentStore>>,
+    pub(crate) min_free_space: u64,
+    pub(crate) memory_budget: Option<usize>,
+    /// The size in bytes of the largest email, that is accepted.
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) dest_map: AddressMatcher<Arc<Mapping>>,
+    /// The mappings with header conditions in the order, in which they were loaded. Each has its own matcher, because they may share address
+    /// patterns with each other and with the mappings in `dest_map`, which they take precedence over.
+    pub(crate) header_mappings: Vec<AddressMatcher<Arc<Mapping>>>,
+    pub(crate) delivery_workers: usize,
+    /// Deliver an email only once to a mapping, that multiple of its recipients are mapped to.
+    pub(crate) dedupe_per_destination: bool,
+    pub(crate) shutdown_grace: Duration,
+    pub(crate) tls_config: Option<Arc<ServerConfig>>,
+    /// The domains, that the TLS config has certificates for.
+    pub(crate) tls_domains: Vec<String>,
+    pub(crate) logging: LoggingConfig,
+    /// The proxy for outbound connections of destinations, that don't configure their own.
+    pub(crate) proxy: Option<Arc<Proxy>>,
+    /// The resolver shared by everything, that needs DNS.
+    pub(crate) resolver: SharedResolver,
+    /// The mapping, that receives the emails for recipients without mapping, if a dead-letter destination is
+    /// configured.
+    pub(crate) dead_letter: Option<Arc<Mapping>>,
+    /// The mapping, that receives a copy of every accepted email, if an archive destination is configured.
+    pub(crate) archive: Option<Arc<Mapping>>,
+    /// The tenants, whose mappings are part of `dest_map`.
+    pub(crate) tenants: Vec<Arc<Tenant>>,
+    pub(crate) self_test: Option<SelfTestConfig>,
+    /// The statsd server, that metrics are pushed to.
+    pub(crate) statsd: Option<StatsdConfig>,
+    /// Problems in the config file, that did not prevent loading it, e.g. unknown fields. They are collected, because
+    /// the logger is not initialized yet while the config is loaded.
+    pub(crate) warnings: Vec<String>,
+}
+
+/// The name of the mapping of the dead-letter destination.
+pub(crate) const DEAD_LETTER_MAPPING: &str = "dead-letter";
+
+/// The name of the mapping of the archive destination.
+pub(crate) const ARCHIVE_MAPPING: &str = "archive";
+
+impl Config {
+    /// Loads the config file at the given path.
+    pub(crate) async fn load(config_path: &str) -> Result<Self, Error> {
+        // Load config file:
+        let mut cfg_file_buf = String::new();
+        let mut cfg_file = File::open(config_path)?; // TODO: Make async
+        cfg_file.read_to_string(&mut cfg_file_buf)?;
+        Self::parse(&cfg_file_buf).await
+    }
+
+    /// Loads the config from the value of the environment variable `KUTSCHE_CONFIG`, which contains the whole config
+    /// file either as TOML or base64 encoded.
+    pub(crate) async fn load_inline(value: &str) -> Result<Self, Error> {
+        Self::parse(&inline_source(value)?).await
+    }
+
+    /// Loads the config from the content of a config file.
+    async fn parse(cfg_file_buf: &str) -> Result<Self, Error> {
+        let file_cfg = if let toml::Value::Table(map) = toml::from_str(cfg_file_buf)
+            .map_err(|e| Error::config(format!("Could not parse config file: {}", e)))?
+        {
+            map
+        } else {
+            return Err(Error::config(
+                "Could not parse config file: Root Value not a Table.".to_string(),
+            ));
+        };
+        // Files in older formats are read like their upgraded version:
+        let mut warnings = migration::deprecated_fields(&file_cfg)?;
+        let legacy = migration::version(&file_cfg)? < migration::CURRENT_VERSION;
+        let file_cfg = migration::upgrade(file_cfg)?;
+        for path in schema::unknown_fields(&file_cfg) {
+            // The destinations of old configs are named like the mappings, that contained their fields:
+            let path = match path.strip_prefix("destinations.") {
+                Some(rest) if legacy => format!("mappings.{}", rest),
+                _ => path,
+            };
+            warnings.push(format!("Unknown field '{}' is ignored.", path));
+        }
+
+        // Get local socket addresses or default:
+        let mut bind_hosts = vec![];
+        let mut hidden_service_addrs = vec![];
+        let mut loop_checks = vec![];
+        let mut greetings = vec![];
+        let mut delayed_deliveries = vec![];
+        let mut allow_from = vec![];
+        let mut socket_options = vec![];
+        let mut test_listeners = vec![];
+        let local_addrs = match file_cfg.get("listeners") {
+            Some(toml::Value::Array(listeners)) => {
+                let mut local_addrs = vec![];
+                for listener in listeners.iter() {
+                    let addr = listener
+                        .as_table()
+                        .ok_or_else(|| {
+                            Error::config(
+                                "'listeners' contains a value with wrong type (expected table)."
+                                    .to_string(),
+                            )
+                        })?
+                        .get("address")
+                        .ok_or_else(|| {
+                            Error::config("A listener is missing the 'address' field.".to_string())
+                        })?
+                        .as_str()
+                        .ok_or_else(|| {
+                            Error::config(
+                                "Field 'address' of a listener has wrong type (expected string)."
+                                    .to_string(),
+                            )
+                        })?;
+                    let resolved: Vec<SocketAddr> = addr
+                        .to_socket_addrs()
+                        .map_err(|_| {
+                            Error::config(format!(
+                                "Could not resolve address '{}' of a listener.",
+                                addr
+                            ))
+                        })?
+                        .collect();
+                    // Remember entries, that are hostnames, to detect when their addresses change:
+                    if addr.parse::<SocketAddr>().is_err() {
+                        bind_hosts.push((addr.to_string(), resolved.clone()));
+                    }
+                    let hidden_service = match listener.get("hidden_service") {
+                        Some(val) => val.as_bool().ok_or_else(|| {
+                            Error::config(
+                                "Field 'hidden_service' of a listener has wrong type (expected boolean)."
+                                    .to_string(),
+                            )
+                        })?,
+                        None => false,
+                    };
+                    if hidden_service {
+                        // The hidden service should only be reachable through the local Tor daemon:
+                        if resolved.iter().any(|addr| !addr.ip().is_loopback()) {
+                            warnings.push(format!(
+                                "The hidden service listener '{}' is not bound to a loopback address.",
+                                addr
+                            ));
+                        }
+                        hidden_service_addrs.extend(resolved.iter().cloned());
+                    }
+                    if let Some(networks) = load_allow_from(listener)? {
+                        // All connections to a hidden service come from the local Tor daemon:
+                        if hidden_service {
+                            return Err(Error::config(format!(
+                                "The hidden service listener '{}' can't restrict its peers with 'allow_from'.",
+                                addr
+                            )));
+                        }
+                        allow_from.extend(resolved.iter().map(|addr| (*addr, networks.clone())));
+                    }
+                    let test = match listener.get("test") {
+                        Some(val) => val.as_bool().ok_or_else(|| {
+                            Error::config(
+                                "Field 'test' of a listener has wrong type (expected boolean)."
+                                    .to_string(),
+                            )
+                        })?,
+                        None => false,
+                    };
+                    if test {
+                        let test_mapping = listener
+                            .get("test_mapping")
+                            .ok_or_else(|| {
+                                Error::config(format!(
+                                    "The test listener '{}' is missing the 'test_mapping' field.",
+                                    addr
+                                ))
+                            })?
+                            .as_str()
+                            .ok_or_else(|| {
+                                Error::config(
+                                    "Field 'test_mapping' of a listener has wrong type (expected string)."
+                                        .to_string(),
+                                )
+                            })?;
+                        // Without policy checks, the listener should only be reachable from this host:
+                        if resolved.iter().any(|addr| !addr.ip().is_loopback()) {
+                            warnings.push(format!(
+                                "The test listener '{}' is not bound to a loopback address.",
+                                addr
+                            ));
+                        }
+                        test_listeners.extend(
+                            resolved
+                                .iter()
+                                .map(|addr| (*addr, test_mapping.to_string())),
+                        );
+                    } else if let Some(loop_check) = load_loop_check(listener)? {
+                        loop_checks.extend(resolved.iter().map(|addr| (*addr, loop_check.clone())));
+                    }
+                    let greeting = load_greeting(listener)?;
+                    if greeting != Greeting::default() {
+                        greetings.extend(resolved.iter().map(|addr| (*addr, greeting.clone())));
+                    }
+                    let options = load_socket_options(listener)?;
+                    if options != SocketOptions::default() {
+                        socket_options.extend(resolved.iter().map(|addr| (*addr, options.clone())));
+                    }
+                    let delayed_delivery = load_delayed_delivery(listener)?;
+                    if delayed_delivery != DelayedDelivery::default() {
+                        delayed_deliveries.extend(
+                            resolved
+                                .iter()
+                                .map(|addr| (*addr, delayed_delivery.clone())),
+                        );
+                    }
+                    local_addrs.extend(resolved);
+                }
+                local_addrs
+            }
+            Some(_) => {
+                return Err(Error::config(
+                    "Field 'listeners' has wrong type (expected array of tables).".to_string(),
+                ));
+            }
+            None => vec!["127.0.0.1:25"
+                .to_socket_addrs()
+                .expect("This should always work.")
+                .next()
+                .unwrap()],
+        };
+
+        // Get new unix user and group:
+        let effective_user = if let Some(name_val) = file_cfg.get("unix_user") {
+            Some(
+                get_user_by_name(name_val.as_str().ok_or_else(|| {
+                    Error::config(
+                        "Value of field 'unix_user' has wrong type (expected string).".to_string(),
+                    )
+                })?)
+                .ok_or_else(|| {
+                    Error::config("The user given by 'unix_user' does not exist.".to_string())
+                })?,
+            )
+        } else {
+            None
+        };
+        let effective_group = if let Some(name_val) = file_cfg.get("unix_group") {
+            Some(
+                get_group_by_name(name_val.as_str().ok_or_else(|| {
+                    Error::config(
+                        "Value of field 'unix_group' has wrong type (expected string).".to_string(),
+                    )
+                })?)
+                .ok_or_else(|| {
+                    Error::config("The group given by 'unix_group' does not exist.".to_string())
+                })?,
+            )
+        } else {
+            None
+        };
+
+        // Get TLS configuration:
+        let mut tls_domains = vec![];
+        let tls_config = if local_addrs.iter().any(|addr| addr.port() == 465) {
+            let cert_section = file_cfg
+                .get("certificates")
+                .ok_or_else(|| {
+                    Error::config("Missing 'certificates' section in config file.".to_string())
+                })?
+                .as_table()
+                .ok_or_else(|| {
+                    Error::config(
+                        "Wrong type of 'certificate' section in config file (expected table)."
+                            .to_string(),
+                    )
+                })?;
+
+            let tls_config = TlsConfig::try_from(cert_section)?;
+            tls_domains = cert_section.keys().cloned().collect();
+            Some(tls_config.into())
+        } else {
+            None
+        };
+
+        // Get default file destination base directory:
+        let default_path: Option<PathBuf> = if let Some(val) = file_cfg.get("default_path") {
+            Some(PathBuf::from(val.as_str().ok_or_else(|| {
+                Error::config(
+                    "Value of field 'default_path' has wrong type (expected string).".to_string(),
+                )
+            })?))
+        } else {
+            None
+        };
+
+        // Get the time, for which binding to unavailable addresses is retried:
+        let bind_retry = match file_cfg.get("bind_retry_secs") {
+            Some(val) => Duration::from_secs(
+                val.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Value of field 'bind_retry_secs' has wrong type (expected non-negative integer)."
+                                .to_string(),
+                        )
+                    })?,
+            ),
+            None => Duration::from_secs(30),
+        };
+
+        // Get the interval, in which hostnames in 'bind_addresses' are resolved again:
+        let bind_recheck = match file_cfg.get("bind_recheck_secs") {
+            Some(val) => Duration::from_secs(
+                val.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Value of field 'bind_recheck_secs' has wrong type (expected positive integer)."
+                                .to_string(),
+                        )
+                    })?,
+            ),
+            None => Duration::from_secs(300),
+        };
+
+        // Get the name, with which the server introduces itself, or the hostname of the system:
+        let hostname = match file_cfg.get("hostname") {
+            Some(val) => val
+                .as_str()
+                .ok_or_else(|| {
+                    Error::config(
+                        "Value of field 'hostname' has wrong type (expected string).".to_string(),
+                    )
+                })?
+                .to_string(),
+            None => std::fs::read_to_string("/etc/hostname")
+                .ok()
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .unwrap_or_else(|| "localhost".to_string()),
+        };
+
+        // Get state directory:
+        let state_dir: Option<PathBuf> = if let Some(val) = file_cfg.get("state_dir") {
+            Some(PathBuf::from(val.as_str().ok_or_else(|| {
+                Error::config(
+                    "Value of field 'state_dir' has wrong type (expected string).".to_string(),
+                )
+            })?))
+        } else {
+            None
+        };
+
+        // Get path of the control socket:
+        let control_socket: Option<PathBuf> = if let Some(val) = file_cfg.get("control_socket") {
+            Some(PathBuf::from(val.as_str().ok_or_else(|| {
+                Error::config(
+                    "Value of field 'control_socket' has wrong type (expected string).".to_string(),
+                )
+            })?))
+        } else {
+            None
+        };
+
+        // Get the tokens for the control socket:
+        let api_tokens = match file_cfg.get("api_tokens") {
+            Some(val) => load_tokens(val.as_table().ok_or_else(|| {
+                Error::config(
+                    "Wrong type of 'api_tokens' section in config file (expected table)."
+                        .to_string(),
+                )
+            })?)?,
+            None => vec![],
+        };
+
+        // Get path of the audit log:
+        let audit_log: Option<PathBuf> = if let Some(val) = file_cfg.get("audit_log") {
+            Some(PathBuf::from(val.as_str().ok_or_else(|| {
+                Error::config(
+                    "Value of field 'audit_log' has wrong type (expected string).".to_string(),
+                )
+            })?))
+        } else {
+            None
+        };
+        let audit_log_sign_interval = match file_cfg.get("audit_log_sign_interval_secs") {
+            Some(val) => Some(Duration::from_secs(
+                val.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Value of field 'audit_log_sign_interval_secs' has wrong type (expected positive integer).",
+                        )
+                    })?,
+            )),
+            None => None,
+        };
+        if audit_log_sign_interval.is_some() && (audit_log.is_none() || state_dir.is_none()) {
+            return Err(Error::config(
+                "Field 'audit_log_sign_interval_secs' requires the fields 'audit_log' and 'state_dir'.",
+            ));
+        }
+
+        // Get the name of this server for active/passive mode:
+        let ha_node = match file_cfg.get("ha_node") {
+            Some(val) => Some(
+                val.as_str()
+                    .filter(|name| {
+                        !name.is_empty()
+                            && name
+                                .chars()
+                                .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c))
+                    })
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Value of field 'ha_node' has wrong type (expected name of letters, digits, '-', '_' and '.').",
+                        )
+                    })?
+                    .to_string(),
+            ),
+            None => None,
+        };
+        if ha_node.is_some() && state_dir.is_none() {
+            return Err(Error::config(
+                "Field 'ha_node' requires the field 'state_dir'.",
+            ));
+        }
+        let ha_lease = match file_cfg.get("ha_lease_secs") {
+            Some(val) => Duration::from_secs(
+                val.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .filter(|n| *n >= 3)
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Value of field 'ha_lease_secs' has wrong type (expected integer of at least 3).",
+                        )
+                    })?,
+            ),
+            None => Duration::from_secs(30),
+        };
+
+        // Get the store for large attachments:
+        let attachment_store = match file_cfg.get("attachment_store") {
+            Some(val) => Some(Arc::new(load_attachment_store(val)?)),
+            None => None,
+        };
+
+        // Get minimal free space on the volumes we write to:
+        let min_free_space = match file_cfg.get("min_free_space_mb") {
+            Some(val) => val
+                .as_integer()
+                .and_then(|n| u64::try_from(n).ok())
+                .ok_or_else(|| {
+                    Error::config(
+                        "Value of field 'min_free_space_mb' has wrong type (expected non-negative integer)."
+                            .to_string(),
+                    )
+                })?
+                * 1024
+                * 1024,
+            None => 64 * 1024 * 1024,
+        };
+
+        // Get the memory budget for buffering received emails:
+        let memory_budget = match file_cfg.get("memory_budget_mb") {
+            Some(val) => Some(
+                val.as_integer()
+                    .and_then(|n| usize::try_from(n).ok())
+                    .and_then(|n| n.checked_mul(1024 * 1024))
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Value of field 'memory_budget_mb' has wrong type (expected non-negative integer)."
+                                .to_string(),
+                        )
+                    })?,
+            ),
+            None => None,
+        };
+
+        // Get the size limit of received emails:
+        let max_message_size = match file_cfg.get("max_message_size_mb") {
+            Some(val) => Some(
+                val.as_integer()
+                    .and_then(|n| usize::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .and_then(|n| n.checked_mul(1024 * 1024))
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Value of field 'max_message_size_mb' has wrong type (expected positive integer)."
+                                .to_string(),
+                        )
+                    })?,
+            ),
+            None => None,
+        };
+
+        // Get number of concurrent deliveries:
+        let delivery_workers = match file_cfg.get("delivery_workers") {
+            Some(val) => val
+                .as_integer()
+                .and_then(|n| usize::try_from(n).ok())
+                .filter(|n| *n > 0)
+                .ok_or_else(|| {
+                    Error::config(
+                        "Value of field 'delivery_workers' has wrong type (expected positive integer)."
+                            .to_string(),
+                    )
+                })?,
+            None => 4,
+        };
+
+        // Get whether recipients with the same mapping share a delivery:
+        let dedupe_per_destination = match file_cfg.get("dedupe_per_destination") {
+            Some(val) => val.as_bool().ok_or_else(|| {
+                Error::config(
+                    "Value of field 'dedupe_per_destination' has wrong type (expected boolean)."
+                        .to_string(),
+                )
+            })?,
+            None => false,
+        };
+
+        // Get the maximal time, for which new connections are answered with 421 before shutting down:
+        let shutdown_grace = match file_cfg.get("shutdown_grace_secs") {
+            Some(val) => Duration::from_secs(
+                val.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Value of field 'shutdown_grace_secs' has wrong type (expected non-negative integer)."
+                                .to_string(),
+                        )
+                    })?,
+            ),
+            None => Duration::from_secs(10),
+        };
+
+        // Get the log levels:
+        let logging = match file_cfg.get("logging") {
+            Some(val) => LoggingConfig::try_from(val.as_table().ok_or_else(|| {
+                Error::config(
+                    "Wrong type of 'logging' section in config file (expected table).".to_string(),
+                )
+            })?)?,
+            None => LoggingConfig::default(),
+        };
+
+        // Get the default proxy for outbound connections:
+        let proxy = match file_cfg.get("proxy") {
+            Some(val) => Some(Arc::new(
+                val.as_str()
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Value of field 'proxy' has wrong type (expected string).".to_string(),
+                        )
+                    })?
+                    .parse::<Proxy>()?,
+            )),
+            None => None,
+        };
+
+        // Get the DNS resolver:
+        let resolver = SharedResolver::new(match file_cfg.get("dns") {
+            Some(val) => DnsConfig::try_from(val.as_table().ok_or_else(|| {
+                Error::config(
+                    "Wrong type of 'dns' section in config file (expected table).".to_string(),
+                )
+            })?)?,
+            None => DnsConfig::default(),
+        });
+
+        // Get the periodic self-test:
+        let self_test = match file_cfg.get("self_test") {
+            Some(val) => Some(SelfTestConfig::try_from(val.as_table().ok_or_else(
+                || {
+                    Error::config(
+                        "Wrong type of 'self_test' section in config file (expected table)."
+                            .to_string(),
+                    )
+                },
+            )?)?),
+            None => None,
+        };
+
+        // Get the statsd exporter:
+        let statsd = match file_cfg.get("statsd") {
+            Some(val) => Some(StatsdConfig::try_from(val.as_table().ok_or_else(
+                || Error::config("Wrong type of 'statsd' section in config file (expected table)."),
+            )?)?),
+            None => None,
+        };
+
+        // Get the state shared with other servers:
+        let shared_state = match file_cfg.get("shared_state") {
+            Some(val) => Some(Arc::new(load_shared_state(val)?)),
+            None => None,
+        };
+
+        // Get the tenants and their config files:
+        let tenant_configs = match file_cfg.get("tenants") {
+            Some(val) => load_tenants(
+                val.as_table().ok_or_else(|| {
+                    Error::config(
+                        "Wrong type of 'tenants' section in config file (expected table)."
+                            .to_string(),
+                    )
+                })?,
+                shared_state.as_ref(),
+                &mut warnings,
+            )?,
+            None => vec![],
+        };
+
+        let (root_mappings, root_destinations) = mapping_sections(&file_cfg, "config file")?;
+        let mut config = Config {
+            effective_user,
+            effective_group,
+            local_addrs,
+            bind_retry,
+            bind_hosts,
+            hidden_service_addrs,
+            loop_checks,
+            greetings,
+            delayed_deliveries,
+            allow_from,
+            socket_options,
+            test_listeners,
+            bind_recheck,
+            hostname,
+            default_path,
+            state_dir,
+            control_socket,
+            api_tokens,
+            secrets: SecretStore::default(),
+            audit_log,
+            audit_log_sign_interval,
+            ha_node,
+            ha_lease,
+            attachment_store,
+            min_free_space,
+            memory_budget,
+            max_message_size,
+            dest_map: AddressMatcher::new(),
+            header_mappings: vec![],
+            dead_letter: None,
+            archive: None,
+            delivery_workers,
+            dedupe_per_destination,
+            shutdown_grace,
+            tls_config,
+            tls_domains,
+            logging,
+            proxy,
+            resolver,
+            tenants: tenant_configs
+                .iter()
+                .map(|(tenant, _)| tenant.clone())
+                .collect(),
+            self_test,
+            statsd,
+            warnings,
+        }
+        .load_mapping(root_mappings, root_destinations, None)
+        .await?;
+        // The mappings of the tenants are loaded from their own config files:
+        for (tenant, tenant_cfg) in tenant_configs.iter() {
+            let (tenant_mappings, tenant_destinations) = mapping_sections(
+                tenant_cfg,
+                &format!("config file of tenant '{}'", tenant.name),
+            )?;
+            config = config
+                .load_mapping(tenant_mappings, tenant_destinations, Some(tenant))
+                .await?;
+        }
+        // Emails for recipients without mapping are captured by the dead-letter destination:
+        if let Some(dest_name) = file_cfg.get("dead_letter_destination") {
+            let dest_name = dest_name.as_str().ok_or_else(|| {
+                Error::config(
+                    "Field 'dead_letter_destination' has wrong type (expected string).".to_string(),
+                )
+            })?;
+            if config.mapping(DEAD_LETTER_MAPPING).is_some() {
+                return Err(Error::config(format!(
+                    "The name of mapping '{}' is reserved for the dead-letter destination.",
+                    DEAD_LETTER_MAPPING
+                )));
+            }
+            let dest_section =
+                destination_section(root_destinations, DEAD_LETTER_MAPPING, dest_name)?;
+            let destination = load_destination(dest_name, dest_section, &config, None, None)
+                .await
+                .map_err(|e| e.in_mapping(DEAD_LETTER_MAPPING))?;
+            config.dead_letter = Some(Arc::new(Mapping::new(DEAD_LETTER_MAPPING, destination)));
+        }
+        // Every accepted email is journaled to the archive destination:
+        if let Some(dest_name) = file_cfg.get("archive_destination") {
+            let dest_name = dest_name.as_str().ok_or_else(|| {
+                Error::config(
+                    "Field 'archive_destination' has wrong type (expected string).".to_string(),
+                )
+            })?;
+            if config.mapping(ARCHIVE_MAPPING).is_some() {
+                return Err(Error::config(format!(
+                    "The name of mapping '{}' is reserved for the archive destination.",
+                    ARCHIVE_MAPPING
+                )));
+            }
+            let dest_section = destination_section(root_destinations, ARCHIVE_MAPPING, dest_name)?;
+            let destination = load_destination(dest_name, dest_section, &config, None, None)
+                .await
+                .map_err(|e| e.in_mapping(ARCHIVE_MAPPING))?;
+            config.archive = Some(Arc::new(Mapping::new(ARCHIVE_MAPPING, destination)));
+        }
+        for (_, test_mapping) in config.test_listeners.iter() {
+            if config.mapping(test_mapping).is_none() {
+                return Err(Error::config(format!(
+                    "The test mapping '{}' of a listener does not exist.",
+                    test_mapping
+                )));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Returns the mapping for `address` and the pattern, that matched it, or None, if no mapping matches.
+    ///
+    /// Addresses in the domains of a tenant are only routed to the mappings of this tenant, e.g. not to a catch-all
+    /// mapping of the main config file.
+    pub(crate) fn route(&self, address: &str) -> Option<(String, &Arc<Mapping>)> {
+        self.route_in(&self.dest_map, address)
+    }
+
+    /// Like `route()`, but also considers the mappings with header conditions, that the email fulfills.
+    pub(crate) fn route_email(
+        &self,
+        address: &str,
+        email: &Email<'_>,
+    ) -> Option<(String, &Arc<Mapping>)> {
+        self.header_mappings
+            .iter()
+            .filter_map(|matcher| self.route_in(matcher, address))
+            .find(|(_, mapping)| mapping.matches_headers(email))
+            .or_else(|| self.route(address))
+    }
+
+    fn route_in<'m>(
+        &self,
+        matcher: &'m AddressMatcher<Arc<Mapping>>,
+        address: &str,
+    ) -> Option<(String, &'m Arc<Mapping>)> {
+        let owner = self.tenants.iter().find(|tenant| tenant.owns(address));
+        matcher
+            .get_with_pattern(address)
+            .filter(|(_, mapping)| match (owner, &mapping.tenant) {
+                (Some(owner), Some(tenant)) => Arc::ptr_eq(owner, tenant),
+                (None, None) => true,
+                _ => false,
+            })
+    }
+
+    /// Returns all mappings, including the ones of the dead-letter and archive destinations.
+    pub(crate) fn mappings(&self) -> impl Iterator<Item = &Arc<Mapping>> {
+        self.dest_map
+            .values()
+            .chain(self.header_mappings.iter().flat_map(AddressMatcher::values))
+            .chain(self.dead_letter.iter())
+            .chain(self.archive.iter())
+    }
+
+    /// Opens the audit log, if one is configured. It is signed with the key in the state directory, if signing is
+    /// enabled.
+    pub(crate) fn open_audit_log(&self) -> Result<Option<AuditLog>, Error> {
+        match (
+            &self.audit_log,
+            self.audit_log_sign_interval,
+            &self.state_dir,
+        ) {
+            (Some(path), Some(_), Some(state_dir)) => {
+                AuditLog::open_signed(path, state_dir).map(Some)
+            }
+            (Some(path), _, _) => AuditLog::open(path).map(Some),
+            (None, _, _) => Ok(None),
+        }
+    }
+
+    /// Returns the mapping named `name`.
+    pub(crate) fn mapping(&self, name: &str) -> Option<&Arc<Mapping>> {
+        self.mappings().find(|mapping| mapping.name == name)
+    }
+
+    /// Loads a destination mapping from the given mappings sections from the config file to the own field dest_map.
+    ///
+    /// If the sections are from the config file of a tenant, the mappings may only use addresses in the domains of the
+    /// tenant and their names are prefixed with the name of the tenant (e.g. `acme/alerts`). Otherwise they may not use
+    /// addresses in the domains of any tenant.
+    async fn load_mapping(
+        mut self,
+        mapping_sections: &Table,
+        destination_sections: Option<&Table>,
+        tenant: Option<&Arc<Tenant>>,
+    ) -> Result<Self, Error> {
+        for mapping_key in mapping_sections.keys() {
+            let map_section = mapping_sections
+                .get(mapping_key)
+                .unwrap() // Cannor be None, because mapping_key is in mapping_sections.keys().
+                .as_table()
+                .ok_or_else(|| {
+                    Error::config(format!(
+                        "Section 'mappings.{}' has wrong type (expected table).",
+                        mapping_key
+                    ))
+                })?;
+            let mapping_name = &match tenant {
+                Some(tenant) => format!("{}/{}", tenant.name, mapping_key),
+                None => mapping_key.clone(),
+            };
+
+            let addr_key = map_section
+                .get("address")
+                .ok_or_else(|| Error::config(format!("Mapping {} is missing 'address' field.", mapping_name)))?
+                .as_str()
+                .ok_or_else(|| {
+                    Error::config(format!("Field 'address' for mapping '{mapping_name}' has wrong type (expected string)."))
+                })?;
+            // Get additional addresses, that are mapped to the same destination:
+            let mut addr_patterns = vec![addr_key];
+            if let Some(aliases) = map_section.get("aliases") {
+                for alias in aliases
+                    .as_array()
+                    .ok_or_else(|| Error::config(format!("Field 'aliases' for mapping '{mapping_name}' has wrong type (expected array).")))?
+                {
+                    addr_patterns.push(alias.as_str().ok_or_else(|| {
+                        Error::config(format!("Field 'aliases' for mapping '{mapping_name}' contains a value with wrong type (expected string)."))
+                    })?);
+                }
+            }
+            // Tenants may only receive emails for their own domains:
+            for pattern in addr_patterns.iter() {
+                match tenant {
+                    Some(tenant) if !tenant.owns(pattern) => {
+                        return Err(Error::config(format!("The address '{pattern}' of mapping '{mapping_name}' is not in a domain of tenant '{}'.", tenant.name)));
+                    }
+                    None => {
+                        if let Some(owner) = self.tenants.iter().find(|owner| owner.owns(pattern)) {
+                            return Err(Error::config(format!("The address '{pattern}' of mapping '{mapping_name}' is in a domain of tenant '{}'.", owner.name)));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // The format of file destinations:
+            let format = match map_section.get("dest_format") {
+                Some(format) => Some(match format.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'dest_format' for mapping '{mapping_name}' has wrong type (expected string).")))?
+                    .parse()?
+                {
+                    FileFormat::Maildir { .. } => FileFormat::Maildir { hostname: self.hostname.clone() },
+                    format => format,
+                }),
+                None => None,
+            };
+
+            let destination: Box<dyn EmailDestination + Send + Sync> = if let Some(dest_names) =
+                map_section.get("destination")
+            {
+                // Either the name of one destination or an array of names, that emails are delivered to all of:
+                let dest_names = match dest_names {
+                    toml::Value::String(dest_name) => vec![dest_name.as_str()],
+                    toml::Value::Array(dest_names) if !dest_names.is_empty() => dest_names.iter()
+                        .map(|dest_name| dest_name.as_str()
+                            .ok_or_else(|| Error::config(format!("Field 'destination' for mapping '{mapping_name}' contains a value with wrong type (expected string)."))))
+                        .collect::<Result<_, _>>()?,
+                    _ => return Err(Error::config(format!("Field 'destination' for mapping '{mapping_name}' has wrong type (expected string or non-empty array)."))),
+                };
+                let mut destinations = vec![];
+                for dest_name in dest_names {
+                    let destination = load_destination(
+                        dest_name,
+                        destination_section(destination_sections, mapping_name, dest_name)?,
+                        &self,
+                        tenant.map(Arc::as_ref),
+                        format.clone(),
+                    )
+                    .await
+                    .map_err(|e| e.in_mapping(mapping_name))?;
+                    destinations.push((dest_name, destination));
+                }
+                if destinations.len() == 1 {
+                    destinations.remove(0).1
+                } else {
+                    let mut fan_out = FanOutDestination::new();
+                    for (dest_name, destination) in destinations {
+                        fan_out.push(dest_name, destination);
+                    }
+                    Box::new(fan_out)
+                }
+            } else if let Some(base_path) = match tenant {
+                Some(tenant) => tenant.default_path.as_ref(),
+                None => self.default_path.as_ref(),
+            } {
+                // Create default file destination:
+
+                let mut path = PathBuf::from(base_path);
+                path.push(addr_key);
+                Box::new(FileDestination::with_format(
+                    path,
+                    format.clone().unwrap_or(FileFormat::Flat),
+                )?)
+            } else {
+                return Err(Error::config(format!(
+                    "Missing destination for mapping '{mapping_name}'."
+                )));
+            };
+
+            // Rules route some emails to other destinations than the one of the mapping:
+            let destination = match map_section.get("rules") {
+                Some(rules) => {
+                    let rules = rules.as_array()
+                        .ok_or_else(|| Error::config(format!("Field 'rules' for mapping '{mapping_name}' has wrong type (expected array).")))?;
+                    let mut ruled = RuleDestination::new(destination);
+                    for (i, rule) in rules.iter().enumerate() {
+                        let (rule, dest_name) = load_routing_rule(rule, mapping_name, i + 1)?;
+                        let destination = load_destination(
+                            dest_name,
+                            destination_section(destination_sections, mapping_name, dest_name)?,
+                            &self,
+                            tenant.map(Arc::as_ref),
+                            format.clone(),
+                        )
+                        .await
+                        .map_err(|e| e.in_mapping(mapping_name))?;
+                        ruled.push_rule(rule, dest_name, destination);
+                    }
+                    Box::new(ruled)
+                }
+                None => destination,
+            };
+
+            let mut mapping = Mapping::new(mapping_name, destination);
+            mapping.tenant = tenant.cloned();
+            if let Some(dest_name) = map_section.get("fallback_destination") {
+                let dest_name = dest_name.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'fallback_destination' for mapping '{mapping_name}' has wrong type (expected string).")))?;
+                mapping.fallback = Some(
+                    load_destination(
+                        dest_name,
+                        destination_section(destination_sections, mapping_name, dest_name)?,
+                        &self,
+                        tenant.map(Arc::as_ref),
+                        format.clone(),
+                    )
+                    .await
+                    .map_err(|e| e.in_mapping(mapping_name))?,
+                );
+            }
+            if let Some(priority) = map_section.get("priority") {
+                mapping.priority = priority.as_integer()
+                    .and_then(|p| u8::try_from(p).ok())
+                    .ok_or_else(|| Error::config(format!("Field 'priority' for mapping '{mapping_name}' has wrong type (expected integer between 0 and 255).")))?;
+            }
+
+            if let Some(synchronous) = map_section.get("synchronous_delivery") {
+                mapping.synchronous_delivery = synchronous.as_bool()
+                    .ok_or_else(|| Error::config(format!("Field 'synchronous_delivery' for mapping '{mapping_name}' has wrong type (expected boolean).")))?;
+            }
+
+            if let Some(auto_replies) = map_section.get("auto_replies") {
+                mapping.auto_replies = auto_replies.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'auto_replies' for mapping '{mapping_name}' has wrong type (expected string).")))?
+                    .parse()?;
+            }
+
+            if let Some(retention_days) = map_section.get("retention_days") {
+                mapping.retention_days = Some(retention_days.as_integer()
+                    .and_then(|days| u64::try_from(days).ok())
+                    .ok_or_else(|| Error::config(format!("Field 'retention_days' for mapping '{mapping_name}' has wrong type (expected non-negative integer).")))?);
+            }
+
+            if let Some(legal_hold) = map_section.get("legal_hold") {
+                mapping.legal_hold = legal_hold.as_bool()
+                    .ok_or_else(|| Error::config(format!("Field 'legal_hold' for mapping '{mapping_name}' has wrong type (expected boolean).")))?;
+            }
+
+            if let Some(listeners) = map_section.get("listeners") {
+                let listeners = listeners.as_array()
+                    .ok_or_else(|| Error::config(format!("Field 'listeners' for mapping '{mapping_name}' has wrong type (expected array).")))?;
+                for listener in listeners {
+                    let addr = listener.as_str()
+                        .ok_or_else(|| Error::config(format!("Field 'listeners' for mapping '{mapping_name}' contains a value with wrong type (expected string).")))?;
+                    let resolved = addr.to_socket_addrs().map_err(|_| {
+                        Error::config(format!(
+                            "Could not resolve listener '{addr}' of mapping '{mapping_name}'."
+                        ))
+                    })?;
+                    mapping.listeners.extend(resolved);
+                }
+            }
+
+            if let Some(headers) = map_section.get("headers") {
+                let headers = headers.as_table()
+                    .ok_or_else(|| Error::config(format!("Field 'headers' for mapping '{mapping_name}' has wrong type (expected table).")))?;
+                for (name, pattern) in headers {
+                    let pattern = pattern.as_str()
+                        .ok_or_else(|| Error::config(format!("Header '{name}' in field 'headers' for mapping '{mapping_name}' has wrong type (expected string).")))?;
+                    let pattern = Regex::new(pattern).map_err(|e| {
+                        Error::config(format!(
+                            "Invalid pattern for header '{name}' of mapping '{mapping_name}': {e}"
+                        ))
+                    })?;
+                    mapping.headers.push(HeaderCondition {
+                        name: name.clone(),
+                        pattern,
+                    });
+                }
+            }
+
+            if mapping.headers.is_empty() {
+                self.dest_map.insert(addr_patterns, Arc::new(mapping))?;
+            } else {
+                let mut matcher = AddressMatcher::new();
+                matcher.insert(addr_patterns, Arc::new(mapping))?;
+                self.header_mappings.push(matcher);
+            }
+        }
+
+        Ok(self)
+    }
+}
+
+/// Loads the greeting of a listener from its fields 'banner', 'greeting_delay_secs' and 'esmtp_keywords'.
+fn load_greeting(listener: &toml::Value) -> Result<Greeting, Error> {
+    let mut greeting = Greeting::default();
+    if let Some(banner) = listener.get("banner") {
+        greeting.banner = Some(
+            banner
+                .as_str()
+                .filter(|banner| !banner.contains(['\r', '\n']))
+                .ok_or_else(|| {
+                    Error::config(
+                        "Field 'banner' of a listener has wrong type (expected single line string).",
+                    )
+                })?
+                .to_string(),
+        );
+    }
+    if let Some(delay) = listener.get("greeting_delay_secs") {
+        greeting.delay = Duration::from_secs(
+            delay
+                .as_integer()
+                .and_then(|n| u64::try_from(n).ok())
+                .ok_or_else(|| {
+                    Error::config(
+                        "Field 'greeting_delay_secs' of a listener has wrong type (expected non-negative integer).",
+                    )
+                })?,
+        );
+    }
+    if let Some(keywords) = listener.get("esmtp_keywords") {
+        greeting.esmtp_keywords = Some(
+            keywords
+                .as_array()
+                .and_then(|keywords| {
+                    keywords
+                        .iter()
+                        .map(|keyword| keyword.as_str().map(str::to_string))
+                        .collect()
+                })
+                .ok_or_else(|| {
+                    Error::config(
+                        "Field 'esmtp_keywords' of a listener has wrong type (expected array of strings).",
+                    )
+                })?,
+        );
+    }
+    Ok(greeting)
+}
+
+/// Loads the networks, that a listener accepts connections from, from its field 'allow_from'. Single addresses are
+/// accepted as networks with only one address.
+fn load_allow_from(listener: &toml::Value) -> Result<Option<Vec<IpNet>>, Error> {
+    let entries = match listener.get("allow_from") {
+        Some(entries) => entries.as_array().ok_or_else(|| {
+            Error::config(
+                "Field 'allow_from' of a listener has wrong type (expected array of strings).",
+            )
+        })?,
+        None => return Ok(None),
+    };
+    let networks = entries
+        .iter()
+        .map(|entry| {
+            let entry = entry.as_str().ok_or_else(|| {
+                Error::config(
+                    "Field 'allow_from' of a listener has wrong type (expected array of strings).",
+                )
+            })?;
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| {
+                    Error::config(format!(
+                        "Invalid network '{}' in field 'allow_from'.",
+                        entry
+                    ))
+                })
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
+    Ok(Some(networks))
+}
+
+/// Loads the socket options of a listener from its fields 'tcp_keepalive_secs', 'tcp_nodelay', 'listen_backlog',
+/// 'recv_buffer_size' and 'acceptors'.
+fn load_socket_options(listener: &toml::Value) -> Result<SocketOptions, Error> {
+    let positive = |field: &str| -> Result<Option<u32>, Error> {
+        listener
+            .get(field)
+            .map(|value| {
+                value
+                    .as_integer()
+                    .and_then(|n| u32::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| {
+                        Error::config(format!(
+                            "Field '{}' of a listener has wrong type (expected positive integer).",
+                            field
+                        ))
+                    })
+            })
+            .transpose()
+    };
+    let mut options = SocketOptions {
+        keepalive: positive("tcp_keepalive_secs")?.map(|secs| Duration::from_secs(secs.into())),
+        backlog: positive("listen_backlog")?,
+        recv_buffer_size: positive("recv_buffer_size")?,
+        acceptors: positive("acceptors")?.map(|n| n as usize),
+        ..SocketOptions::default()
+    };
+    if let Some(nodelay) = listener.get("tcp_nodelay") {
+        options.nodelay = nodelay.as_bool().ok_or_else(|| {
+            Error::config("Field 'tcp_nodelay' of a listener has wrong type (expected boolean).")
+        })?;
+    }
+    Ok(options)
+}
+
+/// Loads the extensions for delayed delivery, that a listener offers, from its fields 'future_release_max_secs' and
+/// 'deliver_by'.
+fn load_delayed_delivery(listener: &toml::Value) -> Result<DelayedDelivery, Error> {
+    let mut delayed_delivery = DelayedDelivery::default();
+    if let Some(max_hold) = listener.get("future_release_max_secs") {
+        delayed_delivery.max_hold = Some(Duration::from_secs(
+            max_hold
+                .as_integer()
+                .and_then(|n| u64::try_from(n).ok())
+                .filter(|n| *n > 0)
+                .ok_or_else(|| {
+                    Error::config(
+                        "Field 'future_release_max_secs' of a listener has wrong type (expected positive integer).",
+                    )
+                })?,
+        ));
+    }
+    if let Some(deliver_by) = listener.get("deliver_by") {
+        delayed_delivery.deliver_by = deliver_by.as_bool().ok_or_else(|| {
+            Error::config("Field 'deliver_by' of a listener has wrong type (expected boolean).")
+        })?;
+    }
+    Ok(delayed_delivery)
+}
+
+/// Loads the loop check of a listener from its fields 'max_hops', 'detect_own_hostname' and 'on_loop'. Returns None,
+/// if none of them is given.
+fn load_loop_check(listener: &toml::Value) -> Result<Option<LoopCheck>, Error> {
+    let max_hops = listener.get("max_hops");
+    let own_hostname = listener.get("detect_own_hostname");
+    let action = listener.get("on_loop");
+    if max_hops.is_none() && own_hostname.is_none() && action.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(LoopCheck {
+        max_hops: match max_hops {
+            Some(val) => val
+                .as_integer()
+                .and_then(|n| usize::try_from(n).ok())
+                .ok_or_else(|| {
+                    Error::config(
+                        "Field 'max_hops' of a listener has wrong type (expected non-negative integer).",
+                    )
+                })?,
+            None => DEFAULT_MAX_HOPS,
+        },
+        own_hostname: match own_hostname {
+            Some(val) => val.as_bool().ok_or_else(|| {
+                Error::config(
+                    "Field 'detect_own_hostname' of a listener has wrong type (expected boolean).",
+                )
+            })?,
+            None => false,
+        },
+        action: match action.map(toml::Value::as_str) {
+            None | Some(Some("reject")) => LoopAction::Reject,
+            Some(Some("flag")) => LoopAction::Flag,
+            Some(_) => {
+                return Err(Error::config(
+                    "Field 'on_loop' of a listener has wrong type (expected \"reject\" or \"flag\").",
+                ))
+            }
+        },
+    }))
+}
+
+/// Returns the 'mappings' and 'destinations' sections of a config file, described by `file_desc` in errors.
+fn mapping_sections<'a>(
+    file_cfg: &'a Table,
+    file_desc: &str,
+) -> Result<(&'a Table, Option<&'a Table>), Error> {
+    let mapping_sections = file_cfg
+        .get("mappings")
+        .ok_or_else(|| Error::config(format!("Missing 'mappings' sections in {}.", file_desc)))?
+        .as_table()
+        .ok_or_else(|| {
+            Error::config(format!(
+                "Wrong type of 'mappings' section in {} (expected table).",
+                file_desc
+            ))
+        })?;
+    let destination_sections = match file_cfg.get("destinations") {
+        Some(destinations) => Some(destinations.as_table().ok_or_else(|| {
+            Error::config(format!(
+                "Wrong type of 'destinations' section in {} (expected table).",
+                file_desc
+            ))
+        })?),
+        None => None,
+    };
+    Ok((mapping_sections, destination_sections))
+}
+
+/// Creates the tenants described by the section 'tenants' and reads their config files.
+fn load_tenants(
+    tenant_sections: &Table,
+    shared_state: Option<&Arc<SharedState>>,
+    warnings: &mut Vec<String>,
+) -> Result<Vec<(Arc<Tenant>, Table)>, Error> {
+    let mut tenants: Vec<(Arc<Tenant>, Table)> = vec![];
+    for (tenant_name, tenant_section) in tenant_sections.iter() {
+        let tenant_section = tenant_section.as_table().ok_or_else(|| {
+            Error::config(format!(
+                "Section 'tenants.{tenant_name}' has wrong type (expected table)."
+            ))
+        })?;
+
+        let config_file = tenant_section
+            .get("config_file")
+            .ok_or_else(|| Error::config(format!("Missing field 'config_file' for tenant '{tenant_name}'.")))?
+            .as_str()
+            .ok_or_else(|| Error::config(format!("Field 'config_file' for tenant '{tenant_name}' has wrong type (expected string).")))?;
+        let domains = tenant_section
+            .get("domains")
+            .ok_or_else(|| Error::config(format!("Missing field 'domains' for tenant '{tenant_name}'.")))?
+            .as_array()
+            .filter(|domains| !domains.is_empty())
+            .ok_or_else(|| Error::config(format!("Field 'domains' for tenant '{tenant_name}' has wrong type (expected non-empty array).")))?
+            .iter()
+            .map(|domain| {
+                domain.as_str().map(str::to_string).ok_or_else(|| {
+                    Error::config(format!("Field 'domains' for tenant '{tenant_name}' contains a value with wrong type (expected string)."))
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut tenant = Tenant::new(tenant_name, domains, config_file);
+        for domain in tenant.domains.iter() {
+            if let Some((other, _)) = tenants
+                .iter()
+                .find(|(other, _)| other.domains.contains(domain))
+            {
+                return Err(Error::config(format!(
+                    "The domain '{domain}' is assigned to the tenants '{}' and '{tenant_name}'.",
+                    other.name
+                )));
+            }
+        }
+        if let Some(state_dir) = tenant_section.get("state_dir") {
+            tenant.set_state_dir(state_dir.as_str()
+                .ok_or_else(|| Error::config(format!("Field 'state_dir' for tenant '{tenant_name}' has wrong type (expected string).")))?);
+        }
+        if let Some(max) = tenant_section.get("max_emails_per_minute") {
+            tenant.set_max_emails_per_minute(max.as_integer()
+                .and_then(|n| u32::try_from(n).ok())
+                .filter(|n| *n > 0)
+                .ok_or_else(|| Error::config(format!("Field 'max_emails_per_minute' for tenant '{tenant_name}' has wrong type (expected positive integer).")))?);
+        }
+        if let Some(shared_state) = shared_state {
+            tenant.set_shared_state(shared_state.clone());
+        }
+
+        // Read the config file of the tenant:
+        let tenant_cfg = match toml::from_str(&std::fs::read_to_string(&tenant.config_file)?)
+            .map_err(|e| {
+                Error::config(format!(
+                    "Could not parse config file of tenant '{tenant_name}': {}",
+                    e
+                ))
+            })? {
+            toml::Value::Table(map) => map,
+            _ => {
+                return Err(Error::config(format!(
+                    "Could not parse config file of tenant '{tenant_name}': Root Value not a Table."
+                )))
+            }
+        };
+        for path in schema::unknown_tenant_fields(&tenant_cfg) {
+            warnings.push(format!(
+                "Unknown field '{}' in config file of tenant '{}' is ignored.",
+                path, tenant_name
+            ));
+        }
+        if let Some(default_path) = tenant_cfg.get("default_path") {
+            tenant.default_path = Some(tenant.resolve_path(default_path.as_str()
+                .ok_or_else(|| Error::config(format!("Value of field 'default_path' in config file of tenant '{tenant_name}' has wrong type (expected string).")))?));
+        }
+        tenants.push((Arc::new(tenant), tenant_cfg));
+    }
+    Ok(tenants)
+}
+
+/// Loads the password or token in the field `field` of a destination or from the file in the field '<field>_file' and
+/// registers it in the secret store of the config, so it can be rotated through the control socket. Returns None, if
+/// neither field is given.
+fn load_secret(
+    dest_name: &str,
+    dest_section: &Table,
+    field: &str,
+    config: &Config,
+    tenant: Option<&Tenant>,
+) -> Result<Option<Secret>, Error> {
+    let file_field = format!("{field}_file");
+    let name = match tenant {
+        Some(tenant) => format!("{}/{dest_name}.{field}", tenant.name),
+        None => format!("{dest_name}.{field}"),
+    };
+    let secret = match (dest_section.get(field), dest_section.get(&file_field)) {
+        (Some(_), Some(_)) => return Err(Error::config(format!("Destination '{dest_name}' may only have one of the fields '{field}' and '{file_field}'."))),
+        (Some(value), None) => {
+            let value = value.as_str()
+                .ok_or_else(|| Error::config(format!("Field '{field}' for destination '{dest_name}' has wrong type (expected string).")))?;
+            config.secrets.get_or_load(&name, || Ok(Secret::from(value)))?
+        }
+        (None, Some(path)) => {
+            let path = path.as_str()
+                .ok_or_else(|| Error::config(format!("Field '{file_field}' for destination '{dest_name}' has wrong type (expected string).")))?;
+            let path = match tenant {
+                Some(tenant) => tenant.resolve_path(path),
+                None => PathBuf::from(path),
+            };
+            config.secrets.get_or_load(&name, || Secret::from_file(path))?
+        }
+        (None, None) => return Ok(None),
+    };
+    Ok(Some(secret))
+}
+
+/// Returns the section 'destinations.<dest_name>', that the mapping refers to.
+fn destination_section<'a>(
+    destination_sections: Option<&'a Table>,
+    mapping_name: &str,
+    dest_name: &str,
+) -> Result<&'a Table, Error> {
+    destination_sections
+        .and_then(|sections| sections.get(dest_name))
+        .ok_or_else(|| {
+            Error::config(format!(
+                "Mapping '{mapping_name}' refers to unknown destination '{dest_name}'."
+            ))
+        })?
+        .as_table()
+        .ok_or_else(|| {
+            Error::config(format!(
+                "Section 'destinations.{dest_name}' has wrong type (expected table)."
+            ))
+        })
+}
+
+/// Creates the destination described by the section 'destinations.<dest_name>'.
+async fn load_destination(
+    dest_name: &str,
+    dest_section: &Table,
+    config: &Config,
+    tenant: Option<&Tenant>,
+    format: Option<FileFormat>,
+) -> Result<Box<dyn EmailDestination + Send + Sync>, Error> {
+    // Relative paths in the config file of a tenant are within its state directory:
+    let resolve_path = |path: &str| match tenant {
+        Some(tenant) => tenant.resolve_path(path),
+        None => PathBuf::from(path),
+    };
+    // Get the proxy for outbound connections, if any:
+    let proxy = match dest_section.get("proxy") {
+        Some(proxy) => Some(Arc::new(proxy.as_str()
+            .ok_or_else(|| Error::config(format!("Field 'proxy' for destination '{dest_name}' has wrong type (expected string).")))?
+            .parse::<Proxy>()?)),
+        None => config.proxy.clone(),
+    };
+    let dest_type = dest_section
+        .get("type")
+        .ok_or_else(|| {
+            Error::config(format!(
+                "Destination '{dest_name}' is missing 'type' field."
+            ))
+        })?
+        .as_str()
+        .ok_or_else(|| {
+            Error::config(format!(
+                "Field 'type' for destination '{dest_name}' has wrong type (expected string)."
+            ))
+        })?;
+    if format.is_some() && dest_type != "file" {
+        return Err(Error::config(format!(
+            "Field 'dest_format' requires a file destination, but '{dest_name}' has type '{dest_type}'."
+        )));
+    }
+    let destination: Box<dyn EmailDestination + Send + Sync> = match dest_type {
+        "matrix" => {
+            // Create matrix destination:
+
+            let matrix_homeserver = dest_section
+                .get("homeserver")
+                .ok_or_else(|| Error::config(format!("Missing field 'homeserver' for destination '{dest_name}'.")))?;
+            // Onion services can only be reached through a proxy, that resolves their names:
+            if matrix_homeserver.as_str().is_some_and(is_onion)
+                && !proxy.as_ref().is_some_and(|proxy| proxy.resolves_for_http_clients())
+            {
+                return Err(Error::config(format!("The homeserver of destination '{dest_name}' is an onion service, which requires a 'proxy' with the scheme socks5h or http.")));
+            }
+            let matrix_homeserver = matrix_homeserver.as_str()
+                .ok_or_else(|| Error::config(format!("Field 'homeserver' for destination '{dest_name}' has wrong type (expected string).")))?;
+            let session_file_path;
+            // Keep the state and the encryption keys in a store, if one is given:
+            let mut dest_builder = match dest_section.get("store_path") {
+                Some(store_path) => {
+                    let store_path = resolve_path(store_path.as_str()
+                        .ok_or_else(|| Error::config(format!("Field 'store_path' for destination '{dest_name}' has wrong type (expected string).")))?);
+                    // The keys in the store belong to one device, which a new login would replace:
+                    if !["session_file", "access_token", "access_token_file"].iter().any(|field| dest_section.contains_key(*field)) {
+                        return Err(Error::config(format!("Destination '{dest_name}' has a 'store_path', which requires a 'session_file' or 'access_token'.")));
+                    }
+                    let passphrase = load_secret(dest_name, dest_section, "store_passphrase", config, tenant)?;
+                    MatrixDestBuilder::with_store(
+                        matrix_homeserver,
+                        proxy.as_deref(),
+                        &store_path,
+                        passphrase.map(|passphrase| passphrase.get()).as_deref(),
+                    ).await?
+                }
+                None => MatrixDestBuilder::with_proxy(matrix_homeserver, proxy.as_deref()).await?,
+            };
+            // Set session file path, if given:
+            if let Some(path) = dest_section.get("session_file") {
+                session_file_path = resolve_path(
+                    path.as_str()
+                        .ok_or_else(|| Error::config(format!("Field 'session_file' for destination '{dest_name}' has wrong type (expected string).")))?
+                );
+                dest_builder.set_session_path(&session_file_path);
+            }
+            // Set login data, if given:
+            if let Some(username) = dest_section.get("username") {
+                let username = username.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'username' for destination '{dest_name}' has wrong type (expected string).")))?;
+                let password = load_secret(dest_name, dest_section, "password", config, tenant)?
+                    .ok_or_else(|| Error::config(format!("Expected a field 'password' or 'password_file', because the field 'username' was present in destination '{dest_name}'.")))?;
+                dest_builder.set_login(username, password);
+            }
+            // Set the pre-provisioned access token, if given:
+            if let Some(access_token) = load_secret(dest_name, dest_section, "access_token", config, tenant)? {
+                if dest_section.contains_key("username") {
+                    return Err(Error::config(format!("Destination '{dest_name}' may only have one of the fields 'username' and 'access_token'.")));
+                }
+                dest_builder.set_access_token(access_token);
+            }
+            // Act as application service, if its token is given:
+            if let Some(token) = load_secret(dest_name, dest_section, "appservice_token", config, tenant)? {
+                if dest_section.contains_key("username") || dest_section.contains_key("access_token") || dest_section.contains_key("access_token_file") {
+                    return Err(Error::config(format!("Destination '{dest_name}' may only have one of the fields 'username', 'access_token' and 'appservice_token'.")));
+                }
+                let sender = UserId::parse(dest_section.get("appservice_sender")
+                    .ok_or_else(|| Error::config(format!("Expected a field 'appservice_sender', because the field 'appservice_token' was present in destination '{dest_name}'.")))?
+                    .as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'appservice_sender' for destination '{dest_name}' has wrong type (expected string).")))?)
+                    .map_err(|e| Error::config(format!("Could not parse Matrix user id for destination '{dest_name}': {}", e)))?;
+                let user_prefix = match dest_section.get("appservice_user_prefix") {
+                    Some(user_prefix) => user_prefix.as_str()
+                        .ok_or_else(|| Error::config(format!("Field 'appservice_user_prefix' for destination '{dest_name}' has wrong type (expected string).")))?,
+                    None => DEFAULT_USER_PREFIX,
+                };
+                dest_builder.set_appservice(token, sender, user_prefix);
+            }
+            // Set room ID or create rooms per thread or sender:
+            match (dest_section.get("room_id"), dest_section.get("room_per")) {
+                (Some(room_id), None) => {
+                    let room_id = RoomId::parse(room_id.as_str()
+                        .ok_or_else(|| Error::config(format!("Field 'room_id' for destination '{dest_name}' has wrong type (expected string).")))?)
+                        .map_err(|e| Error::config(format!("Could not parse Matrix room id for destination '{dest_name}': {}", e)))?;
+                    dest_builder.set_room_id(room_id);
+                }
+                (None, Some(room_per)) => {
+                    let room_per: RoomPer = room_per.as_str()
+                        .ok_or_else(|| Error::config(format!("Field 'room_per' for destination '{dest_name}' has wrong type (expected string).")))?
+                        .parse()?;
+                    let path = resolve_path(dest_section.get("room_index")
+                        .ok_or_else(|| Error::config(format!("Expected a field 'room_index', because the field 'room_per' was present in destination '{dest_name}'.")))?
+                        .as_str()
+                        .ok_or_else(|| Error::config(format!("Field 'room_index' for destination '{dest_name}' has wrong type (expected string).")))?);
+                    let index = RoomIndex::open(&path)
+                        .map_err(|e| Error::config(format!("Could not open room index '{}' of destination '{dest_name}': {}", path.display(), e)))?;
+                    let mut room_creation = RoomCreation::new(room_per, index);
+                    if let Some(room_name) = dest_section.get("room_name") {
+                        room_creation.name = room_name.as_str()
+                            .ok_or_else(|| Error::config(format!("Field 'room_name' for destination '{dest_name}' has wrong type (expected string).")))?
+                            .to_string();
+                    }
+                    if let Some(invite) = dest_section.get("invite") {
+                        room_creation.invite = invite.as_array()
+                            .ok_or_else(|| Error::config(format!("Field 'invite' for destination '{dest_name}' has wrong type (expected array of strings).")))?
+                            .iter()
+                            .map(|user_id| {
+                                let user_id = user_id.as_str()
+                                    .ok_or_else(|| Error::config(format!("Field 'invite' for destination '{dest_name}' has wrong type (expected array of strings).")))?;
+                                UserId::parse(user_id)
+                                    .map_err(|e| Error::config(format!("Could not parse Matrix user id '{user_id}' for destination '{dest_name}': {}", e)))
+                            })
+                            .collect::<Result<_, _>>()?;
+                    }
+                    dest_builder.set_room_creation(room_creation);
+                }
+                (Some(_), Some(_)) => return Err(Error::config(format!("Destination '{dest_name}' may only have one of the fields 'room_id' and 'room_per'."))),
+                (None, None) => return Err(Error::config(format!("Missing field 'room_id' for destination '{dest_name}'."))),
+            }
+            // Set language of the notifications, if given:
+            if let Some(locale) = dest_section.get("locale") {
+                dest_builder.set_locale(locale.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'locale' for destination '{dest_name}' has wrong type (expected string).")))?
+                    .parse()?);
+            }
+            // Set severity rules, if given:
+            if let Some(rules) = dest_section.get("severity_rules") {
+                dest_builder.set_classifier(load_severity_rules(rules)?);
+            }
+            // Redact the bodies, if given:
+            if let Some(redact) = dest_section.get("redact") {
+                dest_builder.set_redactor(load_redaction(redact)?);
+            }
+            // Post HTML bodies as formatted messages, if enabled:
+            if let Some(html_messages) = dest_section.get("html_messages") {
+                dest_builder.set_html_messages(html_messages.as_bool()
+                    .ok_or_else(|| Error::config(format!("Field 'html_messages' for destination '{dest_name}' has wrong type (expected boolean).")))?);
+            }
+            // Upload attachments up to the size limit, if enabled:
+            let upload_attachments = match dest_section.get("upload_attachments") {
+                Some(val) => val.as_bool()
+                    .ok_or_else(|| Error::config(format!("Field 'upload_attachments' for destination '{dest_name}' has wrong type (expected boolean).")))?,
+                None => false,
+            };
+            let max_size = match dest_section.get("max_attachment_size_kb") {
+                Some(_) if !upload_attachments => {
+                    return Err(Error::config(format!("Field 'max_attachment_size_kb' for destination '{dest_name}' requires 'upload_attachments = true'.")));
+                }
+                Some(val) => val.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .and_then(|n| n.checked_mul(1024))
+                    .ok_or_else(|| Error::config(format!("Field 'max_attachment_size_kb' for destination '{dest_name}' has wrong type (expected non-negative integer).")))?,
+                None => DEFAULT_MAX_UPLOAD_SIZE,
+            };
+            if upload_attachments {
+                dest_builder.set_upload_attachments(max_size);
+            }
+            // Track conversations, if an index file is given:
+            if let Some(path) = dest_section.get("thread_index") {
+                let path = resolve_path(path.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'thread_index' for destination '{dest_name}' has wrong type (expected string).")))?);
+                dest_builder.set_thread_index(ThreadIndex::open(&path)
+                    .map_err(|e| Error::config(format!("Could not open thread index '{}' of destination '{dest_name}': {}", path.display(), e)))?);
+            }
+
+            Box::new(dest_builder.build().await?)
+        }
+        "relay" => {
+            // Create relay destination:
+
+            let relay_port = match dest_section.get("port") {
+                Some(port) => port.as_integer()
+                    .and_then(|p| u16::try_from(p).ok())
+                    .ok_or_else(|| Error::config(format!("Field 'port' for destination '{dest_name}' has wrong type (expected port number).")))?,
+                None => 25,
+            };
+            let relay_host = match dest_section.get("host") {
+                Some(host) => Some(host.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'host' for destination '{dest_name}' has wrong type (expected string).")))?
+                    .to_string()),
+                None => None,
+            };
+            if relay_host.as_deref().is_some_and(is_onion) && proxy.is_none() {
+                return Err(Error::config(format!("The host of destination '{dest_name}' is an onion service, which requires a 'proxy'.")));
+            }
+            let mut destination = RelayDestination::new(
+                relay_host,
+                relay_port,
+                dest_section.get("recipient")
+                    .ok_or_else(|| Error::config(format!("Missing field 'recipient' for destination '{dest_name}'.")))?
+                    .as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'recipient' for destination '{dest_name}' has wrong type (expected string).")))?,
+            )?;
+            destination.set_resolver(config.resolver.get()?);
+            destination.set_hostname(&config.hostname);
+            if let Some(max_hops) = dest_section.get("max_hops") {
+                destination.set_max_hops(max_hops.as_integer()
+                    .and_then(|n| usize::try_from(n).ok())
+                    .ok_or_else(|| Error::config(format!("Field 'max_hops' for destination '{dest_name}' has wrong type (expected non-negative integer).")))?);
+            }
+            if let Some(proxy) = proxy {
+                destination.set_proxy(proxy);
+            }
+            if let Some(sender) = dest_section.get("sender") {
+                destination.set_sender(sender.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'sender' for destination '{dest_name}' has wrong type (expected string).")))?);
+            }
+            if let Some(helo_name) = dest_section.get("helo_name") {
+                destination.set_helo_name(helo_name.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'helo_name' for destination '{dest_name}' has wrong type (expected string).")))?);
+            }
+            if let Some(source_addr) = dest_section.get("source_address") {
+                destination.set_source_addr(source_addr.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'source_address' for destination '{dest_name}' has wrong type (expected string).")))?
+                    .parse::<IpAddr>()
+                    .map_err(|e| Error::config(format!("Could not parse 'source_address' for destination '{dest_name}': {}", e)))?);
+            }
+            let starttls = match dest_section.get("starttls") {
+                Some(starttls) => Some(starttls.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'starttls' for destination '{dest_name}' has wrong type (expected string).")))?
+                    .parse::<StartTls>()?),
+                None => None,
+            };
+            if let Some(starttls) = starttls {
+                destination.set_starttls(starttls);
+            }
+            if let Some(username) = dest_section.get("username") {
+                // The credentials must not be sent unencrypted or to a relay with an unverified certificate:
+                match starttls {
+                    None => destination.set_starttls(StartTls::Required),
+                    Some(StartTls::Required) => {}
+                    Some(_) => return Err(Error::config(format!("Destination '{dest_name}' authenticates with 'username', so 'starttls' has to be \"required\"."))),
+                }
+                let username = username.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'username' for destination '{dest_name}' has wrong type (expected string).")))?;
+                let password = load_secret(dest_name, dest_section, "password", config, tenant)?
+                    .ok_or_else(|| Error::config(format!("Expected a field 'password' or 'password_file', because the field 'username' was present in destination '{dest_name}'.")))?;
+                destination.set_login(username, password);
+            }
+            let mut pool_config = PoolConfig::default();
+            if let Some(max_idle) = dest_section.get("pool_size") {
+                pool_config.max_idle = max_idle.as_integer()
+                    .and_then(|n| usize::try_from(n).ok())
+                    .ok_or_else(|| Error::config(format!("Field 'pool_size' for destination '{dest_name}' has wrong type (expected non-negative integer).")))?;
+            }
+            if let Some(timeout) = dest_section.get("pool_idle_timeout") {
+                pool_config.idle_timeout = Duration::from_secs(timeout.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .ok_or_else(|| Error::config(format!("Field 'pool_idle_timeout' for destination '{dest_name}' has wrong type (expected non-negative integer).")))?);
+            }
+            if let Some(lifetime) = dest_section.get("pool_max_lifetime") {
+                pool_config.max_lifetime = Duration::from_secs(lifetime.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .ok_or_else(|| Error::config(format!("Field 'pool_max_lifetime' for destination '{dest_name}' has wrong type (expected non-negative integer).")))?);
+            }
+            destination.set_pool_config(pool_config);
+
+            Box::new(destination)
+        }
+        "file" => {
+            // Create file destination:
+
+            let path = dest_section
+                .get("path")
+                .ok_or_else(|| Error::config(format!("Missing field 'path' for destination '{dest_name}'.")))?;
+            let format = format.unwrap_or(FileFormat::Flat);
+            let mut destination = FileDestination::with_format(
+                resolve_path(
+                    path.as_str()
+                        .ok_or_else(|| Error::config(format!("Field 'path' for destination '{dest_name}' has wrong type (expected string).")))?
+                ),
+                format.clone(),
+            )?;
+            if let Some(store_attachments) = dest_section.get("store_attachments") {
+                let store_attachments = store_attachments.as_bool()
+                    .ok_or_else(|| Error::config(format!("Field 'store_attachments' for destination '{dest_name}' has wrong type (expected boolean).")))?;
+                if store_attachments {
+                    let store = config.attachment_store.as_ref()
+                        .ok_or_else(|| Error::config(format!("Destination '{dest_name}' stores attachments, but there is no 'attachment_store' section.")))?;
+                    if format == FileFormat::Mbox {
+                        return Err(Error::config(format!("Destination '{dest_name}' can't store attachments, because it is an mbox.")));
+                    }
+                    destination.set_attachment_store(store.clone());
+                }
+            }
+            Box::new(destination)
+        }
+        "lmtp" => {
+            // Create LMTP destination:
+
+            let address = dest_section
+                .get("address")
+                .ok_or_else(|| Error::config(format!("Missing field 'address' for destination '{dest_name}'.")))?
+                .as_str()
+                .ok_or_else(|| Error::config(format!("Field 'address' for destination '{dest_name}' has wrong type (expected string).")))?
+                .parse::<LmtpAddress>()?;
+            let mut destination = LmtpDestination::new(address);
+            destination.set_lhlo_name(&config.hostname);
+            if let Some(recipients) = dest_section.get("recipients") {
+                destination.set_recipients(recipients.as_array()
+                    .and_then(|recipients| recipients.iter().map(|r| r.as_str().map(String::from)).collect::<Option<Vec<_>>>())
+                    .ok_or_else(|| Error::config(format!("Field 'recipients' for destination '{dest_name}' has wrong type (expected array of strings).")))?);
+            }
+            if let Some(lhlo_name) = dest_section.get("lhlo_name") {
+                destination.set_lhlo_name(lhlo_name.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'lhlo_name' for destination '{dest_name}' has wrong type (expected string).")))?);
+            }
+            Box::new(destination)
+        }
+        "webhook" => {
+            // Create webhook destination:
+
+            let url = dest_section
+                .get("url")
+                .ok_or_else(|| Error::config(format!("Missing field 'url' for destination '{dest_name}'.")))?
+                .as_str()
+                .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+                .ok_or_else(|| Error::config(format!("Field 'url' for destination '{dest_name}' has wrong type (expected URL).")))?;
+            let mut destination = WebhookDestination::new(url);
+            if let Some(token) = load_secret(dest_name, dest_section, "bearer_token", config, tenant)? {
+                destination.set_bearer_token(token);
+            }
+            if let Some(retries) = dest_section.get("retries") {
+                destination.set_retries(retries.as_integer()
+                    .and_then(|n| u32::try_from(n).ok())
+                    .ok_or_else(|| Error::config(format!("Field 'retries' for destination '{dest_name}' has wrong type (expected non-negative integer).")))?);
+            }
+            if let Some(timeout) = dest_section.get("timeout_secs") {
+                destination.set_timeout(timeout.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| Error::config(format!("Field 'timeout_secs' for destination '{dest_name}' has wrong type (expected positive integer).")))?)?;
+            }
+            Box::new(destination)
+        }
+        "discord" => {
+            // Create Discord destination:
+
+            let url = dest_section
+                .get("webhook_url")
+                .ok_or_else(|| Error::config(format!("Missing field 'webhook_url' for destination '{dest_name}'.")))?
+                .as_str()
+                .filter(|url| url.starts_with("https://"))
+                .ok_or_else(|| Error::config(format!("Field 'webhook_url' for destination '{dest_name}' has wrong type (expected HTTPS URL).")))?;
+            let mut destination = DiscordDestination::new(url);
+            if let Some(username) = dest_section.get("username") {
+                destination.set_username(username.as_str()
+                    .ok_or_else(|| Error::config(format!("Field 'username' for destination '{dest_name}' has wrong type (expected string).")))?);
+            }
+            if let Some(redact) = dest_section.get("redact") {
+                destination.set_redactor(load_redaction(redact)?);
+            }
+            Box::new(destination)
+        }
+        "mqtt" => {
+            // Create MQTT destination:
+
+            let get_str = |key: &str| -> Result<Option<&str>, Error> {
+                dest_section.get(key).map(|val| val.as_str()
+                    .ok_or_else(|| Error::config(format!("Field '{key}' for destination '{dest_name}' has wrong type (expected string)."))))
+                    .transpose()
+            };
+            let address = get_str("url")?
+                .ok_or_else(|| Error::config(format!("Missing field 'url' for destination '{dest_name}'.")))?
+                .parse()?;
+            let topic = get_str("topic")?
+                .filter(|topic| !topic.is_empty() && !topic.contains(['+', '#']))
+                .ok_or_else(|| Error::config(format!("Field 'topic' for destination '{dest_name}' is missing or contains wildcards.")))?;
+            let mut destination = MqttDestination::new(address, topic);
+            if let Some(payload) = get_str("payload")? {
+                destination.set_payload(payload.parse()?);
+            }
+            if let Some(client_id) = get_str("client_id")? {
+                destination.set_client_id(client_id);
+            }
+            match (get_str("username")?, get_str("password")?) {
+                (Some(username), Some(password)) => destination.set_credentials(username, password),
+                (None, None) => {}
+                _ => return Err(Error::config(format!("Destination '{dest_name}' needs both 'username' and 'password' or none of them."))),
+            }
+            if let Some(qos) = dest_section.get("qos") {
+                destination.set_qos(qos.as_integer()
+                    .and_then(|n| u8::try_from(n).ok())
+                    .ok_or_else(|| Error::config(format!("Field 'qos' for destination '{dest_name}' has wrong type (expected 0 or 1).")))?)?;
+            }
+            if let Some(retain) = dest_section.get("retain") {
+                destination.set_retain(retain.as_bool()
+                    .ok_or_else(|| Error::config(format!("Field 'retain' for destination '{dest_name}' has wrong type (expected boolean).")))?);
+            }
+            Box::new(destination)
+        }
+        "nats" => {
+            // Create NATS destination:
+
+            let get_str = |key: &str| -> Result<Option<&str>, Error> {
+                dest_section.get(key).map(|val| val.as_str()
+                    .ok_or_else(|| Error::config(format!("Field '{key}' for destination '{dest_name}' has wrong type (expected string)."))))
+                    .transpose()
+            };
+            let address = get_str("url")?
+                .ok_or_else(|| Error::config(format!("Missing field 'url' for destination '{dest_name}'.")))?
+                .parse()?;
+            let subject = get_str("subject")?
+                .filter(|subject| !subject.contains(char::is_whitespace) && subject.split('.').all(|token| !token.is_empty() && token != "*" && token != ">"))
+                .ok_or_else(|| Error::config(format!("Field 'subject' for destination '{dest_name}' is missing, invalid or contains wildcards.")))?;
+            let mut destination = NatsDestination::new(address, subject);
+            if let Some(payload) = get_str("payload")? {
+                destination.set_payload(payload.parse()?);
+            }
+            match (get_str("username")?, get_str("password")?, get_str("token")?) {
+                (Some(username), Some(password), None) => destination.set_credentials(username, password),
+                (None, None, Some(token)) => destination.set_token(token),
+                (None, None, None) => {}
+                _ => return Err(Error::config(format!("Destination '{dest_name}' needs either 'username' and 'password' or 'token'."))),
+            }
+            if let Some(jetstream) = dest_section.get("jetstream") {
+                destination.set_jetstream(jetstream.as_bool()
+                    .ok_or_else(|| Error::config(format!("Field 'jetstream' for destination '{dest_name}' has wrong type (expected boolean).")))?);
+            }
+            if let Some(timeout) = dest_section.get("timeout_secs") {
+                destination.set_timeout(timeout.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| Error::config(format!("Field 'timeout_secs' for destination '{dest_name}' has wrong type (expected positive integer).")))?);
+            }
+            Box::new(destination)
+        }
+        "amqp" => {
+            // Create AMQP destination:
+
+            let get_str = |key: &str| -> Result<Option<&str>, Error> {
+                dest_section.get(key).map(|val| val.as_str()
+                    .ok_or_else(|| Error::config(format!("Field '{key}' for destination '{dest_name}' has wrong type (expected string)."))))
+                    .transpose()
+            };
+            let address = get_str("url")?
+                .ok_or_else(|| Error::config(format!("Missing field 'url' for destination '{dest_name}'.")))?
+                .parse()?;
+            let routing_key = get_str("routing_key")?
+                .ok_or_else(|| Error::config(format!("Missing field 'routing_key' for destination '{dest_name}'.")))?;
+            let mut destination = AmqpDestination::new(address, get_str("exchange")?.unwrap_or_default(), routing_key);
+            if let Some(payload) = get_str("payload")? {
+                destination.set_payload(payload.parse()?);
+            }
+            if let Some(timeout) = dest_section.get("timeout_secs") {
+                destination.set_timeout(timeout.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| Error::config(format!("Field 'timeout_secs' for destination '{dest_name}' has wrong type (expected positive integer).")))?);
+            }
+            Box::new(destination)
+        }
+        "postgres" => {
+            // Create PostgreSQL destination:
+
+            let get_str = |key: &str| -> Result<Option<&str>, Error> {
+                dest_section.get(key).map(|val| val.as_str()
+                    .ok_or_else(|| Error::config(format!("Field '{key}' for destination '{dest_name}' has wrong type (expected string)."))))
+                    .transpose()
+            };
+            let address = get_str("url")?
+                .ok_or_else(|| Error::config(format!("Missing field 'url' for destination '{dest_name}'.")))?
+                .parse()?;
+            let mut destination = PostgresDestination::new(address);
+            if let Some(table) = get_str("table")? {
+                destination.set_table(table)?;
+            }
+            if let Some(pool_size) = dest_section.get("pool_size") {
+                destination.set_pool_size(pool_size.as_integer()
+                    .and_then(|n| usize::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| Error::config(format!("Field 'pool_size' for destination '{dest_name}' has wrong type (expected positive integer).")))?);
+            }
+            if let Some(timeout) = dest_section.get("timeout_secs") {
+                destination.set_timeout(timeout.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| Error::config(format!("Field 'timeout_secs' for destination '{dest_name}' has wrong type (expected positive integer).")))?);
+            }
+            Box::new(destination)
+        }
+        "redis" => {
+            // Create Redis destination:
+
+            let get_str = |key: &str| -> Result<Option<&str>, Error> {
+                dest_section.get(key).map(|val| val.as_str()
+                    .ok_or_else(|| Error::config(format!("Field '{key}' for destination '{dest_name}' has wrong type (expected string)."))))
+                    .transpose()
+            };
+            let mut client = RedisClient::new(get_str("url")?
+                .ok_or_else(|| Error::config(format!("Missing field 'url' for destination '{dest_name}'.")))?
+                .parse()?);
+            if let Some(timeout) = dest_section.get("timeout_secs") {
+                client.set_timeout(timeout.as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .map(Duration::from_secs)
+                    .ok_or_else(|| Error::config(format!("Field 'timeout_secs' for destination '{dest_name}' has wrong type (expected positive integer).")))?);
+            }
+            let key = get_str("key")?
+                .filter(|key| !key.is_empty())
+                .ok_or_else(|| Error::config(format!("Field 'key' for destination '{dest_name}' is missing or empty.")))?;
+            let mut destination = RedisDestination::new(client, key);
+            if let Some(mode) = get_str("mode")? {
+                destination.set_mode(mode.parse()?);
+            }
+            if let Some(payload) = get_str("payload")? {
+                destination.set_payload(payload.parse()?);
+            }
+            if let Some(max_len) = dest_section.get("max_len") {
+                destination.set_max_len(max_len.as_integer()
+                    .and_then(|n| usize::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| Error::config(format!("Field 'max_len' for destination '{dest_name}' has wrong type (expected positive integer).")))?);
+            }
+            Box::new(destination)
+        }
+        _ => {
+            return Err(Error::config(format!(
+                "Unknown type '{dest_type}' of destination '{dest_name}' (expected matrix, relay, file, lmtp, webhook, discord, mqtt, nats, amqp, postgres or redis)."
+            )))
+        }
+    };
+    Ok(destination)
+}
+
+/// Loads the section 'attachment_store' and opens the store.
+fn load_attachment_store(section: &toml::Value) -> Result<AttachmentStore, Error> {
+    let section = section.as_table().ok_or_else(|| {
+        Error::config("Wrong type of 'attachment_store' section in config file (expected table).")
+    })?;
+    let path = section
+        .get("path")
+        .ok_or_else(|| Error::config("Missing field 'path' in section 'attachment_store'."))?
+        .as_str()
+        .ok_or_else(|| {
+            Error::config(
+                "Field 'path' in section 'attachment_store' has wrong type (expected string).",
+            )
+        })?;
+    let min_size = match section.get("min_size_kb") {
+        Some(val) => val
+            .as_integer()
+            .and_then(|n| usize::try_from(n).ok())
+            .and_then(|n| n.checked_mul(1024))
+            .ok_or_else(|| {
+                Error::config("Field 'min_size_kb' in section 'attachment_store' has wrong type (expected non-negative integer).")
+            })?,
+        None => DEFAULT_MIN_ATTACHMENT_SIZE,
+    };
+    AttachmentStore::open(path, min_size).map_err(|e| {
+        Error::config(format!(
+            "Could not open attachment store at '{}': {}",
+            path, e
+        ))
+    })
+}
+
+/// Returns the TOML config in the value of `KUTSCHE_CONFIG`, which may be base64 encoded. Some container platforms only
+/// pass single line values, so a value, that is no valid TOML, is decoded.
+fn inline_source(value: &str) -> Result<String, Error> {
+    if value.parse::<toml::Value>().is_ok() {
+        return Ok(value.to_string());
+    }
+    let compact: String = value.split_whitespace().collect();
+    base64::decode(compact)
+        .ok()
+        .and_then(|decoded| String::from_utf8(decoded).ok())
+        .ok_or_else(|| {
+            Error::config(format!(
+                "The variable {} contains neither TOML nor base64 encoded TOML.",
+                CONFIG_ENV
+            ))
+        })
+}
+
+/// Loads the section 'shared_state' with the fields 'url' and optionally 'key_prefix' and 'timeout_secs'.
+fn load_shared_state(section: &toml::Value) -> Result<SharedState, Error> {
+    let section = section.as_table().ok_or_else(|| {
+        Error::config("Wrong type of 'shared_state' section in config file (expected table).")
+    })?;
+    let url = section
+        .get("url")
+        .ok_or_else(|| Error::config("Missing field 'url' in section 'shared_state'."))?
+        .as_str()
+        .ok_or_else(|| {
+            Error::config("Field 'url' in section 'shared_state' has wrong type (expected string).")
+        })?;
+    let mut client = RedisClient::new(url.parse()?);
+    if let Some(timeout) = section.get("timeout_secs") {
+        client.set_timeout(
+            timeout
+                .as_integer()
+                .and_then(|n| u64::try_from(n).ok())
+                .filter(|n| *n > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| {
+                    Error::config("Field 'timeout_secs' in section 'shared_state' has wrong type (expected positive integer).")
+                })?,
+        );
+    }
+    let key_prefix = match section.get("key_prefix") {
+        Some(prefix) => prefix.as_str().ok_or_else(|| {
+            Error::config(
+                "Field 'key_prefix' in section 'shared_state' has wrong type (expected string).",
+            )
+        })?,
+        None => DEFAULT_SHARED_STATE_PREFIX,
+    };
+    Ok(SharedState::new(client, key_prefix))
+}
+
+/// Loads the `i`th entry of the 'rules' field of a mapping: A table with the name of a destination and at least one of
+/// the conditions 'from_domain', 'subject', 'has_attachment' and 'list_id'.
+fn load_routing_rule<'a>(
+    rule: &'a toml::Value,
+    mapping_name: &str,
+    i: usize,
+) -> Result<(RoutingRule, &'a str), Error> {
+    let rule = rule.as_table().ok_or_else(|| {
+        Error::config(format!(
+            "Rule {i} of mapping '{mapping_name}' has wrong type (expected table)."
+        ))
+    })?;
+    let get_str = |key: &str| -> Result<Option<&'a str>, Error> {
+        rule.get(key)
+            .map(|val| {
+                val.as_str().ok_or_else(|| {
+                    Error::config(format!(
+                        "Field '{key}' of rule {i} of mapping '{mapping_name}' has wrong type (expected string)."
+                    ))
+                })
+            })
+            .transpose()
+    };
+    let get_regex = |key: &str| -> Result<Option<Regex>, Error> {
+        get_str(key)?
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|e| {
+                    Error::config(format!(
+                        "Invalid pattern in field '{key}' of rule {i} of mapping '{mapping_name}': {e}"
+                    ))
+                })
+            })
+            .transpose()
+    };
+    let dest_name = get_str("destination")?.ok_or_else(|| {
+        Error::config(format!(
+            "Rule {i} of mapping '{mapping_name}' is missing the field 'destination'."
+        ))
+    })?;
+    let routing_rule = RoutingRule {
+        from_domain: get_str("from_domain")?.map(str::to_lowercase),
+        subject: get_regex("subject")?,
+        has_attachment: rule
+            .get("has_attachment")
+            .map(|val| {
+                val.as_bool().ok_or_else(|| {
+                    Error::config(format!(
+                        "Field 'has_attachment' of rule {i} of mapping '{mapping_name}' has wrong type (expected boolean)."
+                    ))
+                })
+            })
+            .transpose()?,
+        list_id: get_regex("list_id")?,
+    };
+    if routing_rule.is_empty() {
+        return Err(Error::config(format!(
+            "Rule {i} of mapping '{mapping_name}' has no condition."
+        )));
+    }
+    Ok((routing_rule, dest_name))
+}
+
+/// Loads the value of a 'severity_rules' field: An array of tables with the fields 'pattern', 'severity' and optionally
+/// 'emoji', 'color', 'listeners' and 'bcc'.
+fn load_severity_rules(rules: &toml::Value) -> Result<SeverityClassifier, Error> {
+    let mut classifier = SeverityClassifier::new();
+    for rule in rules
+        .as_array()
+        .ok_or_else(|| Error::config("Field 'severity_rules' has wrong type (expected array)."))?
+    {
+        let rule = rule.as_table().ok_or_else(|| {
+            Error::config(
+                "Field 'severity_rules' contains a value with wrong type (expected table).",
+            )
+        })?;
+        let get_str = |key: &str| -> Result<Option<&str>, Error> {
+            rule.get(key)
+                .map(|val| {
+                    val.as_str().ok_or_else(|| {
+                        Error::config(format!(
+                            "Field '{}' of a severity rule has wrong type (expected string).",
+                            key
+                        ))
+                    })
+                })
+                .transpose()
+        };
+        let pattern = get_str("pattern")?
+            .ok_or_else(|| Error::config("Severity rule is missing the field 'pattern'."))?;
+        let severity = get_str("severity")?
+            .ok_or_else(|| Error::config("Severity rule is missing the field 'severity'."))?
+            .parse()?;
+        let mut listeners = vec![];
+        if let Some(addrs) = rule.get("listeners") {
+            let addrs = addrs.as_array().ok_or_else(|| {
+                Error::config(
+                    "Field 'listeners' of a severity rule has wrong type (expected array).",
+                )
+            })?;
+            for addr in addrs {
+                let addr = addr.as_str().ok_or_else(|| {
+                    Error::config("Field 'listeners' of a severity rule contains a value with wrong type (expected string).")
+                })?;
+                listeners.extend(addr.to_socket_addrs().map_err(|_| {
+                    Error::config(format!(
+                        "Could not resolve listener '{}' of a severity rule.",
+                        addr
+                    ))
+                })?);
+            }
+        }
+        let bcc = match rule.get("bcc") {
+            Some(val) => val.as_bool().ok_or_else(|| {
+                Error::config("Field 'bcc' of a severity rule has wrong type (expected boolean).")
+            })?,
+            None => false,
+        };
+        classifier.add_rule(
+            pattern,
+            severity,
+            get_str("emoji")?.map(String::from),
+            get_str("color")?.map(String::from),
+            listeners,
+            bcc,
+        )?;
+    }
+    Ok(classifier)
+}
+
+/// Loads the value of a 'redact' field: A table with the optional fields 'addresses' and 'phone_numbers' ("partial" or
+/// "full") and 'patterns', an array of tables with the fields 'pattern' and 'replacement'.
+fn load_redaction(redact: &toml::Value) -> Result<Redactor, Error> {
+    let redact = redact
+        .as_table()
+        .ok_or_else(|| Error::config("Field 'redact' has wrong type (expected table)."))?;
+    let mut redactor = Redactor::new();
+    let masking = |key: &str| -> Result<Option<Masking>, Error> {
+        redact
+            .get(key)
+            .map(|val| {
+                val.as_str()
+                    .ok_or_else(|| {
+                        Error::config(format!(
+                            "Field '{}' of 'redact' has wrong type (expected string).",
+                            key
+                        ))
+                    })?
+                    .parse()
+            })
+            .transpose()
+    };
+    if let Some(masking) = masking("addresses")? {
+        redactor.set_addresses(masking);
+    }
+    if let Some(masking) = masking("phone_numbers")? {
+        redactor.set_phone_numbers(masking);
+    }
+    if let Some(patterns) = redact.get("patterns") {
+        for pattern in patterns.as_array().ok_or_else(|| {
+            Error::config("Field 'patterns' of 'redact' has wrong type (expected array).")
+        })? {
+            let get_str = |key: &str| {
+                pattern.get(key).and_then(toml::Value::as_str).ok_or_else(|| {
+                    Error::config(format!(
+                        "Redaction pattern is missing the field '{}' or it has wrong type (expected string).",
+                        key
+                    ))
+                })
+            };
+            redactor.add_pattern(get_str("pattern")?, get_str("replacement")?)?;
+        }
+    }
+    Ok(redactor)
+}
+
+// We only use this struct to circumvent rusts rules for implementing foreign traits on foreign types.
+// We cannot directly implement TryFrom<Table> for ServerConfig.
+struct TlsConfig(ServerConfig);
+impl From<TlsConfig> for Arc<ServerConfig> {
+    fn from(conf: TlsConfig) -> Self {
+        Arc::new(conf.0)
+    }
+}
+impl TryFrom<&Table> for TlsConfig {
+    type Error = Error;
+
+    fn try_from(cert_section: &Table) -> Result<Self, Self::Error> {
+        let mut resolver = CertResolver::new();
+
+        for domain in cert_section.keys() {
+            // Get configured paths:
+            let domain_cert_obj = cert_section[domain]
+				.as_table()
+				.ok_or_else(|| Error::config(format!("Value for domain {} in 'certificates' section has wrong type (expected table).", domain)))?;
+            let cert_file_path = domain_cert_obj
+				.get("cert_file")
+				.ok_or_else(|| Error::config(format!("Missing field 'cert_file' for domain {}.", domain)))?
+				.as_str()
+				.ok_or_else(|| Error::config(format!("Value for field 'cert_file' for domain {} in 'certificates' section has wrong type (expected string).", domain)))?;
+            let key_file_path = domain_cert_obj
+				.get("private_key_file")
+				.ok_or_else(|| Error::config(format!("Missing field 'private_key_file' for domain {}.", domain)))?
+				.as_str()
+				.ok_or_else(|| Error::config(format!("Value for field 'private_key_file' for domain {} in 'certificates' section has wrong type (expected string).", domain)))?;
+
+            // Read certificates:
+            let cert_file = File::open(cert_file_path)?;
+            let mut reader = BufReader::new(cert_file);
+            let certs = read_all(&mut reader)?
+                .into_iter()
+                .filter_map(|item| {
+                    if let Item::X509Certificate(raw) = item {
+                        Some(Certificate(raw))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+
+            // Read private key:
+            let key_file = File::open(key_file_path)?;
+            let mut reader = BufReader::new(key_file);
+            let priv_key_signer =
+                if let Some(Item::RSAKey(raw) | Item::PKCS8Key(raw) | Item::ECKey(raw)) =
+                    read_one(&mut reader)?
+                {
+                    rustls::sign::any_supported_type(&PrivateKey(raw)).map_err(|e| {
+                        Error::config(format!(
+                            "Could not sign with private key given for domain {}: {}",
+                            domain, e
+                        ))
+                    })?
+                } else {
+                    return Err(Error::config(format!(
+                        "Could not read key from {} given by 'private_key_file'.",
+                        key_file_path
+                    )));
+                };
+
+            resolver.add_domain(
+                domain.to_string(),
+                CertifiedKey::new(certs, priv_key_signer),
+            );
+        }
+
+        Ok(Self(
+            ServerConfig::builder()
+                .with_safe_defaults()
+                .with_no_client_auth()
+                .with_cert_resolver(Arc::new(resolver)),
+        ))
+    }
+}
+
+pub(crate) struct CertResolver {
+    domain_cert_map: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub(crate) fn new() -> Self {
+        CertResolver {
+            domain_cert_map: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn add_domain(&mut self, domain: String, cert: CertifiedKey) {
+        self.domain_cert_map.insert(domain, Arc::new(cert));
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        if let Some(domain) = client_hello.server_name() {
+            self.domain_cert_map.get(domain).cloned()
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            effective_user: None,
+            effective_group: None,
+            local_addrs: "127.0.0.1:25".to_socket_addrs().unwrap().collect(),
+            bind_retry: Duration::ZERO,
+            bind_hosts: vec![],
+            hidden_service_addrs: vec![],
+            loop_checks: vec![],
+            greetings: vec![],
+            delayed_deliveries: vec![],
+            allow_from: vec![],
+            socket_options: vec![],
+            test_listeners: vec![],
+            bind_recheck: Duration::from_secs(300),
+            hostname: "localhost".to_string(),
+            default_path: None,
+            state_dir: None,
+            control_socket: None,
+            api_tokens: vec![],
+            secrets: SecretStore::default(),
+            audit_log: None,
+            audit_log_sign_interval: None,
+            ha_node: None,
+            ha_lease: Duration::from_secs(30),
+            attachment_store: None,
+            min_free_space: 0,
+            memory_budget: None,
+            max_message_size: None,
+            dest_map: AddressMatcher::new(),
+            header_mappings: vec![],
+            dead_letter: None,
+            archive: None,
+            delivery_workers: 1,
+            dedupe_per_destination: false,
+            shutdown_grace: Duration::ZERO,
+            tls_config: None,
+            tls_domains: vec![],
+            logging: LoggingConfig::default(),
+            proxy: None,
+            resolver: SharedResolver::new(DnsConfig::default()),
+            tenants: vec![],
+            self_test: None,
+            statsd: None,
+            warnings: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inline_source() {
+        let toml = "hostname = \"mail.example.com\"\n[mappings]\n";
+        assert_eq!(inline_source(toml).unwrap(), toml);
+        let encoded = base64::encode(toml);
+        assert_eq!(inline_source(&encoded).unwrap(), toml);
+        // Line breaks of tools like base64(1) are ignored:
+        let wrapped = format!("{}\n{}\n", &encoded[..20], &encoded[20..]);
+        assert_eq!(inline_source(&wrapped).unwrap(), toml);
+        assert!(inline_source("hostname = ").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_fan_out() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("archive")).unwrap();
+        std::fs::create_dir(dir.path().join("flat")).unwrap();
+        let toml = format!(
+            "[mappings.alerts]\n\
+address = \"alerts@example.com\"\n\
+destination = [\"archive\", \"flat\"]\n\
+[destinations.archive]\n\
+type = \"file\"\n\
+path = \"{0}/archive\"\n\
+[destinations.flat]\n\
+type = \"file\"\n\
+path = \"{0}/flat\"\n",
+            dir.path().display()
+        );
+        let config = Config::parse(&toml).await.unwrap();
+        let mapping = config.dest_map.get("alerts@example.com").unwrap();
+        assert_eq!(
+            mapping.destination.storage_path(),
+            Some(dir.path().join("archive").as_path())
+        );
+        assert!(mapping.destination.describe().contains(" and "));
+
+        let empty = toml.replace("[\"archive\", \"flat\"]", "[]");
+        assert!(Config::parse(&empty).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!(
+            "dead_letter_destination = \"lost\"\n\
+[mappings.alerts]\n\
+address = \"alerts@example.com\"\n\
+destination = \"lost\"\n\
+[destinations.lost]\n\
+type = \"file\"\n\
+path = \"{}\"\n",
+            dir.path().display()
+        );
+        let config = Config::parse(&toml).await.unwrap();
+        assert!(config.route("typo@example.com").is_none());
+        assert_eq!(
+            config
+                .dead_letter
+                .as_ref()
+                .map(|mapping| mapping.name.as_str()),
+            Some(DEAD_LETTER_MAPPING)
+        );
+        assert!(config.mapping(DEAD_LETTER_MAPPING).is_some());
+
+        let unknown = toml.replacen("\"lost\"", "\"missing\"", 1);
+        assert!(Config::parse(&unknown).await.is_err());
+        let reserved = toml.replace("[mappings.alerts]", "[mappings.dead-letter]");
+        assert!(Config::parse(&reserved).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let toml = format!(
+            "archive_destination = \"journal\"\n\
+[mappings.alerts]\n\
+address = \"alerts@example.com\"\n\
+destination = \"alerts\"\n\
+[destinations.alerts]\n\
+type = \"file\"\n\
+path = \"{0}\"\n\
+[destinations.journal]\n\
+type = \"file\"\n\
+path = \"{0}\"\n",
+            dir.path().display()
+        );
+        let config = Config::parse(&toml).await.unwrap();
+        assert!(config.archive.is_some());
+        assert!(config.mapping(ARCHIVE_MAPPING).is_some());
+
+        let reserved = toml.replace("[mappings.alerts]", "[mappings.archive]");
+        assert!(Config::parse(&reserved).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_relay_credentials() {
+        let toml = "[mappings.forward]\n\
+address = \"forward@example.com\"\n\
+destination = \"relay\"\n\
+[destinations.relay]\n\
+type = \"relay\"\n\
+host = \"relay.example.org\"\n\
+recipient = \"admin@example.org\"\n\
+username = \"forwarder\"\n\
+password = \"123abc\"\n";
+        assert!(Config::parse(toml).await.is_ok());
+        let required = format!("{}starttls = \"required\"\n", toml);
+        assert!(Config::parse(&required).await.is_ok());
+        let opportunistic = format!("{}starttls = \"opportunistic\"\n", toml);
+        assert!(Config::parse(&opportunistic).await.is_err());
+        let disabled = format!("{}starttls = \"disabled\"\n", toml);
+        assert!(Config::parse(&disabled).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_secret_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let token_path = dir.path().join("token");
+        std::fs::write(&token_path, "s3cr3t\n").unwrap();
+        let toml = format!(
+            "[mappings.hook]\n\
+address = \"hook@example.com\"\n\
+destination = \"hook\"\n\
+[destinations.hook]\n\
+type = \"webhook\"\n\
+url = \"https://hooks.example.com/mail\"\n\
+bearer_token_file = \"{}\"\n",
+            token_path.display()
+        );
+        let config = Config::parse(&toml).await.unwrap();
+        let secret = config.secrets.get("hook.bearer_token").unwrap();
+        assert_eq!(secret.get(), "s3cr3t");
+        assert_eq!(secret.path(), Some(token_path.as_path()));
+
+        let both = format!("{}bearer_token = \"inline\"\n", toml);
+        assert!(Config::parse(&both).await.is_err());
+        std::fs::remove_file(&token_path).unwrap();
+        assert!(Config::parse(&toml).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_routing_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("archive")).unwrap();
+        std::fs::create_dir(dir.path().join("newsletters")).unwrap();
+        let toml = format!(
+            "[mappings.alerts]\n\
+address = \"alerts@example.com\"\n\
+destination = \"archive\"\n\
+rules = [{{ list_id = \"\\\\.example\\\\.com$\", destination = \"newsletters\" }}]\n\
+[destinations.archive]\n\
+type = \"file\"\n\
+path = \"{0}/archive\"\n\
+[destinations.newsletters]\n\
+type = \"file\"\n\
+path = \"{0}/newsletters\"\n",
+            dir.path().display()
+        );
+        let config = Config::parse(&toml).await.unwrap();
+        let mapping = config.dest_map.get("alerts@example.com").unwrap();
+        assert_eq!(mapping.destination.kind(), "rules");
+        assert_eq!(
+            mapping.destination.storage_path(),
+            Some(dir.path().join("archive").as_path())
+        );
+
+        // Rules without a condition would match every email:
+        let unconditional = toml.replace("list_id = \"\\\\.example\\\\.com$\", ", "");
+        assert!(Config::parse(&unconditional).await.is_err());
+        let invalid = toml.replace("$", "(");
+        assert!(Config::parse(&invalid).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_redaction() {
+        let toml = "config_version = 2\n\
+[mappings.support]\n\
+address = \"support@example.com\"\n\
+destination = \"chat\"\n\
+[destinations.chat]\n\
+type = \"discord\"\n\
+webhook_url = \"https://discord.com/api/webhooks/1/t\"\n\
+redact = { addresses = \"partial\", phone_numbers = \"full\", patterns = [{ pattern = \"IBAN [A-Z0-9 ]+\", replacement = \"IBAN [redacted]\" }] }\n";
+        let config = Config::parse(toml).await.unwrap();
+        assert!(config.warnings.is_empty());
+
+        let unknown_masking = toml.replace("\"partial\"", "\"half\"");
+        assert!(Config::parse(&unknown_masking).await.is_err());
+        let invalid_pattern = toml.replace("IBAN [A-Z0-9 ]+", "IBAN (");
+        assert!(Config::parse(&invalid_pattern).await.is_err());
+        let missing_replacement = toml.replace(", replacement = \"IBAN [redacted]\"", "");
+        assert!(Config::parse(&missing_replacement).await.is_err());
+    }
+}
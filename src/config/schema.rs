@@ -0,0 +1,443 @@
+//! The fields of the current config format, used to find fields, that the server would ignore (e.g. because of typos).
+
+use super::migration::format_key;
+
+type Table = toml::map::Map<String, toml::Value>;
+
+const ROOT_FIELDS: &[&str] = &[
+    "config_version",
+    "unix_user",
+    "unix_group",
+    "listeners",
+    "bind_retry_secs",
+    "bind_recheck_secs",
+    "hostname",
+    "default_path",
+    "state_dir",
+    "control_socket",
+    "api_tokens",
+    "audit_log",
+    "audit_log_sign_interval_secs",
+    "ha_node",
+    "ha_lease_secs",
+    "attachment_store",
+    "shared_state",
+    "min_free_space_mb",
+    "memory_budget_mb",
+    "max_message_size_mb",
+    "delivery_workers",
+    "dedupe_per_destination",
+    "dead_letter_destination",
+    "archive_destination",
+    "shutdown_grace_secs",
+    "logging",
+    "dns",
+    "proxy",
+    "certificates",
+    "self_test",
+    "statsd",
+    "tenants",
+    "mappings",
+    "destinations",
+];
+const SELF_TEST_FIELDS: &[&str] = &[
+    "address",
+    "relay",
+    "interval_secs",
+    "timeout_secs",
+    "webhook",
+];
+const ATTACHMENT_STORE_FIELDS: &[&str] = &["path", "min_size_kb"];
+const SHARED_STATE_FIELDS: &[&str] = &["url", "key_prefix", "timeout_secs"];
+const STATSD_FIELDS: &[&str] = &["address", "prefix", "interval_secs", "dogstatsd"];
+
+const DNS_FIELDS: &[&str] = &[
+    "servers",
+    "port",
+    "protocol",
+    "tls_name",
+    "timeout_secs",
+    "attempts",
+    "cache_size",
+    "min_ttl_secs",
+    "max_ttl_secs",
+    "negative_min_ttl_secs",
+    "negative_max_ttl_secs",
+];
+const LISTENER_FIELDS: &[&str] = &[
+    "address",
+    "hidden_service",
+    "allow_from",
+    "tcp_keepalive_secs",
+    "tcp_nodelay",
+    "listen_backlog",
+    "recv_buffer_size",
+    "acceptors",
+    "max_hops",
+    "detect_own_hostname",
+    "on_loop",
+    "banner",
+    "greeting_delay_secs",
+    "esmtp_keywords",
+    "future_release_max_secs",
+    "deliver_by",
+    "test",
+    "test_mapping",
+];
+const CERTIFICATE_FIELDS: &[&str] = &["cert_file", "private_key_file"];
+const MAPPING_FIELDS: &[&str] = &[
+    "address",
+    "aliases",
+    "destination",
+    "fallback_destination",
+    "priority",
+    "synchronous_delivery",
+    "listeners",
+    "headers",
+    "auto_replies",
+    "dest_format",
+    "retention_days",
+    "legal_hold",
+    "rules",
+];
+const ROUTING_RULE_FIELDS: &[&str] = &[
+    "destination",
+    "from_domain",
+    "subject",
+    "has_attachment",
+    "list_id",
+];
+const MATRIX_FIELDS: &[&str] = &[
+    "type",
+    "homeserver",
+    "username",
+    "password",
+    "password_file",
+    "access_token",
+    "access_token_file",
+    "appservice_token",
+    "appservice_token_file",
+    "appservice_sender",
+    "appservice_user_prefix",
+    "session_file",
+    "store_path",
+    "store_passphrase",
+    "store_passphrase_file",
+    "room_id",
+    "room_per",
+    "room_name",
+    "room_index",
+    "invite",
+    "locale",
+    "severity_rules",
+    "redact",
+    "html_messages",
+    "upload_attachments",
+    "max_attachment_size_kb",
+    "proxy",
+    "thread_index",
+];
+const RELAY_FIELDS: &[&str] = &[
+    "type",
+    "host",
+    "port",
+    "recipient",
+    "sender",
+    "helo_name",
+    "source_address",
+    "starttls",
+    "username",
+    "password",
+    "password_file",
+    "pool_size",
+    "pool_idle_timeout",
+    "pool_max_lifetime",
+    "proxy",
+    "max_hops",
+];
+const FILE_FIELDS: &[&str] = &["type", "path", "store_attachments"];
+const LMTP_FIELDS: &[&str] = &["type", "address", "recipients", "lhlo_name"];
+const WEBHOOK_FIELDS: &[&str] = &[
+    "type",
+    "url",
+    "bearer_token",
+    "bearer_token_file",
+    "retries",
+    "timeout_secs",
+];
+const DISCORD_FIELDS: &[&str] = &["type", "webhook_url", "username", "redact"];
+const MQTT_FIELDS: &[&str] = &[
+    "type",
+    "url",
+    "topic",
+    "payload",
+    "client_id",
+    "username",
+    "password",
+    "qos",
+    "retain",
+];
+const NATS_FIELDS: &[&str] = &[
+    "type",
+    "url",
+    "subject",
+    "payload",
+    "username",
+    "password",
+    "token",
+    "jetstream",
+    "timeout_secs",
+];
+const AMQP_FIELDS: &[&str] = &[
+    "type",
+    "url",
+    "exchange",
+    "routing_key",
+    "payload",
+    "timeout_secs",
+];
+const POSTGRES_FIELDS: &[&str] = &["type", "url", "table", "pool_size", "timeout_secs"];
+const REDIS_FIELDS: &[&str] = &[
+    "type",
+    "url",
+    "key",
+    "mode",
+    "payload",
+    "max_len",
+    "timeout_secs",
+];
+const API_TOKEN_FIELDS: &[&str] = &["token", "scopes"];
+const TENANT_FIELDS: &[&str] = &[
+    "config_file",
+    "domains",
+    "state_dir",
+    "max_emails_per_minute",
+];
+const TENANT_FILE_FIELDS: &[&str] = &["default_path", "mappings", "destinations"];
+const SEVERITY_RULE_FIELDS: &[&str] =
+    &["pattern", "severity", "emoji", "color", "listeners", "bcc"];
+const REDACT_FIELDS: &[&str] = &["addresses", "phone_numbers", "patterns"];
+const REDACTION_PATTERN_FIELDS: &[&str] = &["pattern", "replacement"];
+
+/// Returns the TOML paths (e.g. `mappings.example.adress`) of all fields of a config in the current format, that are
+/// not used by the server. Sections and values with a wrong type are skipped, because loading reports them anyway.
+pub(crate) fn unknown_fields(config: &Table) -> Vec<String> {
+    let mut unknown = vec![];
+    check_table(config, "", ROOT_FIELDS, &mut unknown);
+
+    if let Some(toml::Value::Array(listeners)) = config.get("listeners") {
+        for (i, listener) in listeners.iter().enumerate() {
+            if let toml::Value::Table(listener) = listener {
+                check_table(
+                    listener,
+                    &format!("listeners[{}]", i),
+                    LISTENER_FIELDS,
+                    &mut unknown,
+                );
+            }
+        }
+    }
+    if let Some(toml::Value::Table(dns)) = config.get("dns") {
+        check_table(dns, "dns", DNS_FIELDS, &mut unknown);
+    }
+    if let Some(toml::Value::Table(self_test)) = config.get("self_test") {
+        check_table(self_test, "self_test", SELF_TEST_FIELDS, &mut unknown);
+    }
+    if let Some(toml::Value::Table(store)) = config.get("attachment_store") {
+        check_table(
+            store,
+            "attachment_store",
+            ATTACHMENT_STORE_FIELDS,
+            &mut unknown,
+        );
+    }
+    if let Some(toml::Value::Table(shared_state)) = config.get("shared_state") {
+        check_table(
+            shared_state,
+            "shared_state",
+            SHARED_STATE_FIELDS,
+            &mut unknown,
+        );
+    }
+    if let Some(toml::Value::Table(statsd)) = config.get("statsd") {
+        check_table(statsd, "statsd", STATSD_FIELDS, &mut unknown);
+    }
+    for (domain, certificate) in sections(config, "certificates") {
+        check_table(
+            certificate,
+            &path("certificates", domain),
+            CERTIFICATE_FIELDS,
+            &mut unknown,
+        );
+    }
+    for (token_name, token) in sections(config, "api_tokens") {
+        check_table(
+            token,
+            &path("api_tokens", token_name),
+            API_TOKEN_FIELDS,
+            &mut unknown,
+        );
+    }
+    for (tenant_name, tenant) in sections(config, "tenants") {
+        check_table(
+            tenant,
+            &path("tenants", tenant_name),
+            TENANT_FIELDS,
+            &mut unknown,
+        );
+    }
+    check_mappings(config, &mut unknown);
+    unknown
+}
+
+/// Like `unknown_fields()`, but for the config file of a tenant.
+pub(crate) fn unknown_tenant_fields(config: &Table) -> Vec<String> {
+    let mut unknown = vec![];
+    check_table(config, "", TENANT_FILE_FIELDS, &mut unknown);
+    check_mappings(config, &mut unknown);
+    unknown
+}
+
+/// Checks the sections 'mappings' and 'destinations', that the main config file and the ones of tenants share.
+fn check_mappings(config: &Table, unknown: &mut Vec<String>) {
+    for (mapping_name, mapping) in sections(config, "mappings") {
+        check_table(
+            mapping,
+            &path("mappings", mapping_name),
+            MAPPING_FIELDS,
+            unknown,
+        );
+        if let Some(toml::Value::Array(rules)) = mapping.get("rules") {
+            for (i, rule) in rules.iter().enumerate() {
+                if let toml::Value::Table(rule) = rule {
+                    check_table(
+                        rule,
+                        &format!("{}.rules[{}]", path("mappings", mapping_name), i),
+                        ROUTING_RULE_FIELDS,
+                        unknown,
+                    );
+                }
+            }
+        }
+    }
+    for (dest_name, destination) in sections(config, "destinations") {
+        let prefix = path("destinations", dest_name);
+        let fields = match destination.get("type").and_then(toml::Value::as_str) {
+            Some("matrix") => MATRIX_FIELDS,
+            Some("relay") => RELAY_FIELDS,
+            Some("file") => FILE_FIELDS,
+            Some("lmtp") => LMTP_FIELDS,
+            Some("webhook") => WEBHOOK_FIELDS,
+            Some("discord") => DISCORD_FIELDS,
+            Some("mqtt") => MQTT_FIELDS,
+            Some("nats") => NATS_FIELDS,
+            Some("amqp") => AMQP_FIELDS,
+            Some("postgres") => POSTGRES_FIELDS,
+            Some("redis") => REDIS_FIELDS,
+            _ => continue,
+        };
+        check_table(destination, &prefix, fields, unknown);
+        if let Some(toml::Value::Array(rules)) = destination.get("severity_rules") {
+            for (i, rule) in rules.iter().enumerate() {
+                if let toml::Value::Table(rule) = rule {
+                    check_table(
+                        rule,
+                        &format!("{}.severity_rules[{}]", prefix, i),
+                        SEVERITY_RULE_FIELDS,
+                        unknown,
+                    );
+                }
+            }
+        }
+        if let Some(toml::Value::Table(redact)) = destination.get("redact") {
+            let redact_prefix = format!("{}.redact", prefix);
+            check_table(redact, &redact_prefix, REDACT_FIELDS, unknown);
+            if let Some(toml::Value::Array(patterns)) = redact.get("patterns") {
+                for (i, pattern) in patterns.iter().enumerate() {
+                    if let toml::Value::Table(pattern) = pattern {
+                        check_table(
+                            pattern,
+                            &format!("{}.patterns[{}]", redact_prefix, i),
+                            REDACTION_PATTERN_FIELDS,
+                            unknown,
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the subsections of the section `name`, that are tables.
+fn sections<'a>(config: &'a Table, name: &str) -> impl Iterator<Item = (&'a String, &'a Table)> {
+    config
+        .get(name)
+        .and_then(toml::Value::as_table)
+        .into_iter()
+        .flat_map(|section| section.iter())
+        .filter_map(|(key, value)| value.as_table().map(|table| (key, table)))
+}
+
+fn path(prefix: &str, key: &str) -> String {
+    if prefix.is_empty() {
+        format_key(key)
+    } else {
+        format!("{}.{}", prefix, format_key(key))
+    }
+}
+
+fn check_table(table: &Table, prefix: &str, known: &[&str], unknown: &mut Vec<String>) {
+    for key in table.keys() {
+        if !known.contains(&key.as_str()) {
+            unknown.push(path(prefix, key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unknown_fields() {
+        let config: Table = toml::from_str(
+            r#"
+config_version = 2
+bind_adresses = [ "127.0.0.1:25" ]
+
+[[listeners]]
+address = "127.0.0.1:25"
+port = 25
+
+[certificates]
+"example.com" = { cert_file = "cert.pem", private_key = "key.pem" }
+
+[mappings.example]
+adress = "user@example.com"
+destination = "alerts"
+
+[destinations.alerts]
+type = "matrix"
+homeserver = "matrix.example.com"
+room_id = "!room:example.com"
+relay_host = "smtp.example.com"
+severity_rules = [ { pattern = "down", severity = "critical", colour = "red" } ]
+"#,
+        )
+        .unwrap();
+
+        let mut unknown = unknown_fields(&config);
+        unknown.sort();
+        assert_eq!(
+            unknown,
+            vec![
+                "bind_adresses",
+                "certificates.\"example.com\".private_key",
+                "destinations.alerts.relay_host",
+                "destinations.alerts.severity_rules[0].colour",
+                "listeners[0].port",
+                "mappings.example.adress",
+            ]
+        );
+    }
+}
@@ -0,0 +1,544 @@
+use log::{debug, error, info, warn};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream},
+    net::{UnixListener, UnixStream},
+    sync::broadcast::{self, error::RecvError},
+};
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::api_token::{ApiToken, Scope};
+use crate::feed::MailFeed;
+use crate::mapping::Mapping;
+use crate::metrics::Metrics;
+use crate::queue::DeliveryQueue;
+use crate::secret::SecretStore;
+use crate::smtp_server::SmtpServer;
+use crate::state_lock;
+use crate::Error;
+
+/// The longest time, that a backup may block the writes to the state files.
+const FREEZE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// A unix socket, through which the running server can be controlled.
+///
+/// Clients send one command per line. The answer to every command ends with a line, that is either "OK" or starts with
+/// "ERR ".
+///
+/// After the answer "OK" to `tail`, the connection only receives the events of the mail feed, one per line, until the
+/// client closes it.
+///
+/// The answer "OK" to `freeze` is delayed until all running writes to the state files are finished. Further writes
+/// are blocked, until the client closes the connection, so a backup can copy a consistent state.
+///
+/// `rotate <name> <value>` replaces a password or token of a destination (e.g. `rotate matrix.password ...`) for the
+/// following deliveries and logins, and `reload-secrets` reads all secrets, that are given by files, again. The
+/// values are never logged.
+///
+/// If API tokens are configured, clients have to authenticate with `auth <token>` first and may only use the commands
+/// of the scopes of their token. Otherwise every client, that may open the socket, may use all commands.
+pub(crate) struct ControlSocket {
+    listener: UnixListener,
+    servers: Vec<Arc<SmtpServer>>,
+    metrics: Option<Arc<Metrics>>,
+    mappings: Vec<Arc<Mapping>>,
+    queue: Option<Arc<DeliveryQueue>>,
+    feed: Option<Arc<MailFeed>>,
+    tokens: Vec<ApiToken>,
+    secrets: SecretStore,
+}
+
+impl ControlSocket {
+    /// Binds the control socket to `path`, replacing a socket file left by a previous run.
+    /// Only the owner and the group of the socket file may use it.
+    pub(crate) fn bind(path: &Path, servers: Vec<Arc<SmtpServer>>) -> Result<Self, Error> {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o660))?;
+        Ok(ControlSocket {
+            listener,
+            servers,
+            metrics: None,
+            mappings: vec![],
+            queue: None,
+            feed: None,
+            tokens: vec![],
+            secrets: SecretStore::default(),
+        })
+    }
+
+    /// Lets the `metrics` command return the given metrics.
+    pub(crate) fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Lets the `mappings` command list the given mappings.
+    pub(crate) fn set_mappings(&mut self, mappings: Vec<Arc<Mapping>>) {
+        self.mappings = mappings;
+    }
+
+    /// Lets the `queue` command report the length of the given queue.
+    pub(crate) fn set_queue(&mut self, queue: Arc<DeliveryQueue>) {
+        self.queue = Some(queue);
+    }
+
+    /// Lets the `tail` command follow the given feed.
+    pub(crate) fn set_feed(&mut self, feed: Arc<MailFeed>) {
+        self.feed = Some(feed);
+    }
+
+    /// Requires clients to authenticate with one of the given tokens.
+    pub(crate) fn set_tokens(&mut self, tokens: Vec<ApiToken>) {
+        self.tokens = tokens;
+    }
+
+    /// Lets the `secrets`, `rotate` and `reload-secrets` commands use the given secrets.
+    pub(crate) fn set_secrets(&mut self, secrets: SecretStore) {
+        self.secrets = secrets;
+    }
+
+    /// Returns the scopes of a new connection, which are all scopes, if no tokens are required.
+    fn initial_scopes(&self) -> Vec<Scope> {
+        if self.tokens.is_empty() {
+            Scope::ALL.to_vec()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Accepts control connections forever.
+    pub(crate) async fn run(self) {
+        let control = Arc::new(self);
+        loop {
+            let stream = match control.listener.accept().await {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    error!("Could not accept connection on control socket: {}", e);
+                    continue;
+                }
+            };
+            let control = control.clone();
+            tokio::spawn(async move {
+                if let Err(e) = control.handle_conn(stream).await {
+                    warn!("Error on control connection: {}", e);
+                }
+            });
+        }
+    }
+
+    async fn handle_conn(&self, stream: UnixStream) -> Result<(), Error> {
+        let mut stream = BufStream::new(stream);
+        let mut scopes = self.initial_scopes();
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await? == 0 {
+                return Ok(());
+            }
+            let answer = self.execute(line.trim(), &mut scopes);
+            if line.trim() == "freeze" && answer == "OK\n" {
+                return freeze(stream).await;
+            }
+            // The client must not miss events published right after the answer:
+            let events = match self.feed {
+                Some(ref feed) if line.trim() == "tail" && answer == "OK\n" => {
+                    Some(feed.subscribe())
+                }
+                _ => None,
+            };
+            stream.write_all(answer.as_bytes()).await?;
+            stream.flush().await?;
+            if let Some(events) = events {
+                return tail(stream, events).await;
+            }
+        }
+    }
+
+    /// Executes a single command and returns the answer for the client. `scopes` are the scopes of the connection,
+    /// which are replaced by the ones of the token, that the client authenticates with.
+    fn execute(&self, command: &str, scopes: &mut Vec<Scope>) -> String {
+        let mut words = command.split_whitespace();
+        let verb = words.next();
+        if let Some(required) = match verb {
+            Some("status" | "metrics" | "stats") => Some(Scope::Metrics),
+            Some("mappings") => Some(Scope::Mappings),
+            Some("queue" | "pause" | "resume") => Some(Scope::Queue),
+            Some("tail") => Some(Scope::Tail),
+            Some("freeze") => Some(Scope::Backup),
+            Some("secrets" | "rotate" | "reload-secrets") => Some(Scope::Secrets),
+            _ => None,
+        } {
+            if !scopes.contains(&required) {
+                return format!("ERR missing scope {}\n", required.name());
+            }
+        }
+        match (verb, words.next(), words.next()) {
+            (Some("auth"), Some(secret), None) => {
+                match self.tokens.iter().find(|token| token.matches(secret)) {
+                    Some(token) => {
+                        info!("Control client authenticated with token '{}'.", token.name);
+                        *scopes = token.scopes.clone();
+                        "OK\n".to_string()
+                    }
+                    None => {
+                        warn!("Control client used an invalid token.");
+                        "ERR invalid token\n".to_string()
+                    }
+                }
+            }
+            (Some("status"), None, _) => {
+                let mut answer = String::new();
+                for server in self.servers.iter() {
+                    if let Ok(addr) = server.local_addr() {
+                        let state = if server.is_paused() {
+                            "paused"
+                        } else {
+                            "active"
+                        };
+                        answer.push_str(&format!("{} {}\n", addr, state));
+                    }
+                }
+                answer.push_str("OK\n");
+                answer
+            }
+            (Some("metrics"), None, _) => match self.metrics {
+                Some(ref metrics) => format!("{}OK\n", metrics.render()),
+                None => "ERR metrics are not collected\n".to_string(),
+            },
+            (Some("mappings"), None, _) => {
+                let mut answer = String::new();
+                for mapping in self.mappings.iter() {
+                    answer.push_str(&format!(
+                        "{} {}\n",
+                        mapping.name,
+                        mapping.destination.describe()
+                    ));
+                }
+                answer.push_str("OK\n");
+                answer
+            }
+            (Some("stats"), None, _) => {
+                let mut answer = String::new();
+                for mapping in self.mappings.iter() {
+                    answer.push_str(&format!("{} {}\n", mapping.name, mapping.stats.describe()));
+                }
+                answer.push_str("OK\n");
+                answer
+            }
+            (Some("queue"), None, _) => match self.queue {
+                Some(ref queue) => format!("{} queued deliveries\nOK\n", queue.len()),
+                None => "ERR no delivery queue\n".to_string(),
+            },
+            (Some("tail"), None, _) => match self.feed {
+                Some(_) => "OK\n".to_string(),
+                None => "ERR no mail feed\n".to_string(),
+            },
+            (Some("freeze"), None, _) => "OK\n".to_string(),
+            (Some("secrets"), None, _) => {
+                let mut answer = String::new();
+                for (name, path) in self.secrets.list() {
+                    match path {
+                        Some(path) => answer.push_str(&format!("{} {}\n", name, path.display())),
+                        None => answer.push_str(&format!("{} inline\n", name)),
+                    }
+                }
+                answer.push_str("OK\n");
+                answer
+            }
+            (Some("rotate"), Some(name), Some(_)) => match self.secrets.get(name) {
+                Some(secret) => {
+                    // The value may contain whitespace:
+                    let value = command["rotate".len()..].trim_start()[name.len()..].trim();
+                    secret.set(value);
+                    info!("Rotated secret '{}' through control socket.", name);
+                    "OK\n".to_string()
+                }
+                None => format!("ERR unknown secret {}\n", name),
+            },
+            (Some("reload-secrets"), None, _) => match self.secrets.reload_all() {
+                Ok(changed) => {
+                    let mut answer = String::new();
+                    for name in changed {
+                        answer.push_str(&format!("{} changed\n", name));
+                    }
+                    answer.push_str("OK\n");
+                    answer
+                }
+                Err(e) => format!("ERR {}\n", e),
+            },
+            (Some(cmd @ ("pause" | "resume")), Some(addr), None) => {
+                let addr: SocketAddr = match addr.parse() {
+                    Ok(addr) => addr,
+                    Err(_) => return format!("ERR invalid address {}\n", addr),
+                };
+                match self
+                    .servers
+                    .iter()
+                    .find(|server| server.local_addr().is_ok_and(|a| a == addr))
+                {
+                    Some(server) => {
+                        let paused = cmd == "pause";
+                        server.set_paused(paused);
+                        info!(
+                            "{} listener on {} through control socket.",
+                            if paused { "Paused" } else { "Resumed" },
+                            addr
+                        );
+                        "OK\n".to_string()
+                    }
+                    None => format!("ERR unknown listener {}\n", addr),
+                }
+            }
+            _ => format!("ERR unknown command {}\n", command),
+        }
+    }
+}
+
+/// Writes the events of the mail feed to the client, until it closes the connection.
+async fn tail(
+    stream: BufStream<UnixStream>,
+    mut events: broadcast::Receiver<String>,
+) -> Result<(), Error> {
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let mut buf = [0; 64];
+    loop {
+        let event = tokio::select! {
+            event = events.recv() => event,
+            // Further input is ignored, but the end of it ends the feed:
+            read = reader.read(&mut buf) => match read? {
+                0 => return Ok(()),
+                _ => continue,
+            },
+        };
+        let line = match event {
+            Ok(line) => line,
+            Err(RecvError::Lagged(skipped)) => {
+                debug!(
+                    "Control client skipped {} events of the mail feed.",
+                    skipped
+                );
+                format!("{} events skipped", skipped)
+            }
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        writer.write_all(line.as_bytes()).await?;
+        writer.write_all(b"\n").await?;
+        writer.flush().await?;
+    }
+}
+
+/// Blocks the writes to the state files, until the client closes the connection or the timeout expires.
+async fn freeze(stream: BufStream<UnixStream>) -> Result<(), Error> {
+    let stream = stream.into_inner().into_std()?;
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(FREEZE_TIMEOUT))?;
+    // The writers block the threads of the runtime, so the lock is held and released outside of them:
+    tokio::task::spawn_blocking(move || {
+        let mut stream = stream;
+        let _frozen = state_lock::freeze();
+        info!("Froze the state files for a backup.");
+        stream.write_all(b"OK\n")?;
+        let mut buf = [0; 64];
+        let res = loop {
+            match stream.read(&mut buf) {
+                Ok(0) => break Ok(()),
+                Ok(_) => continue,
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    warn!(
+                        "Backup client did not finish within {} seconds.",
+                        FREEZE_TIMEOUT.as_secs()
+                    );
+                    break Ok(());
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        info!("Thawed the state files.");
+        Ok(res?)
+    })
+    .await
+    .expect("Freezing the state files panicked.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::smtp_server::SocketOptions;
+
+    const CONTROL_TEST_PORT: u16 = 4030;
+
+    impl ControlSocket {
+        /// Executes a command on a connection with all scopes.
+        fn execute_all(&self, command: &str) -> String {
+            self.execute(command, &mut Scope::ALL.to_vec())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pause_resume() {
+        let addr: SocketAddr = format!("127.0.0.1:{}", CONTROL_TEST_PORT).parse().unwrap();
+        let server = Arc::new(
+            SmtpServer::new(&addr, None, SocketOptions::default())
+                .await
+                .unwrap(),
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let control =
+            ControlSocket::bind(&dir.path().join("control.sock"), vec![server.clone()]).unwrap();
+
+        assert_eq!(control.execute_all("pause 127.0.0.1:4030"), "OK\n");
+        assert!(server.is_paused());
+        assert_eq!(control.execute_all("status"), "127.0.0.1:4030 paused\nOK\n");
+        assert_eq!(control.execute_all("resume 127.0.0.1:4030"), "OK\n");
+        assert!(!server.is_paused());
+
+        assert!(control.execute_all("pause 127.0.0.1:1").starts_with("ERR "));
+        assert!(control.execute_all("pause nonsense").starts_with("ERR "));
+        assert!(control.execute_all("reboot").starts_with("ERR "));
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut control = ControlSocket::bind(&dir.path().join("control.sock"), vec![]).unwrap();
+        assert!(control.execute_all("metrics").starts_with("ERR "));
+
+        control.set_metrics(Arc::new(Metrics::new()));
+        let answer = control.execute_all("metrics");
+        assert!(answer.starts_with("# HELP "));
+        assert!(answer.ends_with("\nOK\n"));
+    }
+
+    #[tokio::test]
+    async fn test_stats() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut control = ControlSocket::bind(&dir.path().join("control.sock"), vec![]).unwrap();
+        let mapping = Arc::new(Mapping::new(
+            "files",
+            Box::new(crate::maildest::FileDestination::new(dir.path()).unwrap()),
+        ));
+        mapping.stats.record_failure();
+        control.set_mappings(vec![mapping]);
+        assert_eq!(
+            control.execute_all("stats"),
+            "files delivered=0 bytes=0 failures=1 last_delivery=never\nOK\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tokens() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut control = ControlSocket::bind(&dir.path().join("control.sock"), vec![]).unwrap();
+        control.set_metrics(Arc::new(Metrics::new()));
+        control.set_queue(Arc::new(DeliveryQueue::new()));
+        control.set_tokens(vec![ApiToken::new(
+            "monitoring",
+            "s3cr3t",
+            vec![Scope::Metrics],
+        )]);
+
+        let mut scopes = control.initial_scopes();
+        assert_eq!(
+            control.execute("metrics", &mut scopes),
+            "ERR missing scope metrics\n"
+        );
+        assert_eq!(
+            control.execute("auth wrong", &mut scopes),
+            "ERR invalid token\n"
+        );
+        assert_eq!(control.execute("auth s3cr3t", &mut scopes), "OK\n");
+        assert!(control.execute("metrics", &mut scopes).ends_with("\nOK\n"));
+        assert_eq!(
+            control.execute("queue", &mut scopes),
+            "ERR missing scope queue\n"
+        );
+        assert_eq!(control.execute_all("queue"), "0 queued deliveries\nOK\n");
+    }
+
+    #[tokio::test]
+    async fn test_rotate() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut control = ControlSocket::bind(&dir.path().join("control.sock"), vec![]).unwrap();
+        let secrets = SecretStore::default();
+        let secret = secrets
+            .get_or_load("relay.password", || Ok("old".into()))
+            .unwrap();
+        control.set_secrets(secrets);
+
+        assert_eq!(
+            control.execute_all("secrets"),
+            "relay.password inline\nOK\n"
+        );
+        assert_eq!(
+            control.execute_all("rotate relay.password  correct horse "),
+            "OK\n"
+        );
+        assert_eq!(secret.get(), "correct horse");
+        assert!(control
+            .execute_all("rotate matrix.password x")
+            .starts_with("ERR "));
+        assert!(control
+            .execute_all("rotate relay.password")
+            .starts_with("ERR "));
+        assert_eq!(control.execute_all("reload-secrets"), "OK\n");
+    }
+
+    #[tokio::test]
+    async fn test_tail() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let mut control = ControlSocket::bind(&path, vec![]).unwrap();
+        assert!(control.execute_all("tail").starts_with("ERR "));
+        let feed = Arc::new(MailFeed::new());
+        control.set_feed(feed.clone());
+        tokio::spawn(control.run());
+
+        let mut client = BufStream::new(UnixStream::connect(&path).await.unwrap());
+        client.write_all(b"tail\n").await.unwrap();
+        client.flush().await.unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "OK\n");
+
+        feed.delivered("tail@example.com", "files", &Ok(()));
+        line.clear();
+        client.read_line(&mut line).await.unwrap();
+        assert!(line.ends_with("\tdelivered\ttail@example.com\tfiles\n"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_freeze() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        tokio::spawn(ControlSocket::bind(&path, vec![]).unwrap().run());
+
+        let mut client = BufStream::new(UnixStream::connect(&path).await.unwrap());
+        client.write_all(b"freeze\n").await.unwrap();
+        client.flush().await.unwrap();
+        let mut line = String::new();
+        client.read_line(&mut line).await.unwrap();
+        assert_eq!(line, "OK\n");
+
+        // Writes wait, until the client closes the connection:
+        let writer = std::thread::spawn(|| drop(state_lock::writing()));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(!writer.is_finished());
+        drop(client);
+        tokio::task::spawn_blocking(move || writer.join().unwrap())
+            .await
+            .unwrap();
+    }
+}
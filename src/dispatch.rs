@@ -0,0 +1,682 @@
+use async_trait::async_trait;
+use log::{debug, error, info, warn};
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::audit::AuditLog;
+use crate::bounce::Bounce;
+use crate::config::Config;
+use crate::email::{with_routing_headers, Email, SmtpEmail};
+use crate::error::SmtpErrorCode;
+use crate::feed::MailFeed;
+use crate::mapping::Mapping;
+use crate::queue::{DeliveryJob, DeliveryQueue};
+use crate::smtp_server::MailAcceptor;
+use crate::tenant::Tenant;
+use crate::Error;
+
+/// The maximum number of remembered synchronous deliveries of partially delivered emails.
+const MAX_PARTIAL_DELIVERIES: usize = 1000;
+
+/// Accepts received emails by queueing a delivery for the mapping of every recipient.
+///
+/// Every delivered copy gets `Delivered-To` and `X-Original-To` headers with the envelope recipient, that selected the
+/// mapping. If multiple recipients select the same mapping, the email is delivered once per recipient or, with
+/// `dedupe_per_destination`, once with headers for all of these recipients.
+///
+/// Mappings with synchronous delivery are delivered to directly instead. If one of these deliveries fails, the email
+/// is rejected with 451 and nothing is queued, so the sender retries the whole email. The synchronous deliveries, that
+/// succeeded before, are remembered by the Message-ID of the email and skipped, when the retry is received. Only the
+/// last `MAX_PARTIAL_DELIVERIES` are remembered (and only until a restart), so a late retry may still deliver the
+/// email to these mappings again.
+///
+/// Bounces (delivery status notifications) are delivered like other emails, but are also logged and recorded in the
+/// audit log together with the mappings, that the bounced email was delivered to.
+///
+/// Recipients without mapping are routed to the dead-letter destination, if one is configured, and skipped otherwise.
+/// With an archive destination, every accepted email is additionally queued for it once, with headers for all routed
+/// recipients.
+///
+/// The dispatcher of a test listener delivers all emails to its test mapping, without counting them against the rate
+/// limits of tenants.
+pub(crate) struct Dispatcher {
+    config: Arc<Config>,
+    queue: Arc<DeliveryQueue>,
+    audit_log: Option<Arc<AuditLog>>,
+    feed: Option<Arc<MailFeed>>,
+    test_mapping: Option<Arc<Mapping>>,
+    /// The Message-IDs and mapping names of synchronous deliveries of rejected emails, that succeeded.
+    partial_deliveries: Mutex<VecDeque<(String, String)>>,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(config: Arc<Config>, queue: Arc<DeliveryQueue>) -> Self {
+        Dispatcher {
+            config,
+            queue,
+            audit_log: None,
+            feed: None,
+            test_mapping: None,
+            partial_deliveries: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Lets all recipients be routed to the given mapping, bypassing the rate limits of tenants.
+    pub(crate) fn set_test_mapping(&mut self, mapping: Arc<Mapping>) {
+        self.test_mapping = Some(mapping);
+    }
+
+    /// Lets accepted emails and synchronous deliveries be recorded in the given audit log.
+    pub(crate) fn set_audit_log(&mut self, audit_log: Arc<AuditLog>) {
+        self.audit_log = Some(audit_log);
+    }
+
+    /// Lets accepted emails and the outcomes of synchronous deliveries be published to the given feed.
+    pub(crate) fn set_feed(&mut self, feed: Arc<MailFeed>) {
+        self.feed = Some(feed);
+    }
+
+    /// Returns true, if the email was delivered to the mapping already, before a later synchronous delivery failed.
+    fn delivered_before(&self, message_id: &str, mapping: &str) -> bool {
+        self.partial_deliveries
+            .lock()
+            .expect("Partial deliveries are poisoned.")
+            .iter()
+            .any(|(id, name)| id == message_id && name == mapping)
+    }
+
+    /// Remembers the successful synchronous deliveries of an email, that is rejected, so that they are skipped, when
+    /// the sender retries it. With `delivered` being `None`, the deliveries of the email are forgotten.
+    fn remember_deliveries(&self, message_id: &str, delivered: Option<Vec<String>>) {
+        let mut partial = self
+            .partial_deliveries
+            .lock()
+            .expect("Partial deliveries are poisoned.");
+        partial.retain(|(id, _)| id != message_id);
+        for mapping in delivered.into_iter().flatten() {
+            if partial.len() >= MAX_PARTIAL_DELIVERIES {
+                partial.pop_front();
+            }
+            partial.push_back((message_id.to_string(), mapping));
+        }
+    }
+
+    /// Logs a received bounce and records it in the audit log together with the mappings, that the bounced email was
+    /// delivered to.
+    fn record_bounce(&self, message_id: &str, bounce: &Bounce) {
+        let original = bounce.original_message_id.as_deref().unwrap_or("unknown");
+        for recipient in bounce.recipients.iter() {
+            warn!(
+                "Received {} bounce for email {} to {}: {} {}",
+                recipient.kind.name(),
+                original,
+                recipient.recipient,
+                recipient.status,
+                recipient.diagnostic.as_deref().unwrap_or_default()
+            );
+        }
+        let audit_log = match self.audit_log {
+            Some(ref audit_log) => audit_log,
+            None => return,
+        };
+        let mappings = match bounce.original_message_id {
+            Some(ref original) => audit_log.delivered_mappings(original).unwrap_or_else(|e| {
+                error!("Could not look up bounced email in audit log: {}", e);
+                vec![]
+            }),
+            None => vec![],
+        };
+        if let Err(e) = audit_log.bounced(message_id, bounce, &mappings) {
+            error!("Could not record bounce in audit log: {}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl MailAcceptor for Dispatcher {
+    async fn accept(&self, email: &SmtpEmail<'_>) -> Result<(), Error> {
+        let mut routes: Vec<(Vec<&str>, &Arc<Mapping>)> = vec![];
+        for addr in email.to.iter() {
+            let recipient = AsRef::<str>::as_ref(addr);
+            let route = self.config.route_email(recipient, &email.content);
+            let mapping = match (&self.test_mapping, route) {
+                (Some(test_mapping), _) => test_mapping,
+                (None, Some((_, mapping))) if mapping.accepts_from(email.client.listener) => {
+                    mapping
+                }
+                (None, Some((_, mapping))) => {
+                    warn!(
+                        mapping:% = mapping.name;
+                        "Received an email for mapping '{}' on a listener, that the mapping is not restricted to.",
+                        mapping.name
+                    );
+                    continue;
+                }
+                (None, None) => match self.config.dead_letter {
+                    Some(ref dead_letter) => {
+                        warn!("Received an email without a destination mapping, which is delivered to the dead-letter destination.");
+                        dead_letter
+                    }
+                    None => {
+                        warn!("Received an email without a destination mapping.");
+                        continue;
+                    }
+                },
+            };
+            let shared = routes.iter_mut().find(|(_, other)| {
+                self.config.dedupe_per_destination && Arc::ptr_eq(other, mapping)
+            });
+            match shared {
+                Some((recipients, _)) => recipients.push(recipient),
+                None => routes.push((vec![recipient], mapping)),
+            }
+        }
+        if routes.is_empty() {
+            return Err(Error::Policy(
+                "No destination mapping for any recipient.".to_string(),
+            ));
+        }
+        if let (Some(archive), None) = (&self.config.archive, &self.test_mapping) {
+            let recipients = routes
+                .iter()
+                .flat_map(|(recipients, _)| recipients.iter().copied())
+                .collect();
+            routes.push((recipients, archive));
+        }
+
+        // Every tenant counts the email once, regardless of the number of its recipients:
+        let mut tenants: Vec<&Arc<Tenant>> = vec![];
+        for tenant in routes
+            .iter()
+            .filter(|_| self.test_mapping.is_none())
+            .filter_map(|(_, mapping)| mapping.tenant.as_ref())
+        {
+            if !tenants.iter().any(|other| Arc::ptr_eq(other, tenant)) {
+                tenant.count_email().await?;
+                tenants.push(tenant);
+            }
+        }
+
+        // Held emails are queued even for mappings with synchronous delivery:
+        let envelope = Arc::new(email.envelope());
+        let held = email
+            .params
+            .hold_until
+            .is_some_and(|hold_until| hold_until > envelope.received_at);
+        let (synchronous, queued): (Vec<_>, Vec<_>) = routes
+            .into_iter()
+            .partition(|(_, mapping)| mapping.synchronous_delivery && !held);
+        let message_id = &email.content.message_id;
+        let mut delivered = vec![];
+        for (recipients, mapping) in synchronous {
+            if self.delivered_before(message_id, &mapping.name) {
+                info!(
+                    email_message_id:% = message_id, mapping:% = mapping.name;
+                    "Skipping synchronous delivery of email {} for mapping '{}', which succeeded before the email was rejected.",
+                    message_id, mapping.name
+                );
+                delivered.push(mapping.name.clone());
+                continue;
+            }
+            let raw = with_routing_headers(email.content.raw, &recipients);
+            let mut routed = Email::parse(&raw)?;
+            routed.envelope = Some(envelope.clone());
+            let res = mapping.deliver(&routed, self.audit_log.as_deref()).await;
+            if let Some(ref feed) = self.feed {
+                feed.delivered(&routed.message_id, &mapping.name, &res);
+            }
+            if let Err(e) = res {
+                self.remember_deliveries(message_id, Some(delivered));
+                return Err(Error::smtp(
+                    SmtpErrorCode::Reply(451),
+                    format!("Synchronous delivery failed: {}", e),
+                )
+                .in_mapping(&mapping.name));
+            }
+            delivered.push(mapping.name.clone());
+        }
+        if !delivered.is_empty() {
+            self.remember_deliveries(message_id, None);
+        }
+
+        for (recipients, mapping) in queued {
+            let raw: Arc<[u8]> = Arc::from(with_routing_headers(email.content.raw, &recipients));
+            let mut job = DeliveryJob::new(mapping.clone(), raw, Some(envelope.clone()));
+            job.hold_until = email.params.hold_until;
+            job.deliver_by = email.params.deliver_by;
+            self.queue.enqueue(job)?;
+        }
+        debug!("{} deliveries are queued.", self.queue.len());
+        if let Some(ref feed) = self.feed {
+            feed.accepted(email);
+        }
+        if let Some(ref audit_log) = self.audit_log {
+            let hidden_recipients = email.content.missing_from_headers(&envelope.rcpt_to);
+            if let Err(e) =
+                audit_log.received(&email.content.message_id, &email.client, &hidden_recipients)
+            {
+                error!("Could not record received email in audit log: {}", e);
+            }
+        }
+        if let Some(bounce) = Bounce::parse(email.content.raw) {
+            self.record_bounce(&email.content.message_id, &bounce);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::address_matcher::AddressMatcher;
+    use crate::error::SmtpError;
+    use crate::maildest::{EmailDestination, Receipt};
+    use crate::mapping::HeaderCondition;
+
+    use regex::Regex;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TEST_EMAIL: &[u8] = b"From: sender@example.com\r\n\
+To: sync@example.org, queued@example.org\r\n\
+Message-ID: <dispatch-test@example.com>\r\n\
+\r\n\
+Hello world.\r\n";
+
+    struct FailingDestination;
+
+    #[async_trait]
+    impl EmailDestination for FailingDestination {
+        async fn write_email(&self, _email: &Email<'_>) -> Result<Receipt, Error> {
+            Err(Error::smtp(
+                SmtpErrorCode::Unreachable,
+                "Destination is down.",
+            ))
+        }
+
+        fn kind(&self) -> &'static str {
+            "test"
+        }
+
+        fn describe(&self) -> String {
+            "a failing destination".to_string()
+        }
+    }
+
+    /// Counts the delivered emails.
+    struct CountingDestination(Arc<AtomicUsize>);
+
+    #[async_trait]
+    impl EmailDestination for CountingDestination {
+        async fn write_email(&self, _email: &Email<'_>) -> Result<Receipt, Error> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(Receipt::new("counted"))
+        }
+
+        fn kind(&self) -> &'static str {
+            "test"
+        }
+
+        fn describe(&self) -> String {
+            "a counting destination".to_string()
+        }
+    }
+
+    fn dispatcher(synchronous: bool) -> (Dispatcher, Arc<DeliveryQueue>) {
+        let mut config = Config::default();
+        let mut sync_mapping = Mapping::new("sync", Box::new(FailingDestination));
+        sync_mapping.synchronous_delivery = synchronous;
+        config
+            .dest_map
+            .insert(["sync@example.org"], Arc::new(sync_mapping))
+            .unwrap();
+        config
+            .dest_map
+            .insert(
+                ["queued@example.org"],
+                Arc::new(Mapping::new("queued", Box::new(FailingDestination))),
+            )
+            .unwrap();
+        let queue = Arc::new(DeliveryQueue::new());
+        (Dispatcher::new(Arc::new(config), queue.clone()), queue)
+    }
+
+    fn email(to: &[&str]) -> SmtpEmail<'static> {
+        let to = to
+            .iter()
+            .map(|addr| lettre::EmailAddress::new(addr.to_string()).unwrap())
+            .collect();
+        SmtpEmail::new(None, to, TEST_EMAIL).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_queued_delivery() {
+        let (dispatcher, queue) = dispatcher(false);
+        dispatcher
+            .accept(&email(&["sync@example.org", "queued@example.org"]))
+            .await
+            .unwrap();
+        assert_eq!(queue.len(), 2);
+
+        let job = queue.try_pop().unwrap();
+        let recipient = match job.mapping.name.as_str() {
+            "sync" => "sync@example.org",
+            _ => "queued@example.org",
+        };
+        let email = Email::parse(&job.raw).unwrap();
+        let delivered_to = email
+            .headers()
+            .find(|(name, _)| name.as_str() == "Delivered-To")
+            .map(|(_, value)| value.trim().to_string());
+        assert_eq!(delivered_to.as_deref(), Some(recipient));
+    }
+
+    #[tokio::test]
+    async fn test_dedupe_per_destination() {
+        for dedupe in [false, true] {
+            let mut config = Config::default();
+            config.dedupe_per_destination = dedupe;
+            config
+                .dest_map
+                .insert(
+                    ["a@example.org", "b@example.org"],
+                    Arc::new(Mapping::new("shared", Box::new(FailingDestination))),
+                )
+                .unwrap();
+            let queue = Arc::new(DeliveryQueue::new());
+            Dispatcher::new(Arc::new(config), queue.clone())
+                .accept(&email(&["a@example.org", "b@example.org"]))
+                .await
+                .unwrap();
+
+            if dedupe {
+                assert_eq!(queue.len(), 1);
+                let job = queue.try_pop().unwrap();
+                let email = Email::parse(&job.raw).unwrap();
+                let delivered_to: Vec<String> = email
+                    .headers()
+                    .filter(|(name, _)| name.as_str() == "Delivered-To")
+                    .map(|(_, value)| value.trim().to_string())
+                    .collect();
+                assert_eq!(delivered_to, vec!["a@example.org", "b@example.org"]);
+                assert_eq!(job.envelope.unwrap().rcpt_to.len(), 2);
+            } else {
+                assert_eq!(queue.len(), 2);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_synchronous_delivery() {
+        let (dispatcher, queue) = dispatcher(true);
+        let res = dispatcher
+            .accept(&email(&["sync@example.org", "queued@example.org"]))
+            .await;
+        assert!(matches!(
+            res,
+            Err(Error::Smtp(SmtpError {
+                code: SmtpErrorCode::Reply(451),
+                mapping: Some(_),
+                ..
+            }))
+        ));
+        assert_eq!(crate::smtp_server::reply_for(&res.unwrap_err()).code, 451);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_partial_synchronous_delivery() {
+        let delivered = Arc::new(AtomicUsize::new(0));
+        let mut config = Config::default();
+        let mut first = Mapping::new("first", Box::new(CountingDestination(delivered.clone())));
+        first.synchronous_delivery = true;
+        config
+            .dest_map
+            .insert(["queued@example.org"], Arc::new(first))
+            .unwrap();
+        let mut second = Mapping::new("second", Box::new(FailingDestination));
+        second.synchronous_delivery = true;
+        config
+            .dest_map
+            .insert(["sync@example.org"], Arc::new(second))
+            .unwrap();
+        let dispatcher = Dispatcher::new(Arc::new(config), Arc::new(DeliveryQueue::new()));
+
+        // The retries of the sender are not delivered to the first mapping again:
+        for _ in 0..2 {
+            assert!(dispatcher
+                .accept(&email(&["queued@example.org", "sync@example.org"]))
+                .await
+                .is_err());
+        }
+        assert_eq!(delivered.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_tenants() {
+        let mut config = Config::default();
+        let mut tenant = Tenant::new("acme", vec!["acme.example".to_string()], "acme.toml");
+        tenant.set_max_emails_per_minute(1);
+        let tenant = Arc::new(tenant);
+        config.tenants.push(tenant.clone());
+        let mut mapping = Mapping::new("acme/alerts", Box::new(FailingDestination));
+        mapping.tenant = Some(tenant);
+        config
+            .dest_map
+            .insert(["alerts@acme.example"], Arc::new(mapping))
+            .unwrap();
+        config
+            .dest_map
+            .insert(
+                ["*"],
+                Arc::new(Mapping::new("catch-all", Box::new(FailingDestination))),
+            )
+            .unwrap();
+        let queue = Arc::new(DeliveryQueue::new());
+        let dispatcher = Dispatcher::new(Arc::new(config), queue.clone());
+
+        // The catch-all mapping does not receive emails for the domains of tenants:
+        let res = dispatcher.accept(&email(&["other@acme.example"])).await;
+        assert!(matches!(res, Err(Error::Policy(_))));
+
+        dispatcher
+            .accept(&email(&["alerts@acme.example", "user@example.org"]))
+            .await
+            .unwrap();
+        assert_eq!(queue.len(), 2);
+        let res = dispatcher.accept(&email(&["alerts@acme.example"])).await;
+        assert_eq!(res.unwrap_err().code(), "smtp.rate_limited");
+        assert_eq!(queue.len(), 2);
+
+        // Test listeners bypass the rate limit:
+        let mut test_dispatcher = Dispatcher::new(dispatcher.config.clone(), queue.clone());
+        test_dispatcher.set_test_mapping(
+            dispatcher
+                .config
+                .dest_map
+                .get("alerts@acme.example")
+                .unwrap()
+                .clone(),
+        );
+        test_dispatcher
+            .accept(&email(&["alerts@acme.example", "unknown@example.org"]))
+            .await
+            .unwrap();
+        assert_eq!(queue.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_listener_restriction() {
+        let mut config = Config::default();
+        let mut internal = Mapping::new("internal", Box::new(FailingDestination));
+        internal.listeners = vec!["127.0.0.1:2525".parse().unwrap()];
+        config
+            .dest_map
+            .insert(["internal@example.org"], Arc::new(internal))
+            .unwrap();
+        let queue = Arc::new(DeliveryQueue::new());
+        let dispatcher = Dispatcher::new(Arc::new(config), queue.clone());
+
+        let mut public = email(&["internal@example.org"]);
+        public.client.listener = Some("0.0.0.0:25".parse().unwrap());
+        let res = dispatcher.accept(&public).await;
+        assert!(matches!(res, Err(Error::Policy(_))));
+
+        let mut internal = email(&["internal@example.org"]);
+        internal.client.listener = Some("127.0.0.1:2525".parse().unwrap());
+        dispatcher.accept(&internal).await.unwrap();
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_header_routing() {
+        let mut config = Config::default();
+        config
+            .dest_map
+            .insert(
+                ["*@example.org"],
+                Arc::new(Mapping::new("catch-all", Box::new(FailingDestination))),
+            )
+            .unwrap();
+        for (name, pattern) in [("other", "^<other@"), ("dispatch-test", "^<dispatch-test@")] {
+            let mut mapping = Mapping::new(name, Box::new(FailingDestination));
+            mapping.headers.push(HeaderCondition {
+                name: "message-id".to_string(),
+                pattern: Regex::new(pattern).unwrap(),
+            });
+            let mut matcher = AddressMatcher::new();
+            matcher
+                .insert(["queued@example.org"], Arc::new(mapping))
+                .unwrap();
+            config.header_mappings.push(matcher);
+        }
+        let queue = Arc::new(DeliveryQueue::new());
+        let dispatcher = Dispatcher::new(Arc::new(config), queue.clone());
+
+        dispatcher
+            .accept(&email(&["queued@example.org", "sync@example.org"]))
+            .await
+            .unwrap();
+        let mut names: Vec<String> = (0..2)
+            .map(|_| queue.try_pop().unwrap().mapping.name.clone())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["catch-all", "dispatch-test"]);
+    }
+
+    #[tokio::test]
+    async fn test_bounce() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log = Arc::new(AuditLog::open(&dir.path().join("audit.log")).unwrap());
+        audit_log
+            .delivered("forwarded@example.com", "relay", None)
+            .unwrap();
+        let (mut dispatcher, _) = dispatcher(false);
+        dispatcher.set_audit_log(audit_log.clone());
+
+        let dsn = b"Message-ID: <dsn@mx.example.org>\r\n\
+Content-Type: multipart/report; report-type=delivery-status; boundary=b\r\n\
+\r\n\
+--b\r\n\
+Content-Type: message/delivery-status\r\n\
+\r\n\
+Final-Recipient: rfc822; unknown@example.net\r\n\
+Action: failed\r\n\
+Status: 5.1.1\r\n\
+--b\r\n\
+Content-Type: text/rfc822-headers\r\n\
+\r\n\
+Message-ID: <forwarded@example.com>\r\n\
+--b--\r\n";
+        let to = vec![lettre::EmailAddress::new("queued@example.org".to_string()).unwrap()];
+        dispatcher
+            .accept(&SmtpEmail::new(None, to, dsn).unwrap())
+            .await
+            .unwrap();
+
+        let content = std::fs::read_to_string(dir.path().join("audit.log")).unwrap();
+        let bounced: serde_json::Value =
+            serde_json::from_str(content.lines().last().unwrap()).unwrap();
+        assert_eq!(bounced["event"], "bounced");
+        assert_eq!(bounced["original_message_id"], "forwarded@example.com");
+        assert_eq!(bounced["kind"], "hard");
+        assert_eq!(bounced["mappings"], serde_json::json!(["relay"]));
+    }
+
+    #[tokio::test]
+    async fn test_unmapped_recipients() {
+        let (dispatcher, queue) = dispatcher(false);
+        let res = dispatcher.accept(&email(&["unknown@example.org"])).await;
+        assert!(matches!(res, Err(Error::Policy(_))));
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter() {
+        let mut config = Config::default();
+        config
+            .dest_map
+            .insert(
+                ["queued@example.org"],
+                Arc::new(Mapping::new("queued", Box::new(FailingDestination))),
+            )
+            .unwrap();
+        config.dead_letter = Some(Arc::new(Mapping::new(
+            "dead-letter",
+            Box::new(FailingDestination),
+        )));
+        let queue = Arc::new(DeliveryQueue::new());
+        let dispatcher = Dispatcher::new(Arc::new(config), queue.clone());
+
+        dispatcher
+            .accept(&email(&["queued@example.org", "typo@example.org"]))
+            .await
+            .unwrap();
+        assert_eq!(queue.len(), 2);
+        let mut names: Vec<String> = (0..2)
+            .map(|_| queue.try_pop().unwrap().mapping.name.clone())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["dead-letter", "queued"]);
+    }
+
+    #[tokio::test]
+    async fn test_archive() {
+        let mut config = Config::default();
+        config
+            .dest_map
+            .insert(
+                ["queued@example.org", "other@example.org"],
+                Arc::new(Mapping::new("queued", Box::new(FailingDestination))),
+            )
+            .unwrap();
+        config.archive = Some(Arc::new(Mapping::new(
+            "archive",
+            Box::new(FailingDestination),
+        )));
+        let queue = Arc::new(DeliveryQueue::new());
+        let dispatcher = Dispatcher::new(Arc::new(config), queue.clone());
+
+        dispatcher
+            .accept(&email(&[
+                "queued@example.org",
+                "other@example.org",
+                "typo@example.org",
+            ]))
+            .await
+            .unwrap();
+        assert_eq!(queue.len(), 3);
+        let archived = (0..3)
+            .map(|_| queue.try_pop().unwrap())
+            .find(|job| job.mapping.name == "archive")
+            .unwrap();
+        let archived = Email::parse(&archived.raw).unwrap();
+        let delivered_to: Vec<String> = archived
+            .headers()
+            .filter(|(name, _)| name.as_str() == "Delivered-To")
+            .map(|(_, value)| value.trim().to_string())
+            .collect();
+        assert_eq!(delivered_to, ["queued@example.org", "other@example.org"]);
+    }
+}
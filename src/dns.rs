@@ -0,0 +1,344 @@
+use trust_dns_resolver::{
+    config::{NameServerConfigGroup, ResolverConfig, ResolverOpts},
+    system_conf::read_system_conf,
+    TokioAsyncResolver,
+};
+
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::Error;
+
+/// The protocol used to query the upstream resolvers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DnsProtocol {
+    /// Plain DNS over UDP, falling back to TCP for large responses.
+    Plain,
+    /// DNS over TLS (RFC 7858).
+    Tls,
+    /// DNS over HTTPS (RFC 8484).
+    Https,
+}
+
+impl DnsProtocol {
+    fn default_port(self) -> u16 {
+        match self {
+            DnsProtocol::Plain => 53,
+            DnsProtocol::Tls => 853,
+            DnsProtocol::Https => 443,
+        }
+    }
+}
+
+/// The `dns` section of the config file: How all parts of the server, that need DNS (e.g. finding the MX hosts of
+/// relay destinations), resolve names.
+#[derive(Debug, PartialEq)]
+pub(crate) struct DnsConfig {
+    /// The upstream resolvers. If empty, the resolvers of the system (`/etc/resolv.conf`) are used.
+    pub(crate) servers: Vec<IpAddr>,
+    pub(crate) port: Option<u16>,
+    pub(crate) protocol: DnsProtocol,
+    /// The name in the certificates of the servers, that is required for DNS over TLS and HTTPS.
+    pub(crate) tls_name: Option<String>,
+    pub(crate) timeout: Duration,
+    pub(crate) attempts: usize,
+    /// The maximum number of cached responses.
+    pub(crate) cache_size: usize,
+    /// Bounds for the time a response is cached, overriding its TTL. Negative responses (e.g. NXDOMAIN) are cached for
+    /// the TTL of the SOA record of the zone.
+    pub(crate) min_ttl: Option<Duration>,
+    pub(crate) max_ttl: Option<Duration>,
+    pub(crate) negative_min_ttl: Option<Duration>,
+    pub(crate) negative_max_ttl: Option<Duration>,
+}
+
+impl Default for DnsConfig {
+    fn default() -> Self {
+        let opts = ResolverOpts::default();
+        DnsConfig {
+            servers: vec![],
+            port: None,
+            protocol: DnsProtocol::Plain,
+            tls_name: None,
+            timeout: opts.timeout,
+            attempts: opts.attempts,
+            cache_size: opts.cache_size,
+            min_ttl: None,
+            max_ttl: None,
+            negative_min_ttl: None,
+            negative_max_ttl: None,
+        }
+    }
+}
+
+impl TryFrom<&toml::map::Map<String, toml::Value>> for DnsConfig {
+    type Error = Error;
+
+    fn try_from(section: &toml::map::Map<String, toml::Value>) -> Result<Self, Self::Error> {
+        let mut config = DnsConfig::default();
+        if let Some(servers) = section.get("servers") {
+            config.servers = servers
+                .as_array()
+                .ok_or_else(|| {
+                    Error::config(
+                        "Field 'dns.servers' has wrong type (expected array).".to_string(),
+                    )
+                })?
+                .iter()
+                .map(|server| {
+                    server
+                        .as_str()
+                        .and_then(|server| server.parse::<IpAddr>().ok())
+                        .ok_or_else(|| {
+                            Error::config(
+                                "Field 'dns.servers' contains a value with wrong type (expected IP address)."
+                                    .to_string(),
+                            )
+                        })
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(port) = section.get("port") {
+            config.port = Some(
+                port.as_integer()
+                    .and_then(|port| u16::try_from(port).ok())
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Field 'dns.port' has wrong type (expected port number).".to_string(),
+                        )
+                    })?,
+            );
+        }
+        if let Some(protocol) = section.get("protocol") {
+            config.protocol = match protocol.as_str() {
+                Some("plain") => DnsProtocol::Plain,
+                Some("tls") => DnsProtocol::Tls,
+                Some("https") => DnsProtocol::Https,
+                _ => {
+                    return Err(Error::config(
+                        "Field 'dns.protocol' has wrong type (expected \"plain\", \"tls\" or \"https\")."
+                            .to_string(),
+                    ))
+                }
+            };
+        }
+        if let Some(tls_name) = section.get("tls_name") {
+            config.tls_name = Some(
+                tls_name
+                    .as_str()
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Field 'dns.tls_name' has wrong type (expected string).".to_string(),
+                        )
+                    })?
+                    .to_string(),
+            );
+        }
+        if let Some(timeout) = section.get("timeout_secs") {
+            config.timeout = Duration::from_secs(
+                timeout
+                    .as_integer()
+                    .and_then(|n| u64::try_from(n).ok())
+                    .filter(|n| *n > 0)
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Field 'dns.timeout_secs' has wrong type (expected positive integer)."
+                                .to_string(),
+                        )
+                    })?,
+            );
+        }
+        if let Some(attempts) = section.get("attempts") {
+            config.attempts = attempts
+                .as_integer()
+                .and_then(|n| usize::try_from(n).ok())
+                .filter(|n| *n > 0)
+                .ok_or_else(|| {
+                    Error::config(
+                        "Field 'dns.attempts' has wrong type (expected positive integer)."
+                            .to_string(),
+                    )
+                })?;
+        }
+        if let Some(cache_size) = section.get("cache_size") {
+            config.cache_size = cache_size
+                .as_integer()
+                .and_then(|n| usize::try_from(n).ok())
+                .ok_or_else(|| {
+                    Error::config(
+                        "Field 'dns.cache_size' has wrong type (expected non-negative integer)."
+                            .to_string(),
+                    )
+                })?;
+        }
+        config.min_ttl = ttl(section, "min_ttl_secs")?;
+        config.max_ttl = ttl(section, "max_ttl_secs")?;
+        config.negative_min_ttl = ttl(section, "negative_min_ttl_secs")?;
+        config.negative_max_ttl = ttl(section, "negative_max_ttl_secs")?;
+
+        if config.servers.is_empty() && config.protocol != DnsProtocol::Plain {
+            return Err(Error::config(
+                "DNS over TLS or HTTPS requires the field 'dns.servers'.".to_string(),
+            ));
+        }
+        if config.protocol != DnsProtocol::Plain && config.tls_name.is_none() {
+            return Err(Error::config(
+                "DNS over TLS or HTTPS requires the field 'dns.tls_name'.".to_string(),
+            ));
+        }
+        for (min, max, name) in [
+            (config.min_ttl, config.max_ttl, "max_ttl_secs"),
+            (
+                config.negative_min_ttl,
+                config.negative_max_ttl,
+                "negative_max_ttl_secs",
+            ),
+        ] {
+            if let (Some(min), Some(max)) = (min, max) {
+                if min > max {
+                    return Err(Error::config(format!(
+                        "Field 'dns.{}' must not be smaller than the corresponding minimum.",
+                        name
+                    )));
+                }
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Reads the optional TTL bound `key` in seconds.
+fn ttl(
+    section: &toml::map::Map<String, toml::Value>,
+    key: &str,
+) -> Result<Option<Duration>, Error> {
+    match section.get(key) {
+        Some(val) => Ok(Some(Duration::from_secs(
+            val.as_integer()
+                .and_then(|n| u64::try_from(n).ok())
+                .ok_or_else(|| {
+                    Error::config(format!(
+                        "Field 'dns.{}' has wrong type (expected non-negative integer).",
+                        key
+                    ))
+                })?,
+        ))),
+        None => Ok(None),
+    }
+}
+
+impl DnsConfig {
+    /// Creates a resolver with this configuration.
+    ///
+    /// The resolver caches positive and negative responses for their TTL (within the configured bounds). Clones of it
+    /// share their cache, so one resolver should be created and cloned for every part of the server, that needs DNS,
+    /// e.g. to avoid repeating the same lookups for every connection.
+    pub(crate) fn resolver(&self) -> Result<TokioAsyncResolver, Error> {
+        let (resolver_config, mut opts) = if self.servers.is_empty() {
+            read_system_conf()?
+        } else {
+            let port = self.port.unwrap_or_else(|| self.protocol.default_port());
+            let tls_name = self.tls_name.clone().unwrap_or_default();
+            let servers = match self.protocol {
+                DnsProtocol::Plain => {
+                    NameServerConfigGroup::from_ips_clear(&self.servers, port, true)
+                }
+                DnsProtocol::Tls => {
+                    NameServerConfigGroup::from_ips_tls(&self.servers, port, tls_name, true)
+                }
+                DnsProtocol::Https => {
+                    NameServerConfigGroup::from_ips_https(&self.servers, port, tls_name, true)
+                }
+            };
+            (
+                ResolverConfig::from_parts(None, vec![], servers),
+                ResolverOpts::default(),
+            )
+        };
+        opts.timeout = self.timeout;
+        opts.attempts = self.attempts;
+        opts.cache_size = self.cache_size;
+        opts.positive_min_ttl = self.min_ttl;
+        opts.positive_max_ttl = self.max_ttl;
+        opts.negative_min_ttl = self.negative_min_ttl;
+        opts.negative_max_ttl = self.negative_max_ttl;
+        Ok(TokioAsyncResolver::tokio(resolver_config, opts)?)
+    }
+}
+
+/// The resolver shared by all parts of the server, that need DNS. It is created when it is first needed, so the system
+/// configuration is only required, if DNS is used at all.
+pub(crate) struct SharedResolver {
+    config: DnsConfig,
+    resolver: Mutex<Option<TokioAsyncResolver>>,
+}
+
+impl SharedResolver {
+    pub(crate) fn new(config: DnsConfig) -> Self {
+        SharedResolver {
+            config,
+            resolver: Mutex::new(None),
+        }
+    }
+
+    /// Returns a clone of the shared resolver, creating it on the first call.
+    pub(crate) fn get(&self) -> Result<TokioAsyncResolver, Error> {
+        let mut resolver = self.resolver.lock().expect("Shared resolver is poisoned.");
+        match *resolver {
+            Some(ref resolver) => Ok(resolver.clone()),
+            None => {
+                let created = self.config.resolver()?;
+                *resolver = Some(created.clone());
+                Ok(created)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dns_config() {
+        let section = toml::from_str(
+            r#"
+servers = [ "9.9.9.9", "2620:fe::fe" ]
+protocol = "tls"
+tls_name = "dns.quad9.net"
+timeout_secs = 2
+cache_size = 1024
+negative_min_ttl_secs = 60
+"#,
+        )
+        .unwrap();
+        let config = DnsConfig::try_from(&section).unwrap();
+        assert_eq!(config.servers.len(), 2);
+        assert_eq!(config.protocol, DnsProtocol::Tls);
+        assert_eq!(config.timeout, Duration::from_secs(2));
+        assert_eq!(config.cache_size, 1024);
+        assert_eq!(config.negative_min_ttl, Some(Duration::from_secs(60)));
+        assert_eq!(config.negative_max_ttl, None);
+        assert_eq!(config.attempts, ResolverOpts::default().attempts);
+
+        let section = toml::from_str("protocol = \"https\"\nservers = [ \"9.9.9.9\" ]").unwrap();
+        assert!(DnsConfig::try_from(&section).is_err());
+        let section = toml::from_str("servers = [ \"dns.example.com\" ]").unwrap();
+        assert!(DnsConfig::try_from(&section).is_err());
+        let section = toml::from_str("min_ttl_secs = 600\nmax_ttl_secs = 60").unwrap();
+        assert!(DnsConfig::try_from(&section).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shared_resolver() {
+        let shared = SharedResolver::new(DnsConfig {
+            servers: vec!["127.0.0.1".parse().unwrap()],
+            ..DnsConfig::default()
+        });
+        assert!(shared.resolver.lock().unwrap().is_none());
+        shared.get().unwrap();
+        assert!(shared.resolver.lock().unwrap().is_some());
+        shared.get().unwrap();
+    }
+}
@@ -1,8 +1,16 @@
 use lettre::{self, EmailAddress};
-use mail_parser::{BodyPart, HeaderName, Message};
+use mail_parser::{
+    Addr, BodyPart, HeaderName, HeaderValue, Message, MessageAttachment, MessagePart,
+    MessageStructure, RfcHeader, RfcHeaders,
+};
+
+use serde_json::{json, Value};
 
 use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
+use crate::smtp_server::{ClientIdentity, MailParams};
 use crate::Error;
 
 #[derive(Debug, PartialEq)]
@@ -10,16 +18,23 @@ pub(crate) struct Email<'a> {
     pub(crate) message_id: String,
     pub(crate) raw: &'a [u8],
     parsed_message: Message<'a>,
+    /// The envelope, with which the email was received over SMTP. It is None for emails, that were loaded from
+    /// storage.
+    pub(crate) envelope: Option<Arc<Envelope>>,
+    /// Whether an earlier delivery of the email failed, so it is delivered later than it was received.
+    pub(crate) retried: bool,
 }
 
 impl<'a, 'b> Email<'a> {
-    fn parse(raw: &'a [u8]) -> Result<Email<'a>, Error> {
+    pub(crate) fn parse(raw: &'a [u8]) -> Result<Email<'a>, Error> {
         if let Some(parsed_message) = Message::parse(raw) {
             if let Some(id) = parsed_message.get_message_id() {
                 Ok(Email {
                     message_id: id.to_string(),
                     raw,
                     parsed_message,
+                    envelope: None,
+                    retried: false,
                 })
             } else {
                 Err(Error::MailParsing("Missing message-id header."))
@@ -35,12 +50,602 @@ impl<'a, 'b> Email<'a> {
         self.parsed_message.get_raw_headers()
     }
 
+    pub fn subject(&'b self) -> Option<&'b str> {
+        self.parsed_message.get_subject()
+    }
+
+    /// Returns the software, that the email claims to be sent with, from the User-Agent or X-Mailer header.
+    pub fn client_software(&self) -> Option<String> {
+        self.headers()
+            .find(|(name, _)| {
+                name.as_str().eq_ignore_ascii_case("user-agent")
+                    || name.as_str().eq_ignore_ascii_case("x-mailer")
+            })
+            .map(|(_, value)| value.trim().to_string())
+    }
+
+    /// Returns the number of Received headers, i.e. the number of SMTP servers, that the email passed.
+    pub fn received_hops(&self) -> usize {
+        self.headers()
+            .filter(|(name, _)| name.as_str().eq_ignore_ascii_case("received"))
+            .count()
+    }
+
+    /// Returns true, if a Received header names `hostname` as the receiving server ("by <hostname>").
+    pub fn received_by(&self, hostname: &str) -> bool {
+        self.headers()
+            .filter(|(name, _)| name.as_str().eq_ignore_ascii_case("received"))
+            .any(|(_, value)| {
+                let words: Vec<&str> = value.split_whitespace().collect();
+                words.windows(2).any(|pair| {
+                    pair[0].eq_ignore_ascii_case("by")
+                        && pair[1].trim_end_matches(';').eq_ignore_ascii_case(hostname)
+                })
+            })
+    }
+
+    /// Returns true, if the email was generated automatically (e.g. a bounce or a vacation reply) according to its
+    /// Auto-Submitted header (RFC 3834).
+    pub fn is_auto_submitted(&self) -> bool {
+        self.headers()
+            .find(|(name, _)| name.as_str().eq_ignore_ascii_case("auto-submitted"))
+            .is_some_and(|(_, value)| {
+                let keyword = value.split(';').next().unwrap_or_default().trim();
+                !keyword.is_empty() && !keyword.eq_ignore_ascii_case("no")
+            })
+    }
+
+    /// Returns true, if the sender asked to deliver the email even if the TLS policy of a hop can't be met, with the
+    /// header "TLS-Required: No" (RFC 8689, section 5).
+    pub fn tls_optional(&self) -> bool {
+        self.headers()
+            .find(|(name, _)| name.as_str().eq_ignore_ascii_case("tls-required"))
+            .is_some_and(|(_, value)| value.trim().eq_ignore_ascii_case("no"))
+    }
+
+    /// Returns true, if the email is an automatic reply, that should not draw attention: a vacation reply or other
+    /// response with `Auto-Submitted: auto-replied`, an email with `Precedence: bulk` (or `junk`, `auto_reply`), an
+    /// email with an X-Autoreply or X-Autorespond header, or the automatic response to a calendar invitation.
+    pub fn is_auto_reply(&self) -> bool {
+        let header = |wanted: &str| {
+            self.headers()
+                .find(|(name, _)| name.as_str().eq_ignore_ascii_case(wanted))
+                .map(|(_, value)| {
+                    value
+                        .split(';')
+                        .next()
+                        .unwrap_or_default()
+                        .trim()
+                        .to_ascii_lowercase()
+                })
+        };
+        if header("auto-submitted").as_deref() == Some("auto-replied")
+            || matches!(
+                header("precedence").as_deref(),
+                Some("bulk" | "junk" | "auto_reply")
+            )
+            || header("x-autoreply").is_some()
+            || header("x-autorespond").is_some()
+        {
+            return true;
+        }
+        // Calendar clients answer invitations with an iCalendar object with the method REPLY:
+        let mut lines = self
+            .raw
+            .split(|b| *b == b'\n')
+            .map(|line| line.strip_suffix(b"\r").unwrap_or(line));
+        lines
+            .clone()
+            .any(|line| line.eq_ignore_ascii_case(b"BEGIN:VCALENDAR"))
+            && lines.any(|line| line.eq_ignore_ascii_case(b"METHOD:REPLY"))
+    }
+
+    /// Returns the link from the List-Unsubscribe header (RFC 2369), preferring HTTPS over mailto links. The second
+    /// value is true, if the list supports unsubscribing with a single POST request to the link (RFC 8058).
+    pub fn unsubscribe_link(&self) -> Option<(String, bool)> {
+        let header = |wanted: &str| {
+            self.headers()
+                .find(|(name, _)| name.as_str().eq_ignore_ascii_case(wanted))
+                .map(|(_, value)| value.into_owned())
+        };
+        let links: Vec<String> = header("list-unsubscribe")?
+            .split(',')
+            .filter_map(|link| {
+                let link = link.trim().strip_prefix('<')?.strip_suffix('>')?;
+                Some(link.split_whitespace().collect())
+            })
+            .collect();
+        let https = links
+            .iter()
+            .find(|link| link.to_ascii_lowercase().starts_with("https://"));
+        let one_click = https.is_some()
+            && header("list-unsubscribe-post").is_some_and(|value| {
+                value
+                    .trim()
+                    .eq_ignore_ascii_case("List-Unsubscribe=One-Click")
+            });
+        https
+            .or_else(|| {
+                links
+                    .iter()
+                    .find(|link| link.to_ascii_lowercase().starts_with("mailto:"))
+            })
+            .map(|link| (link.clone(), one_click))
+    }
+
+    /// Returns the addresses in the To and Cc headers.
+    pub fn header_recipients(&self) -> Vec<String> {
+        let mut recipients = vec![];
+        for value in [self.parsed_message.get_to(), self.parsed_message.get_cc()] {
+            collect_addresses(value, &mut recipients);
+        }
+        recipients
+    }
+
+    /// Returns the addresses in the From header.
+    pub fn header_senders(&self) -> Vec<String> {
+        let mut senders = vec![];
+        collect_addresses(self.parsed_message.get_from(), &mut senders);
+        senders
+    }
+
+    /// Returns true, if `address` is a sender or recipient of the email in its From, To, Cc or Delivered-To headers
+    /// or its envelope, compared case-insensitively.
+    pub(crate) fn involves(&self, address: &str) -> bool {
+        let mut addresses = self.header_senders();
+        addresses.extend(self.header_recipients());
+        addresses.extend(
+            self.headers()
+                .filter(|(name, _)| name.as_str().eq_ignore_ascii_case("Delivered-To"))
+                .map(|(_, value)| value.trim().to_string()),
+        );
+        if let Some(ref envelope) = self.envelope {
+            addresses.extend(envelope.mail_from.iter().chain(&envelope.rcpt_to).cloned());
+        }
+        addresses
+            .iter()
+            .any(|other| other.eq_ignore_ascii_case(address))
+    }
+
+    /// Returns true, if any part of the email is an attachment, i.e. has the disposition "attachment" or a file name.
+    pub fn has_attachment(&self) -> bool {
+        self.mime_tree().walk().iter().any(|part| {
+            part.disposition().as_deref() == Some("attachment") || part.filename().is_some()
+        })
+    }
+
+    /// Returns the envelope recipients of this copy of the email, that don't appear in its To and Cc headers, e.g.
+    /// because they were addressed by Bcc or the headers are forged. The recipients of the copy are taken from the
+    /// `Delivered-To` headers added on routing, or from the envelope, if there are none.
+    pub fn hidden_recipients(&self) -> Vec<String> {
+        let mut recipients: Vec<String> = self
+            .headers()
+            .filter(|(name, _)| name.as_str().eq_ignore_ascii_case("Delivered-To"))
+            .map(|(_, value)| value.trim().to_string())
+            .collect();
+        if recipients.is_empty() {
+            if let Some(ref envelope) = self.envelope {
+                recipients = envelope.rcpt_to.clone();
+            }
+        }
+        self.missing_from_headers(&recipients)
+    }
+
+    /// Returns the addresses of `recipients`, that don't appear in the To and Cc headers, compared case-insensitively.
+    pub(crate) fn missing_from_headers(&self, recipients: &[String]) -> Vec<String> {
+        let header_recipients = self.header_recipients();
+        recipients
+            .iter()
+            .filter(|recipient| {
+                !header_recipients
+                    .iter()
+                    .any(|other| other.eq_ignore_ascii_case(recipient))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the Message-IDs of the earlier emails of the conversation, that this email refers to in its
+    /// In-Reply-To and References headers, starting with the most recent one.
+    pub fn thread_references(&self) -> Vec<String> {
+        let mut references: Vec<String> = vec![];
+        let mut push = |value: &HeaderValue<'_>| match value {
+            HeaderValue::Text(id) => references.push(id.to_string()),
+            HeaderValue::TextList(ids) => {
+                references.extend(ids.iter().rev().map(|id| id.to_string()))
+            }
+            _ => {}
+        };
+        push(self.parsed_message.get_in_reply_to());
+        push(self.parsed_message.get_references());
+        let mut seen = vec![];
+        references.retain(|id| {
+            let new = !seen.contains(id);
+            seen.push(id.clone());
+            new
+        });
+        references
+    }
+
     pub fn text_body_parts(&'b self) -> impl Iterator<Item = &'b dyn BodyPart<'b>> {
         self.parsed_message.get_text_bodies()
     }
     pub fn html_body_parts(&'b self) -> impl Iterator<Item = &'b dyn BodyPart<'b>> {
         self.parsed_message.get_html_bodies()
     }
+
+    /// Returns the root of the MIME tree of the email, which includes all parts, not only the bodies.
+    pub fn mime_tree(&'b self) -> MimePart<'b> {
+        MimePart::from_message(&self.parsed_message, vec![])
+    }
+}
+
+/// A part of the MIME tree of an email: A multipart with its children, a nested message with its root part as only
+/// child or a leaf with its decoded contents.
+#[derive(Debug)]
+pub(crate) struct MimePart<'b> {
+    /// The indices of the children, that lead from the root to this part.
+    pub(crate) path: Vec<usize>,
+    headers: Option<&'b RfcHeaders<'b>>,
+    contents: &'b [u8],
+    pub(crate) children: Vec<MimePart<'b>>,
+}
+
+impl<'b> MimePart<'b> {
+    fn from_message(message: &'b Message<'b>, path: Vec<usize>) -> Self {
+        let mut root = Self::from_structure(message, &message.structure, path);
+        // The headers of the message are the ones of its root part, except that the parser moves the Content-*
+        // headers of single part messages to the part:
+        if !matches!(message.structure, MessageStructure::Part(_)) {
+            root.headers = Some(&message.headers_rfc);
+        }
+        root
+    }
+
+    fn from_structure(
+        message: &'b Message<'b>,
+        structure: &'b MessageStructure,
+        path: Vec<usize>,
+    ) -> Self {
+        let children = |structures: &'b [MessageStructure], path: &[usize]| {
+            structures
+                .iter()
+                .enumerate()
+                .map(|(i, structure)| {
+                    let mut child_path = path.to_vec();
+                    child_path.push(i);
+                    Self::from_structure(message, structure, child_path)
+                })
+                .collect()
+        };
+        match structure {
+            MessageStructure::Part(id) => match message.parts.get(*id) {
+                Some(part) => Self::from_part(part, path),
+                None => MimePart {
+                    path,
+                    headers: None,
+                    contents: &[],
+                    children: vec![],
+                },
+            },
+            MessageStructure::MultiPart((id, structures)) => MimePart {
+                children: children(structures, &path),
+                headers: match message.parts.get(*id) {
+                    Some(MessagePart::Multipart(part)) => Some(&part.headers_rfc),
+                    _ => None,
+                },
+                path,
+                contents: &[],
+            },
+            // Parts without multipart header are treated like the children of one:
+            MessageStructure::List(structures) => MimePart {
+                children: children(structures, &path),
+                headers: None,
+                path,
+                contents: &[],
+            },
+        }
+    }
+
+    fn from_part(part: &'b MessagePart<'b>, path: Vec<usize>) -> Self {
+        let (headers, contents, children) = match part {
+            MessagePart::Text(part) | MessagePart::Html(part) => {
+                (&part.headers_rfc, part.body.as_bytes(), vec![])
+            }
+            MessagePart::Binary(part) | MessagePart::InlineBinary(part) => {
+                (&part.headers_rfc, part.body.as_ref(), vec![])
+            }
+            MessagePart::Message(part) => match part.body {
+                MessageAttachment::Parsed(ref message) => {
+                    let mut child_path = path.clone();
+                    child_path.push(0);
+                    (
+                        &part.headers_rfc,
+                        message.raw_message.as_ref(),
+                        vec![Self::from_message(message, child_path)],
+                    )
+                }
+                MessageAttachment::Raw(ref raw) => (&part.headers_rfc, raw.as_ref(), vec![]),
+            },
+            MessagePart::Multipart(part) => (&part.headers_rfc, &[][..], vec![]),
+        };
+        MimePart {
+            path,
+            headers: Some(headers),
+            contents,
+            children,
+        }
+    }
+
+    fn header(&self, name: RfcHeader) -> Option<&'b HeaderValue<'b>> {
+        self.headers.and_then(|headers| headers.get(&name))
+    }
+
+    /// Returns the lowercase media type, e.g. "text/plain". Parts without Content-Type are text/plain, unless they
+    /// have children.
+    pub(crate) fn content_type(&self) -> String {
+        match self
+            .header(RfcHeader::ContentType)
+            .and_then(HeaderValue::as_content_type_ref)
+        {
+            Some(content_type) => format!(
+                "{}/{}",
+                content_type.get_type(),
+                content_type.get_subtype().unwrap_or_default()
+            )
+            .to_lowercase(),
+            None if self.children.is_empty() => "text/plain".to_string(),
+            None => "multipart/mixed".to_string(),
+        }
+    }
+
+    /// Returns the lowercase disposition, e.g. "inline" or "attachment", if the part has a Content-Disposition.
+    pub(crate) fn disposition(&self) -> Option<String> {
+        self.header(RfcHeader::ContentDisposition)
+            .and_then(HeaderValue::as_content_type_ref)
+            .map(|disposition| disposition.get_type().to_lowercase())
+    }
+
+    /// Returns the file name from the Content-Disposition or the Content-Type.
+    pub(crate) fn filename(&self) -> Option<&'b str> {
+        self.header(RfcHeader::ContentDisposition)
+            .and_then(HeaderValue::as_content_type_ref)
+            .and_then(|disposition| disposition.get_attribute("filename"))
+            .or_else(|| {
+                self.header(RfcHeader::ContentType)
+                    .and_then(HeaderValue::as_content_type_ref)
+                    .and_then(|content_type| content_type.get_attribute("name"))
+            })
+    }
+
+    /// Returns the Content-ID without angle brackets, that HTML bodies refer to inline images with.
+    pub(crate) fn content_id(&self) -> Option<&'b str> {
+        self.header(RfcHeader::ContentId)
+            .and_then(HeaderValue::as_text_ref)
+            .map(|id| id.trim_start_matches('<').trim_end_matches('>'))
+    }
+
+    /// Returns the decoded contents of a leaf (text as UTF-8) or the raw nested message. Multiparts have no contents.
+    pub(crate) fn contents(&self) -> &'b [u8] {
+        self.contents
+    }
+
+    /// Returns this part and all its descendants in depth-first order.
+    pub(crate) fn walk(&self) -> Vec<&MimePart<'b>> {
+        let mut parts = vec![self];
+        for child in self.children.iter() {
+            parts.extend(child.walk());
+        }
+        parts
+    }
+}
+
+fn collect_addresses(value: &HeaderValue<'_>, addresses: &mut Vec<String>) {
+    let mut push = |addr: &Addr<'_>| {
+        if let Some(ref address) = addr.address {
+            addresses.push(address.to_string());
+        }
+    };
+    match value {
+        HeaderValue::Address(addr) => push(addr),
+        HeaderValue::AddressList(list) => list.iter().for_each(&mut push),
+        HeaderValue::Group(group) => group.addresses.iter().for_each(&mut push),
+        HeaderValue::GroupList(groups) => groups
+            .iter()
+            .flat_map(|group| group.addresses.iter())
+            .for_each(&mut push),
+        _ => {}
+    }
+}
+
+/// The SMTP envelope of a received email and how it was received. This information is not part of the message itself.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Envelope {
+    pub(crate) mail_from: Option<String>,
+    pub(crate) rcpt_to: Vec<String>,
+    pub(crate) client: ClientIdentity,
+    /// The time the email was accepted in seconds since the unix epoch.
+    pub(crate) received_at: u64,
+    /// Whether the sender requested, that the email is only forwarded over TLS (REQUIRETLS).
+    pub(crate) require_tls: bool,
+}
+
+impl Envelope {
+    pub(crate) fn to_json(&self) -> Value {
+        json!({
+            "mail_from": self.mail_from,
+            "rcpt_to": self.rcpt_to,
+            "client_ip": self.client.peer.map(|peer| peer.ip().to_string()),
+            "helo": self.client.helo,
+            "tls": self.client.tls.as_ref().map(|tls| json!({
+                "protocol": tls.protocol,
+                "cipher_suite": tls.cipher_suite,
+                "server_name": tls.server_name,
+            })),
+            "listener": self.client.listener.map(|listener| listener.to_string()),
+            "listener_tls": self.client.listener_tls.name(),
+            "received_at": self.received_at,
+            "require_tls": self.require_tls,
+        })
+    }
+}
+
+/// Returns a copy of the raw email with `Delivered-To` and `X-Original-To` headers for every given envelope recipient
+/// in front of the other headers, so the routing information is kept by destinations, that store the email.
+pub(crate) fn with_routing_headers(raw: &[u8], recipients: &[&str]) -> Vec<u8> {
+    let mut headers = String::new();
+    for recipient in recipients {
+        // Envelope addresses should never contain line breaks, but a header must not be injected in any case:
+        let recipient: String = recipient
+            .chars()
+            .filter(|c| *c != '\r' && *c != '\n')
+            .collect();
+        headers.push_str(&format!(
+            "Delivered-To: {}\r\nX-Original-To: {}\r\n",
+            recipient, recipient
+        ));
+    }
+    let mut routed = Vec::with_capacity(headers.len() + raw.len());
+    routed.extend_from_slice(headers.as_bytes());
+    routed.extend_from_slice(raw);
+    routed
+}
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// A time in UTC split into the parts, that dates are formatted with.
+struct DateParts {
+    weekday: &'static str,
+    day: i64,
+    month: &'static str,
+    year: i64,
+    time: String,
+}
+
+impl DateParts {
+    fn of(secs: u64) -> Self {
+        let days = secs / 86400;
+        let time = secs % 86400;
+        // Convert the days to a date in the Gregorian calendar (algorithm "civil_from_days" by Howard Hinnant):
+        let z = days as i64 + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        DateParts {
+            weekday: WEEKDAYS[(days % 7) as usize],
+            day,
+            month: MONTHS[(month - 1) as usize],
+            year,
+            time: format!(
+                "{:02}:{:02}:{:02}",
+                time / 3600,
+                time % 3600 / 60,
+                time % 60
+            ),
+        }
+    }
+}
+
+/// Formats a time in seconds since the unix epoch as date in the format of RFC 5322, e.g. for Received headers.
+pub(crate) fn rfc5322_date(secs: u64) -> String {
+    let date = DateParts::of(secs);
+    format!(
+        "{}, {} {} {} {} +0000",
+        date.weekday, date.day, date.month, date.year, date.time
+    )
+}
+
+/// Formats a time in seconds since the unix epoch like the C function asctime(), e.g. for the "From " lines of mbox
+/// files.
+pub(crate) fn asctime_date(secs: u64) -> String {
+    let date = DateParts::of(secs);
+    format!(
+        "{} {} {:>2} {} {}",
+        date.weekday, date.month, date.day, date.time, date.year
+    )
+}
+
+/// Formats a time in seconds since the unix epoch as date-time in UTC in the format of RFC 3339.
+pub(crate) fn rfc3339_date(secs: u64) -> String {
+    let date = DateParts::of(secs);
+    let month = MONTHS
+        .iter()
+        .position(|month| *month == date.month)
+        .expect("The month was taken from MONTHS.")
+        + 1;
+    format!("{}-{:02}-{:02}T{}Z", date.year, month, date.day, date.time)
+}
+
+/// Parses a date-time in the format of RFC 3339 (e.g. "2022-03-01T10:00:00Z" or "2022-03-01T11:00:00.5+01:00") and
+/// returns it in seconds since the unix epoch. Fractions of seconds are ignored.
+pub(crate) fn parse_rfc3339(text: &str) -> Option<u64> {
+    let number = |digits: &str| -> Option<i64> {
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    };
+    let (date, time) = text.split_once(['T', 't'])?;
+    let mut date = date.split('-');
+    let (year, month, day) = (
+        number(date.next()?)?,
+        number(date.next()?)?,
+        number(date.next()?)?,
+    );
+    let (clock, offset) = match time.strip_suffix(['Z', 'z']) {
+        Some(clock) => (clock, 0),
+        None => {
+            let (clock, offset) = time.split_at(time.rfind(['+', '-'])?);
+            let (hours, minutes) = offset[1..].split_once(':')?;
+            let secs = number(hours)? * 3600 + number(minutes)? * 60;
+            (clock, if offset.starts_with('-') { -secs } else { secs })
+        }
+    };
+    let mut clock = clock.split('.').next()?.split(':');
+    let (hour, minute, second) = (
+        number(clock.next()?)?,
+        number(clock.next()?)?,
+        number(clock.next()?)?,
+    );
+    if date.next().is_some()
+        || clock.next().is_some()
+        || !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+    // Convert the date to days since the epoch (algorithm "days_from_civil" by Howard Hinnant):
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    u64::try_from(days * 86400 + hour * 3600 + minute * 60 + second - offset).ok()
+}
+
+/// Parses a date in the format of the C function asctime() in UTC (e.g. "Tue Mar  1 10:00:00 2022" from the "From "
+/// line of an mbox file) and returns it in seconds since the unix epoch.
+pub(crate) fn parse_asctime(text: &str) -> Option<u64> {
+    let mut parts = text.split_whitespace().skip(1);
+    let (month, day, time, year) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    let month = MONTHS.iter().position(|name| *name == month)? + 1;
+    let day: u32 = day.parse().ok()?;
+    if parts.next().is_some() {
+        return None;
+    }
+    parse_rfc3339(&format!("{}-{:02}-{:02}T{}Z", year, month, day, time))
 }
 
 #[derive(Debug, PartialEq)]
@@ -48,6 +653,9 @@ pub(crate) struct SmtpEmail<'b> {
     pub(crate) from: Option<EmailAddress>,
     pub(crate) to: Vec<EmailAddress>,
     pub(crate) content: Email<'b>,
+    pub(crate) client: ClientIdentity,
+    /// The parameters of MAIL FROM, e.g. the requested time of delivery.
+    pub(crate) params: MailParams,
 }
 
 impl<'b> SmtpEmail<'b> {
@@ -60,8 +668,23 @@ impl<'b> SmtpEmail<'b> {
             from,
             to,
             content: Email::parse(data)?,
+            client: ClientIdentity::default(),
+            params: MailParams::default(),
         })
     }
+
+    /// Returns the envelope of this email with the current time as time of reception.
+    pub(crate) fn envelope(&self) -> Envelope {
+        Envelope {
+            mail_from: self.from.as_ref().map(|addr| addr.to_string()),
+            rcpt_to: self.to.iter().map(|addr| addr.to_string()).collect(),
+            client: self.client.clone(),
+            received_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            require_tls: self.params.require_tls,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -97,8 +720,239 @@ mod tests {
                     raw: buf.as_slice(),
                     parsed_message: Message::parse(buf.as_slice())
                         .expect("Could not parse message."),
+                    envelope: None,
+                    retried: false,
                 },
+                client: ClientIdentity::default(),
+                params: MailParams::default(),
             }
         }
     }
+
+    #[test]
+    fn test_header_recipients() {
+        let raw = b"From: sender@example.com\r\n\
+To: First <first@example.org>, second@example.org\r\n\
+Cc: team: third@example.org;\r\n\
+Message-ID: <recipients@example.com>\r\n\
+X-Mailer: cron 4.2\r\n\
+\r\n\
+Hello world.\r\n";
+        let email = Email::parse(raw).unwrap();
+        assert_eq!(email.client_software().as_deref(), Some("cron 4.2"));
+
+        let routed = with_routing_headers(raw, &["first@example.org\r\nBcc: evil@example.org"]);
+        let routed = Email::parse(&routed).unwrap();
+        let headers: Vec<(String, String)> = routed
+            .headers()
+            .take(2)
+            .map(|(name, value)| (name.as_str().to_string(), value.trim().to_string()))
+            .collect();
+        assert_eq!(
+            headers,
+            vec![
+                (
+                    "Delivered-To".to_string(),
+                    "first@example.orgBcc: evil@example.org".to_string()
+                ),
+                (
+                    "X-Original-To".to_string(),
+                    "first@example.orgBcc: evil@example.org".to_string()
+                ),
+            ]
+        );
+        assert_eq!(routed.message_id, email.message_id);
+        assert_eq!(
+            email.header_recipients(),
+            vec![
+                "first@example.org",
+                "second@example.org",
+                "third@example.org"
+            ]
+        );
+        assert!(email.hidden_recipients().is_empty());
+        assert_eq!(email.header_senders(), vec!["sender@example.com"]);
+        assert!(!email.has_attachment());
+
+        let routed = with_routing_headers(raw, &["FIRST@example.org", "hidden@example.org"]);
+        let routed = Email::parse(&routed).unwrap();
+        assert_eq!(routed.hidden_recipients(), vec!["hidden@example.org"]);
+        assert!(routed.involves("Hidden@example.org"));
+        assert!(email.involves("SENDER@example.com"));
+        assert!(email.involves("third@example.org"));
+        assert!(!email.involves("hidden@example.org"));
+    }
+
+    #[test]
+    fn test_mime_tree() {
+        let raw = b"Message-ID: <tree@example.com>\r\n\
+Content-Type: multipart/mixed; boundary=outer\r\n\
+\r\n\
+--outer\r\n\
+Content-Type: multipart/related; boundary=inner\r\n\
+\r\n\
+--inner\r\n\
+Content-Type: text/html; charset=utf-8\r\n\
+\r\n\
+<img src=\"cid:logo@example.com\">\r\n\
+--inner\r\n\
+Content-Type: image/png\r\n\
+Content-ID: <logo@example.com>\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+iVBORw==\r\n\
+--inner--\r\n\
+--outer\r\n\
+Content-Type: message/rfc822\r\n\
+Content-Disposition: attachment; filename=\"forwarded.eml\"\r\n\
+\r\n\
+Message-ID: <nested@example.com>\r\n\
+Subject: Nested\r\n\
+\r\n\
+Nested body.\r\n\
+--outer--\r\n";
+        let email = Email::parse(raw).unwrap();
+        let tree = email.mime_tree();
+        let parts: Vec<(Vec<usize>, String)> = tree
+            .walk()
+            .into_iter()
+            .map(|part| (part.path.clone(), part.content_type()))
+            .collect();
+        assert_eq!(
+            parts,
+            vec![
+                (vec![], "multipart/mixed".to_string()),
+                (vec![0], "multipart/related".to_string()),
+                (vec![0, 0], "text/html".to_string()),
+                (vec![0, 1], "image/png".to_string()),
+                (vec![1], "message/rfc822".to_string()),
+                (vec![1, 0], "text/plain".to_string()),
+            ]
+        );
+        let image = &tree.children[0].children[1];
+        assert_eq!(image.content_id(), Some("logo@example.com"));
+        assert_eq!(image.contents(), b"\x89PNG");
+        assert_eq!(image.disposition(), None);
+        let forwarded = &tree.children[1];
+        assert_eq!(forwarded.disposition().as_deref(), Some("attachment"));
+        assert_eq!(forwarded.filename(), Some("forwarded.eml"));
+        assert!(forwarded
+            .contents()
+            .starts_with(b"Message-ID: <nested@example.com>"));
+        assert_eq!(forwarded.children[0].contents(), b"Nested body.");
+        assert!(email.has_attachment());
+    }
+
+    #[test]
+    fn test_loop_headers() {
+        let raw = b"Received: from mx.example.org by relay.example.com; Tue, 1 Mar 2022 10:00:00 +0000\r\n\
+Received: from client by mx.example.org with ESMTP; Tue, 1 Mar 2022 09:59:59 +0000\r\n\
+Auto-Submitted: auto-replied; owner-email=\"me@example.org\"\r\n\
+Message-ID: <loop@example.com>\r\n\
+\r\n\
+I am on vacation.\r\n";
+        let email = Email::parse(raw).unwrap();
+        assert_eq!(email.received_hops(), 2);
+        assert!(email.received_by("relay.example.com"));
+        assert!(email.received_by("MX.example.org"));
+        assert!(!email.received_by("client"));
+        assert!(email.is_auto_submitted());
+
+        let email = Email::parse(b"Auto-Submitted: no\r\nMessage-ID: <a@b>\r\n\r\nHi\r\n").unwrap();
+        assert!(!email.is_auto_submitted());
+        assert_eq!(email.received_hops(), 0);
+    }
+
+    #[test]
+    fn test_auto_reply() {
+        let is_auto_reply = |raw: &[u8]| Email::parse(raw).unwrap().is_auto_reply();
+        assert!(is_auto_reply(
+            b"Auto-Submitted: auto-replied\r\nMessage-ID: <a@b>\r\n\r\nI am on vacation.\r\n"
+        ));
+        assert!(is_auto_reply(
+            b"Precedence: Bulk\r\nMessage-ID: <a@b>\r\n\r\nNewsletter\r\n"
+        ));
+        assert!(is_auto_reply(
+            b"X-Autoreply: yes\r\nMessage-ID: <a@b>\r\n\r\nI am on vacation.\r\n"
+        ));
+        assert!(is_auto_reply(
+            b"Message-ID: <a@b>\r\nContent-Type: text/calendar; method=REPLY\r\n\r\n\
+BEGIN:VCALENDAR\r\nMETHOD:REPLY\r\nEND:VCALENDAR\r\n"
+        ));
+        assert!(!is_auto_reply(
+            b"Auto-Submitted: auto-generated\r\nMessage-ID: <a@b>\r\n\r\nBackup failed.\r\n"
+        ));
+        assert!(!is_auto_reply(
+            b"Message-ID: <a@b>\r\n\r\nBEGIN:VCALENDAR\r\nMETHOD:REQUEST\r\nEND:VCALENDAR\r\n"
+        ));
+    }
+
+    #[test]
+    fn test_unsubscribe_link() {
+        let email = Email::parse(
+            b"List-Unsubscribe: <mailto:leave@lists.example.com>,\r\n <https://lists.example.com/\r\n leave?id=42>\r\n\
+List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n\
+Message-ID: <news@example.com>\r\n\r\nNews\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            email.unsubscribe_link(),
+            Some(("https://lists.example.com/leave?id=42".to_string(), true))
+        );
+
+        let email = Email::parse(
+            b"List-Unsubscribe: <mailto:leave@lists.example.com>\r\nMessage-ID: <news@example.com>\r\n\r\nNews\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            email.unsubscribe_link(),
+            Some(("mailto:leave@lists.example.com".to_string(), false))
+        );
+        let email = Email::parse(b"Message-ID: <a@b>\r\n\r\nHi\r\n").unwrap();
+        assert_eq!(email.unsubscribe_link(), None);
+    }
+
+    #[test]
+    fn test_thread_references() {
+        let email = Email::parse(
+            b"Message-ID: <c@example.com>\r\n\
+In-Reply-To: <b@example.com>\r\n\
+References: <a@example.com>\r\n <b@example.com>\r\n\
+\r\n\
+Hi\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            email.thread_references(),
+            vec!["b@example.com", "a@example.com"]
+        );
+        let email = Email::parse(b"Message-ID: <a@b>\r\n\r\nHi\r\n").unwrap();
+        assert!(email.thread_references().is_empty());
+    }
+
+    #[test]
+    fn test_rfc5322_date() {
+        assert_eq!(rfc5322_date(0), "Thu, 1 Jan 1970 00:00:00 +0000");
+        assert_eq!(rfc5322_date(1646128800), "Tue, 1 Mar 2022 10:00:00 +0000");
+        assert_eq!(rfc5322_date(951782400), "Tue, 29 Feb 2000 00:00:00 +0000");
+        assert_eq!(asctime_date(1646128800), "Tue Mar  1 10:00:00 2022");
+        assert_eq!(rfc3339_date(1646128800), "2022-03-01T10:00:00Z");
+    }
+
+    #[test]
+    fn test_parse_rfc3339() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("2022-03-01T10:00:00Z"), Some(1646128800));
+        assert_eq!(
+            parse_rfc3339("2022-03-01t11:00:00.75+01:00"),
+            Some(1646128800)
+        );
+        assert_eq!(parse_rfc3339("2000-02-28T19:00:00-05:00"), Some(951782400));
+        assert_eq!(parse_rfc3339("2022-13-01T10:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2022-03-01 10:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2022-03-01T10:00Z"), None);
+        assert_eq!(parse_asctime("Tue Mar  1 10:00:00 2022"), Some(1646128800));
+        assert_eq!(parse_asctime("Tue Mar  1 10:00:00 2022 +0100"), None);
+        assert_eq!(parse_asctime("Tue Mrz  1 10:00:00 2022"), None);
+    }
 }
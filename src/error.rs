@@ -0,0 +1,515 @@
+use std::{any::Any, fmt, io, net::IpAddr};
+
+/// The errors of this crate.
+///
+/// Besides a description, the variants carry a code classifying the error and, where available, the source error and
+/// the context it occurred in (mapping name, peer IP). This allows callers to handle errors programmatically, e.g. to
+/// decide whether a delivery should be retried or which SMTP reply should be sent.
+#[derive(Debug)]
+pub(crate) enum Error {
+    /// The communication with a message broker (e.g. an MQTT broker) failed.
+    Broker(BrokerError),
+    Config(ConfigError),
+    /// The communication with a database server (e.g. PostgreSQL) failed.
+    Database(DatabaseError),
+    Dns(trust_dns_resolver::error::ResolveError),
+    Http(HttpError),
+    MailParsing(&'static str),
+    Matrix(MatrixError),
+    /// A destination implementation panicked while delivering an email.
+    Panic(PanicError),
+    /// The email is refused by the rules of this server, e.g. because none of its recipients is mapped.
+    Policy(String),
+    Smtp(SmtpError),
+    SysIo(io::Error),
+    Tls(rustls::Error),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BrokerErrorCode {
+    /// The broker could not be reached or is temporarily unavailable.
+    Unavailable,
+    /// The broker rejected the credentials or the client is not authorized.
+    Auth,
+    /// The broker violated the protocol or rejected the request for another reason.
+    Protocol,
+}
+
+#[derive(Debug)]
+pub(crate) struct BrokerError {
+    pub(crate) code: BrokerErrorCode,
+    pub(crate) desc: String,
+    pub(crate) mapping: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct ConfigError {
+    pub(crate) desc: String,
+    pub(crate) mapping: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum DatabaseErrorCode {
+    /// The server could not be reached, is shutting down or the transaction failed for a transient reason (e.g. a
+    /// deadlock).
+    Unavailable,
+    /// The server rejected the credentials or the user lacks a privilege.
+    Auth,
+    /// The server rejected the connection or the statement for another reason, e.g. because the table does not exist.
+    Query,
+}
+
+#[derive(Debug)]
+pub(crate) struct DatabaseError {
+    pub(crate) code: DatabaseErrorCode,
+    pub(crate) desc: String,
+    pub(crate) mapping: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct HttpError {
+    /// The status of the response or None, if no response was received.
+    pub(crate) status: Option<u16>,
+    pub(crate) desc: String,
+    pub(crate) mapping: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum MatrixErrorCode {
+    /// The homeserver could not be reached or did not respond as expected.
+    Unavailable,
+    /// The homeserver rejected the login data or the restored session.
+    Auth,
+    /// The client is not a member of the configured room.
+    NotInRoom,
+    /// Any other error of the Matrix SDK.
+    Sdk,
+}
+
+#[derive(Debug)]
+pub(crate) struct MatrixError {
+    pub(crate) code: MatrixErrorCode,
+    pub(crate) desc: String,
+    pub(crate) source: Option<Box<matrix_sdk::Error>>,
+    pub(crate) mapping: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct PanicError {
+    /// The message the panic was started with, if it was a string.
+    pub(crate) desc: String,
+    pub(crate) mapping: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SmtpErrorCode {
+    /// The peer violated the SMTP protocol.
+    Protocol,
+    /// The session ended without a complete email.
+    NoMessage,
+    /// The remote server replied with the given (unexpected) reply code.
+    Reply(u16),
+    /// No connection to the remote server could be established.
+    Unreachable,
+    /// The remote server does not support a required extension.
+    MissingExtension,
+    /// The recipient domain does not accept emails (null MX).
+    NullMx,
+    /// We refused the session with 421, e.g. because the listener is paused.
+    Refused,
+    /// We refused the email, because a rate limit was exceeded.
+    RateLimited,
+}
+
+#[derive(Debug)]
+pub(crate) struct SmtpError {
+    pub(crate) code: SmtpErrorCode,
+    pub(crate) desc: String,
+    pub(crate) peer: Option<IpAddr>,
+    pub(crate) mapping: Option<String>,
+}
+
+impl Error {
+    pub(crate) fn broker(code: BrokerErrorCode, desc: impl Into<String>) -> Self {
+        Error::Broker(BrokerError {
+            code,
+            desc: desc.into(),
+            mapping: None,
+        })
+    }
+
+    pub(crate) fn config(desc: impl Into<String>) -> Self {
+        Error::Config(ConfigError {
+            desc: desc.into(),
+            mapping: None,
+        })
+    }
+
+    pub(crate) fn database(code: DatabaseErrorCode, desc: impl Into<String>) -> Self {
+        Error::Database(DatabaseError {
+            code,
+            desc: desc.into(),
+            mapping: None,
+        })
+    }
+
+    pub(crate) fn http(status: Option<u16>, desc: impl Into<String>) -> Self {
+        Error::Http(HttpError {
+            status,
+            desc: desc.into(),
+            mapping: None,
+        })
+    }
+
+    pub(crate) fn matrix(code: MatrixErrorCode, desc: impl Into<String>) -> Self {
+        Error::Matrix(MatrixError {
+            code,
+            desc: desc.into(),
+            source: None,
+            mapping: None,
+        })
+    }
+
+    /// Creates an error from the payload of a caught panic.
+    pub(crate) fn panic(payload: &(dyn Any + Send)) -> Self {
+        let desc = if let Some(desc) = payload.downcast_ref::<&str>() {
+            desc.to_string()
+        } else if let Some(desc) = payload.downcast_ref::<String>() {
+            desc.clone()
+        } else {
+            "Unknown panic payload.".to_string()
+        };
+        Error::Panic(PanicError {
+            desc,
+            mapping: None,
+        })
+    }
+
+    pub(crate) fn smtp(code: SmtpErrorCode, desc: impl Into<String>) -> Self {
+        Error::Smtp(SmtpError {
+            code,
+            desc: desc.into(),
+            peer: None,
+            mapping: None,
+        })
+    }
+
+    /// Adds the name of the mapping, for which the error occurred, to the context of the error.
+    /// Errors, that only wrap the error of another library, don't have a context and are returned unchanged.
+    pub(crate) fn in_mapping(mut self, name: &str) -> Self {
+        match &mut self {
+            Error::Broker(BrokerError { mapping, .. })
+            | Error::Config(ConfigError { mapping, .. })
+            | Error::Database(DatabaseError { mapping, .. })
+            | Error::Http(HttpError { mapping, .. })
+            | Error::Matrix(MatrixError { mapping, .. })
+            | Error::Panic(PanicError { mapping, .. })
+            | Error::Smtp(SmtpError { mapping, .. }) => *mapping = Some(name.to_string()),
+            _ => {}
+        }
+        self
+    }
+
+    /// Adds the IP of the SMTP peer to the context of an SMTP error.
+    pub(crate) fn with_peer(mut self, ip: IpAddr) -> Self {
+        if let Error::Smtp(SmtpError { peer, .. }) = &mut self {
+            *peer = Some(ip);
+        }
+        self
+    }
+
+    /// Returns a stable identifier of the kind of this error, e.g. for logs and metrics.
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            Error::Broker(e) => match e.code {
+                BrokerErrorCode::Unavailable => "broker.unavailable",
+                BrokerErrorCode::Auth => "broker.auth",
+                BrokerErrorCode::Protocol => "broker.protocol",
+            },
+            Error::Config(_) => "config",
+            Error::Database(e) => match e.code {
+                DatabaseErrorCode::Unavailable => "database.unavailable",
+                DatabaseErrorCode::Auth => "database.auth",
+                DatabaseErrorCode::Query => "database.query",
+            },
+            Error::Dns(_) => "dns",
+            Error::Http(e) => match e.status {
+                Some(_) => "http.status",
+                None => "http.unavailable",
+            },
+            Error::MailParsing(_) => "mail_parsing",
+            Error::Matrix(e) => match e.code {
+                MatrixErrorCode::Unavailable => "matrix.unavailable",
+                MatrixErrorCode::Auth => "matrix.auth",
+                MatrixErrorCode::NotInRoom => "matrix.not_in_room",
+                MatrixErrorCode::Sdk => "matrix.sdk",
+            },
+            Error::Panic(_) => "destination.panic",
+            Error::Policy(_) => "policy",
+            Error::Smtp(e) => match e.code {
+                SmtpErrorCode::Protocol => "smtp.protocol",
+                SmtpErrorCode::NoMessage => "smtp.no_message",
+                SmtpErrorCode::Reply(_) => "smtp.reply",
+                SmtpErrorCode::Unreachable => "smtp.unreachable",
+                SmtpErrorCode::MissingExtension => "smtp.missing_extension",
+                SmtpErrorCode::NullMx => "smtp.null_mx",
+                SmtpErrorCode::Refused => "smtp.refused",
+                SmtpErrorCode::RateLimited => "smtp.rate_limited",
+            },
+            Error::SysIo(_) => "io",
+            Error::Tls(_) => "tls",
+        }
+    }
+
+    /// Returns true, if repeating the failed operation later might succeed.
+    pub(crate) fn is_temporary(&self) -> bool {
+        match self {
+            Error::Config(_) | Error::MailParsing(_) | Error::Policy(_) | Error::Tls(_) => false,
+            // The bug may only be triggered by the current state of the destination (e.g. an unusual homeserver
+            // response), so the delivery is retried:
+            Error::Dns(_) | Error::Panic(_) | Error::SysIo(_) => true,
+            Error::Broker(e) => e.code == BrokerErrorCode::Unavailable,
+            Error::Database(e) => e.code == DatabaseErrorCode::Unavailable,
+            // Server errors and rate limits may be over soon, other client errors will not go away:
+            Error::Http(e) => e.status.is_none_or(|status| status >= 500 || status == 429),
+            Error::Matrix(e) => {
+                matches!(e.code, MatrixErrorCode::Unavailable | MatrixErrorCode::Sdk)
+            }
+            Error::Smtp(e) => match e.code {
+                SmtpErrorCode::Reply(reply) => (400..500).contains(&reply),
+                SmtpErrorCode::Unreachable
+                | SmtpErrorCode::Refused
+                | SmtpErrorCode::RateLimited => true,
+                SmtpErrorCode::Protocol
+                | SmtpErrorCode::NoMessage
+                | SmtpErrorCode::MissingExtension
+                | SmtpErrorCode::NullMx => false,
+            },
+        }
+    }
+
+    /// Returns true, if the destination rejected the credentials, that it was accessed with, e.g. because they
+    /// expired or were rotated.
+    pub(crate) fn is_auth_failure(&self) -> bool {
+        match self {
+            Error::Http(e) => e.status == Some(401),
+            Error::Matrix(e) => e.code == MatrixErrorCode::Auth,
+            Error::Smtp(e) => e.code == SmtpErrorCode::Reply(535),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+
+        match self {
+            Broker(e) => {
+                write!(f, "Error in communication with message broker")?;
+                if let Some(ref mapping) = e.mapping {
+                    write!(f, " for mapping '{}'", mapping)?;
+                }
+                write!(f, ": {}", e.desc)
+            }
+            Config(e) => {
+                write!(f, "Error in config")?;
+                if let Some(ref mapping) = e.mapping {
+                    write!(f, " for mapping '{}'", mapping)?;
+                }
+                write!(f, ": {}", e.desc)
+            }
+            Database(e) => {
+                write!(f, "Error in database communication")?;
+                if let Some(ref mapping) = e.mapping {
+                    write!(f, " for mapping '{}'", mapping)?;
+                }
+                write!(f, ": {}", e.desc)
+            }
+            Dns(inner) => write!(f, "Error during DNS resolution: {}", inner),
+            Http(e) => {
+                write!(f, "Error in HTTP request")?;
+                if let Some(ref mapping) = e.mapping {
+                    write!(f, " for mapping '{}'", mapping)?;
+                }
+                write!(f, ": {}", e.desc)
+            }
+            MailParsing(desc) => write!(f, "Could not parse email: {}", desc),
+            Matrix(e) => {
+                write!(f, "Error in Matrix communication")?;
+                if let Some(ref mapping) = e.mapping {
+                    write!(f, " for mapping '{}'", mapping)?;
+                }
+                write!(f, ": {}", e.desc)?;
+                if let Some(ref source) = e.source {
+                    write!(f, ": {}", source)?;
+                }
+                Ok(())
+            }
+            Panic(e) => {
+                write!(f, "Destination panicked")?;
+                if let Some(ref mapping) = e.mapping {
+                    write!(f, " for mapping '{}'", mapping)?;
+                }
+                write!(f, ": {}", e.desc)
+            }
+            Policy(desc) => write!(f, "Email refused: {}", desc),
+            Smtp(e) => {
+                write!(f, "Error in SMTP communication")?;
+                if let Some(ref peer) = e.peer {
+                    write!(f, " with {}", peer)?;
+                }
+                if let Some(ref mapping) = e.mapping {
+                    write!(f, " for mapping '{}'", mapping)?;
+                }
+                write!(f, ": {}", e.desc)
+            }
+            SysIo(inner) => write!(f, "IO error: {}", inner),
+            Tls(inner) => write!(f, "TLS error: {}", inner),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Dns(inner) => Some(inner),
+            Error::Matrix(MatrixError {
+                source: Some(inner),
+                ..
+            }) => Some(inner.as_ref()),
+            Error::SysIo(inner) => Some(inner),
+            Error::Tls(inner) => Some(inner),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(inner: io::Error) -> Self {
+        Self::SysIo(inner)
+    }
+}
+impl From<rustls::Error> for Error {
+    fn from(inner: rustls::Error) -> Self {
+        Self::Tls(inner)
+    }
+}
+impl From<log4rs::config::runtime::ConfigErrors> for Error {
+    fn from(inner: log4rs::config::runtime::ConfigErrors) -> Self {
+        match inner.errors().first() {
+            Some(log4rs::config::runtime::ConfigError::DuplicateAppenderName(descr)) => {
+                Self::config(format!(
+                    "Duplicate Appender name in logger configuration: {}",
+                    descr
+                ))
+            }
+            Some(log4rs::config::runtime::ConfigError::NonexistentAppender(descr)) => Self::config(
+                format!("Nonexistent Appender in logger configuration: {}", descr),
+            ),
+            Some(log4rs::config::runtime::ConfigError::DuplicateLoggerName(descr)) => Self::config(
+                format!("Duplicate Logger name in logger configuration: {}", descr),
+            ),
+            Some(log4rs::config::runtime::ConfigError::InvalidLoggerName(descr)) => Self::config(
+                format!("Invalid Logger name in logger configuration: {}", descr),
+            ),
+            _ => Self::config("Error in logger configuration."),
+        }
+    }
+}
+impl From<log::SetLoggerError> for Error {
+    fn from(inner: log::SetLoggerError) -> Self {
+        Self::config(format!("Error while setting logger: {}", inner))
+    }
+}
+impl From<trust_dns_resolver::error::ResolveError> for Error {
+    fn from(inner: trust_dns_resolver::error::ResolveError) -> Self {
+        Self::Dns(inner)
+    }
+}
+impl From<matrix_sdk::Error> for Error {
+    fn from(inner: matrix_sdk::Error) -> Self {
+        match inner {
+            matrix_sdk::Error::Io(e) => Error::SysIo(e),
+            other => {
+                let (code, desc) = if is_unknown_token(&other) {
+                    (
+                        MatrixErrorCode::Auth,
+                        "Homeserver rejected the access token",
+                    )
+                } else if matches!(other, matrix_sdk::Error::Http(_)) {
+                    (MatrixErrorCode::Unavailable, "Request to homeserver failed")
+                } else {
+                    (MatrixErrorCode::Sdk, "Matrix SDK failed")
+                };
+                Error::Matrix(MatrixError {
+                    code,
+                    desc: desc.to_string(),
+                    source: Some(Box::new(other)),
+                    mapping: None,
+                })
+            }
+        }
+    }
+}
+
+/// Returns true, if the homeserver answered with M_UNKNOWN_TOKEN, e.g. because the access token expired or the device
+/// was logged out.
+fn is_unknown_token(e: &matrix_sdk::Error) -> bool {
+    use matrix_sdk::{HttpError, RumaApiError};
+    use ruma::api::client::error::ErrorKind;
+    use ruma::api::error::{FromHttpResponseError, ServerError};
+
+    matches!(
+        e,
+        matrix_sdk::Error::Http(HttpError::Api(FromHttpResponseError::Server(
+            ServerError::Known(RumaApiError::ClientApi(ruma::api::client::Error {
+                kind: ErrorKind::UnknownToken { .. },
+                ..
+            }))
+        )))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_in_display() {
+        let e = Error::smtp(SmtpErrorCode::Reply(451), "Try again later.")
+            .with_peer("192.0.2.1".parse().unwrap())
+            .in_mapping("example");
+        assert_eq!(
+            e.to_string(),
+            "Error in SMTP communication with 192.0.2.1 for mapping 'example': Try again later."
+        );
+        assert_eq!(e.code(), "smtp.reply");
+    }
+
+    #[test]
+    fn test_is_temporary() {
+        assert!(Error::smtp(SmtpErrorCode::Reply(451), "").is_temporary());
+        assert!(!Error::smtp(SmtpErrorCode::Reply(550), "").is_temporary());
+        assert!(Error::matrix(MatrixErrorCode::Unavailable, "").is_temporary());
+        assert!(!Error::matrix(MatrixErrorCode::Auth, "").is_temporary());
+        assert!(Error::http(Some(503), "").is_temporary());
+        assert!(Error::http(None, "").is_temporary());
+        assert!(!Error::http(Some(404), "").is_temporary());
+        assert!(!Error::config("").is_temporary());
+        assert!(Error::broker(BrokerErrorCode::Unavailable, "").is_temporary());
+        assert!(!Error::broker(BrokerErrorCode::Auth, "").is_temporary());
+        assert!(Error::database(DatabaseErrorCode::Unavailable, "").is_temporary());
+        assert!(!Error::database(DatabaseErrorCode::Query, "").is_temporary());
+    }
+
+    #[test]
+    fn test_is_auth_failure() {
+        assert!(Error::http(Some(401), "").is_auth_failure());
+        assert!(!Error::http(Some(403), "").is_auth_failure());
+        assert!(Error::smtp(SmtpErrorCode::Reply(535), "").is_auth_failure());
+        assert!(!Error::smtp(SmtpErrorCode::Reply(550), "").is_auth_failure());
+        assert!(Error::matrix(MatrixErrorCode::Auth, "").is_auth_failure());
+        assert!(!Error::config("").is_auth_failure());
+    }
+}
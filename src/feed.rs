@@ -0,0 +1,120 @@
+use tokio::sync::broadcast;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::email::{rfc5322_date, SmtpEmail};
+use crate::Error;
+
+/// The number of events buffered for subscribers, that don't keep up. Older events are skipped for them.
+const FEED_CAPACITY: usize = 256;
+
+/// A live feed of the mail flow: accepted emails and the outcomes of their deliveries.
+///
+/// Every event is a line with tab-separated fields, starting with the time and the kind of the event:
+/// - `accepted`, the message ID, the envelope sender, the envelope recipients (separated by commas) and the subject
+/// - `delivered`, the message ID and the mapping
+/// - `failed`, the message ID, the mapping and the error
+///
+/// Events are only formatted while someone is subscribed.
+pub(crate) struct MailFeed {
+    sender: broadcast::Sender<String>,
+}
+
+impl MailFeed {
+    pub(crate) fn new() -> Self {
+        let (sender, _) = broadcast::channel(FEED_CAPACITY);
+        MailFeed { sender }
+    }
+
+    /// Returns a receiver for all events published from now on.
+    pub(crate) fn subscribe(&self) -> broadcast::Receiver<String> {
+        self.sender.subscribe()
+    }
+
+    pub(crate) fn accepted(&self, email: &SmtpEmail<'_>) {
+        self.publish(|| {
+            let recipients: Vec<String> = email.to.iter().map(ToString::to_string).collect();
+            vec![
+                "accepted".to_string(),
+                email.content.message_id.clone(),
+                email
+                    .from
+                    .as_ref()
+                    .map_or_else(String::new, ToString::to_string),
+                recipients.join(","),
+                email.content.subject().unwrap_or_default().to_string(),
+            ]
+        });
+    }
+
+    pub(crate) fn delivered(&self, message_id: &str, mapping: &str, result: &Result<(), Error>) {
+        self.publish(|| match result {
+            Ok(()) => vec![
+                "delivered".to_string(),
+                message_id.to_string(),
+                mapping.to_string(),
+            ],
+            Err(e) => vec![
+                "failed".to_string(),
+                message_id.to_string(),
+                mapping.to_string(),
+                e.to_string(),
+            ],
+        });
+    }
+
+    fn publish(&self, fields: impl FnOnce() -> Vec<String>) {
+        if self.sender.receiver_count() == 0 {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let mut line = rfc5322_date(now);
+        for field in fields() {
+            line.push('\t');
+            // Subjects and errors must not break the line format:
+            line.extend(field.chars().map(|c| if c.is_control() { ' ' } else { c }));
+        }
+        // Sending only fails without subscribers:
+        let _ = self.sender.send(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_events() {
+        let feed = MailFeed::new();
+        // Without subscribers nothing is buffered:
+        feed.delivered("lost@example.com", "files", &Ok(()));
+
+        let mut receiver = feed.subscribe();
+        let email = SmtpEmail::new(
+            Some("cron@example.org".parse().unwrap()),
+            vec![
+                "a@example.com".parse().unwrap(),
+                "b@example.com".parse().unwrap(),
+            ],
+            b"Message-ID: <feed@example.com>\r\nSubject: Backup\tdone\r\n\r\nHi\r\n",
+        )
+        .unwrap();
+        feed.accepted(&email);
+        feed.delivered(
+            "feed@example.com",
+            "files",
+            &Err(Error::config("Disk on fire.")),
+        );
+
+        let accepted = receiver.try_recv().unwrap();
+        assert!(accepted.ends_with(
+            "\taccepted\tfeed@example.com\tcron@example.org\ta@example.com,b@example.com\tBackup done"
+        ));
+        let failed = receiver.try_recv().unwrap();
+        assert!(failed.contains("\tfailed\tfeed@example.com\tfiles\t"));
+        assert!(failed.ends_with("Disk on fire."));
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -0,0 +1,388 @@
+//! Active/passive high availability: Two servers share a state directory (e.g. on NFS), and only the one holding the
+//! lease in it accepts and delivers emails. The queued deliveries are kept in a spool in the shared directory, so the
+//! passive server delivers them, when it takes over the lease of a failed active one.
+
+use fs2::FileExt;
+use log::{error, info, warn};
+use serde_json::{json, Value};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::email::Envelope;
+use crate::mapping::Mapping;
+use crate::queue::{DeliveryJob, DeliveryQueue};
+use crate::smtp_server::{ByMode, ClientIdentity, DeliverBy, SmtpServer};
+use crate::state_lock;
+use crate::Error;
+
+/// The file in the state directory, that names the active server and the end of its lease.
+const LEASE_FILE: &str = "leader.lease";
+/// The directory in the state directory, that holds the queued deliveries.
+const SPOOL_DIR: &str = "spool";
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// The lease, that makes a server the active one.
+///
+/// The lease file contains the name of the holder and the end of the lease in seconds since the unix epoch. The holder
+/// renews it regularly. Another server takes it over, once it ended, so the clocks of the servers have to be
+/// synchronized.
+pub(crate) struct Lease {
+    path: PathBuf,
+    node: String,
+    duration: Duration,
+}
+
+impl Lease {
+    pub(crate) fn new(state_dir: &Path, node: impl Into<String>, duration: Duration) -> Self {
+        Lease {
+            path: state_dir.join(LEASE_FILE),
+            node: node.into(),
+            duration,
+        }
+    }
+
+    /// Takes the lease, if it is free or ended, or renews it, if this server holds it. Returns true, if this server
+    /// holds the lease afterwards.
+    pub(crate) fn acquire(&self) -> Result<bool, Error> {
+        self.update(|holder, until| {
+            (holder == self.node || until <= now()).then(|| now() + self.duration.as_secs())
+        })
+    }
+
+    /// Reads the holder and the end of the lease under a lock and lets this server hold it until the time returned by
+    /// `f`, if any. Returns whether it was changed.
+    fn update(&self, f: impl FnOnce(&str, u64) -> Option<u64>) -> Result<bool, Error> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        file.lock_exclusive()?;
+        let res = (|| {
+            let mut content = String::new();
+            file.read_to_string(&mut content)?;
+            let mut fields = content.split_whitespace();
+            let holder = fields.next().unwrap_or_default();
+            let until = fields
+                .next()
+                .and_then(|until| until.parse().ok())
+                .unwrap_or(0);
+            let until = match f(holder, until) {
+                Some(until) => until,
+                None => return Ok(false),
+            };
+            file.set_len(0)?;
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(format!("{} {}\n", self.node, until).as_bytes())?;
+            file.sync_all()?;
+            Ok(true)
+        })();
+        file.unlock()?;
+        res
+    }
+}
+
+/// Waits for the lease and makes this server the active one, when it gets it: The deliveries in the spool are queued
+/// and the given listeners, which have to be paused before, are resumed.
+///
+/// Returns, when the lease can't be renewed in time anymore. Then this server must stop delivering soon, because the
+/// other one may take over the lease with the remaining third of it.
+pub(crate) async fn run(
+    lease: Lease,
+    servers: Vec<Arc<SmtpServer>>,
+    queue: Arc<DeliveryQueue>,
+    mapping: impl Fn(&str) -> Option<Arc<Mapping>>,
+) {
+    let mut ticker = tokio::time::interval(lease.duration / 3);
+    let mut renewed: Option<Instant> = None;
+    loop {
+        ticker.tick().await;
+        match lease.acquire() {
+            Ok(true) => {
+                if renewed.is_none() {
+                    info!("Became the active server (node '{}').", lease.node);
+                    match queue.restore(&mapping) {
+                        Ok(count) => info!("Queued {} deliveries from the spool.", count),
+                        Err(e) => error!("Could not read the spool: {}", e),
+                    }
+                    for server in servers.iter() {
+                        server.set_paused(false);
+                    }
+                }
+                renewed = Some(Instant::now());
+            }
+            Ok(false) if renewed.is_some() => {
+                error!("Another server took over the lease.");
+                return;
+            }
+            Ok(false) => {}
+            Err(e) => warn!("Could not renew the lease: {}", e),
+        }
+        // Leave a third of the lease as margin for delays and slightly different clocks:
+        if renewed.is_some_and(|renewed| renewed.elapsed() > lease.duration * 2 / 3) {
+            error!("Could not renew the lease in time.");
+            return;
+        }
+    }
+}
+
+/// The queued deliveries in the shared state directory.
+///
+/// Every delivery is stored as two files: `<id>.eml` with the email and `<id>.json` with the mapping and the envelope.
+/// The JSON file is written last, so only complete deliveries are read. Before the first attempt to deliver it, the
+/// file `<id>.attempted` is created, so a server taking over knows, that the delivery may have been interrupted, and
+/// retries it instead of delivering it anew. All files are removed, when the delivery succeeded or was given up.
+pub(crate) struct Spool {
+    dir: PathBuf,
+    node: String,
+    next: AtomicU64,
+}
+
+impl Spool {
+    pub(crate) fn open(state_dir: &Path, node: impl Into<String>) -> Result<Self, Error> {
+        let dir = state_dir.join(SPOOL_DIR);
+        fs::create_dir_all(&dir)?;
+        Ok(Spool {
+            dir,
+            node: node.into(),
+            next: AtomicU64::new(0),
+        })
+    }
+
+    /// Stores a delivery and returns its ID.
+    pub(crate) fn store(&self, job: &DeliveryJob) -> Result<String, Error> {
+        let id = format!(
+            "{}-{}-{}",
+            self.node,
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_nanos()),
+            self.next.fetch_add(1, Ordering::Relaxed)
+        );
+        let envelope = job.envelope.as_ref().map(|envelope| {
+            json!({
+                "mail_from": envelope.mail_from,
+                "rcpt_to": envelope.rcpt_to,
+                "peer": envelope.client.peer.map(|peer| peer.to_string()),
+                "helo": envelope.client.helo,
+                "received_at": envelope.received_at,
+                "require_tls": envelope.require_tls,
+            })
+        });
+        let deliver_by = job.deliver_by.map(|by| {
+            json!({
+                "deadline": by.deadline,
+                "mode": if by.mode == ByMode::Return { "return" } else { "notify" },
+            })
+        });
+        let meta = json!({
+            "mapping": job.mapping.name,
+            "envelope": envelope,
+            "hold_until": job.hold_until,
+            "deliver_by": deliver_by,
+        });
+        let _writing = state_lock::writing();
+        write_synced(&self.dir.join(format!("{}.eml", id)), &job.raw)?;
+        let tmp_path = self.dir.join(format!("{}.tmp", id));
+        write_synced(&tmp_path, meta.to_string().as_bytes())?;
+        fs::rename(&tmp_path, self.dir.join(format!("{}.json", id)))?;
+        Ok(id)
+    }
+
+    /// Marks a delivery as attempted, before it is delivered the first time.
+    pub(crate) fn mark_attempted(&self, id: &str) -> Result<(), Error> {
+        let _writing = state_lock::writing();
+        write_synced(
+            &self.dir.join(format!("{}.attempted", id)),
+            self.node.as_bytes(),
+        )
+    }
+
+    /// Removes a delivery.
+    pub(crate) fn remove(&self, id: &str) -> Result<(), Error> {
+        let _writing = state_lock::writing();
+        // The JSON file goes first, so a delivery is never read without its email:
+        for extension in ["json", "attempted", "eml"] {
+            match fs::remove_file(self.dir.join(format!("{}.{}", id, extension))) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads all stored deliveries. Deliveries for unknown mappings are skipped and stay in the spool.
+    pub(crate) fn load(
+        &self,
+        mapping: impl Fn(&str) -> Option<Arc<Mapping>>,
+    ) -> Result<Vec<DeliveryJob>, Error> {
+        let mut jobs = vec![];
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let id = match (path.extension(), path.file_stem()) {
+                (Some(extension), Some(id)) if extension == "json" => {
+                    id.to_string_lossy().into_owned()
+                }
+                _ => continue,
+            };
+            let meta: Value = match serde_json::from_slice(&fs::read(&path)?) {
+                Ok(meta) => meta,
+                Err(e) => {
+                    warn!("Skipped damaged delivery {} in spool: {}", id, e);
+                    continue;
+                }
+            };
+            let name = meta["mapping"].as_str().unwrap_or_default();
+            let mapping = match mapping(name) {
+                Some(mapping) => mapping,
+                None => {
+                    warn!(
+                        "Skipped delivery {} in spool for unknown mapping '{}'.",
+                        id, name
+                    );
+                    continue;
+                }
+            };
+            let raw = fs::read(self.dir.join(format!("{}.eml", id)))?;
+            let envelope = meta["envelope"].as_object().map(|envelope| {
+                Arc::new(Envelope {
+                    mail_from: envelope["mail_from"].as_str().map(str::to_string),
+                    rcpt_to: envelope["rcpt_to"]
+                        .as_array()
+                        .map(|rcpt_to| {
+                            rcpt_to
+                                .iter()
+                                .filter_map(|rcpt| rcpt.as_str().map(str::to_string))
+                                .collect()
+                        })
+                        .unwrap_or_default(),
+                    client: ClientIdentity {
+                        peer: envelope["peer"].as_str().and_then(|ip| ip.parse().ok()),
+                        helo: envelope["helo"].as_str().map(str::to_string),
+                        ..ClientIdentity::default()
+                    },
+                    received_at: envelope["received_at"].as_u64().unwrap_or(0),
+                    require_tls: envelope["require_tls"].as_bool().unwrap_or(false),
+                })
+            });
+            let mut job = DeliveryJob::new(mapping, Arc::from(raw), envelope);
+            job.hold_until = meta["hold_until"].as_u64();
+            job.deliver_by = meta["deliver_by"]["deadline"]
+                .as_u64()
+                .map(|deadline| DeliverBy {
+                    deadline,
+                    mode: if meta["deliver_by"]["mode"] == "return" {
+                        ByMode::Return
+                    } else {
+                        ByMode::Notify
+                    },
+                });
+            // The delivery was interrupted, e.g. because the server lost the lease, so it is treated as a retry (see
+            // `Email::retried`):
+            match fs::read_to_string(self.dir.join(format!("{}.attempted", id))) {
+                Ok(node) => {
+                    warn!(
+                        "Delivery {} in spool was interrupted on node '{}' and is retried.",
+                        id, node
+                    );
+                    job.attempts = 1;
+                }
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                Err(_) => {}
+            }
+            job.spool_id = Some(id);
+            jobs.push(job);
+        }
+        Ok(jobs)
+    }
+}
+
+/// Writes a new file and waits until it is stored, so it survives a crash of the server.
+fn write_synced(path: &Path, content: &[u8]) -> Result<(), Error> {
+    let mut file = File::create(path)?;
+    file.write_all(content)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maildest::FileDestination;
+
+    #[test]
+    fn test_lease() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = Lease::new(dir.path(), "first", Duration::from_secs(30));
+        let second = Lease::new(dir.path(), "second", Duration::from_secs(30));
+        assert!(first.acquire().unwrap());
+        assert!(!second.acquire().unwrap());
+        assert!(first.acquire().unwrap());
+
+        // The lease of a failed server ends:
+        fs::write(
+            dir.path().join(LEASE_FILE),
+            format!("first {}\n", now() - 1),
+        )
+        .unwrap();
+        assert!(second.acquire().unwrap());
+        assert!(!first.acquire().unwrap());
+    }
+
+    #[test]
+    fn test_spool() {
+        let dir = tempfile::tempdir().unwrap();
+        let mapping = Arc::new(Mapping::new(
+            "files",
+            Box::new(FileDestination::new(dir.path()).unwrap()),
+        ));
+        let spool = Spool::open(dir.path(), "first").unwrap();
+        let mut job = DeliveryJob::new(
+            mapping.clone(),
+            Arc::from(&b"Subject: Hi\r\n\r\nHi\r\n"[..]),
+            Some(Arc::new(Envelope {
+                mail_from: Some("a@example.com".to_string()),
+                rcpt_to: vec!["b@example.com".to_string()],
+                client: ClientIdentity {
+                    peer: Some("192.0.2.1:25".parse().unwrap()),
+                    ..ClientIdentity::default()
+                },
+                received_at: 1700000000,
+                require_tls: true,
+            })),
+        );
+        job.deliver_by = Some(DeliverBy {
+            deadline: 1700000600,
+            mode: ByMode::Return,
+        });
+        let id = spool.store(&job).unwrap();
+        spool.store(&job).unwrap();
+
+        // The other server reads the deliveries with its own mappings:
+        let other = Spool::open(dir.path(), "second").unwrap();
+        assert!(other.load(|_| None).unwrap().is_empty());
+        let jobs = other.load(|_| Some(mapping.clone())).unwrap();
+        assert_eq!(jobs.len(), 2);
+        let restored = jobs
+            .iter()
+            .find(|job| job.spool_id.as_deref() == Some(&id))
+            .unwrap();
+        assert_eq!(&restored.raw[..], &job.raw[..]);
+        assert_eq!(restored.envelope, job.envelope);
+        assert_eq!(restored.deliver_by, job.deliver_by);
+
+        other.remove(&id).unwrap();
+        assert_eq!(spool.load(|_| Some(mapping.clone())).unwrap().len(), 1);
+    }
+}
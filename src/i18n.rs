@@ -0,0 +1,135 @@
+use std::str::FromStr;
+
+use crate::bounce::FailureKind;
+use crate::Error;
+
+/// The language of the text, that is added to notifications (e.g. in Matrix rooms).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub(crate) enum Locale {
+    #[default]
+    En,
+    De,
+    Fr,
+}
+
+impl Locale {
+    /// The line introducing the headers of a new email.
+    pub(crate) fn received_message(&self) -> &'static str {
+        match self {
+            Locale::En => "Received new message:",
+            Locale::De => "Neue Nachricht empfangen:",
+            Locale::Fr => "Nouveau message reçu :",
+        }
+    }
+
+    /// The notice following an email, whose delivery was delayed by failed attempts.
+    pub(crate) fn delayed_message(&self, sender: &str, received_at: &str) -> String {
+        match self {
+            Locale::En => format!(
+                "Delayed delivery: email from {} originally received at {}",
+                sender, received_at
+            ),
+            Locale::De => format!(
+                "Verspätete Zustellung: E-Mail von {}, ursprünglich empfangen am {}",
+                sender, received_at
+            ),
+            Locale::Fr => format!(
+                "Livraison retardée : e-mail de {} reçu initialement le {}",
+                sender, received_at
+            ),
+        }
+    }
+
+    /// The notice about an attachment, that was not uploaded, because it is larger than the limit (both in KB).
+    pub(crate) fn attachment_too_large_message(&self, name: &str, size: u64, limit: u64) -> String {
+        match self {
+            Locale::En => format!(
+                "Attachment {} ({} KB) was not uploaded, because it is larger than {} KB.",
+                name, size, limit
+            ),
+            Locale::De => format!(
+                "Anhang {} ({} KB) wurde nicht hochgeladen, weil er größer als {} KB ist.",
+                name, size, limit
+            ),
+            Locale::Fr => format!(
+                "La pièce jointe {} ({} Ko) n'a pas été téléversée, car elle dépasse {} Ko.",
+                name, size, limit
+            ),
+        }
+    }
+
+    /// The label of the link for unsubscribing from a mailing list.
+    pub(crate) fn unsubscribe_label(&self, one_click: bool) -> &'static str {
+        match (self, one_click) {
+            (Locale::En, false) => "Unsubscribe",
+            (Locale::En, true) => "Unsubscribe (one click)",
+            (Locale::De, false) => "Abbestellen",
+            (Locale::De, true) => "Abbestellen (ein Klick)",
+            (Locale::Fr, false) => "Se désabonner",
+            (Locale::Fr, true) => "Se désabonner (un clic)",
+        }
+    }
+
+    /// The line introducing a bounce for the email with the given Message-ID, which is followed by the failed
+    /// recipients and the headers of the bounce.
+    pub(crate) fn bounce_message(&self, kind: FailureKind, message_id: &str) -> String {
+        match (self, kind) {
+            (Locale::En, FailureKind::Hard) => {
+                format!(
+                    "Received bounce (permanent failure) for email {}:",
+                    message_id
+                )
+            }
+            (Locale::En, FailureKind::Soft) => {
+                format!(
+                    "Received bounce (temporary failure) for email {}:",
+                    message_id
+                )
+            }
+            (Locale::De, FailureKind::Hard) => format!(
+                "Unzustellbarkeitsbericht (dauerhafter Fehler) für E-Mail {} empfangen:",
+                message_id
+            ),
+            (Locale::De, FailureKind::Soft) => format!(
+                "Unzustellbarkeitsbericht (vorübergehender Fehler) für E-Mail {} empfangen:",
+                message_id
+            ),
+            (Locale::Fr, FailureKind::Hard) => format!(
+                "Avis de non-remise (échec permanent) reçu pour l'e-mail {} :",
+                message_id
+            ),
+            (Locale::Fr, FailureKind::Soft) => format!(
+                "Avis de non-remise (échec temporaire) reçu pour l'e-mail {} :",
+                message_id
+            ),
+        }
+    }
+}
+
+impl FromStr for Locale {
+    type Err = Error;
+
+    /// Parses a language tag like "de" or "de-AT". Only the primary language is used.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let language = s.split(['-', '_']).next().unwrap_or_default();
+        match language.to_ascii_lowercase().as_str() {
+            "en" => Ok(Locale::En),
+            "de" => Ok(Locale::De),
+            "fr" => Ok(Locale::Fr),
+            _ => Err(Error::config(format!("Unsupported locale '{}'.", s))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_locale() {
+        assert_eq!("de".parse::<Locale>().unwrap(), Locale::De);
+        assert_eq!("de-AT".parse::<Locale>().unwrap(), Locale::De);
+        assert_eq!("EN_us".parse::<Locale>().unwrap(), Locale::En);
+        assert!("tlh".parse::<Locale>().is_err());
+    }
+}
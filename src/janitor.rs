@@ -0,0 +1,189 @@
+use log::{debug, error, info, warn};
+use tokio::time::interval;
+
+use std::fs;
+use std::io::ErrorKind;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::cli::export::{mapping_messages, sidecar_path, ENVELOPE_SUFFIX, HOLD_SUFFIX};
+use crate::mapping::Mapping;
+use crate::Error;
+
+/// The time between two sweeps.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Deletes the emails stored by file destinations, that are older than the retention period of their mapping.
+///
+/// Emails of mappings with `legal_hold` are never deleted. Single emails are put under legal hold by creating an empty
+/// file named like the email (without the maildir subdirectory and flags) with the suffix `.hold` next to its envelope.
+pub(crate) struct Janitor {
+    mappings: Vec<Arc<Mapping>>,
+}
+
+impl Janitor {
+    /// Creates a janitor for the given mappings, of which only the ones with a retention period are swept.
+    pub(crate) fn new<'a>(mappings: impl Iterator<Item = &'a Arc<Mapping>>) -> Self {
+        let mut mappings: Vec<Arc<Mapping>> = mappings
+            .filter(|mapping| mapping.retention_days.is_some())
+            .filter(|mapping| {
+                if mapping.legal_hold {
+                    info!(
+                        "Mapping '{}' is under legal hold, its emails are not deleted.",
+                        mapping.name
+                    );
+                    false
+                } else if mapping.destination.storage_path().is_none()
+                    || mapping.destination.is_mbox()
+                {
+                    warn!(
+                        "Ignoring the retention period of mapping '{}', because its destination does not store emails in separate files.",
+                        mapping.name
+                    );
+                    false
+                } else {
+                    true
+                }
+            })
+            .cloned()
+            .collect();
+        // A mapping may be used for several addresses:
+        mappings.sort_by(|a, b| a.name.cmp(&b.name));
+        mappings.dedup_by(|a, b| Arc::ptr_eq(a, b));
+        Janitor { mappings }
+    }
+
+    /// Returns true, if there are mappings to sweep.
+    pub(crate) fn is_needed(&self) -> bool {
+        !self.mappings.is_empty()
+    }
+
+    /// Deletes all expired emails, that are not under legal hold, and their envelopes. `now` is the current time in
+    /// seconds since the unix epoch. Returns the number of deleted emails.
+    pub(crate) fn sweep(&self, now: u64) -> usize {
+        let mut deleted = 0;
+        for mapping in self.mappings.iter() {
+            match self.sweep_mapping(mapping, now) {
+                Ok(count) => deleted += count,
+                Err(e) => error!(
+                    "Could not delete expired emails of mapping '{}': {}",
+                    mapping.name, e
+                ),
+            }
+        }
+        deleted
+    }
+
+    fn sweep_mapping(&self, mapping: &Mapping, now: u64) -> Result<usize, Error> {
+        let (dir, retention_days) =
+            match (mapping.destination.storage_path(), mapping.retention_days) {
+                (Some(dir), Some(retention_days)) => (dir, retention_days),
+                _ => return Ok(0),
+            };
+        let deadline = now.saturating_sub(retention_days.saturating_mul(24 * 60 * 60));
+        let mut deleted = 0;
+        for (received_at, _, path) in mapping_messages(mapping)?.unwrap_or_default() {
+            if received_at >= deadline {
+                continue;
+            }
+            if sidecar_path(dir, &path, HOLD_SUFFIX).exists() {
+                debug!(
+                    "Keeping {}, because it is under legal hold.",
+                    path.display()
+                );
+                continue;
+            }
+            fs::remove_file(&path)?;
+            match fs::remove_file(sidecar_path(dir, &path, ENVELOPE_SUFFIX)) {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+                _ => {}
+            }
+            deleted += 1;
+        }
+        if deleted > 0 {
+            info!(
+                "Deleted {} emails of mapping '{}' older than {} days.",
+                deleted, mapping.name, retention_days
+            );
+        }
+        Ok(deleted)
+    }
+
+    /// Sweeps periodically forever.
+    pub(crate) async fn run(&self) {
+        let mut ticker = interval(SWEEP_INTERVAL);
+        loop {
+            ticker.tick().await;
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            self.sweep(now);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maildest::{FileDestination, FileFormat};
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn store(dir: &std::path::Path, name: &str, received_at: u64) {
+        fs::write(
+            dir.join(name),
+            format!("Message-ID: <{}>\r\n\r\nHi\r\n", name),
+        )
+        .unwrap();
+        fs::write(
+            dir.join(format!("{}{}", name, ENVELOPE_SUFFIX)),
+            format!(r#"{{"received_at":{}}}"#, received_at),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_sweep() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = 100 * DAY;
+        store(dir.path(), "old@example.com", now - 31 * DAY);
+        store(dir.path(), "held@example.com", now - 31 * DAY);
+        fs::write(dir.path().join("held@example.com.hold"), "").unwrap();
+        store(dir.path(), "new@example.com", now - 29 * DAY);
+        let mut mapping =
+            Mapping::new("files", Box::new(FileDestination::new(dir.path()).unwrap()));
+        mapping.retention_days = Some(30);
+        let mapping = Arc::new(mapping);
+
+        let janitor = Janitor::new([mapping].iter());
+        assert!(janitor.is_needed());
+        assert_eq!(janitor.sweep(now), 1);
+        assert!(!dir.path().join("old@example.com").exists());
+        assert!(!dir.path().join("old@example.com.envelope.json").exists());
+        assert!(dir.path().join("held@example.com").exists());
+        assert!(dir.path().join("new@example.com").exists());
+        assert_eq!(janitor.sweep(now), 0);
+    }
+
+    #[test]
+    fn test_mapping_under_legal_hold() {
+        let dir = tempfile::tempdir().unwrap();
+        store(dir.path(), "old@example.com", 0);
+        let mut mapping =
+            Mapping::new("files", Box::new(FileDestination::new(dir.path()).unwrap()));
+        mapping.retention_days = Some(1);
+        mapping.legal_hold = true;
+        let held = Arc::new(mapping);
+        let mbox = Arc::new(Mapping::new(
+            "mbox",
+            Box::new(
+                FileDestination::with_format(dir.path().join("a.mbox"), FileFormat::Mbox).unwrap(),
+            ),
+        ));
+
+        let janitor = Janitor::new([held, mbox].iter());
+        assert!(!janitor.is_needed());
+        assert_eq!(janitor.sweep(100 * DAY), 0);
+        assert!(dir.path().join("old@example.com").exists());
+    }
+}
@@ -0,0 +1,182 @@
+use log::kv::{self, Key, Value, VisitSource};
+use log::{Level, Record};
+use log4rs::append::Append;
+
+use std::fmt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+
+/// The socket, on which journald receives entries in its native protocol.
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// An appender, that sends log records to systemd-journald.
+///
+/// Besides the message, every entry has the fields PRIORITY, SYSLOG_IDENTIFIER, TARGET, CODE_FILE and CODE_LINE. The
+/// key-values of a record are added as fields with uppercase names, e.g. `email_message_id`, `peer_ip` and `mapping`
+/// as EMAIL_MESSAGE_ID, PEER_IP and MAPPING. Empty values are left out. (MESSAGE_ID is reserved by journald for
+/// identifying the kind of an entry.)
+pub(crate) struct JournaldAppender {
+    socket: UnixDatagram,
+    path: PathBuf,
+}
+
+impl JournaldAppender {
+    pub(crate) fn new() -> std::io::Result<Self> {
+        Self::with_socket(Path::new(JOURNAL_SOCKET))
+    }
+
+    /// Creates an appender, that sends entries to the given socket instead of the one of journald.
+    fn with_socket(path: &Path) -> std::io::Result<Self> {
+        Ok(JournaldAppender {
+            socket: UnixDatagram::unbound()?,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Returns the fields of the journal entry for `record`.
+    fn fields(&self, record: &Record) -> Vec<(String, String)> {
+        let message = record.args().to_string();
+        let priority = match record.level() {
+            Level::Error => "3",
+            Level::Warn => "4",
+            Level::Info => "6",
+            Level::Debug | Level::Trace => "7",
+        };
+        let mut fields = vec![
+            ("PRIORITY".to_string(), priority.to_string()),
+            ("SYSLOG_IDENTIFIER".to_string(), "kutsche".to_string()),
+            ("TARGET".to_string(), record.target().to_string()),
+        ];
+        if let Some(file) = record.file() {
+            fields.push(("CODE_FILE".to_string(), file.to_string()));
+        }
+        if let Some(line) = record.line() {
+            fields.push(("CODE_LINE".to_string(), line.to_string()));
+        }
+        // Visiting our own fields never fails:
+        let _ = record.key_values().visit(&mut KeyValueFields(&mut fields));
+        fields.push(("MESSAGE".to_string(), message));
+        fields
+    }
+}
+
+/// Adds the key-values of a record to the fields of its journal entry.
+struct KeyValueFields<'a>(&'a mut Vec<(String, String)>);
+
+impl<'kvs> VisitSource<'kvs> for KeyValueFields<'_> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        let value = value.to_string();
+        if !value.is_empty() {
+            self.0.push((field_name(key.as_str()), value));
+        }
+        Ok(())
+    }
+}
+
+/// Returns the name of the journal field for a key, which may only contain uppercase letters, digits and underscores
+/// and must not start with an underscore (these fields are set by journald itself).
+fn field_name(key: &str) -> String {
+    let name: String = key
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    name.trim_start_matches(['_', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9'])
+        .to_string()
+}
+
+/// Serializes fields in the native journal protocol.
+fn serialize<N: AsRef<str>>(fields: &[(N, String)]) -> Vec<u8> {
+    let mut buf = vec![];
+    for (name, value) in fields {
+        buf.extend_from_slice(name.as_ref().as_bytes());
+        if value.contains('\n') {
+            // Values with newlines are written with their length instead of being terminated by a newline:
+            buf.push(b'\n');
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+    buf
+}
+
+impl Append for JournaldAppender {
+    fn append(&self, record: &Record) -> anyhow::Result<()> {
+        self.socket
+            .send_to(&serialize(&self.fields(record)), &self.path)?;
+        Ok(())
+    }
+
+    fn flush(&self) {}
+}
+
+impl fmt::Debug for JournaldAppender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JournaldAppender")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_serialize() {
+        let fields = vec![
+            ("PRIORITY", "6".to_string()),
+            ("MESSAGE", "two\nlines".to_string()),
+        ];
+        assert_eq!(
+            serialize(&fields),
+            b"PRIORITY=6\nMESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n".to_vec()
+        );
+    }
+
+    #[test]
+    fn test_structured_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("journal.socket");
+        let journal = UnixDatagram::bind(&path).unwrap();
+        let appender = JournaldAppender::with_socket(&path).unwrap();
+
+        appender
+            .append(
+                &Record::builder()
+                    .level(Level::Error)
+                    .target("kutsche::queue")
+                    .args(format_args!("Could not forward email: Timeout."))
+                    .key_values(&[
+                        ("email_message_id", "abc@example.com"),
+                        ("peer_ip", "192.0.2.1"),
+                        ("mapping", "alerts"),
+                        ("tenant", ""),
+                    ])
+                    .build(),
+            )
+            .unwrap();
+
+        let mut buf = [0; 1024];
+        let len = journal.recv(&mut buf).unwrap();
+        let entry = String::from_utf8_lossy(&buf[..len]);
+        let fields: Vec<&str> = entry.lines().collect();
+        assert!(fields.contains(&"PRIORITY=3"));
+        assert!(fields.contains(&"TARGET=kutsche::queue"));
+        assert!(fields.contains(&"EMAIL_MESSAGE_ID=abc@example.com"));
+        assert!(fields.contains(&"PEER_IP=192.0.2.1"));
+        assert!(fields.contains(&"MAPPING=alerts"));
+        assert!(!fields.iter().any(|field| field.starts_with("TENANT")));
+    }
+
+    #[test]
+    fn test_field_name() {
+        assert_eq!(field_name("email_message_id"), "EMAIL_MESSAGE_ID");
+        assert_eq!(field_name("_peer-ip"), "PEER_IP");
+    }
+}
@@ -0,0 +1,173 @@
+use log::LevelFilter;
+use log4rs::{
+    append::{console::ConsoleAppender, Append},
+    config::{Appender, Config, Logger, Root},
+};
+
+use std::str::FromStr;
+
+use crate::report;
+use crate::Error;
+use journald::JournaldAppender;
+
+mod journald;
+
+/// Short names for the log targets of a part of the server, that may be used as keys in the `logging` section. Each
+/// includes the libraries, that part uses.
+const ALIASES: &[(&str, &[&str])] = &[
+    ("smtp", &["kutsche::smtp_server", "mailin"]),
+    ("matrix", &["kutsche::maildest::matrix_dest", "matrix_sdk"]),
+    ("relay", &["kutsche::maildest::relay_dest", "lettre"]),
+];
+
+/// Where log messages are written to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LogOutput {
+    Stdout,
+    /// The native protocol of systemd-journald, which allows to add structured fields.
+    Journald,
+}
+
+/// The `logging` section of the config file.
+#[derive(Debug, PartialEq)]
+pub(crate) struct LoggingConfig {
+    pub(crate) output: LogOutput,
+    /// The level of all log targets, that don't have their own level.
+    pub(crate) level: LevelFilter,
+    /// The levels of log targets (module paths).
+    pub(crate) targets: Vec<(String, LevelFilter)>,
+    /// The URL, that reported errors are posted to.
+    pub(crate) error_webhook: Option<String>,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        LoggingConfig {
+            output: LogOutput::Stdout,
+            level: LevelFilter::Info,
+            targets: vec![],
+            error_webhook: None,
+        }
+    }
+}
+
+impl TryFrom<&toml::map::Map<String, toml::Value>> for LoggingConfig {
+    type Error = Error;
+
+    fn try_from(section: &toml::map::Map<String, toml::Value>) -> Result<Self, Self::Error> {
+        let mut config = LoggingConfig::default();
+        for (key, value) in section.iter() {
+            if key == "output" {
+                config.output = match value.as_str() {
+                    Some("stdout") => LogOutput::Stdout,
+                    Some("journald") => LogOutput::Journald,
+                    _ => {
+                        return Err(Error::config(
+                            "Value of field 'logging.output' has wrong type (expected \"stdout\" or \"journald\")."
+                                .to_string(),
+                        ))
+                    }
+                };
+                continue;
+            }
+            if key == "error_webhook" {
+                config.error_webhook = Some(
+                    value
+                        .as_str()
+                        .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+                        .ok_or_else(|| {
+                            Error::config(
+                                "Value of field 'logging.error_webhook' has wrong type (expected URL).",
+                            )
+                        })?
+                        .to_string(),
+                );
+                continue;
+            }
+            let level = value
+                .as_str()
+                .and_then(|level| LevelFilter::from_str(level).ok())
+                .ok_or_else(|| {
+                    Error::config(format!(
+                        "Value of field 'logging.{}' has wrong type (expected one of \"off\", \"error\", \"warn\", \"info\", \"debug\" or \"trace\").",
+                        key
+                    ))
+                })?;
+            if key == "level" {
+                config.level = level;
+                continue;
+            }
+            match ALIASES.iter().find(|(alias, _)| alias == key) {
+                Some((_, targets)) => config
+                    .targets
+                    .extend(targets.iter().map(|target| (target.to_string(), level))),
+                // Everything else is the path of a module in this crate or a library:
+                None => config.targets.push((key.clone(), level)),
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// Initializes the global logger, that writes to the configured output.
+pub(crate) fn init(config: &LoggingConfig) -> Result<(), Error> {
+    let appender: Box<dyn Append> = match config.output {
+        LogOutput::Stdout => Box::new(ConsoleAppender::builder().build()),
+        LogOutput::Journald => Box::new(JournaldAppender::new()?),
+    };
+
+    let log_config = config
+        .targets
+        .iter()
+        .fold(
+            Config::builder().appender(Appender::builder().build("output", appender)),
+            |builder, (target, level)| builder.logger(Logger::builder().build(target, *level)),
+        )
+        .build(Root::builder().appender("output").build(config.level))?;
+
+    log4rs::init_config(log_config)?;
+    if let Some(ref url) = config.error_webhook {
+        report::set_webhook(url);
+    }
+    report::logger_ready();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        let section = toml::from_str(
+            r#"
+output = "journald"
+level = "warn"
+error_webhook = "https://alerts.example.org/kutsche"
+smtp = "debug"
+"kutsche::queue" = "TRACE"
+"#,
+        )
+        .unwrap();
+        let config = LoggingConfig::try_from(&section).unwrap();
+        assert_eq!(config.output, LogOutput::Journald);
+        assert_eq!(config.level, LevelFilter::Warn);
+        assert_eq!(
+            config.error_webhook.as_deref(),
+            Some("https://alerts.example.org/kutsche")
+        );
+        // Keys are sorted by the TOML map:
+        assert_eq!(
+            config.targets,
+            vec![
+                ("kutsche::queue".to_string(), LevelFilter::Trace),
+                ("kutsche::smtp_server".to_string(), LevelFilter::Debug),
+                ("mailin".to_string(), LevelFilter::Debug),
+            ]
+        );
+
+        let section = toml::from_str("matrix = \"verbose\"").unwrap();
+        assert!(LoggingConfig::try_from(&section).is_err());
+    }
+}
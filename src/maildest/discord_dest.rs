@@ -0,0 +1,358 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use super::{EmailDestination, Receipt};
+use crate::email::Email;
+use crate::redaction::Redactor;
+use crate::Error;
+
+/// The maximal number of characters of a Discord message.
+const MESSAGE_LIMIT: usize = 2000;
+/// The maximal number of messages posted for one email. Longer emails are cut and attached as .eml file.
+const MAX_MESSAGES: usize = 4;
+/// The size of the largest email, that is attached. Discord rejects larger uploads to webhooks.
+const ATTACHMENT_LIMIT: usize = 8 * 1024 * 1024;
+/// The number of times a message is posted again after a rate limit response, before the delivery fails.
+const RATE_LIMIT_RETRIES: u32 = 3;
+/// The longest time, that is waited for a rate limit to end. Longer limits fail the delivery, so it is retried by the
+/// delivery queue later.
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(10);
+
+/// Posts every email to a Discord channel using an incoming webhook.
+///
+/// The first message contains the subject, sender and recipients of the email, followed by its text body. Bodies, that
+/// don't fit into one message, are split into multiple messages. If they would need more than `MAX_MESSAGES` messages,
+/// the last one is cut and the complete email is attached as .eml file instead.
+pub(crate) struct DiscordDestination {
+    url: String,
+    username: Option<String>,
+    redactor: Redactor,
+    client: reqwest::Client,
+}
+
+impl DiscordDestination {
+    pub(crate) fn new(url: impl Into<String>) -> Self {
+        DiscordDestination {
+            url: url.into(),
+            username: None,
+            redactor: Redactor::new(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// Sets the name, that the messages are posted with, instead of the one configured for the webhook.
+    pub(crate) fn set_username(&mut self, username: impl Into<String>) {
+        self.username = Some(username.into());
+    }
+
+    /// Sets the redactor, that masks email addresses, phone numbers and custom patterns in the bodies of the emails.
+    pub(crate) fn set_redactor(&mut self, redactor: Redactor) {
+        self.redactor = redactor;
+    }
+
+    /// Returns the messages to post for the email and whether the email has to be attached, because it was cut.
+    fn messages(&self, email: &Email<'_>) -> (Vec<String>, bool) {
+        let mut text = format!("**{}**", email.subject().unwrap_or("(no subject)"));
+        for (name, value) in email.headers() {
+            if ["from", "to", "cc"]
+                .iter()
+                .any(|header| name.as_str().eq_ignore_ascii_case(header))
+            {
+                text.push_str(&format!("\n{}: {}", name.as_str(), value.trim()));
+            }
+        }
+        for part in email.text_body_parts() {
+            text.push_str("\n\n");
+            text.push_str(self.redactor.redact(part.get_text_contents()).trim_end());
+        }
+
+        let mut messages = split_message(&text, MESSAGE_LIMIT);
+        if messages.len() <= MAX_MESSAGES {
+            return (messages, false);
+        }
+        messages.truncate(MAX_MESSAGES);
+        let note = "\n[…] The complete email is attached.";
+        let last = messages
+            .last_mut()
+            .expect("There are MAX_MESSAGES messages.");
+        let kept: String = last
+            .chars()
+            .take(MESSAGE_LIMIT - note.chars().count())
+            .collect();
+        *last = kept + note;
+        (messages, true)
+    }
+
+    fn payload(&self, content: &str) -> Value {
+        let mut payload = json!({
+            "content": content,
+            // Emails must not ping anybody:
+            "allowed_mentions": { "parse": [] },
+        });
+        if let Some(ref username) = self.username {
+            payload["username"] = json!(username);
+        }
+        payload
+    }
+
+    /// Posts one message, optionally with the email as attachment, and returns the ID of the message.
+    async fn post(&self, content: &str, attachment: Option<&Email<'_>>) -> Result<String, Error> {
+        let payload = self.payload(content);
+        let mut retries = 0;
+        loop {
+            let request = self.client.post(&self.url).query(&[("wait", "true")]);
+            let request = match attachment {
+                Some(email) => {
+                    let (content_type, body) = multipart_body(&payload, email);
+                    request
+                        .header(reqwest::header::CONTENT_TYPE, content_type)
+                        .body(body)
+                }
+                None => request.json(&payload),
+            };
+            let response = request.send().await.map_err(|e| {
+                Error::http(None, format!("Could not post email to Discord: {}", e))
+            })?;
+            let status = response.status();
+            if status.is_success() {
+                let message: Value = response.json().await.map_err(|e| {
+                    Error::http(
+                        Some(status.as_u16()),
+                        format!("Invalid response from Discord: {}", e),
+                    )
+                })?;
+                return Ok(message["id"].as_str().unwrap_or_default().to_string());
+            }
+            let wait = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<f64>().ok())
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .filter(|wait| *wait <= MAX_RATE_LIMIT_WAIT);
+            match wait {
+                Some(wait)
+                    if status == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        && retries < RATE_LIMIT_RETRIES =>
+                {
+                    warn!(
+                        "Discord webhook is rate limited, retrying in {} seconds.",
+                        wait.as_secs_f32()
+                    );
+                    tokio::time::sleep(wait).await;
+                    retries += 1;
+                }
+                _ => {
+                    return Err(Error::http(
+                        Some(status.as_u16()),
+                        format!("Discord webhook responded with {}.", status),
+                    ))
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EmailDestination for DiscordDestination {
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        let (messages, cut) = self.messages(email);
+        let attach = cut && email.raw.len() <= ATTACHMENT_LIMIT;
+        if cut && !attach {
+            warn!(
+                "Email with id {} is too large to be attached to a Discord message.",
+                email.message_id
+            );
+        }
+        let mut ids = vec![];
+        for (i, message) in messages.iter().enumerate() {
+            let attachment = Some(email).filter(|_| attach && i + 1 == messages.len());
+            ids.push(self.post(message, attachment).await?);
+        }
+        info!(
+            "Posted email with id {} to Discord in {} messages.",
+            email.message_id,
+            ids.len()
+        );
+        Ok(Receipt::new(ids.join(",")))
+    }
+
+    fn kind(&self) -> &'static str {
+        "discord"
+    }
+
+    fn describe(&self) -> String {
+        "Discord webhook".to_string()
+    }
+}
+
+/// Splits `text` into messages of at most `limit` characters, preferably at line breaks.
+fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut messages = vec![];
+    let mut current = String::new();
+    let mut current_len = 0;
+    for line in text.split_inclusive('\n') {
+        let mut chars: Vec<char> = line.chars().collect();
+        if current_len + chars.len() > limit && current_len > 0 {
+            messages.push(std::mem::take(&mut current));
+            current_len = 0;
+        }
+        // Lines, that don't fit into a message on their own, are split anywhere:
+        while chars.len() > limit {
+            let rest = chars.split_off(limit);
+            messages.push(chars.into_iter().collect());
+            chars = rest;
+        }
+        current_len += chars.len();
+        current.extend(chars);
+    }
+    if !current.trim().is_empty() || messages.is_empty() {
+        messages.push(current);
+    }
+    messages
+        .into_iter()
+        .map(|message| message.trim_end().to_string())
+        .collect()
+}
+
+/// Returns the content type and body of a multipart request, that posts a message with the email as .eml file.
+fn multipart_body(payload: &Value, email: &Email<'_>) -> (String, Vec<u8>) {
+    let mut boundary = format!(
+        "kutsche-{:x}",
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_nanos())
+    );
+    while email
+        .raw
+        .windows(boundary.len())
+        .any(|window| window == boundary.as_bytes())
+    {
+        boundary.push('x');
+    }
+    let file_name: String = email
+        .message_id
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+
+    let mut body = Vec::new();
+    body.extend_from_slice(
+        format!(
+            "--{boundary}\r\nContent-Disposition: form-data; name=\"payload_json\"\r\n\
+Content-Type: application/json\r\n\r\n{payload}\r\n\
+--{boundary}\r\nContent-Disposition: form-data; name=\"files[0]\"; filename=\"{file_name}.eml\"\r\n\
+Content-Type: message/rfc822\r\n\r\n"
+        )
+        .as_bytes(),
+    );
+    body.extend_from_slice(email.raw);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+    (format!("multipart/form-data; boundary={}", boundary), body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redaction::Masking;
+
+    use wiremock::matchers::{body_partial_json, body_string_contains, method, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_split_message() {
+        assert_eq!(split_message("", 10), vec![""]);
+        assert_eq!(
+            split_message("first\nsecond\nthird", 13),
+            vec!["first\nsecond", "third"]
+        );
+        assert_eq!(
+            split_message("short\n0123456789abc", 10),
+            vec!["short", "0123456789", "abc"]
+        );
+        assert!(split_message(&"ä\n".repeat(3000), MESSAGE_LIMIT)
+            .iter()
+            .all(|message| message.chars().count() <= MESSAGE_LIMIT));
+    }
+
+    #[tokio::test]
+    async fn test_post() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(query_param("wait", "true"))
+            .and(body_partial_json(json!({
+                "content": "**Disk full**\nFrom: cron@example.org\n\n/var is full.",
+                "username": "kutsche",
+                "allowed_mentions": { "parse": [] },
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "1234" })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut destination = DiscordDestination::new(format!("{}/api/webhooks/1/t", server.uri()));
+        destination.set_username("kutsche");
+        let email = Email::parse(
+            b"Message-ID: <disk@example.com>\r\nFrom: cron@example.org\r\nSubject: Disk full\r\n\r\n/var is full.\r\n",
+        )
+        .unwrap();
+        let receipt = destination.write_email(&email).await.unwrap();
+        assert_eq!(receipt.reference.unwrap(), "1234");
+    }
+
+    #[tokio::test]
+    async fn test_long_email() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_string_contains("filename=\"long_example.com.eml\""))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "2" })))
+            .expect(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "id": "1" })))
+            .expect(MAX_MESSAGES as u64 - 1)
+            .mount(&server)
+            .await;
+
+        let destination = DiscordDestination::new(server.uri());
+        let raw = format!(
+            "Message-ID: <long@example.com>\r\nSubject: Log\r\n\r\n{}",
+            "All systems nominal.\r\n".repeat(1000)
+        );
+        let email = Email::parse(raw.as_bytes()).unwrap();
+        let (messages, cut) = destination.messages(&email);
+        assert!(cut);
+        assert!(messages.last().unwrap().ends_with("is attached."));
+        let receipt = destination.write_email(&email).await.unwrap();
+        assert_eq!(receipt.reference.unwrap(), "1,1,1,2");
+    }
+
+    #[test]
+    fn test_redaction() {
+        let mut redactor = Redactor::new();
+        redactor.set_addresses(Masking::Full);
+        let mut destination = DiscordDestination::new("https://discord.com/api/webhooks/1/t");
+        destination.set_redactor(redactor);
+        let email = Email::parse(
+            b"Message-ID: <r@example.com>\r\nFrom: cron@example.org\r\nSubject: Report\r\n\r\nContact: bob@example.net\r\n",
+        )
+        .unwrap();
+        let (messages, _) = destination.messages(&email);
+        // Only the body is redacted:
+        assert_eq!(
+            messages,
+            ["**Report**\nFrom: cron@example.org\n\nContact: [email]"]
+        );
+    }
+}
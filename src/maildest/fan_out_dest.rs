@@ -0,0 +1,247 @@
+use async_trait::async_trait;
+use log::{error, info};
+use ring::digest;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::{EmailDestination, Receipt};
+use crate::email::Email;
+use crate::Error;
+
+/// The number of partially delivered emails, that are remembered at most. If more emails are only delivered to some
+/// destinations, arbitrary ones are forgotten, so their retries are written to all destinations again.
+const MAX_PARTIAL: usize = 1024;
+
+/// Delivers every email to multiple destinations, e.g. to a file archive and as a notification to Matrix.
+///
+/// The delivery only succeeds, if it succeeded for all destinations. Failures are logged per destination, and when the
+/// email is retried, it is only written to the destinations, that did not receive it yet.
+pub(crate) struct FanOutDestination {
+    destinations: Vec<(String, Box<dyn EmailDestination + Send + Sync>)>,
+    /// The destinations (by index), that received emails, which were not delivered to all destinations yet. The
+    /// emails are identified by the SHA-256 digest of their content, because the copies for different recipients
+    /// share the message ID.
+    partial: Mutex<HashMap<Vec<u8>, Vec<usize>>>,
+}
+
+impl FanOutDestination {
+    pub(crate) fn new() -> Self {
+        FanOutDestination {
+            destinations: vec![],
+            partial: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds a destination with the name of its section in the config file, which is used in the logs.
+    pub(crate) fn push(
+        &mut self,
+        name: impl Into<String>,
+        destination: Box<dyn EmailDestination + Send + Sync>,
+    ) {
+        self.destinations.push((name.into(), destination));
+    }
+
+    /// Returns the first destination, that writes emails to a local directory or mbox file.
+    fn storing(&self) -> Option<&(dyn EmailDestination + Send + Sync)> {
+        self.destinations
+            .iter()
+            .map(|(_, destination)| destination.as_ref())
+            .find(|destination| destination.storage_path().is_some())
+    }
+
+    async fn write(&self, email: &Email<'_>, notice: bool) -> Result<Receipt, Error> {
+        let key = digest::digest(&digest::SHA256, email.raw).as_ref().to_vec();
+        let done = self
+            .partial
+            .lock()
+            .unwrap()
+            .get(&key)
+            .cloned()
+            .unwrap_or_default();
+        let mut delivered = done.clone();
+        let mut references = vec![];
+        let mut failure: Option<Error> = None;
+        for (i, (name, destination)) in self.destinations.iter().enumerate() {
+            if done.contains(&i) {
+                continue;
+            }
+            let res = if notice {
+                destination.write_notice(email).await
+            } else {
+                destination.write_email(email).await
+            };
+            match res {
+                Ok(receipt) => {
+                    info!(
+                        "Delivered email with id {} to destination '{}'.",
+                        email.message_id, name
+                    );
+                    delivered.push(i);
+                    if let Some(reference) = receipt.reference {
+                        references.push(format!("{}: {}", name, reference));
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "Could not deliver email with id {} to destination '{}': {}",
+                        email.message_id, name, e
+                    );
+                    // A temporary failure lets the email be retried, even if another destination failed permanently:
+                    if failure
+                        .as_ref()
+                        .is_none_or(|f| !f.is_temporary() && e.is_temporary())
+                    {
+                        failure = Some(e);
+                    }
+                }
+            }
+        }
+
+        let mut partial = self.partial.lock().unwrap();
+        match failure {
+            Some(e) => {
+                if partial.len() >= MAX_PARTIAL && !partial.contains_key(&key) {
+                    if let Some(forgotten) = partial.keys().next().cloned() {
+                        partial.remove(&forgotten);
+                    }
+                }
+                partial.insert(key, delivered);
+                Err(e)
+            }
+            None => {
+                partial.remove(&key);
+                Ok(Receipt {
+                    reference: Some(references.join("; ")).filter(|r| !r.is_empty()),
+                })
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EmailDestination for FanOutDestination {
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        self.write(email, false).await
+    }
+
+    async fn write_notice(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        self.write(email, true).await
+    }
+
+    fn kind(&self) -> &'static str {
+        "fan-out"
+    }
+
+    fn describe(&self) -> String {
+        self.destinations
+            .iter()
+            .map(|(_, destination)| destination.describe())
+            .collect::<Vec<_>>()
+            .join(" and ")
+    }
+
+    fn storage_path(&self) -> Option<&Path> {
+        self.storing()
+            .and_then(|destination| destination.storage_path())
+    }
+
+    fn is_maildir(&self) -> bool {
+        self.storing()
+            .is_some_and(|destination| destination.is_maildir())
+    }
+
+    fn is_mbox(&self) -> bool {
+        self.storing()
+            .is_some_and(|destination| destination.is_mbox())
+    }
+    async fn forget(&self, address: &str, message_ids: &[String]) -> Result<Vec<String>, Error> {
+        let mut removed = vec![];
+        for (_, destination) in self.destinations.iter() {
+            removed.extend(destination.forget(address, message_ids).await?);
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::SmtpErrorCode;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    /// Counts its writes and fails the first `failures` of them.
+    struct CountingDestination {
+        writes: Arc<AtomicUsize>,
+        failures: usize,
+    }
+
+    #[async_trait]
+    impl EmailDestination for CountingDestination {
+        async fn write_email(&self, _email: &Email<'_>) -> Result<Receipt, Error> {
+            let n = self.writes.fetch_add(1, Ordering::SeqCst);
+            if n < self.failures {
+                Err(Error::smtp(
+                    SmtpErrorCode::Unreachable,
+                    "Destination is down.",
+                ))
+            } else {
+                Ok(Receipt::new(format!("write {}", n + 1)))
+            }
+        }
+
+        fn kind(&self) -> &'static str {
+            "test"
+        }
+
+        fn describe(&self) -> String {
+            "a counting destination".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_partial_failure() {
+        let archive = Arc::new(AtomicUsize::new(0));
+        let matrix = Arc::new(AtomicUsize::new(0));
+        let mut destination = FanOutDestination::new();
+        destination.push(
+            "archive",
+            Box::new(CountingDestination {
+                writes: archive.clone(),
+                failures: 0,
+            }),
+        );
+        destination.push(
+            "matrix",
+            Box::new(CountingDestination {
+                writes: matrix.clone(),
+                failures: 1,
+            }),
+        );
+        let email = Email::parse(b"Message-ID: <fan-out@example.com>\r\n\r\nHi\r\n").unwrap();
+
+        let e = destination.write_email(&email).await.unwrap_err();
+        assert!(e.is_temporary());
+        assert_eq!(archive.load(Ordering::SeqCst), 1);
+
+        // The retry is only written to the destination, that failed:
+        let receipt = destination.write_email(&email).await.unwrap();
+        assert_eq!(receipt.reference.as_deref(), Some("matrix: write 2"));
+        assert_eq!(archive.load(Ordering::SeqCst), 1);
+        assert_eq!(matrix.load(Ordering::SeqCst), 2);
+
+        // Once delivered to all destinations, the email is forgotten:
+        let receipt = destination.write_email(&email).await.unwrap();
+        assert_eq!(
+            receipt.reference.as_deref(),
+            Some("archive: write 2; matrix: write 3")
+        );
+        assert_eq!(
+            destination.describe(),
+            "a counting destination and a counting destination"
+        );
+    }
+}
@@ -1,58 +1,489 @@
-use std::path::PathBuf;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use async_trait::async_trait;
+use fs2::FileExt;
 use log::info;
-use tokio::{
-    fs::OpenOptions,
-    io::{AsyncWriteExt, BufWriter},
-};
 
-use super::EmailDestination;
-use crate::email::Email;
+use super::{EmailDestination, Receipt};
+use crate::attachment_store::AttachmentStore;
+use crate::email::{asctime_date, Email};
 use crate::Error;
 
+/// The number of emails delivered to maildirs by this process, which makes their file names unique.
+static MAILDIR_DELIVERIES: AtomicU64 = AtomicU64::new(0);
+
+/// How a `FileDestination` lays out the stored emails.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum FileFormat {
+    /// Every email is stored in a file named like its message ID, preceded by a line with the message ID.
+    Flat,
+    /// Emails are stored in a maildir (with the subdirectories tmp, new and cur), that MUAs like mutt or IMAP servers
+    /// like Dovecot can read directly. File names are unique and contain the given hostname.
+    Maildir { hostname: String },
+    /// Emails are appended to a single mbox file (in the mboxrd format), that is locked while writing. The path of the
+    /// destination is the path of this file.
+    Mbox,
+}
+
+impl FromStr for FileFormat {
+    type Err = Error;
+
+    /// Parses "flat", "maildir" or "mbox". The hostname of a maildir is empty and has to be set afterwards.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flat" => Ok(FileFormat::Flat),
+            "maildir" => Ok(FileFormat::Maildir {
+                hostname: String::new(),
+            }),
+            "mbox" => Ok(FileFormat::Mbox),
+            _ => Err(Error::config(format!(
+                "Unknown file format '{}' (expected flat, maildir or mbox).",
+                s
+            ))),
+        }
+    }
+}
+
+/// Stores every email in a file in a directory: Either named like its message ID or in a maildir. Alternatively, all
+/// emails are appended to one mbox file.
+///
+/// If the email was received over SMTP, its envelope is stored as JSON in a file in the directory, whose name is the
+/// name of the email file (without the maildir subdirectory) with the suffix `.envelope.json`. In an mbox file, only
+/// the envelope sender and the time of reception are kept in the "From " line.
+///
+/// Flat files and maildirs may move large attachments to an attachment store, so emails, that are received multiple
+/// times, only store them once.
 pub(crate) struct FileDestination {
     base_path: PathBuf,
+    format: FileFormat,
+    attachment_store: Option<Arc<AttachmentStore>>,
 }
 
 impl FileDestination {
+    #[cfg(test)]
     pub fn new<A: Into<PathBuf>>(path: A) -> Result<Self, Error> {
+        Self::with_format(path, FileFormat::Flat)
+    }
+
+    /// Creates a destination, that stores emails in the given format. The subdirectories of a maildir are created, if
+    /// they don't exist.
+    pub fn with_format<A: Into<PathBuf>>(path: A, format: FileFormat) -> Result<Self, Error> {
         let base_path = path.into();
-        if base_path.is_dir() {
-            Ok(Self { base_path })
+        // The path of an mbox is the file, which is created with the first email:
+        let dir = match format {
+            FileFormat::Mbox => base_path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty())
+                .unwrap_or_else(|| Path::new(".")),
+            _ => &base_path,
+        };
+        if dir.is_dir() {
+            if let FileFormat::Maildir { .. } = format {
+                for subdir in ["tmp", "new", "cur"] {
+                    std::fs::create_dir_all(base_path.join(subdir))?;
+                }
+            }
+            Ok(Self {
+                base_path,
+                format,
+                attachment_store: None,
+            })
         } else {
             Err(Error::SysIo(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
                 format!(
                     "{} is not a directory.",
-                    base_path.to_str().unwrap_or("The given path")
+                    dir.to_str().unwrap_or("The given path")
                 ),
             )))
         }
     }
 }
 
+impl FileDestination {
+    /// Sets the store, that large attachments are moved to. It is not used for mbox files.
+    pub(crate) fn set_attachment_store(&mut self, store: Arc<AttachmentStore>) {
+        self.attachment_store = Some(store);
+    }
+
+    /// Writes the email to a file named like its message ID, preceded by a line with the message ID. Returns the path
+    /// of the file.
+    async fn write_flat(&self, email: &Email<'_>) -> Result<PathBuf, Error> {
+        let dest_path = self.base_path.join(&email.message_id);
+        let id_line = format!("{}\n\n", email.message_id);
+        let raw = email.raw.to_vec();
+        let store = self.attachment_store.clone();
+        let sidecar = self.sidecar(email, &dest_path);
+
+        blocking(move || {
+            let mut content = id_line.into_bytes();
+            match store {
+                Some(store) => content.extend(store.externalize(&raw)?),
+                None => content.extend(raw),
+            }
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&dest_path)?;
+            file.write_all(&content)?;
+            write_sidecar(sidecar)?;
+            Ok(dest_path)
+        })
+        .await
+    }
+
+    /// Writes the email to the tmp directory of the maildir and moves it to new, once it is complete, so readers never
+    /// see partial emails. Returns the path of the file in new.
+    async fn write_maildir(&self, email: &Email<'_>, hostname: &str) -> Result<PathBuf, Error> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        // The unique name from the maildir specification, e.g. "1646128800.M123456P4711Q1.mx.example.com":
+        let name = format!(
+            "{}.M{}P{}Q{}.{}",
+            now.as_secs(),
+            now.subsec_micros(),
+            std::process::id(),
+            MAILDIR_DELIVERIES.fetch_add(1, Ordering::Relaxed) + 1,
+            hostname.replace('/', "\\057").replace(':', "\\072")
+        );
+        let tmp_path = self.base_path.join("tmp").join(&name);
+        let new_path = self.base_path.join("new").join(&name);
+        let raw = email.raw.to_vec();
+        let store = self.attachment_store.clone();
+        let sidecar = self.sidecar(email, &new_path);
+
+        blocking(move || {
+            let content = match store {
+                Some(store) => store.externalize(&raw)?,
+                None => raw,
+            };
+            let mut file = std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&tmp_path)?;
+            let written = (|| {
+                file.write_all(&content)?;
+                file.sync_all()?;
+                std::fs::rename(&tmp_path, &new_path)
+            })();
+            if let Err(e) = written {
+                let _ = std::fs::remove_file(&tmp_path);
+                return Err(e.into());
+            }
+            write_sidecar(sidecar)?;
+            Ok(new_path)
+        })
+        .await
+    }
+
+    /// Returns the path and content of the file, that the envelope of an email stored at `dest_path` is written to,
+    /// if the email was received over SMTP.
+    fn sidecar(&self, email: &Email<'_>, dest_path: &Path) -> Option<(PathBuf, String)> {
+        let envelope = email.envelope.as_ref()?;
+        let mut envelope_name = dest_path.file_name().unwrap_or_default().to_os_string();
+        envelope_name.push(".envelope.json");
+        Some((
+            self.base_path.join(envelope_name),
+            envelope.to_json().to_string(),
+        ))
+    }
+
+    /// Appends the email to the mbox file, while holding an exclusive lock on it, so other writers (and readers, that
+    /// respect the lock) never see partial emails. Returns the offset of the email in the file.
+    async fn write_mbox(&self, email: &Email<'_>) -> Result<u64, Error> {
+        let (sender, received_at) = match email.envelope {
+            Some(ref envelope) => (envelope.mail_from.clone(), envelope.received_at),
+            None => (
+                None,
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs()),
+            ),
+        };
+        let mut entry = vec![];
+        write_mbox_entry(
+            &mut entry,
+            sender
+                .as_deref()
+                .filter(|sender| !sender.is_empty())
+                .unwrap_or("MAILER-DAEMON"),
+            received_at,
+            email.raw,
+        )?;
+
+        let path = self.base_path.clone();
+        blocking(move || {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            file.lock_exclusive()?;
+            let written = (|| -> Result<u64, Error> {
+                let offset = file.metadata()?.len();
+                file.write_all(&entry)?;
+                file.sync_all()?;
+                Ok(offset)
+            })();
+            let _ = FileExt::unlock(&file);
+            written
+        })
+        .await
+    }
+}
+
+/// Runs the file operations of one delivery in a single blocking task. Every operation of `tokio::fs` is a separate
+/// task on the blocking thread pool, so grouping them saves a round trip for each of them.
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, Error> + Send + 'static,
+) -> Result<T, Error> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .expect("Writing to the file destination panicked.")
+}
+
+/// Writes the envelope of an email to a new file, if there is one.
+fn write_sidecar(sidecar: Option<(PathBuf, String)>) -> Result<(), Error> {
+    if let Some((path, content)) = sidecar {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        file.write_all(content.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Writes an email in the mboxrd format: A "From " line, the email with LF line endings and every line, that starts
+/// with any number of '>' followed by "From ", quoted with another '>', and an empty line.
+pub(crate) fn write_mbox_entry(
+    out: &mut impl Write,
+    sender: &str,
+    received_at: u64,
+    raw: &[u8],
+) -> Result<(), Error> {
+    writeln!(out, "From {} {}", sender, asctime_date(received_at))?;
+    let mut lines = raw.split(|b| *b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        // The last element is empty, if the email ends with a line break:
+        if line.is_empty() && lines.peek().is_none() {
+            break;
+        }
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let unquoted = &line[line.iter().take_while(|b| **b == b'>').count()..];
+        if unquoted.starts_with(b"From ") {
+            out.write_all(b">")?;
+        }
+        out.write_all(line)?;
+        out.write_all(b"\n")?;
+    }
+    out.write_all(b"\n")?;
+    Ok(())
+}
+
 #[async_trait]
 impl EmailDestination for FileDestination {
-    async fn write_email(&self, email: &Email<'_>) -> Result<(), Error> {
-        let mut dest_path = self.base_path.clone();
-        dest_path.push(&email.message_id);
-        let mut file_options = OpenOptions::new();
-        file_options.write(true).create_new(true);
-        let file = file_options.open(dest_path).await?;
-
-        // Write email to file:
-        let mut writer = BufWriter::new(file);
-        // Write message ID:
-        writer.write_all(email.message_id.as_bytes()).await?;
-        writer.write_all("\n\n".as_bytes()).await?;
-        // Write content:
-        writer.write_all(email.raw).await?;
-
-        writer.flush().await?;
-
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        let dest_path = match self.format {
+            FileFormat::Flat => self.write_flat(email).await?,
+            FileFormat::Maildir { ref hostname } => self.write_maildir(email, hostname).await?,
+            FileFormat::Mbox => {
+                let offset = self.write_mbox(email).await?;
+                info!("Appended email with id {} to mbox file.", &email.message_id);
+                return Ok(Receipt::new(format!(
+                    "{} at offset {}",
+                    self.base_path.display(),
+                    offset
+                )));
+            }
+        };
         info!("Wrote email with id {} to filesystem.", &email.message_id);
 
-        Ok(())
+        Ok(Receipt::new(dest_path.to_string_lossy()))
+    }
+
+    fn kind(&self) -> &'static str {
+        "file"
+    }
+
+    fn describe(&self) -> String {
+        match self.format {
+            FileFormat::Flat => format!("files in {}", self.base_path.display()),
+            FileFormat::Maildir { .. } => format!("maildir {}", self.base_path.display()),
+            FileFormat::Mbox => format!("mbox file {}", self.base_path.display()),
+        }
+    }
+
+    fn storage_path(&self) -> Option<&Path> {
+        Some(&self.base_path)
+    }
+
+    fn is_maildir(&self) -> bool {
+        matches!(self.format, FileFormat::Maildir { .. })
+    }
+
+    fn is_mbox(&self) -> bool {
+        self.format == FileFormat::Mbox
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::Envelope;
+    use crate::smtp_server::ClientIdentity;
+
+    use std::sync::Arc;
+
+    const TEST_EMAIL: &[u8] = b"From: sender@example.com\r\n\
+Message-ID: <file-test@example.com>\r\n\
+\r\n\
+Hello world.\r\n";
+
+    #[tokio::test]
+    async fn test_envelope_sidecar() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = FileDestination::new(dir.path()).unwrap();
+        let mut email = Email::parse(TEST_EMAIL).unwrap();
+        email.envelope = Some(Arc::new(Envelope {
+            mail_from: Some("sender@example.com".to_string()),
+            rcpt_to: vec!["a@example.org".to_string(), "b@example.org".to_string()],
+            client: ClientIdentity {
+                peer: Some("192.0.2.1:4711".parse().unwrap()),
+                ..ClientIdentity::default()
+            },
+            received_at: 1_656_000_000,
+            require_tls: false,
+        }));
+        destination.write_email(&email).await.unwrap();
+
+        let sidecar = dir
+            .path()
+            .join(format!("{}.envelope.json", email.message_id));
+        let envelope: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(sidecar).unwrap()).unwrap();
+        assert_eq!(envelope["mail_from"], "sender@example.com");
+        assert_eq!(envelope["rcpt_to"][1], "b@example.org");
+        assert_eq!(envelope["client_ip"], "192.0.2.1");
+        assert_eq!(envelope["received_at"], 1_656_000_000);
+        assert!(dir.path().join(&email.message_id).is_file());
+    }
+
+    #[test]
+    fn test_write_mbox_entry() {
+        let mut out = vec![];
+        write_mbox_entry(
+            &mut out,
+            "sender@example.com",
+            1646128800,
+            b"Subject: Hi\r\n\r\nFrom here on\r\n>From there\r\n",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "From sender@example.com Tue Mar  1 10:00:00 2022\n\
+Subject: Hi\n\n>From here on\n>>From there\n\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("alerts.mbox");
+        let destination = FileDestination::with_format(&path, FileFormat::Mbox).unwrap();
+        assert!(destination.is_mbox());
+        let mut email =
+            Email::parse(b"Message-ID: <mbox-test@example.com>\r\n\r\nFrom now on\r\n").unwrap();
+        email.envelope = Some(Arc::new(Envelope {
+            mail_from: Some("cron@example.org".to_string()),
+            rcpt_to: vec![],
+            client: ClientIdentity::default(),
+            received_at: 1646128800,
+            require_tls: false,
+        }));
+        let first = destination.write_email(&email).await.unwrap();
+        destination.write_email(&email).await.unwrap();
+        assert!(first.reference.unwrap().ends_with("at offset 0"));
+
+        let entry = "From cron@example.org Tue Mar  1 10:00:00 2022\n\
+Message-ID: <mbox-test@example.com>\n\n>From now on\n\n";
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}{}", entry, entry)
+        );
+        assert!(
+            FileDestination::with_format(dir.path().join("missing/a.mbox"), FileFormat::Mbox)
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_maildir() {
+        let dir = tempfile::tempdir().unwrap();
+        let destination = FileDestination::with_format(
+            dir.path(),
+            FileFormat::Maildir {
+                hostname: "mx/1:25".to_string(),
+            },
+        )
+        .unwrap();
+        assert!(destination.is_maildir());
+        let email = Email::parse(TEST_EMAIL).unwrap();
+        let first = destination.write_email(&email).await.unwrap();
+        let second = destination.write_email(&email).await.unwrap();
+        assert_ne!(first, second);
+
+        let mut delivered: Vec<_> = std::fs::read_dir(dir.path().join("new"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        delivered.sort();
+        assert_eq!(delivered.len(), 2);
+        assert!(delivered[0].to_string_lossy().ends_with(".mx\\0571\\07225"));
+        assert_eq!(std::fs::read(&delivered[0]).unwrap(), TEST_EMAIL);
+        assert_eq!(
+            std::fs::read_dir(dir.path().join("tmp")).unwrap().count(),
+            0
+        );
+        assert!(dir.path().join("cur").is_dir());
+    }
+
+    #[tokio::test]
+    async fn test_attachment_store() {
+        let raw = format!(
+            "Message-ID: <store-test@example.com>\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain\r\n\
+\r\n\
+Hello world.\r\n\
+--b\r\n\
+Content-Type: application/octet-stream\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+{}\r\n\
+--b--\r\n",
+            "QUJD".repeat(100)
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let store_dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(AttachmentStore::open(store_dir.path(), 100).unwrap());
+        let mut destination = FileDestination::new(dir.path()).unwrap();
+        destination.set_attachment_store(store.clone());
+        let email = Email::parse(raw.as_bytes()).unwrap();
+        destination.write_email(&email).await.unwrap();
+
+        let stored = std::fs::read(dir.path().join("store-test@example.com")).unwrap();
+        let stored = stored
+            .strip_prefix(b"store-test@example.com\n\n".as_slice())
+            .unwrap();
+        assert!(stored.len() < 400);
+        assert_eq!(store.restore(stored).unwrap(), raw.as_bytes());
     }
 }
@@ -0,0 +1,416 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::{TcpStream, UnixStream};
+
+use std::path::PathBuf;
+
+use super::{EmailDestination, Receipt};
+use crate::email::Email;
+use crate::error::{Error, SmtpErrorCode};
+
+/// A bidirectional byte stream to an LMTP server.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Where the LMTP server listens.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum LmtpAddress {
+    Tcp(String, u16),
+    Unix(PathBuf),
+}
+
+impl std::str::FromStr for LmtpAddress {
+    type Err = Error;
+
+    /// Parses an absolute path of a unix socket or "host:port".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.starts_with('/') {
+            return Ok(LmtpAddress::Unix(PathBuf::from(s)));
+        }
+        s.rsplit_once(':')
+            .and_then(|(host, port)| {
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                Some(LmtpAddress::Tcp(host.to_string(), port.parse().ok()?))
+            })
+            .ok_or_else(|| {
+                Error::config(format!(
+                    "Invalid LMTP address '{}' (expected \"host:port\" or the absolute path of a unix socket).",
+                    s
+                ))
+            })
+    }
+}
+
+impl std::fmt::Display for LmtpAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LmtpAddress::Tcp(host, port) if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            LmtpAddress::Tcp(host, port) => write!(f, "{}:{}", host, port),
+            LmtpAddress::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// Delivers emails to a local mailbox store over LMTP (RFC 2033), e.g. to Dovecot.
+///
+/// Emails are delivered to the configured recipients or, without them, to the envelope recipients, that selected the
+/// mapping (from the `Delivered-To` headers). The LMTP server replies once per recipient after the message. The
+/// delivery only fails, if it failed for all recipients, because retrying it would duplicate the email for the others;
+/// failures for single recipients are logged and listed in the receipt.
+pub(crate) struct LmtpDestination {
+    address: LmtpAddress,
+    recipients: Vec<String>,
+    /// The name this server identifies with in the LHLO command.
+    lhlo_name: String,
+}
+
+impl LmtpDestination {
+    pub(crate) fn new(address: LmtpAddress) -> Self {
+        LmtpDestination {
+            address,
+            recipients: vec![],
+            lhlo_name: "localhost".to_string(),
+        }
+    }
+
+    /// Lets all emails be delivered to the given recipients instead of their envelope recipients.
+    pub(crate) fn set_recipients(&mut self, recipients: Vec<String>) {
+        self.recipients = recipients;
+    }
+
+    pub(crate) fn set_lhlo_name(&mut self, name: impl Into<String>) {
+        self.lhlo_name = name.into();
+    }
+
+    /// Returns the recipients of `email` at the LMTP server.
+    fn recipients_of(&self, email: &Email<'_>) -> Vec<String> {
+        if !self.recipients.is_empty() {
+            return self.recipients.clone();
+        }
+        let mut recipients: Vec<String> = email
+            .headers()
+            .filter(|(name, _)| name.as_str().eq_ignore_ascii_case("Delivered-To"))
+            .map(|(_, value)| value.trim().to_string())
+            .collect();
+        if recipients.is_empty() {
+            if let Some(ref envelope) = email.envelope {
+                recipients = envelope.rcpt_to.clone();
+            }
+        }
+        recipients
+    }
+
+    async fn connect(&self) -> Result<LmtpConnection, Error> {
+        let unreachable = |e: std::io::Error| {
+            Error::smtp(
+                SmtpErrorCode::Unreachable,
+                format!("Could not connect to LMTP server {}: {}", self.address, e),
+            )
+        };
+        let stream: Box<dyn Stream> = match self.address {
+            LmtpAddress::Tcp(ref host, port) => Box::new(
+                TcpStream::connect((host.as_str(), port))
+                    .await
+                    .map_err(unreachable)?,
+            ),
+            LmtpAddress::Unix(ref path) => {
+                Box::new(UnixStream::connect(path).await.map_err(unreachable)?)
+            }
+        };
+        let mut conn = LmtpConnection {
+            stream: BufStream::new(stream),
+        };
+        conn.expect_response(220).await?;
+        conn.command(&format!("LHLO {}", self.lhlo_name), 250)
+            .await?;
+        Ok(conn)
+    }
+}
+
+#[async_trait]
+impl EmailDestination for LmtpDestination {
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        let recipients = self.recipients_of(email);
+        if recipients.is_empty() {
+            return Err(Error::config(format!(
+                "The email with id {} has no recipients for LMTP server {}.",
+                email.message_id, self.address
+            )));
+        }
+        let sender = email
+            .envelope
+            .as_ref()
+            .and_then(|envelope| envelope.mail_from.as_deref())
+            .unwrap_or_default();
+
+        let mut conn = self.connect().await?;
+        let results = conn.send_mail(sender, &recipients, email.raw).await?;
+        if let Err(e) = conn.quit().await {
+            warn!(
+                "Could not close connection to LMTP server {}: {}",
+                self.address, e
+            );
+        }
+
+        let mut delivered = vec![];
+        let mut failures = vec![];
+        for (recipient, result) in recipients.iter().zip(results) {
+            match result {
+                Ok(_) => delivered.push(recipient.as_str()),
+                Err(e) => {
+                    warn!(
+                        "LMTP server {} did not accept email with id {} for {}: {}",
+                        self.address, email.message_id, recipient, e
+                    );
+                    failures.push((recipient.as_str(), e));
+                }
+            }
+        }
+        if delivered.is_empty() {
+            // All recipients failed, so retrying the email does not duplicate it. A temporary failure of one recipient
+            // is enough to retry it:
+            let (_, error) = match failures.iter().position(|(_, e)| e.is_temporary()) {
+                Some(i) => failures.swap_remove(i),
+                None => failures.swap_remove(0),
+            };
+            return Err(error);
+        }
+        info!(
+            "Delivered email with id {} to {} via LMTP.",
+            email.message_id,
+            delivered.join(", ")
+        );
+        let mut reference = format!("{}: delivered to {}", self.address, delivered.join(", "));
+        for (recipient, e) in failures {
+            reference.push_str(&format!("; failed for {}: {}", recipient, e));
+        }
+        Ok(Receipt::new(reference))
+    }
+
+    fn kind(&self) -> &'static str {
+        "lmtp"
+    }
+
+    fn describe(&self) -> String {
+        if self.recipients.is_empty() {
+            format!("LMTP delivery via {}", self.address)
+        } else {
+            format!(
+                "LMTP delivery to {} via {}",
+                self.recipients.join(", "),
+                self.address
+            )
+        }
+    }
+}
+
+/// The client side of an LMTP connection.
+struct LmtpConnection {
+    stream: BufStream<Box<dyn Stream>>,
+}
+
+impl LmtpConnection {
+    /// Performs a mail transaction and returns the result for every recipient in the given order.
+    async fn send_mail(
+        &mut self,
+        sender: &str,
+        recipients: &[String],
+        content: &[u8],
+    ) -> Result<Vec<Result<String, Error>>, Error> {
+        self.command(&format!("MAIL FROM:<{}>", sender), 250)
+            .await?;
+        let mut results = vec![];
+        for recipient in recipients {
+            results.push(self.command(&format!("RCPT TO:<{}>", recipient), 250).await);
+        }
+        if results.iter().all(Result::is_err) {
+            return Ok(results);
+        }
+
+        self.command("DATA", 354).await?;
+        for line in content.split_inclusive(|b| *b == b'\n') {
+            // Dot-stuffing as described in RFC 5321, section 4.5.2:
+            if line.first() == Some(&b'.') {
+                self.stream.write_all(b".").await?;
+            }
+            self.stream.write_all(line).await?;
+        }
+        if !content.ends_with(b"\r\n") {
+            self.stream.write_all(b"\r\n").await?;
+        }
+        self.stream.write_all(b".\r\n").await?;
+        self.stream.flush().await?;
+        // There is one reply for every accepted recipient:
+        for result in results.iter_mut().filter(|result| result.is_ok()) {
+            *result = self.expect_response(250).await;
+        }
+        Ok(results)
+    }
+
+    async fn quit(mut self) -> Result<(), Error> {
+        self.command("QUIT", 221).await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Reads a (possibly multiline) response and returns its code and the text of all lines.
+    async fn read_response(&mut self) -> Result<(u16, String), Error> {
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(Error::smtp(
+                    SmtpErrorCode::Protocol,
+                    "LMTP server closed the connection unexpectedly.",
+                ));
+            }
+            let code = line
+                .get(..3)
+                .and_then(|c| c.parse::<u16>().ok())
+                .ok_or_else(|| {
+                    Error::smtp(
+                        SmtpErrorCode::Protocol,
+                        format!("Invalid response from LMTP server: {}", line),
+                    )
+                })?;
+            text.push_str(line.get(4..).unwrap_or("").trim_end());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok((code, text));
+            }
+            text.push('\n');
+        }
+    }
+
+    async fn expect_response(&mut self, expected: u16) -> Result<String, Error> {
+        let (code, text) = self.read_response().await?;
+        if code == expected {
+            Ok(text)
+        } else {
+            Err(Error::smtp(
+                SmtpErrorCode::Reply(code),
+                format!(
+                    "LMTP server responded with {} {} (expected {}).",
+                    code, text, expected
+                ),
+            ))
+        }
+    }
+
+    async fn command(&mut self, cmd: &str, expected: u16) -> Result<String, Error> {
+        self.stream.write_all(cmd.as_bytes()).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await?;
+        self.expect_response(expected).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::{with_routing_headers, Envelope};
+
+    use tokio::net::UnixListener;
+
+    use std::sync::Arc;
+
+    /// Accepts one LMTP session, rejects RCPT for "unknown@example.com", answers the message with the given replies
+    /// and returns the received lines.
+    async fn serve_session(
+        stream: impl AsyncRead + AsyncWrite + Unpin,
+        data_replies: &[&str],
+    ) -> Vec<String> {
+        let mut stream = BufStream::new(stream);
+        let mut lines = vec![];
+        reply(&mut stream, "220 lmtp ready\r\n".to_string()).await;
+        let mut in_data = false;
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 {
+                return lines;
+            }
+            lines.push(line.trim_end().to_string());
+            if in_data {
+                if line == ".\r\n" {
+                    in_data = false;
+                    let replies: String =
+                        data_replies.iter().map(|r| format!("{}\r\n", r)).collect();
+                    reply(&mut stream, replies).await;
+                }
+                continue;
+            }
+            let answer = match line.trim_end() {
+                "RCPT TO:<unknown@example.com>" => "550 5.1.1 User unknown",
+                "DATA" => {
+                    in_data = true;
+                    "354 Go ahead"
+                }
+                "QUIT" => "221 Bye",
+                _ => "250 OK",
+            };
+            reply(&mut stream, format!("{}\r\n", answer)).await;
+        }
+    }
+
+    async fn reply(stream: &mut (impl AsyncWrite + Unpin), text: String) {
+        stream.write_all(text.as_bytes()).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_per_recipient_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket_path = dir.path().join("lmtp");
+        let listener = UnixListener::bind(&socket_path).unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let first = serve_session(stream, &["250 2.0.0 <a@example.com> Saved"]).await;
+            let (stream, _) = listener.accept().await.unwrap();
+            let second = serve_session(stream, &["452 4.2.2 Mailbox full"]).await;
+            (first, second)
+        });
+
+        let address: LmtpAddress = socket_path.to_str().unwrap().parse().unwrap();
+        let destination = LmtpDestination::new(address);
+        let raw = with_routing_headers(
+            b"Message-ID: <lmtp@example.com>\r\n\r\n.Hello\r\n",
+            &["a@example.com", "unknown@example.com"],
+        );
+        let mut email = Email::parse(&raw).unwrap();
+        email.envelope = Some(Arc::new(Envelope {
+            mail_from: Some("cron@example.org".to_string()),
+            rcpt_to: vec![],
+            client: Default::default(),
+            received_at: 0,
+            require_tls: false,
+        }));
+
+        // The unknown recipient is only reported in the receipt:
+        let receipt = destination.write_email(&email).await.unwrap();
+        let reference = receipt.reference.unwrap();
+        assert!(reference.contains("delivered to a@example.com"));
+        assert!(reference.contains("failed for unknown@example.com"));
+
+        // If no recipient gets the email, it is retried on temporary failures:
+        let error = destination.write_email(&email).await.unwrap_err();
+        assert!(error.is_temporary());
+
+        let (first, _) = server.await.unwrap();
+        assert_eq!(first[0], "LHLO localhost");
+        assert_eq!(first[1], "MAIL FROM:<cron@example.org>");
+        assert!(first.contains(&"..Hello".to_string()));
+    }
+
+    #[test]
+    fn test_parse_address() {
+        assert_eq!(
+            "[::1]:24".parse::<LmtpAddress>().unwrap(),
+            LmtpAddress::Tcp("::1".to_string(), 24)
+        );
+        assert_eq!(
+            "/run/dovecot/lmtp".parse::<LmtpAddress>().unwrap(),
+            LmtpAddress::Unix(PathBuf::from("/run/dovecot/lmtp"))
+        );
+        assert!("localhost".parse::<LmtpAddress>().is_err());
+    }
+}
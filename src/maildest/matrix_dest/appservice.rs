@@ -0,0 +1,459 @@
+//! The application service mode of Matrix destinations, in which every email is posted by a virtual user of its sender
+//! address, like a bridge, instead of by a single bot account.
+
+use log::{debug, info, warn};
+use reqwest::{header::CONTENT_TYPE, Method, RequestBuilder, StatusCode, Url};
+use ruma::{OwnedMxcUri, OwnedRoomId, OwnedUserId, RoomId, UserId};
+use serde_json::{json, Value};
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::error::{Error, MatrixErrorCode};
+use crate::secret::Secret;
+
+/// The prefix of the localparts of the virtual users, if none is configured.
+pub(crate) const DEFAULT_USER_PREFIX: &str = "email_";
+
+/// Sends events as the virtual users of an application service, that is registered at the homeserver.
+///
+/// A virtual user is registered and joined to the room, when it sends its first message. The room must let the sender
+/// of the application service invite users, or let anyone join.
+pub(crate) struct Appservice {
+    client: reqwest::Client,
+    homeserver: Url,
+    /// The token, that the application service authenticates with (`as_token` in the registration).
+    token: Secret,
+    /// The user of the `sender_localpart` of the registration, which invites the virtual users.
+    sender: OwnedUserId,
+    user_prefix: String,
+    /// The virtual users, that are known to be members of the room.
+    joined: Mutex<HashSet<OwnedUserId>>,
+    /// The prefix and counter of the transaction IDs, which must be unique for every sent event.
+    txn_prefix: u128,
+    txn_counter: AtomicU64,
+}
+
+impl Appservice {
+    pub(crate) fn new(
+        homeserver: Url,
+        token: Secret,
+        sender: OwnedUserId,
+        proxy: Option<&str>,
+    ) -> Result<Self, Error> {
+        let mut client = reqwest::Client::builder();
+        if let Some(proxy) = proxy {
+            client = client.proxy(
+                reqwest::Proxy::all(proxy)
+                    .map_err(|e| Error::config(format!("Could not use proxy: {}", e)))?,
+            );
+        }
+        Ok(Appservice {
+            client: client
+                .build()
+                .map_err(|e| Error::config(format!("Could not create HTTP client: {}", e)))?,
+            homeserver,
+            token,
+            sender,
+            user_prefix: DEFAULT_USER_PREFIX.to_string(),
+            joined: Mutex::new(HashSet::new()),
+            txn_prefix: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            txn_counter: AtomicU64::new(0),
+        })
+    }
+
+    /// Sets the prefix of the localparts of the virtual users. It must match the user namespace of the registration.
+    pub(crate) fn set_user_prefix(&mut self, user_prefix: impl Into<String>) {
+        self.user_prefix = user_prefix.into();
+    }
+
+    pub(crate) fn sender(&self) -> &UserId {
+        &self.sender
+    }
+
+    /// Returns the virtual user of the email address, e.g. `@email_alice=40example.com:example.org` for
+    /// alice@example.com.
+    pub(crate) fn virtual_user(&self, address: &str) -> Result<OwnedUserId, Error> {
+        let user_id = format!(
+            "@{}{}:{}",
+            self.user_prefix,
+            escape_localpart(address),
+            self.sender.server_name()
+        );
+        UserId::parse(user_id.as_str()).map_err(|e| {
+            Error::matrix(
+                MatrixErrorCode::Sdk,
+                format!("Invalid virtual user {}: {}", user_id, e),
+            )
+        })
+    }
+
+    /// Registers the virtual user of the email address and lets it join the room, if this was not done yet.
+    pub(crate) async fn prepare_user(
+        &self,
+        address: &str,
+        room_id: &RoomId,
+    ) -> Result<OwnedUserId, Error> {
+        let user_id = self.virtual_user(address)?;
+        if self.joined.lock().unwrap().contains(&user_id) {
+            return Ok(user_id);
+        }
+
+        let (status, response) = self
+            .send_request(Method::POST, "client", &["register"], None, |request| {
+                request.json(&json!({
+                    "type": "m.login.application_service",
+                    "username": user_id.localpart(),
+                }))
+            })
+            .await?;
+        if status.is_success() {
+            info!("Registered virtual Matrix user {}.", user_id);
+            // The display name is only cosmetic, so the user is used without it:
+            if let Err(e) = self
+                .request(
+                    Method::PUT,
+                    &["profile", user_id.as_str(), "displayname"],
+                    Some(&user_id),
+                    json!({ "displayname": address }),
+                    MatrixErrorCode::Sdk,
+                )
+                .await
+            {
+                warn!("Could not set display name of {}: {}", user_id, e);
+            }
+        } else if response["errcode"] != "M_USER_IN_USE" {
+            // Registrations outside of the namespace of the application service are rejected:
+            return Err(response_error(
+                "register",
+                status,
+                &response,
+                MatrixErrorCode::Auth,
+            ));
+        }
+
+        // The invite fails, if the user is a member already or the room is public, which the join reveals:
+        if let Err(e) = self
+            .request(
+                Method::POST,
+                &["rooms", room_id.as_str(), "invite"],
+                None,
+                json!({ "user_id": user_id }),
+                MatrixErrorCode::NotInRoom,
+            )
+            .await
+        {
+            debug!("Could not invite {} to room {}: {}", user_id, room_id, e);
+        }
+        self.request(
+            Method::POST,
+            &["join", room_id.as_str()],
+            Some(&user_id),
+            json!({}),
+            MatrixErrorCode::NotInRoom,
+        )
+        .await?;
+        self.joined.lock().unwrap().insert(user_id.clone());
+        Ok(user_id)
+    }
+
+    /// Creates a private room as the sender of the application service and invites the given users.
+    pub(crate) async fn create_room(
+        &self,
+        name: &str,
+        invite: &[OwnedUserId],
+    ) -> Result<OwnedRoomId, Error> {
+        let response = self
+            .request(
+                Method::POST,
+                &["createRoom"],
+                None,
+                json!({ "name": name, "invite": invite, "preset": "private_chat" }),
+                MatrixErrorCode::Sdk,
+            )
+            .await?;
+        response["room_id"]
+            .as_str()
+            .and_then(|room_id| RoomId::parse(room_id).ok())
+            .ok_or_else(|| {
+                Error::matrix(
+                    MatrixErrorCode::Unavailable,
+                    "The homeserver did not return the ID of the created room.",
+                )
+            })
+    }
+
+    /// Sends the content of an `m.room.message` event as the given user and returns the ID of the event.
+    pub(crate) async fn send(
+        &self,
+        room_id: &RoomId,
+        user_id: &UserId,
+        content: Value,
+    ) -> Result<String, Error> {
+        let txn_id = format!(
+            "kutsche.{}.{}",
+            self.txn_prefix,
+            self.txn_counter.fetch_add(1, Ordering::Relaxed)
+        );
+        let response = self
+            .request(
+                Method::PUT,
+                &["rooms", room_id.as_str(), "send", "m.room.message", &txn_id],
+                Some(user_id),
+                content,
+                MatrixErrorCode::NotInRoom,
+            )
+            .await
+            .inspect_err(|_| {
+                // The user may have been kicked, so it joins again for the next email:
+                self.joined.lock().unwrap().remove(user_id);
+            })?;
+        response["event_id"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| {
+                Error::matrix(
+                    MatrixErrorCode::Unavailable,
+                    "The homeserver did not return the ID of the sent event.",
+                )
+            })
+    }
+
+    /// Uploads a file to the media repository as the given user and returns its content URI.
+    pub(crate) async fn upload(
+        &self,
+        user_id: &UserId,
+        content_type: &str,
+        data: &[u8],
+    ) -> Result<OwnedMxcUri, Error> {
+        let (status, response) = self
+            .send_request(
+                Method::POST,
+                "media",
+                &["upload"],
+                Some(user_id),
+                |request| {
+                    request
+                        .header(CONTENT_TYPE, content_type)
+                        .body(data.to_vec())
+                },
+            )
+            .await?;
+        if !status.is_success() {
+            return Err(response_error(
+                "upload",
+                status,
+                &response,
+                MatrixErrorCode::Sdk,
+            ));
+        }
+        response["content_uri"]
+            .as_str()
+            .map(OwnedMxcUri::from)
+            .filter(|uri| uri.is_valid())
+            .ok_or_else(|| {
+                Error::matrix(
+                    MatrixErrorCode::Unavailable,
+                    "The homeserver did not return the URI of the uploaded file.",
+                )
+            })
+    }
+
+    /// Sends a request to the client-server API, as the given virtual user or as the sender of the application service,
+    /// and returns the response, if it is successful.
+    ///
+    /// Client errors other than rejected tokens and rate limits are reported with `code`.
+    async fn request(
+        &self,
+        method: Method,
+        path: &[&str],
+        user_id: Option<&UserId>,
+        body: Value,
+        code: MatrixErrorCode,
+    ) -> Result<Value, Error> {
+        let (status, response) = self
+            .send_request(method, "client", path, user_id, |request| {
+                request.json(&body)
+            })
+            .await?;
+        if status.is_success() {
+            Ok(response)
+        } else {
+            Err(response_error(path[0], status, &response, code))
+        }
+    }
+
+    /// Sends a request to the given API of the homeserver (e.g. "client" or "media") with the body added by `body`
+    /// and returns the status and body of the response. If the token is rejected, it is read from its file again and
+    /// the request is repeated once, if it was rotated.
+    async fn send_request(
+        &self,
+        method: Method,
+        api: &str,
+        path: &[&str],
+        user_id: Option<&UserId>,
+        body: impl Fn(RequestBuilder) -> RequestBuilder,
+    ) -> Result<(StatusCode, Value), Error> {
+        let mut url = self.homeserver.clone();
+        url.path_segments_mut()
+            .map_err(|_| Error::config(format!("Invalid homeserver URL {}.", self.homeserver)))?
+            .pop_if_empty()
+            .extend(["_matrix", api, "v3"])
+            .extend(path);
+        if let Some(user_id) = user_id {
+            url.query_pairs_mut()
+                .append_pair("user_id", user_id.as_str());
+        }
+
+        let mut reloaded = false;
+        loop {
+            let response = body(
+                self.client
+                    .request(method.clone(), url.clone())
+                    .bearer_auth(self.token.get()),
+            )
+            .send()
+            .await
+            .map_err(|e| {
+                Error::matrix(
+                    MatrixErrorCode::Unavailable,
+                    format!("Could not reach homeserver {}: {}", self.homeserver, e),
+                )
+            })?;
+            let status = response.status();
+            if status == StatusCode::UNAUTHORIZED && !reloaded && self.reload_token() {
+                warn!("The homeserver rejected the application service token, retrying with the rotated one.");
+                reloaded = true;
+                continue;
+            }
+            return Ok((status, response.json().await.unwrap_or_default()));
+        }
+    }
+
+    /// Reads the token from its file again and returns true, if it changed.
+    fn reload_token(&self) -> bool {
+        match self.token.reload() {
+            Ok(changed) => changed,
+            Err(e) => {
+                warn!("Could not reload application service token: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// Returns the error for an unsuccessful response to a request of the given kind. Client errors other than rejected
+/// tokens and rate limits are reported with `code`.
+fn response_error(
+    kind: &str,
+    status: StatusCode,
+    response: &Value,
+    code: MatrixErrorCode,
+) -> Error {
+    let code = match status.as_u16() {
+        401 => MatrixErrorCode::Auth,
+        429 | 500.. => MatrixErrorCode::Unavailable,
+        _ => code,
+    };
+    Error::matrix(
+        code,
+        format!(
+            "Homeserver rejected {} request with {}: {} {}",
+            kind,
+            status,
+            response["errcode"].as_str().unwrap_or_default(),
+            response["error"].as_str().unwrap_or_default()
+        ),
+    )
+}
+
+/// Escapes an email address for a Matrix localpart. Letters are lowercased and all characters, that are not allowed in
+/// localparts, are replaced with `=` and the hex code of their UTF-8 bytes.
+fn escape_localpart(address: &str) -> String {
+    let mut escaped = String::new();
+    for c in address.to_lowercase().chars() {
+        if c.is_ascii_lowercase() || c.is_ascii_digit() || "._-/".contains(c) {
+            escaped.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                escaped.push_str(&format!("={:02x}", byte));
+            }
+        }
+    }
+    escaped
+}
+
+/// Returns the registration file of an application service, that the homeserver is configured with.
+///
+/// The homeserver never pushes events to kutsche (the URL is null), so the `hs_token` is only required by the format.
+pub(crate) fn registration(
+    id: &str,
+    as_token: &str,
+    hs_token: &str,
+    sender: &UserId,
+    user_prefix: &str,
+) -> String {
+    let users = format!(
+        "@{}.*:{}",
+        regex::escape(user_prefix),
+        regex::escape(sender.server_name().as_str())
+    );
+    format!(
+        "id: '{}'\n\
+url: null\n\
+as_token: '{}'\n\
+hs_token: '{}'\n\
+sender_localpart: '{}'\n\
+rate_limited: false\n\
+namespaces:\n\
+\x20 users:\n\
+\x20   - exclusive: true\n\
+\x20     regex: '{}'\n\
+\x20 aliases: []\n\
+\x20 rooms: []\n",
+        id.replace('\'', "''"),
+        as_token,
+        hs_token,
+        sender.localpart(),
+        users.replace('\'', "''"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_user() {
+        let appservice = Appservice::new(
+            Url::parse("https://matrix.example.org").unwrap(),
+            Secret::from("as_token"),
+            UserId::parse("@kutsche:example.org").unwrap(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            appservice.virtual_user("Alice+Ops@Example.com").unwrap(),
+            "@email_alice=2bops=40example.com:example.org"
+        );
+        assert_eq!(
+            appservice.virtual_user("jörg@example.com").unwrap(),
+            "@email_j=c3=b6rg=40example.com:example.org"
+        );
+    }
+
+    #[test]
+    fn test_registration() {
+        let sender = UserId::parse("@kutsche:example.org").unwrap();
+        let registration = registration("kutsche", "as", "hs", &sender, "email_");
+        assert!(registration.contains("sender_localpart: 'kutsche'\n"));
+        assert!(registration.contains("regex: '@email_.*:example\\.org'\n"));
+        assert!(registration.contains("url: null\n"));
+    }
+}
@@ -0,0 +1,88 @@
+use mail_parser::BodyPart;
+use regex::Regex;
+
+/// Tags, whose contents are not shown by mail clients either.
+const HIDDEN_PATTERN: &str =
+    r"(?is)<!--.*?-->|<(?:head|style|script|title)\b[^>]*>.*?</(?:head|style|script|title)\s*>";
+/// The contents of the body element of complete HTML documents.
+const BODY_PATTERN: &str = r"(?is)<body\b[^>]*>(.*?)(?:</body\s*>|$)";
+/// Tags, that end a line in the plain text.
+const LINE_BREAK_PATTERN: &str = r"(?i)<br\s*/?>|</(?:p|div|h[1-6]|li|tr|table|blockquote)\s*>";
+const TAG_PATTERN: &str = r"<[^>]*>";
+
+/// Returns true, if the body part is an HTML document. The HTML bodies of plain text emails are their text parts.
+pub(super) fn is_html(part: &dyn BodyPart<'_>) -> bool {
+    part.get_content_type().is_some_and(|content_type| {
+        content_type
+            .get_subtype()
+            .is_some_and(|subtype| subtype.eq_ignore_ascii_case("html"))
+    })
+}
+
+/// Returns the HTML of a formatted message: The contents of the body without comments, styles and scripts, which
+/// Matrix clients would show as text.
+pub(super) fn formatted_body(html: &str) -> String {
+    let body = Regex::new(BODY_PATTERN)
+        .expect("The body pattern is valid.")
+        .captures(html)
+        .and_then(|captures| captures.get(1))
+        .map_or(html, |body| body.as_str());
+    Regex::new(HIDDEN_PATTERN)
+        .expect("The hidden pattern is valid.")
+        .replace_all(body, "")
+        .trim()
+        .to_string()
+}
+
+/// Returns the plain text fallback of a formatted message, for clients, that don't render HTML.
+pub(super) fn plain_text(html: &str) -> String {
+    let html = formatted_body(html);
+    let html = Regex::new(LINE_BREAK_PATTERN)
+        .expect("The line break pattern is valid.")
+        .replace_all(&html, "\n");
+    let text = Regex::new(TAG_PATTERN)
+        .expect("The tag pattern is valid.")
+        .replace_all(&html, "");
+    let text = text
+        .replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&");
+    // Drop the indentation of the source and empty lines between blocks:
+    let mut lines: Vec<&str> = vec![];
+    for line in text.lines().map(str::trim) {
+        if !line.is_empty() || lines.last().is_some_and(|last| !last.is_empty()) {
+            lines.push(line);
+        }
+    }
+    lines.join("\n").trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NEWSLETTER: &str = "<!DOCTYPE html>\n<html><head><title>News</title>\
+<style>p { color: red; }</style></head>\n<body>\n  <!-- tracking -->\n  <h1>Release 2.0</h1>\n\
+  <p>Faster &amp; <b>safer</b>.<br>See <a href=\"https://example.com\">the notes</a>.</p>\n</body></html>";
+
+    #[test]
+    fn test_formatted_body() {
+        assert_eq!(
+            formatted_body(NEWSLETTER),
+            "<h1>Release 2.0</h1>\n<p>Faster &amp; <b>safer</b>.<br>See <a href=\"https://example.com\">the notes</a>.</p>"
+        );
+        // Fragments are kept:
+        assert_eq!(formatted_body("<p>Hi</p>"), "<p>Hi</p>");
+    }
+
+    #[test]
+    fn test_plain_text() {
+        assert_eq!(
+            plain_text(NEWSLETTER),
+            "Release 2.0\n\nFaster & safer.\nSee the notes."
+        );
+    }
+}
@@ -0,0 +1,1003 @@
+use async_trait::async_trait;
+use log::{error, info, warn};
+use matrix_sdk::{
+    attachment::AttachmentConfig,
+    config::{StoreConfig, SyncSettings},
+    room::{Joined, Room},
+    Client, ClientBuildError, Session,
+};
+use mime::Mime;
+use ruma::{
+    api::client::{
+        room::create_room::{self, v3::RoomPreset},
+        session::login,
+        uiaa::UserIdentifier,
+    },
+    events::{
+        room::{
+            encryption::RoomEncryptionEventContent,
+            message::{
+                FileInfo, FileMessageEventContent, ImageMessageEventContent, MessageType, Relation,
+                RoomMessageEventContent, Thread,
+            },
+            ImageInfo,
+        },
+        EmptyStateKey, InitialStateEvent,
+    },
+    serde::Raw,
+    EventEncryptionAlgorithm, EventId, OwnedMxcUri, OwnedRoomId, OwnedUserId, RoomId, RoomName,
+    UInt, UserId,
+};
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use super::{EmailDestination, Receipt};
+use crate::bounce::Bounce;
+use crate::email::{rfc5322_date, Email, MimePart};
+use crate::error::{Error, MatrixErrorCode};
+use crate::i18n::Locale;
+use crate::proxy::Proxy;
+use crate::redaction::Redactor;
+use crate::secret::Secret;
+use crate::severity::SeverityClassifier;
+use crate::thread_index::{ThreadEntry, ThreadIndex};
+
+pub(crate) use appservice::{registration, Appservice, DEFAULT_USER_PREFIX};
+pub(crate) use rooms::{RoomCreation, RoomIndex, RoomPer};
+
+mod appservice;
+mod html;
+mod rooms;
+#[cfg(test)]
+mod tests;
+
+/// The name of attachments without file name.
+const ATTACHMENT_NAME: &str = "attachment";
+
+pub(crate) struct MatrixDestBuilder<'a> {
+    matrix_client: Client,
+    session_file_path: Option<&'a Path>,
+    login_data: Option<(&'a str, Secret)>, // username, password
+    access_token: Option<Secret>,
+    appservice: Option<(Secret, OwnedUserId, String)>, // token, sender, user prefix
+    proxy: Option<String>,
+    room_id: Option<OwnedRoomId>,
+    room_creation: Option<RoomCreation>,
+    encryption: bool,
+    locale: Locale,
+    classifier: SeverityClassifier,
+    redactor: Redactor,
+    html_messages: bool,
+    max_attachment_size: Option<u64>,
+    thread_index: Option<ThreadIndex>,
+}
+impl<'a> MatrixDestBuilder<'a> {
+    #[cfg(test)]
+    pub async fn new(homeserver_url: impl AsRef<str>) -> Result<MatrixDestBuilder<'a>, Error> {
+        Self::with_proxy(homeserver_url, None).await
+    }
+
+    /// Creates a builder like `new()`, but lets all requests to the homeserver (including the auto-discovery) go
+    /// through the given proxy.
+    pub async fn with_proxy(
+        homeserver_url: impl AsRef<str>,
+        proxy: Option<&Proxy>,
+    ) -> Result<MatrixDestBuilder<'a>, Error> {
+        Self::create(homeserver_url, proxy, None).await
+    }
+
+    /// Creates a builder like `with_proxy()`, whose client keeps its state and encryption keys in a store in the given
+    /// directory, optionally encrypted with the passphrase. With the store, the client can post into end-to-end
+    /// encrypted rooms.
+    ///
+    /// The keys belong to the device of the session, so the session must be kept with a session file or access token.
+    pub async fn with_store(
+        homeserver_url: impl AsRef<str>,
+        proxy: Option<&Proxy>,
+        store_path: &Path,
+        passphrase: Option<&str>,
+    ) -> Result<MatrixDestBuilder<'a>, Error> {
+        let store_config =
+            matrix_sdk::store::make_store_config(store_path, passphrase).map_err(|e| {
+                Error::config(format!(
+                    "Could not open Matrix store {}: {}",
+                    store_path.display(),
+                    e
+                ))
+            })?;
+        let mut builder = Self::create(homeserver_url, proxy, Some(store_config)).await?;
+        builder.encryption = true;
+        Ok(builder)
+    }
+
+    async fn create(
+        homeserver_url: impl AsRef<str>,
+        proxy: Option<&Proxy>,
+        store_config: Option<StoreConfig>,
+    ) -> Result<MatrixDestBuilder<'a>, Error> {
+        let mut client_builder = Client::builder()
+            .homeserver_url(homeserver_url)
+            .respect_login_well_known(true);
+        if let Some(proxy) = proxy {
+            client_builder = client_builder.proxy(proxy.url());
+        }
+        if let Some(store_config) = store_config {
+            client_builder = client_builder.store_config(store_config);
+        }
+        let matrix_client = match client_builder.build().await {
+            Ok(c) => c,
+            Err(ClientBuildError::Url(url_parse_err)) => {
+                return Err(Error::config(format!(
+                    "Could not parse homeserver URL: {}",
+                    url_parse_err
+                )));
+            }
+            Err(ClientBuildError::Http(http_err)) => {
+                return Err(Error::matrix(
+                    MatrixErrorCode::Unavailable,
+                    format!("Error during HTTP request: {}", http_err),
+                ));
+            }
+            Err(ClientBuildError::AutoDiscovery(err)) => {
+                return Err(Error::matrix(
+                    MatrixErrorCode::Unavailable,
+                    format!("Could not perform auto-discovery: {}", err),
+                ));
+            }
+            Err(ClientBuildError::SledStore(_)) => {
+                error!("Creation of matrix client resulted in unexpected sled error.");
+                panic!("I don't think this can happen, because the builder never opens a sled store itself.");
+            }
+            Err(ClientBuildError::MissingHomeserver) => {
+                error!("Creation of matrix client resulted in unexpected MissingHomeserver error.");
+                panic!(
+                    "This shouldn't be possible, because we called .homeserver_url() previously."
+                );
+            }
+        };
+
+        Ok(MatrixDestBuilder {
+            matrix_client,
+            session_file_path: None,
+            login_data: None,
+            access_token: None,
+            appservice: None,
+            proxy: proxy.map(|proxy| proxy.url().to_string()),
+            room_id: None,
+            room_creation: None,
+            encryption: false,
+            locale: Locale::default(),
+            classifier: SeverityClassifier::new(),
+            redactor: Redactor::new(),
+            html_messages: false,
+            max_attachment_size: None,
+            thread_index: None,
+        })
+    }
+
+    pub fn set_login(&mut self, user: &'a str, password: impl Into<Secret>) {
+        self.login_data = Some((user, password.into()));
+    }
+
+    /// Sets a pre-provisioned access token, e.g. for homeservers, that only allow logging in with SSO.
+    pub fn set_access_token(&mut self, access_token: impl Into<Secret>) {
+        self.access_token = Some(access_token.into());
+    }
+
+    /// Lets the destination act as the application service with the given token (`as_token`) and sender, whose virtual
+    /// users (with the given localpart prefix) post the emails of their sender addresses. No session is used then.
+    pub fn set_appservice(
+        &mut self,
+        token: impl Into<Secret>,
+        sender: OwnedUserId,
+        user_prefix: impl Into<String>,
+    ) {
+        self.appservice = Some((token.into(), sender, user_prefix.into()));
+    }
+
+    pub fn set_session_path(&mut self, session_file_path: &'a Path) {
+        self.session_file_path = Some(session_file_path);
+    }
+
+    pub fn set_room_id(&mut self, room_id: OwnedRoomId) {
+        self.room_id = Some(room_id);
+    }
+
+    /// Lets the destination create a new room per conversation or sender instead of using the room of `set_room_id()`.
+    pub fn set_room_creation(&mut self, room_creation: RoomCreation) {
+        self.room_creation = Some(room_creation);
+    }
+
+    /// Sets the language of the text, that is added to the content of the emails.
+    pub fn set_locale(&mut self, locale: Locale) {
+        self.locale = locale;
+    }
+
+    /// Sets the classifier, that determines the emoji, color and message type of the notifications.
+    pub fn set_classifier(&mut self, classifier: SeverityClassifier) {
+        self.classifier = classifier;
+    }
+
+    /// Sets the redactor, that masks email addresses, phone numbers and custom patterns in the bodies of the emails.
+    pub fn set_redactor(&mut self, redactor: Redactor) {
+        self.redactor = redactor;
+    }
+
+    /// Posts HTML bodies as formatted messages instead of their source, so they are readable in clients like Element.
+    pub fn set_html_messages(&mut self, html_messages: bool) {
+        self.html_messages = html_messages;
+    }
+
+    /// Uploads the attachments of emails up to `max_size` bytes to the media repository and posts them after the
+    /// bodies. Larger attachments are replaced with a notice.
+    pub fn set_upload_attachments(&mut self, max_size: u64) {
+        self.max_attachment_size = Some(max_size);
+    }
+
+    /// Sets the index, in which the conversations of the delivered emails are tracked, so replies are posted as thread
+    /// replies to the first email of their conversation.
+    pub fn set_thread_index(&mut self, thread_index: ThreadIndex) {
+        self.thread_index = Some(thread_index);
+    }
+
+    /// Creates a new MatrixDestination by logging the internal Matrix client in or restoring an existing session.
+    ///
+    /// If an application service was set with `set_appservice()`, the client is not logged in at all.
+    /// Otherwise, if an existing file was set with `set_session_path()` a session is restored from this file.
+    /// Otherwise, if an access token was set with `set_access_token()`, the session of the token is used.
+    /// Otherwise, if login data was set with `set_login()` a new session is created. If a non-existing session file was set with
+    /// `set_session_path()` the new session is saved to the given path.
+    /// If neither an existing session file nor an access token or login data is given, an error is returned.
+    /// Panics, if this is called before a room ID was set with 'set_room_id' or room creation with 'set_room_creation'.
+    pub async fn build(self) -> Result<MatrixDestination, Error> {
+        // We allow blocking calls in this function, because it should only be called during the startup of the server.
+
+        let rooms = match (self.room_id, self.room_creation) {
+            (_, Some(room_creation)) => Rooms::Created(room_creation),
+            (Some(room_id), None) => Rooms::Fixed(room_id),
+            (None, None) => panic!("MatrixDestBuilder::build() was called before calling MatrixDestBuilder::set_room_id()"),
+        };
+        if let Some((token, sender, user_prefix)) = self.appservice {
+            if self.encryption {
+                return Err(Error::config(
+                    "Encrypted rooms are not supported in application service mode.".to_string(),
+                ));
+            }
+            let mut appservice = Appservice::new(
+                self.matrix_client.homeserver().await,
+                token,
+                sender,
+                self.proxy.as_deref(),
+            )?;
+            appservice.set_user_prefix(user_prefix);
+            return Ok(MatrixDestination {
+                matrix_client: self.matrix_client,
+                renewal: None,
+                session_file_path: None,
+                rooms,
+                appservice: Some(appservice),
+                encryption: false,
+                locale: self.locale,
+                classifier: self.classifier,
+                redactor: self.redactor,
+                html_messages: self.html_messages,
+                max_attachment_size: self.max_attachment_size,
+                thread_index: self.thread_index,
+            });
+        }
+
+        if let Some(session_file_path) = self.session_file_path.filter(|path| path.is_file()) {
+            let session_file = File::open(session_file_path)?;
+            let session = serde_json::from_reader(BufReader::new(session_file))
+                .map_err(|e| Error::config(format!("Could not parse session file: {}", e)))?;
+            self.matrix_client.restore_login(session).await?;
+        } else if let Some(ref access_token) = self.access_token {
+            restore_with_token(&self.matrix_client, access_token).await?;
+        } else {
+            let (username, ref password) = self.login_data.as_ref().ok_or_else(|| {
+                Error::config("Missing session file path, access token or login data.".to_string())
+            })?;
+            // If a nonexisting session file is given, we create is and save the new session:
+            login(
+                &self.matrix_client,
+                username,
+                password,
+                self.session_file_path,
+            )
+            .await?;
+        }
+        if !self.matrix_client.logged_in().await {
+            error!("Tried to use a matrix client, that was not logged in.");
+            panic!("Called MatrixDestBuilder.build() before logging in or restoring a session.");
+        }
+
+        Ok(MatrixDestination {
+            matrix_client: self.matrix_client,
+            renewal: match (self.login_data, self.access_token) {
+                (Some((username, password)), _) => {
+                    Some(Renewal::Password(username.to_string(), password))
+                }
+                (None, Some(access_token)) => Some(Renewal::AccessToken(access_token)),
+                (None, None) => None,
+            },
+            session_file_path: self.session_file_path.map(Path::to_path_buf),
+            rooms,
+            appservice: None,
+            encryption: self.encryption,
+            locale: self.locale,
+            classifier: self.classifier,
+            redactor: self.redactor,
+            html_messages: self.html_messages,
+            max_attachment_size: self.max_attachment_size,
+            thread_index: self.thread_index,
+        })
+    }
+}
+
+/// Logs the client in with the password and saves the new session to the session file, if one is given.
+async fn login(
+    client: &Client,
+    username: &str,
+    password: &Secret,
+    session_file_path: Option<&Path>,
+) -> Result<(), Error> {
+    client
+        .login(username, &password.get(), None, Some("kutsche-server"))
+        .await
+        .map_err(|e| login_error(Error::from(e), username))?;
+    if let Some(session_file_path) = session_file_path {
+        save_session(client, session_file_path).await?;
+    }
+    Ok(())
+}
+
+/// Restores the session of the access token with the user and device, that the homeserver reports for it.
+async fn restore_with_token(client: &Client, access_token: &Secret) -> Result<(), Error> {
+    let access_token = access_token.get();
+    // The SDK only sends authenticated requests with a session, so the owner of the token is asked with a provisional
+    // one:
+    client
+        .store()
+        .restore_session(Session {
+            access_token: access_token.clone(),
+            user_id: UserId::parse("@kutsche:localhost").expect("The user ID is valid."),
+            device_id: "KUTSCHE".into(),
+        })
+        .await
+        .map_err(|e| Error::from(matrix_sdk::Error::from(e)))?;
+    let whoami =
+        client
+            .whoami()
+            .await
+            .map_err(|e| match Error::from(matrix_sdk::Error::from(e)) {
+                Error::Matrix(mut e) => {
+                    e.code = MatrixErrorCode::Auth;
+                    e.desc = "Could not look up the owner of the access token".to_string();
+                    Error::Matrix(e)
+                }
+                other => other,
+            })?;
+    let device_id = whoami.device_id.ok_or_else(|| {
+        Error::matrix(
+            MatrixErrorCode::Auth,
+            format!(
+                "The homeserver does not report the device of the access token of {}.",
+                whoami.user_id
+            ),
+        )
+    })?;
+    client
+        .restore_login(Session {
+            access_token,
+            user_id: whoami.user_id,
+            device_id,
+        })
+        .await?;
+    Ok(())
+}
+
+fn login_error(e: Error, username: &str) -> Error {
+    match e {
+        Error::Matrix(mut e) => {
+            e.code = MatrixErrorCode::Auth;
+            e.desc = format!("Could not log in as {}", username);
+            Error::Matrix(e)
+        }
+        other => other,
+    }
+}
+
+async fn save_session(client: &Client, session_file_path: &Path) -> Result<(), Error> {
+    let session_file = File::create(session_file_path)?;
+    serde_json::to_writer_pretty(
+        BufWriter::new(session_file),
+        &client
+            .session()
+            .await
+            .expect("We only call this after logging in previously."),
+    )
+    .map_err(|e| Error::config(format!("Could save session to file: {}", e)))
+}
+
+/// Sends every email as messages to a Matrix room.
+///
+/// If the homeserver rejects the access token (e.g. because it expired or the device was logged out) and login data is
+/// given, the client logs in again with the password, which is read from its file again, if it was rotated. A
+/// pre-provisioned access token is replaced, if it was rotated.
+///
+/// In application service mode, the messages are sent by the virtual user of the sender address of the email instead.
+///
+/// With a store, the client syncs before every email, which uploads the keys of its device and updates the members
+/// of the room and their devices, so messages to end-to-end encrypted rooms are encrypted for all of them.
+pub(crate) struct MatrixDestination {
+    matrix_client: Client,
+    renewal: Option<Renewal>,
+    session_file_path: Option<PathBuf>,
+    rooms: Rooms,
+    appservice: Option<Appservice>,
+    encryption: bool,
+    locale: Locale,
+    classifier: SeverityClassifier,
+    redactor: Redactor,
+    html_messages: bool,
+    /// The size in bytes of the largest attachment, that is uploaded, or None, if attachments are not uploaded.
+    max_attachment_size: Option<u64>,
+    thread_index: Option<ThreadIndex>,
+}
+
+/// The rooms, that the emails are posted into.
+enum Rooms {
+    /// All emails go into one room.
+    Fixed(OwnedRoomId),
+    /// A new room is created per conversation or sender.
+    Created(RoomCreation),
+}
+
+/// How a session is renewed, after the homeserver rejected its access token.
+enum Renewal {
+    /// Logging in again with the username and password.
+    Password(String, Secret),
+    /// Using the pre-provisioned access token, if it was rotated.
+    AccessToken(Secret),
+}
+
+impl MatrixDestination {
+    /// Logs in again with the password on the device of the current session and replaces the access token of the
+    /// session. The SDK only supports logging in once per client, so the request is sent directly.
+    async fn renew_session(&self, username: &str, password: &Secret) -> Result<(), Error> {
+        let device_id = self
+            .matrix_client
+            .session()
+            .await
+            .map(|session| session.device_id);
+        let password = password.get();
+        let login_info = login::v3::LoginInfo::Password(login::v3::Password::new(
+            UserIdentifier::UserIdOrLocalpart(username),
+            &password,
+        ));
+        let mut request = login::v3::Request::new(login_info);
+        request.device_id = device_id.as_deref();
+        request.initial_device_display_name = Some("kutsche-server");
+        let response = self
+            .matrix_client
+            .send(request, None)
+            .await
+            .map_err(|e| login_error(Error::from(matrix_sdk::Error::from(e)), username))?;
+        self.matrix_client
+            .store()
+            .restore_session(Session {
+                access_token: response.access_token,
+                user_id: response.user_id,
+                device_id: response.device_id,
+            })
+            .await
+            .map_err(|e| Error::from(matrix_sdk::Error::from(e)))?;
+        info!("Logged in again as {}.", username);
+        if let Some(ref session_file_path) = self.session_file_path {
+            save_session(&self.matrix_client, session_file_path).await?;
+        }
+        Ok(())
+    }
+
+    /// Replaces the access token of the session with the rotated pre-provisioned one. Returns false, if it was not
+    /// rotated.
+    async fn renew_token(&self, access_token: &Secret) -> Result<bool, Error> {
+        if let Err(e) = access_token.reload() {
+            warn!("Could not reload Matrix access token: {}", e);
+        }
+        let session = match self.matrix_client.session().await {
+            Some(session) => session,
+            None => return Ok(false),
+        };
+        let access_token = access_token.get();
+        if session.access_token == access_token {
+            return Ok(false);
+        }
+        self.matrix_client
+            .store()
+            .restore_session(Session {
+                access_token,
+                ..session
+            })
+            .await
+            .map_err(|e| Error::from(matrix_sdk::Error::from(e)))?;
+        info!("Replaced the Matrix access token with the rotated one.");
+        Ok(true)
+    }
+
+    /// Sends the email like `send_email()`, but renews the session and repeats it once, if the access token was
+    /// rejected.
+    async fn send_with_login(&self, email: &Email<'_>, quiet: bool) -> Result<Receipt, Error> {
+        match (self.send_email(email, quiet).await, self.renewal.as_ref()) {
+            (Err(e), Some(Renewal::Password(username, password))) if e.is_auth_failure() => {
+                warn!("{} Logging in again as {}.", e, username);
+                if let Err(e) = password.reload() {
+                    warn!("Could not reload password of {}: {}", username, e);
+                }
+                self.renew_session(username, password).await?;
+                self.send_email(email, quiet).await
+            }
+            (Err(e), Some(Renewal::AccessToken(access_token))) if e.is_auth_failure() => {
+                if self.renew_token(access_token).await? {
+                    self.send_email(email, quiet).await
+                } else {
+                    Err(e)
+                }
+            }
+            (res, _) => res,
+        }
+    }
+
+    /// Receives the changes since the last sync without waiting for new events and sends the pending requests for
+    /// end-to-end encryption, e.g. the upload of the keys of the device.
+    async fn sync(&self) -> Result<(), Error> {
+        let mut settings = SyncSettings::default().timeout(Duration::ZERO);
+        if let Some(token) = self.matrix_client.sync_token().await {
+            settings = settings.token(token);
+        }
+        self.matrix_client.sync_once(settings).await?;
+        Ok(())
+    }
+
+    /// Returns the joined room of the client or, in application service mode, the virtual user of the sender of the
+    /// email, that is registered and joined to the room, if necessary. Emails without sender are sent by the sender
+    /// of the application service.
+    async fn sender<'a>(
+        &'a self,
+        email: &Email<'_>,
+        room_id: &'a RoomId,
+    ) -> Result<Sender<'a>, Error> {
+        if let Some(ref appservice) = self.appservice {
+            let user_id = match rooms::sender_address(email) {
+                Some(address) => appservice.prepare_user(&address, room_id).await?,
+                None => appservice.sender().to_owned(),
+            };
+            return Ok(Sender::Appservice(appservice, room_id, user_id));
+        }
+        if self.encryption {
+            self.sync().await?;
+        }
+        match self.matrix_client.get_room(room_id) {
+            Some(Room::Joined(r)) => Ok(Sender::Client(r)),
+            Some(_) => Err(Error::matrix(
+                MatrixErrorCode::NotInRoom,
+                format!(
+                    "Client is not a member of the given room with ID {}",
+                    room_id
+                ),
+            )),
+            None => Err(Error::matrix(
+                MatrixErrorCode::NotInRoom,
+                format!("Could not get room with ID {}", room_id),
+            )),
+        }
+    }
+
+    /// Returns the room for the email. With room creation, this is the room of its conversation or sender, which is
+    /// created, if there is none yet.
+    async fn room_for(&self, email: &Email<'_>) -> Result<OwnedRoomId, Error> {
+        let room_creation = match self.rooms {
+            Rooms::Fixed(ref room_id) => return Ok(room_id.clone()),
+            Rooms::Created(ref room_creation) => room_creation,
+        };
+        let _creating = room_creation.creating.lock().await;
+        let room_id = match room_creation.find(email) {
+            Some(room_id) => room_id,
+            None => {
+                let room_id = self.create_room(email, room_creation).await?;
+                info!(
+                    "Created Matrix room {} for email with id {}.",
+                    room_id, email.message_id
+                );
+                room_id
+            }
+        };
+        room_creation.record(email, &room_id)?;
+        Ok(room_id)
+    }
+
+    /// Creates a private room for the email and invites the listed users. With a store, the room is encrypted.
+    async fn create_room(
+        &self,
+        email: &Email<'_>,
+        room_creation: &RoomCreation,
+    ) -> Result<OwnedRoomId, Error> {
+        let name = room_creation.room_name(email);
+        if let Some(ref appservice) = self.appservice {
+            return appservice.create_room(&name, &room_creation.invite).await;
+        }
+        let name = <&RoomName>::try_from(name.as_str()).ok();
+        let initial_state = [Raw::new(&InitialStateEvent {
+            content: RoomEncryptionEventContent::new(EventEncryptionAlgorithm::MegolmV1AesSha2),
+            state_key: EmptyStateKey,
+        })
+        .map_err(|e| Error::from(matrix_sdk::Error::from(e)))?
+        .cast()];
+        let mut request = create_room::v3::Request::new();
+        request.name = name;
+        request.invite = &room_creation.invite;
+        request.preset = Some(RoomPreset::PrivateChat);
+        if self.encryption {
+            request.initial_state = &initial_state;
+        }
+        let room_id = self
+            .matrix_client
+            .create_room(request)
+            .await
+            .map_err(|e| Error::from(matrix_sdk::Error::from(e)))?
+            .room_id;
+        // The client only knows the rooms of its syncs:
+        self.sync().await?;
+        Ok(room_id)
+    }
+
+    /// Sends the email to the room. With `quiet`, all messages are sent as `m.notice`, regardless of the severity of the
+    /// email.
+    async fn send_email(&self, email: &Email<'_>, quiet: bool) -> Result<Receipt, Error> {
+        let room_id = self.room_for(email).await?;
+        let room = self.sender(email, &room_id).await?;
+
+        let listener = email
+            .envelope
+            .as_ref()
+            .and_then(|envelope| envelope.client.listener);
+        let bcc = !email.hidden_recipients().is_empty();
+        let classification = email
+            .subject()
+            .and_then(|subject| self.classifier.classify(subject, listener, bcc));
+        let notice = quiet
+            || classification
+                .as_ref()
+                .is_some_and(|c| c.severity.is_notice());
+
+        // Send headers:
+        let mut content = String::new();
+        if let Some(ref classification) = classification {
+            content.push_str(classification.emoji);
+            content.push(' ');
+        }
+        match Bounce::parse(email.raw) {
+            Some(bounce) => {
+                content.push_str(&self.locale.bounce_message(
+                    bounce.kind(),
+                    bounce.original_message_id.as_deref().unwrap_or("?"),
+                ));
+                for recipient in bounce.recipients.iter() {
+                    content.push_str(&format!(
+                        "\n{}: {} {}",
+                        recipient.recipient,
+                        recipient.status,
+                        recipient.diagnostic.as_deref().unwrap_or_default()
+                    ));
+                }
+                content.push('\n');
+            }
+            None => content.push_str(self.locale.received_message()),
+        }
+        for (header_name, header_value) in email.headers() {
+            content.push('\n');
+            content.push_str(header_name.as_str());
+            content.push_str(": ");
+            content.push_str(header_value.as_ref());
+        }
+        let color = classification.and_then(|c| c.color);
+        let mut html = escape_html(&content).replace('\n', "<br>");
+        if let Some(color) = color {
+            html = format!(
+                "<font data-mx-color=\"{}\">{}</font>",
+                escape_html(color),
+                html
+            );
+        }
+        // Offer to unsubscribe from newsletters and mailing lists:
+        let unsubscribe = email.unsubscribe_link();
+        if let Some((ref link, one_click)) = unsubscribe {
+            let label = self.locale.unsubscribe_label(one_click);
+            content.push_str(&format!("\n\n{}: {}", label, link));
+            html.push_str(&format!(
+                "<br><br><a href=\"{}\">{}</a>",
+                escape_html(link),
+                label
+            ));
+        }
+        let event = match (color, unsubscribe) {
+            (None, None) => plain_message(content, notice),
+            _ if notice => RoomMessageEventContent::notice_html(content, html),
+            _ => RoomMessageEventContent::text_html(content, html),
+        };
+        // Replies are posted in the thread of the first delivered email of their conversation:
+        let thread = self
+            .thread_index
+            .as_ref()
+            .and_then(|index| index.find(email));
+        let thread_root = thread
+            .as_ref()
+            .and_then(|entry| EventId::parse(&entry.reference).ok());
+        let in_thread = |mut event: RoomMessageEventContent| {
+            if let Some(ref root) = thread_root {
+                event.relates_to =
+                    Some(Relation::Thread(Thread::plain(root.clone(), root.clone())));
+            }
+            event
+        };
+        let mut event_ids = vec![room.send(in_thread(event)).await?];
+        // With formatted messages, the text body is only sent, if the email has no HTML body, which it alternates:
+        let formatted = self.html_messages && email.html_body_parts().any(html::is_html);
+        // Send text body:
+        for text in email
+            .text_body_parts()
+            .filter(|_| !formatted)
+            .map(|part| String::from(self.redactor.redact(part.get_text_contents())))
+        {
+            event_ids.push(room.send(in_thread(plain_message(text, notice))).await?);
+        }
+        // Send HTML body (the HTML body of a plain text email is its text body, which was sent already):
+        for part in email.html_body_parts().filter(|part| html::is_html(*part)) {
+            let html = self.redactor.redact(part.get_text_contents());
+            let event = if self.html_messages {
+                let body = html::plain_text(&html);
+                let formatted_body = html::formatted_body(&html);
+                if notice {
+                    RoomMessageEventContent::notice_html(body, formatted_body)
+                } else {
+                    RoomMessageEventContent::text_html(body, formatted_body)
+                }
+            } else {
+                plain_message(html.into_owned(), notice)
+            };
+            event_ids.push(room.send(in_thread(event)).await?);
+        }
+        // Send attachments:
+        if let Some(max_size) = self.max_attachment_size {
+            let tree = email.mime_tree();
+            for part in tree.walk().into_iter().filter(|part| is_attachment(part)) {
+                let size = part.contents().len() as u64;
+                let event_id = if size > max_size {
+                    let notice = self.locale.attachment_too_large_message(
+                        part.filename().unwrap_or(ATTACHMENT_NAME),
+                        size.div_ceil(1024),
+                        max_size / 1024,
+                    );
+                    room.send(in_thread(plain_message(notice, true))).await?
+                } else {
+                    self.send_attachment(&room, part, &in_thread).await?
+                };
+                event_ids.push(event_id);
+            }
+        }
+        info!("Wrote email with id {} to Matrix room.", &email.message_id);
+
+        // Tell readers, that the email arrives out of order. The email is already delivered, so a failure is not
+        // reported to the queue, which would deliver it again:
+        if let (true, Some(envelope)) = (email.retried, email.envelope.as_ref()) {
+            let notice = self.locale.delayed_message(
+                envelope.mail_from.as_deref().unwrap_or("<>"),
+                &rfc5322_date(envelope.received_at),
+            );
+            match room.send(in_thread(plain_message(notice, true))).await {
+                Ok(event_id) => event_ids.push(event_id),
+                Err(e) => warn!(
+                    "Could not send notice about delayed email with id {}: {}",
+                    email.message_id, e
+                ),
+            }
+        }
+
+        if let Some(ref index) = self.thread_index {
+            let entry = thread.unwrap_or_else(|| ThreadEntry {
+                thread: email.message_id.clone(),
+                reference: event_ids[0].clone(),
+            });
+            if let Err(e) = index.record(&email.message_id, entry) {
+                warn!(
+                    "Could not record the thread of email with id {}: {}",
+                    email.message_id, e
+                );
+            }
+        }
+
+        Ok(Receipt::new(format!(
+            "{}: {}",
+            room_id,
+            event_ids.join(", ")
+        )))
+    }
+
+    /// Uploads an attachment to the media repository and posts it as an image or file message. In encrypted rooms, the
+    /// SDK encrypts the file and posts it outside of threads.
+    async fn send_attachment(
+        &self,
+        room: &Sender<'_>,
+        part: &MimePart<'_>,
+        in_thread: &impl Fn(RoomMessageEventContent) -> RoomMessageEventContent,
+    ) -> Result<String, Error> {
+        let name = part.filename().unwrap_or(ATTACHMENT_NAME);
+        let content_type: Mime = part
+            .content_type()
+            .parse()
+            .unwrap_or(mime::APPLICATION_OCTET_STREAM);
+        let mut data = part.contents();
+        let url = match room {
+            Sender::Client(joined) if joined.is_encrypted() => {
+                let response = joined
+                    .send_attachment(
+                        name,
+                        &content_type,
+                        &mut std::io::Cursor::new(data),
+                        AttachmentConfig::<&[u8]>::new(),
+                    )
+                    .await?;
+                return Ok(response.event_id.to_string());
+            }
+            Sender::Client(_) => {
+                self.matrix_client
+                    .upload(&content_type, &mut data)
+                    .await?
+                    .content_uri
+            }
+            Sender::Appservice(appservice, _, user_id) => {
+                appservice
+                    .upload(user_id, content_type.essence_str(), data)
+                    .await?
+            }
+        };
+        room.send(in_thread(attachment_message(
+            name,
+            &content_type,
+            url,
+            part.contents().len(),
+        )))
+        .await
+    }
+}
+
+#[async_trait]
+impl EmailDestination for MatrixDestination {
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        self.send_with_login(email, false).await
+    }
+
+    async fn write_notice(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        self.send_with_login(email, true).await
+    }
+
+    fn kind(&self) -> &'static str {
+        "matrix"
+    }
+
+    fn describe(&self) -> String {
+        let mut description = match self.rooms {
+            Rooms::Fixed(ref room_id) => format!("Matrix room {}", room_id),
+            Rooms::Created(ref room_creation) => match room_creation.per {
+                RoomPer::Thread => "new Matrix room per thread".to_string(),
+                RoomPer::Sender => "new Matrix room per sender".to_string(),
+            },
+        };
+        if let Some(ref appservice) = self.appservice {
+            description.push_str(&format!(" as application service {}", appservice.sender()));
+        } else if self.encryption {
+            description.push_str(" with encryption store");
+        }
+        description.push_str(&format!(" ({} severity rules)", self.classifier.len()));
+        description
+    }
+
+    /// Removes the entries of the emails from the thread index and the room of the sender from the room index. The
+    /// messages in the rooms are kept.
+    async fn forget(&self, address: &str, message_ids: &[String]) -> Result<Vec<String>, Error> {
+        let mut removed = vec![];
+        if let Some(ref index) = self.thread_index {
+            let count = index.forget(message_ids)?;
+            if count > 0 {
+                removed.push(format!("{} entries of the thread index", count));
+            }
+        }
+        if let Rooms::Created(ref room_creation) = self.rooms {
+            let count = room_creation.forget(address, message_ids)?;
+            if count > 0 {
+                removed.push(format!("{} entries of the room index", count));
+            }
+        }
+        Ok(removed)
+    }
+}
+
+/// Who sends the messages of an email to the room.
+enum Sender<'a> {
+    /// The logged in client, which is a member of the room.
+    Client(Joined),
+    /// A virtual user of an application service.
+    Appservice(&'a Appservice, &'a RoomId, OwnedUserId),
+}
+
+impl Sender<'_> {
+    /// Sends the message and returns the ID of its event.
+    async fn send(&self, event: RoomMessageEventContent) -> Result<String, Error> {
+        match self {
+            Sender::Client(room) => Ok(room.send(event, None).await?.event_id.to_string()),
+            Sender::Appservice(appservice, room_id, user_id) => {
+                let content = serde_json::to_value(&event).map_err(|e| {
+                    Error::matrix(
+                        MatrixErrorCode::Sdk,
+                        format!("Could not serialize message: {}", e),
+                    )
+                })?;
+                appservice.send(room_id, user_id, content).await
+            }
+        }
+    }
+}
+
+/// Returns true, if the part is a leaf of the MIME tree, that is marked as attachment or has a file name.
+fn is_attachment(part: &MimePart<'_>) -> bool {
+    part.children.is_empty()
+        && (part.disposition().as_deref() == Some("attachment") || part.filename().is_some())
+}
+
+/// Returns an image message for images and a file message for all other attachments.
+fn attachment_message(
+    name: &str,
+    content_type: &Mime,
+    url: OwnedMxcUri,
+    size: usize,
+) -> RoomMessageEventContent {
+    let mimetype = Some(content_type.essence_str().to_string());
+    let size = UInt::new(size as u64);
+    let msgtype = if content_type.type_() == mime::IMAGE {
+        let mut info = ImageInfo::new();
+        info.mimetype = mimetype;
+        info.size = size;
+        MessageType::Image(ImageMessageEventContent::plain(
+            name.to_string(),
+            url,
+            Some(Box::new(info)),
+        ))
+    } else {
+        let mut info = FileInfo::new();
+        info.mimetype = mimetype;
+        info.size = size;
+        let mut content =
+            FileMessageEventContent::plain(name.to_string(), url, Some(Box::new(info)));
+        content.filename = Some(name.to_string());
+        MessageType::File(content)
+    };
+    RoomMessageEventContent::new(msgtype)
+}
+
+fn plain_message(body: String, notice: bool) -> RoomMessageEventContent {
+    if notice {
+        RoomMessageEventContent::notice_plain(body)
+    } else {
+        RoomMessageEventContent::text_plain(body)
+    }
+}
+
+fn escape_html(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
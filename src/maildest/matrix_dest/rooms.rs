@@ -0,0 +1,255 @@
+use ruma::{OwnedRoomId, OwnedUserId, RoomId};
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use crate::email::Email;
+use crate::state_lock;
+use crate::Error;
+
+/// The maximal length of room names in bytes.
+const MAX_NAME_LEN: usize = 255;
+
+/// What a new room is created for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RoomPer {
+    /// Every conversation, that is correlated by the In-Reply-To and References headers, gets its own room.
+    Thread,
+    /// Every sender address gets its own room.
+    Sender,
+}
+
+impl FromStr for RoomPer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "thread" => Ok(RoomPer::Thread),
+            "sender" => Ok(RoomPer::Sender),
+            _ => Err(Error::config(format!(
+                "Unknown room creation '{}' (expected thread or sender).",
+                s
+            ))),
+        }
+    }
+}
+
+/// Creates rooms per conversation or sender instead of posting all emails into one room.
+///
+/// The rooms are named with a template, in which `{subject}` and `{sender}` are replaced with the subject and the
+/// sender address of the first email of the room. The listed users are invited to every new room.
+pub(crate) struct RoomCreation {
+    pub(crate) per: RoomPer,
+    pub(crate) name: String,
+    pub(crate) invite: Vec<OwnedUserId>,
+    index: RoomIndex,
+    /// Held while a room is looked up and created, so concurrent emails of a new conversation share their room.
+    pub(crate) creating: tokio::sync::Mutex<()>,
+}
+
+impl RoomCreation {
+    pub(crate) fn new(per: RoomPer, index: RoomIndex) -> Self {
+        RoomCreation {
+            per,
+            name: match per {
+                RoomPer::Thread => "{subject}",
+                RoomPer::Sender => "{sender}",
+            }
+            .to_string(),
+            invite: vec![],
+            index,
+            creating: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Returns the room of the conversation or sender of the email, if it was created already.
+    pub(crate) fn find(&self, email: &Email<'_>) -> Option<OwnedRoomId> {
+        match self.per {
+            // A retried email finds the room, that was created for it:
+            RoomPer::Thread => std::iter::once(&email.message_id)
+                .chain(email.thread_references().iter())
+                .find_map(|id| self.index.get(id)),
+            RoomPer::Sender => self.index.get(&sender_key(email)),
+        }
+    }
+
+    /// Records, that the email is posted into the room, so later emails of the conversation or sender find it.
+    pub(crate) fn record(&self, email: &Email<'_>, room_id: &RoomId) -> Result<(), Error> {
+        let key = match self.per {
+            RoomPer::Thread => email.message_id.clone(),
+            RoomPer::Sender => sender_key(email),
+        };
+        if self.index.get(&key).is_some() {
+            return Ok(());
+        }
+        self.index.record(&key, room_id)
+    }
+
+    /// Removes the room of the sender `address` and the rooms of the emails with the given Message-IDs from the index,
+    /// so new rooms are created for them. Returns the number of removed entries.
+    pub(crate) fn forget(&self, address: &str, message_ids: &[String]) -> Result<usize, Error> {
+        let sender_key = format!("sender:{}", address.to_lowercase());
+        self.index
+            .forget(|key| key == sender_key || message_ids.iter().any(|id| id == key))
+    }
+
+    /// Returns the name of a new room for the email, which is cut off at the maximal length of room names.
+    pub(crate) fn room_name(&self, email: &Email<'_>) -> String {
+        let mut name = self
+            .name
+            .replace("{subject}", email.subject().unwrap_or_default().trim())
+            .replace("{sender}", &sender_address(email).unwrap_or_default());
+        if name.len() > MAX_NAME_LEN {
+            let mut end = MAX_NAME_LEN;
+            while !name.is_char_boundary(end) {
+                end -= 1;
+            }
+            name.truncate(end);
+        }
+        name
+    }
+}
+
+/// Returns the first address of the From header, or the envelope sender, if there is none.
+pub(crate) fn sender_address(email: &Email<'_>) -> Option<String> {
+    email.header_senders().into_iter().next().or_else(|| {
+        email
+            .envelope
+            .as_ref()
+            .and_then(|envelope| envelope.mail_from.clone())
+    })
+}
+
+fn sender_key(email: &Email<'_>) -> String {
+    format!(
+        "sender:{}",
+        sender_address(email).unwrap_or_default().to_lowercase()
+    )
+}
+
+/// A persistent index of the created rooms by the Message-IDs of the emails posted into them, or by sender.
+///
+/// Every entry is appended to the file as one line of JSON with the fields "key" and "room_id", so the rooms are reused
+/// after restarts.
+pub(crate) struct RoomIndex {
+    state: Mutex<(File, HashMap<String, OwnedRoomId>)>,
+}
+
+impl RoomIndex {
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        let mut rooms = HashMap::new();
+        if path.is_file() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let entry: Value = match serde_json::from_str(&line?) {
+                    Ok(entry) => entry,
+                    // Skip lines, that were cut off, e.g. by a full disk:
+                    Err(_) => continue,
+                };
+                if let (Some(key), Some(Ok(room_id))) = (
+                    entry["key"].as_str(),
+                    entry["room_id"].as_str().map(RoomId::parse),
+                ) {
+                    rooms.insert(key.to_string(), room_id);
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(RoomIndex {
+            state: Mutex::new((file, rooms)),
+        })
+    }
+
+    fn get(&self, key: &str) -> Option<OwnedRoomId> {
+        self.state
+            .lock()
+            .expect("Room index is poisoned.")
+            .1
+            .get(key)
+            .cloned()
+    }
+
+    fn record(&self, key: &str, room_id: &RoomId) -> Result<(), Error> {
+        let mut line = json!({ "key": key, "room_id": room_id }).to_string();
+        line.push('\n');
+        let _writing = state_lock::writing();
+        let mut state = self.state.lock().expect("Room index is poisoned.");
+        state.0.write_all(line.as_bytes())?;
+        state.0.flush()?;
+        state.1.insert(key.to_string(), room_id.to_owned());
+        Ok(())
+    }
+
+    /// Removes the entries with matching keys and rewrites the file with the remaining entries. Returns the number of
+    /// removed entries.
+    fn forget(&self, matches: impl Fn(&str) -> bool) -> Result<usize, Error> {
+        let _writing = state_lock::writing();
+        let mut state = self.state.lock().expect("Room index is poisoned.");
+        let (ref mut file, ref mut rooms) = *state;
+        let before = rooms.len();
+        rooms.retain(|key, _| !matches(key));
+        let removed = before - rooms.len();
+        if removed > 0 {
+            let mut content = String::new();
+            for (key, room_id) in rooms.iter() {
+                content.push_str(&json!({ "key": key, "room_id": room_id }).to_string());
+                content.push('\n');
+            }
+            // The file is opened for appending, so the content is written to its start after truncating it:
+            file.set_len(0)?;
+            file.write_all(content.as_bytes())?;
+            file.flush()?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rooms.jsonl");
+        let room_id = RoomId::parse("!thread:localhost").unwrap();
+        let first = Email::parse(
+            b"Message-ID: <a@example.com>\r\nFrom: alice@example.com\r\nSubject: Backup failed\r\n\r\nHi\r\n",
+        )
+        .unwrap();
+        let reply = Email::parse(
+            b"Message-ID: <b@example.com>\r\nFrom: bob@example.com\r\nIn-Reply-To: <a@example.com>\r\n\r\nHi\r\n",
+        )
+        .unwrap();
+
+        let creation = RoomCreation::new(RoomPer::Thread, RoomIndex::open(&path).unwrap());
+        assert_eq!(creation.room_name(&first), "Backup failed");
+        assert_eq!(creation.find(&first), None);
+        creation.record(&first, &room_id).unwrap();
+        assert_eq!(creation.find(&first), Some(room_id.clone()));
+        assert_eq!(creation.find(&reply), Some(room_id.clone()));
+        drop(creation);
+
+        // The index is restored from the file:
+        let creation = RoomCreation::new(RoomPer::Thread, RoomIndex::open(&path).unwrap());
+        assert_eq!(creation.find(&reply), Some(room_id));
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut creation = RoomCreation::new(
+            RoomPer::Sender,
+            RoomIndex::open(&dir.path().join("rooms.jsonl")).unwrap(),
+        );
+        creation.name = "Mail from {sender}".to_string();
+        assert_eq!(creation.room_name(&first), "Mail from alice@example.com");
+        let room_id = RoomId::parse("!alice:localhost").unwrap();
+        creation.record(&first, &room_id).unwrap();
+        assert_eq!(creation.find(&first), Some(room_id));
+        assert_eq!(creation.find(&reply), None);
+        assert_eq!(creation.forget("Alice@example.com", &[]).unwrap(), 1);
+        assert_eq!(creation.find(&first), None);
+    }
+}
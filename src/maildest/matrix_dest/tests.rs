@@ -0,0 +1,962 @@
+use matrix_sdk::config::SyncSettings;
+use ruma::RoomId;
+use serde_json::json;
+use tempfile::TempDir;
+use wiremock::{
+    matchers::{body_string_contains, header, method, path, path_regex, query_param},
+    Mock, MockServer, ResponseTemplate,
+};
+
+use super::*;
+use crate::email::SmtpEmail;
+
+const TEST_ROOM_ID: &str = "!test_room:localhost";
+const TEST_USER_ID: &str = "@kutsche:localhost";
+
+const TEST_EMAIL: &[u8] = b"From: sender@example.com\r\n\
+To: receiver@example.org\r\n\
+Subject: Hello world\r\n\
+Message-ID: <test-message@example.com>\r\n\
+\r\n\
+Hello world.\r\n";
+
+/// Starts a mocked homeserver, that answers the requests every client sends independently of the test case.
+async fn start_homeserver() -> MockServer {
+    let server = MockServer::start().await;
+
+    Mock::given(method("GET"))
+        .and(path("/_matrix/client/versions"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "versions": ["r0.6.1", "v1.1", "v1.2"]
+        })))
+        .mount(&server)
+        .await;
+    // The crypto machine uploads and queries keys during syncs. We answer both with one body, because additional
+    // fields are ignored on deserialization:
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/(r0|v3)/keys/(upload|query)$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "one_time_key_counts": { "signed_curve25519": 50 },
+            "device_keys": {},
+            "failures": {}
+        })))
+        .mount(&server)
+        .await;
+    // The test room is not encrypted:
+    Mock::given(method("GET"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/state/m(\.|%2E)room(\.|%2E)encryption/?$",
+        ))
+        .respond_with(ResponseTemplate::new(404).set_body_json(json!({
+            "errcode": "M_NOT_FOUND",
+            "error": "Event not found."
+        })))
+        .mount(&server)
+        .await;
+
+    server
+}
+
+async fn mock_login(server: &MockServer, expected_calls: u64) {
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/(r0|v3)/login$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "user_id": TEST_USER_ID,
+            "access_token": "test_access_token",
+            "device_id": "TESTDEVICE"
+        })))
+        .expect(expected_calls)
+        .mount(server)
+        .await;
+}
+
+async fn mock_joined_sync(server: &MockServer) {
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/(r0|v3)/sync$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "next_batch": "s1",
+            "rooms": {
+                "join": {
+                    TEST_ROOM_ID: {
+                        "timeline": { "events": [], "limited": false },
+                        "state": { "events": [] },
+                        "ephemeral": { "events": [] },
+                        "account_data": { "events": [] }
+                    }
+                }
+            }
+        })))
+        .mount(server)
+        .await;
+}
+
+fn write_session_file(dir: &TempDir) -> std::path::PathBuf {
+    let session_path = dir.path().join("session.json");
+    std::fs::write(
+        &session_path,
+        json!({
+            "access_token": "restored_access_token",
+            "user_id": TEST_USER_ID,
+            "device_id": "RESTOREDDEVICE"
+        })
+        .to_string(),
+    )
+    .expect("Could not write session file.");
+    session_path
+}
+
+#[tokio::test]
+async fn test_login_with_password() {
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    let dest = builder.build().await.expect("Could not log in.");
+
+    assert!(dest.matrix_client.logged_in().await);
+}
+
+#[tokio::test]
+async fn test_login_saves_session() {
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+    let dir = TempDir::new().unwrap();
+    let session_path = dir.path().join("session.json");
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_session_path(&session_path);
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    builder.build().await.expect("Could not log in.");
+
+    let saved: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&session_path).unwrap()).unwrap();
+    assert_eq!(saved["access_token"], "test_access_token");
+    assert_eq!(saved["user_id"], TEST_USER_ID);
+}
+
+#[tokio::test]
+async fn test_session_restore() {
+    let server = start_homeserver().await;
+    // Restoring a session must not log in again:
+    mock_login(&server, 0).await;
+    let dir = TempDir::new().unwrap();
+    let session_path = write_session_file(&dir);
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_session_path(&session_path);
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    let dest = builder.build().await.expect("Could not restore session.");
+
+    assert!(dest.matrix_client.logged_in().await);
+    assert_eq!(
+        dest.matrix_client
+            .session()
+            .await
+            .map(|s| s.access_token)
+            .as_deref(),
+        Some("restored_access_token")
+    );
+}
+
+#[tokio::test]
+async fn test_invalid_session_file() {
+    let server = start_homeserver().await;
+    let dir = TempDir::new().unwrap();
+    let session_path = dir.path().join("session.json");
+    std::fs::write(&session_path, "not a session").unwrap();
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_session_path(&session_path);
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+
+    assert!(matches!(builder.build().await, Err(Error::Config(_))));
+}
+
+#[tokio::test]
+async fn test_missing_login_data() {
+    let server = start_homeserver().await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+
+    assert!(matches!(builder.build().await, Err(Error::Config(_))));
+}
+
+#[tokio::test]
+async fn test_login_rejected() {
+    let server = start_homeserver().await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/(r0|v3)/login$"))
+        .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+            "errcode": "M_FORBIDDEN",
+            "error": "Invalid password."
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "wrong");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+
+    assert!(matches!(builder.build().await, Err(Error::Matrix(_))));
+}
+
+#[tokio::test]
+async fn test_invalid_homeserver_url() {
+    assert!(matches!(
+        MatrixDestBuilder::new("not a url").await,
+        Err(Error::Config(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_send_to_joined_room() {
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+    mock_joined_sync(&server).await;
+    // One message for the headers and one for the single text part:
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$test_event:localhost"
+        })))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    let dest = builder.build().await.unwrap();
+    dest.matrix_client
+        .sync_once(SyncSettings::default())
+        .await
+        .expect("Could not sync with mocked homeserver.");
+
+    let email = SmtpEmail::new(None, vec![], TEST_EMAIL).unwrap();
+    let receipt = dest
+        .write_email(&email.content)
+        .await
+        .expect("Could not send email to room.");
+    assert_eq!(
+        receipt.reference.unwrap(),
+        format!(
+            "{}: $test_event:localhost, $test_event:localhost",
+            TEST_ROOM_ID
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_delayed_delivery_notice() {
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+    mock_joined_sync(&server).await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .and(body_string_contains(
+            "Delayed delivery: email from cron@example.org originally received at Tue, 1 Mar 2022 10:00:00 +0000",
+        ))
+        .and(body_string_contains("m.notice"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$notice:localhost"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$test_event:localhost"
+        })))
+        .expect(4)
+        .mount(&server)
+        .await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    let dest = builder.build().await.unwrap();
+    dest.matrix_client
+        .sync_once(SyncSettings::default())
+        .await
+        .unwrap();
+
+    let mut email = SmtpEmail::new(
+        Some(lettre::EmailAddress::new("cron@example.org".to_string()).unwrap()),
+        vec![],
+        TEST_EMAIL,
+    )
+    .unwrap();
+    let mut envelope = email.envelope();
+    envelope.received_at = 1646128800;
+    email.content.envelope = Some(std::sync::Arc::new(envelope));
+    // Emails delivered at the first attempt get no notice, retried ones get one after the header and the body:
+    dest.write_email(&email.content).await.unwrap();
+
+    email.content.retried = true;
+    let receipt = dest.write_email(&email.content).await.unwrap();
+    assert!(receipt.reference.unwrap().ends_with(", $notice:localhost"));
+}
+
+#[tokio::test]
+async fn test_send_to_unknown_room() {
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/(r0|v3)/rooms/.*/send/.*$"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(0)
+        .mount(&server)
+        .await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    let dest = builder.build().await.unwrap();
+
+    let email = SmtpEmail::new(None, vec![], TEST_EMAIL).unwrap();
+    assert!(matches!(
+        dest.write_email(&email.content).await,
+        Err(Error::Matrix(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_send_rejected_by_homeserver() {
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+    mock_joined_sync(&server).await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+            "errcode": "M_FORBIDDEN",
+            "error": "You are not allowed to send messages in this room."
+        })))
+        .mount(&server)
+        .await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    let dest = builder.build().await.unwrap();
+    dest.matrix_client
+        .sync_once(SyncSettings::default())
+        .await
+        .unwrap();
+
+    let email = SmtpEmail::new(None, vec![], TEST_EMAIL).unwrap();
+    assert!(dest.write_email(&email.content).await.is_err());
+}
+
+#[tokio::test]
+async fn test_localized_notification() {
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+    mock_joined_sync(&server).await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .and(body_string_contains("Neue Nachricht empfangen:"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$test_event:localhost"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$test_event:localhost"
+        })))
+        .mount(&server)
+        .await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    builder.set_locale(Locale::De);
+    let dest = builder.build().await.unwrap();
+    dest.matrix_client
+        .sync_once(SyncSettings::default())
+        .await
+        .unwrap();
+
+    let email = SmtpEmail::new(None, vec![], TEST_EMAIL).unwrap();
+    dest.write_email(&email.content)
+        .await
+        .expect("Could not send email to room.");
+}
+
+#[tokio::test]
+async fn test_unsubscribe_link() {
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+    mock_joined_sync(&server).await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .and(body_string_contains(
+            r#"<a href=\"https://lists.example.com/leave?id=42\">Unsubscribe (one click)</a>"#,
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$test_event:localhost"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$test_event:localhost"
+        })))
+        .mount(&server)
+        .await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    let dest = builder.build().await.unwrap();
+    dest.matrix_client
+        .sync_once(SyncSettings::default())
+        .await
+        .unwrap();
+
+    let raw = b"From: news@lists.example.com\r\n\
+List-Unsubscribe: <https://lists.example.com/leave?id=42>\r\n\
+List-Unsubscribe-Post: List-Unsubscribe=One-Click\r\n\
+Message-ID: <newsletter@example.com>\r\n\
+\r\n\
+News.\r\n";
+    let email = SmtpEmail::new(None, vec![], raw).unwrap();
+    dest.write_email(&email.content)
+        .await
+        .expect("Could not send email to room.");
+}
+
+#[tokio::test]
+async fn test_thread_reply() {
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+    mock_joined_sync(&server).await;
+    // The header and the text body of the reply are posted in the thread of the original email:
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .and(body_string_contains(r#""rel_type":"io.element.thread""#))
+        .and(body_string_contains(r#""event_id":"$original:localhost""#))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$reply:localhost"
+        })))
+        .expect(2)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$original:localhost"
+        })))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let dir = TempDir::new().unwrap();
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    builder.set_thread_index(ThreadIndex::open(&dir.path().join("threads.jsonl")).unwrap());
+    let dest = builder.build().await.unwrap();
+    dest.matrix_client
+        .sync_once(SyncSettings::default())
+        .await
+        .unwrap();
+
+    let email = SmtpEmail::new(None, vec![], TEST_EMAIL).unwrap();
+    dest.write_email(&email.content).await.unwrap();
+    let reply = b"From: receiver@example.org\r\n\
+Subject: Re: Hello world\r\n\
+Message-ID: <reply@example.org>\r\n\
+In-Reply-To: <test-message@example.com>\r\n\
+\r\n\
+Hello back.\r\n";
+    let reply = SmtpEmail::new(None, vec![], reply).unwrap();
+    dest.write_email(&reply.content).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_login_again_after_unknown_token() {
+    let server = start_homeserver().await;
+    mock_login(&server, 2).await;
+    mock_joined_sync(&server).await;
+    // The access token expires before the first message:
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .respond_with(ResponseTemplate::new(401).set_body_json(json!({
+            "errcode": "M_UNKNOWN_TOKEN",
+            "error": "Access token has expired",
+            "soft_logout": true
+        })))
+        .up_to_n_times(1)
+        .with_priority(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$test_event:localhost"
+        })))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let dir = tempfile::tempdir().unwrap();
+    let password_path = dir.path().join("password");
+    std::fs::write(&password_path, "secret\n").unwrap();
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", Secret::from_file(&password_path).unwrap());
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    let dest = builder.build().await.unwrap();
+    dest.matrix_client
+        .sync_once(SyncSettings::default())
+        .await
+        .expect("Could not sync with mocked homeserver.");
+
+    let email = SmtpEmail::new(None, vec![], TEST_EMAIL).unwrap();
+    dest.write_email(&email.content)
+        .await
+        .expect("Could not send email after logging in again.");
+}
+
+#[tokio::test]
+async fn test_access_token() {
+    let server = start_homeserver().await;
+    mock_login(&server, 0).await;
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/(r0|v3)/account/whoami$"))
+        .and(header("Authorization", "Bearer provisioned_token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "user_id": TEST_USER_ID,
+            "device_id": "SSODEVICE"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_access_token("provisioned_token");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    let dest = builder.build().await.expect("Could not use access token.");
+
+    let session = dest.matrix_client.session().await.unwrap();
+    assert_eq!(session.access_token, "provisioned_token");
+    assert_eq!(session.user_id, TEST_USER_ID);
+    assert_eq!(session.device_id, "SSODEVICE");
+}
+
+#[tokio::test]
+async fn test_appservice() {
+    const VIRTUAL_USER: &str = "@email_sender=40example.com:localhost";
+    let server = start_homeserver().await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/v3/register$"))
+        .and(header("Authorization", "Bearer as_token"))
+        .and(body_string_contains(
+            "\"username\":\"email_sender=40example.com\"",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "user_id": VIRTUAL_USER })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(r"^/_matrix/client/v3/profile/.*/displayname$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+        .mount(&server)
+        .await;
+    // The virtual user is a member already:
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/v3/rooms/.*/invite$"))
+        .respond_with(ResponseTemplate::new(403).set_body_json(json!({
+            "errcode": "M_FORBIDDEN",
+            "error": "User is already in the room."
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/v3/join/.*$"))
+        .and(query_param("user_id", VIRTUAL_USER))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "room_id": TEST_ROOM_ID })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/v3/rooms/.*/send/m\.room\.message/.*$",
+        ))
+        .and(query_param("user_id", VIRTUAL_USER))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "event_id": "$event" })))
+        .expect(4)
+        .mount(&server)
+        .await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_appservice(
+        "as_token",
+        UserId::parse("@kutsche:localhost").unwrap(),
+        DEFAULT_USER_PREFIX,
+    );
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    let dest = builder
+        .build()
+        .await
+        .expect("Could not use application service.");
+    assert!(!dest.matrix_client.logged_in().await);
+
+    let email = Email::parse(TEST_EMAIL).unwrap();
+    let receipt = dest.write_email(&email).await.unwrap();
+    assert_eq!(
+        receipt.reference.as_deref(),
+        Some("!test_room:localhost: $event, $event")
+    );
+    // The virtual user is registered and joined only once:
+    dest.write_email(&email).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_encrypted_room() {
+    let server = start_homeserver().await;
+    let member = json!({
+        "type": "m.room.member",
+        "state_key": TEST_USER_ID,
+        "sender": TEST_USER_ID,
+        "event_id": "$member:localhost",
+        "origin_server_ts": 1,
+        "content": { "membership": "join" }
+    });
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/(r0|v3)/sync$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "next_batch": "s1",
+            "rooms": {
+                "join": {
+                    TEST_ROOM_ID: {
+                        "timeline": { "events": [], "limited": false },
+                        "state": { "events": [
+                            {
+                                "type": "m.room.encryption",
+                                "state_key": "",
+                                "sender": TEST_USER_ID,
+                                "event_id": "$encryption:localhost",
+                                "origin_server_ts": 1,
+                                "content": { "algorithm": "m.megolm.v1.aes-sha2" }
+                            },
+                            member
+                        ] },
+                        "ephemeral": { "events": [] },
+                        "account_data": { "events": [] }
+                    }
+                }
+            }
+        })))
+        .expect(1..)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/(r0|v3)/rooms/.*/members$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({ "chunk": [member] })))
+        .mount(&server)
+        .await;
+    // Only encrypted messages are sent:
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)encrypted/.*$",
+        ))
+        .and(body_string_contains("m.megolm.v1.aes-sha2"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$encrypted:localhost"
+        })))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let dir = TempDir::new().unwrap();
+    let session_path = write_session_file(&dir);
+
+    let store_path = dir.path().join("store");
+    let mut builder = MatrixDestBuilder::with_store(server.uri(), None, &store_path, Some("pass"))
+        .await
+        .unwrap();
+    builder.set_session_path(&session_path);
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    let dest = builder.build().await.unwrap();
+
+    let email = SmtpEmail::new(None, vec![], TEST_EMAIL).unwrap();
+    dest.write_email(&email.content)
+        .await
+        .expect("Could not send email to encrypted room.");
+    assert!(dest.describe().contains("encryption store"));
+}
+
+#[tokio::test]
+async fn test_room_per_thread() {
+    const CREATED_ROOM_ID: &str = "!created:localhost";
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/client/(r0|v3)/createRoom$"))
+        .and(body_string_contains("\"name\":\"Mail: Hello world\""))
+        .and(body_string_contains("\"invite\":[\"@admin:localhost\"]"))
+        .respond_with(
+            ResponseTemplate::new(200).set_body_json(json!({ "room_id": CREATED_ROOM_ID })),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path_regex(r"^/_matrix/client/(r0|v3)/sync$"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "next_batch": "s1",
+            "rooms": {
+                "join": {
+                    CREATED_ROOM_ID: {
+                        "timeline": { "events": [], "limited": false },
+                        "state": { "events": [] },
+                        "ephemeral": { "events": [] },
+                        "account_data": { "events": [] }
+                    }
+                }
+            }
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/(!|%21)created(:|%3A)localhost/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$test_event:localhost"
+        })))
+        .expect(4)
+        .mount(&server)
+        .await;
+    let dir = TempDir::new().unwrap();
+
+    let mut room_creation = RoomCreation::new(
+        RoomPer::Thread,
+        RoomIndex::open(&dir.path().join("rooms.jsonl")).unwrap(),
+    );
+    room_creation.name = "Mail: {subject}".to_string();
+    room_creation.invite = vec![UserId::parse("@admin:localhost").unwrap()];
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_room_creation(room_creation);
+    let dest = builder.build().await.unwrap();
+
+    let email = Email::parse(TEST_EMAIL).unwrap();
+    let receipt = dest.write_email(&email).await.unwrap();
+    assert!(receipt.reference.unwrap().starts_with(CREATED_ROOM_ID));
+    // The reply is posted into the same room:
+    let reply = b"From: receiver@example.org\r\n\
+Subject: Re: Hello world\r\n\
+Message-ID: <reply@example.org>\r\n\
+In-Reply-To: <test-message@example.com>\r\n\
+\r\n\
+Hi.\r\n";
+    dest.write_email(&Email::parse(reply).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(
+        dest.describe(),
+        "new Matrix room per thread (0 severity rules)"
+    );
+}
+
+#[tokio::test]
+async fn test_html_messages() {
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+    mock_joined_sync(&server).await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .and(body_string_contains(
+            r#""formatted_body":"<p>Backup <b>failed</b>.</p>""#,
+        ))
+        .and(body_string_contains(r#""body":"Backup failed.""#))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$html_event:localhost"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    // The headers are sent in their own message, but the text alternative is not:
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$test_event:localhost"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    builder.set_html_messages(true);
+    let dest = builder.build().await.unwrap();
+    dest.matrix_client
+        .sync_once(SyncSettings::default())
+        .await
+        .unwrap();
+
+    let raw = b"From: backup@example.com\r\n\
+Subject: Backup report\r\n\
+Message-ID: <html-report@example.com>\r\n\
+MIME-Version: 1.0\r\n\
+Content-Type: multipart/alternative; boundary=\"b\"\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+\r\n\
+Backup *failed*.\r\n\
+--b\r\n\
+Content-Type: text/html; charset=utf-8\r\n\
+\r\n\
+<html><head><style>b { color: red; }</style></head><body><p>Backup <b>failed</b>.</p></body></html>\r\n\
+--b--\r\n";
+    let email = SmtpEmail::new(None, vec![], raw).unwrap();
+    let receipt = dest.write_email(&email.content).await.unwrap();
+    assert_eq!(
+        receipt.reference.unwrap(),
+        format!(
+            "{}: $test_event:localhost, $html_event:localhost",
+            TEST_ROOM_ID
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_upload_attachments() {
+    let server = start_homeserver().await;
+    mock_login(&server, 1).await;
+    mock_joined_sync(&server).await;
+    Mock::given(method("POST"))
+        .and(path_regex(r"^/_matrix/media/(r0|v3)/upload$"))
+        .and(header("content-type", "image/png"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "content_uri": "mxc://localhost/chart"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .and(body_string_contains(r#""msgtype":"m.image""#))
+        .and(body_string_contains(r#""url":"mxc://localhost/chart""#))
+        .and(body_string_contains(r#""body":"chart.png""#))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$image_event:localhost"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    // The dump is too large, so only a notice about it is posted:
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .and(body_string_contains(
+            "Attachment dump.bin (2 KB) was not uploaded, because it is larger than 1 KB.",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$notice_event:localhost"
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("PUT"))
+        .and(path_regex(
+            r"^/_matrix/client/(r0|v3)/rooms/.*/send/m(\.|%2E)room(\.|%2E)message/.*$",
+        ))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "event_id": "$test_event:localhost"
+        })))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let mut builder = MatrixDestBuilder::new(server.uri()).await.unwrap();
+    builder.set_login("kutsche", "secret");
+    builder.set_room_id(RoomId::parse(TEST_ROOM_ID).unwrap());
+    builder.set_upload_attachments(1024);
+    let dest = builder.build().await.unwrap();
+    dest.matrix_client
+        .sync_once(SyncSettings::default())
+        .await
+        .unwrap();
+
+    let raw = format!(
+        "From: monitoring@example.com\r\n\
+Subject: Weekly report\r\n\
+Message-ID: <attachments@example.com>\r\n\
+MIME-Version: 1.0\r\n\
+Content-Type: multipart/mixed; boundary=\"b\"\r\n\
+\r\n\
+--b\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+\r\n\
+See the attached chart.\r\n\
+--b\r\n\
+Content-Type: image/png\r\n\
+Content-Disposition: attachment; filename=\"chart.png\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+iVBORw0KGgo=\r\n\
+--b\r\n\
+Content-Type: application/octet-stream\r\n\
+Content-Disposition: attachment; filename=\"dump.bin\"\r\n\
+\r\n\
+{}\r\n\
+--b--\r\n",
+        "x".repeat(2000)
+    );
+    let email = SmtpEmail::new(None, vec![], raw.as_bytes()).unwrap();
+    let receipt = dest.write_email(&email.content).await.unwrap();
+    assert_eq!(
+        receipt.reference.unwrap(),
+        format!(
+            "{}: $test_event:localhost, $test_event:localhost, $image_event:localhost, $notice_event:localhost",
+            TEST_ROOM_ID
+        )
+    );
+}
@@ -1,15 +1,188 @@
 use async_trait::async_trait;
+use rustls::{ClientConfig, OwnedTrustAnchor, RootCertStore};
+use serde_json::json;
+use tokio_rustls::TlsConnector;
+
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use crate::email::Email;
 use crate::Error;
 
+mod amqp_dest;
+mod discord_dest;
+mod fan_out_dest;
 mod file_dest;
+mod lmtp_dest;
 mod matrix_dest;
+mod mqtt_dest;
+mod nats_dest;
+mod postgres_dest;
+mod redis_dest;
+mod relay_dest;
+mod rules_dest;
+mod webhook_dest;
+
+pub(crate) use amqp_dest::AmqpDestination;
+pub(crate) use discord_dest::DiscordDestination;
+pub(crate) use fan_out_dest::FanOutDestination;
+pub(crate) use file_dest::{write_mbox_entry, FileDestination, FileFormat};
+pub(crate) use lmtp_dest::{LmtpAddress, LmtpDestination};
+pub(crate) use matrix_dest::{
+    registration, MatrixDestBuilder, RoomCreation, RoomIndex, RoomPer, DEFAULT_USER_PREFIX,
+};
+pub(crate) use mqtt_dest::MqttDestination;
+pub(crate) use nats_dest::NatsDestination;
+pub(crate) use postgres_dest::PostgresDestination;
+pub(crate) use redis_dest::RedisDestination;
+pub(crate) use relay_dest::{PoolConfig, RelayDestination, StartTls};
+pub(crate) use rules_dest::{RoutingRule, RuleDestination};
+pub(crate) use webhook_dest::WebhookDestination;
+
+/// Details about a successful delivery, that can be recorded to prove that an email was forwarded.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub(crate) struct Receipt {
+    /// A reference to the delivered email, that the destination reported, e.g. the event IDs in a Matrix room or the
+    /// final reply of a relay.
+    pub(crate) reference: Option<String>,
+}
+
+impl Receipt {
+    pub(crate) fn new(reference: impl Into<String>) -> Self {
+        Receipt {
+            reference: Some(reference.into()),
+        }
+    }
+}
 
-pub(crate) use file_dest::FileDestination;
-pub(crate) use matrix_dest::MatrixDestBuilder;
+/// Returns a connector for TLS connections to servers with certificates of the web PKI.
+pub(crate) fn tls_connector() -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+        OwnedTrustAnchor::from_subject_spki_name_constraints(
+            ta.subject,
+            ta.spki,
+            ta.name_constraints,
+        )
+    }));
+    TlsConnector::from(Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+/// Decodes the %-escapes in a part of a URL.
+pub(crate) fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            bytes.push(b);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// What destinations, that publish to a message broker, publish for every email.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum BrokerPayload {
+    /// A JSON object with the message ID, the envelope, the subject and the sender and recipients from the headers.
+    Json,
+    /// The text body of the email.
+    Text,
+    /// The complete email as received.
+    Raw,
+}
+
+impl BrokerPayload {
+    fn encode(self, email: &Email<'_>) -> Vec<u8> {
+        match self {
+            BrokerPayload::Json => {
+                let from = email
+                    .headers()
+                    .find(|(name, _)| name.as_str().eq_ignore_ascii_case("from"))
+                    .map(|(_, value)| value.trim().to_string());
+                json!({
+                    "message_id": email.message_id,
+                    "envelope": email.envelope.as_ref().map(|envelope| envelope.to_json()),
+                    "subject": email.subject(),
+                    "from": from,
+                    "to": email.header_recipients(),
+                    "size": email.raw.len(),
+                })
+                .to_string()
+                .into_bytes()
+            }
+            BrokerPayload::Text => email
+                .text_body_parts()
+                .map(|part| part.get_text_contents())
+                .collect::<Vec<_>>()
+                .join("\n")
+                .into_bytes(),
+            BrokerPayload::Raw => email.raw.to_vec(),
+        }
+    }
+}
+
+impl FromStr for BrokerPayload {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(BrokerPayload::Json),
+            "text" => Ok(BrokerPayload::Text),
+            "raw" => Ok(BrokerPayload::Raw),
+            _ => Err(Error::config(format!(
+                "Unknown payload '{}' (expected json, text or raw).",
+                s
+            ))),
+        }
+    }
+}
 
 #[async_trait]
 pub(crate) trait EmailDestination {
-    async fn write_email(&self, email: &Email<'_>) -> Result<(), Error>;
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error>;
+
+    /// Writes an email, that should not draw attention, e.g. an automatic reply. Destinations without a less
+    /// prominent form write it like any other email.
+    async fn write_notice(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        self.write_email(email).await
+    }
+
+    /// Returns the type of the destination like in the config file, e.g. "matrix".
+    fn kind(&self) -> &'static str;
+
+    /// Returns a short human readable description of where emails are delivered to.
+    fn describe(&self) -> String;
+
+    /// Returns the local directory (or the mbox file) this destination writes emails to, if any.
+    fn storage_path(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Returns true, if the storage path is a maildir instead of a directory with files named like the message IDs.
+    fn is_maildir(&self) -> bool {
+        false
+    }
+
+    /// Returns true, if the storage path is an mbox file, that all emails are appended to.
+    fn is_mbox(&self) -> bool {
+        false
+    }
+
+    /// Removes what the destination keeps about emails from or to `address` outside of its storage path, e.g. rows of
+    /// a database or entries of indexes. `message_ids` are the IDs of the stored emails, that involve the address.
+    /// Returns a description of everything, that was removed.
+    async fn forget(&self, _address: &str, _message_ids: &[String]) -> Result<Vec<String>, Error> {
+        Ok(vec![])
+    }
 }
@@ -0,0 +1,407 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use rustls::ServerName;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use std::str::FromStr;
+
+use super::{tls_connector, BrokerPayload, EmailDestination, Receipt};
+use crate::email::Email;
+use crate::error::{BrokerErrorCode, Error};
+
+/// The keep alive interval sent to the broker. Connections only live for one email, so it is never needed.
+const KEEP_ALIVE_SECS: u16 = 60;
+/// The largest packet, that MQTT can encode (the remaining length has at most four bytes).
+const MAX_PACKET_SIZE: usize = 268_435_455;
+
+/// A bidirectional byte stream to an MQTT broker.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Where the broker listens, given as URL like `mqtt://broker:1883` or `mqtts://broker:8883`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct MqttAddress {
+    host: String,
+    port: u16,
+    tls: bool,
+}
+
+impl FromStr for MqttAddress {
+    type Err = Error;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            Error::config(format!(
+                "Invalid MQTT URL '{}' (expected \"mqtt://host[:port]\" or \"mqtts://host[:port]\").",
+                url
+            ))
+        };
+        let (tls, rest) = match url.split_once("://") {
+            Some(("mqtt", rest)) => (false, rest),
+            Some(("mqtts", rest)) => (true, rest),
+            _ => return Err(invalid()),
+        };
+        let rest = rest.trim_end_matches('/');
+        let default_port = if tls { 8883 } else { 1883 };
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            }
+            _ => (rest, default_port),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid());
+        }
+        Ok(MqttAddress {
+            host: host.to_string(),
+            port,
+            tls,
+        })
+    }
+}
+
+impl std::fmt::Display for MqttAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = if self.tls { "mqtts" } else { "mqtt" };
+        if self.host.contains(':') {
+            write!(f, "{}://[{}]:{}", scheme, self.host, self.port)
+        } else {
+            write!(f, "{}://{}:{}", scheme, self.host, self.port)
+        }
+    }
+}
+
+/// Publishes every email to a topic of an MQTT broker (MQTT 3.1.1), e.g. for home automation.
+///
+/// Every email is published on a new connection, that is closed afterwards. With QoS 1 (the default), the delivery
+/// only succeeds, after the broker acknowledged the message.
+pub(crate) struct MqttDestination {
+    address: MqttAddress,
+    topic: String,
+    payload: BrokerPayload,
+    client_id: String,
+    credentials: Option<(String, String)>,
+    qos: u8,
+    retain: bool,
+    tls_connector: TlsConnector,
+}
+
+impl MqttDestination {
+    pub(crate) fn new(address: MqttAddress, topic: impl Into<String>) -> Self {
+        MqttDestination {
+            address,
+            topic: topic.into(),
+            payload: BrokerPayload::Json,
+            client_id: "kutsche".to_string(),
+            credentials: None,
+            qos: 1,
+            retain: false,
+            tls_connector: tls_connector(),
+        }
+    }
+
+    pub(crate) fn set_payload(&mut self, payload: BrokerPayload) {
+        self.payload = payload;
+    }
+
+    /// Sets the client identifier, that the broker knows this client by. It must be unique among its clients.
+    pub(crate) fn set_client_id(&mut self, client_id: impl Into<String>) {
+        self.client_id = client_id.into();
+    }
+
+    pub(crate) fn set_credentials(
+        &mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) {
+        self.credentials = Some((username.into(), password.into()));
+    }
+
+    /// Sets the quality of service: 0 (at most once) or 1 (at least once).
+    pub(crate) fn set_qos(&mut self, qos: u8) -> Result<(), Error> {
+        if qos > 1 {
+            return Err(Error::config("MQTT destinations only support QoS 0 and 1."));
+        }
+        self.qos = qos;
+        Ok(())
+    }
+
+    /// Lets the broker keep the last email for clients, that subscribe to the topic later.
+    pub(crate) fn set_retain(&mut self, retain: bool) {
+        self.retain = retain;
+    }
+
+    async fn connect(&self) -> Result<MqttConnection, Error> {
+        let unavailable = |e: std::io::Error| {
+            Error::broker(
+                BrokerErrorCode::Unavailable,
+                format!("Could not connect to MQTT broker {}: {}", self.address, e),
+            )
+        };
+        let tcp_stream = TcpStream::connect((self.address.host.as_str(), self.address.port))
+            .await
+            .map_err(unavailable)?;
+        let stream: Box<dyn Stream> = if self.address.tls {
+            let server_name = ServerName::try_from(self.address.host.as_str()).map_err(|_| {
+                Error::config(format!(
+                    "MQTT broker {} is not a valid TLS server name.",
+                    self.address.host
+                ))
+            })?;
+            Box::new(
+                self.tls_connector
+                    .connect(server_name, tcp_stream)
+                    .await
+                    .map_err(unavailable)?,
+            )
+        } else {
+            Box::new(tcp_stream)
+        };
+        let mut conn = MqttConnection {
+            stream: BufStream::new(stream),
+        };
+        conn.send_connect(&self.client_id, self.credentials.as_ref())
+            .await?;
+        Ok(conn)
+    }
+}
+
+#[async_trait]
+impl EmailDestination for MqttDestination {
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        let payload = self.payload.encode(email);
+        let mut conn = self.connect().await?;
+        conn.publish(&self.topic, &payload, self.qos, self.retain)
+            .await?;
+        if let Err(e) = conn.disconnect().await {
+            warn!(
+                "Could not close connection to MQTT broker {}: {}",
+                self.address, e
+            );
+        }
+        info!(
+            "Published email with id {} to MQTT topic {}.",
+            email.message_id, self.topic
+        );
+        Ok(Receipt::new(format!("{}/{}", self.address, self.topic)))
+    }
+
+    fn kind(&self) -> &'static str {
+        "mqtt"
+    }
+
+    fn describe(&self) -> String {
+        format!("MQTT topic {} at {}", self.topic, self.address)
+    }
+}
+
+/// The client side of a connection to an MQTT broker.
+struct MqttConnection {
+    stream: BufStream<Box<dyn Stream>>,
+}
+
+impl MqttConnection {
+    /// Sends CONNECT and waits for the CONNACK of the broker.
+    async fn send_connect(
+        &mut self,
+        client_id: &str,
+        credentials: Option<&(String, String)>,
+    ) -> Result<(), Error> {
+        // Clean session, since no state is kept between connections:
+        let mut flags = 0x02;
+        let mut body = vec![];
+        put_string(&mut body, "MQTT");
+        body.push(4); // protocol level of MQTT 3.1.1
+        let flags_index = body.len();
+        body.push(0);
+        body.extend_from_slice(&KEEP_ALIVE_SECS.to_be_bytes());
+        put_string(&mut body, client_id);
+        if let Some((username, password)) = credentials {
+            flags |= 0xC0;
+            put_string(&mut body, username);
+            put_string(&mut body, password);
+        }
+        body[flags_index] = flags;
+        self.write_packet(0x10, &body).await?;
+
+        let (packet_type, body) = self.read_packet().await?;
+        if packet_type != 0x20 || body.len() != 2 {
+            return Err(protocol_error("Expected CONNACK from MQTT broker."));
+        }
+        match body[1] {
+            0 => Ok(()),
+            code @ (4 | 5) => Err(Error::broker(
+                BrokerErrorCode::Auth,
+                format!(
+                    "MQTT broker refused the credentials (return code {}).",
+                    code
+                ),
+            )),
+            3 => Err(Error::broker(
+                BrokerErrorCode::Unavailable,
+                "MQTT broker is unavailable (return code 3).",
+            )),
+            code => Err(protocol_error(format!(
+                "MQTT broker refused the connection (return code {}).",
+                code
+            ))),
+        }
+    }
+
+    /// Publishes `payload` and waits for the PUBACK of the broker, if `qos` is 1.
+    async fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: u8,
+        retain: bool,
+    ) -> Result<(), Error> {
+        // Only one message is published per connection, so the packet identifier is always the same:
+        let packet_id: u16 = 1;
+        let mut body = vec![];
+        put_string(&mut body, topic);
+        if qos > 0 {
+            body.extend_from_slice(&packet_id.to_be_bytes());
+        }
+        body.extend_from_slice(payload);
+        self.write_packet(0x30 | (qos << 1) | u8::from(retain), &body)
+            .await?;
+        if qos == 0 {
+            return Ok(());
+        }
+        let (packet_type, body) = self.read_packet().await?;
+        if packet_type != 0x40 || body != packet_id.to_be_bytes() {
+            return Err(protocol_error("Expected PUBACK from MQTT broker."));
+        }
+        Ok(())
+    }
+
+    async fn disconnect(mut self) -> Result<(), Error> {
+        self.write_packet(0xE0, &[]).await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    async fn write_packet(&mut self, header: u8, body: &[u8]) -> Result<(), Error> {
+        if body.len() > MAX_PACKET_SIZE {
+            return Err(protocol_error("The email is too large for an MQTT packet."));
+        }
+        self.stream.write_u8(header).await?;
+        let mut len = body.len();
+        loop {
+            let mut byte = (len % 128) as u8;
+            len /= 128;
+            if len > 0 {
+                byte |= 0x80;
+            }
+            self.stream.write_u8(byte).await?;
+            if len == 0 {
+                break;
+            }
+        }
+        self.stream.write_all(body).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+
+    /// Reads a packet and returns its type (the upper four bits of the first byte) and its body.
+    async fn read_packet(&mut self) -> Result<(u8, Vec<u8>), Error> {
+        let header = self.stream.read_u8().await?;
+        let mut len = 0;
+        for i in 0..4 {
+            let byte = self.stream.read_u8().await?;
+            len += usize::from(byte & 0x7F) << (7 * i);
+            if byte & 0x80 == 0 {
+                let mut body = vec![0; len];
+                self.stream.read_exact(&mut body).await?;
+                return Ok((header & 0xF0, body));
+            }
+        }
+        Err(protocol_error("Invalid packet length from MQTT broker."))
+    }
+}
+
+fn put_string(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+fn protocol_error(desc: impl Into<String>) -> Error {
+    Error::broker(BrokerErrorCode::Protocol, desc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_address() {
+        let address: MqttAddress = "mqtts://broker.example.com".parse().unwrap();
+        assert_eq!(address.to_string(), "mqtts://broker.example.com:8883");
+        let address: MqttAddress = "mqtt://[::1]:1884/".parse().unwrap();
+        assert_eq!(address.to_string(), "mqtt://[::1]:1884");
+        assert!("http://broker.example.com".parse::<MqttAddress>().is_err());
+        assert!("mqtt://:1883".parse::<MqttAddress>().is_err());
+    }
+
+    /// Accepts one connection, answers CONNECT and PUBLISH with `connack` and PUBACK and returns all received packets.
+    async fn fake_broker(listener: TcpListener, connack: u8) -> Vec<(u8, Vec<u8>)> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut conn = MqttConnection {
+            stream: BufStream::new(Box::new(stream) as Box<dyn Stream>),
+        };
+        let mut packets = vec![];
+        while let Ok((header, body)) = conn.read_packet().await {
+            match header {
+                0x10 => conn.write_packet(0x20, &[0, connack]).await.unwrap(),
+                0x30 => conn.write_packet(0x40, &[0, 1]).await.unwrap(),
+                _ => {}
+            }
+            packets.push((header, body));
+        }
+        packets
+    }
+
+    #[tokio::test]
+    async fn test_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("mqtt://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let broker = tokio::spawn(fake_broker(listener, 0));
+
+        let mut destination = MqttDestination::new(address.parse().unwrap(), "home/mail");
+        destination.set_credentials("kutsche", "s3cr3t");
+        destination.set_payload(BrokerPayload::Text);
+        let email = Email::parse(
+            b"Message-ID: <door@example.com>\r\nSubject: Doorbell\r\n\r\nSomebody rang.\r\n",
+        )
+        .unwrap();
+        destination.write_email(&email).await.unwrap();
+
+        let packets = broker.await.unwrap();
+        assert_eq!(packets.len(), 3);
+        let (_, connect) = &packets[0];
+        assert_eq!(connect[7], 0xC2);
+        assert!(connect.ends_with(b"\x00\x07kutsche\x00\x07kutsche\x00\x06s3cr3t"));
+        let (_, publish) = &packets[1];
+        assert_eq!(publish, b"\x00\x09home/mail\x00\x01Somebody rang.\r\n");
+        assert_eq!(packets[2].0, 0xE0);
+    }
+
+    #[tokio::test]
+    async fn test_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("mqtt://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        tokio::spawn(fake_broker(listener, 5));
+
+        let destination = MqttDestination::new(address.parse().unwrap(), "home/mail");
+        let email = Email::parse(b"Message-ID: <door@example.com>\r\n\r\n").unwrap();
+        let e = destination.write_email(&email).await.unwrap_err();
+        assert_eq!(e.code(), "broker.auth");
+        assert!(!e.is_temporary());
+    }
+}
@@ -0,0 +1,579 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use rustls::ServerName;
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use super::{tls_connector, BrokerPayload, EmailDestination, Receipt};
+use crate::email::Email;
+use crate::error::{BrokerErrorCode, Error};
+
+/// The default time, after which a delivery is given up, if the server did not acknowledge the message.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// The longest line of the protocol, that is accepted from the server.
+const MAX_LINE_LENGTH: u64 = 64 * 1024;
+
+/// The number of messages published by this process, which makes the subjects of their replies unique.
+static PUBLISHED: AtomicU64 = AtomicU64::new(0);
+
+/// A bidirectional byte stream to a NATS server.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> Stream for S {}
+
+/// Where the server listens, given as URL like `nats://nats:4222` or `tls://nats:4222`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct NatsAddress {
+    host: String,
+    port: u16,
+    tls: bool,
+}
+
+impl FromStr for NatsAddress {
+    type Err = Error;
+
+    fn from_str(url: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            Error::config(format!(
+                "Invalid NATS URL '{}' (expected \"nats://host[:port]\" or \"tls://host[:port]\").",
+                url
+            ))
+        };
+        let (tls, rest) = match url.split_once("://") {
+            Some(("nats", rest)) => (false, rest),
+            Some(("tls", rest)) => (true, rest),
+            _ => return Err(invalid()),
+        };
+        let rest = rest.trim_end_matches('/');
+        let (host, port) = match rest.rsplit_once(':') {
+            Some((host, port)) if !port.contains(']') => {
+                (host, port.parse().map_err(|_| invalid())?)
+            }
+            _ => (rest, 4222),
+        };
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid());
+        }
+        Ok(NatsAddress {
+            host: host.to_string(),
+            port,
+            tls,
+        })
+    }
+}
+
+impl std::fmt::Display for NatsAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let scheme = if self.tls { "tls" } else { "nats" };
+        if self.host.contains(':') {
+            write!(f, "{}://[{}]:{}", scheme, self.host, self.port)
+        } else {
+            write!(f, "{}://{}:{}", scheme, self.host, self.port)
+        }
+    }
+}
+
+/// How the destination authenticates to the server.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum NatsAuth {
+    None,
+    Credentials(String, String),
+    Token(String),
+}
+
+/// Publishes every email to a subject of a NATS server.
+///
+/// By default, the subject must belong to a JetStream stream: The delivery only succeeds, after the stream stored the
+/// message, and the message ID of the email is sent as `Nats-Msg-Id`, so the stream drops duplicates of emails, that
+/// are delivered again after a lost acknowledgement. Without JetStream, the delivery succeeds, as soon as the server
+/// received the message, which is lost, if no subscriber is connected.
+///
+/// Every email is published on a new connection, that is closed afterwards.
+pub(crate) struct NatsDestination {
+    address: NatsAddress,
+    subject: String,
+    payload: BrokerPayload,
+    auth: NatsAuth,
+    jetstream: bool,
+    timeout: Duration,
+    tls_connector: TlsConnector,
+}
+
+impl NatsDestination {
+    pub(crate) fn new(address: NatsAddress, subject: impl Into<String>) -> Self {
+        NatsDestination {
+            address,
+            subject: subject.into(),
+            payload: BrokerPayload::Json,
+            auth: NatsAuth::None,
+            jetstream: true,
+            timeout: DEFAULT_TIMEOUT,
+            tls_connector: tls_connector(),
+        }
+    }
+
+    pub(crate) fn set_payload(&mut self, payload: BrokerPayload) {
+        self.payload = payload;
+    }
+
+    pub(crate) fn set_credentials(
+        &mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) {
+        self.auth = NatsAuth::Credentials(username.into(), password.into());
+    }
+
+    pub(crate) fn set_token(&mut self, token: impl Into<String>) {
+        self.auth = NatsAuth::Token(token.into());
+    }
+
+    /// Sets whether the subject belongs to a JetStream stream, that acknowledges stored messages.
+    pub(crate) fn set_jetstream(&mut self, jetstream: bool) {
+        self.jetstream = jetstream;
+    }
+
+    /// Sets the time, after which a delivery is given up.
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    async fn connect(&self) -> Result<NatsConnection, Error> {
+        let unavailable = |e: std::io::Error| {
+            Error::broker(
+                BrokerErrorCode::Unavailable,
+                format!("Could not connect to NATS server {}: {}", self.address, e),
+            )
+        };
+        let tcp_stream = TcpStream::connect((self.address.host.as_str(), self.address.port))
+            .await
+            .map_err(unavailable)?;
+        // The server sends its INFO before the connection is upgraded to TLS:
+        let mut conn = NatsConnection {
+            stream: BufStream::new(Box::new(tcp_stream)),
+            max_payload: None,
+        };
+        let info = conn.read_info().await?;
+        let tls_required = info
+            .get("tls_required")
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        if self.address.tls || tls_required {
+            let server_name = ServerName::try_from(self.address.host.as_str()).map_err(|_| {
+                Error::config(format!(
+                    "NATS server {} is not a valid TLS server name.",
+                    self.address.host
+                ))
+            })?;
+            let tcp_stream = conn.stream.into_inner();
+            conn.stream = BufStream::new(Box::new(
+                self.tls_connector
+                    .connect(server_name, tcp_stream)
+                    .await
+                    .map_err(unavailable)?,
+            ));
+        }
+        conn.max_payload = info.get("max_payload").and_then(Value::as_u64);
+        conn.send_connect(&self.auth, self.address.tls || tls_required)
+            .await?;
+        Ok(conn)
+    }
+
+    /// Publishes the payload and returns the reference of the receipt.
+    async fn publish(&self, email: &Email<'_>, payload: &[u8]) -> Result<String, Error> {
+        let mut conn = self.connect().await?;
+        let reference = if self.jetstream {
+            let (stream, seq) = conn
+                .publish_jetstream(&self.subject, &email.message_id, payload)
+                .await?;
+            format!(
+                "{}/{}: stream {} seq {}",
+                self.address, self.subject, stream, seq
+            )
+        } else {
+            conn.publish(&self.subject, payload).await?;
+            format!("{}/{}", self.address, self.subject)
+        };
+        if let Err(e) = conn.stream.shutdown().await {
+            warn!(
+                "Could not close connection to NATS server {}: {}",
+                self.address, e
+            );
+        }
+        Ok(reference)
+    }
+}
+
+#[async_trait]
+impl EmailDestination for NatsDestination {
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        let payload = self.payload.encode(email);
+        let reference = tokio::time::timeout(self.timeout, self.publish(email, &payload))
+            .await
+            .map_err(|_| {
+                Error::broker(
+                    BrokerErrorCode::Unavailable,
+                    format!(
+                        "NATS server {} did not acknowledge the message in time.",
+                        self.address
+                    ),
+                )
+            })??;
+        info!(
+            "Published email with id {} to NATS subject {}.",
+            email.message_id, self.subject
+        );
+        Ok(Receipt::new(reference))
+    }
+
+    fn kind(&self) -> &'static str {
+        "nats"
+    }
+
+    fn describe(&self) -> String {
+        format!("NATS subject {} at {}", self.subject, self.address)
+    }
+}
+
+/// The client side of a connection to a NATS server.
+struct NatsConnection {
+    stream: BufStream<Box<dyn Stream>>,
+    /// The size of the largest message, that the server accepts.
+    max_payload: Option<u64>,
+}
+
+impl NatsConnection {
+    /// Reads the INFO, that the server sends after accepting the connection, and returns its JSON object.
+    async fn read_info(&mut self) -> Result<Value, Error> {
+        let line = self.read_line().await?;
+        line.strip_prefix("INFO ")
+            .and_then(|info| serde_json::from_str(info).ok())
+            .ok_or_else(|| protocol_error("Expected INFO from NATS server."))
+    }
+
+    /// Sends CONNECT and waits for the reply to a PING, so refused credentials are noticed.
+    async fn send_connect(&mut self, auth: &NatsAuth, tls: bool) -> Result<(), Error> {
+        let mut options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "tls_required": tls,
+            "name": "kutsche",
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "protocol": 1,
+            "headers": true,
+            "no_responders": true,
+        });
+        match auth {
+            NatsAuth::None => {}
+            NatsAuth::Credentials(username, password) => {
+                options["user"] = json!(username);
+                options["pass"] = json!(password);
+            }
+            NatsAuth::Token(token) => options["auth_token"] = json!(token),
+        }
+        self.write(format!("CONNECT {}\r\nPING\r\n", options).as_bytes())
+            .await?;
+        self.read_pong().await
+    }
+
+    /// Publishes `payload` and waits until the server processed it.
+    async fn publish(&mut self, subject: &str, payload: &[u8]) -> Result<(), Error> {
+        self.check_size(payload.len())?;
+        let mut command = format!("PUB {} {}\r\n", subject, payload.len()).into_bytes();
+        command.extend_from_slice(payload);
+        command.extend_from_slice(b"\r\nPING\r\n");
+        self.write(&command).await?;
+        self.read_pong().await
+    }
+
+    /// Publishes `payload` to a subject of a JetStream stream and returns the name of the stream and the sequence
+    /// number of the message, after the stream acknowledged it.
+    async fn publish_jetstream(
+        &mut self,
+        subject: &str,
+        message_id: &str,
+        payload: &[u8],
+    ) -> Result<(String, u64), Error> {
+        let inbox = format!(
+            "_INBOX.kutsche.{}.{}",
+            std::process::id(),
+            PUBLISHED.fetch_add(1, Ordering::Relaxed)
+        );
+        // Line breaks would end the header:
+        let message_id: String = message_id.chars().filter(|c| !c.is_control()).collect();
+        let headers = format!("NATS/1.0\r\nNats-Msg-Id: {}\r\n\r\n", message_id);
+        self.check_size(headers.len() + payload.len())?;
+        let mut command = format!(
+            "SUB {} 1\r\nHPUB {} {} {} {}\r\n{}",
+            inbox,
+            subject,
+            inbox,
+            headers.len(),
+            headers.len() + payload.len(),
+            headers
+        )
+        .into_bytes();
+        command.extend_from_slice(payload);
+        command.extend_from_slice(b"\r\n");
+        self.write(&command).await?;
+
+        let (headers, reply) = self.read_msg().await?;
+        // With no_responders, the server replies with status 503, if no stream listens on the subject:
+        if headers.starts_with("NATS/1.0 503") {
+            return Err(Error::broker(
+                BrokerErrorCode::Unavailable,
+                format!(
+                    "No JetStream stream stores messages of subject {}.",
+                    subject
+                ),
+            ));
+        }
+        let ack: Value = serde_json::from_slice(&reply)
+            .map_err(|_| protocol_error("Invalid acknowledgement from JetStream."))?;
+        if let Some(error) = ack.get("error") {
+            return Err(protocol_error(format!(
+                "JetStream refused the message: {}",
+                error
+                    .get("description")
+                    .and_then(Value::as_str)
+                    .unwrap_or("unknown error")
+            )));
+        }
+        match (
+            ack.get("stream").and_then(Value::as_str),
+            ack.get("seq").and_then(Value::as_u64),
+        ) {
+            (Some(stream), Some(seq)) => Ok((stream.to_string(), seq)),
+            _ => Err(protocol_error("Invalid acknowledgement from JetStream.")),
+        }
+    }
+
+    fn check_size(&self, size: usize) -> Result<(), Error> {
+        match self.max_payload {
+            Some(max_payload) if size as u64 > max_payload => Err(protocol_error(format!(
+                "The message of {} bytes is larger than the {} bytes, that the NATS server accepts.",
+                size, max_payload
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits for the PONG, that answers a PING.
+    async fn read_pong(&mut self) -> Result<(), Error> {
+        match self.read_op().await?.as_str() {
+            "PONG" => Ok(()),
+            line => Err(protocol_error(format!(
+                "Unexpected reply from NATS server: {}",
+                line
+            ))),
+        }
+    }
+
+    /// Reads the next MSG or HMSG and returns its headers and its payload.
+    async fn read_msg(&mut self) -> Result<(String, Vec<u8>), Error> {
+        let line = self.read_op().await?;
+        let args: Vec<&str> = line.split_whitespace().collect();
+        // MSG <subject> <sid> [reply-to] <#bytes> or HMSG <subject> <sid> [reply-to] <#header bytes> <#total bytes>:
+        let (header_len, total_len) = match args.as_slice() {
+            ["MSG", _, _, .., total] => (Some("0"), *total),
+            ["HMSG", _, _, .., header, total] => (Some(*header), *total),
+            _ => (None, ""),
+        };
+        let (header_len, total_len) = match (
+            header_len.and_then(|len| len.parse::<usize>().ok()),
+            total_len.parse::<usize>().ok(),
+        ) {
+            (Some(header_len), Some(total_len)) if header_len <= total_len => {
+                (header_len, total_len)
+            }
+            _ => {
+                return Err(protocol_error(format!(
+                    "Unexpected message from NATS server: {}",
+                    line
+                )))
+            }
+        };
+        let mut msg = vec![0; total_len + 2];
+        self.stream.read_exact(&mut msg).await?;
+        msg.truncate(total_len);
+        let payload = msg.split_off(header_len);
+        Ok((String::from_utf8_lossy(&msg).into_owned(), payload))
+    }
+
+    /// Reads the next protocol line, that is no PING or +OK, and answers PINGs of the server. Errors of the server are
+    /// returned as error.
+    async fn read_op(&mut self) -> Result<String, Error> {
+        loop {
+            let line = self.read_line().await?;
+            match line.as_str() {
+                "PING" => self.write(b"PONG\r\n").await?,
+                "+OK" => {}
+                _ => {
+                    if let Some(e) = line.strip_prefix("-ERR ") {
+                        let e = e.trim_matches('\'');
+                        let code = if e.to_ascii_lowercase().contains("authorization") {
+                            BrokerErrorCode::Auth
+                        } else {
+                            BrokerErrorCode::Protocol
+                        };
+                        return Err(Error::broker(
+                            code,
+                            format!("NATS server reported an error: {}", e),
+                        ));
+                    }
+                    return Ok(line);
+                }
+            }
+        }
+    }
+
+    async fn read_line(&mut self) -> Result<String, Error> {
+        let mut line = vec![];
+        (&mut self.stream)
+            .take(MAX_LINE_LENGTH)
+            .read_until(b'\n', &mut line)
+            .await?;
+        if !line.ends_with(b"\r\n") {
+            return Err(protocol_error(
+                "Connection to NATS server closed unexpectedly.",
+            ));
+        }
+        line.truncate(line.len() - 2);
+        Ok(String::from_utf8_lossy(&line).into_owned())
+    }
+
+    async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.stream.write_all(data).await?;
+        self.stream.flush().await?;
+        Ok(())
+    }
+}
+
+fn protocol_error(desc: impl Into<String>) -> Error {
+    Error::broker(BrokerErrorCode::Protocol, desc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::BufReader;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_address() {
+        let address: NatsAddress = "tls://nats.example.com".parse().unwrap();
+        assert_eq!(address.to_string(), "tls://nats.example.com:4222");
+        let address: NatsAddress = "nats://[::1]:4223/".parse().unwrap();
+        assert_eq!(address.to_string(), "nats://[::1]:4223");
+        assert!("mqtt://nats.example.com".parse::<NatsAddress>().is_err());
+        assert!("nats://:4222".parse::<NatsAddress>().is_err());
+    }
+
+    /// Accepts one connection, answers PINGs with `pong` and HPUBs with `ack` and returns the received lines (and
+    /// messages of HPUBs).
+    async fn fake_server(listener: TcpListener, pong: &'static str, ack: String) -> Vec<String> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        stream
+            .write_all(b"INFO {\"server_id\":\"test\",\"max_payload\":1048576}\r\n")
+            .await
+            .unwrap();
+        let mut lines = vec![];
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+                break;
+            }
+            let line = line.trim_end().to_string();
+            if line == "PING" {
+                stream.write_all(pong.as_bytes()).await.unwrap();
+            } else if line.starts_with("HPUB ") {
+                let total: usize = line.rsplit(' ').next().unwrap().parse().unwrap();
+                let mut msg = vec![0; total + 2];
+                stream.read_exact(&mut msg).await.unwrap();
+                lines.push(line);
+                lines.push(String::from_utf8(msg).unwrap());
+                stream.write_all(ack.as_bytes()).await.unwrap();
+                continue;
+            }
+            lines.push(line);
+        }
+        lines
+    }
+
+    #[tokio::test]
+    async fn test_publish_jetstream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("nats://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let ack = "{\"stream\":\"MAIL\",\"seq\":7}";
+        // The server may ping the client at any time:
+        let ack = format!("PING\r\nMSG _INBOX 1 {}\r\n{}\r\n", ack.len(), ack);
+        let broker = tokio::spawn(fake_server(listener, "PONG\r\n", ack));
+
+        let mut destination = NatsDestination::new(address.parse().unwrap(), "mail.alerts");
+        destination.set_token("s3cr3t");
+        destination.set_payload(BrokerPayload::Text);
+        let email = Email::parse(
+            b"Message-ID: <disk@example.com>\r\nSubject: Disk full\r\n\r\n/var is full.\r\n",
+        )
+        .unwrap();
+        let receipt = destination.write_email(&email).await.unwrap();
+        assert_eq!(
+            receipt.reference.unwrap(),
+            format!("{}/mail.alerts: stream MAIL seq 7", address)
+        );
+
+        let lines = broker.await.unwrap();
+        assert!(lines[0].starts_with("CONNECT {"));
+        assert!(lines[0].contains("\"auth_token\":\"s3cr3t\""));
+        assert_eq!(lines[1], "PING");
+        assert!(lines[2].starts_with("SUB _INBOX.kutsche."));
+        assert!(lines[3].starts_with("HPUB mail.alerts _INBOX.kutsche."));
+        assert!(lines[3].ends_with(" 43 58"));
+        assert_eq!(
+            lines[4],
+            "NATS/1.0\r\nNats-Msg-Id: disk@example.com\r\n\r\n/var is full.\r\n\r\n"
+        );
+        assert_eq!(lines[5], "PONG");
+    }
+
+    #[tokio::test]
+    async fn test_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("nats://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        tokio::spawn(fake_server(
+            listener,
+            "-ERR 'Authorization Violation'\r\n",
+            String::new(),
+        ));
+
+        let mut destination = NatsDestination::new(address.parse().unwrap(), "mail.alerts");
+        destination.set_credentials("kutsche", "wrong");
+        let email = Email::parse(b"Message-ID: <disk@example.com>\r\n\r\n").unwrap();
+        let e = destination.write_email(&email).await.unwrap_err();
+        assert_eq!(e.code(), "broker.auth");
+        assert!(!e.is_temporary());
+    }
+
+    #[tokio::test]
+    async fn test_no_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("nats://127.0.0.1:{}", listener.local_addr().unwrap().port());
+        let ack = "HMSG _INBOX 1 16 16\r\nNATS/1.0 503\r\n\r\n\r\n".to_string();
+        tokio::spawn(fake_server(listener, "PONG\r\n", ack));
+
+        let destination = NatsDestination::new(address.parse().unwrap(), "mail.alerts");
+        let email = Email::parse(b"Message-ID: <disk@example.com>\r\n\r\n").unwrap();
+        let e = destination.write_email(&email).await.unwrap_err();
+        assert_eq!(e.code(), "broker.unavailable");
+        assert!(e.is_temporary());
+    }
+}
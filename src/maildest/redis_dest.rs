@@ -0,0 +1,253 @@
+use async_trait::async_trait;
+use log::info;
+
+use std::str::FromStr;
+
+use super::{BrokerPayload, EmailDestination, Receipt};
+use crate::email::Email;
+use crate::error::{DatabaseErrorCode, Error};
+use crate::redis::{RedisClient, Reply};
+
+/// The data structure, that emails are added to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum RedisMode {
+    /// The payload is appended to a list with RPUSH, so consumers can take the oldest one with BLPOP.
+    List,
+    /// An entry with the fields "message_id" and "payload" is appended to a stream with XADD, so consumer groups can
+    /// read it.
+    Stream,
+}
+
+impl FromStr for RedisMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "list" => Ok(RedisMode::List),
+            "stream" => Ok(RedisMode::Stream),
+            _ => Err(Error::config(format!(
+                "Unknown Redis mode '{}' (expected list or stream).",
+                s
+            ))),
+        }
+    }
+}
+
+/// Pushes every email onto a Redis list or stream, so lightweight consumers can pop them.
+///
+/// With a maximal length, the oldest entries are removed, when it is exceeded. Streams are trimmed approximately
+/// (`MAXLEN ~`), which lets Redis remove whole nodes at once, so they may hold a few more entries.
+pub(crate) struct RedisDestination {
+    client: RedisClient,
+    key: String,
+    mode: RedisMode,
+    payload: BrokerPayload,
+    max_len: Option<usize>,
+}
+
+impl RedisDestination {
+    pub(crate) fn new(client: RedisClient, key: impl Into<String>) -> Self {
+        RedisDestination {
+            client,
+            key: key.into(),
+            mode: RedisMode::List,
+            payload: BrokerPayload::Json,
+            max_len: None,
+        }
+    }
+
+    pub(crate) fn set_mode(&mut self, mode: RedisMode) {
+        self.mode = mode;
+    }
+
+    pub(crate) fn set_payload(&mut self, payload: BrokerPayload) {
+        self.payload = payload;
+    }
+
+    /// Sets the number of entries, that the list or stream keeps at most.
+    pub(crate) fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = Some(max_len);
+    }
+
+    fn unexpected_reply(&self, command: &str) -> Error {
+        Error::database(
+            DatabaseErrorCode::Query,
+            format!(
+                "Unexpected reply of Redis server {} to {}.",
+                self.client.address(),
+                command
+            ),
+        )
+    }
+}
+
+#[async_trait]
+impl EmailDestination for RedisDestination {
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        let payload = self.payload.encode(email);
+        let reference = match self.mode {
+            RedisMode::List => {
+                let len = match self
+                    .client
+                    .query(&[b"RPUSH", self.key.as_bytes(), &payload])
+                    .await?
+                {
+                    Reply::Integer(len) => len,
+                    _ => return Err(self.unexpected_reply("RPUSH")),
+                };
+                if let Some(max_len) = self.max_len {
+                    if len > max_len as i64 {
+                        self.client
+                            .query(&[
+                                b"LTRIM",
+                                self.key.as_bytes(),
+                                format!("-{}", max_len).as_bytes(),
+                                b"-1",
+                            ])
+                            .await?;
+                    }
+                }
+                format!("{} item {}", self.key, len)
+            }
+            RedisMode::Stream => {
+                let max_len = self.max_len.map(|max_len| max_len.to_string());
+                let mut args: Vec<&[u8]> = vec![b"XADD", self.key.as_bytes()];
+                if let Some(ref max_len) = max_len {
+                    args.extend_from_slice(&[b"MAXLEN", b"~", max_len.as_bytes()]);
+                }
+                args.extend_from_slice(&[
+                    b"*",
+                    b"message_id",
+                    email.message_id.as_bytes(),
+                    b"payload",
+                    &payload,
+                ]);
+                match self.client.query(&args).await? {
+                    Reply::Bulk(Some(id)) => {
+                        format!("{} entry {}", self.key, String::from_utf8_lossy(&id))
+                    }
+                    _ => return Err(self.unexpected_reply("XADD")),
+                }
+            }
+        };
+        info!(
+            "Pushed email with id {} to Redis key '{}'.",
+            email.message_id, self.key
+        );
+        Ok(Receipt::new(reference))
+    }
+
+    fn kind(&self) -> &'static str {
+        "redis"
+    }
+
+    fn describe(&self) -> String {
+        let kind = match self.mode {
+            RedisMode::List => "list",
+            RedisMode::Stream => "stream",
+        };
+        format!("Redis {} '{}' at {}", kind, self.key, self.client.address())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufStream};
+    use tokio::net::TcpListener;
+
+    const EMAIL: &[u8] = b"Message-ID: <redis@example.com>\r\nSubject: Disk full\r\n\r\nHi\r\n";
+
+    /// Answers the commands of one connection with the given replies and returns the received commands.
+    async fn fake_server(listener: TcpListener, replies: &[&str]) -> Vec<Vec<String>> {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufStream::new(stream);
+        let mut commands = vec![];
+        for reply in replies {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let count: usize = line[1..].trim().parse().unwrap();
+            let mut command = vec![];
+            for _ in 0..count {
+                line.clear();
+                stream.read_line(&mut line).await.unwrap();
+                let len: usize = line[1..].trim().parse().unwrap();
+                let mut arg = vec![0; len + 2];
+                stream.read_exact(&mut arg).await.unwrap();
+                arg.truncate(len);
+                command.push(String::from_utf8(arg).unwrap());
+            }
+            commands.push(command);
+            stream.write_all(reply.as_bytes()).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+        commands
+    }
+
+    async fn destination(
+        replies: &'static [&'static str],
+    ) -> (RedisDestination, tokio::task::JoinHandle<Vec<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!(
+            "redis://127.0.0.1:{}",
+            listener.local_addr().unwrap().port()
+        )
+        .parse()
+        .unwrap();
+        let server = tokio::spawn(fake_server(listener, replies));
+        (
+            RedisDestination::new(RedisClient::new(address), "mail"),
+            server,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let (mut destination, server) = destination(&[":3\r\n", "+OK\r\n"]).await;
+        destination.set_payload(BrokerPayload::Raw);
+        destination.set_max_len(2);
+        let email = Email::parse(EMAIL).unwrap();
+        let receipt = destination.write_email(&email).await.unwrap();
+        assert_eq!(receipt.reference.as_deref(), Some("mail item 3"));
+
+        let commands = server.await.unwrap();
+        assert_eq!(
+            commands[0],
+            ["RPUSH", "mail", std::str::from_utf8(EMAIL).unwrap()]
+        );
+        assert_eq!(commands[1], ["LTRIM", "mail", "-2", "-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let (mut destination, server) = destination(&["$15\r\n1700000000000-0\r\n"]).await;
+        destination.set_mode(RedisMode::Stream);
+        destination.set_max_len(1000);
+        let email = Email::parse(EMAIL).unwrap();
+        let receipt = destination.write_email(&email).await.unwrap();
+        assert_eq!(
+            receipt.reference.as_deref(),
+            Some("mail entry 1700000000000-0")
+        );
+
+        let commands = server.await.unwrap();
+        assert_eq!(
+            commands[0][..7],
+            ["XADD", "mail", "MAXLEN", "~", "1000", "*", "message_id"]
+        );
+        assert_eq!(commands[0][7], "redis@example.com");
+        assert!(commands[0][9].contains("\"subject\":\"Disk full\""));
+    }
+
+    #[tokio::test]
+    async fn test_wrong_type() {
+        let (destination, _server) = destination(&[
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n",
+        ])
+        .await;
+        let email = Email::parse(EMAIL).unwrap();
+        let e = destination.write_email(&email).await.unwrap_err();
+        assert_eq!(e.code(), "database.query");
+        assert!(!e.is_temporary());
+    }
+}
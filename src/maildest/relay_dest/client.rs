@@ -0,0 +1,457 @@
+use log::debug;
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, ClientConfig, ServerName};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufStream};
+use tokio_rustls::TlsConnector;
+
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use crate::error::{Error, SmtpErrorCode};
+use crate::maildest::tls_connector;
+use crate::secret::Secret;
+
+/// A bidirectional byte stream, that can be used for SMTP connections, i.e. a TCP stream with or without TLS.
+pub(super) trait Stream: AsyncRead + AsyncWrite + Unpin + Send + Sync {}
+impl<S: AsyncRead + AsyncWrite + Unpin + Send + Sync> Stream for S {}
+
+/// When to upgrade connections to the relay with STARTTLS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum StartTls {
+    /// Never use STARTTLS.
+    Disabled,
+    /// Use STARTTLS, if the relay offers it. The certificate of the relay is not verified, because opportunistic TLS
+    /// only protects against passive eavesdropping (RFC 7435) and many MX hosts have no valid certificate for their
+    /// name.
+    Opportunistic,
+    /// Refuse to deliver emails, if the relay does not offer STARTTLS or has no valid certificate.
+    Required,
+}
+
+impl std::str::FromStr for StartTls {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(StartTls::Disabled),
+            "opportunistic" => Ok(StartTls::Opportunistic),
+            "required" => Ok(StartTls::Required),
+            other => Err(Error::config(format!(
+                "Unknown STARTTLS mode '{}' (expected 'disabled', 'opportunistic' or 'required').",
+                other
+            ))),
+        }
+    }
+}
+
+/// Everything needed to open an authenticated session with a relay.
+#[derive(Clone)]
+pub(super) struct SessionParams {
+    pub(super) helo_name: String,
+    pub(super) starttls: StartTls,
+    pub(super) credentials: Option<(String, Secret)>, // username, password
+    /// The connector for required STARTTLS, which verifies the certificate of the relay.
+    pub(super) tls_connector: TlsConnector,
+    /// The connector for opportunistic STARTTLS, which accepts any certificate.
+    pub(super) opportunistic_tls_connector: TlsConnector,
+}
+
+impl SessionParams {
+    pub(super) fn new() -> Self {
+        SessionParams {
+            helo_name: "localhost".to_string(),
+            starttls: StartTls::Opportunistic,
+            credentials: None,
+            tls_connector: tls_connector(),
+            opportunistic_tls_connector: unverified_tls_connector(),
+        }
+    }
+}
+
+/// Accepts the certificate of any server. The signatures of the handshake are still checked with its key.
+struct AnyCertificate;
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// Returns a connector for TLS connections, that don't verify the certificate of the server.
+fn unverified_tls_connector() -> TlsConnector {
+    TlsConnector::from(Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate))
+            .with_no_client_auth(),
+    ))
+}
+
+/// The client side of an SMTP connection.
+pub(super) struct SmtpConnection {
+    stream: BufStream<Box<dyn Stream>>,
+    /// Whether the connection was upgraded with STARTTLS and the certificate of the relay was verified.
+    verified: bool,
+    /// The extension keywords announced by the server in the last reply to EHLO.
+    extensions: Vec<String>,
+}
+
+impl SmtpConnection {
+    /// Performs the greeting, EHLO, STARTTLS (if configured) and AUTH (if configured) on a newly opened connection to
+    /// `host`. The returned connection is ready for the first mail transaction.
+    ///
+    /// The credentials are only sent over a connection with a verified certificate, so they can't be intercepted by
+    /// stripping STARTTLS from the EHLO reply or by presenting another certificate.
+    pub(super) async fn open(
+        stream: impl Stream + 'static,
+        host: &str,
+        params: &SessionParams,
+    ) -> Result<Self, Error> {
+        let mut conn = SmtpConnection {
+            stream: BufStream::new(Box::new(stream)),
+            verified: false,
+            extensions: vec![],
+        };
+
+        conn.expect_response(220).await?;
+        let mut extensions = conn.ehlo(&params.helo_name).await?;
+
+        let offers_starttls = extensions.iter().any(|ext| ext == "STARTTLS");
+        match params.starttls {
+            StartTls::Required if !offers_starttls => {
+                return Err(Error::smtp(
+                    SmtpErrorCode::MissingExtension,
+                    format!(
+                        "Relay {} does not offer STARTTLS, but it is required.",
+                        host
+                    ),
+                ));
+            }
+            StartTls::Required | StartTls::Opportunistic if offers_starttls => {
+                conn.command("STARTTLS", 220).await?;
+                let server_name =
+                    ServerName::try_from(host.trim_end_matches('.')).map_err(|_| {
+                        Error::smtp(
+                            SmtpErrorCode::Protocol,
+                            format!("Relay host {} is not a valid TLS server name.", host),
+                        )
+                    })?;
+                let connector = match params.starttls {
+                    StartTls::Required => &params.tls_connector,
+                    _ => &params.opportunistic_tls_connector,
+                };
+                let tcp_stream = conn.stream.into_inner();
+                let tls_stream = connector.connect(server_name, tcp_stream).await?;
+                conn = SmtpConnection {
+                    stream: BufStream::new(Box::new(tls_stream)),
+                    verified: params.starttls == StartTls::Required,
+                    extensions: vec![],
+                };
+                debug!("Upgraded connection to relay {} with STARTTLS.", host);
+                extensions = conn.ehlo(&params.helo_name).await?;
+            }
+            _ => {}
+        }
+
+        if let Some((ref username, ref password)) = params.credentials {
+            if !conn.verified {
+                return Err(Error::smtp(
+                    SmtpErrorCode::MissingExtension,
+                    format!(
+                        "Refusing to authenticate with relay {} over a connection without a verified certificate.",
+                        host
+                    ),
+                ));
+            }
+            if !extensions
+                .iter()
+                .any(|ext| ext.starts_with("AUTH") && ext.split(' ').any(|m| m == "PLAIN"))
+            {
+                return Err(Error::smtp(
+                    SmtpErrorCode::MissingExtension,
+                    format!("Relay {} does not offer AUTH PLAIN.", host),
+                ));
+            }
+            let token = base64::encode(format!("\0{}\0{}", username, password.get()));
+            conn.command(&format!("AUTH PLAIN {}", token), 235).await?;
+        }
+
+        conn.extensions = extensions;
+        Ok(conn)
+    }
+
+    /// Sends EHLO and returns the extension keywords announced by the server.
+    async fn ehlo(&mut self, helo_name: &str) -> Result<Vec<String>, Error> {
+        let text = self.command(&format!("EHLO {}", helo_name), 250).await?;
+        // The first line contains the greeting:
+        Ok(text
+            .lines()
+            .skip(1)
+            .map(|line| line.trim().to_uppercase())
+            .collect())
+    }
+
+    pub(super) fn is_verified(&self) -> bool {
+        self.verified
+    }
+
+    /// Returns true, if the server announced the extension `keyword` (in upper case).
+    pub(super) fn offers(&self, keyword: &str) -> bool {
+        self.extensions
+            .iter()
+            .any(|ext| ext.split(' ').next() == Some(keyword))
+    }
+
+    /// Performs a complete mail transaction. The connection can be reused afterwards.
+    /// With `require_tls`, the REQUIRETLS parameter is passed on, so the following hops also use TLS.
+    pub(super) async fn send_mail(
+        &mut self,
+        sender: &str,
+        recipient: &str,
+        content: &[u8],
+        require_tls: bool,
+    ) -> Result<String, Error> {
+        let mail = match require_tls {
+            true => format!("MAIL FROM:<{}> REQUIRETLS", sender),
+            false => format!("MAIL FROM:<{}>", sender),
+        };
+        self.command(&mail, 250).await?;
+        self.command(&format!("RCPT TO:<{}>", recipient), 250)
+            .await?;
+        self.command("DATA", 354).await?;
+        self.send_data(content).await
+    }
+
+    /// Checks whether the connection is still usable and resets the session state.
+    pub(super) async fn reset(&mut self) -> Result<(), Error> {
+        self.command("RSET", 250).await.map(|_| ())
+    }
+
+    pub(super) async fn quit(mut self) -> Result<(), Error> {
+        self.command("QUIT", 221).await?;
+        self.stream.shutdown().await?;
+        Ok(())
+    }
+
+    /// Reads a (possibly multiline) response and returns its code and the text of all lines.
+    async fn read_response(&mut self) -> Result<(u16, String), Error> {
+        let mut text = String::new();
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(Error::smtp(
+                    SmtpErrorCode::Protocol,
+                    "Relay closed the connection unexpectedly.",
+                ));
+            }
+            let code = line
+                .get(..3)
+                .and_then(|c| c.parse::<u16>().ok())
+                .ok_or_else(|| {
+                    Error::smtp(
+                        SmtpErrorCode::Protocol,
+                        format!("Invalid response from relay: {}", line),
+                    )
+                })?;
+            text.push_str(line.get(4..).unwrap_or("").trim_end());
+            if line.as_bytes().get(3) != Some(&b'-') {
+                return Ok((code, text));
+            }
+            text.push('\n');
+        }
+    }
+
+    async fn expect_response(&mut self, expected: u16) -> Result<String, Error> {
+        let (code, text) = self.read_response().await?;
+        if code == expected {
+            Ok(text)
+        } else {
+            Err(Error::smtp(
+                SmtpErrorCode::Reply(code),
+                format!(
+                    "Relay responded with {} {} (expected {}).",
+                    code, text, expected
+                ),
+            ))
+        }
+    }
+
+    async fn command(&mut self, cmd: &str, expected: u16) -> Result<String, Error> {
+        self.stream.write_all(cmd.as_bytes()).await?;
+        self.stream.write_all(b"\r\n").await?;
+        self.stream.flush().await?;
+        self.expect_response(expected).await
+    }
+
+    /// Sends the message content after a DATA command including the terminating ".". Every line is terminated with
+    /// CRLF, even if it ends with a bare LF (or not at all) in `content`.
+    /// Returns the text of the final reply.
+    async fn send_data(&mut self, content: &[u8]) -> Result<String, Error> {
+        for line in content.split_inclusive(|b| *b == b'\n') {
+            let line = line.strip_suffix(b"\n").unwrap_or(line);
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            // Dot-stuffing as described in RFC 5321, section 4.5.2:
+            if line.first() == Some(&b'.') {
+                self.stream.write_all(b".").await?;
+            }
+            self.stream.write_all(line).await?;
+            self.stream.write_all(b"\r\n").await?;
+        }
+        self.stream.write_all(b".\r\n").await?;
+        self.stream.flush().await?;
+        self.expect_response(250).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, AsyncRead, AsyncReadExt, DuplexStream};
+    use tokio_rustls::TlsAcceptor;
+
+    /// Returns a connection, that is ready for a mail transaction, and the stream of the server side.
+    fn connection() -> (SmtpConnection, DuplexStream) {
+        let (client, server) = duplex(64 * 1024);
+        let conn = SmtpConnection {
+            stream: BufStream::new(Box::new(client)),
+            verified: false,
+            extensions: vec![],
+        };
+        (conn, server)
+    }
+
+    /// Returns the bytes, that are sent for `content` after DATA.
+    async fn sent_data(content: &[u8]) -> String {
+        let (mut conn, mut server) = connection();
+        server.write_all(b"250 Queued\r\n").await.unwrap();
+        assert_eq!(conn.send_data(content).await.unwrap(), "Queued");
+        drop(conn);
+        let mut sent = String::new();
+        server.read_to_string(&mut sent).await.unwrap();
+        sent
+    }
+
+    #[tokio::test]
+    async fn test_send_data() {
+        assert_eq!(sent_data(b"Hello\r\n").await, "Hello\r\n.\r\n");
+        assert_eq!(sent_data(b"Hello\n").await, "Hello\r\n.\r\n");
+        assert_eq!(sent_data(b"").await, ".\r\n");
+        assert_eq!(
+            sent_data(b"Subject: Dots\n\n.leading dot\r\nbare LF\n..\nno newline").await,
+            "Subject: Dots\r\n\r\n..leading dot\r\nbare LF\r\n...\r\nno newline\r\n.\r\n"
+        );
+    }
+
+    /// Reads a command starting with `command` and sends `reply`.
+    async fn answer(
+        stream: &mut BufStream<impl AsyncRead + AsyncWrite + Unpin>,
+        command: &str,
+        reply: &[u8],
+    ) {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        assert!(line.starts_with(command), "Unexpected command {}", line);
+        stream.write_all(reply).await.unwrap();
+        stream.flush().await.unwrap();
+    }
+
+    /// Accepts a connection as a relay, that offers STARTTLS with a self-signed certificate.
+    async fn starttls_relay(stream: DuplexStream) {
+        let generated =
+            rcgen::generate_simple_self_signed(vec!["relay.example.org".to_string()]).unwrap();
+        let config = rustls::ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_single_cert(
+                vec![Certificate(generated.serialize_der().unwrap())],
+                rustls::PrivateKey(generated.serialize_private_key_der()),
+            )
+            .unwrap();
+        let mut stream = BufStream::new(stream);
+        stream
+            .write_all(b"220 relay.example.org\r\n")
+            .await
+            .unwrap();
+        stream.flush().await.unwrap();
+        answer(
+            &mut stream,
+            "EHLO",
+            b"250-relay.example.org\r\n250 STARTTLS\r\n",
+        )
+        .await;
+        answer(&mut stream, "STARTTLS", b"220 Go ahead\r\n").await;
+        // The handshake fails, if the client does not accept the certificate:
+        if let Ok(tls_stream) = TlsAcceptor::from(Arc::new(config))
+            .accept(stream.into_inner())
+            .await
+        {
+            answer(
+                &mut BufStream::new(tls_stream),
+                "EHLO",
+                b"250 relay.example.org\r\n",
+            )
+            .await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_opportunistic_starttls() {
+        let (client, server) = duplex(64 * 1024);
+        tokio::spawn(starttls_relay(server));
+        let conn = SmtpConnection::open(client, "relay.example.org", &SessionParams::new())
+            .await
+            .unwrap();
+        assert!(!conn.is_verified());
+
+        // Required STARTTLS needs a valid certificate:
+        let (client, server) = duplex(64 * 1024);
+        tokio::spawn(starttls_relay(server));
+        let params = SessionParams {
+            starttls: StartTls::Required,
+            ..SessionParams::new()
+        };
+        assert!(SmtpConnection::open(client, "relay.example.org", &params)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_no_auth_without_verified_tls() {
+        let (client, server) = duplex(64 * 1024);
+        let relay = tokio::spawn(async move {
+            let mut stream = BufStream::new(server);
+            stream
+                .write_all(b"220 relay.example.org\r\n")
+                .await
+                .unwrap();
+            stream.flush().await.unwrap();
+            // A relay without STARTTLS (or an attacker, who removed it from the reply):
+            answer(
+                &mut stream,
+                "EHLO",
+                b"250-relay.example.org\r\n250 AUTH PLAIN\r\n",
+            )
+            .await;
+            let mut rest = String::new();
+            stream.read_to_string(&mut rest).await.unwrap();
+            rest
+        });
+        let params = SessionParams {
+            credentials: Some(("forwarder".to_string(), Secret::from("123abc"))),
+            ..SessionParams::new()
+        };
+        assert!(SmtpConnection::open(client, "relay.example.org", &params)
+            .await
+            .is_err());
+        // The connection is closed without sending the credentials:
+        assert_eq!(relay.await.unwrap(), "");
+    }
+}
@@ -0,0 +1,274 @@
+use log::{debug, warn};
+use tokio::{
+    net::{TcpSocket, TcpStream},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+use trust_dns_resolver::{error::ResolveErrorKind, TokioAsyncResolver};
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::{Error, SmtpErrorCode};
+use crate::proxy::Proxy;
+
+/// The delay between two connection attempts, as recommended by RFC 8305, section 8.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+/// The time after which a single connection attempt is given up.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+/// The time an address is skipped after a failed connection attempt.
+const DEAD_HOST_TTL: Duration = Duration::from_secs(300);
+
+/// Resolves relay targets and opens connections to them.
+///
+/// Addresses of a host are tried with the Happy Eyeballs algorithm (RFC 8305): IPv6 and IPv4 addresses are interleaved
+/// and a new attempt is started every `CONNECTION_ATTEMPT_DELAY` or as soon as the previous one failed, while earlier
+/// attempts keep running. Addresses, that could not be connected to, are skipped for `DEAD_HOST_TTL`.
+pub(super) struct Connector {
+    resolver: TokioAsyncResolver,
+    source_addr: Option<IpAddr>,
+    dead_hosts: Mutex<HashMap<SocketAddr, Instant>>,
+    /// If set, connections are opened through this proxy, which also resolves the host names.
+    proxy: Option<Arc<Proxy>>,
+}
+
+impl Connector {
+    pub(super) fn new(source_addr: Option<IpAddr>) -> Result<Self, Error> {
+        Ok(Connector {
+            resolver: TokioAsyncResolver::tokio_from_system_conf()?,
+            source_addr,
+            dead_hosts: Mutex::new(HashMap::new()),
+            proxy: None,
+        })
+    }
+
+    pub(super) fn set_resolver(&mut self, resolver: TokioAsyncResolver) {
+        self.resolver = resolver;
+    }
+
+    pub(super) fn set_proxy(&mut self, proxy: Arc<Proxy>) {
+        self.proxy = Some(proxy);
+    }
+
+    pub(super) fn set_source_addr(&mut self, addr: IpAddr) {
+        self.source_addr = Some(addr);
+    }
+
+    /// Returns the hosts responsible for receiving emails for `domain` ordered by their MX preference.
+    ///
+    /// If the domain has no MX records, the domain itself is returned as implicit MX (RFC 5321, section 5.1). If the
+    /// domain has a null MX record (RFC 7505), an error is returned.
+    pub(super) async fn mx_hosts(&self, domain: &str) -> Result<Vec<String>, Error> {
+        match self.resolver.mx_lookup(domain).await {
+            Ok(lookup) => {
+                let mut records: Vec<_> = lookup.iter().collect();
+                if records.iter().any(|mx| mx.exchange().is_root()) {
+                    return Err(Error::smtp(
+                        SmtpErrorCode::NullMx,
+                        format!("The domain {} does not accept emails (null MX).", domain),
+                    ));
+                }
+                records.sort_by_key(|mx| mx.preference());
+                Ok(records
+                    .into_iter()
+                    .map(|mx| mx.exchange().to_utf8())
+                    .collect())
+            }
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => {
+                debug!(
+                    "No MX records for {}, using the domain as implicit MX.",
+                    domain
+                );
+                Ok(vec![domain.to_string()])
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Opens a TCP connection to one of the addresses of `host`.
+    pub(super) async fn connect(&self, host: &str, port: u16) -> Result<TcpStream, Error> {
+        if let Some(ref proxy) = self.proxy {
+            return match timeout(CONNECT_TIMEOUT, proxy.connect(host, port)).await {
+                Ok(res) => res,
+                Err(_) => Err(Error::smtp(
+                    SmtpErrorCode::Unreachable,
+                    format!("Connecting to {} through the proxy timed out.", host),
+                )),
+            };
+        }
+        let addrs: Vec<IpAddr> = self
+            .resolver
+            .lookup_ip(host)
+            .await?
+            .iter()
+            // Only use addresses of the same family as the source address:
+            .filter(|ip| {
+                self.source_addr
+                    .is_none_or(|source| source.is_ipv4() == ip.is_ipv4())
+            })
+            .collect();
+        let targets: Vec<SocketAddr> = interleave_families(addrs)
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip, port))
+            .filter(|target| !self.is_dead(target))
+            .collect();
+        if targets.is_empty() {
+            return Err(Error::smtp(
+                SmtpErrorCode::Unreachable,
+                format!(
+                    "Host {} has no reachable address usable from the configured source address.",
+                    host
+                ),
+            ));
+        }
+
+        self.race(targets).await
+    }
+
+    /// Starts staggered connection attempts to the given targets and returns the first established connection.
+    async fn race(&self, targets: Vec<SocketAddr>) -> Result<TcpStream, Error> {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut pending = targets.into_iter().peekable();
+        let mut attempts = Vec::new();
+        let mut running = 0;
+        let mut last_err = None;
+
+        while pending.peek().is_some() || running > 0 {
+            if let Some(target) = pending.next() {
+                let tx = tx.clone();
+                let source = self.source_addr;
+                attempts.push(tokio::spawn(async move {
+                    let res = match timeout(CONNECT_TIMEOUT, connect_from(source, target)).await {
+                        Ok(res) => res,
+                        Err(_) => Err(Error::smtp(
+                            SmtpErrorCode::Unreachable,
+                            format!("Connection attempt to {} timed out.", target),
+                        )),
+                    };
+                    // The receiver is dropped, if another attempt already succeeded:
+                    let _ = tx.send((target, res));
+                }));
+                running += 1;
+            }
+
+            // Wait until an attempt finishes or it is time for the next one:
+            let delay = sleep(CONNECTION_ATTEMPT_DELAY);
+            tokio::pin!(delay);
+            tokio::select! {
+                Some((target, res)) = rx.recv() => {
+                    running -= 1;
+                    match res {
+                        Ok(stream) => {
+                            for attempt in attempts.iter() {
+                                attempt.abort();
+                            }
+                            return Ok(stream);
+                        }
+                        Err(e) => {
+                            warn!("Could not connect to {}: {}", target, e);
+                            self.mark_dead(target);
+                            last_err = Some(e);
+                        }
+                    }
+                }
+                _ = &mut delay, if pending.peek().is_some() => {}
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            Error::smtp(SmtpErrorCode::Unreachable, "No address to connect to.")
+        }))
+    }
+
+    fn is_dead(&self, target: &SocketAddr) -> bool {
+        let mut dead_hosts = self
+            .dead_hosts
+            .lock()
+            .expect("Dead host cache is poisoned.");
+        match dead_hosts.get(target) {
+            Some(since) if since.elapsed() < DEAD_HOST_TTL => true,
+            Some(_) => {
+                dead_hosts.remove(target);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn mark_dead(&self, target: SocketAddr) {
+        self.dead_hosts
+            .lock()
+            .expect("Dead host cache is poisoned.")
+            .insert(target, Instant::now());
+    }
+}
+
+/// Orders addresses alternating between IPv6 and IPv4, starting with IPv6 (RFC 8305, section 4).
+fn interleave_families(addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+    let (v6, v4): (Vec<IpAddr>, Vec<IpAddr>) = addrs.into_iter().partition(IpAddr::is_ipv6);
+    let mut v6 = v6.into_iter();
+    let mut v4 = v4.into_iter();
+    let mut res = Vec::new();
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return res,
+            (a, b) => res.extend(a.into_iter().chain(b)),
+        }
+    }
+}
+
+/// Connects to `target`. If `source` is given, the local end of the connection is bound to it.
+async fn connect_from(source: Option<IpAddr>, target: SocketAddr) -> Result<TcpStream, Error> {
+    if let Some(source) = source {
+        let socket = if target.is_ipv4() {
+            TcpSocket::new_v4()?
+        } else {
+            TcpSocket::new_v6()?
+        };
+        socket.bind(SocketAddr::new(source, 0))?;
+        Ok(socket.connect(target).await?)
+    } else {
+        Ok(TcpStream::connect(target).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interleave_families() {
+        let v4_1: IpAddr = "192.0.2.1".parse().unwrap();
+        let v4_2: IpAddr = "192.0.2.2".parse().unwrap();
+        let v4_3: IpAddr = "192.0.2.3".parse().unwrap();
+        let v6_1: IpAddr = "2001:db8::1".parse().unwrap();
+
+        assert_eq!(
+            interleave_families(vec![v4_1, v4_2, v6_1, v4_3]),
+            vec![v6_1, v4_1, v4_2, v4_3]
+        );
+        assert_eq!(interleave_families(vec![v4_1, v4_2]), vec![v4_1, v4_2]);
+    }
+
+    #[tokio::test]
+    async fn test_race_skips_unreachable_targets() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let reachable = listener.local_addr().unwrap();
+        // Nothing listens on this address, because we just got and released it:
+        let unreachable = {
+            let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            l.local_addr().unwrap()
+        };
+        let connector = Connector::new(None).unwrap();
+
+        let stream = connector
+            .race(vec![unreachable, reachable])
+            .await
+            .expect("Could not connect to reachable target.");
+        assert_eq!(stream.peer_addr().unwrap(), reachable);
+        assert!(connector.is_dead(&unreachable));
+        assert!(!connector.is_dead(&reachable));
+    }
+}
@@ -0,0 +1,497 @@
+use async_trait::async_trait;
+use log::{debug, info, warn};
+use trust_dns_resolver::TokioAsyncResolver;
+
+use std::borrow::Cow;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use super::{EmailDestination, Receipt};
+use crate::email::{rfc5322_date, Email};
+use crate::error::{Error, SmtpErrorCode};
+use crate::proxy::Proxy;
+use crate::secret::Secret;
+
+mod client;
+mod connect;
+mod pool;
+
+use client::{SessionParams, SmtpConnection};
+use connect::Connector;
+use pool::ConnectionPool;
+
+pub(crate) use client::StartTls;
+pub(crate) use pool::PoolConfig;
+
+/// The default number of Received headers, above which an email is considered to be looping.
+const DEFAULT_MAX_HOPS: usize = 50;
+
+/// Forwards emails to an upstream SMTP server.
+///
+/// If no relay host is given, the email is delivered directly to the MX hosts of the recipient domain, trying them in
+/// order of their preference.
+/// Connections are kept open after a delivery and reused for following emails to the same host, so bursts of emails
+/// don't need a complete TLS and AUTH handshake per email. If the relay rejects the password, it is read from its file
+/// again and the authentication is repeated once, if it was rotated.
+///
+/// Forwarded emails get a Received header with the hostname of this server. Emails, that already passed this server,
+/// passed too many servers or are automatic replies of the recipient, are refused to break forwarding loops.
+pub(crate) struct RelayDestination {
+    host: Option<String>,
+    port: u16,
+    /// The name of this server in the Received headers, that it adds.
+    hostname: Option<String>,
+    max_hops: usize,
+    connector: Connector,
+    session_params: SessionParams,
+    pool: ConnectionPool,
+    sender: String,
+    recipient: String,
+}
+
+impl RelayDestination {
+    /// Creates a destination, that forwards every email to `recipient` using the SMTP server at `host`:`port` or the
+    /// MX hosts of the recipient domain, if `host` is None.
+    /// The envelope sender is the null reverse-path, unless it is changed with `set_sender()`.
+    pub fn new(
+        host: Option<String>,
+        port: u16,
+        recipient: impl Into<String>,
+    ) -> Result<Self, Error> {
+        Ok(RelayDestination {
+            host,
+            port,
+            hostname: None,
+            max_hops: DEFAULT_MAX_HOPS,
+            connector: Connector::new(None)?,
+            session_params: SessionParams::new(),
+            pool: ConnectionPool::new(PoolConfig::default()),
+            sender: String::new(),
+            recipient: recipient.into(),
+        })
+    }
+
+    /// Sets the resolver used to find the MX hosts of the recipient domain and the addresses of relay hosts, instead of
+    /// one using the system configuration.
+    pub fn set_resolver(&mut self, resolver: TokioAsyncResolver) {
+        self.connector.set_resolver(resolver);
+    }
+
+    /// Lets connections to the relay host or the MX hosts be opened through the given proxy. The MX hosts are still
+    /// looked up with the resolver.
+    pub fn set_proxy(&mut self, proxy: Arc<Proxy>) {
+        self.connector.set_proxy(proxy);
+    }
+
+    /// Sets the local address outgoing connections are bound to.
+    /// This is useful on hosts with multiple addresses, where only one of them has a proper PTR record or is allowed
+    /// by the SPF record of the sending domain.
+    pub fn set_source_addr(&mut self, addr: IpAddr) {
+        self.connector.set_source_addr(addr);
+    }
+
+    /// Sets the name this server identifies with in the EHLO command.
+    pub fn set_helo_name(&mut self, name: impl Into<String>) {
+        self.session_params.helo_name = name.into();
+    }
+
+    pub fn set_starttls(&mut self, starttls: StartTls) {
+        self.session_params.starttls = starttls;
+    }
+
+    /// Sets the credentials used to authenticate with AUTH PLAIN. They are only sent with required STARTTLS.
+    pub fn set_login(&mut self, username: impl Into<String>, password: impl Into<Secret>) {
+        self.session_params.credentials = Some((username.into(), password.into()));
+    }
+
+    pub fn set_pool_config(&mut self, config: PoolConfig) {
+        self.pool = ConnectionPool::new(config);
+    }
+
+    /// Sets the name of this server, that is added to forwarded emails in a Received header and recognized in the
+    /// Received headers of emails, that loop back. Without it, no header is added and loops are only detected by
+    /// their number of hops.
+    pub fn set_hostname(&mut self, hostname: impl Into<String>) {
+        self.hostname = Some(hostname.into());
+    }
+
+    /// Sets the number of Received headers, above which emails are not forwarded anymore.
+    pub fn set_max_hops(&mut self, max_hops: usize) {
+        self.max_hops = max_hops;
+    }
+
+    /// Returns an error, if forwarding the email would (probably) continue a forwarding loop.
+    fn check_loop(&self, email: &Email<'_>) -> Result<(), Error> {
+        let hops = email.received_hops();
+        if hops > self.max_hops {
+            return Err(Error::Policy(format!(
+                "Mail loop detected: The email passed {} servers (at most {} allowed).",
+                hops, self.max_hops
+            )));
+        }
+        if let Some(ref hostname) = self.hostname {
+            if email.received_by(hostname) {
+                return Err(Error::Policy(format!(
+                    "Mail loop detected: The email was already forwarded by {}.",
+                    hostname
+                )));
+            }
+        }
+        // Automatic replies (RFC 3834) of the recipient must not be sent back to it:
+        let from_recipient = email
+            .envelope
+            .as_ref()
+            .and_then(|envelope| envelope.mail_from.as_deref())
+            .is_some_and(|sender| sender.eq_ignore_ascii_case(&self.recipient));
+        if from_recipient && email.is_auto_submitted() {
+            return Err(Error::Policy(format!(
+                "Mail loop detected: The email is an automatic reply of {}.",
+                self.recipient
+            )));
+        }
+        Ok(())
+    }
+
+    /// Returns the email to send, which starts with a Received header, if a hostname is set.
+    fn traced<'e>(&self, email: &'e Email<'_>) -> Cow<'e, [u8]> {
+        match self.hostname {
+            Some(ref hostname) => {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_secs());
+                let mut traced = format!(
+                    "Received: by {} (kutsche) for <{}>; {}\r\n",
+                    hostname,
+                    self.recipient,
+                    rfc5322_date(now)
+                )
+                .into_bytes();
+                traced.extend_from_slice(email.raw);
+                Cow::Owned(traced)
+            }
+            None => Cow::Borrowed(email.raw),
+        }
+    }
+
+    /// Sets the envelope sender used for forwarded emails.
+    pub fn set_sender(&mut self, sender: impl Into<String>) {
+        self.sender = sender.into();
+    }
+
+    /// Returns the hosts, that should be tried in the given order.
+    async fn target_hosts(&self) -> Result<Vec<String>, Error> {
+        if let Some(ref host) = self.host {
+            return Ok(vec![host.clone()]);
+        }
+        let domain = self
+            .recipient
+            .rsplit_once('@')
+            .map(|(_, domain)| domain)
+            .ok_or_else(|| {
+                Error::config(format!("Relay recipient {} has no domain.", self.recipient))
+            })?;
+        self.connector.mx_hosts(domain).await
+    }
+
+    /// Returns a pooled connection to `host`, that is still alive, or opens a new one.
+    /// With `require_tls`, opportunistic STARTTLS is required, so the certificate of the relay is verified. With
+    /// `tls_optional`, the connection is not refused, if STARTTLS is required but not offered, unless credentials have
+    /// to be sent over it.
+    async fn get_connection(
+        &self,
+        host: &str,
+        require_tls: bool,
+        tls_optional: bool,
+    ) -> Result<(SmtpConnection, Instant), Error> {
+        let starttls = match self.session_params.starttls {
+            StartTls::Opportunistic if require_tls => StartTls::Required,
+            StartTls::Required if tls_optional && self.session_params.credentials.is_none() => {
+                StartTls::Opportunistic
+            }
+            starttls => starttls,
+        };
+        let changed;
+        let params = if starttls != self.session_params.starttls {
+            changed = SessionParams {
+                starttls,
+                ..self.session_params.clone()
+            };
+            &changed
+        } else {
+            &self.session_params
+        };
+        while let Some((mut conn, opened)) = self.pool.take(host) {
+            // Connections opened with opportunistic STARTTLS must not be used, when a verified one is required:
+            if params.starttls == StartTls::Required && !conn.is_verified() {
+                debug!("Closing unverified pooled connection to {}.", host);
+                if let Err(e) = conn.quit().await {
+                    debug!("Could not close connection to relay {}: {}", host, e);
+                }
+                continue;
+            }
+            match conn.reset().await {
+                Ok(()) => {
+                    debug!("Reusing pooled connection to relay {}.", host);
+                    return Ok((conn, opened));
+                }
+                Err(e) => debug!("Discarding broken pooled connection to {}: {}", host, e),
+            }
+        }
+
+        let stream = self.connector.connect(host, self.port).await?;
+        let conn = match SmtpConnection::open(stream, host, params).await {
+            Ok(conn) => conn,
+            Err(e) if e.is_auth_failure() && self.reload_password() => {
+                warn!("{} Retrying with the rotated password.", e);
+                let stream = self.connector.connect(host, self.port).await?;
+                SmtpConnection::open(stream, host, params).await?
+            }
+            Err(e) => return Err(e),
+        };
+        Ok((conn, Instant::now()))
+    }
+
+    /// Reads the password from its file again and returns true, if it changed.
+    fn reload_password(&self) -> bool {
+        match self.session_params.credentials {
+            Some((_, ref password)) => match password.reload() {
+                Ok(changed) => changed,
+                Err(e) => {
+                    warn!("Could not reload password for relay: {}", e);
+                    false
+                }
+            },
+            None => false,
+        }
+    }
+
+    /// Performs a complete SMTP transaction with `host` and returns the connection to the pool afterwards.
+    ///
+    /// Emails with `require_tls` (REQUIRETLS, RFC 8689) are only sent over an encrypted connection to a relay, that
+    /// supports REQUIRETLS itself. Emails with `tls_optional` are sent even if STARTTLS is required but not offered.
+    async fn deliver_via(
+        &self,
+        host: &str,
+        raw: &[u8],
+        require_tls: bool,
+        tls_optional: bool,
+    ) -> Result<Receipt, Error> {
+        let (mut conn, opened) = self.get_connection(host, require_tls, tls_optional).await?;
+        let unmet = if !require_tls {
+            None
+        } else if !conn.is_verified() {
+            Some(format!(
+                "The email requires TLS, but the connection to relay {} is not encrypted with a verified certificate.",
+                host
+            ))
+        } else if !conn.offers("REQUIRETLS") {
+            Some(format!(
+                "The email requires TLS, but relay {} does not support REQUIRETLS.",
+                host
+            ))
+        } else {
+            None
+        };
+        if let Some(desc) = unmet {
+            return Err(Error::smtp(SmtpErrorCode::MissingExtension, desc));
+        }
+        let reply = conn
+            .send_mail(&self.sender, &self.recipient, raw, require_tls)
+            .await?;
+
+        if let Some(conn) = self.pool.put(host, conn, opened) {
+            if let Err(e) = conn.quit().await {
+                debug!("Could not close connection to relay {}: {}", host, e);
+            }
+        }
+
+        Ok(Receipt::new(format!("{}: {}", host, reply.trim_end())))
+    }
+}
+
+#[async_trait]
+impl EmailDestination for RelayDestination {
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        self.check_loop(email)?;
+        let raw = self.traced(email);
+        // REQUIRETLS takes precedence over "TLS-Required: No" (RFC 8689, section 5):
+        let require_tls = email
+            .envelope
+            .as_ref()
+            .is_some_and(|envelope| envelope.require_tls);
+        let tls_optional = !require_tls && email.tls_optional();
+        let mut last_err = None;
+        for host in self.target_hosts().await? {
+            match self
+                .deliver_via(&host, &raw, require_tls, tls_optional)
+                .await
+            {
+                Ok(receipt) => {
+                    info!("Relayed email with id {} to {}.", &email.message_id, host);
+                    return Ok(receipt);
+                }
+                Err(e) => {
+                    warn!(
+                        "Could not relay email with id {} via {}: {}",
+                        &email.message_id, host, e
+                    );
+                    last_err = Some(e);
+                }
+            }
+        }
+
+        Err(last_err
+            .unwrap_or_else(|| Error::smtp(SmtpErrorCode::Unreachable, "No relay host available.")))
+    }
+
+    fn kind(&self) -> &'static str {
+        "relay"
+    }
+
+    fn describe(&self) -> String {
+        let via = match self.host {
+            Some(ref host) => format!("{}:{}", host, self.port),
+            None => format!("the MX hosts of its domain on port {}", self.port),
+        };
+        format!("SMTP relay to {} via {}", self.recipient, via)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::Envelope;
+
+    #[tokio::test]
+    async fn test_loop_detection() {
+        let mut destination = RelayDestination::new(None, 25, "admin@example.org").unwrap();
+        destination.set_hostname("kutsche.example.com");
+        destination.set_max_hops(2);
+
+        let email = Email::parse(b"Message-ID: <loop@example.com>\r\n\r\nHello.\r\n").unwrap();
+        assert!(destination.check_loop(&email).is_ok());
+        let traced = destination.traced(&email);
+        let traced = Email::parse(&traced).unwrap();
+        assert!(traced.received_by("kutsche.example.com"));
+        assert!(matches!(
+            destination.check_loop(&traced),
+            Err(Error::Policy(_))
+        ));
+
+        let raw = b"Received: by a.example.com; Tue, 1 Mar 2022 10:00:00 +0000\r\n\
+Received: by b.example.com; Tue, 1 Mar 2022 10:00:00 +0000\r\n\
+Received: by c.example.com; Tue, 1 Mar 2022 10:00:00 +0000\r\n\
+Message-ID: <hops@example.com>\r\n\r\nHello.\r\n";
+        assert!(destination.check_loop(&Email::parse(raw).unwrap()).is_err());
+
+        let raw =
+            b"Auto-Submitted: auto-replied\r\nMessage-ID: <reply@example.org>\r\n\r\nAway.\r\n";
+        let mut email = Email::parse(raw).unwrap();
+        assert!(destination.check_loop(&email).is_ok());
+        email.envelope = Some(Arc::new(Envelope {
+            mail_from: Some("Admin@example.org".to_string()),
+            rcpt_to: vec!["alerts@example.com".to_string()],
+            client: Default::default(),
+            received_at: 0,
+            require_tls: false,
+        }));
+        assert!(destination.check_loop(&email).is_err());
+    }
+
+    /// Accepts `connections` connections one after another as a relay without STARTTLS and REQUIRETLS and returns the
+    /// received commands.
+    async fn plain_relay(listener: tokio::net::TcpListener, connections: usize) -> Vec<String> {
+        use tokio::io::{AsyncWriteExt, BufStream};
+
+        let mut commands = vec![];
+        for _ in 0..connections {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufStream::new(stream);
+            stream
+                .write_all(b"220 relay.example.org\r\n")
+                .await
+                .unwrap();
+            stream.flush().await.unwrap();
+            plain_session(&mut stream, &mut commands).await;
+        }
+        commands
+    }
+
+    async fn plain_session(
+        stream: &mut (impl tokio::io::AsyncBufRead + tokio::io::AsyncWrite + Unpin),
+        commands: &mut Vec<String>,
+    ) {
+        use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+        loop {
+            let mut line = String::new();
+            if stream.read_line(&mut line).await.unwrap() == 0 {
+                return;
+            }
+            let command = line.trim_end().to_string();
+            let reply: &[u8] = match command.split(' ').next().unwrap() {
+                "EHLO" => b"250-relay.example.org\r\n250 8BITMIME\r\n",
+                "DATA" => {
+                    stream.write_all(b"354 Go ahead\r\n").await.unwrap();
+                    stream.flush().await.unwrap();
+                    while line != ".\r\n" {
+                        line.clear();
+                        stream.read_line(&mut line).await.unwrap();
+                    }
+                    b"250 Queued\r\n"
+                }
+                "QUIT" => b"221 Bye\r\n",
+                _ => b"250 OK\r\n",
+            };
+            stream.write_all(reply).await.unwrap();
+            stream.flush().await.unwrap();
+            commands.push(command);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_tls_requirements() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let relay = tokio::spawn(plain_relay(listener, 2));
+        let mut destination =
+            RelayDestination::new(Some("127.0.0.1".to_string()), port, "admin@example.org")
+                .unwrap();
+        destination.set_starttls(StartTls::Required);
+        destination.set_pool_config(PoolConfig {
+            max_idle: 0,
+            ..PoolConfig::default()
+        });
+
+        // REQUIRETLS can't be met by a relay without TLS:
+        let mut email =
+            Email::parse(b"Message-ID: <secret@example.com>\r\nTLS-Required: No\r\n\r\nHi\r\n")
+                .unwrap();
+        email.envelope = Some(Arc::new(Envelope {
+            mail_from: None,
+            rcpt_to: vec!["alerts@example.com".to_string()],
+            client: Default::default(),
+            received_at: 0,
+            require_tls: true,
+        }));
+        let e = destination.write_email(&email).await.unwrap_err();
+        assert!(!e.is_temporary());
+        assert_eq!(e.code(), "smtp.missing_extension");
+
+        // Without REQUIRETLS, "TLS-Required: No" overrides the configured STARTTLS requirement:
+        email.envelope = None;
+        destination.write_email(&email).await.unwrap();
+        assert_eq!(
+            relay.await.unwrap(),
+            vec![
+                "EHLO localhost",
+                "EHLO localhost",
+                "MAIL FROM:<>",
+                "RCPT TO:<admin@example.org>",
+                "DATA",
+                "QUIT"
+            ]
+        );
+    }
+}
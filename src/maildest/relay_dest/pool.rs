@@ -0,0 +1,281 @@
+use log::debug;
+use tokio::time::sleep_until;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use super::client::SmtpConnection;
+
+/// Limits for keeping idle connections to a relay open.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct PoolConfig {
+    /// The maximum number of idle connections per host. Zero disables pooling.
+    pub(crate) max_idle: usize,
+    /// The time after which an unused connection is closed.
+    pub(crate) idle_timeout: Duration,
+    /// The time after which a connection is closed, regardless of its usage.
+    pub(crate) max_lifetime: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_idle: 4,
+            idle_timeout: Duration::from_secs(30),
+            max_lifetime: Duration::from_secs(300),
+        }
+    }
+}
+
+struct IdleConnection {
+    conn: SmtpConnection,
+    opened: Instant,
+    last_used: Instant,
+}
+
+impl IdleConnection {
+    /// Returns the time, when the connection has to be closed.
+    fn expires(&self, config: &PoolConfig) -> Instant {
+        (self.last_used + config.idle_timeout).min(self.opened + config.max_lifetime)
+    }
+}
+
+#[derive(Default)]
+struct IdleConnections {
+    by_host: HashMap<String, Vec<IdleConnection>>,
+    /// Whether a task is running, that closes the connections, when they expire.
+    reaping: bool,
+}
+
+impl IdleConnections {
+    /// Removes the expired connections and returns them together with their host.
+    fn remove_expired(&mut self, config: &PoolConfig) -> Vec<(String, SmtpConnection)> {
+        let now = Instant::now();
+        let mut expired = vec![];
+        for (host, conns) in self.by_host.iter_mut() {
+            let (gone, kept) = std::mem::take(conns)
+                .into_iter()
+                .partition(|entry| entry.expires(config) <= now);
+            *conns = kept;
+            expired.extend(
+                gone.into_iter()
+                    .map(|entry: IdleConnection| (host.clone(), entry.conn)),
+            );
+        }
+        self.by_host.retain(|_, conns| !conns.is_empty());
+        expired
+    }
+}
+
+/// Idle, already authenticated connections to relay hosts.
+///
+/// Connections are closed with QUIT, when they expire, even if the pool is not used anymore.
+pub(super) struct ConnectionPool {
+    config: PoolConfig,
+    idle: Arc<Mutex<IdleConnections>>,
+}
+
+impl ConnectionPool {
+    pub(super) fn new(config: PoolConfig) -> Self {
+        ConnectionPool {
+            config,
+            idle: Arc::new(Mutex::new(IdleConnections::default())),
+        }
+    }
+
+    /// Takes the most recently used idle connection to `host` together with the time it was opened.
+    pub(super) fn take(&self, host: &str) -> Option<(SmtpConnection, Instant)> {
+        let mut idle = self.idle.lock().expect("Connection pool is poisoned.");
+        let expired = idle.remove_expired(&self.config);
+        let taken = idle
+            .by_host
+            .get_mut(host)
+            .and_then(Vec::pop)
+            .map(|entry| (entry.conn, entry.opened));
+        drop(idle);
+        close_all(expired);
+        taken
+    }
+
+    /// Returns a connection, that was opened at `opened`, to the pool.
+    /// Returns the connection back, if it should be closed instead.
+    pub(super) fn put(
+        &self,
+        host: &str,
+        conn: SmtpConnection,
+        opened: Instant,
+    ) -> Option<SmtpConnection> {
+        if opened.elapsed() >= self.config.max_lifetime {
+            return Some(conn);
+        }
+        let mut idle = self.idle.lock().expect("Connection pool is poisoned.");
+        let expired = idle.remove_expired(&self.config);
+        let conns = idle.by_host.entry(host.to_string()).or_default();
+        let rejected = if conns.len() >= self.config.max_idle {
+            Some(conn)
+        } else {
+            conns.push(IdleConnection {
+                conn,
+                opened,
+                last_used: Instant::now(),
+            });
+            None
+        };
+        if !idle.reaping && !idle.by_host.is_empty() {
+            idle.reaping = true;
+            tokio::spawn(reap(Arc::downgrade(&self.idle), self.config));
+        }
+        drop(idle);
+        close_all(expired);
+        rejected
+    }
+}
+
+/// Closes the idle connections, when they expire, until the pool is empty or dropped.
+async fn reap(idle: Weak<Mutex<IdleConnections>>, config: PoolConfig) {
+    loop {
+        let next_expiry = match idle.upgrade() {
+            Some(idle) => {
+                let mut idle = idle.lock().expect("Connection pool is poisoned.");
+                let next_expiry = idle
+                    .by_host
+                    .values()
+                    .flatten()
+                    .map(|entry| entry.expires(&config))
+                    .min();
+                if next_expiry.is_none() {
+                    idle.reaping = false;
+                }
+                next_expiry
+            }
+            None => return,
+        };
+        let next_expiry = match next_expiry {
+            Some(next_expiry) => next_expiry,
+            None => return,
+        };
+        sleep_until(next_expiry.into()).await;
+        let expired = match idle.upgrade() {
+            Some(idle) => idle
+                .lock()
+                .expect("Connection pool is poisoned.")
+                .remove_expired(&config),
+            None => return,
+        };
+        close_all(expired);
+    }
+}
+
+/// Closes the connections with QUIT in the background.
+fn close_all(conns: Vec<(String, SmtpConnection)>) {
+    if conns.is_empty() {
+        return;
+    }
+    tokio::spawn(async move {
+        for (host, conn) in conns {
+            debug!("Closing expired connection to relay {}.", host);
+            if let Err(e) = conn.quit().await {
+                debug!("Could not close connection to relay {}: {}", host, e);
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maildest::relay_dest::client::SessionParams;
+
+    use tokio::io::{duplex, AsyncBufReadExt, AsyncWriteExt, BufStream};
+    use tokio::task::JoinHandle;
+
+    /// Opens a connection to a relay, that runs until the connection is closed and returns the received commands.
+    async fn connection() -> (SmtpConnection, JoinHandle<Vec<String>>) {
+        let (client, server) = duplex(4096);
+        let relay = tokio::spawn(async move {
+            let mut stream = BufStream::new(server);
+            stream
+                .write_all(b"220 relay.example.org\r\n")
+                .await
+                .unwrap();
+            stream.flush().await.unwrap();
+            let mut commands = vec![];
+            let mut line = String::new();
+            while stream.read_line(&mut line).await.unwrap_or(0) > 0 {
+                let reply: &[u8] = match line.trim_end() {
+                    "QUIT" => b"221 Bye\r\n",
+                    _ => b"250 relay.example.org\r\n",
+                };
+                commands.push(line.trim_end().to_string());
+                if stream.write_all(reply).await.is_err() || stream.flush().await.is_err() {
+                    break;
+                }
+                line.clear();
+            }
+            commands
+        });
+        let conn = SmtpConnection::open(client, "relay.example.org", &SessionParams::new())
+            .await
+            .unwrap();
+        (conn, relay)
+    }
+
+    #[tokio::test]
+    async fn test_max_idle() {
+        let pool = ConnectionPool::new(PoolConfig {
+            max_idle: 1,
+            ..PoolConfig::default()
+        });
+        let (first, _) = connection().await;
+        let (second, _) = connection().await;
+        assert!(pool
+            .put("relay.example.org", first, Instant::now())
+            .is_none());
+        assert!(pool
+            .put("relay.example.org", second, Instant::now())
+            .is_some());
+        assert!(pool.take("relay.example.org").is_some());
+        assert!(pool.take("relay.example.org").is_none());
+        assert!(pool.take("other.example.org").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_max_lifetime() {
+        let pool = ConnectionPool::new(PoolConfig {
+            max_lifetime: Duration::from_millis(100),
+            ..PoolConfig::default()
+        });
+        let (conn, _) = connection().await;
+        let opened = Instant::now() - Duration::from_millis(100);
+        assert!(pool.put("relay.example.org", conn, opened).is_some());
+
+        let (conn, relay) = connection().await;
+        let opened = Instant::now() - Duration::from_millis(50);
+        assert!(pool.put("relay.example.org", conn, opened).is_none());
+        // The connection expires, although it was used recently:
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(pool.take("relay.example.org").is_none());
+        assert_eq!(relay.await.unwrap(), vec!["EHLO localhost", "QUIT"]);
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() {
+        let pool = ConnectionPool::new(PoolConfig {
+            idle_timeout: Duration::from_millis(50),
+            ..PoolConfig::default()
+        });
+        let (conn, relay) = connection().await;
+        assert!(pool
+            .put("relay.example.org", conn, Instant::now())
+            .is_none());
+
+        // The connection is closed without further use of the pool:
+        let commands = tokio::time::timeout(Duration::from_secs(5), relay)
+            .await
+            .expect("The expired connection was not closed.")
+            .unwrap();
+        assert_eq!(commands, vec!["EHLO localhost", "QUIT"]);
+        assert!(pool.take("relay.example.org").is_none());
+    }
+}
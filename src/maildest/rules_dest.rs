@@ -0,0 +1,224 @@
+use async_trait::async_trait;
+use log::debug;
+use regex::Regex;
+
+use std::path::Path;
+
+use super::{EmailDestination, Receipt};
+use crate::email::Email;
+use crate::Error;
+
+/// The conditions of a routing rule on the attributes of an email. A rule matches, if all of its conditions are
+/// fulfilled.
+#[derive(Debug, Default)]
+pub(crate) struct RoutingRule {
+    /// The domain of an address in the From header, compared case-insensitively. Subdomains match too.
+    pub(crate) from_domain: Option<String>,
+    pub(crate) subject: Option<Regex>,
+    pub(crate) has_attachment: Option<bool>,
+    /// A pattern for the List-Id header, e.g. of newsletters and mailing lists.
+    pub(crate) list_id: Option<Regex>,
+}
+
+impl RoutingRule {
+    /// Returns true, if the rule has no conditions, so it would match every email.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.from_domain.is_none()
+            && self.subject.is_none()
+            && self.has_attachment.is_none()
+            && self.list_id.is_none()
+    }
+
+    pub(crate) fn matches(&self, email: &Email<'_>) -> bool {
+        if let Some(ref domain) = self.from_domain {
+            let from_domain = email.header_senders().iter().any(|sender| {
+                sender.rsplit_once('@').is_some_and(|(_, sender_domain)| {
+                    let sender_domain = sender_domain.to_lowercase();
+                    sender_domain == *domain || sender_domain.ends_with(&format!(".{}", domain))
+                })
+            });
+            if !from_domain {
+                return false;
+            }
+        }
+        if let Some(ref subject) = self.subject {
+            if !subject.is_match(email.subject().unwrap_or_default()) {
+                return false;
+            }
+        }
+        if let Some(has_attachment) = self.has_attachment {
+            if email.has_attachment() != has_attachment {
+                return false;
+            }
+        }
+        if let Some(ref list_id) = self.list_id {
+            let matching = email
+                .headers()
+                .filter(|(name, _)| name.as_str().eq_ignore_ascii_case("List-Id"))
+                .any(|(_, value)| list_id.is_match(value.trim()));
+            if !matching {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Delivers every email to the destination of the first routing rule, that matches it, or to the default destination,
+/// e.g. newsletters to a file and everything else to Matrix.
+///
+/// Local storage (e.g. for exports and the janitor) is the one of the default destination.
+pub(crate) struct RuleDestination {
+    rules: Vec<(RoutingRule, String, Box<dyn EmailDestination + Send + Sync>)>,
+    default: Box<dyn EmailDestination + Send + Sync>,
+}
+
+impl RuleDestination {
+    pub(crate) fn new(default: Box<dyn EmailDestination + Send + Sync>) -> Self {
+        RuleDestination {
+            rules: vec![],
+            default,
+        }
+    }
+
+    /// Adds a rule after the existing ones, with the name of the section of its destination in the config file.
+    pub(crate) fn push_rule(
+        &mut self,
+        rule: RoutingRule,
+        name: impl Into<String>,
+        destination: Box<dyn EmailDestination + Send + Sync>,
+    ) {
+        self.rules.push((rule, name.into(), destination));
+    }
+
+    /// Returns the destination for the email.
+    fn select(&self, email: &Email<'_>) -> &(dyn EmailDestination + Send + Sync) {
+        match self
+            .rules
+            .iter()
+            .enumerate()
+            .find(|(_, (rule, _, _))| rule.matches(email))
+        {
+            Some((i, (_, name, destination))) => {
+                debug!(
+                    "Routing email with id {} to destination '{}' by rule {}.",
+                    email.message_id,
+                    name,
+                    i + 1
+                );
+                destination.as_ref()
+            }
+            None => self.default.as_ref(),
+        }
+    }
+}
+
+#[async_trait]
+impl EmailDestination for RuleDestination {
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        self.select(email).write_email(email).await
+    }
+
+    async fn write_notice(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        self.select(email).write_notice(email).await
+    }
+
+    fn kind(&self) -> &'static str {
+        "rules"
+    }
+
+    fn describe(&self) -> String {
+        let mut description = self.default.describe();
+        for (_, name, destination) in self.rules.iter() {
+            description.push_str(&format!(
+                ", or by rule to '{}' ({})",
+                name,
+                destination.describe()
+            ));
+        }
+        description
+    }
+
+    fn storage_path(&self) -> Option<&Path> {
+        self.default.storage_path()
+    }
+
+    fn is_maildir(&self) -> bool {
+        self.default.is_maildir()
+    }
+
+    fn is_mbox(&self) -> bool {
+        self.default.is_mbox()
+    }
+    async fn forget(&self, address: &str, message_ids: &[String]) -> Result<Vec<String>, Error> {
+        let mut removed = self.default.forget(address, message_ids).await?;
+        for (_, _, destination) in self.rules.iter() {
+            removed.extend(destination.forget(address, message_ids).await?);
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct NamedDestination(&'static str);
+
+    #[async_trait]
+    impl EmailDestination for NamedDestination {
+        async fn write_email(&self, _email: &Email<'_>) -> Result<Receipt, Error> {
+            Ok(Receipt::new(self.0))
+        }
+
+        fn kind(&self) -> &'static str {
+            "test"
+        }
+
+        fn describe(&self) -> String {
+            self.0.to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rules() {
+        let mut destination = RuleDestination::new(Box::new(NamedDestination("matrix")));
+        destination.push_rule(
+            RoutingRule {
+                list_id: Some(Regex::new("news\\.example\\.com").unwrap()),
+                ..RoutingRule::default()
+            },
+            "newsletters",
+            Box::new(NamedDestination("newsletters")),
+        );
+        destination.push_rule(
+            RoutingRule {
+                from_domain: Some("billing.example.com".to_string()),
+                has_attachment: Some(true),
+                ..RoutingRule::default()
+            },
+            "invoices",
+            Box::new(NamedDestination("invoices")),
+        );
+
+        let write = |raw: &'static [u8]| {
+            let destination = &destination;
+            async move {
+                let email = Email::parse(raw).unwrap();
+                destination.write_email(&email).await.unwrap().reference
+            }
+        };
+        let newsletter = b"Message-ID: <1@example.com>\r\nList-Id: Weekly <weekly.news.example.com>\r\n\r\nHi\r\n";
+        assert_eq!(write(newsletter).await.as_deref(), Some("newsletters"));
+        let invoice = b"Message-ID: <2@example.com>\r\nFrom: Billing <noreply@EU.billing.example.com>\r\n\
+Content-Type: application/pdf\r\nContent-Disposition: attachment; filename=invoice.pdf\r\n\r\nJVBERi0=\r\n";
+        assert_eq!(write(invoice).await.as_deref(), Some("invoices"));
+        // Without the attachment, the second rule does not match:
+        let reminder =
+            b"Message-ID: <3@example.com>\r\nFrom: noreply@billing.example.com\r\n\r\nPlease pay.\r\n";
+        assert_eq!(write(reminder).await.as_deref(), Some("matrix"));
+        let phishing = b"Message-ID: <4@example.com>\r\nFrom: noreply@fakebilling.example.com\r\n\
+Content-Disposition: attachment; filename=invoice.pdf\r\n\r\nJVBERi0=\r\n";
+        assert_eq!(write(phishing).await.as_deref(), Some("matrix"));
+    }
+}
@@ -0,0 +1,277 @@
+use async_trait::async_trait;
+use log::{info, warn};
+use serde_json::{json, Value};
+
+use std::time::Duration;
+
+use super::{EmailDestination, Receipt};
+use crate::email::Email;
+use crate::secret::Secret;
+use crate::Error;
+
+/// The default number of retries of a request, that failed with a server error.
+const DEFAULT_RETRIES: u32 = 2;
+/// The delay before the first retry. It doubles with every further retry.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Posts every email as JSON object to an HTTP endpoint, e.g. a serverless function.
+///
+/// The object contains the message ID, the envelope (as in the envelope files of file destinations), the headers, the
+/// subject, the text and HTML bodies, a description of every leaf of the MIME tree (e.g. attachments) and the complete
+/// email encoded with base64 in the field "raw".
+///
+/// Requests failing with a server error (5xx) or without a response are retried a few times with increasing delays,
+/// before the delivery fails with a temporary error, so it is retried by the delivery queue later. If the endpoint
+/// rejects the bearer token (401), the token is read from its file again and the request is repeated once, if it was
+/// rotated.
+pub(crate) struct WebhookDestination {
+    url: String,
+    bearer_token: Option<Secret>,
+    client: reqwest::Client,
+    retries: u32,
+    retry_delay: Duration,
+}
+
+impl WebhookDestination {
+    pub(crate) fn new(url: impl Into<String>) -> Self {
+        WebhookDestination {
+            url: url.into(),
+            bearer_token: None,
+            client: reqwest::Client::new(),
+            retries: DEFAULT_RETRIES,
+            retry_delay: RETRY_DELAY,
+        }
+    }
+
+    /// Sets the token sent in the Authorization header.
+    pub(crate) fn set_bearer_token(&mut self, token: impl Into<Secret>) {
+        self.bearer_token = Some(token.into());
+    }
+
+    /// Sets the number of retries after server errors.
+    pub(crate) fn set_retries(&mut self, retries: u32) {
+        self.retries = retries;
+    }
+
+    /// Sets the time, after which a request is aborted.
+    pub(crate) fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .map_err(|e| Error::config(format!("Could not create HTTP client: {}", e)))?;
+        Ok(())
+    }
+
+    fn payload(email: &Email<'_>) -> Value {
+        let headers: Vec<Value> = email
+            .headers()
+            .map(|(name, value)| json!({ "name": name.as_str(), "value": value.trim() }))
+            .collect();
+        let text: Vec<&str> = email
+            .text_body_parts()
+            .map(|part| part.get_text_contents())
+            .collect();
+        let html: Vec<&str> = email
+            .html_body_parts()
+            .map(|part| part.get_text_contents())
+            .collect();
+        let tree = email.mime_tree();
+        let parts: Vec<Value> = tree
+            .walk()
+            .into_iter()
+            .filter(|part| part.children.is_empty())
+            .map(|part| {
+                json!({
+                    "path": part.path,
+                    "content_type": part.content_type(),
+                    "disposition": part.disposition(),
+                    "filename": part.filename(),
+                    "content_id": part.content_id(),
+                    "size": part.contents().len(),
+                })
+            })
+            .collect();
+        json!({
+            "message_id": email.message_id,
+            "envelope": email.envelope.as_ref().map(|envelope| envelope.to_json()),
+            "headers": headers,
+            "subject": email.subject(),
+            "text": text,
+            "html": html,
+            "parts": parts,
+            "raw": base64::encode(email.raw),
+        })
+    }
+
+    /// Reads the bearer token from its file again and returns true, if it changed.
+    fn reload_token(&self) -> bool {
+        match self.bearer_token.as_ref().map(Secret::reload) {
+            Some(Ok(changed)) => changed,
+            Some(Err(e)) => {
+                warn!(
+                    "Could not reload bearer token of webhook {}: {}",
+                    self.url, e
+                );
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Sends the payload once and returns the status of the response.
+    async fn post(&self, payload: &Value) -> Result<u16, Error> {
+        let mut request = self.client.post(&self.url).json(payload);
+        if let Some(ref token) = self.bearer_token {
+            request = request.bearer_auth(token.get());
+        }
+        let response = request.send().await.map_err(|e| {
+            Error::http(None, format!("Could not post email to {}: {}", self.url, e))
+        })?;
+        let status = response.status();
+        if status.is_success() {
+            Ok(status.as_u16())
+        } else {
+            Err(Error::http(
+                Some(status.as_u16()),
+                format!("Webhook {} responded with {}.", self.url, status),
+            ))
+        }
+    }
+}
+
+#[async_trait]
+impl EmailDestination for WebhookDestination {
+    async fn write_email(&self, email: &Email<'_>) -> Result<Receipt, Error> {
+        let payload = Self::payload(email);
+        let mut delay = self.retry_delay;
+        let mut retries = 0;
+        let mut reloaded = false;
+        loop {
+            match self.post(&payload).await {
+                Ok(status) => {
+                    info!(
+                        "Posted email with id {} to webhook {}.",
+                        email.message_id, self.url
+                    );
+                    return Ok(Receipt::new(format!("{}: {}", self.url, status)));
+                }
+                Err(e) if e.is_auth_failure() && !reloaded && self.reload_token() => {
+                    warn!("{} Retrying with the rotated bearer token.", e);
+                    reloaded = true;
+                }
+                Err(e) if e.is_temporary() && retries < self.retries => {
+                    warn!("{} Retrying in {} seconds.", e, delay.as_secs_f32());
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn kind(&self) -> &'static str {
+        "webhook"
+    }
+
+    fn describe(&self) -> String {
+        format!("HTTP webhook {}", self.url)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::email::Envelope;
+
+    use wiremock::matchers::{body_partial_json, header, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use std::sync::Arc;
+
+    const TEST_EMAIL: &[u8] =
+        b"Message-ID: <hook@example.com>\r\nSubject: Disk full\r\n\r\n/var is full.\r\n";
+
+    #[tokio::test]
+    async fn test_post() {
+        let server = MockServer::start().await;
+        // The first request fails with a server error and is retried:
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .with_priority(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(header("Authorization", "Bearer s3cr3t"))
+            .and(body_partial_json(json!({
+                "message_id": "hook@example.com",
+                "envelope": { "mail_from": "cron@example.org" },
+                "subject": "Disk full",
+                "text": ["/var is full.\r\n"],
+                "parts": [{ "path": [], "content_type": "text/plain", "size": 15 }],
+                "raw": base64::encode(TEST_EMAIL),
+            })))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut destination = WebhookDestination::new(server.uri());
+        destination.set_bearer_token("s3cr3t");
+        destination.retry_delay = Duration::from_millis(10);
+        let mut email = Email::parse(TEST_EMAIL).unwrap();
+        email.envelope = Some(Arc::new(Envelope {
+            mail_from: Some("cron@example.org".to_string()),
+            rcpt_to: vec!["root@example.com".to_string()],
+            client: Default::default(),
+            received_at: 0,
+            require_tls: false,
+        }));
+        let receipt = destination.write_email(&email).await.unwrap();
+        assert_eq!(receipt.reference.unwrap(), format!("{}: 204", server.uri()));
+    }
+
+    #[tokio::test]
+    async fn test_client_error() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(400))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let destination = WebhookDestination::new(server.uri());
+        let email = Email::parse(TEST_EMAIL).unwrap();
+        let e = destination.write_email(&email).await.unwrap_err();
+        assert!(!e.is_temporary());
+        assert_eq!(e.code(), "http.status");
+    }
+
+    #[tokio::test]
+    async fn test_rotated_token() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(header("Authorization", "Bearer new"))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .respond_with(ResponseTemplate::new(401))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        std::fs::write(&path, "old\n").unwrap();
+        let mut destination = WebhookDestination::new(server.uri());
+        destination.set_bearer_token(Secret::from_file(&path).unwrap());
+        // The token is rotated after the start:
+        std::fs::write(&path, "new\n").unwrap();
+        let email = Email::parse(TEST_EMAIL).unwrap();
+        destination.write_email(&email).await.unwrap();
+    }
+}
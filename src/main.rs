@@ -1,242 +1,394 @@
-use log::{error, info, warn, LevelFilter};
-use log4rs::{
-    append::console::ConsoleAppender,
-    config::{Appender, Config, Root},
-};
-use users::switch::{set_effective_gid, set_effective_uid};
+use log::{debug, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
 
-use std::{collections::VecDeque, env::args, fmt, io, process::ExitCode, sync::Arc};
+use std::{
+    collections::VecDeque,
+    env::{self, args},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
 
+use cli::{Args, Command};
+pub(crate) use error::Error;
+use error::{SmtpError, SmtpErrorCode};
+use ha::Lease;
+use report::report_error;
 use smtp_server::SmtpServer;
+use startup::{Phase, Resources, StartupError};
 
+mod address_matcher;
+mod api_token;
+mod attachment_store;
+mod audit;
+mod bind_check;
+mod bounce;
+mod budget;
+mod cli;
 mod config;
+mod control;
+mod dispatch;
+mod dns;
 mod email;
+mod error;
+mod feed;
+mod ha;
+mod i18n;
+mod janitor;
+mod logging;
 mod maildest;
+mod mapping;
+mod metrics;
+mod proxy;
+mod queue;
+mod redaction;
+mod redis;
+mod report;
+mod secret;
+mod self_test;
+mod severity;
+mod shared_state;
 mod smtp_server;
+mod startup;
+mod state_lock;
+mod stats;
+mod supervisor;
+mod tenant;
+mod thread_index;
+mod watchdog;
+
+/// The delay before the first restart of a crashed accept loop.
+const ACCEPT_LOOP_BACKOFF: Duration = Duration::from_secs(1);
+/// The interval, in which the shutdown checks whether all sessions and deliveries are finished.
+const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(100);
 
 #[tokio::main]
 async fn main() -> ExitCode {
-    let config = match config::Config::with_args(
-        args().skip_while(|s| s.ends_with("kutsche") && !s.starts_with('-')),
-    )
-    .await
-    {
-        Ok(c) => c,
+    let args = match Args::parse(args().skip(1)) {
+        Ok(args) => args,
         Err(e) => {
-            eprintln!("Error while loading configuration: {}", &e);
-            error!("Could not load configuration: {}", e);
+            eprintln!("Invalid command line: {}", e);
             return ExitCode::from(1);
         }
     };
 
-    if let Err(e) = init_logger(&config) {
-        eprintln!("Error while initializing logger: {}", &e);
-        error!("Could not initialize logger: {}", e);
-        return ExitCode::from(2);
+    // These commands work on the config file itself, which may not exist or be loadable by this version:
+    let config_path = args.config_path().to_string();
+    match args.command {
+        Command::Init { output, options } => {
+            return cli::init::run(output.as_deref().unwrap_or(&config_path), options)
+        }
+        Command::MigrateConfig { ref output } => {
+            return cli::migrate::run(&config_path, output.as_deref())
+        }
+        Command::CreateToken {
+            ref name,
+            ref scopes,
+        } => return cli::token::run(name, scopes),
+        Command::CreateRegistration {
+            ref sender,
+            ref id,
+            ref user_prefix,
+        } => return cli::registration::run(sender, id.as_deref(), user_prefix.as_deref()),
+        _ => {}
     }
 
-    // TODO: Refactor to filter_map when async closures become stable (issue 62290)
-    let mut smtp_servers = Vec::new();
-    for addr in config.local_addrs.iter() {
-        match SmtpServer::new(addr, config.tls_config.clone()).await {
-            Ok(server) => {
-                log::info!("Startet server bound to {}", addr);
-                smtp_servers.push(server);
-            }
-            Err(e) => {
-                eprintln!(
-                    "Error while starting server for local address {}: {}",
-                    addr, &e
-                );
-                error!("Could not start server for local address {}: {}", addr, e);
-            }
+    // Without a config file on the command line, the config may be given in the environment:
+    let config = match (&args.config_path, env::var(config::CONFIG_ENV)) {
+        (None, Ok(value)) => config::Config::load_inline(&value).await,
+        _ => config::Config::load(&config_path).await,
+    };
+    // The other commands don't have startup phases, so their errors are reported without one:
+    let fail = |exit_code: u8, message: String| match args.command {
+        Command::Serve { .. } => StartupError::new(Phase::ParseConfig, exit_code, message).report(),
+        _ => {
+            report_error!("{}", message);
+            ExitCode::from(exit_code)
         }
+    };
+    let config = match config {
+        Ok(c) => c,
+        Err(e) => return fail(1, format!("Could not load configuration: {}", e)),
+    };
+
+    if let Err(e) = logging::init(&config.logging) {
+        return fail(2, format!("Could not initialize logger: {}", e));
+    }
+    for warning in config.warnings.iter() {
+        warn!("{}", warning);
     }
-    if smtp_servers.is_empty() {
-        eprintln!("Starting server failed for all local addresses.");
-        error!("Could not start server for any local address.");
-        return ExitCode::from(3);
-    } else {
-        info!("Started {} SMTP servers.", smtp_servers.len());
+    info!("Loaded {} mappings.", config.mappings().count());
+    // The user of the other commands watches the terminal rather than the log:
+    if !matches!(args.command, Command::Serve { .. }) {
+        report::always_to_stderr();
     }
 
-    // Dropping privileges:
-    if let Some(user) = &config.effective_user {
-        info!("Changing effective user ID to {}...", user.uid());
-        if let Err(e) = set_effective_uid(user.uid()) {
-            eprintln!("Error while changing effective user: {}", &e);
-            error!("Could not change effective user: {}", e);
-            return ExitCode::from(4);
+    match args.command {
+        Command::Serve { dry_startup } => serve(Arc::new(config), dry_startup).await,
+        Command::Replay { target, recipients } => {
+            cli::replay::run(&config, &target, recipients).await
         }
-    }
-    if let Some(group) = &config.effective_group {
-        info!("Changing effective group ID to {}...", group.gid());
-        if let Err(e) = set_effective_gid(group.gid()) {
-            eprintln!("Error while changing effective group: {}", &e);
-            error!("Could not change effective group: {}", e);
-            return ExitCode::from(5);
+        Command::Init { .. }
+        | Command::MigrateConfig { .. }
+        | Command::CreateToken { .. }
+        | Command::CreateRegistration { .. } => {
+            unreachable!("Handled before loading the config.")
+        }
+        Command::Route { address, from } => cli::route::run(&config, &address, from.as_deref()),
+        Command::Export { mapping, output } => cli::export::run(&config, &mapping, &output),
+        Command::Import {
+            source,
+            mapping,
+            recipients,
+        } => cli::import::run(&config, &source, mapping.as_deref(), recipients).await,
+        Command::Search { query, mapping } => cli::search::run(&config, &query, mapping.as_deref()),
+        Command::Ctl { command, token } => cli::ctl::run(&config, &command, token.as_deref()),
+        Command::VerifyAuditLog { public_key } => cli::verify::run(&config, public_key.as_deref()),
+        Command::Backup { archive, token } => {
+            cli::backup::backup(&config, &archive, token.as_deref())
         }
+        Command::Restore { archive } => cli::backup::restore(&config, &archive),
+        Command::Forget { address } => cli::forget::run(&config, &address).await,
+    }
+}
+
+/// Runs the SMTP servers until a shutdown signal is received. With `dry_startup`, the server stops after starting the
+/// destinations instead of accepting connections.
+async fn serve(config: Arc<config::Config>, dry_startup: bool) -> ExitCode {
+    info!("Effective configuration: {}", config.summary());
+    let mut resources = match startup::open_resources(&config) {
+        Ok(resources) => resources,
+        Err(e) => return e.report(),
+    };
+    let (smtp_servers, control_socket) = match startup::bind_listeners(&config, &resources).await {
+        Ok(listeners) => listeners,
+        Err(e) => return e.report(),
+    };
+    if let Err(e) = startup::drop_privileges(&config) {
+        return e.report();
     }
-    if config.effective_user.is_some() || config.effective_group.is_some() {
-        info!("Dropped privileges.");
+    let stats_file = startup::start_destinations(&config, &mut resources, control_socket);
+    let Resources {
+        audit_log, queue, ..
+    } = resources;
+    if dry_startup {
+        info!("Dry startup succeeded, stopping without accepting connections.");
+        return ExitCode::SUCCESS;
     }
 
-    info!("Accepting connections...");
-    let config = Arc::new(config);
+    Phase::Accept.begin();
+    // In active/passive mode the listeners wait for the lease:
+    let lease_task = match (&config.ha_node, &config.state_dir) {
+        (Some(node), Some(state_dir)) => {
+            for server in smtp_servers.iter() {
+                server.set_paused(true);
+            }
+            info!("Waiting for the lease as node '{}'...", node);
+            let lease = Lease::new(state_dir, node.as_str(), config.ha_lease);
+            let config = config.clone();
+            Some(tokio::spawn(ha::run(
+                lease,
+                smtp_servers.clone(),
+                queue.clone(),
+                move |name| config.mapping(name).cloned(),
+            )))
+        }
+        _ => None,
+    };
     // TODO: As soon as tokio::task::JoinSet is stabilized: replace the task_lists
     let mut server_task_list = vec![];
-    for server in smtp_servers {
-        let config_ref = config.clone();
-        let server_ref = Arc::new(server);
-        server_task_list.push(tokio::spawn(async move {
-            // TODO: As soon as tokio::task::JoinSet is stabilized: replace the task_lists
-            let mut conn_task_list = VecDeque::new();
-            loop {
-                let (stream, addr) = match server_ref.accept_conn().await {
-                    Err(e) => {
-                        eprintln!("Error while accepting TCP connection: {}", &e);
-                        error!("Could not accept TCP connection: {}", e);
-                        continue;
-                    }
-                    Ok((stream, addr)) => {
-                        info!("Accepted incoming TCP connection.");
-                        (stream, addr)
-                    }
-                };
-                let config = config_ref.clone();
-                let server = server_ref.clone();
-                conn_task_list.push_back(tokio::spawn(async move {
-                    let mut buf = Vec::new();
-                    match server.recv_mail(stream, addr, &mut buf).await {
-                        Ok(email) => {
-                            for addr in email.to {
-                                if let Some(dest) = config.dest_map.get(AsRef::<str>::as_ref(&addr))
-                                {
-                                    if let Err(e) = dest.write_email(&email.content).await {
-                                        eprintln!("Error while forwarding email: {}", &e);
-                                        error!("Could not forward email: {}", e);
-                                    }
-                                } else {
-                                    warn!("Received an email without a destination mapping.");
-                                }
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("Error while receiving email: {}", &e);
-                            error!("Could not receive mail: {}", e);
-                        }
-                    }
-                }));
-
-                // Remove finished tasks from the conn_task_list list to prevent it from growing invinitely:
-                while conn_task_list.front().is_some()
-                    && conn_task_list.front().unwrap().is_finished()
-                {
-                    if conn_task_list.pop_front().unwrap().await.is_err() {
-                        eprintln!("Error while joining the connection tasks: Task panicked.");
-                        error!("One of the connection tasks panicked.");
-                    }
+    for server in smtp_servers.iter() {
+        // Listeners with SO_REUSEPORT have one socket and accept loop per acceptor:
+        for acceptor in 0..server.acceptors() {
+            let server_ref = server.clone();
+            let mut name = match server.local_addr() {
+                Ok(addr) => format!("accepting connections on {}", addr),
+                Err(_) => "accepting connections".to_string(),
+            };
+            if server.acceptors() > 1 {
+                name.push_str(&format!(" (acceptor {})", acceptor));
+            }
+            server_task_list.push(tokio::spawn(async move {
+                supervisor::supervise(
+                    &name,
+                    || tokio::spawn(accept_loop(server_ref.clone(), acceptor)),
+                    ACCEPT_LOOP_BACKOFF,
+                )
+                .await
+            }));
+        }
+    }
+    let lease_lost = async {
+        match lease_task {
+            Some(task) => {
+                let _ = task.await;
+            }
+            None => std::future::pending().await,
+        }
+    };
+    let signal = tokio::select! {
+        signal = shutdown_signal() => signal,
+        _ = lease_lost => {
+            // The other server may take over any moment, so no new deliveries are started, and the running ones only
+            // get a part of the remaining lease to finish. All are left in the spool for the other server, which
+            // retries the interrupted ones (see `Spool::mark_attempted()`).
+            report_error!("Stopping, because this server lost the lease.");
+            queue.stop();
+            for server in smtp_servers.iter() {
+                server.set_paused(true);
+            }
+            let finished = async {
+                while queue.running() > 0 {
+                    tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
                 }
+            };
+            if tokio::time::timeout(config.ha_lease / 6, finished).await.is_err() {
+                warn!("Interrupted {} running deliveries.", queue.running());
             }
-            #[allow(unreachable_code)]
-            // This code will be necessary, when we implement a gracefull shutdown and replace the loop with a while.
-            for handle in conn_task_list.into_iter() {
-                if handle.await.is_err() {
-                    eprintln!("Error while joining the connection tasks: Task panicked.");
-                    error!("One of the connection tasks panicked.");
+            return ExitCode::from(10);
+        }
+    };
+    match signal {
+        Ok(()) => {
+            info!(
+                "Shutting down within {} seconds, new connections are answered with 421...",
+                config.shutdown_grace.as_secs()
+            );
+            for server in smtp_servers.iter() {
+                server.drain();
+            }
+            let busy = || {
+                let sessions: usize = smtp_servers.iter().map(|s| s.active_sessions()).sum();
+                (sessions, !queue.is_idle())
+            };
+            let idle = async {
+                while busy() != (0, false) {
+                    tokio::time::sleep(SHUTDOWN_POLL_INTERVAL).await;
                 }
+            };
+            if tokio::time::timeout(config.shutdown_grace, idle)
+                .await
+                .is_err()
+            {
+                let (sessions, delivering) = busy();
+                warn!(
+                    "Shutting down with {} running sessions{}, because the grace period is over.",
+                    sessions,
+                    if delivering {
+                        " and running deliveries"
+                    } else {
+                        ""
+                    }
+                );
             }
-        }));
+            for server in smtp_servers.iter() {
+                server.close();
+            }
+        }
+        Err(e) => {
+            report_error!("Could not listen for shutdown signals: {}", e);
+        }
     }
     for handle in server_task_list.into_iter() {
         if handle.await.is_err() {
-            eprintln!("Error while joining the server tasks: Task panicked.");
-            error!("One of the server tasks panicked.");
+            report_error!("One of the server tasks panicked.");
         }
     }
 
-    ExitCode::SUCCESS
-}
-
-fn init_logger(_conf: &config::Config) -> Result<(), Error> {
-    let stdout = ConsoleAppender::builder().build();
-
-    let config = Config::builder()
-        .appender(Appender::builder().build("stdout", Box::new(stdout)))
-        .build(Root::builder().appender("stdout").build(LevelFilter::Info))?;
-
-    log4rs::init_config(config)?;
-
-    Ok(())
-}
-
-#[derive(Debug)]
-pub(crate) enum Error {
-    Config(String),
-    MailParsing(&'static str),
-    Matrix(String),
-    Smtp(String),
-    SysIo(io::Error),
-    Tls(rustls::Error),
-}
-
-impl fmt::Display for Error {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use Error::*;
-
-        match self {
-            Config(desc) => write!(f, "Error in config: {}", desc),
-            MailParsing(desc) => write!(f, "Could not parse email: {}", desc),
-            Matrix(desc) => write!(f, "Error in Matrix communication: {}", desc),
-            Smtp(desc) => write!(f, "Error in SMTP communication: {}", desc),
-            SysIo(inner) => write!(f, "IO error: {}", inner),
-            Tls(inner) => write!(f, "TLS error: {}", inner),
+    if let Some(stats_file) = stats_file {
+        if let Err(e) = stats_file.save() {
+            report_error!("Could not save delivery stats: {}", e);
         }
     }
-}
-
-impl From<io::Error> for Error {
-    fn from(inner: io::Error) -> Self {
-        Self::SysIo(inner)
+    // The entries of the last interval are signed, so they don't stay unprotected until the next start:
+    if let Some(ref audit_log) = audit_log {
+        if let Err(e) = audit_log.sign() {
+            report_error!("Could not sign audit log: {}", e);
+        }
     }
-}
-impl From<rustls::Error> for Error {
-    fn from(inner: rustls::Error) -> Self {
-        Self::Tls(inner)
+    let queued = queue.len();
+    if queued > 0 {
+        warn!("Shut down with {} undelivered emails in the queue.", queued);
+    }
+    let waiting = queue.waiting();
+    if waiting > 0 {
+        if queue.is_spooled() {
+            warn!(
+                "Shut down with {} emails held or waiting for a retry in the spool.",
+                waiting
+            );
+        } else {
+            warn!("Shut down with {} emails held or waiting for a retry, which are lost without a spool.", waiting);
+        }
     }
+    info!("Shut down.");
+    ExitCode::SUCCESS
 }
-impl From<log4rs::config::runtime::ConfigErrors> for Error {
-    fn from(inner: log4rs::config::runtime::ConfigErrors) -> Self {
-        match inner.errors().first() {
-            Some(log4rs::config::runtime::ConfigError::DuplicateAppenderName(descr)) => {
-                Self::Config(format!(
-                    "Duplicate Appender name in logger configuration: {}",
-                    descr
-                ))
+
+/// Accepts connections on the listening socket `acceptor` of `server` and receives emails from them, until the server
+/// is closed.
+/// If this panics, it is restarted by a supervisor. The listening socket is owned by the server and outlives this task,
+/// so it doesn't need to be bound again.
+async fn accept_loop(server: Arc<SmtpServer>, acceptor: usize) {
+    // TODO: As soon as tokio::task::JoinSet is stabilized: replace the task_lists
+    let mut conn_task_list = VecDeque::new();
+    loop {
+        let accepted = tokio::select! {
+            accepted = server.accept_conn(acceptor) => accepted,
+            _ = server.closed() => break,
+        };
+        let (stream, addr) = match accepted {
+            Err(e) => {
+                report_error!("Could not accept TCP connection: {}", e);
+                continue;
+            }
+            Ok((stream, addr)) => {
+                info!("Accepted incoming TCP connection.");
+                (stream, addr)
+            }
+        };
+        let server = server.clone();
+        conn_task_list.push_back(tokio::spawn(async move {
+            let mut buf = Vec::new();
+            match server.recv_mail(stream, addr, &mut buf).await {
+                Ok(email) => {
+                    debug!("Received email with id {}.", email.content.message_id);
+                }
+                Err(
+                    e @ Error::Smtp(SmtpError {
+                        code: SmtpErrorCode::Refused,
+                        ..
+                    }),
+                ) => {
+                    info!("{}", e);
+                }
+                Err(e) => {
+                    report_error!("Could not receive mail: {}", e);
+                }
+            }
+        }));
+
+        // Remove finished tasks from the conn_task_list list to prevent it from growing invinitely:
+        while conn_task_list.front().is_some() && conn_task_list.front().unwrap().is_finished() {
+            if conn_task_list.pop_front().unwrap().await.is_err() {
+                report_error!("One of the connection tasks panicked.");
             }
-            Some(log4rs::config::runtime::ConfigError::NonexistentAppender(descr)) => Self::Config(
-                format!("Nonexistent Appender in logger configuration: {}", descr),
-            ),
-            Some(log4rs::config::runtime::ConfigError::DuplicateLoggerName(descr)) => Self::Config(
-                format!("Duplicate Logger name in logger configuration: {}", descr),
-            ),
-            Some(log4rs::config::runtime::ConfigError::InvalidLoggerName(descr)) => Self::Config(
-                format!("Invalid Logger name in logger configuration: {}", descr),
-            ),
-            _ => Self::Config("Error in logger configuration.".to_string()),
         }
     }
-}
-impl From<log::SetLoggerError> for Error {
-    fn from(inner: log::SetLoggerError) -> Self {
-        Self::Config(format!("Error while setting logger: {}", inner))
+    for handle in conn_task_list.into_iter() {
+        if handle.await.is_err() {
+            report_error!("One of the connection tasks panicked.");
+        }
     }
 }
-impl From<matrix_sdk::Error> for Error {
-    fn from(inner: matrix_sdk::Error) -> Self {
-        match inner {
-            matrix_sdk::Error::Io(e) => Error::SysIo(e),
-            other => Error::Matrix(format!("{}", other)),
-        }
+
+/// Waits for SIGTERM or SIGINT.
+async fn shutdown_signal() -> Result<(), Error> {
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = sigint.recv() => {}
     }
+    Ok(())
 }
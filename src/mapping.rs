@@ -0,0 +1,399 @@
+use log::{error, info, warn};
+use regex::Regex;
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use crate::audit::AuditLog;
+use crate::email::Email;
+use crate::maildest::{EmailDestination, Receipt};
+use crate::stats::DeliveryStats;
+use crate::tenant::Tenant;
+use crate::Error;
+
+/// A mapping section from the config file: The destination for a set of recipient addresses and the options applied
+/// to emails delivered there.
+pub(crate) struct Mapping {
+    pub(crate) name: String,
+    pub(crate) destination: Box<dyn EmailDestination + Send + Sync>,
+    /// The destination, that emails are written to, if writing them to the destination fails, e.g. a local directory
+    /// for a Matrix room, so emails are not lost while the homeserver is down.
+    pub(crate) fallback: Option<Box<dyn EmailDestination + Send + Sync>>,
+    /// Emails for mappings with a higher priority are delivered first.
+    pub(crate) priority: u8,
+    /// Emails are delivered before the end of DATA is acknowledged instead of being queued.
+    pub(crate) synchronous_delivery: bool,
+    /// The tenant, whose config file contains the mapping.
+    pub(crate) tenant: Option<Arc<Tenant>>,
+    /// The local addresses of the listeners, that may receive emails for this mapping. If empty, all listeners may.
+    pub(crate) listeners: Vec<SocketAddr>,
+    /// Conditions, that the headers of an email must fulfill in addition to a matching recipient address. If empty,
+    /// the mapping applies to all emails for its addresses.
+    pub(crate) headers: Vec<HeaderCondition>,
+    /// What happens to automatic replies like vacation replies, e.g. so they don't spam a Matrix room.
+    pub(crate) auto_replies: AutoReplyHandling,
+    /// The number of days, after which emails stored by the file destination are deleted by the janitor. If None,
+    /// emails are kept forever.
+    pub(crate) retention_days: Option<u64>,
+    /// Emails of this mapping are under legal hold, so the janitor never deletes them regardless of their age.
+    pub(crate) legal_hold: bool,
+    /// The counters of deliveries, that are kept across restarts in the state directory.
+    pub(crate) stats: DeliveryStats,
+    /// The number of deliveries, in which the destination panicked.
+    panics: AtomicU64,
+}
+
+/// How a mapping handles automatic replies (see `Email::is_auto_reply()`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub(crate) enum AutoReplyHandling {
+    /// Automatic replies are delivered like other emails.
+    #[default]
+    Deliver,
+    /// Automatic replies are delivered in a form, that does not draw attention, e.g. as `m.notice` in Matrix.
+    Notice,
+    /// Automatic replies are dropped without delivery.
+    Suppress,
+}
+
+impl FromStr for AutoReplyHandling {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "deliver" => Ok(AutoReplyHandling::Deliver),
+            "notice" => Ok(AutoReplyHandling::Notice),
+            "suppress" => Ok(AutoReplyHandling::Suppress),
+            _ => Err(Error::config(format!(
+                "Unknown handling of automatic replies '{}' (expected deliver, notice or suppress).",
+                s
+            ))),
+        }
+    }
+}
+
+/// A condition on a header of emails, e.g. on the X-Device-Id header set by a fleet of devices, that all send from the
+/// same address.
+pub(crate) struct HeaderCondition {
+    /// The name of the header, which is compared case-insensitively.
+    pub(crate) name: String,
+    pub(crate) pattern: Regex,
+}
+
+impl HeaderCondition {
+    /// Returns true, if any header of the email with the name of the condition matches its pattern.
+    fn matches(&self, email: &Email<'_>) -> bool {
+        email
+            .headers()
+            .filter(|(name, _)| name.as_str().eq_ignore_ascii_case(&self.name))
+            .any(|(_, value)| self.pattern.is_match(value.trim()))
+    }
+}
+
+impl Mapping {
+    pub(crate) fn new(
+        name: impl Into<String>,
+        destination: Box<dyn EmailDestination + Send + Sync>,
+    ) -> Self {
+        Mapping {
+            name: name.into(),
+            destination,
+            fallback: None,
+            priority: 0,
+            synchronous_delivery: false,
+            tenant: None,
+            listeners: vec![],
+            headers: vec![],
+            auto_replies: AutoReplyHandling::default(),
+            retention_days: None,
+            legal_hold: false,
+            stats: DeliveryStats::default(),
+            panics: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of deliveries since the start, in which the destination panicked.
+    pub(crate) fn panics(&self) -> u64 {
+        self.panics.load(Ordering::Relaxed)
+    }
+
+    /// Returns true, if emails received by the listener bound to `listener` may be delivered to this mapping. Emails,
+    /// that were not received by a listener (e.g. replayed ones), are always allowed.
+    pub(crate) fn accepts_from(&self, listener: Option<SocketAddr>) -> bool {
+        match listener {
+            Some(listener) => self.listeners.is_empty() || self.listeners.contains(&listener),
+            None => true,
+        }
+    }
+
+    /// Returns true, if the email fulfills all header conditions of this mapping.
+    pub(crate) fn matches_headers(&self, email: &Email<'_>) -> bool {
+        self.headers
+            .iter()
+            .all(|condition| condition.matches(email))
+    }
+
+    /// Writes the email to the destination of this mapping and records the receipt in the audit log, if one is given.
+    /// Automatic replies are suppressed or written as notice, if the mapping is configured so. If writing to the
+    /// destination fails, the email is written to the fallback destination, if there is one, and the error is only
+    /// returned, if that fails too.
+    ///
+    /// A panic of the destination is returned as a temporary `Error::Panic`, so a bug in a destination implementation
+    /// (or a library it uses) only fails this delivery instead of the task it runs in.
+    pub(crate) async fn deliver(
+        &self,
+        email: &Email<'_>,
+        audit_log: Option<&AuditLog>,
+    ) -> Result<(), Error> {
+        let auto_reply = self.auto_replies != AutoReplyHandling::Deliver && email.is_auto_reply();
+        if auto_reply && self.auto_replies == AutoReplyHandling::Suppress {
+            info!(
+                email_message_id:% = email.message_id, mapping:% = self.name;
+                "Suppressed automatic reply with id {} for mapping '{}'.",
+                email.message_id, self.name
+            );
+            return Ok(());
+        }
+        let receipt = match self
+            .write(self.destination.as_ref(), email, auto_reply)
+            .await
+        {
+            Ok(receipt) => receipt,
+            Err(e) => {
+                let fallback = match self.fallback {
+                    Some(ref fallback) => fallback,
+                    None => {
+                        self.stats.record_failure();
+                        return Err(e);
+                    }
+                };
+                warn!(
+                    email_message_id:% = email.message_id, mapping:% = self.name;
+                    "Writing email with id {} to fallback destination of mapping '{}', because the delivery failed: {}",
+                    email.message_id, self.name, e
+                );
+                match self.write(fallback.as_ref(), email, auto_reply).await {
+                    Ok(receipt) => Receipt {
+                        reference: Some(match receipt.reference {
+                            Some(reference) => format!("fallback: {}", reference),
+                            None => "fallback".to_string(),
+                        }),
+                    },
+                    Err(fallback_e) => {
+                        error!(
+                            email_message_id:% = email.message_id, mapping:% = self.name;
+                            "Could not write email with id {} to fallback destination of mapping '{}': {}",
+                            email.message_id, self.name, fallback_e
+                        );
+                        self.stats.record_failure();
+                        return Err(e);
+                    }
+                }
+            }
+        };
+        info!(
+            email_message_id:% = email.message_id, mapping:% = self.name;
+            "Delivered email with id {} for mapping '{}'.",
+            email.message_id, self.name
+        );
+        self.stats.record_delivery(email.raw.len());
+        if let Some(audit_log) = audit_log {
+            if let Err(e) =
+                audit_log.delivered(&email.message_id, &self.name, receipt.reference.as_deref())
+            {
+                error!("Could not record delivery in audit log: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the email to `destination`, as notice, if `notice` is true, and turns a panic into an error.
+    async fn write(
+        &self,
+        destination: &(dyn EmailDestination + Send + Sync),
+        email: &Email<'_>,
+        notice: bool,
+    ) -> Result<Receipt, Error> {
+        let write = if notice {
+            destination.write_notice(email)
+        } else {
+            destination.write_email(email)
+        };
+        match CatchUnwind(write).await {
+            Ok(res) => res.map_err(|e| e.in_mapping(&self.name)),
+            Err(payload) => {
+                let panics = self.panics.fetch_add(1, Ordering::Relaxed) + 1;
+                let e = Error::panic(payload.as_ref()).in_mapping(&self.name);
+                error!(
+                    email_message_id:% = email.message_id, mapping:% = self.name;
+                    "Destination panicked while delivering email with id {} ({} panics for mapping '{}' so far): {}",
+                    email.message_id, panics, self.name, e
+                );
+                Err(e)
+            }
+        }
+    }
+}
+
+/// Resolves to the output of the wrapped future or to the payload of a panic while polling it.
+struct CatchUnwind<F>(F);
+
+impl<F: Future + Unpin> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, Box<dyn std::any::Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The future is not polled again after a panic, so no broken state can be observed:
+        match catch_unwind(AssertUnwindSafe(|| Pin::new(&mut self.0).poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Metrics;
+    use async_trait::async_trait;
+
+    struct PanickingDestination;
+
+    #[async_trait]
+    impl EmailDestination for PanickingDestination {
+        async fn write_email(&self, _email: &Email<'_>) -> Result<Receipt, Error> {
+            tokio::task::yield_now().await;
+            panic!("Bug in destination.");
+        }
+
+        fn kind(&self) -> &'static str {
+            "test"
+        }
+
+        fn describe(&self) -> String {
+            "a panicking destination".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_panicking_destination() {
+        let mapping = Arc::new(Mapping::new("buggy", Box::new(PanickingDestination)));
+        let email = Email::parse(b"Message-ID: <panic@example.com>\r\n\r\nHello.\r\n").unwrap();
+
+        let res = mapping.deliver(&email, None).await;
+        let e = res.unwrap_err();
+        assert_eq!(e.code(), "destination.panic");
+        assert!(e.is_temporary());
+        assert_eq!(
+            e.to_string(),
+            "Destination panicked for mapping 'buggy': Bug in destination."
+        );
+        assert_eq!(mapping.panics.load(Ordering::Relaxed), 1);
+
+        let mut metrics = Metrics::new();
+        metrics.set_mappings(vec![mapping]);
+        assert!(metrics
+            .render()
+            .lines()
+            .any(|line| line == "kutsche_destination_panics_total{mapping=\"buggy\"} 1"));
+    }
+
+    #[test]
+    fn test_header_conditions() {
+        let mut mapping = Mapping::new("sensors", Box::new(PanickingDestination));
+        let email = Email::parse(
+            b"Message-ID: <sensor@example.com>\r\nx-device-id:  sensor-17\r\nSubject: Temperature\r\n\r\n21\r\n",
+        )
+        .unwrap();
+        assert!(mapping.matches_headers(&email));
+
+        mapping.headers.push(HeaderCondition {
+            name: "X-Device-Id".to_string(),
+            pattern: Regex::new("^sensor-1[0-9]$").unwrap(),
+        });
+        assert!(mapping.matches_headers(&email));
+
+        mapping.headers.push(HeaderCondition {
+            name: "Subject".to_string(),
+            pattern: Regex::new("(?i)humidity").unwrap(),
+        });
+        assert!(!mapping.matches_headers(&email));
+    }
+
+    struct NoticeDestination;
+
+    #[async_trait]
+    impl EmailDestination for NoticeDestination {
+        async fn write_email(&self, _email: &Email<'_>) -> Result<Receipt, Error> {
+            Ok(Receipt::new("email"))
+        }
+
+        async fn write_notice(&self, _email: &Email<'_>) -> Result<Receipt, Error> {
+            Ok(Receipt::new("notice"))
+        }
+
+        fn kind(&self) -> &'static str {
+            "test"
+        }
+
+        fn describe(&self) -> String {
+            "a destination with notices".to_string()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auto_replies() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log = AuditLog::open(&dir.path().join("audit.log")).unwrap();
+        let vacation = Email::parse(
+            b"Auto-Submitted: auto-replied\r\nMessage-ID: <vacation@example.com>\r\n\r\nI am on vacation.\r\n",
+        )
+        .unwrap();
+
+        // Suppressed emails don't reach the destination:
+        let mut mapping = Mapping::new("buggy", Box::new(PanickingDestination));
+        mapping.auto_replies = AutoReplyHandling::Suppress;
+        mapping.deliver(&vacation, None).await.unwrap();
+
+        let mut mapping = Mapping::new("matrix", Box::new(NoticeDestination));
+        mapping.deliver(&vacation, Some(&audit_log)).await.unwrap();
+        mapping.auto_replies = AutoReplyHandling::Notice;
+        mapping.deliver(&vacation, Some(&audit_log)).await.unwrap();
+        let content = std::fs::read_to_string(dir.path().join("audit.log")).unwrap();
+        let references: Vec<String> = content
+            .lines()
+            .map(|line| {
+                serde_json::from_str::<serde_json::Value>(line).unwrap()["reference"].to_string()
+            })
+            .collect();
+        assert_eq!(references, ["\"email\"", "\"notice\""]);
+    }
+
+    #[tokio::test]
+    async fn test_fallback() {
+        let dir = tempfile::tempdir().unwrap();
+        let audit_log = AuditLog::open(&dir.path().join("audit.log")).unwrap();
+        let email = Email::parse(b"Message-ID: <fallback@example.com>\r\n\r\nHello.\r\n").unwrap();
+
+        let mut mapping = Mapping::new("matrix", Box::new(PanickingDestination));
+        mapping.fallback = Some(Box::new(NoticeDestination));
+        mapping.deliver(&email, Some(&audit_log)).await.unwrap();
+        let stats = mapping.stats.describe();
+        assert!(stats.starts_with("delivered=1 ") && stats.contains(" failures=0 "));
+        let content = std::fs::read_to_string(dir.path().join("audit.log")).unwrap();
+        let delivered: serde_json::Value =
+            serde_json::from_str(content.lines().last().unwrap()).unwrap();
+        assert_eq!(delivered["reference"], "fallback: email");
+
+        // If the fallback fails too, the error of the destination is returned:
+        mapping.fallback = Some(Box::new(PanickingDestination));
+        let e = mapping.deliver(&email, None).await.unwrap_err();
+        assert_eq!(e.code(), "destination.panic");
+        assert_eq!(mapping.panics.load(Ordering::Relaxed), 3);
+    }
+}
@@ -0,0 +1,633 @@
+use log::{debug, warn};
+use tokio::net::UdpSocket;
+use tokio::time::interval;
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::mapping::Mapping;
+use crate::Error;
+
+/// The maximum size of the UDP packets sent to statsd. Larger packets may be fragmented or dropped.
+const STATSD_MAX_PACKET: usize = 1432;
+
+/// The upper bounds of the buckets of latency histograms in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0];
+
+/// The SMTP commands, whose processing latency is measured.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SmtpCommand {
+    /// EHLO or HELO.
+    Ehlo,
+    Mail,
+    Rcpt,
+    Data,
+    /// The line ending the message, whose reply waits for the acceptance of the email.
+    DataEnd,
+}
+
+impl SmtpCommand {
+    const ALL: [SmtpCommand; 5] = [
+        SmtpCommand::Ehlo,
+        SmtpCommand::Mail,
+        SmtpCommand::Rcpt,
+        SmtpCommand::Data,
+        SmtpCommand::DataEnd,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            SmtpCommand::Ehlo => "ehlo",
+            SmtpCommand::Mail => "mail",
+            SmtpCommand::Rcpt => "rcpt",
+            SmtpCommand::Data => "data",
+            SmtpCommand::DataEnd => "data_end",
+        }
+    }
+
+    /// Returns the command of a line sent by an SMTP client or None, if it is not measured.
+    /// `in_data` tells whether the line is part of a message.
+    pub(crate) fn of_line(line: &str, in_data: bool) -> Option<Self> {
+        if in_data {
+            return if line.trim_end_matches(&['\r', '\n'][..]) == "." {
+                Some(SmtpCommand::DataEnd)
+            } else {
+                None
+            };
+        }
+        let verb = line.split_whitespace().next()?.to_ascii_uppercase();
+        match verb.as_str() {
+            "EHLO" | "HELO" => Some(SmtpCommand::Ehlo),
+            "MAIL" => Some(SmtpCommand::Mail),
+            "RCPT" => Some(SmtpCommand::Rcpt),
+            "DATA" => Some(SmtpCommand::Data),
+            _ => None,
+        }
+    }
+}
+
+/// A histogram with the buckets of `LATENCY_BUCKETS`.
+#[derive(Default)]
+struct Histogram {
+    /// The number of observations in each bucket, not including the smaller buckets.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Writes the histogram in the Prometheus text format.
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, bound, cumulative
+            );
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(
+            out,
+            "{}_sum{{{}}} {}",
+            name,
+            labels,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+/// The metrics collected by the server, shared by all its parts.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// The time from receiving an SMTP command until its reply is sent, indexed like `SmtpCommand::ALL`.
+    smtp_command_latency: [Histogram; SmtpCommand::ALL.len()],
+    /// The number of accepted TCP connections, including those, that were dropped, because the peer is not allowed.
+    accepted_connections: AtomicU64,
+    /// The number of errors reported to operators (see `report`).
+    reported_errors: AtomicU64,
+    /// The number of self-test probes sent and the number of them, that were not delivered in time.
+    self_test_probes: AtomicU64,
+    self_test_failures: AtomicU64,
+    /// The time until the last successful probe was delivered.
+    self_test_last_micros: AtomicU64,
+    /// The mappings, whose counters of panicked deliveries are exported.
+    mappings: Vec<Arc<Mapping>>,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Lets the counters of the given mappings be exported.
+    pub(crate) fn set_mappings(&mut self, mappings: Vec<Arc<Mapping>>) {
+        self.mappings = mappings;
+    }
+
+    pub(crate) fn observe_smtp_command(&self, command: SmtpCommand, latency: Duration) {
+        let i = SmtpCommand::ALL
+            .iter()
+            .position(|c| *c == command)
+            .expect("All commands are listed.");
+        self.smtp_command_latency[i].observe(latency);
+    }
+
+    pub(crate) fn observe_accepted_connection(&self) {
+        self.accepted_connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn observe_reported_error(&self) {
+        self.reported_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the result of a self-test probe: the time until it was delivered or None, if it was not delivered.
+    pub(crate) fn observe_self_test(&self, delivered_after: Option<Duration>) {
+        self.self_test_probes.fetch_add(1, Ordering::Relaxed);
+        match delivered_after {
+            Some(duration) => self
+                .self_test_last_micros
+                .store(duration.as_micros() as u64, Ordering::Relaxed),
+            None => {
+                self.self_test_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Returns all metrics in the Prometheus text format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP kutsche_smtp_command_duration_seconds The time from receiving an SMTP command until its reply is sent.\n");
+        out.push_str("# TYPE kutsche_smtp_command_duration_seconds histogram\n");
+        for (command, histogram) in SmtpCommand::ALL
+            .iter()
+            .zip(self.smtp_command_latency.iter())
+        {
+            histogram.render(
+                &mut out,
+                "kutsche_smtp_command_duration_seconds",
+                &format!("command=\"{}\"", command.name()),
+            );
+        }
+
+        out.push_str(
+            "# HELP kutsche_connections_accepted_total The number of accepted TCP connections.\n",
+        );
+        out.push_str("# TYPE kutsche_connections_accepted_total counter\n");
+        let _ = writeln!(
+            out,
+            "kutsche_connections_accepted_total {}",
+            self.accepted_connections.load(Ordering::Relaxed)
+        );
+
+        out.push_str(
+            "# HELP kutsche_reported_errors_total The number of errors reported to operators.\n",
+        );
+        out.push_str("# TYPE kutsche_reported_errors_total counter\n");
+        let _ = writeln!(
+            out,
+            "kutsche_reported_errors_total {}",
+            self.reported_errors.load(Ordering::Relaxed)
+        );
+
+        if !self.mappings.is_empty() {
+            out.push_str("# HELP kutsche_destination_panics_total The number of deliveries, in which the destination of a mapping panicked.\n");
+            out.push_str("# TYPE kutsche_destination_panics_total counter\n");
+            for mapping in self.mappings.iter() {
+                let _ = writeln!(
+                    out,
+                    "kutsche_destination_panics_total{{mapping=\"{}\"}} {}",
+                    mapping.name.replace('\\', "\\\\").replace('"', "\\\""),
+                    mapping.panics()
+                );
+            }
+        }
+
+        // The self-test metrics are only meaningful, if the self-test is enabled:
+        let probes = self.self_test_probes.load(Ordering::Relaxed);
+        if probes > 0 {
+            out.push_str(
+                "# HELP kutsche_self_test_probes_total The number of self-test emails sent.\n",
+            );
+            out.push_str("# TYPE kutsche_self_test_probes_total counter\n");
+            let _ = writeln!(out, "kutsche_self_test_probes_total {}", probes);
+            out.push_str("# HELP kutsche_self_test_failures_total The number of self-test emails, that were not delivered in time.\n");
+            out.push_str("# TYPE kutsche_self_test_failures_total counter\n");
+            let _ = writeln!(
+                out,
+                "kutsche_self_test_failures_total {}",
+                self.self_test_failures.load(Ordering::Relaxed)
+            );
+            out.push_str("# HELP kutsche_self_test_duration_seconds The time until the last delivered self-test email was delivered.\n");
+            out.push_str("# TYPE kutsche_self_test_duration_seconds gauge\n");
+            let _ = writeln!(
+                out,
+                "kutsche_self_test_duration_seconds {}",
+                self.self_test_last_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+            );
+        }
+        out
+    }
+
+    /// Returns the current values of all metrics for push exporters: the name (without prefix), the labels, the value
+    /// and whether it is a counter (or a gauge otherwise).
+    fn samples(&self) -> Vec<Sample> {
+        let mut samples = vec![];
+        for (command, histogram) in SmtpCommand::ALL
+            .iter()
+            .zip(self.smtp_command_latency.iter())
+        {
+            let labels = vec![("command", command.name().to_string())];
+            samples.push(Sample {
+                name: "smtp_command_count",
+                labels: labels.clone(),
+                value: histogram.count.load(Ordering::Relaxed),
+                counter: true,
+            });
+            samples.push(Sample {
+                name: "smtp_command_duration_micros",
+                labels,
+                value: histogram.sum_micros.load(Ordering::Relaxed),
+                counter: true,
+            });
+        }
+        samples.push(Sample {
+            name: "connections_accepted",
+            labels: vec![],
+            value: self.accepted_connections.load(Ordering::Relaxed),
+            counter: true,
+        });
+        samples.push(Sample {
+            name: "reported_errors",
+            labels: vec![],
+            value: self.reported_errors.load(Ordering::Relaxed),
+            counter: true,
+        });
+        for mapping in self.mappings.iter() {
+            samples.push(Sample {
+                name: "destination_panics",
+                labels: vec![("mapping", mapping.name.clone())],
+                value: mapping.panics(),
+                counter: true,
+            });
+        }
+        let probes = self.self_test_probes.load(Ordering::Relaxed);
+        if probes > 0 {
+            samples.push(Sample {
+                name: "self_test_probes",
+                labels: vec![],
+                value: probes,
+                counter: true,
+            });
+            samples.push(Sample {
+                name: "self_test_failures",
+                labels: vec![],
+                value: self.self_test_failures.load(Ordering::Relaxed),
+                counter: true,
+            });
+            samples.push(Sample {
+                name: "self_test_duration_ms",
+                labels: vec![],
+                value: self.self_test_last_micros.load(Ordering::Relaxed) / 1000,
+                counter: false,
+            });
+        }
+        samples
+    }
+}
+
+/// The value of a metric at one point in time.
+struct Sample {
+    name: &'static str,
+    labels: Vec<(&'static str, String)>,
+    value: u64,
+    /// Whether the value only increases. Only the increase since the last push is sent for counters.
+    counter: bool,
+}
+
+/// The section 'statsd' of the config file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct StatsdConfig {
+    /// The statsd server as "host:port".
+    pub(crate) address: String,
+    /// The prefix of the names of all metrics, without the trailing dot.
+    pub(crate) prefix: String,
+    pub(crate) interval: Duration,
+    /// Whether labels are sent as DogStatsD tags instead of being appended to the names.
+    pub(crate) dogstatsd: bool,
+}
+
+impl TryFrom<&toml::map::Map<String, toml::Value>> for StatsdConfig {
+    type Error = Error;
+
+    fn try_from(section: &toml::map::Map<String, toml::Value>) -> Result<Self, Self::Error> {
+        let address = section
+            .get("address")
+            .ok_or_else(|| Error::config("Missing field 'statsd.address'."))?
+            .as_str()
+            .filter(|address| {
+                address
+                    .rsplit_once(':')
+                    .is_some_and(|(_, port)| port.parse::<u16>().is_ok())
+            })
+            .ok_or_else(|| {
+                Error::config("Field 'statsd.address' has wrong type (expected \"host:port\").")
+            })?
+            .to_string();
+        let prefix = match section.get("prefix") {
+            Some(prefix) => prefix
+                .as_str()
+                .ok_or_else(|| {
+                    Error::config("Field 'statsd.prefix' has wrong type (expected string).")
+                })?
+                .trim_end_matches('.')
+                .to_string(),
+            None => "kutsche".to_string(),
+        };
+        let interval = match section.get("interval_secs") {
+            Some(val) => val
+                .as_integer()
+                .and_then(|n| u64::try_from(n).ok())
+                .filter(|n| *n > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| {
+                    Error::config(
+                        "Field 'statsd.interval_secs' has wrong type (expected positive integer).",
+                    )
+                })?,
+            None => Duration::from_secs(10),
+        };
+        let dogstatsd = match section.get("dogstatsd") {
+            Some(val) => val.as_bool().ok_or_else(|| {
+                Error::config("Field 'statsd.dogstatsd' has wrong type (expected boolean).")
+            })?,
+            None => false,
+        };
+        Ok(StatsdConfig {
+            address,
+            prefix,
+            interval,
+            dogstatsd,
+        })
+    }
+}
+
+/// Pushes the metrics periodically to a statsd server over UDP.
+pub(crate) struct StatsdExporter {
+    config: StatsdConfig,
+    metrics: Arc<Metrics>,
+    /// The values of the counters at the last push, by their line without the value.
+    pushed: HashMap<String, u64>,
+}
+
+impl StatsdExporter {
+    pub(crate) fn new(config: StatsdConfig, metrics: Arc<Metrics>) -> Self {
+        StatsdExporter {
+            config,
+            metrics,
+            pushed: HashMap::new(),
+        }
+    }
+
+    /// Returns the lines, that are pushed next, and remembers the pushed counter values.
+    fn lines(&mut self) -> Vec<String> {
+        let mut lines = vec![];
+        for sample in self.metrics.samples() {
+            let mut name = format!("{}.{}", self.config.prefix, sample.name);
+            let mut tags = String::new();
+            for (key, value) in sample.labels.iter() {
+                // Mapping names may contain the separators of the statsd format:
+                let value = value.replace([':', '|', '@', '#', ','], "_");
+                if self.config.dogstatsd {
+                    tags.push(if tags.is_empty() { '#' } else { ',' });
+                    let _ = write!(tags, "{}:{}", key, value);
+                } else {
+                    let _ = write!(name, ".{}", value);
+                }
+            }
+            let (value, kind) = if sample.counter {
+                let previous = self
+                    .pushed
+                    .insert(format!("{}|{}", name, tags), sample.value)
+                    .unwrap_or(0);
+                // Counters are only reset by a restart, which also resets `pushed`:
+                (sample.value.saturating_sub(previous), "c")
+            } else {
+                (sample.value, "g")
+            };
+            if sample.counter && value == 0 {
+                continue;
+            }
+            if tags.is_empty() {
+                lines.push(format!("{}:{}|{}", name, value, kind));
+            } else {
+                lines.push(format!("{}:{}|{}|{}", name, value, kind, tags));
+            }
+        }
+        lines
+    }
+
+    /// Pushes the metrics forever. Errors are logged, because statsd is best-effort.
+    pub(crate) async fn run(mut self) {
+        let bind_addr = if self.config.address.starts_with('[') {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let socket = match UdpSocket::bind(bind_addr).await {
+            Ok(socket) => socket,
+            Err(e) => {
+                warn!("Could not create socket for statsd: {}", e);
+                return;
+            }
+        };
+        let mut ticker = interval(self.config.interval);
+        loop {
+            ticker.tick().await;
+            let mut packet = String::new();
+            for line in self.lines() {
+                if !packet.is_empty() && packet.len() + line.len() + 1 > STATSD_MAX_PACKET {
+                    self.send(&socket, &packet).await;
+                    packet.clear();
+                }
+                if !packet.is_empty() {
+                    packet.push('\n');
+                }
+                packet.push_str(&line);
+            }
+            if !packet.is_empty() {
+                self.send(&socket, &packet).await;
+            }
+        }
+    }
+
+    async fn send(&self, socket: &UdpSocket, packet: &str) {
+        // The address is resolved for every packet, so changes of the statsd host are followed:
+        match socket
+            .send_to(packet.as_bytes(), self.config.address.as_str())
+            .await
+        {
+            Ok(_) => debug!("Pushed {} bytes of metrics to statsd.", packet.len()),
+            Err(e) => warn!(
+                "Could not push metrics to statsd at {}: {}",
+                self.config.address, e
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maildest::FileDestination;
+
+    #[test]
+    fn test_of_line() {
+        assert_eq!(
+            SmtpCommand::of_line("ehlo client.example\r\n", false),
+            Some(SmtpCommand::Ehlo)
+        );
+        assert_eq!(
+            SmtpCommand::of_line("RCPT TO:<user@example.com>\r\n", false),
+            Some(SmtpCommand::Rcpt)
+        );
+        assert_eq!(SmtpCommand::of_line("QUIT\r\n", false), None);
+        assert_eq!(SmtpCommand::of_line("MAIL is great\r\n", true), None);
+        assert_eq!(
+            SmtpCommand::of_line(".\r\n", true),
+            Some(SmtpCommand::DataEnd)
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.observe_smtp_command(SmtpCommand::Rcpt, Duration::from_millis(3));
+        metrics.observe_smtp_command(SmtpCommand::Rcpt, Duration::from_millis(200));
+
+        let rendered = metrics.render();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert!(lines.contains(
+            &"kutsche_smtp_command_duration_seconds_bucket{command=\"rcpt\",le=\"0.005\"} 1"
+        ));
+        assert!(lines.contains(
+            &"kutsche_smtp_command_duration_seconds_bucket{command=\"rcpt\",le=\"0.5\"} 2"
+        ));
+        assert!(lines.contains(&"kutsche_smtp_command_duration_seconds_count{command=\"rcpt\"} 2"));
+        assert!(
+            lines.contains(&"kutsche_smtp_command_duration_seconds_sum{command=\"rcpt\"} 0.203")
+        );
+        assert!(lines.contains(&"kutsche_smtp_command_duration_seconds_count{command=\"mail\"} 0"));
+        assert!(!rendered.contains("kutsche_self_test"));
+
+        metrics.observe_self_test(Some(Duration::from_millis(1500)));
+        metrics.observe_self_test(None);
+        let rendered = metrics.render();
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert!(lines.contains(&"kutsche_self_test_probes_total 2"));
+        assert!(lines.contains(&"kutsche_self_test_failures_total 1"));
+        assert!(lines.contains(&"kutsche_self_test_duration_seconds 1.5"));
+        assert!(!rendered.contains("kutsche_destination_panics_total"));
+    }
+
+    #[test]
+    fn test_destination_panics() {
+        let mut metrics = Metrics::new();
+        metrics.set_mappings(vec![Arc::new(Mapping::new(
+            "acme/alerts",
+            Box::new(FileDestination::new(std::env::temp_dir()).unwrap()),
+        ))]);
+        let metrics = Arc::new(metrics);
+        assert!(metrics
+            .render()
+            .lines()
+            .any(|line| line == "kutsche_destination_panics_total{mapping=\"acme/alerts\"} 0"));
+
+        let config = StatsdConfig {
+            address: "127.0.0.1:8125".to_string(),
+            prefix: "kutsche".to_string(),
+            interval: Duration::from_secs(10),
+            dogstatsd: true,
+        };
+        let mut exporter = StatsdExporter::new(config, metrics);
+        // Unchanged counters are not pushed:
+        assert!(!exporter
+            .lines()
+            .iter()
+            .any(|line| line.contains("destination_panics")));
+    }
+
+    #[test]
+    fn test_statsd_lines() {
+        let metrics = Arc::new(Metrics::new());
+        metrics.observe_smtp_command(SmtpCommand::Rcpt, Duration::from_millis(3));
+        let config = StatsdConfig {
+            address: "127.0.0.1:8125".to_string(),
+            prefix: "kutsche".to_string(),
+            interval: Duration::from_secs(10),
+            dogstatsd: false,
+        };
+        let mut exporter = StatsdExporter::new(config.clone(), metrics.clone());
+        assert_eq!(
+            exporter.lines(),
+            [
+                "kutsche.smtp_command_count.rcpt:1|c",
+                "kutsche.smtp_command_duration_micros.rcpt:3000|c"
+            ]
+        );
+        // Only the increase is pushed:
+        assert!(exporter.lines().is_empty());
+        metrics.observe_smtp_command(SmtpCommand::Rcpt, Duration::from_millis(1));
+        metrics.observe_self_test(Some(Duration::from_millis(1500)));
+        assert_eq!(
+            exporter.lines(),
+            [
+                "kutsche.smtp_command_count.rcpt:1|c",
+                "kutsche.smtp_command_duration_micros.rcpt:1000|c",
+                "kutsche.self_test_probes:1|c",
+                "kutsche.self_test_duration_ms:1500|g"
+            ]
+        );
+
+        let mut exporter = StatsdExporter::new(
+            StatsdConfig {
+                dogstatsd: true,
+                ..config
+            },
+            metrics,
+        );
+        assert!(exporter
+            .lines()
+            .contains(&"kutsche.smtp_command_count:2|c|#command:rcpt".to_string()));
+    }
+
+    #[test]
+    fn test_statsd_config() {
+        let section: toml::map::Map<String, toml::Value> =
+            toml::from_str("address = \"[::1]:8125\"\nprefix = \"mx.\"").unwrap();
+        let config = StatsdConfig::try_from(&section).unwrap();
+        assert_eq!(config.prefix, "mx");
+        assert_eq!(config.interval, Duration::from_secs(10));
+        assert!(!config.dogstatsd);
+
+        let section: toml::map::Map<String, toml::Value> =
+            toml::from_str("address = \"localhost\"").unwrap();
+        assert!(StatsdConfig::try_from(&section).is_err());
+    }
+}
@@ -0,0 +1,579 @@
+use log::{error, warn};
+use tokio::sync::Notify;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::audit::AuditLog;
+use crate::email::{Email, Envelope};
+use crate::feed::MailFeed;
+use crate::ha::Spool;
+use crate::mapping::Mapping;
+use crate::report::report_error;
+use crate::smtp_server::{ByMode, DeliverBy};
+use crate::Error;
+
+/// The waiting time after which a job is treated as if its priority was one level higher.
+/// This prevents emails for low-priority mappings from starving, while emails with higher priority keep arriving.
+const AGING_INTERVAL: Duration = Duration::from_secs(10);
+/// The number of deliveries of a job, after which a temporary error is not retried anymore.
+const MAX_ATTEMPTS: u32 = 5;
+/// The delay before the first retry of a job. It doubles with every further attempt.
+const RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// An email waiting for its delivery to the destination of a mapping.
+pub(crate) struct DeliveryJob {
+    pub(crate) mapping: Arc<Mapping>,
+    pub(crate) raw: Arc<[u8]>,
+    pub(crate) envelope: Option<Arc<Envelope>>,
+    /// The time in seconds since the unix epoch, before which the email must not be delivered.
+    pub(crate) hold_until: Option<u64>,
+    /// The deadline of the delivery, after which it is not retried anymore or only with a warning.
+    pub(crate) deliver_by: Option<DeliverBy>,
+    /// The ID of the job in the spool, if it is stored there.
+    pub(crate) spool_id: Option<String>,
+    enqueued: Instant,
+    /// The number of failed deliveries of this job. A delivery, that was interrupted by the loss of the lease of
+    /// another server, counts as failed (see `Spool::mark_attempted()`).
+    pub(crate) attempts: u32,
+}
+
+impl DeliveryJob {
+    pub(crate) fn new(
+        mapping: Arc<Mapping>,
+        raw: Arc<[u8]>,
+        envelope: Option<Arc<Envelope>>,
+    ) -> Self {
+        DeliveryJob {
+            mapping,
+            raw,
+            envelope,
+            hold_until: None,
+            deliver_by: None,
+            spool_id: None,
+            enqueued: Instant::now(),
+            attempts: 0,
+        }
+    }
+
+    /// Returns the delay before this job should be retried after a failed delivery with a temporary error, or None if
+    /// it was retried too often.
+    fn retry_delay(&self) -> Option<Duration> {
+        if self.attempts < MAX_ATTEMPTS {
+            Some(RETRY_DELAY * 2u32.pow(self.attempts - 1))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the time, that the job has to wait before its first delivery, if it is held.
+    fn hold_time(&self) -> Option<Duration> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.hold_until
+            .filter(|hold_until| *hold_until > now)
+            .map(|hold_until| Duration::from_secs(hold_until - now))
+    }
+
+    /// The priority of the mapping raised by one level for every `AGING_INTERVAL` the job has been waiting.
+    fn effective_priority(&self, now: Instant) -> u64 {
+        let waited = now.saturating_duration_since(self.enqueued);
+        u64::from(self.mapping.priority) + (waited.as_millis() / AGING_INTERVAL.as_millis()) as u64
+    }
+}
+
+/// The queue of emails, that were received but not yet delivered.
+///
+/// Jobs are kept in one FIFO per priority. `pop()` takes the oldest job of the priority with the highest effective
+/// priority (see `DeliveryJob::effective_priority()`), so higher priorities are serviced first, but waiting jobs
+/// eventually overtake newer ones with higher priority.
+///
+/// If a spool is set, new jobs are stored in it until they are finished, so another server can deliver them, if this
+/// one fails.
+#[derive(Default)]
+pub(crate) struct DeliveryQueue {
+    jobs: Mutex<BTreeMap<u8, VecDeque<DeliveryJob>>>,
+    /// The jobs waiting for a retry or held until later (see `DeliveryJob::hold_until`) with the time they are due.
+    /// `pop()` moves them to `jobs`, when they are due. It is only locked while `jobs` is locked.
+    later: Mutex<Vec<(Instant, DeliveryJob)>>,
+    notify: Notify,
+    spool: Option<Spool>,
+    /// The number of jobs taken by `pop()`, whose delivery is still running. It is only increased while `jobs` is
+    /// locked, so `is_idle()` never misses a job between the queue and its delivery.
+    running: AtomicUsize,
+    /// Set by `stop()`, after which `pop()` doesn't return jobs anymore.
+    stopped: AtomicBool,
+}
+
+/// Counts a job taken by `pop()` as running, until it is dropped.
+pub(crate) struct Running<'a>(&'a AtomicUsize);
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl DeliveryQueue {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn set_spool(&mut self, spool: Spool) {
+        self.spool = Some(spool);
+    }
+
+    /// Stores a new job in the spool, if there is one, and schedules it.
+    pub(crate) fn enqueue(self: &Arc<Self>, mut job: DeliveryJob) -> Result<(), Error> {
+        if let Some(ref spool) = self.spool {
+            job.spool_id = Some(spool.store(&job)?);
+        }
+        self.schedule(job);
+        Ok(())
+    }
+
+    /// Schedules all jobs in the spool, e.g. after taking over from a failed server, and returns their number.
+    pub(crate) fn restore(
+        self: &Arc<Self>,
+        mapping: impl Fn(&str) -> Option<Arc<Mapping>>,
+    ) -> Result<usize, Error> {
+        let jobs = match self.spool {
+            Some(ref spool) => spool.load(mapping)?,
+            None => return Ok(0),
+        };
+        let count = jobs.len();
+        for job in jobs {
+            self.schedule(job);
+        }
+        Ok(count)
+    }
+
+    /// Marks the first delivery of a job in the spool, so a server taking over knows, that it may have been delivered
+    /// partly.
+    fn mark_attempted(&self, job: &DeliveryJob) {
+        if let (Some(spool), Some(id), 0) = (&self.spool, &job.spool_id, job.attempts) {
+            if let Err(e) = spool.mark_attempted(id) {
+                error!(
+                    "Could not mark delivery {} in spool as attempted: {}",
+                    id, e
+                );
+            }
+        }
+    }
+
+    /// Removes a job from the spool, after it was delivered or given up.
+    fn finish(&self, job: &DeliveryJob) {
+        if let (Some(spool), Some(id)) = (&self.spool, &job.spool_id) {
+            if let Err(e) = spool.remove(id) {
+                error!("Could not remove delivery {} from spool: {}", id, e);
+            }
+        }
+    }
+
+    pub(crate) fn push(&self, job: DeliveryJob) {
+        self.jobs
+            .lock()
+            .expect("Delivery queue is poisoned.")
+            .entry(job.mapping.priority)
+            .or_default()
+            .push_back(job);
+        self.notify.notify_one();
+    }
+
+    /// Pushes the job after `delay`.
+    pub(crate) fn push_later(&self, job: DeliveryJob, delay: Duration) {
+        self.later
+            .lock()
+            .expect("Delivery queue is poisoned.")
+            .push((Instant::now() + delay, job));
+        // A waiting worker has to wait for the new due time, if it is earlier than the others:
+        self.notify.notify_one();
+    }
+
+    /// Pushes the job, when it is not held anymore (see `DeliveryJob::hold_until`).
+    pub(crate) fn schedule(&self, mut job: DeliveryJob) {
+        match job.hold_time() {
+            Some(hold_time) => {
+                // The job should not gain priority while it is held:
+                job.enqueued += hold_time;
+                self.push_later(job, hold_time);
+            }
+            None => self.push(job),
+        }
+    }
+
+    /// Takes the next job from the queue or returns None, if the queue is empty.
+    #[cfg(test)]
+    pub(crate) fn try_pop(&self) -> Option<DeliveryJob> {
+        let mut jobs = self.jobs.lock().expect("Delivery queue is poisoned.");
+        self.release_due(&mut jobs);
+        Self::take_next(&mut jobs)
+    }
+
+    /// Moves the jobs in `later`, that are due, to `jobs` and returns the time the next one is due.
+    fn release_due(&self, jobs: &mut BTreeMap<u8, VecDeque<DeliveryJob>>) -> Option<Instant> {
+        let now = Instant::now();
+        let mut later = self.later.lock().expect("Delivery queue is poisoned.");
+        let mut i = 0;
+        while i < later.len() {
+            if later[i].0 <= now {
+                let (_, job) = later.swap_remove(i);
+                jobs.entry(job.mapping.priority).or_default().push_back(job);
+            } else {
+                i += 1;
+            }
+        }
+        later.iter().map(|(due, _)| *due).min()
+    }
+
+    fn take_next(jobs: &mut BTreeMap<u8, VecDeque<DeliveryJob>>) -> Option<DeliveryJob> {
+        let now = Instant::now();
+        // On ties the higher priority wins, because we iterate in descending order and only replace strictly better
+        // candidates:
+        let priority = jobs
+            .iter()
+            .rev()
+            .filter_map(|(priority, queue)| {
+                queue
+                    .front()
+                    .map(|job| (*priority, job.effective_priority(now)))
+            })
+            .fold(
+                None,
+                |best: Option<(u8, u64)>, (priority, effective)| match best {
+                    Some((_, best_effective)) if best_effective >= effective => best,
+                    _ => Some((priority, effective)),
+                },
+            )?
+            .0;
+        jobs.get_mut(&priority)
+            .expect("The priority was taken from the map.")
+            .pop_front()
+    }
+
+    /// Waits for the next job, which counts as running, until the returned guard is dropped. After `stop()`, it waits
+    /// forever.
+    pub(crate) async fn pop(&self) -> (DeliveryJob, Running<'_>) {
+        loop {
+            if self.stopped.load(Ordering::Relaxed) {
+                std::future::pending::<()>().await;
+            }
+            let next = {
+                let mut jobs = self.jobs.lock().expect("Delivery queue is poisoned.");
+                let next_due = self.release_due(&mut jobs);
+                match Self::take_next(&mut jobs) {
+                    Some(job) => {
+                        self.running.fetch_add(1, Ordering::Relaxed);
+                        Ok(job)
+                    }
+                    None => Err(next_due),
+                }
+            };
+            match next {
+                Ok(job) => {
+                    self.mark_attempted(&job);
+                    return (job, Running(&self.running));
+                }
+                Err(Some(due)) => {
+                    tokio::select! {
+                        _ = self.notify.notified() => {}
+                        _ = tokio::time::sleep_until(due.into()) => {}
+                    }
+                }
+                Err(None) => self.notify.notified().await,
+            }
+        }
+    }
+
+    /// Lets the workers stop taking jobs, e.g. when this server lost the lease. The jobs stay in the spool.
+    pub(crate) fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        self.notify.notify_waiters();
+    }
+
+    /// Returns the number of jobs taken by `pop()`, whose delivery is still running.
+    pub(crate) fn running(&self) -> usize {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Returns true, if no job is waiting for its delivery or being delivered. Jobs waiting for a retry or held
+    /// until later are not counted.
+    pub(crate) fn is_idle(&self) -> bool {
+        let jobs = self.jobs.lock().expect("Delivery queue is poisoned.");
+        jobs.values().all(VecDeque::is_empty) && self.running.load(Ordering::Relaxed) == 0
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.jobs
+            .lock()
+            .expect("Delivery queue is poisoned.")
+            .values()
+            .map(VecDeque::len)
+            .sum()
+    }
+
+    /// Returns the number of jobs waiting for a retry or held until later.
+    pub(crate) fn waiting(&self) -> usize {
+        self.later
+            .lock()
+            .expect("Delivery queue is poisoned.")
+            .len()
+    }
+
+    /// Returns true, if the jobs are stored in a spool, so they are not lost, when the server is stopped.
+    pub(crate) fn is_spooled(&self) -> bool {
+        self.spool.is_some()
+    }
+}
+
+/// Delivers the jobs of `queue` forever and publishes the outcomes to `feed`.
+///
+/// Jobs failing with a temporary error (including a panic of the destination) are pushed to the queue again after a
+/// delay, until they failed `MAX_ATTEMPTS` times or the retry would miss the deadline of a job, that should be given up
+/// then.
+pub(crate) async fn run_worker(
+    queue: Arc<DeliveryQueue>,
+    audit_log: Option<Arc<AuditLog>>,
+    feed: Arc<MailFeed>,
+) {
+    loop {
+        let (mut job, _running) = queue.pop().await;
+        let mut email = match Email::parse(&job.raw) {
+            Ok(email) => email,
+            Err(e) => {
+                error!("Could not parse queued email: {}", e);
+                queue.finish(&job);
+                continue;
+            }
+        };
+        email.envelope = job.envelope.clone();
+        email.retried = job.attempts > 0;
+        let res = job.mapping.deliver(&email, audit_log.as_deref()).await;
+        feed.delivered(&email.message_id, &job.mapping.name, &res);
+        let e = match res {
+            Ok(()) => {
+                queue.finish(&job);
+                continue;
+            }
+            Err(e) => e,
+        };
+        report_error!(
+            email_message_id = email.message_id, mapping = job.mapping.name;
+            "Could not forward email ({}): {}", e.code(), e
+        );
+        job.attempts += 1;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        // Retries, that would miss the deadline requested with DELIVERBY, are given up or only logged:
+        let deliver_by = job.deliver_by;
+        let missed_deadline = |delay: Duration| {
+            deliver_by
+                .filter(|by| now + delay.as_secs() > by.deadline)
+                .map(|by| by.mode)
+        };
+        match job.retry_delay() {
+            Some(delay) if e.is_temporary() && missed_deadline(delay) == Some(ByMode::Return) => {
+                error!(
+                    email_message_id:% = email.message_id, mapping:% = job.mapping.name;
+                    "Gave up delivering email with id {}, because a retry would miss its deadline.",
+                    email.message_id
+                );
+            }
+            Some(delay) if e.is_temporary() => {
+                if missed_deadline(delay).is_some() {
+                    warn!(
+                        email_message_id:% = email.message_id, mapping:% = job.mapping.name;
+                        "The delivery of email with id {} misses its deadline.",
+                        email.message_id
+                    );
+                }
+                warn!(
+                    email_message_id:% = email.message_id, mapping:% = job.mapping.name;
+                    "Retrying delivery of email with id {} in {} seconds.",
+                    email.message_id,
+                    delay.as_secs()
+                );
+                queue.push_later(job, delay);
+                continue;
+            }
+            Some(_) => {}
+            None => error!(
+                email_message_id:% = email.message_id, mapping:% = job.mapping.name;
+                "Gave up delivering email with id {} after {} attempts.",
+                email.message_id, job.attempts
+            ),
+        }
+        queue.finish(&job);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maildest::{EmailDestination, Receipt};
+    use async_trait::async_trait;
+
+    struct NullDestination;
+
+    #[async_trait]
+    impl EmailDestination for NullDestination {
+        async fn write_email(&self, _email: &Email<'_>) -> Result<Receipt, Error> {
+            Ok(Receipt::default())
+        }
+
+        fn kind(&self) -> &'static str {
+            "test"
+        }
+
+        fn describe(&self) -> String {
+            "nowhere".to_string()
+        }
+    }
+
+    fn mapping(name: &str, priority: u8) -> Arc<Mapping> {
+        let mut mapping = Mapping::new(name, Box::new(NullDestination));
+        mapping.priority = priority;
+        Arc::new(mapping)
+    }
+
+    fn job(mapping: &Arc<Mapping>, age: Duration) -> DeliveryJob {
+        let mut job = DeliveryJob::new(mapping.clone(), Arc::from(&b""[..]), None);
+        job.enqueued -= age;
+        job
+    }
+
+    #[test]
+    fn test_higher_priority_first() {
+        let low = mapping("low", 0);
+        let high = mapping("high", 5);
+        let queue = DeliveryQueue::new();
+        queue.push(job(&low, Duration::ZERO));
+        queue.push(job(&high, Duration::ZERO));
+        queue.push(job(&low, Duration::ZERO));
+
+        assert_eq!(queue.try_pop().unwrap().mapping.name, "high");
+        assert_eq!(queue.try_pop().unwrap().mapping.name, "low");
+        assert_eq!(queue.try_pop().unwrap().mapping.name, "low");
+        assert!(queue.try_pop().is_none());
+    }
+
+    #[test]
+    fn test_fifo_within_priority() {
+        let first = mapping("first", 1);
+        let second = mapping("second", 1);
+        let queue = DeliveryQueue::new();
+        queue.push(job(&first, Duration::ZERO));
+        queue.push(job(&second, Duration::ZERO));
+
+        assert_eq!(queue.try_pop().unwrap().mapping.name, "first");
+        assert_eq!(queue.try_pop().unwrap().mapping.name, "second");
+    }
+
+    #[test]
+    fn test_no_starvation() {
+        let low = mapping("low", 0);
+        let high = mapping("high", 2);
+        let queue = DeliveryQueue::new();
+        // Waiting for three aging intervals raises the low priority above the high one:
+        queue.push(job(&low, AGING_INTERVAL * 3));
+        queue.push(job(&high, Duration::ZERO));
+
+        assert_eq!(queue.try_pop().unwrap().mapping.name, "low");
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let mut job = job(&mapping("retried", 0), Duration::ZERO);
+        job.attempts = 1;
+        assert_eq!(job.retry_delay(), Some(RETRY_DELAY));
+        job.attempts = 3;
+        assert_eq!(job.retry_delay(), Some(RETRY_DELAY * 4));
+        job.attempts = MAX_ATTEMPTS;
+        assert_eq!(job.retry_delay(), None);
+    }
+
+    #[tokio::test]
+    async fn test_held_job() {
+        let mapping = mapping("held", 0);
+        let queue = Arc::new(DeliveryQueue::new());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut held = job(&mapping, Duration::ZERO);
+        held.hold_until = Some(now + 3600);
+        assert!(held.hold_time().unwrap() > Duration::from_secs(3590));
+        queue.schedule(held);
+        assert_eq!(queue.len(), 0);
+        assert_eq!(queue.waiting(), 1);
+
+        // Jobs, whose release time passed, are queued immediately:
+        let mut released = job(&mapping, Duration::ZERO);
+        released.hold_until = Some(now - 60);
+        queue.schedule(released);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.waiting(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_push_later() {
+        let mapping = mapping("retry", 0);
+        let queue = DeliveryQueue::new();
+        queue.push_later(job(&mapping, Duration::ZERO), Duration::from_millis(50));
+        assert!(queue.try_pop().is_none());
+        assert_eq!(queue.waiting(), 1);
+
+        // A waiting worker takes the job, when it is due:
+        let (_job, _running) = tokio::time::timeout(Duration::from_secs(5), queue.pop())
+            .await
+            .unwrap();
+        assert_eq!(queue.waiting(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_takeover_while_delivering() {
+        let dir = tempfile::tempdir().unwrap();
+        let mapping = mapping("spooled", 0);
+        let mut queue = DeliveryQueue::new();
+        queue.set_spool(Spool::open(dir.path(), "first").unwrap());
+        let queue = Arc::new(queue);
+        queue.enqueue(job(&mapping, Duration::ZERO)).unwrap();
+        queue.enqueue(job(&mapping, Duration::ZERO)).unwrap();
+
+        // The first server loses the lease, while it delivers one of the jobs:
+        let (running_job, running) = queue.pop().await;
+        queue.stop();
+        let no_job = tokio::time::timeout(Duration::from_millis(50), queue.pop()).await;
+        assert!(no_job.is_err());
+        assert_eq!(queue.running(), 1);
+        drop(running);
+
+        // The second server retries the interrupted delivery and delivers the other one anew:
+        let other = Spool::open(dir.path(), "second").unwrap();
+        let jobs = other.load(|_| Some(mapping.clone())).unwrap();
+        assert_eq!(jobs.len(), 2);
+        for job in jobs {
+            let interrupted = job.spool_id == running_job.spool_id;
+            assert_eq!(job.attempts, interrupted as u32);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_idle() {
+        let queue = DeliveryQueue::new();
+        assert!(queue.is_idle());
+        queue.push(job(&mapping("slow", 0), Duration::ZERO));
+        assert!(!queue.is_idle());
+
+        // The queue is busy, until the delivery of the job is finished:
+        let (_job, running) = queue.pop().await;
+        assert!(!queue.is_idle());
+        drop(running);
+        assert!(queue.is_idle());
+    }
+}
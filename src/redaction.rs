@@ -0,0 +1,182 @@
+use regex::{Captures, Regex};
+
+use std::borrow::Cow;
+use std::str::FromStr;
+
+use crate::Error;
+
+/// Email addresses, as they appear in texts. Quoted local parts are rare enough to be ignored.
+const ADDRESS_PATTERN: &str = r"(?i)\b([a-z0-9._%+-]+)@([a-z0-9-]+(?:\.[a-z0-9-]+)*\.[a-z]{2,})\b";
+/// Candidates for phone numbers, which start with a country code or a trunk prefix, e.g. "+49 30 1234567" or
+/// "030/1234567". They are checked by `is_phone_number`.
+const PHONE_PATTERN: &str = r"(?:\+\d|\b0)[\d ()/-]{5,}\d";
+/// Dates, which look like phone numbers, e.g. "01-05-2024".
+const DATE_PATTERN: &str = r"^\d{1,4}[-/]\d{1,2}[-/]\d{1,4}$";
+
+/// How much of a redacted email address or phone number remains visible.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Masking {
+    /// The first character of the address and of its domain and the last two digits of phone numbers remain, e.g.
+    /// "a***@e***.com" and "+** ** *****67", so readers can still tell them apart.
+    Partial,
+    /// The address or number is replaced with "[email]" or "[phone]".
+    Full,
+}
+
+impl FromStr for Masking {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "partial" => Ok(Masking::Partial),
+            "full" => Ok(Masking::Full),
+            _ => Err(Error::config(format!(
+                "Unknown masking '{}' (expected partial or full).",
+                s
+            ))),
+        }
+    }
+}
+
+/// Redacts email addresses, phone numbers and custom patterns in the bodies of emails, before they are posted to
+/// shared chat rooms.
+///
+/// The custom patterns are applied first, in the order they were added, followed by addresses and phone numbers.
+#[derive(Default)]
+pub(crate) struct Redactor {
+    addresses: Option<(Regex, Masking)>,
+    phone_numbers: Option<(Regex, Regex, Masking)>,
+    patterns: Vec<(Regex, String)>,
+}
+
+impl Redactor {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn set_addresses(&mut self, masking: Masking) {
+        let pattern = Regex::new(ADDRESS_PATTERN).expect("The address pattern is valid.");
+        self.addresses = Some((pattern, masking));
+    }
+
+    pub(crate) fn set_phone_numbers(&mut self, masking: Masking) {
+        let pattern = Regex::new(PHONE_PATTERN).expect("The phone pattern is valid.");
+        let date = Regex::new(DATE_PATTERN).expect("The date pattern is valid.");
+        self.phone_numbers = Some((pattern, date, masking));
+    }
+
+    /// Adds a pattern, whose matches are replaced with `replacement`, which may refer to groups of the pattern like
+    /// "$1".
+    pub(crate) fn add_pattern(
+        &mut self,
+        pattern: &str,
+        replacement: impl Into<String>,
+    ) -> Result<(), Error> {
+        let pattern = Regex::new(pattern)
+            .map_err(|e| Error::config(format!("Invalid redaction pattern: {}", e)))?;
+        self.patterns.push((pattern, replacement.into()));
+        Ok(())
+    }
+
+    /// Returns the text with all matches of the configured redactions replaced.
+    pub(crate) fn redact<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        let mut text = Cow::Borrowed(text);
+        for (pattern, replacement) in self.patterns.iter() {
+            if let Cow::Owned(redacted) = pattern.replace_all(&text, replacement.as_str()) {
+                text = Cow::Owned(redacted);
+            }
+        }
+        if let Some((ref pattern, masking)) = self.addresses {
+            if let Cow::Owned(redacted) =
+                pattern.replace_all(&text, |captures: &Captures<'_>| match masking {
+                    Masking::Partial => mask_address(&captures[1], &captures[2]),
+                    Masking::Full => "[email]".to_string(),
+                })
+            {
+                text = Cow::Owned(redacted);
+            }
+        }
+        if let Some((ref pattern, ref date, masking)) = self.phone_numbers {
+            if let Cow::Owned(redacted) = pattern.replace_all(&text, |captures: &Captures<'_>| {
+                let number = &captures[0];
+                if !is_phone_number(number, date) {
+                    number.to_string()
+                } else {
+                    match masking {
+                        Masking::Partial => mask_phone_number(number),
+                        Masking::Full => "[phone]".to_string(),
+                    }
+                }
+            }) {
+                text = Cow::Owned(redacted);
+            }
+        }
+        text
+    }
+}
+
+/// Keeps the first character of the local part and of the domain and the top-level domain, e.g. "a***@e***.com".
+fn mask_address(local_part: &str, domain: &str) -> String {
+    let first = |s: &str| s.chars().next().unwrap_or('*');
+    let tld = domain.rsplit('.').next().unwrap_or_default();
+    format!("{}***@{}***.{}", first(local_part), first(domain), tld)
+}
+
+/// Replaces all digits except the last two with '*', keeping the separators.
+fn mask_phone_number(number: &str) -> String {
+    let digits = number.chars().filter(char::is_ascii_digit).count();
+    let mut seen = 0;
+    number
+        .chars()
+        .map(|c| {
+            if !c.is_ascii_digit() {
+                return c;
+            }
+            seen += 1;
+            if seen + 2 > digits {
+                c
+            } else {
+                '*'
+            }
+        })
+        .collect()
+}
+
+/// Returns true, if the candidate has as many digits as phone numbers (7 to 15) and is not a date.
+fn is_phone_number(candidate: &str, date: &Regex) -> bool {
+    let digits = candidate.chars().filter(char::is_ascii_digit).count();
+    (7..=15).contains(&digits) && !date.is_match(candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact() {
+        let text = "Call Alice at +49 30 1234567 or 030/7654321, or write to alice.smith@mail.example.com.\n\
+Order 12345678 was shipped on 01-05-2024. Customer ID: KD-4711";
+        let mut redactor = Redactor::new();
+        assert_eq!(redactor.redact(text), text);
+
+        redactor.set_addresses(Masking::Partial);
+        redactor.set_phone_numbers(Masking::Partial);
+        assert_eq!(
+            redactor.redact(text),
+            "Call Alice at +** ** *****67 or ***/*****21, or write to a***@m***.com.\n\
+Order 12345678 was shipped on 01-05-2024. Customer ID: KD-4711"
+        );
+
+        redactor.set_addresses(Masking::Full);
+        redactor.set_phone_numbers(Masking::Full);
+        redactor
+            .add_pattern(r"Customer ID: KD-\d+", "Customer ID: [redacted]")
+            .unwrap();
+        assert_eq!(
+            redactor.redact(text),
+            "Call Alice at [phone] or [phone], or write to [email].\n\
+Order 12345678 was shipped on 01-05-2024. Customer ID: [redacted]"
+        );
+        assert!(redactor.add_pattern("(", "").is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! The single place, that decides where errors are reported to.
+//!
+//! Until the logger is initialized, errors are written to stderr, so failures during startup are visible at all.
+//! Afterwards they are only logged, because the log output is where operators look for them, unless the command
+//! line tool runs a command, whose user watches the terminal (e.g. `replay`). Additionally, every error is counted in
+//! the metrics and posted to the error webhook, if these are set up.
+
+use log::{warn, Level, Record};
+use serde_json::json;
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::metrics::Metrics;
+
+static LOGGER_READY: AtomicBool = AtomicBool::new(false);
+static ALWAYS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+static SINKS: Mutex<Sinks> = Mutex::new(Sinks {
+    metrics: None,
+    webhook: None,
+});
+
+/// Reports an error, that an operator should notice. Takes the same arguments as `format!()`, optionally preceded by
+/// key-values for the log like `mapping = name;`.
+macro_rules! report_error {
+    ($($key:ident = $value:expr),+; $($arg:tt)+) => {
+        $crate::report::error(
+            module_path!(),
+            &[$((stringify!($key), $value.to_string())),+],
+            &format!($($arg)+),
+        )
+    };
+    ($($arg:tt)+) => {
+        $crate::report::error(module_path!(), &[], &format!($($arg)+))
+    };
+}
+pub(crate) use report_error;
+
+/// The places besides stderr and the log, that errors are reported to.
+struct Sinks {
+    metrics: Option<Arc<Metrics>>,
+    /// The URL, that errors are posted to as JSON objects, and the client posting them.
+    webhook: Option<(String, reqwest::Client)>,
+}
+
+impl Sinks {
+    fn report(&self, target: &str, message: &str) {
+        if let Some(ref metrics) = self.metrics {
+            metrics.observe_reported_error();
+        }
+        if let Some((ref url, ref client)) = self.webhook {
+            // Errors outside of a runtime (e.g. while parsing the command line) are not posted:
+            let runtime = match tokio::runtime::Handle::try_current() {
+                Ok(runtime) => runtime,
+                Err(_) => return,
+            };
+            let request = client
+                .post(url)
+                .json(&json!({ "target": target, "message": message }));
+            runtime.spawn(async move {
+                // Not reported as error, because that would post again:
+                match request.send().await.and_then(|res| res.error_for_status()) {
+                    Ok(_) => {}
+                    Err(e) => warn!("Could not post error to webhook: {}", e),
+                }
+            });
+        }
+    }
+}
+
+/// Marks the logger as initialized, so errors are not written to stderr anymore.
+pub(crate) fn logger_ready() {
+    LOGGER_READY.store(true, Ordering::Relaxed);
+}
+
+/// Lets errors be written to stderr even after the logger is initialized, e.g. for commands run in a terminal.
+pub(crate) fn always_to_stderr() {
+    ALWAYS_TO_STDERR.store(true, Ordering::Relaxed);
+}
+
+/// Lets every reported error be counted in the given metrics.
+pub(crate) fn set_metrics(metrics: Arc<Metrics>) {
+    SINKS.lock().expect("Report sinks are poisoned.").metrics = Some(metrics);
+}
+
+/// Lets every reported error be posted to the given URL.
+pub(crate) fn set_webhook(url: impl Into<String>) {
+    SINKS.lock().expect("Report sinks are poisoned.").webhook =
+        Some((url.into(), reqwest::Client::new()));
+}
+
+/// Reports `message` with the key-values `kvs` as if it was logged in the module `target`. Use `report_error!()`
+/// instead of calling this.
+pub(crate) fn error(target: &str, kvs: &[(&str, String)], message: &str) {
+    write_error(&mut std::io::stderr(), target, kvs, message);
+    SINKS
+        .lock()
+        .expect("Report sinks are poisoned.")
+        .report(target, message);
+}
+
+fn write_error(stderr: &mut impl Write, target: &str, kvs: &[(&str, String)], message: &str) {
+    if !LOGGER_READY.load(Ordering::Relaxed) || ALWAYS_TO_STDERR.load(Ordering::Relaxed) {
+        // There is nowhere else to report to, if this fails:
+        let _ = writeln!(stderr, "{}", message);
+    }
+    let kvs: Vec<(&str, &str)> = kvs
+        .iter()
+        .map(|(key, value)| (*key, value.as_str()))
+        .collect();
+    let kvs = &kvs[..];
+    log::logger().log(
+        &Record::builder()
+            .level(Level::Error)
+            .target(target)
+            .args(format_args!("{}", message))
+            .key_values(&kvs)
+            .build(),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use wiremock::matchers::{body_json, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_stderr_until_logger_ready() {
+        let mut stderr = vec![];
+        write_error(&mut stderr, module_path!(), &[], "Could not start.");
+        assert_eq!(stderr, b"Could not start.\n");
+
+        logger_ready();
+        let mut stderr = vec![];
+        write_error(&mut stderr, module_path!(), &[], "Could not deliver.");
+        assert!(stderr.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sinks() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_json(
+                json!({ "target": "kutsche::queue", "message": "Could not deliver." }),
+            ))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let metrics = Arc::new(Metrics::new());
+        let sinks = Sinks {
+            metrics: Some(metrics.clone()),
+            webhook: Some((server.uri(), reqwest::Client::new())),
+        };
+
+        sinks.report("kutsche::queue", "Could not deliver.");
+        assert!(metrics
+            .render()
+            .lines()
+            .any(|line| line == "kutsche_reported_errors_total 1"));
+        // The request is sent in the background:
+        for _ in 0..50 {
+            if !server.received_requests().await.unwrap().is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        server.verify().await;
+    }
+}
@@ -0,0 +1,178 @@
+//! Credentials of destinations (passwords and tokens), that can be rotated while the server is running.
+
+use log::info;
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+use crate::Error;
+
+/// A password or token, that is shared by a destination and the control socket.
+///
+/// A secret read from a file is read again by `reload()`, which destinations call after the credential was rejected,
+/// so a rotated credential is picked up without a restart. Every secret can also be replaced with `set()`.
+#[derive(Clone)]
+pub(crate) struct Secret {
+    value: Arc<RwLock<String>>,
+    path: Option<Arc<PathBuf>>,
+}
+
+impl Secret {
+    /// Reads the secret from the file at `path`, ignoring leading and trailing whitespace (e.g. the final newline).
+    pub(crate) fn from_file(path: impl Into<PathBuf>) -> Result<Self, Error> {
+        let path = path.into();
+        let value = read_file(&path)?;
+        Ok(Secret {
+            value: Arc::new(RwLock::new(value)),
+            path: Some(Arc::new(path)),
+        })
+    }
+
+    pub(crate) fn get(&self) -> String {
+        self.value.read().unwrap().clone()
+    }
+
+    pub(crate) fn set(&self, value: impl Into<String>) {
+        *self.value.write().unwrap() = value.into();
+    }
+
+    /// Returns the file, that the secret is read from, if any.
+    pub(crate) fn path(&self) -> Option<&Path> {
+        self.path.as_deref().map(PathBuf::as_path)
+    }
+
+    /// Reads the file of the secret again and returns true, if its value changed. Secrets without file never change.
+    pub(crate) fn reload(&self) -> Result<bool, Error> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(false),
+        };
+        let value = read_file(path)?;
+        let mut current = self.value.write().unwrap();
+        if *current == value {
+            Ok(false)
+        } else {
+            info!("Reloaded rotated secret from {}.", path.display());
+            *current = value;
+            Ok(true)
+        }
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        Secret::from(value.to_string())
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Secret {
+            value: Arc::new(RwLock::new(value)),
+            path: None,
+        }
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, Error> {
+    let value = std::fs::read_to_string(path).map_err(|e| {
+        Error::config(format!(
+            "Could not read secret from {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+    let value = value.trim();
+    if value.is_empty() {
+        return Err(Error::config(format!(
+            "Secret file {} is empty.",
+            path.display()
+        )));
+    }
+    Ok(value.to_string())
+}
+
+/// The secrets of all destinations by name, e.g. "matrix.password" for the field 'password' of the destination
+/// 'matrix', so the control socket can rotate them.
+#[derive(Clone, Default)]
+pub(crate) struct SecretStore {
+    secrets: Arc<Mutex<BTreeMap<String, Secret>>>,
+}
+
+impl SecretStore {
+    /// Returns the secret with the given name, or loads and registers it, if there is none yet. Destinations loaded
+    /// for multiple mappings share their secrets this way.
+    pub(crate) fn get_or_load(
+        &self,
+        name: &str,
+        load: impl FnOnce() -> Result<Secret, Error>,
+    ) -> Result<Secret, Error> {
+        let mut secrets = self.secrets.lock().unwrap();
+        match secrets.get(name) {
+            Some(secret) => Ok(secret.clone()),
+            None => {
+                let secret = load()?;
+                secrets.insert(name.to_string(), secret.clone());
+                Ok(secret)
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, name: &str) -> Option<Secret> {
+        self.secrets.lock().unwrap().get(name).cloned()
+    }
+
+    /// Returns the names of all secrets with the files, that they are read from.
+    pub(crate) fn list(&self) -> Vec<(String, Option<PathBuf>)> {
+        self.secrets
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, secret)| (name.clone(), secret.path().map(Path::to_path_buf)))
+            .collect()
+    }
+
+    /// Reads all secrets from their files again and returns the names of the ones, that changed.
+    pub(crate) fn reload_all(&self) -> Result<Vec<String>, Error> {
+        let mut changed = vec![];
+        for (name, secret) in self.secrets.lock().unwrap().iter() {
+            if secret.reload()? {
+                changed.push(name.clone());
+            }
+        }
+        Ok(changed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("token");
+        std::fs::write(&path, "old\n").unwrap();
+        let store = SecretStore::default();
+        let secret = store
+            .get_or_load("webhook.bearer_token", || Secret::from_file(&path))
+            .unwrap();
+        assert_eq!(secret.get(), "old");
+        // The secret is shared with the store:
+        let shared = store
+            .get_or_load("webhook.bearer_token", || unreachable!())
+            .unwrap();
+
+        assert!(!secret.reload().unwrap());
+        std::fs::write(&path, "new\n").unwrap();
+        assert_eq!(store.reload_all().unwrap(), ["webhook.bearer_token"]);
+        assert_eq!(shared.get(), "new");
+
+        shared.set("rotated");
+        assert_eq!(secret.get(), "rotated");
+        std::fs::write(&path, "").unwrap();
+        assert!(secret.reload().is_err());
+        assert!(!Secret::from("inline").reload().unwrap());
+    }
+}
@@ -0,0 +1,405 @@
+//! End-to-end monitoring of the server.
+//!
+//! The server periodically sends a probe email to itself through a listener (or an external relay, that forwards it
+//! back) and waits until a file destination stored it. If a probe is not delivered in time, this is logged, counted in
+//! the metrics and reported to an optional webhook, so a server, that stopped accepting or delivering emails, doesn't
+//! fail silently.
+
+use log::{error, info, warn};
+use serde_json::json;
+use tokio::time::{interval, sleep, timeout, MissedTickBehavior};
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::Config;
+use crate::email::{rfc5322_date, Email};
+use crate::maildest::{EmailDestination, RelayDestination, StartTls};
+use crate::metrics::Metrics;
+use crate::Error;
+
+/// The time between two checks, whether the probe was stored.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The section 'self_test' of the config file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct SelfTestConfig {
+    /// The recipient of the probes. It must be mapped to a file destination.
+    pub(crate) address: String,
+    /// The SMTP server, that probes are sent to. If None, they are sent to the first listener.
+    pub(crate) relay: Option<(String, u16)>,
+    pub(crate) interval: Duration,
+    /// The time, in which a probe must be delivered.
+    pub(crate) timeout: Duration,
+    /// The URL, that failed and recovered self-tests are posted to.
+    pub(crate) webhook: Option<String>,
+}
+
+impl TryFrom<&toml::map::Map<String, toml::Value>> for SelfTestConfig {
+    type Error = Error;
+
+    fn try_from(section: &toml::map::Map<String, toml::Value>) -> Result<Self, Self::Error> {
+        let address = section
+            .get("address")
+            .ok_or_else(|| Error::config("Missing field 'self_test.address'."))?
+            .as_str()
+            .ok_or_else(|| {
+                Error::config("Field 'self_test.address' has wrong type (expected string).")
+            })?
+            .to_string();
+        let relay = match section.get("relay") {
+            Some(relay) => Some(
+                relay
+                    .as_str()
+                    .and_then(|relay| relay.rsplit_once(':'))
+                    .and_then(|(host, port)| Some((host.to_string(), port.parse().ok()?)))
+                    .ok_or_else(|| {
+                        Error::config(
+                            "Field 'self_test.relay' has wrong type (expected \"host:port\").",
+                        )
+                    })?,
+            ),
+            None => None,
+        };
+        let secs = |field: &str, default: u64| match section.get(field) {
+            Some(val) => val
+                .as_integer()
+                .and_then(|n| u64::try_from(n).ok())
+                .filter(|n| *n > 0)
+                .map(Duration::from_secs)
+                .ok_or_else(|| {
+                    Error::config(format!(
+                        "Field 'self_test.{}' has wrong type (expected positive integer).",
+                        field
+                    ))
+                }),
+            None => Ok(Duration::from_secs(default)),
+        };
+        let interval = secs("interval_secs", 300)?;
+        let timeout = secs("timeout_secs", 60)?;
+        if timeout >= interval {
+            return Err(Error::config(
+                "Field 'self_test.timeout_secs' must be smaller than 'self_test.interval_secs'.",
+            ));
+        }
+        let webhook = match section.get("webhook") {
+            Some(webhook) => Some(
+                webhook
+                    .as_str()
+                    .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+                    .ok_or_else(|| {
+                        Error::config("Field 'self_test.webhook' has wrong type (expected URL).")
+                    })?
+                    .to_string(),
+            ),
+            None => None,
+        };
+        Ok(SelfTestConfig {
+            address,
+            relay,
+            interval,
+            timeout,
+            webhook,
+        })
+    }
+}
+
+/// Sends probes and checks, that they are delivered.
+pub(crate) struct SelfTest {
+    config: SelfTestConfig,
+    hostname: String,
+    /// The directory, in which the file destination of the probe address stores emails.
+    storage_path: PathBuf,
+    relay: RelayDestination,
+    metrics: Option<Arc<Metrics>>,
+    /// Whether the last probe failed, so failures and recoveries are only reported once.
+    failing: AtomicBool,
+    sent: AtomicU64,
+}
+
+impl SelfTest {
+    /// Creates a self-test, that sends probes to `host`:`port` and expects them in `storage_path`.
+    pub(crate) fn new(
+        config: SelfTestConfig,
+        hostname: impl Into<String>,
+        host: String,
+        port: u16,
+        storage_path: impl Into<PathBuf>,
+    ) -> Result<Self, Error> {
+        let mut relay = RelayDestination::new(Some(host), port, config.address.clone())?;
+        // Our own listeners have no certificate for their loopback address:
+        if config.relay.is_none() {
+            relay.set_starttls(StartTls::Disabled);
+        }
+        Ok(SelfTest {
+            config,
+            hostname: hostname.into(),
+            storage_path: storage_path.into(),
+            relay,
+            metrics: None,
+            failing: AtomicBool::new(false),
+            sent: AtomicU64::new(0),
+        })
+    }
+
+    /// Creates the self-test configured in the section 'self_test' of the config file, if there is one.
+    ///
+    /// Without a relay, probes are sent to the first listener without implicit TLS, using the loopback address, if the
+    /// listener is bound to all addresses.
+    pub(crate) fn from_config(config: &Config) -> Result<Option<Self>, Error> {
+        let self_test_config = match config.self_test {
+            Some(ref self_test_config) => self_test_config.clone(),
+            None => return Ok(None),
+        };
+        let storage_path = config
+            .route(&self_test_config.address)
+            .filter(|(_, mapping)| {
+                !mapping.destination.is_maildir() && !mapping.destination.is_mbox()
+            })
+            .and_then(|(_, mapping)| mapping.destination.storage_path())
+            .ok_or_else(|| {
+                Error::config(format!(
+                    "The self-test address {} is not mapped to a file destination (without maildir or mbox format).",
+                    self_test_config.address
+                ))
+            })?
+            .to_path_buf();
+        let (host, port) = match self_test_config.relay {
+            Some(ref relay) => relay.clone(),
+            None => {
+                let listener = config
+                    .local_addrs
+                    .iter()
+                    .find(|addr| addr.port() != 465)
+                    .ok_or_else(|| {
+                        Error::config(
+                            "The self-test needs a relay or a listener without implicit TLS.",
+                        )
+                    })?;
+                let ip = match listener.ip() {
+                    IpAddr::V4(ip) if ip.is_unspecified() => IpAddr::V4(Ipv4Addr::LOCALHOST),
+                    IpAddr::V6(ip) if ip.is_unspecified() => IpAddr::V6(Ipv6Addr::LOCALHOST),
+                    ip => ip,
+                };
+                (ip.to_string(), listener.port())
+            }
+        };
+        let mut self_test = SelfTest::new(
+            self_test_config,
+            config.hostname.as_str(),
+            host,
+            port,
+            storage_path,
+        )?;
+        self_test.relay.set_resolver(config.resolver.get()?);
+        Ok(Some(self_test))
+    }
+
+    /// Lets the results of the probes be recorded in the given metrics.
+    pub(crate) fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Sends a probe and waits until it was stored. Returns the time this took.
+    pub(crate) async fn probe(&self) -> Result<Duration, Error> {
+        let started = Instant::now();
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let message_id = format!(
+            "self-test-{}-{}@{}",
+            now,
+            self.sent.fetch_add(1, Ordering::Relaxed),
+            self.hostname
+        );
+        let raw = format!(
+            "From: kutsche <{address}>\r\n\
+To: <{address}>\r\n\
+Subject: kutsche self-test\r\n\
+Date: {date}\r\n\
+Message-ID: <{message_id}>\r\n\
+\r\n\
+This email checks, that {hostname} accepts and delivers emails.\r\n",
+            address = self.config.address,
+            date = rfc5322_date(now),
+            message_id = message_id,
+            hostname = self.hostname,
+        );
+        let email = Email::parse(raw.as_bytes())?;
+        let stored = self.storage_path.join(&message_id);
+
+        let delivery = async {
+            self.relay.write_email(&email).await?;
+            while !stored.is_file() {
+                sleep(POLL_INTERVAL).await;
+            }
+            Ok(())
+        };
+        let res = match timeout(self.config.timeout, delivery).await {
+            Ok(res) => res.map(|()| started.elapsed()),
+            Err(_) => Err(Error::SysIo(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                format!(
+                    "The self-test email was not delivered within {} seconds.",
+                    self.config.timeout.as_secs()
+                ),
+            ))),
+        };
+        remove_probe(&stored);
+        res
+    }
+
+    /// Sends a probe and reports its result.
+    pub(crate) async fn check(&self) {
+        let res = self.probe().await;
+        if let Some(ref metrics) = self.metrics {
+            metrics.observe_self_test(res.as_ref().ok().copied());
+        }
+        match res {
+            Ok(duration) => {
+                info!(
+                    "Self-test email was delivered after {} ms.",
+                    duration.as_millis()
+                );
+                if self.failing.swap(false, Ordering::Relaxed) {
+                    info!("Self-test succeeded again.");
+                    self.notify("self_test_recovered", None).await;
+                }
+            }
+            Err(e) => {
+                error!("Self-test failed: {}", e);
+                if !self.failing.swap(true, Ordering::Relaxed) {
+                    self.notify("self_test_failed", Some(&e)).await;
+                }
+            }
+        }
+    }
+
+    /// Posts an event to the webhook, if one is configured.
+    async fn notify(&self, event: &str, problem: Option<&Error>) {
+        let url = match self.config.webhook {
+            Some(ref url) => url,
+            None => return,
+        };
+        let body = json!({
+            "event": event,
+            "hostname": self.hostname,
+            "address": self.config.address,
+            "error": problem.map(|e| e.to_string()),
+        });
+        let res = reqwest::Client::new()
+            .post(url)
+            .timeout(self.config.timeout)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(e) = res {
+            warn!("Could not notify self-test webhook: {}", e);
+        }
+    }
+
+    /// Sends probes periodically forever.
+    pub(crate) async fn run(&self) {
+        let mut ticker = interval(self.config.interval);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            self.check().await;
+        }
+    }
+}
+
+/// Removes a stored probe and its envelope, so probes don't fill the storage.
+fn remove_probe(stored: &Path) {
+    if !stored.is_file() {
+        return;
+    }
+    if let Err(e) = fs::remove_file(stored) {
+        warn!(
+            "Could not remove self-test email {}: {}",
+            stored.display(),
+            e
+        );
+    }
+    let mut envelope = stored.as_os_str().to_os_string();
+    envelope.push(".envelope.json");
+    let _ = fs::remove_file(envelope);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use wiremock::matchers::{body_partial_json, method};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    #[test]
+    fn test_config() {
+        let section = toml::from_str(
+            r#"
+address = "selftest@example.com"
+relay = "smtp.example.org:587"
+timeout_secs = 30
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            SelfTestConfig::try_from(&section).unwrap(),
+            SelfTestConfig {
+                address: "selftest@example.com".to_string(),
+                relay: Some(("smtp.example.org".to_string(), 587)),
+                interval: Duration::from_secs(300),
+                timeout: Duration::from_secs(30),
+                webhook: None,
+            }
+        );
+
+        let section =
+            toml::from_str("address = \"selftest@example.com\"\ntimeout_secs = 300").unwrap();
+        assert!(SelfTestConfig::try_from(&section).is_err());
+        let section =
+            toml::from_str("address = \"selftest@example.com\"\nrelay = \"smtp\"").unwrap();
+        assert!(SelfTestConfig::try_from(&section).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_failure_is_reported_once() {
+        let webhook = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(body_partial_json(json!({ "event": "self_test_failed" })))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(1)
+            .mount(&webhook)
+            .await;
+        let dir = tempfile::tempdir().unwrap();
+        let config = SelfTestConfig {
+            address: "selftest@example.com".to_string(),
+            relay: None,
+            interval: Duration::from_secs(2),
+            timeout: Duration::from_secs(1),
+            webhook: Some(webhook.uri()),
+        };
+        // Nothing listens on the discard port:
+        let mut self_test = SelfTest::new(
+            config,
+            "mx.example.com",
+            "127.0.0.1".to_string(),
+            9,
+            dir.path(),
+        )
+        .unwrap();
+        let metrics = Arc::new(Metrics::new());
+        self_test.set_metrics(metrics.clone());
+
+        self_test.check().await;
+        self_test.check().await;
+        assert!(metrics
+            .render()
+            .contains("kutsche_self_test_failures_total 2\n"));
+    }
+}
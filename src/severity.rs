@@ -0,0 +1,239 @@
+use regex::Regex;
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use crate::Error;
+
+/// How urgent an email is, as determined by a `SeverityClassifier`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Severity {
+    Critical,
+    Warning,
+    Info,
+}
+
+impl Severity {
+    fn default_emoji(&self) -> &'static str {
+        match self {
+            Severity::Critical => "🔴",
+            Severity::Warning => "🟠",
+            Severity::Info => "🔵",
+        }
+    }
+
+    /// Returns true, if notifications with this severity should not draw attention, e.g. by being sent as `m.notice`
+    /// in Matrix.
+    pub(crate) fn is_notice(&self) -> bool {
+        *self == Severity::Info
+    }
+}
+
+impl FromStr for Severity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "critical" => Ok(Severity::Critical),
+            "warning" => Ok(Severity::Warning),
+            "info" => Ok(Severity::Info),
+            _ => Err(Error::config(format!(
+                "Unknown severity '{}' (expected critical, warning or info).",
+                s
+            ))),
+        }
+    }
+}
+
+/// The result of classifying an email.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Classification<'a> {
+    pub(crate) severity: Severity,
+    pub(crate) emoji: &'a str,
+    /// The color of the notification as HTML color, if one was configured.
+    pub(crate) color: Option<&'a str>,
+}
+
+struct SeverityRule {
+    pattern: Regex,
+    severity: Severity,
+    emoji: Option<String>,
+    color: Option<String>,
+    /// The listeners, to whose emails the rule applies. If empty, the rule applies to all emails.
+    listeners: Vec<SocketAddr>,
+    /// Whether the rule only applies to emails, whose envelope recipient doesn't appear in the To and Cc headers.
+    bcc: bool,
+}
+
+impl SeverityRule {
+    fn matches(&self, subject: &str, listener: Option<SocketAddr>, bcc: bool) -> bool {
+        let listener_matches = self.listeners.is_empty()
+            || listener.is_some_and(|listener| self.listeners.contains(&listener));
+        listener_matches && (bcc || !self.bcc) && self.pattern.is_match(subject)
+    }
+}
+
+/// Classifies emails by matching their subject against a list of regular expressions.
+/// The first matching rule determines the severity. Rules may be restricted to emails received on certain listeners
+/// and to emails delivered by Bcc.
+#[derive(Default)]
+pub(crate) struct SeverityClassifier {
+    rules: Vec<SeverityRule>,
+}
+
+impl SeverityClassifier {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a rule, that applies to subjects matching `pattern` of emails received on one of `listeners` (or on any
+    /// listener, if empty). If `bcc` is true, the rule only applies to emails, whose envelope recipient is hidden from
+    /// the To and Cc headers. If no emoji is given, a default for the severity is used.
+    pub(crate) fn add_rule(
+        &mut self,
+        pattern: &str,
+        severity: Severity,
+        emoji: Option<String>,
+        color: Option<String>,
+        listeners: Vec<SocketAddr>,
+        bcc: bool,
+    ) -> Result<(), Error> {
+        let pattern = Regex::new(pattern)
+            .map_err(|e| Error::config(format!("Invalid severity pattern: {}", e)))?;
+        self.rules.push(SeverityRule {
+            pattern,
+            severity,
+            emoji,
+            color,
+            listeners,
+            bcc,
+        });
+        Ok(())
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.rules.len()
+    }
+
+    /// Returns the classification of the first rule matching `subject`, the listener, that received the email, and
+    /// whether the email was delivered by Bcc, or None, if no rule matches.
+    pub(crate) fn classify(
+        &self,
+        subject: &str,
+        listener: Option<SocketAddr>,
+        bcc: bool,
+    ) -> Option<Classification<'_>> {
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(subject, listener, bcc))
+            .map(|rule| Classification {
+                severity: rule.severity,
+                emoji: rule
+                    .emoji
+                    .as_deref()
+                    .unwrap_or_else(|| rule.severity.default_emoji()),
+                color: rule.color.as_deref(),
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_first_match_wins() {
+        let mut classifier = SeverityClassifier::new();
+        classifier
+            .add_rule(
+                "(?i)down|failed",
+                Severity::Critical,
+                None,
+                None,
+                vec![],
+                false,
+            )
+            .unwrap();
+        classifier
+            .add_rule(
+                "(?i)backup",
+                Severity::Info,
+                Some("💾".to_string()),
+                Some("#00ff00".to_string()),
+                vec![],
+                false,
+            )
+            .unwrap();
+
+        assert_eq!(
+            classifier.classify("Backup FAILED", None, false).unwrap(),
+            Classification {
+                severity: Severity::Critical,
+                emoji: "🔴",
+                color: None,
+            }
+        );
+        let backup = classifier.classify("Backup finished", None, false).unwrap();
+        assert_eq!(backup.emoji, "💾");
+        assert!(backup.severity.is_notice());
+        assert!(classifier.classify("Hello", None, false).is_none());
+    }
+
+    #[test]
+    fn test_listener_rule() {
+        let internal: SocketAddr = "127.0.0.1:2525".parse().unwrap();
+        let mut classifier = SeverityClassifier::new();
+        classifier
+            .add_rule("", Severity::Info, None, None, vec![internal], false)
+            .unwrap();
+        classifier
+            .add_rule("", Severity::Warning, None, None, vec![], false)
+            .unwrap();
+
+        let classify = |listener| {
+            classifier
+                .classify("Test", listener, false)
+                .unwrap()
+                .severity
+        };
+        assert_eq!(classify(Some(internal)), Severity::Info);
+        assert_eq!(
+            classify(Some("0.0.0.0:25".parse().unwrap())),
+            Severity::Warning
+        );
+        assert_eq!(classify(None), Severity::Warning);
+    }
+
+    #[test]
+    fn test_bcc_rule() {
+        let mut classifier = SeverityClassifier::new();
+        classifier
+            .add_rule(
+                "",
+                Severity::Warning,
+                Some("🙈 BCC delivery:".to_string()),
+                None,
+                vec![],
+                true,
+            )
+            .unwrap();
+        classifier
+            .add_rule("", Severity::Info, None, None, vec![], false)
+            .unwrap();
+
+        let bcc = classifier.classify("Test", None, true).unwrap();
+        assert_eq!(bcc.severity, Severity::Warning);
+        assert_eq!(bcc.emoji, "🙈 BCC delivery:");
+        assert_eq!(
+            classifier.classify("Test", None, false).unwrap().severity,
+            Severity::Info
+        );
+    }
+
+    #[test]
+    fn test_invalid_pattern() {
+        assert!(SeverityClassifier::new()
+            .add_rule("(", Severity::Info, None, None, vec![], false)
+            .is_err());
+    }
+}
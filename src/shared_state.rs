@@ -0,0 +1,107 @@
+//! State, that multiple servers behind a load balancer keep in Redis, so their policy decisions (e.g. the rate limits
+//! of tenants) are consistent.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::error::DatabaseErrorCode;
+use crate::redis::{RedisClient, Reply};
+use crate::Error;
+
+/// Counts an event in the window, whose key is KEYS[1], unless the limit ARGV[1] is reached. The key expires after
+/// ARGV[2] seconds. Returns 1, if the event was counted.
+const COUNT_SCRIPT: &str = "local n = redis.call('INCR', KEYS[1]) \
+if n == 1 then redis.call('EXPIRE', KEYS[1], ARGV[2]) end \
+if n > tonumber(ARGV[1]) then redis.call('DECR', KEYS[1]) return 0 end \
+return 1";
+
+pub(crate) struct SharedState {
+    client: RedisClient,
+    /// The prefix of all keys, so multiple deployments can share a Redis database.
+    key_prefix: String,
+}
+
+impl SharedState {
+    pub(crate) fn new(client: RedisClient, key_prefix: impl Into<String>) -> Self {
+        SharedState {
+            client,
+            key_prefix: key_prefix.into(),
+        }
+    }
+
+    /// Counts an event for `key` in the current window of the given length, unless `max` events were counted in it
+    /// already. Returns whether the event was counted.
+    ///
+    /// The check and the increment happen in one script on the server, so concurrent servers never exceed the limit
+    /// together.
+    pub(crate) async fn count(&self, key: &str, max: u32, window: Duration) -> Result<bool, Error> {
+        let window_secs = window.as_secs().max(1);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let key = format!("{}{}:{}", self.key_prefix, key, now / window_secs);
+        let reply = self
+            .client
+            .query(&[
+                b"EVAL",
+                COUNT_SCRIPT.as_bytes(),
+                b"1",
+                key.as_bytes(),
+                max.to_string().as_bytes(),
+                (window_secs * 2).to_string().as_bytes(),
+            ])
+            .await?;
+        match reply {
+            Reply::Integer(counted) => Ok(counted == 1),
+            _ => Err(Error::database(
+                DatabaseErrorCode::Query,
+                format!(
+                    "Unexpected reply of Redis server {} to counting script.",
+                    self.client.address()
+                ),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufStream};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_count() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = BufStream::new(stream);
+            let mut args = vec![];
+            for reply in [":1\r\n", ":0\r\n"] {
+                let mut line = String::new();
+                stream.read_line(&mut line).await.unwrap();
+                assert_eq!(line, "*6\r\n");
+                for _ in 0..6 {
+                    // Skip the length and read the argument:
+                    stream.read_line(&mut line).await.unwrap();
+                    line.clear();
+                    stream.read_line(&mut line).await.unwrap();
+                    args.push(line.trim_end().to_string());
+                }
+                stream.write_all(reply.as_bytes()).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+            args
+        });
+        let address = format!("redis://127.0.0.1:{}", port).parse().unwrap();
+        let state = SharedState::new(RedisClient::new(address), "kutsche:");
+        let window = Duration::from_secs(60);
+        assert!(state.count("rate:acme", 5, window).await.unwrap());
+        assert!(!state.count("rate:acme", 5, window).await.unwrap());
+
+        let args = server.await.unwrap();
+        assert_eq!(args[0], "EVAL");
+        assert!(args[3].starts_with("kutsche:rate:acme:"));
+        assert_eq!(&args[4..6], ["5", "120"]);
+    }
+}
@@ -0,0 +1,80 @@
+use std::time::Duration;
+
+/// How a listener introduces itself to clients.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Greeting {
+    /// The text after the hostname in the greeting. If None, the greeting is "ESMTP".
+    pub(crate) banner: Option<String>,
+    /// The time, that the server waits before sending the greeting. Clients, that send commands during this time, are
+    /// rejected, because well-behaved clients wait for the greeting (RFC 5321, section 3.1).
+    pub(crate) delay: Duration,
+    /// The ESMTP keywords (e.g. "8BITMIME"), that are advertised in the reply to EHLO. If None, all keywords supported
+    /// by the server are advertised.
+    pub(crate) esmtp_keywords: Option<Vec<String>>,
+}
+
+impl Greeting {
+    /// Returns the reply to EHLO `reply` without the keywords, that should not be advertised.
+    pub(crate) fn filter_ehlo(&self, reply: &[u8]) -> Vec<u8> {
+        let keywords = match self.esmtp_keywords {
+            Some(ref keywords) => keywords,
+            None => return reply.to_vec(),
+        };
+        let text = String::from_utf8_lossy(reply);
+        let mut lines = text.lines();
+        let first = match lines.next() {
+            Some(first) if first.len() >= 4 => first,
+            _ => return reply.to_vec(),
+        };
+        let code = &first[..3];
+        // The first line holds the hostname and is always kept:
+        let mut kept = vec![&first[4..]];
+        kept.extend(lines.filter_map(|line| line.get(4..)).filter(|extension| {
+            let keyword = extension.split_whitespace().next().unwrap_or_default();
+            keywords
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(keyword))
+        }));
+
+        let mut filtered = String::new();
+        for (i, line) in kept.iter().enumerate() {
+            let separator = if i + 1 < kept.len() { '-' } else { ' ' };
+            filtered.push_str(code);
+            filtered.push(separator);
+            filtered.push_str(line);
+            filtered.push_str("\r\n");
+        }
+        filtered.into_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_ehlo() {
+        let reply = b"250-mx.example.com offers a warm hug of welcome\r\n\
+250-8BITMIME\r\n\
+250-ENHANCEDSTATUSCODES\r\n\
+250-STARTTLS\r\n\
+250 AUTH PLAIN\r\n";
+        let mut greeting = Greeting::default();
+        assert_eq!(greeting.filter_ehlo(reply), reply.to_vec());
+
+        greeting.esmtp_keywords = Some(vec!["starttls".to_string(), "8BITMIME".to_string()]);
+        assert_eq!(
+            greeting.filter_ehlo(reply),
+            b"250-mx.example.com offers a warm hug of welcome\r\n\
+250-8BITMIME\r\n\
+250 STARTTLS\r\n"
+                .to_vec()
+        );
+
+        greeting.esmtp_keywords = Some(vec![]);
+        assert_eq!(
+            greeting.filter_ehlo(reply),
+            b"250 mx.example.com offers a warm hug of welcome\r\n".to_vec()
+        );
+    }
+}
@@ -0,0 +1,127 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use rustls::ServerConnection;
+
+/// What is known about the client of an SMTP session, that sent an email.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ClientIdentity {
+    /// The address of the client. It is unknown for clients of hidden service listeners.
+    pub(crate) peer: Option<SocketAddr>,
+    /// The client connected through a listener, that is exposed as (Tor) hidden service.
+    pub(crate) hidden_service: bool,
+    /// The name the client introduced itself with in HELO or EHLO.
+    pub(crate) helo: Option<String>,
+    /// The software, that the email claims to be sent with (User-Agent or X-Mailer header).
+    pub(crate) software: Option<String>,
+    pub(crate) tls: Option<TlsParameters>,
+    /// The local address of the listener, that the client connected to.
+    pub(crate) listener: Option<SocketAddr>,
+    pub(crate) listener_tls: TlsMode,
+}
+
+/// How the listener, that a client connected to, offers TLS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub(crate) enum TlsMode {
+    /// The listener offers no TLS.
+    #[default]
+    None,
+    /// The listener offers STARTTLS.
+    StartTls,
+    /// Sessions start with a TLS handshake.
+    Implicit,
+}
+
+impl TlsMode {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            TlsMode::None => "none",
+            TlsMode::StartTls => "starttls",
+            TlsMode::Implicit => "implicit",
+        }
+    }
+}
+
+/// The parameters negotiated for a TLS connection.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct TlsParameters {
+    pub(crate) protocol: String,
+    pub(crate) cipher_suite: String,
+    /// The server name the client requested with SNI.
+    pub(crate) server_name: Option<String>,
+}
+
+impl TlsParameters {
+    pub(crate) fn of(connection: &ServerConnection) -> Self {
+        TlsParameters {
+            protocol: connection
+                .protocol_version()
+                .map_or_else(|| "unknown".to_string(), |v| format!("{:?}", v)),
+            cipher_suite: connection
+                .negotiated_cipher_suite()
+                .map_or_else(|| "unknown".to_string(), |s| format!("{:?}", s.suite())),
+            server_name: connection.sni_hostname().map(String::from),
+        }
+    }
+}
+
+impl fmt::Display for ClientIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.peer {
+            Some(peer) => write!(f, "{}", peer)?,
+            None if self.hidden_service => write!(f, "hidden service")?,
+            None => write!(f, "unknown peer")?,
+        }
+        if let Some(ref helo) = self.helo {
+            write!(f, ", helo {}", helo)?;
+        }
+        match self.tls {
+            Some(ref tls) => write!(f, ", {} {}", tls.protocol, tls.cipher_suite)?,
+            None => write!(f, ", no TLS")?,
+        }
+        if let Some(ref software) = self.software {
+            write!(f, ", software \"{}\"", software)?;
+        }
+        if let Some(listener) = self.listener {
+            write!(f, ", via {} ({})", listener, self.listener_tls.name())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let mut identity = ClientIdentity {
+            peer: Some("192.0.2.1:4711".parse().unwrap()),
+            ..ClientIdentity::default()
+        };
+        assert_eq!(identity.to_string(), "192.0.2.1:4711, no TLS");
+
+        identity.helo = Some("mail.example.com".to_string());
+        identity.software = Some("cron".to_string());
+        identity.tls = Some(TlsParameters {
+            protocol: "TLSv1_3".to_string(),
+            cipher_suite: "TLS13_AES_128_GCM_SHA256".to_string(),
+            server_name: None,
+        });
+        assert_eq!(
+            identity.to_string(),
+            "192.0.2.1:4711, helo mail.example.com, TLSv1_3 TLS13_AES_128_GCM_SHA256, software \"cron\""
+        );
+        identity.listener = Some("192.0.2.25:465".parse().unwrap());
+        identity.listener_tls = TlsMode::Implicit;
+        assert!(identity
+            .to_string()
+            .ends_with(", via 192.0.2.25:465 (implicit)"));
+
+        let identity = ClientIdentity {
+            hidden_service: true,
+            ..ClientIdentity::default()
+        };
+        assert_eq!(identity.to_string(), "hidden service, no TLS");
+    }
+}
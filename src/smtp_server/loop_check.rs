@@ -0,0 +1,101 @@
+use log::warn;
+
+use crate::email::Email;
+use crate::Error;
+
+/// What happens to an inbound email, that seems to be looping.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LoopAction {
+    /// The email is rejected with 550.
+    Reject,
+    /// The email is accepted with an `X-Loop-Suspected` header, that describes the problem.
+    Flag,
+}
+
+/// Checks of the Received headers of inbound emails, that detect relay loops elsewhere.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct LoopCheck {
+    /// The maximum number of Received headers.
+    pub(crate) max_hops: usize,
+    /// Whether an email, that was already received by a server with our hostname, is considered looping.
+    pub(crate) own_hostname: bool,
+    pub(crate) action: LoopAction,
+}
+
+impl LoopCheck {
+    /// Returns a description of the problem, if the email seems to be looping.
+    fn problem(&self, email: &Email<'_>, hostname: &str) -> Option<String> {
+        let hops = email.received_hops();
+        if hops > self.max_hops {
+            Some(format!(
+                "The email passed {} servers (at most {} allowed).",
+                hops, self.max_hops
+            ))
+        } else if self.own_hostname && email.received_by(hostname) {
+            Some(format!("The email was already received by {}.", hostname))
+        } else {
+            None
+        }
+    }
+
+    /// Applies the check to the raw email `data` received by the server named `hostname`.
+    ///
+    /// Returns an error, if the email is rejected, or adds a header, if it is flagged. Emails, that can't be parsed, are
+    /// left to the following checks.
+    pub(crate) fn apply(&self, data: &mut Vec<u8>, hostname: &str) -> Result<(), Error> {
+        let problem = match Email::parse(data) {
+            Ok(email) => match self.problem(&email, hostname) {
+                Some(problem) => problem,
+                None => return Ok(()),
+            },
+            Err(_) => return Ok(()),
+        };
+        match self.action {
+            LoopAction::Reject => Err(Error::Policy(format!("Mail loop detected: {}", problem))),
+            LoopAction::Flag => {
+                warn!("Flagging received email as looping: {}", problem);
+                let mut flagged = format!("X-Loop-Suspected: {}\r\n", problem).into_bytes();
+                flagged.append(data);
+                *data = flagged;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOOPING_EMAIL: &[u8] = b"Received: by mx.example.com; Tue, 1 Mar 2022 10:00:00 +0000\r\n\
+Received: by relay.example.org; Tue, 1 Mar 2022 09:59:00 +0000\r\n\
+Message-ID: <loop@example.org>\r\n\
+\r\n\
+Hello again.\r\n";
+
+    #[test]
+    fn test_loop_check() {
+        let mut check = LoopCheck {
+            max_hops: 5,
+            own_hostname: false,
+            action: LoopAction::Reject,
+        };
+        let mut data = LOOPING_EMAIL.to_vec();
+        assert!(check.apply(&mut data, "mx.example.com").is_ok());
+
+        check.own_hostname = true;
+        assert!(matches!(
+            check.apply(&mut data, "MX.example.com"),
+            Err(Error::Policy(_))
+        ));
+
+        check.own_hostname = false;
+        check.max_hops = 1;
+        check.action = LoopAction::Flag;
+        check.apply(&mut data, "mx.example.com").unwrap();
+        assert!(data.starts_with(
+            b"X-Loop-Suspected: The email passed 2 servers (at most 1 allowed).\r\nReceived: "
+        ));
+        assert!(Email::parse(&data).is_ok());
+    }
+}
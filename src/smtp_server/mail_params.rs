@@ -0,0 +1,268 @@
+use mailin::Response;
+
+use std::time::Duration;
+
+use crate::email::{parse_rfc3339, rfc3339_date};
+
+/// The ESMTP extensions for delayed delivery, that a listener offers.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct DelayedDelivery {
+    /// The longest time, that clients may hold an email for with FUTURERELEASE (RFC 4865). If None, the extension is not
+    /// offered.
+    pub(crate) max_hold: Option<Duration>,
+    /// Whether clients may set a deadline for the delivery with DELIVERBY (RFC 2852).
+    pub(crate) deliver_by: bool,
+}
+
+impl DelayedDelivery {
+    /// Returns the keywords of the offered extensions, that are advertised in the reply to EHLO at `now`.
+    pub(crate) fn keywords(&self, now: u64) -> Vec<String> {
+        let mut keywords = vec![];
+        if let Some(max_hold) = self.max_hold {
+            keywords.push(format!(
+                "FUTURERELEASE {} {}",
+                max_hold.as_secs(),
+                rfc3339_date(now + max_hold.as_secs())
+            ));
+        }
+        if self.deliver_by {
+            keywords.push("DELIVERBY".to_string());
+        }
+        keywords
+    }
+}
+
+/// What happens to an email, that can't be delivered before its deadline.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ByMode {
+    /// The delivery is given up.
+    Return,
+    /// The delivery goes on, but the missed deadline is logged.
+    Notify,
+}
+
+/// The deadline of a delivery, that was requested with DELIVERBY.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct DeliverBy {
+    /// The deadline in seconds since the unix epoch.
+    pub(crate) deadline: u64,
+    pub(crate) mode: ByMode,
+}
+
+/// The parameters of MAIL FROM, that the SMTP session does not know.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct MailParams {
+    /// The size of the email declared with SIZE (RFC 1870).
+    pub(crate) size: Option<u64>,
+    /// The time in seconds since the unix epoch, before which the email must not be delivered.
+    pub(crate) hold_until: Option<u64>,
+    pub(crate) deliver_by: Option<DeliverBy>,
+    /// Whether the email may only be forwarded over TLS (REQUIRETLS, RFC 8689).
+    pub(crate) require_tls: bool,
+}
+
+impl MailParams {
+    /// Removes the parameters, that the session does not know, from a MAIL command received at `now` and returns the
+    /// remaining line and the parsed parameters. `tls` tells, whether the session is encrypted.
+    ///
+    /// Returns None, if `line` is no MAIL command, or the reply to send instead of processing the line, if a parameter
+    /// is invalid or belongs to an extension, that is not offered.
+    pub(crate) fn take(
+        line: &str,
+        offered: &DelayedDelivery,
+        tls: bool,
+        now: u64,
+    ) -> Option<Result<(String, MailParams), Response>> {
+        if !line
+            .get(..5)
+            .is_some_and(|command| command.eq_ignore_ascii_case("MAIL "))
+        {
+            return None;
+        }
+        let (path, params) = line.split_at(line.rfind('>')? + 1);
+        let mut parsed = MailParams::default();
+        let mut remaining = path.to_string();
+        for param in params.split_whitespace() {
+            let (keyword, value) = param.split_once('=').unwrap_or((param, ""));
+            let res = match keyword.to_ascii_uppercase().as_str() {
+                "SIZE" => value
+                    .parse()
+                    .map(|size| parsed.size = Some(size))
+                    .map_err(|_| "Syntax error in SIZE parameter"),
+                "HOLDFOR" | "HOLDUNTIL" => parsed.parse_hold(keyword, value, offered, now),
+                "BY" => parsed.parse_by(value, offered, now),
+                // REQUIRETLS is only offered in encrypted sessions:
+                "REQUIRETLS" if !tls => Err(TLS_REQUIRED),
+                "REQUIRETLS" if value.is_empty() => {
+                    parsed.require_tls = true;
+                    Ok(())
+                }
+                "REQUIRETLS" => Err("REQUIRETLS has no value"),
+                _ => {
+                    remaining.push(' ');
+                    remaining.push_str(param);
+                    Ok(())
+                }
+            };
+            if let Err(reply) = res {
+                return Some(Err(match reply {
+                    NOT_OFFERED => Response::custom(555, reply.to_string()),
+                    TLS_REQUIRED => Response::custom(530, reply.to_string()),
+                    _ => Response::custom(501, reply.to_string()),
+                }));
+            }
+        }
+        remaining.push_str(&line[line.trim_end().len()..]);
+        Some(Ok((remaining, parsed)))
+    }
+
+    fn parse_hold(
+        &mut self,
+        keyword: &str,
+        value: &str,
+        offered: &DelayedDelivery,
+        now: u64,
+    ) -> Result<(), &'static str> {
+        let max_hold = offered.max_hold.ok_or(NOT_OFFERED)?;
+        if self.hold_until.is_some() {
+            return Err("Only one of HOLDFOR and HOLDUNTIL is allowed");
+        }
+        let hold_until = if keyword.eq_ignore_ascii_case("HOLDFOR") {
+            value
+                .parse::<u64>()
+                .ok()
+                .and_then(|secs| now.checked_add(secs))
+        } else {
+            parse_rfc3339(value)
+        }
+        .ok_or("Syntax error in FUTURERELEASE parameter")?;
+        if hold_until > now + max_hold.as_secs() {
+            return Err("Requested release time is too far in the future");
+        }
+        self.hold_until = Some(hold_until);
+        Ok(())
+    }
+
+    fn parse_by(
+        &mut self,
+        value: &str,
+        offered: &DelayedDelivery,
+        now: u64,
+    ) -> Result<(), &'static str> {
+        if !offered.deliver_by {
+            return Err(NOT_OFFERED);
+        }
+        let invalid = "Syntax error in BY parameter";
+        let (time, mode) = value.split_once(';').ok_or(invalid)?;
+        let time: i64 = time.parse().map_err(|_| invalid)?;
+        // The optional trace modifier "T" is accepted, but there is nothing to trace:
+        let mode = match mode.to_ascii_uppercase().trim_end_matches('T') {
+            "R" if time > 0 => ByMode::Return,
+            "N" => ByMode::Notify,
+            _ => return Err(invalid),
+        };
+        self.deliver_by = Some(DeliverBy {
+            deadline: now.saturating_add_signed(time),
+            mode,
+        });
+        Ok(())
+    }
+}
+
+/// The reply text for parameters of extensions, that are not offered.
+const NOT_OFFERED: &str = "MAIL FROM parameters not recognized or not implemented";
+/// The reply text for REQUIRETLS in an unencrypted session.
+const TLS_REQUIRED: &str = "REQUIRETLS needs an encrypted session";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_take() {
+        let now = 1646128800;
+        let offered = DelayedDelivery {
+            max_hold: Some(Duration::from_secs(86400)),
+            deliver_by: true,
+        };
+        let take = |line: &str, offered: &DelayedDelivery| {
+            MailParams::take(line, offered, true, now).map(|res| res.map_err(|reply| reply.code))
+        };
+
+        assert_eq!(take("RCPT TO:<a@example.com>\r\n", &offered), None);
+        assert_eq!(
+            take(
+                "MAIL FROM:<a@example.com> BODY=8BITMIME SIZE=100 HOLDFOR=60 BY=120;NT\r\n",
+                &offered
+            ),
+            Some(Ok((
+                "MAIL FROM:<a@example.com> BODY=8BITMIME\r\n".to_string(),
+                MailParams {
+                    size: Some(100),
+                    hold_until: Some(now + 60),
+                    deliver_by: Some(DeliverBy {
+                        deadline: now + 120,
+                        mode: ByMode::Notify,
+                    }),
+                    require_tls: false,
+                }
+            )))
+        );
+        assert_eq!(
+            take(
+                "MAIL FROM:<> HOLDUNTIL=2022-03-01T12:00:00+01:00\r\n",
+                &offered
+            ),
+            Some(Ok((
+                "MAIL FROM:<>\r\n".to_string(),
+                MailParams {
+                    hold_until: Some(now + 3600),
+                    ..MailParams::default()
+                }
+            )))
+        );
+        assert_eq!(
+            take("MAIL FROM:<> HOLDFOR=86401\r\n", &offered),
+            Some(Err(501))
+        );
+        assert_eq!(
+            take("MAIL FROM:<> HOLDFOR=1 HOLDFOR=2\r\n", &offered),
+            Some(Err(501))
+        );
+        assert_eq!(take("MAIL FROM:<> BY=0;R\r\n", &offered), Some(Err(501)));
+        assert_eq!(
+            take("MAIL FROM:<> HOLDFOR=60\r\n", &DelayedDelivery::default()),
+            Some(Err(555))
+        );
+        assert_eq!(
+            take("MAIL FROM:<> BY=60;R\r\n", &DelayedDelivery::default()),
+            Some(Err(555))
+        );
+    }
+
+    #[test]
+    fn test_keywords() {
+        let offered = DelayedDelivery {
+            max_hold: Some(Duration::from_secs(86400)),
+            deliver_by: true,
+        };
+        assert_eq!(
+            offered.keywords(1646128800),
+            vec!["FUTURERELEASE 86400 2022-03-02T10:00:00Z", "DELIVERBY"]
+        );
+        assert!(DelayedDelivery::default().keywords(0).is_empty());
+    }
+
+    #[test]
+    fn test_require_tls() {
+        let offered = DelayedDelivery::default();
+        let (_, params) = MailParams::take("MAIL FROM:<> requiretls\r\n", &offered, true, 0)
+            .unwrap()
+            .unwrap();
+        assert!(params.require_tls);
+        let reply = MailParams::take("MAIL FROM:<> REQUIRETLS\r\n", &offered, false, 0)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(reply.code, 530);
+    }
+}
@@ -1,48 +1,285 @@
+use async_trait::async_trait;
+use ipnet::IpNet;
 use lettre::EmailAddress;
-use log::{debug, error, warn};
-use mailin::{response, Handler, Response, SessionBuilder};
+use log::{debug, error, info, warn};
+use mailin::{response, Handler, Response, Session, SessionBuilder};
 use rustls::ServerConfig;
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufStream},
     net::{TcpListener, TcpStream},
+    sync::Notify,
 };
 use tokio_rustls::TlsAcceptor;
 
+use std::io;
 use std::net::{IpAddr, SocketAddr};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use crate::{email::SmtpEmail, Error};
+use crate::{
+    budget::{MemoryBudget, Reservation},
+    email::SmtpEmail,
+    error::{Error, SmtpError, SmtpErrorCode},
+    metrics::{Metrics, SmtpCommand},
+    watchdog::DiskWatchdog,
+};
 
+mod greeting;
+mod identity;
+mod loop_check;
+mod mail_params;
+mod socket_options;
 #[cfg(test)]
 mod tests;
 
+pub(crate) use greeting::Greeting;
+pub(crate) use identity::{ClientIdentity, TlsMode, TlsParameters};
+pub(crate) use loop_check::{LoopAction, LoopCheck};
+pub(crate) use mail_params::{ByMode, DelayedDelivery, DeliverBy, MailParams};
+pub(crate) use socket_options::SocketOptions;
+
+const BIND_RETRY_MIN_BACKOFF: Duration = Duration::from_millis(250);
+const BIND_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(5);
+
 pub(crate) struct SmtpServer {
-    tcp_listener: TcpListener,
+    /// The listening sockets. There are several of them, if they are bound with SO_REUSEPORT.
+    tcp_listeners: Vec<TcpListener>,
+    socket_options: SocketOptions,
     session_builder: SessionBuilder,
+    hostname: String,
     tls_config: Option<TlsAcceptor>,
     implicit_tls: bool,
+    hidden_service: bool,
+    allow_from: Option<Vec<IpNet>>,
+    greeting: Greeting,
+    loop_check: Option<LoopCheck>,
+    disk_watchdog: Option<Arc<DiskWatchdog>>,
+    memory_budget: Option<Arc<MemoryBudget>>,
+    max_message_size: Option<usize>,
+    delayed_delivery: DelayedDelivery,
+    acceptor: Option<Arc<dyn MailAcceptor + Send + Sync>>,
+    metrics: Option<Arc<Metrics>>,
+    paused: AtomicBool,
+    draining: AtomicBool,
+    closed: AtomicBool,
+    closing: Notify,
+    /// The number of sessions, that are currently running.
+    sessions: AtomicUsize,
+}
+
+/// Counts a session as running, until it is dropped.
+struct ActiveSession<'a>(&'a AtomicUsize);
+
+impl<'a> ActiveSession<'a> {
+    fn start(sessions: &'a AtomicUsize) -> Self {
+        sessions.fetch_add(1, Ordering::Relaxed);
+        ActiveSession(sessions)
+    }
+}
+
+impl Drop for ActiveSession<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
 impl<'a> SmtpServer {
+    /// Creates a new server, whose listening socket and accepted connections use the given options.
+    /// Implicit TLS is used on port 465, if a TLS config is given.
     pub(crate) async fn new(
         addr: &SocketAddr,
         tls_config: Option<Arc<ServerConfig>>,
+        socket_options: SocketOptions,
     ) -> Result<Self, Error> {
-        let mut smtp_session_builder = SessionBuilder::new("TCP mail saver");
-        if tls_config.is_some() && addr.port() != 465 {
-            smtp_session_builder.enable_start_tls();
-        }
-        let implicit_tls = tls_config.is_some() && addr.port() == 465;
+        Self::with_implicit_tls(addr, tls_config, addr.port() == 465, socket_options).await
+    }
+
+    /// Creates a new server like `new()`, but lets the caller decide whether implicit TLS is used instead of deriving
+    /// it from the port.
+    /// If `implicit_tls` is false and a TLS config is given, STARTTLS is offered instead.
+    pub(crate) async fn with_implicit_tls(
+        addr: &SocketAddr,
+        tls_config: Option<Arc<ServerConfig>>,
+        implicit_tls: bool,
+        socket_options: SocketOptions,
+    ) -> Result<Self, Error> {
+        let hostname = "localhost".to_string();
+        let implicit_tls = tls_config.is_some() && implicit_tls;
         Ok(SmtpServer {
-            tcp_listener: TcpListener::bind(addr).await?,
-            session_builder: smtp_session_builder,
+            tcp_listeners: socket_options.bind(addr)?,
+            socket_options,
+            session_builder: session_builder(&hostname, tls_config.is_some() && !implicit_tls),
+            hostname,
             tls_config: tls_config.map(TlsAcceptor::from),
             implicit_tls,
+            hidden_service: false,
+            allow_from: None,
+            greeting: Greeting::default(),
+            loop_check: None,
+            disk_watchdog: None,
+            memory_budget: None,
+            max_message_size: None,
+            delayed_delivery: DelayedDelivery::default(),
+            acceptor: None,
+            metrics: None,
+            paused: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            closing: Notify::new(),
+            sessions: AtomicUsize::new(0),
         })
     }
 
-    pub(crate) async fn accept_conn(&self) -> Result<(TcpStream, SocketAddr), Error> {
-        Ok(self.tcp_listener.accept().await?)
+    /// Sets the name, with which the server introduces itself in the greeting and other replies.
+    pub(crate) fn set_hostname(&mut self, hostname: impl Into<String>) {
+        self.hostname = hostname.into();
+        self.session_builder = session_builder(
+            &self.hostname,
+            self.tls_config.is_some() && !self.implicit_tls,
+        );
+    }
+
+    /// Marks the server as listening for connections from a (Tor) hidden service. The peer address of these connections
+    /// is the one of the local Tor daemon, so it is not recorded and no checks based on it are applied.
+    pub(crate) fn set_hidden_service(&mut self, hidden_service: bool) {
+        self.hidden_service = hidden_service;
+    }
+
+    /// Only accepts connections from peers in the given networks. Connections from other peers are closed right after
+    /// they are accepted, without a greeting.
+    pub(crate) fn set_allow_from(&mut self, networks: Vec<IpNet>) {
+        self.allow_from = Some(networks);
+    }
+
+    /// Sets the banner, the delay of the greeting and the ESMTP keywords advertised by the server.
+    pub(crate) fn set_greeting(&mut self, greeting: Greeting) {
+        self.greeting = greeting;
+    }
+
+    /// Lets the server check the Received headers of received emails for signs of a relay loop.
+    pub(crate) fn set_loop_check(&mut self, loop_check: LoopCheck) {
+        self.loop_check = Some(loop_check);
+    }
+
+    /// Lets the server reject new emails with 452, while the given watchdog reports insufficient storage.
+    pub(crate) fn set_disk_watchdog(&mut self, watchdog: Arc<DiskWatchdog>) {
+        self.disk_watchdog = Some(watchdog);
+    }
+
+    /// Lets all sessions of this server share the given budget for buffering messages.
+    /// Emails, that would exceed it, are rejected with 452.
+    pub(crate) fn set_memory_budget(&mut self, budget: Arc<MemoryBudget>) {
+        self.memory_budget = Some(budget);
+    }
+
+    /// Limits the size of received emails. Larger emails are rejected with 552, after MAIL FROM, if the client declares
+    /// their size with the SIZE parameter (RFC 1870), or at the end of DATA otherwise.
+    pub(crate) fn set_max_message_size(&mut self, limit: usize) {
+        self.max_message_size = Some(limit);
+    }
+
+    /// Offers clients to delay the delivery of their emails or to set a deadline for it.
+    pub(crate) fn set_delayed_delivery(&mut self, delayed_delivery: DelayedDelivery) {
+        self.delayed_delivery = delayed_delivery;
+    }
+
+    /// Returns the size of the largest email, that could be accepted, if it is limited.
+    fn size_limit(&self) -> Option<usize> {
+        let budget_limit = self.memory_budget.as_ref().map(|budget| budget.limit());
+        match (self.max_message_size, budget_limit) {
+            (Some(max), Some(budget)) => Some(max.min(budget)),
+            (max, budget) => max.or(budget),
+        }
+    }
+
+    /// Lets the given acceptor decide about every received email, before the reply to the end of DATA is sent.
+    pub(crate) fn set_acceptor(&mut self, acceptor: Arc<dyn MailAcceptor + Send + Sync>) {
+        self.acceptor = Some(acceptor);
+    }
+
+    /// Lets the server record the processing time of SMTP commands in the given metrics.
+    pub(crate) fn set_metrics(&mut self, metrics: Arc<Metrics>) {
+        self.metrics = Some(metrics);
+    }
+
+    /// Pauses or resumes the server. While it is paused, new sessions are answered with 421 and closed.
+    /// Sessions, that already started, are not affected.
+    pub(crate) fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Prepares the shutdown of the server: New sessions are answered with 421, so clients retry later, while running
+    /// sessions may still finish.
+    pub(crate) fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    /// Stops accepting connections. Wakes up everybody waiting in `closed()`.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.closing.notify_waiters();
+    }
+
+    /// Waits until `close()` is called.
+    pub(crate) async fn closed(&self) {
+        let closing = self.closing.notified();
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+        closing.await;
+    }
+
+    /// Returns the number of sessions, that are currently running, e.g. to wait for them before shutting down.
+    pub(crate) fn active_sessions(&self) -> usize {
+        self.sessions.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn local_addr(&self) -> Result<SocketAddr, Error> {
+        Ok(self.tcp_listeners[0].local_addr()?)
+    }
+
+    /// Returns the number of listening sockets, that connections can be accepted from concurrently.
+    pub(crate) fn acceptors(&self) -> usize {
+        self.tcp_listeners.len()
+    }
+
+    /// Accepts the next connection from a peer, that is allowed to connect, on the listening socket with the given
+    /// index (less than `acceptors()`).
+    pub(crate) async fn accept_conn(
+        &self,
+        acceptor: usize,
+    ) -> Result<(TcpStream, SocketAddr), Error> {
+        loop {
+            let (stream, addr) = self.tcp_listeners[acceptor].accept().await?;
+            if let Some(ref metrics) = self.metrics {
+                metrics.observe_accepted_connection();
+            }
+            if self.is_allowed(addr.ip()) {
+                self.socket_options.apply(&stream)?;
+                return Ok((stream, addr));
+            }
+            info!(
+                peer_ip:% = addr.ip();
+                "Dropped connection from {}, that is not allowed.",
+                addr.ip()
+            );
+        }
+    }
+
+    fn is_allowed(&self, ip: IpAddr) -> bool {
+        // IPv4 peers of dual-stack listeners appear as IPv4-mapped IPv6 addresses:
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            IpAddr::V4(_) => ip,
+        };
+        self.allow_from
+            .as_ref()
+            .is_none_or(|networks| networks.iter().any(|net| net.contains(&ip)))
     }
 
     pub(crate) async fn recv_mail(
@@ -51,63 +288,164 @@ impl<'a> SmtpServer {
         peer_addr: SocketAddr,
         buf: &'a mut Vec<u8>,
     ) -> Result<SmtpEmail<'a>, Error> {
-        if self.implicit_tls {
-            self.handle_mail_comm(
-                peer_addr,
-                BufStream::new(
-                    self.tls_config
-                        .as_ref()
-                        .expect("implicit_tls was true, but there was no TLS config.")
-                        .accept(tcp_stream)
-                        .await?,
-                ),
-                buf,
-            )
-            .await
+        let _session = ActiveSession::start(&self.sessions);
+        let mut client = ClientIdentity {
+            peer: Some(peer_addr).filter(|_| !self.hidden_service),
+            hidden_service: self.hidden_service,
+            listener: self.tcp_listeners[0].local_addr().ok(),
+            listener_tls: match (self.tls_config.is_some(), self.implicit_tls) {
+                (false, _) => TlsMode::None,
+                (true, false) => TlsMode::StartTls,
+                (true, true) => TlsMode::Implicit,
+            },
+            ..ClientIdentity::default()
+        };
+        let res = if self.implicit_tls {
+            let tls_stream = self
+                .tls_config
+                .as_ref()
+                .expect("implicit_tls was true, but there was no TLS config.")
+                .accept(tcp_stream)
+                .await?;
+            client.tls = Some(TlsParameters::of(tls_stream.get_ref().1));
+            self.handle_mail_comm(client, BufStream::new(tls_stream), buf)
+                .await
         } else {
-            self.handle_mail_comm(peer_addr, BufStream::new(tcp_stream), buf)
+            self.handle_mail_comm(client, BufStream::new(tcp_stream), buf)
                 .await
-        }
+        };
+
+        let envelope = res.map_err(|e| {
+            if self.hidden_service {
+                e
+            } else {
+                e.with_peer(peer_addr.ip())
+            }
+        })?;
+        let mut email = SmtpEmail::new(envelope.from, envelope.to, buf.as_slice())?;
+        email.client = envelope.client;
+        email.params = envelope.params;
+        Ok(email)
     }
 
+    /// Runs the SMTP session and writes the accepted email to `buf`.
+    /// Returns the envelope of the accepted email.
     async fn handle_mail_comm(
         &self,
-        peer_addr: SocketAddr,
+        mut client: ClientIdentity,
         mut stream: impl AsyncBufReadExt + AsyncWriteExt + Unpin,
-        buf: &'a mut Vec<u8>,
-    ) -> Result<SmtpEmail<'a>, Error> {
-        let mut res = Err(Error::Smtp("No DATA_END reveived.".to_string()));
-        let mail_handler = MailHandler::new(buf, &mut res);
-        let mut session = self.session_builder.build(peer_addr.ip(), mail_handler);
+        buf: &mut Vec<u8>,
+    ) -> Result<Envelope, Error> {
+        let draining = self.draining.load(Ordering::Relaxed);
+        if draining || self.is_paused() {
+            // Unlike a refused TCP connection, 421 makes well-behaved clients retry later without treating us as
+            // broken:
+            let response = Response::custom(
+                421,
+                format!(
+                    "{} Service not available, closing transmission channel",
+                    self.hostname
+                ),
+            );
+            write_resp_async(&response, &mut stream).await?;
+            stream.flush().await?;
+            stream.shutdown().await?;
+            return Err(Error::smtp(
+                SmtpErrorCode::Refused,
+                if draining {
+                    "Refused session, because the server is shutting down."
+                } else {
+                    "Refused session, because the server is paused."
+                },
+            ));
+        }
 
-        let greeting = session.greeting();
+        let mut res = Err(Error::smtp(
+            SmtpErrorCode::NoMessage,
+            "No DATA_END reveived.",
+        ));
+        let received = Arc::new(Mutex::new(None));
+        let mut mail_handler = MailHandler::new(received.clone());
+        mail_handler.disk_watchdog = self.disk_watchdog.clone();
+        mail_handler.reservation = self.memory_budget.clone().map(Reservation::new);
+        mail_handler.max_size = self.max_message_size;
+        let peer_ip = client
+            .peer
+            .map_or(IpAddr::from([0, 0, 0, 0]), |peer| peer.ip());
+        let mut session = self.session_builder.build(peer_ip, mail_handler);
+
+        if !self.greeting.delay.is_zero() {
+            if let Ok(early) = tokio::time::timeout(self.greeting.delay, stream.fill_buf()).await {
+                if !early?.is_empty() {
+                    let response = Response::custom(
+                        554,
+                        format!("{} Commands sent before the greeting", self.hostname),
+                    );
+                    write_resp_async(&response, &mut stream).await?;
+                    stream.flush().await?;
+                    stream.shutdown().await?;
+                    return Err(Error::Policy(
+                        "Client sent commands before the greeting.".to_string(),
+                    ));
+                }
+            }
+        }
+        let greeting = match self.greeting.banner {
+            Some(ref banner) => Response::custom(220, format!("{} {}", self.hostname, banner)),
+            None => session.greeting(),
+        };
         write_resp_async(&greeting, &mut stream).await?;
         stream.flush().await?;
         let mut last_response = greeting;
+        let mut in_data = false;
+        let mut params = MailParams::default();
         while last_response.action != response::Action::Close
             && last_response.action != response::Action::UpgradeTls
         {
             let mut line = String::new();
             stream.read_line(&mut line).await?;
-            last_response = session.process(line.as_bytes());
-            write_resp_async(&last_response, &mut stream).await?;
+            let started = Instant::now();
+            let command = SmtpCommand::of_line(&line, in_data);
+            last_response = self.process_line(&mut session, &line, &client, &mut params);
+            if let Some(response) = self
+                .finish_data(&received, &client, &params, buf, &mut res)
+                .await
+            {
+                last_response = response;
+            }
+            stream
+                .write_all(&self.reply_bytes(&last_response, command, &client)?)
+                .await?;
             stream.flush().await?;
+            in_data = self.observe_command(command, started, in_data, &last_response);
         }
         // If the client requests TLS we upgrade the connection and go on as we would have with a TCP stream:
         if last_response.action == response::Action::UpgradeTls {
-            let mut tls_stream = BufStream::new(
-                self.tls_config
-                    .as_ref()
-                    .expect("STARTTLS was active, but there was no TLS config.")
-                    .accept(stream)
-                    .await?,
-            );
+            let tls_stream = self
+                .tls_config
+                .as_ref()
+                .expect("STARTTLS was active, but there was no TLS config.")
+                .accept(stream)
+                .await?;
+            client.tls = Some(TlsParameters::of(tls_stream.get_ref().1));
+            let mut tls_stream = BufStream::new(tls_stream);
             while last_response.action != response::Action::Close {
                 let mut line = String::new();
                 tls_stream.read_line(&mut line).await?;
-                last_response = session.process(line.as_bytes());
-                write_resp_async(&last_response, &mut tls_stream).await?;
+                let started = Instant::now();
+                let command = SmtpCommand::of_line(&line, in_data);
+                last_response = self.process_line(&mut session, &line, &client, &mut params);
+                if let Some(response) = self
+                    .finish_data(&received, &client, &params, buf, &mut res)
+                    .await
+                {
+                    last_response = response;
+                }
+                tls_stream
+                    .write_all(&self.reply_bytes(&last_response, command, &client)?)
+                    .await?;
                 tls_stream.flush().await?;
+                in_data = self.observe_command(command, started, in_data, &last_response);
             }
             tls_stream.shutdown().await?;
         } else {
@@ -116,35 +454,255 @@ impl<'a> SmtpServer {
 
         res
     }
+
+    /// Passes a line to the session and returns its reply.
+    ///
+    /// The parameters of MAIL FROM, that the session does not know, are handled here and stored in `params`, if the
+    /// command succeeds: Emails, that are declared to be larger than the limit, are rejected before the client starts to
+    /// send them.
+    fn process_line(
+        &self,
+        session: &mut Session<MailHandler>,
+        line: &str,
+        client: &ClientIdentity,
+        params: &mut MailParams,
+    ) -> Response {
+        let tls = client.tls.is_some();
+        let (line, new_params) =
+            match MailParams::take(line, &self.delayed_delivery, tls, unix_now()) {
+                Some(Ok(taken)) => taken,
+                Some(Err(response)) => return response,
+                None => return session.process(line.as_bytes()),
+            };
+        match (new_params.size, self.size_limit()) {
+            (Some(size), Some(limit)) if size > limit as u64 => {
+                warn!(
+                    "Rejecting email with declared size of {} bytes, which exceeds the limit of {} bytes.",
+                    size, limit
+                );
+                Response::custom(
+                    552,
+                    "Message size exceeds fixed maximum message size".to_string(),
+                )
+            }
+            _ => {
+                let response = session.process(line.as_bytes());
+                if response.code == 250 {
+                    *params = new_params;
+                }
+                response
+            }
+        }
+    }
+
+    /// Returns the reply to send, which is the reply to EHLO with the keywords of the extensions handled here and without
+    /// the keywords, that should not be advertised.
+    fn reply_bytes(
+        &self,
+        response: &Response,
+        command: Option<SmtpCommand>,
+        client: &ClientIdentity,
+    ) -> Result<Vec<u8>, Error> {
+        let mut buf = Vec::new();
+        response.write_to(&mut buf)?;
+        if command == Some(SmtpCommand::Ehlo) && response.code == 250 {
+            if let Some(limit) = self.size_limit() {
+                buf = add_ehlo_keyword(&buf, &format!("SIZE {}", limit));
+            }
+            for keyword in self.delayed_delivery.keywords(unix_now()) {
+                buf = add_ehlo_keyword(&buf, &keyword);
+            }
+            // REQUIRETLS must only be offered in encrypted sessions (RFC 8689, section 4.1):
+            if client.tls.is_some() {
+                buf = add_ehlo_keyword(&buf, "REQUIRETLS");
+            }
+            buf = self.greeting.filter_ehlo(&buf);
+        }
+        Ok(buf)
+    }
+
+    /// Records the latency of a processed line, if it was a measured command, and returns whether the next line is part
+    /// of a message.
+    fn observe_command(
+        &self,
+        command: Option<SmtpCommand>,
+        started: Instant,
+        in_data: bool,
+        response: &Response,
+    ) -> bool {
+        if let (Some(command), Some(metrics)) = (command, self.metrics.as_ref()) {
+            metrics.observe_smtp_command(command, started.elapsed());
+        }
+        match command {
+            Some(SmtpCommand::DataEnd) => false,
+            _ => in_data || response.code == 354,
+        }
+    }
+
+    /// Decides about an email, the handler completed while processing the last line, and returns the reply to the end
+    /// of DATA.
+    /// Returns None, if no email was completed.
+    async fn finish_data(
+        &self,
+        received: &Mutex<Option<ReceivedMail>>,
+        client: &ClientIdentity,
+        params: &MailParams,
+        buf: &mut Vec<u8>,
+        res: &mut Result<Envelope, Error>,
+    ) -> Option<Response> {
+        let mut mail = received
+            .lock()
+            .expect("Received mail slot is poisoned.")
+            .take()?;
+
+        let mut client = client.clone();
+        client.helo = mail.helo;
+        // Flagged emails are passed to the acceptor with the added header:
+        let looping = match self.loop_check {
+            Some(ref check) => check.apply(&mut mail.data, &self.hostname),
+            None => Ok(()),
+        };
+        let accepted = match looping
+            .and_then(|()| SmtpEmail::new(mail.from.clone(), mail.to.clone(), &mail.data))
+        {
+            Ok(mut email) => {
+                client.software = email.content.client_software();
+                email.client = client.clone();
+                email.params = params.clone();
+                match self.acceptor {
+                    Some(ref acceptor) => acceptor.accept(&email).await,
+                    None => Ok(()),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        // Hidden services don't know their peers:
+        let peer_ip = client
+            .peer
+            .map(|peer| peer.ip().to_string())
+            .unwrap_or_default();
+        match accepted {
+            Ok(()) => {
+                info!(peer_ip = peer_ip.as_str(); "Accepted an email from {}.", client);
+                *buf = mail.data;
+                *res = Ok(Envelope {
+                    from: mail.from,
+                    to: mail.to,
+                    client,
+                    params: params.clone(),
+                });
+                Some(response::OK)
+            }
+            Err(e) => {
+                let response = reply_for(&e);
+                warn!(
+                    peer_ip = peer_ip.as_str();
+                    "Rejecting received email from {} with {} ({}): {}",
+                    client,
+                    response.code,
+                    e.code(),
+                    e
+                );
+                *res = Err(e);
+                Some(response)
+            }
+        }
+    }
+}
+
+/// Decides, whether a received email is accepted, before the client gets the reply to the end of DATA.
+///
+/// If `accept()` returns an error, the email is rejected with the reply returned by `reply_for()`.
+#[async_trait]
+pub(crate) trait MailAcceptor {
+    async fn accept(&self, email: &SmtpEmail<'_>) -> Result<(), Error>;
 }
 
-struct MailHandler<'a, 'b> {
+/// Returns the SMTP reply, that tells the client about the given error during the acceptance of an email.
+pub(crate) fn reply_for(e: &Error) -> Response {
+    match e {
+        Error::MailParsing(desc) => Response::custom(554, format!("Transaction failed: {}", desc)),
+        Error::Policy(desc) => {
+            Response::custom(550, format!("Requested action not taken: {}", desc))
+        }
+        Error::Smtp(SmtpError {
+            code: SmtpErrorCode::Reply(552),
+            ..
+        }) => Response::custom(552, "Message exceeds storage allocation".to_string()),
+        Error::Smtp(SmtpError {
+            code: SmtpErrorCode::Reply(451),
+            ..
+        }) => Response::custom(
+            451,
+            "Requested action aborted: local error in processing".to_string(),
+        ),
+        e if e.is_temporary() => Response::custom(
+            450,
+            "Requested action not taken, try again later".to_string(),
+        ),
+        _ => Response::custom(554, "Transaction failed".to_string()),
+    }
+}
+
+/// The envelope of an accepted email and the client, that sent it.
+struct Envelope {
     from: Option<EmailAddress>,
     to: Vec<EmailAddress>,
-    msg_buf: Option<&'a mut Vec<u8>>,
-    received_mail: &'b mut Result<SmtpEmail<'a>, Error>,
+    client: ClientIdentity,
+    params: MailParams,
 }
 
-impl<'a, 'b> MailHandler<'a, 'b> {
-    fn new(
-        buf: &'a mut Vec<u8>,
-        result_pointer: &'b mut Result<SmtpEmail<'a>, Error>,
-    ) -> MailHandler<'a, 'b> {
+/// An email completed by the `MailHandler`, that still has to be accepted.
+struct ReceivedMail {
+    from: Option<EmailAddress>,
+    to: Vec<EmailAddress>,
+    helo: Option<String>,
+    data: Vec<u8>,
+}
+
+struct MailHandler {
+    helo: Option<String>,
+    from: Option<EmailAddress>,
+    to: Vec<EmailAddress>,
+    msg_buf: Option<Vec<u8>>,
+    received: Arc<Mutex<Option<ReceivedMail>>>,
+    disk_watchdog: Option<Arc<DiskWatchdog>>,
+    reservation: Option<Reservation>,
+    /// The size limit of emails, that is independent of the memory budget.
+    max_size: Option<usize>,
+    over_budget: bool,
+    too_large: bool,
+}
+
+impl MailHandler {
+    fn new(received: Arc<Mutex<Option<ReceivedMail>>>) -> MailHandler {
         MailHandler {
+            helo: None,
             from: None,
             to: vec![],
-            msg_buf: Some(buf),
-            received_mail: result_pointer,
+            msg_buf: Some(Vec::new()),
+            received,
+            disk_watchdog: None,
+            reservation: None,
+            max_size: None,
+            over_budget: false,
+            too_large: false,
         }
     }
 }
 
-impl<'a, 'b> Handler for MailHandler<'a, 'b> {
-    fn helo(&mut self, _ip: IpAddr, _domain: &str) -> Response {
+impl Handler for MailHandler {
+    fn helo(&mut self, _ip: IpAddr, domain: &str) -> Response {
+        debug!("Client introduced itself as {}.", domain);
+        self.helo = Some(domain.to_string());
         response::OK
     }
 
     fn mail(&mut self, _ip: IpAddr, _domain: &str, from: &str) -> Response {
+        if self.disk_watchdog.as_ref().is_some_and(|w| w.is_low()) {
+            warn!("Rejecting incoming email because of insufficient storage.");
+            return response::Response::custom(452, "Insufficient system storage".to_string());
+        }
         match EmailAddress::new(String::from(from)) {
             Ok(m) => {
                 self.from = Some(m);
@@ -181,25 +739,62 @@ impl<'a, 'b> Handler for MailHandler<'a, 'b> {
             "SMTP server eceived DATA_START: domain: {}, from: {}, 8bit: {}",
             _domain, _from, _is8bit
         );
-        if self.msg_buf.is_none() {
-            warn!("Received DATA_START after the message buf was taken.");
-            return response::Response::custom(503, "Bad sequence of commands".to_string());
-        } else if !self
-            .msg_buf
+        let msg_buf = match self.msg_buf {
+            Some(ref mut msg_buf) => msg_buf,
+            None => {
+                warn!("Received DATA_START after the message buf was taken.");
+                return response::Response::custom(503, "Bad sequence of commands".to_string());
+            }
+        };
+        if let Some(budget) = self
+            .reservation
             .as_ref()
-            .expect("We checked this with the previous case.")
-            .is_empty()
+            .map(Reservation::budget)
+            .filter(|b| b.is_exhausted())
         {
+            warn!(
+                "Deferring incoming email, because the memory budget is exhausted ({} bytes in use).",
+                budget.used()
+            );
+            return response::Response::custom(452, "Insufficient system storage".to_string());
+        } else if !msg_buf.is_empty() {
             warn!("Received DATA_START while the message buf wasn't empty.");
-            self.msg_buf
-                .as_mut()
-                .expect("We checked this with the previous case.")
-                .clear();
+            msg_buf.clear();
         }
         response::OK
     }
 
     fn data(&mut self, buf: &[u8]) -> std::io::Result<()> {
+        if self.over_budget {
+            // The email will be rejected, so we don't need to buffer the rest.
+            return Ok(());
+        }
+        let received = self.msg_buf.as_ref().map_or(0, Vec::len);
+        if self
+            .max_size
+            .is_some_and(|max| received.saturating_add(buf.len()) > max)
+        {
+            warn!("Received an email exceeding the size limit.");
+            self.over_budget = true;
+            self.too_large = true;
+            if let Some(ref mut buf_ref) = self.msg_buf {
+                buf_ref.clear();
+                buf_ref.shrink_to_fit();
+            }
+            return Ok(());
+        }
+        if let Some(ref mut reservation) = self.reservation {
+            if !reservation.try_grow(buf.len()) {
+                warn!("Memory budget exceeded while receiving an email.");
+                self.over_budget = true;
+                self.too_large = reservation.exceeds_limit(buf.len());
+                if let Some(ref mut buf_ref) = self.msg_buf {
+                    buf_ref.clear();
+                    buf_ref.shrink_to_fit();
+                }
+                return Ok(());
+            }
+        }
         if let Some(ref mut buf_ref) = self.msg_buf {
             buf_ref.extend_from_slice(buf);
         } else {
@@ -209,31 +804,37 @@ impl<'a, 'b> Handler for MailHandler<'a, 'b> {
     }
 
     fn data_end(&mut self) -> Response {
-        let buf_ref: &'a mut Vec<u8> = self.msg_buf.take().unwrap();
-        let complete_mail = SmtpEmail::new(
-            self.from.take(),
-            self.to.drain(0..).collect(),
-            buf_ref.as_slice(),
-        );
-        debug!("Received an email over SMTP.");
-        match &self.received_mail {
-            Err(Error::Smtp(_)) => {
-                *self.received_mail = complete_mail;
-                response::OK
+        if self.over_budget {
+            self.over_budget = false;
+            self.from = None;
+            self.to.clear();
+            if std::mem::take(&mut self.too_large) {
+                return response::Response::custom(
+                    552,
+                    "Message exceeds storage allocation".to_string(),
+                );
             }
-            Ok(_) => {
+            return response::Response::custom(452, "Insufficient system storage".to_string());
+        }
+        let data = match self.msg_buf.take() {
+            Some(data) => data,
+            None => {
                 error!("Reveiced DATA_END twice.");
-                *self.received_mail = Err(Error::Smtp("Received multiple DATA_END.".to_string()));
-                response::Response::custom(503, "Received multiple DATA_END.".to_string())
+                return response::Response::custom(503, "Received multiple DATA_END.".to_string());
             }
-            Err(_) => {
-                error!("Reveiced DATA_END after previous error.");
-                response::Response::custom(
-                    554,
-                    "Received DATA_END after previous error.".to_string(),
-                )
-            }
-        }
+        };
+        debug!("Received an email over SMTP.");
+        // The session loop decides about the email and replaces this reply:
+        *self
+            .received
+            .lock()
+            .expect("Received mail slot is poisoned.") = Some(ReceivedMail {
+            from: self.from.take(),
+            to: self.to.drain(0..).collect(),
+            helo: self.helo.clone(),
+            data,
+        });
+        response::OK
     }
 
     fn auth_plain(
@@ -246,6 +847,75 @@ impl<'a, 'b> Handler for MailHandler<'a, 'b> {
     }
 }
 
+/// Creates a server like `SmtpServer::new()`, but retries binding for up to `retry_period`, while the address is not
+/// available (yet).
+///
+/// At boot, addresses may be unavailable for a short time, e.g. because the interface is not up yet or IPv6 duplicate
+/// address detection is still running. Retries are delayed with exponential backoff.
+pub(crate) async fn bind_with_retry(
+    addr: &SocketAddr,
+    tls_config: Option<Arc<ServerConfig>>,
+    socket_options: SocketOptions,
+    retry_period: Duration,
+) -> Result<SmtpServer, Error> {
+    let start = Instant::now();
+    let mut backoff = BIND_RETRY_MIN_BACKOFF;
+    loop {
+        match SmtpServer::new(addr, tls_config.clone(), socket_options.clone()).await {
+            Err(Error::SysIo(e))
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::AddrNotAvailable | io::ErrorKind::AddrInUse
+                ) && start.elapsed() + backoff <= retry_period =>
+            {
+                warn!(
+                    "Could not bind to {} ({}), retrying in {} ms.",
+                    addr,
+                    e,
+                    backoff.as_millis()
+                );
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(BIND_RETRY_MAX_BACKOFF);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Appends an ESMTP keyword to a reply to EHLO.
+fn add_ehlo_keyword(reply: &[u8], keyword: &str) -> Vec<u8> {
+    let text = String::from_utf8_lossy(reply);
+    let text = text.trim_end();
+    let last_start = text.rfind('\n').map_or(0, |i| i + 1);
+    if text.len() < last_start + 4 {
+        return reply.to_vec();
+    }
+    let code = &text[last_start..last_start + 3];
+    format!(
+        "{}{}-{}\r\n{} {}\r\n",
+        &text[..last_start],
+        code,
+        &text[last_start + 4..],
+        code,
+        keyword
+    )
+    .into_bytes()
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn session_builder(hostname: &str, starttls: bool) -> SessionBuilder {
+    let mut builder = SessionBuilder::new(hostname);
+    if starttls {
+        builder.enable_start_tls();
+    }
+    builder
+}
+
 async fn write_resp_async(
     resp: &mailin::response::Response,
     mut writer: impl AsyncWriteExt + Unpin,
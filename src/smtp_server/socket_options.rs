@@ -0,0 +1,119 @@
+use socket2::{SockRef, TcpKeepalive};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// The number of pending connections, that the listening socket queues, if no backlog is configured. This is the same
+/// as for `TcpListener::bind()`.
+const DEFAULT_BACKLOG: u32 = 1024;
+
+/// Options of the listening socket of a server and of the connections it accepts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct SocketOptions {
+    /// The idle time, after which keepalive probes are sent on accepted connections. If None, the system default is
+    /// used, which usually means no keepalive probes.
+    pub(crate) keepalive: Option<Duration>,
+    /// Whether Nagle's algorithm is disabled on accepted connections.
+    pub(crate) nodelay: bool,
+    /// The maximal number of connections, that are queued before they are accepted.
+    pub(crate) backlog: Option<u32>,
+    /// The size of the receive buffer of accepted connections in bytes. If None, the system default is used.
+    pub(crate) recv_buffer_size: Option<u32>,
+    /// The number of listening sockets, that are bound to the same address with SO_REUSEPORT, so connections are
+    /// accepted by one task per socket. The kernel distributes new connections among the sockets. If None, one socket
+    /// is bound without SO_REUSEPORT.
+    pub(crate) acceptors: Option<usize>,
+}
+
+impl SocketOptions {
+    /// Binds the listening sockets to `addr`. Accepted connections inherit their receive buffer size.
+    pub(crate) fn bind(&self, addr: &SocketAddr) -> io::Result<Vec<TcpListener>> {
+        let mut addr = *addr;
+        let mut listeners = vec![];
+        for _ in 0..self.acceptors.unwrap_or(1) {
+            let listener = self.bind_one(&addr)?;
+            // If the port is chosen by the system, all sockets must use the port chosen for the first one:
+            addr = listener.local_addr()?;
+            listeners.push(listener);
+        }
+        Ok(listeners)
+    }
+
+    fn bind_one(&self, addr: &SocketAddr) -> io::Result<TcpListener> {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        // Like TcpListener::bind(), so restarts don't fail while old connections are in TIME_WAIT:
+        socket.set_reuseaddr(true)?;
+        if self.acceptors.is_some() {
+            socket.set_reuseport(true)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        socket.bind(*addr)?;
+        socket.listen(self.backlog.unwrap_or(DEFAULT_BACKLOG))
+    }
+
+    /// Applies the options to an accepted connection.
+    pub(crate) fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            stream.set_nodelay(true)?;
+        }
+        if let Some(time) = self.keepalive {
+            SockRef::from(stream).set_tcp_keepalive(&TcpKeepalive::new().with_time(time))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_apply() {
+        let options = SocketOptions {
+            keepalive: Some(Duration::from_secs(120)),
+            nodelay: true,
+            backlog: Some(16),
+            recv_buffer_size: Some(64 * 1024),
+            acceptors: None,
+        };
+        let listener = options
+            .bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .unwrap()
+            .remove(0);
+        let client = TcpStream::connect(listener.local_addr().unwrap());
+        let (accepted, client) = tokio::join!(listener.accept(), client);
+        let (stream, _) = accepted.unwrap();
+        client.unwrap();
+
+        options.apply(&stream).unwrap();
+        assert!(stream.nodelay().unwrap());
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[tokio::test]
+    async fn test_reuse_port() {
+        let options = SocketOptions {
+            acceptors: Some(3),
+            ..SocketOptions::default()
+        };
+        let listeners = options
+            .bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .unwrap();
+        assert_eq!(listeners.len(), 3);
+        let addr = listeners[0].local_addr().unwrap();
+        assert!(listeners
+            .iter()
+            .all(|listener| listener.local_addr().unwrap() == addr));
+        // Without SO_REUSEPORT, the address is in use:
+        assert!(SocketOptions::default().bind(&addr).is_err());
+    }
+}
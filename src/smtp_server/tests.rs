@@ -3,12 +3,15 @@ use lettre::{
     SendableEmail, Transport,
 };
 use lettre_email::{self, EmailBuilder};
+use rustls::{sign::CertifiedKey, ClientConfig, RootCertStore, ServerName};
 use tokio::runtime::Runtime;
+use tokio_rustls::TlsConnector;
 
 use std::time::Duration;
 use std::{net::ToSocketAddrs, thread};
 
 use super::*;
+use crate::config::CertResolver;
 use crate::email::SmtpEmail;
 
 const SMPT_TEST_PORT: u16 = 4025;
@@ -46,7 +49,7 @@ fn send_mail_local(email: SendableEmail) -> thread::JoinHandle<()> {
         );
         // Send the email
         println!("Sending mail...");
-        let result = mailer.send(email.into());
+        let result = mailer.send(email);
 
         if result.is_ok() {
             println!("Email sent");
@@ -70,14 +73,14 @@ fn receive_mails_cmp(
             .unwrap();
         println!("Binding to address: {}", local_addr);
         let smtp_server = runtime
-            .block_on(SmtpServer::new(&local_addr, None))
+            .block_on(SmtpServer::new(&local_addr, None, SocketOptions::default()))
             .expect("Could not start SMTP server.");
         println!("Started SMTP server.");
         let mut buf = vec![];
         for i in 0..expected_mails.len() {
             buf.clear();
             let (stream, addr) = runtime
-                .block_on(smtp_server.accept_conn())
+                .block_on(smtp_server.accept_conn(0))
                 .expect("Could not accept TCP connection.");
             let new_mail = runtime
                 .block_on(smtp_server.recv_mail(stream, addr, &mut buf))
@@ -98,7 +101,9 @@ fn rm_from_expected(expected_mails: &mut Vec<lettre_email::Email>, received_mail
         // Transform SendableEmail to SmtpEmail:
         let mut buf = vec![];
         let tokio_mail = expected_mails[i].clone().into();
-        let smpt_email = SmtpEmail::from_tokio_mail(tokio_mail, &mut buf);
+        let mut smpt_email = SmtpEmail::from_tokio_mail(tokio_mail, &mut buf);
+        // The client identity depends on the connection, so we don't compare it:
+        smpt_email.client = received_mail.client.clone();
         if smpt_email == received_mail {
             expected_mails.remove(i);
             found = true;
@@ -108,3 +113,642 @@ fn rm_from_expected(expected_mails: &mut Vec<lettre_email::Email>, received_mail
     }
     assert!(found, "Received an unexpected email.");
 }
+
+const STARTTLS_TEST_PORT: u16 = 4026;
+const IMPLICIT_TLS_TEST_PORT: u16 = 4027;
+const SNI_TEST_PORT: u16 = 4028;
+
+const TLS_TEST_EMAIL: &str = "From: sender@example.com\r\n\
+To: receiver@example.org\r\n\
+Subject: Hello TLS\r\n\
+Message-ID: <tls-test@example.com>\r\n\
+\r\n\
+Hello world.\r\n";
+
+/// Generates a self signed certificate for `domain` and returns it together with the key rustls needs to sign with it.
+fn generate_cert(domain: &str) -> (rustls::Certificate, CertifiedKey) {
+    let generated = rcgen::generate_simple_self_signed(vec![domain.to_string()])
+        .expect("Could not generate certificate.");
+    let cert = rustls::Certificate(generated.serialize_der().unwrap());
+    let key = rustls::sign::any_supported_type(&rustls::PrivateKey(
+        generated.serialize_private_key_der(),
+    ))
+    .unwrap();
+    (cert.clone(), CertifiedKey::new(vec![cert], key))
+}
+
+/// Builds a server config, that selects one of the given certificates by SNI.
+fn server_tls_config(certs: Vec<(&str, CertifiedKey)>) -> Arc<ServerConfig> {
+    let mut resolver = CertResolver::new();
+    for (domain, key) in certs {
+        resolver.add_domain(domain.to_string(), key);
+    }
+    Arc::new(
+        ServerConfig::builder()
+            .with_safe_defaults()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver)),
+    )
+}
+
+/// Builds a TLS connector, that only trusts the given certificate.
+fn client_connector(trusted: &rustls::Certificate) -> TlsConnector {
+    let mut roots = RootCertStore::empty();
+    roots.add(trusted).unwrap();
+    TlsConnector::from(Arc::new(
+        ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+/// Reads a (possibly multiline) SMTP response and returns its status code.
+async fn read_response(stream: &mut (impl AsyncBufReadExt + Unpin)) -> u16 {
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        assert!(line.len() >= 4, "Received invalid SMTP response: {}", line);
+        if line.as_bytes()[3] == b' ' {
+            return line[..3].parse().unwrap();
+        }
+    }
+}
+
+async fn send_command(
+    stream: &mut (impl AsyncBufReadExt + AsyncWriteExt + Unpin),
+    cmd: &str,
+) -> u16 {
+    stream.write_all(cmd.as_bytes()).await.unwrap();
+    stream.write_all(b"\r\n").await.unwrap();
+    stream.flush().await.unwrap();
+    read_response(stream).await
+}
+
+/// Sends TLS_TEST_EMAIL over an already established (and greeted) connection.
+async fn send_test_mail(stream: &mut (impl AsyncBufReadExt + AsyncWriteExt + Unpin)) {
+    assert_eq!(send_command(stream, "EHLO client.example").await, 250);
+    assert_eq!(
+        send_command(stream, "MAIL FROM:<sender@example.com>").await,
+        250
+    );
+    assert_eq!(
+        send_command(stream, "RCPT TO:<receiver@example.org>").await,
+        250
+    );
+    assert_eq!(send_command(stream, "DATA").await, 354);
+    stream.write_all(TLS_TEST_EMAIL.as_bytes()).await.unwrap();
+    assert_eq!(send_command(stream, ".").await, 250);
+    assert_eq!(send_command(stream, "QUIT").await, 221);
+}
+
+/// Starts a server on `port` and returns a handle, that resolves to the message ID of the first received email.
+async fn spawn_tls_server(
+    port: u16,
+    tls_config: Arc<ServerConfig>,
+    implicit_tls: bool,
+) -> tokio::task::JoinHandle<Result<(String, ClientIdentity), Error>> {
+    let local_addr = ("localhost", port)
+        .to_socket_addrs()
+        .unwrap()
+        .next()
+        .unwrap();
+    let server = SmtpServer::with_implicit_tls(
+        &local_addr,
+        Some(tls_config),
+        implicit_tls,
+        SocketOptions::default(),
+    )
+    .await
+    .expect("Could not start SMTP server.");
+    tokio::spawn(async move {
+        let (stream, addr) = server.accept_conn(0).await?;
+        let mut buf = vec![];
+        let email = server.recv_mail(stream, addr, &mut buf).await?;
+        Ok((email.content.message_id, email.client))
+    })
+}
+
+#[tokio::test]
+async fn test_starttls() {
+    let (cert, key) = generate_cert("mail.example.com");
+    let server = spawn_tls_server(
+        STARTTLS_TEST_PORT,
+        server_tls_config(vec![("mail.example.com", key)]),
+        false,
+    )
+    .await;
+
+    let mut stream = BufStream::new(
+        TcpStream::connect(("localhost", STARTTLS_TEST_PORT))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(read_response(&mut stream).await, 220);
+    assert_eq!(send_command(&mut stream, "EHLO client.example").await, 250);
+    assert_eq!(send_command(&mut stream, "STARTTLS").await, 220);
+    let mut tls_stream = BufStream::new(
+        client_connector(&cert)
+            .connect(
+                ServerName::try_from("mail.example.com").unwrap(),
+                stream.into_inner(),
+            )
+            .await
+            .expect("TLS handshake after STARTTLS failed."),
+    );
+    send_test_mail(&mut tls_stream).await;
+
+    let (message_id, client) = server
+        .await
+        .unwrap()
+        .expect("Server could not receive email.");
+    assert_eq!(message_id, "tls-test@example.com");
+    assert_eq!(client.helo.as_deref(), Some("client.example"));
+    let tls = client.tls.expect("TLS parameters were not recorded.");
+    assert_eq!(tls.server_name.as_deref(), Some("mail.example.com"));
+}
+
+#[tokio::test]
+async fn test_implicit_tls() {
+    let (cert, key) = generate_cert("mail.example.com");
+    let server = spawn_tls_server(
+        IMPLICIT_TLS_TEST_PORT,
+        server_tls_config(vec![("mail.example.com", key)]),
+        true,
+    )
+    .await;
+
+    let tcp_stream = TcpStream::connect(("localhost", IMPLICIT_TLS_TEST_PORT))
+        .await
+        .unwrap();
+    let mut tls_stream = BufStream::new(
+        client_connector(&cert)
+            .connect(
+                ServerName::try_from("mail.example.com").unwrap(),
+                tcp_stream,
+            )
+            .await
+            .expect("Implicit TLS handshake failed."),
+    );
+    assert_eq!(read_response(&mut tls_stream).await, 220);
+    send_test_mail(&mut tls_stream).await;
+
+    let (message_id, client) = server
+        .await
+        .unwrap()
+        .expect("Server could not receive email.");
+    assert_eq!(message_id, "tls-test@example.com");
+    assert_eq!(client.helo.as_deref(), Some("client.example"));
+    let tls = client.tls.expect("TLS parameters were not recorded.");
+    assert_eq!(tls.server_name.as_deref(), Some("mail.example.com"));
+}
+
+#[tokio::test]
+async fn test_sni_cert_selection() {
+    let (cert_a, key_a) = generate_cert("a.example.com");
+    let (cert_b, key_b) = generate_cert("b.example.com");
+    let tls_config = server_tls_config(vec![("a.example.com", key_a), ("b.example.com", key_b)]);
+
+    // The certificate for the requested name has to be selected:
+    let server = spawn_tls_server(SNI_TEST_PORT, tls_config.clone(), true).await;
+    let tcp_stream = TcpStream::connect(("localhost", SNI_TEST_PORT))
+        .await
+        .unwrap();
+    let mut tls_stream = BufStream::new(
+        client_connector(&cert_b)
+            .connect(ServerName::try_from("b.example.com").unwrap(), tcp_stream)
+            .await
+            .expect("Server did not present the certificate for the requested name."),
+    );
+    assert_eq!(read_response(&mut tls_stream).await, 220);
+    send_test_mail(&mut tls_stream).await;
+    server.await.unwrap().unwrap();
+
+    // A client, that only trusts the certificate of another domain, has to reject the presented one:
+    let server = spawn_tls_server(SNI_TEST_PORT, tls_config.clone(), true).await;
+    let tcp_stream = TcpStream::connect(("localhost", SNI_TEST_PORT))
+        .await
+        .unwrap();
+    assert!(client_connector(&cert_a)
+        .connect(ServerName::try_from("b.example.com").unwrap(), tcp_stream)
+        .await
+        .is_err());
+    assert!(server.await.unwrap().is_err());
+
+    // Unknown names do not resolve to any certificate:
+    let server = spawn_tls_server(SNI_TEST_PORT, tls_config, true).await;
+    let tcp_stream = TcpStream::connect(("localhost", SNI_TEST_PORT))
+        .await
+        .unwrap();
+    assert!(client_connector(&cert_a)
+        .connect(ServerName::try_from("c.example.com").unwrap(), tcp_stream)
+        .await
+        .is_err());
+    assert!(server.await.unwrap().is_err());
+}
+
+const REJECT_TEST_PORT: u16 = 4029;
+
+/// Rejects every email with the error returned by the given function.
+struct RejectingAcceptor(fn() -> Error);
+
+#[async_trait]
+impl MailAcceptor for RejectingAcceptor {
+    async fn accept(&self, _email: &SmtpEmail<'_>) -> Result<(), Error> {
+        Err((self.0)())
+    }
+}
+
+#[test]
+fn test_reply_for() {
+    assert_eq!(reply_for(&Error::MailParsing("")).code, 554);
+    assert_eq!(reply_for(&Error::Policy(String::new())).code, 550);
+    assert_eq!(
+        reply_for(&Error::smtp(SmtpErrorCode::Reply(552), "")).code,
+        552
+    );
+    assert_eq!(
+        reply_for(&Error::smtp(SmtpErrorCode::Reply(451), "")).code,
+        451
+    );
+    assert_eq!(
+        reply_for(&Error::smtp(SmtpErrorCode::Unreachable, "")).code,
+        450
+    );
+    assert_eq!(reply_for(&Error::smtp(SmtpErrorCode::NullMx, "")).code, 554);
+}
+
+#[tokio::test]
+async fn test_rejected_at_data_end() {
+    let local_addr = ("localhost", REJECT_TEST_PORT)
+        .to_socket_addrs()
+        .unwrap()
+        .next()
+        .unwrap();
+    let mut server = SmtpServer::new(&local_addr, None, SocketOptions::default())
+        .await
+        .expect("Could not start SMTP server.");
+    server.set_acceptor(Arc::new(RejectingAcceptor(|| {
+        Error::smtp(SmtpErrorCode::Unreachable, "Destination is down.")
+    })));
+    let server = tokio::spawn(async move {
+        let (stream, addr) = server.accept_conn(0).await?;
+        let mut buf = vec![];
+        server.recv_mail(stream, addr, &mut buf).await.map(|_| ())
+    });
+
+    let mut stream = BufStream::new(
+        TcpStream::connect(("localhost", REJECT_TEST_PORT))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(read_response(&mut stream).await, 220);
+    assert_eq!(send_command(&mut stream, "EHLO client.example").await, 250);
+    assert_eq!(
+        send_command(&mut stream, "MAIL FROM:<sender@example.com>").await,
+        250
+    );
+    assert_eq!(
+        send_command(&mut stream, "RCPT TO:<receiver@example.org>").await,
+        250
+    );
+    assert_eq!(send_command(&mut stream, "DATA").await, 354);
+    stream.write_all(TLS_TEST_EMAIL.as_bytes()).await.unwrap();
+    assert_eq!(send_command(&mut stream, ".").await, 450);
+    assert_eq!(send_command(&mut stream, "QUIT").await, 221);
+
+    assert!(matches!(
+        server.await.unwrap(),
+        Err(Error::Smtp(SmtpError {
+            code: SmtpErrorCode::Unreachable,
+            peer: Some(_),
+            ..
+        }))
+    ));
+}
+
+const DRAIN_TEST_PORT: u16 = 4031;
+
+#[tokio::test]
+async fn test_drain() {
+    let local_addr = ("localhost", DRAIN_TEST_PORT)
+        .to_socket_addrs()
+        .unwrap()
+        .next()
+        .unwrap();
+    let mut server = SmtpServer::new(&local_addr, None, SocketOptions::default())
+        .await
+        .expect("Could not start SMTP server.");
+    server.set_hostname("mx.example.com");
+    server.drain();
+    let server = Arc::new(server);
+    let server_ref = server.clone();
+    let session = tokio::spawn(async move {
+        let (stream, addr) = server_ref.accept_conn(0).await?;
+        let mut buf = vec![];
+        server_ref
+            .recv_mail(stream, addr, &mut buf)
+            .await
+            .map(|_| ())
+    });
+
+    let mut stream = BufStream::new(
+        TcpStream::connect(("localhost", DRAIN_TEST_PORT))
+            .await
+            .unwrap(),
+    );
+    let mut line = String::new();
+    stream.read_line(&mut line).await.unwrap();
+    assert_eq!(
+        line,
+        "421 mx.example.com Service not available, closing transmission channel\r\n"
+    );
+    assert!(matches!(
+        session.await.unwrap(),
+        Err(Error::Smtp(SmtpError {
+            code: SmtpErrorCode::Refused,
+            ..
+        }))
+    ));
+
+    // Closing wakes up everybody waiting for it:
+    let server_ref = server.clone();
+    let waiting = tokio::spawn(async move { server_ref.closed().await });
+    tokio::task::yield_now().await;
+    server.close();
+    tokio::time::timeout(Duration::from_secs(1), waiting)
+        .await
+        .expect("closed() did not return after close().")
+        .unwrap();
+    server.closed().await;
+}
+
+const BIND_RETRY_TEST_PORT: u16 = 4032;
+
+#[tokio::test]
+async fn test_bind_retry() {
+    let local_addr = ("localhost", BIND_RETRY_TEST_PORT)
+        .to_socket_addrs()
+        .unwrap()
+        .next()
+        .unwrap();
+    let blocker = TcpListener::bind(local_addr).await.unwrap();
+
+    // Without a retry period, the error is returned immediately:
+    assert!(
+        bind_with_retry(&local_addr, None, SocketOptions::default(), Duration::ZERO)
+            .await
+            .is_err()
+    );
+
+    // The address becomes available while we retry:
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        drop(blocker);
+    });
+    bind_with_retry(
+        &local_addr,
+        None,
+        SocketOptions::default(),
+        Duration::from_secs(5),
+    )
+    .await
+    .expect("Binding was not retried.");
+}
+
+const METRICS_TEST_PORT: u16 = 4033;
+
+#[tokio::test]
+async fn test_command_metrics() {
+    let local_addr = ("localhost", METRICS_TEST_PORT)
+        .to_socket_addrs()
+        .unwrap()
+        .next()
+        .unwrap();
+    let mut server = SmtpServer::new(&local_addr, None, SocketOptions::default())
+        .await
+        .expect("Could not start SMTP server.");
+    let metrics = Arc::new(Metrics::new());
+    server.set_metrics(metrics.clone());
+    let server = tokio::spawn(async move {
+        let (stream, addr) = server.accept_conn(0).await?;
+        let mut buf = vec![];
+        server.recv_mail(stream, addr, &mut buf).await.map(|_| ())
+    });
+
+    let mut stream = BufStream::new(
+        TcpStream::connect(("localhost", METRICS_TEST_PORT))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(read_response(&mut stream).await, 220);
+    send_test_mail(&mut stream).await;
+    server.await.unwrap().unwrap();
+
+    let rendered = metrics.render();
+    for command in ["ehlo", "mail", "rcpt", "data", "data_end"] {
+        let count = format!(
+            "kutsche_smtp_command_duration_seconds_count{{command=\"{}\"}} 1",
+            command
+        );
+        assert!(rendered.lines().any(|line| line == count), "{}", rendered);
+    }
+}
+
+const GREETING_TEST_PORT: u16 = 4034;
+
+#[tokio::test]
+async fn test_greeting() {
+    let local_addr = ("localhost", GREETING_TEST_PORT)
+        .to_socket_addrs()
+        .unwrap()
+        .next()
+        .unwrap();
+    let mut server = SmtpServer::new(&local_addr, None, SocketOptions::default())
+        .await
+        .expect("Could not start SMTP server.");
+    server.set_hostname("mx.example.com");
+    server.set_greeting(Greeting {
+        banner: Some("kutsche ready".to_string()),
+        delay: Duration::from_millis(200),
+        esmtp_keywords: Some(vec!["8BITMIME".to_string()]),
+    });
+    let server = Arc::new(server);
+    let server_ref = server.clone();
+    let sessions = tokio::spawn(async move {
+        let mut results = vec![];
+        for _ in 0..2 {
+            let (stream, addr) = server_ref.accept_conn(0).await.unwrap();
+            let mut buf = vec![];
+            results.push(server_ref.recv_mail(stream, addr, &mut buf).await.is_ok());
+        }
+        results
+    });
+
+    // Clients, that don't wait for the greeting, are rejected:
+    let mut stream = BufStream::new(
+        TcpStream::connect(("localhost", GREETING_TEST_PORT))
+            .await
+            .unwrap(),
+    );
+    stream.write_all(b"EHLO client.example\r\n").await.unwrap();
+    stream.flush().await.unwrap();
+    assert_eq!(read_response(&mut stream).await, 554);
+
+    let mut stream = BufStream::new(
+        TcpStream::connect(("localhost", GREETING_TEST_PORT))
+            .await
+            .unwrap(),
+    );
+    let mut line = String::new();
+    stream.read_line(&mut line).await.unwrap();
+    assert_eq!(line, "220 mx.example.com kutsche ready\r\n");
+    stream.write_all(b"EHLO client.example\r\n").await.unwrap();
+    stream.flush().await.unwrap();
+    let mut lines = vec![];
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        let last = line.as_bytes()[3] == b' ';
+        lines.push(line);
+        if last {
+            break;
+        }
+    }
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1], "250 8BITMIME\r\n");
+    assert_eq!(send_command(&mut stream, "QUIT").await, 221);
+    assert_eq!(sessions.await.unwrap(), vec![false, false]);
+}
+
+const SIZE_TEST_PORT: u16 = 4035;
+
+#[tokio::test]
+async fn test_size_param() {
+    let local_addr = ("localhost", SIZE_TEST_PORT)
+        .to_socket_addrs()
+        .unwrap()
+        .next()
+        .unwrap();
+    let mut server = SmtpServer::new(&local_addr, None, SocketOptions::default())
+        .await
+        .expect("Could not start SMTP server.");
+    server.set_max_message_size(1000);
+    let session = tokio::spawn(async move {
+        let (stream, addr) = server.accept_conn(0).await.unwrap();
+        let mut buf = vec![];
+        server
+            .recv_mail(stream, addr, &mut buf)
+            .await
+            .map(|email| email.content.message_id)
+    });
+
+    let mut stream = BufStream::new(
+        TcpStream::connect(("localhost", SIZE_TEST_PORT))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(read_response(&mut stream).await, 220);
+    stream.write_all(b"EHLO client.example\r\n").await.unwrap();
+    stream.flush().await.unwrap();
+    let mut lines = vec![];
+    loop {
+        let mut line = String::new();
+        stream.read_line(&mut line).await.unwrap();
+        let last = line.as_bytes()[3] == b' ';
+        lines.push(line);
+        if last {
+            break;
+        }
+    }
+    assert_eq!(lines.last().unwrap(), "250 SIZE 1000\r\n");
+
+    assert_eq!(
+        send_command(&mut stream, "MAIL FROM:<sender@example.com> SIZE=1001").await,
+        552
+    );
+    assert_eq!(
+        send_command(&mut stream, "MAIL FROM:<sender@example.com> SIZE=many").await,
+        501
+    );
+    assert_eq!(
+        send_command(
+            &mut stream,
+            "MAIL FROM:<sender@example.com> BODY=8BITMIME SIZE=100"
+        )
+        .await,
+        250
+    );
+    assert_eq!(
+        send_command(&mut stream, "RCPT TO:<receiver@example.org>").await,
+        250
+    );
+    assert_eq!(send_command(&mut stream, "DATA").await, 354);
+    stream.write_all(TLS_TEST_EMAIL.as_bytes()).await.unwrap();
+    assert_eq!(send_command(&mut stream, ".").await, 250);
+    assert_eq!(send_command(&mut stream, "QUIT").await, 221);
+    assert!(session.await.unwrap().is_ok());
+}
+
+const ALLOW_FROM_TEST_PORT: u16 = 4036;
+
+#[tokio::test]
+async fn test_allow_from() {
+    use tokio::io::AsyncReadExt;
+
+    let local_addr = SocketAddr::from(([127, 0, 0, 1], ALLOW_FROM_TEST_PORT));
+    let mut server = SmtpServer::new(&local_addr, None, SocketOptions::default())
+        .await
+        .expect("Could not start SMTP server.");
+    server.set_allow_from(vec!["192.0.2.0/24".parse().unwrap()]);
+    assert!(server.is_allowed("192.0.2.7".parse().unwrap()));
+    assert!(server.is_allowed("::ffff:192.0.2.7".parse().unwrap()));
+    assert!(!server.is_allowed("2001:db8::1".parse().unwrap()));
+    let accepted = tokio::spawn(async move {
+        tokio::time::timeout(Duration::from_millis(500), server.accept_conn(0))
+            .await
+            .is_ok()
+    });
+
+    // The connection from localhost is closed without a greeting:
+    let mut stream = TcpStream::connect(local_addr).await.unwrap();
+    let mut buf = vec![];
+    assert_eq!(stream.read_to_end(&mut buf).await.unwrap(), 0);
+    assert!(!accepted.await.unwrap());
+}
+
+const SESSIONS_TEST_PORT: u16 = 4037;
+
+#[tokio::test]
+async fn test_active_sessions() {
+    let local_addr = ("localhost", SESSIONS_TEST_PORT)
+        .to_socket_addrs()
+        .unwrap()
+        .next()
+        .unwrap();
+    let server = Arc::new(
+        SmtpServer::new(&local_addr, None, SocketOptions::default())
+            .await
+            .expect("Could not start SMTP server."),
+    );
+    let session = tokio::spawn({
+        let server = server.clone();
+        async move {
+            let (stream, addr) = server.accept_conn(0).await?;
+            let mut buf = vec![];
+            server.recv_mail(stream, addr, &mut buf).await.map(|_| ())
+        }
+    });
+    assert_eq!(server.active_sessions(), 0);
+
+    let mut stream = BufStream::new(
+        TcpStream::connect(("localhost", SESSIONS_TEST_PORT))
+            .await
+            .unwrap(),
+    );
+    assert_eq!(read_response(&mut stream).await, 220);
+    assert_eq!(server.active_sessions(), 1);
+    send_test_mail(&mut stream).await;
+    session.await.unwrap().unwrap();
+    assert_eq!(server.active_sessions(), 0);
+}
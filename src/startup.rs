@@ -0,0 +1,471 @@
+//! The phases of starting the server: parse config → open privileged resources → bind listeners → drop privileges →
+//! start destinations → accept.
+//!
+//! Everything, that needs the privileges of root (files owned by root, ports below 1024, the control socket), happens
+//! before they are dropped. Every phase reports its errors together with its name and ends the startup with its own
+//! exit code, so a failed start shows at once, how far it got.
+
+use log::info;
+use users::switch::{set_effective_gid, set_effective_uid};
+use users::{get_effective_gid, get_effective_uid};
+
+use std::fmt;
+use std::process::ExitCode;
+use std::sync::Arc;
+
+use crate::audit::AuditLog;
+use crate::bind_check::BindAddressCheck;
+use crate::budget::MemoryBudget;
+use crate::config::Config;
+use crate::control::ControlSocket;
+use crate::dispatch::Dispatcher;
+use crate::feed::MailFeed;
+use crate::ha::Spool;
+use crate::janitor::Janitor;
+use crate::metrics::{Metrics, StatsdExporter};
+use crate::queue::{self, DeliveryQueue};
+use crate::report::{self, report_error};
+use crate::self_test::SelfTest;
+use crate::smtp_server::{self, SmtpServer};
+use crate::stats::StatsFile;
+use crate::watchdog::DiskWatchdog;
+use crate::Error;
+
+/// A phase of the startup in the order they are executed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Phase {
+    ParseConfig,
+    OpenResources,
+    BindListeners,
+    DropPrivileges,
+    StartDestinations,
+    Accept,
+}
+
+impl Phase {
+    const ALL: [Phase; 6] = [
+        Phase::ParseConfig,
+        Phase::OpenResources,
+        Phase::BindListeners,
+        Phase::DropPrivileges,
+        Phase::StartDestinations,
+        Phase::Accept,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Phase::ParseConfig => "parse config",
+            Phase::OpenResources => "open privileged resources",
+            Phase::BindListeners => "bind listeners",
+            Phase::DropPrivileges => "drop privileges",
+            Phase::StartDestinations => "start destinations",
+            Phase::Accept => "accept",
+        }
+    }
+
+    /// Logs, that the phase begins.
+    pub(crate) fn begin(self) {
+        info!("Startup {}...", self);
+    }
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let number = Phase::ALL
+            .iter()
+            .position(|phase| phase == self)
+            .unwrap_or(0)
+            + 1;
+        write!(f, "phase {}/{} ({})", number, Phase::ALL.len(), self.name())
+    }
+}
+
+/// An error, that ended the startup.
+#[derive(Debug)]
+pub(crate) struct StartupError {
+    pub(crate) phase: Phase,
+    /// The exit code of the server, which differs for every kind of failure.
+    pub(crate) exit_code: u8,
+    message: String,
+}
+
+impl StartupError {
+    pub(crate) fn new(phase: Phase, exit_code: u8, message: impl Into<String>) -> Self {
+        StartupError {
+            phase,
+            exit_code,
+            message: message.into(),
+        }
+    }
+
+    /// Reports the error and returns the exit code for it.
+    pub(crate) fn report(&self) -> ExitCode {
+        report_error!("{}", self);
+        ExitCode::from(self.exit_code)
+    }
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Startup failed in {}: {}", self.phase, self.message)
+    }
+}
+
+/// What the server opens before binding its listeners.
+pub(crate) struct Resources {
+    pub(crate) disk_watchdog: Arc<DiskWatchdog>,
+    pub(crate) memory_budget: Option<Arc<MemoryBudget>>,
+    pub(crate) audit_log: Option<Arc<AuditLog>>,
+    pub(crate) metrics: Arc<Metrics>,
+    pub(crate) self_test: Option<SelfTest>,
+    pub(crate) queue: Arc<DeliveryQueue>,
+    pub(crate) feed: Arc<MailFeed>,
+    pub(crate) dispatcher: Arc<Dispatcher>,
+}
+
+/// Opens the audit log, the spool and the other resources, that may need the privileges of root.
+pub(crate) fn open_resources(config: &Arc<Config>) -> Result<Resources, StartupError> {
+    Phase::OpenResources.begin();
+    // Watch the free space of the volumes we write to:
+    let storage_paths = config
+        .state_dir
+        .iter()
+        .chain(
+            config
+                .tenants
+                .iter()
+                .filter_map(|tenant| tenant.state_dir.as_ref()),
+        )
+        .cloned()
+        .chain(
+            config
+                .mappings()
+                .filter_map(|mapping| mapping.destination.storage_path())
+                .map(|path| path.to_path_buf()),
+        )
+        .collect();
+    let disk_watchdog = Arc::new(DiskWatchdog::new(storage_paths, config.min_free_space));
+    disk_watchdog.check();
+    let memory_budget = config
+        .memory_budget
+        .map(|limit| Arc::new(MemoryBudget::new(limit)));
+    // The audit log is opened before dropping privileges, so it may be owned by root:
+    let audit_log = config
+        .open_audit_log()
+        .map_err(|e| {
+            StartupError::new(
+                Phase::OpenResources,
+                7,
+                format!("Could not open audit log: {}", e),
+            )
+        })?
+        .map(Arc::new);
+    let mut metrics = Metrics::new();
+    metrics.set_mappings(config.mappings().cloned().collect());
+    let metrics = Arc::new(metrics);
+    report::set_metrics(metrics.clone());
+    let self_test = match SelfTest::from_config(config) {
+        Ok(Some(mut self_test)) => {
+            self_test.set_metrics(metrics.clone());
+            Some(self_test)
+        }
+        Ok(None) => None,
+        Err(e) => {
+            return Err(StartupError::new(
+                Phase::OpenResources,
+                8,
+                format!("Could not set up self-test: {}", e),
+            ))
+        }
+    };
+    let mut queue = DeliveryQueue::new();
+    if let (Some(node), Some(state_dir)) = (&config.ha_node, &config.state_dir) {
+        let spool = Spool::open(state_dir, node.as_str()).map_err(|e| {
+            StartupError::new(
+                Phase::OpenResources,
+                9,
+                format!("Could not open spool: {}", e),
+            )
+        })?;
+        queue.set_spool(spool);
+    }
+    let queue = Arc::new(queue);
+    let feed = Arc::new(MailFeed::new());
+    let mut dispatcher = Dispatcher::new(config.clone(), queue.clone());
+    if let Some(ref audit_log) = audit_log {
+        dispatcher.set_audit_log(audit_log.clone());
+    }
+    dispatcher.set_feed(feed.clone());
+    Ok(Resources {
+        disk_watchdog,
+        memory_budget,
+        audit_log,
+        metrics,
+        self_test,
+        queue,
+        feed,
+        dispatcher: Arc::new(dispatcher),
+    })
+}
+
+/// Binds the SMTP servers and the control socket. Listeners, that can't be bound, are reported and skipped, unless
+/// none of them could be bound.
+pub(crate) async fn bind_listeners(
+    config: &Arc<Config>,
+    resources: &Resources,
+) -> Result<(Vec<Arc<SmtpServer>>, Option<ControlSocket>), StartupError> {
+    Phase::BindListeners.begin();
+    // Addresses, that are not available yet, are retried concurrently, so they don't delay each other:
+    let bind_tasks: Vec<_> = config
+        .local_addrs
+        .iter()
+        .map(|addr| {
+            let (addr, tls_config, retry_period) =
+                (*addr, config.tls_config.clone(), config.bind_retry);
+            let socket_options = config
+                .socket_options
+                .iter()
+                .find(|(options_addr, _)| *options_addr == addr)
+                .map(|(_, options)| options.clone())
+                .unwrap_or_default();
+            tokio::spawn(async move {
+                smtp_server::bind_with_retry(&addr, tls_config, socket_options, retry_period).await
+            })
+        })
+        .collect();
+    let mut smtp_servers = Vec::new();
+    for (addr, task) in config.local_addrs.iter().zip(bind_tasks) {
+        let bound = task
+            .await
+            .unwrap_or_else(|_| Err(Error::config("Binding task panicked.")));
+        match bound {
+            Ok(mut server) => {
+                server.set_hostname(config.hostname.as_str());
+                server.set_disk_watchdog(resources.disk_watchdog.clone());
+                match config
+                    .test_listeners
+                    .iter()
+                    .find(|(test_addr, _)| test_addr == addr)
+                    .and_then(|(_, test_mapping)| config.mapping(test_mapping))
+                {
+                    Some(test_mapping) => {
+                        let mut test_dispatcher =
+                            Dispatcher::new(config.clone(), resources.queue.clone());
+                        test_dispatcher.set_test_mapping(test_mapping.clone());
+                        if let Some(ref audit_log) = resources.audit_log {
+                            test_dispatcher.set_audit_log(audit_log.clone());
+                        }
+                        test_dispatcher.set_feed(resources.feed.clone());
+                        server.set_acceptor(Arc::new(test_dispatcher));
+                    }
+                    None => server.set_acceptor(resources.dispatcher.clone()),
+                }
+                server.set_metrics(resources.metrics.clone());
+                server.set_hidden_service(config.hidden_service_addrs.contains(addr));
+                if let Some((_, loop_check)) = config
+                    .loop_checks
+                    .iter()
+                    .find(|(check_addr, _)| check_addr == addr)
+                {
+                    server.set_loop_check(loop_check.clone());
+                }
+                if let Some((_, greeting)) = config
+                    .greetings
+                    .iter()
+                    .find(|(greeting_addr, _)| greeting_addr == addr)
+                {
+                    server.set_greeting(greeting.clone());
+                }
+                if let Some((_, networks)) = config
+                    .allow_from
+                    .iter()
+                    .find(|(allow_addr, _)| allow_addr == addr)
+                {
+                    server.set_allow_from(networks.clone());
+                }
+                if let Some((_, delayed_delivery)) = config
+                    .delayed_deliveries
+                    .iter()
+                    .find(|(delayed_addr, _)| delayed_addr == addr)
+                {
+                    server.set_delayed_delivery(delayed_delivery.clone());
+                }
+                if let Some(ref budget) = resources.memory_budget {
+                    server.set_memory_budget(budget.clone());
+                }
+                if let Some(limit) = config.max_message_size {
+                    server.set_max_message_size(limit);
+                }
+                info!("Startet server bound to {}", addr);
+                smtp_servers.push(Arc::new(server));
+            }
+            Err(e) => {
+                report_error!("Could not start server for local address {}: {}", addr, e);
+            }
+        }
+    }
+    if smtp_servers.is_empty() {
+        return Err(StartupError::new(
+            Phase::BindListeners,
+            3,
+            "Could not start server for any local address.",
+        ));
+    }
+    info!("Started {} SMTP servers.", smtp_servers.len());
+
+    // The control socket is created before dropping privileges, because it usually lives in a directory only root can
+    // write to:
+    let control_socket = match config.control_socket {
+        Some(ref path) => {
+            let mut control_socket =
+                ControlSocket::bind(path, smtp_servers.clone()).map_err(|e| {
+                    StartupError::new(
+                        Phase::BindListeners,
+                        6,
+                        format!("Could not create control socket: {}", e),
+                    )
+                })?;
+            control_socket.set_metrics(resources.metrics.clone());
+            control_socket.set_mappings(config.mappings().cloned().collect());
+            control_socket.set_queue(resources.queue.clone());
+            control_socket.set_tokens(config.api_tokens.clone());
+            control_socket.set_secrets(config.secrets.clone());
+            control_socket.set_feed(resources.feed.clone());
+            info!("Listening for commands on {}", path.display());
+            Some(control_socket)
+        }
+        None => None,
+    };
+    Ok((smtp_servers, control_socket))
+}
+
+/// Changes the effective group and user and checks, that they took effect.
+///
+/// The group is changed first, because changing it requires the privileges, that changing the user gives up.
+pub(crate) fn drop_privileges(config: &Config) -> Result<(), StartupError> {
+    Phase::DropPrivileges.begin();
+    if let Some(group) = &config.effective_group {
+        info!("Changing effective group ID to {}...", group.gid());
+        set_effective_gid(group.gid())
+            .map_err(|e| {
+                StartupError::new(
+                    Phase::DropPrivileges,
+                    5,
+                    format!("Could not change effective group: {}", e),
+                )
+            })
+            .and_then(|()| match get_effective_gid() {
+                gid if gid == group.gid() => Ok(()),
+                gid => Err(StartupError::new(
+                    Phase::DropPrivileges,
+                    5,
+                    format!("The effective group ID is still {}.", gid),
+                )),
+            })?;
+    }
+    if let Some(user) = &config.effective_user {
+        info!("Changing effective user ID to {}...", user.uid());
+        set_effective_uid(user.uid())
+            .map_err(|e| {
+                StartupError::new(
+                    Phase::DropPrivileges,
+                    4,
+                    format!("Could not change effective user: {}", e),
+                )
+            })
+            .and_then(|()| match get_effective_uid() {
+                uid if uid == user.uid() => Ok(()),
+                uid => Err(StartupError::new(
+                    Phase::DropPrivileges,
+                    4,
+                    format!("The effective user ID is still {}.", uid),
+                )),
+            })?;
+    }
+    if config.effective_user.is_some() || config.effective_group.is_some() {
+        info!("Dropped privileges.");
+    }
+    Ok(())
+}
+
+/// Starts the delivery workers and the other background tasks with dropped privileges. Returns the stats file, that
+/// must be saved at shutdown.
+pub(crate) fn start_destinations(
+    config: &Config,
+    resources: &mut Resources,
+    control_socket: Option<ControlSocket>,
+) -> Option<Arc<StatsFile>> {
+    Phase::StartDestinations.begin();
+    if let Some(control_socket) = control_socket {
+        tokio::spawn(control_socket.run());
+    }
+    if !config.bind_hosts.is_empty() {
+        let check = BindAddressCheck::new(config.bind_hosts.clone(), config.bind_recheck);
+        tokio::spawn(check.run());
+    }
+    let watchdog_ref = resources.disk_watchdog.clone();
+    tokio::spawn(async move { watchdog_ref.run().await });
+    let stats_file = match config.state_dir {
+        Some(ref state_dir) => {
+            match StatsFile::load(state_dir.join("stats.json"), config.mappings()) {
+                Ok(stats_file) => Some(Arc::new(stats_file)),
+                Err(e) => {
+                    report_error!("Could not load delivery stats: {}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    if let Some(ref stats_file) = stats_file {
+        let stats_file = stats_file.clone();
+        tokio::spawn(async move { stats_file.run().await });
+    }
+    if let (Some(audit_log), Some(interval)) =
+        (resources.audit_log.clone(), config.audit_log_sign_interval)
+    {
+        tokio::spawn(audit_log.run_signer(interval));
+    }
+    let janitor = Janitor::new(config.mappings());
+    if janitor.is_needed() {
+        tokio::spawn(async move { janitor.run().await });
+    }
+
+    // Start delivering received emails:
+    for _ in 0..config.delivery_workers {
+        tokio::spawn(queue::run_worker(
+            resources.queue.clone(),
+            resources.audit_log.clone(),
+            resources.feed.clone(),
+        ));
+    }
+    if let Some(self_test) = resources.self_test.take() {
+        tokio::spawn(async move { self_test.run().await });
+    }
+    if let Some(ref statsd) = config.statsd {
+        tokio::spawn(StatsdExporter::new(statsd.clone(), resources.metrics.clone()).run());
+    }
+    stats_file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phases() {
+        assert_eq!(
+            Phase::OpenResources.to_string(),
+            "phase 2/6 (open privileged resources)"
+        );
+        let e = StartupError::new(
+            Phase::BindListeners,
+            3,
+            "Could not start server for any local address.",
+        );
+        assert_eq!(
+            e.to_string(),
+            "Startup failed in phase 3/6 (bind listeners): Could not start server for any local address."
+        );
+    }
+}
@@ -0,0 +1,17 @@
+//! A lock, that keeps the files of the state directories unchanged while a backup copies them.
+
+use std::sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+static STATE_LOCK: RwLock<()> = RwLock::new(());
+
+/// Waits until no backup runs and keeps new backups waiting, until the returned guard is dropped.
+///
+/// Every write to a state file should happen while holding this guard, so a backup never sees half of a change.
+pub(crate) fn writing() -> RwLockReadGuard<'static, ()> {
+    STATE_LOCK.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Waits until all running writes are finished and blocks new ones, until the returned guard is dropped.
+pub(crate) fn freeze() -> RwLockWriteGuard<'static, ()> {
+    STATE_LOCK.write().unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,200 @@
+use log::{debug, error};
+use serde_json::{json, Map, Value};
+use tokio::time::interval;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::email::rfc5322_date;
+use crate::mapping::Mapping;
+use crate::state_lock;
+use crate::Error;
+
+/// The time between two writes of the stats file.
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The counters of the deliveries of a mapping.
+#[derive(Default)]
+pub(crate) struct DeliveryStats {
+    delivered: AtomicU64,
+    /// The sum of the sizes of the delivered emails.
+    bytes: AtomicU64,
+    failures: AtomicU64,
+    /// The time of the last successful delivery in seconds since the unix epoch or 0.
+    last_delivery: AtomicU64,
+    changed: AtomicBool,
+}
+
+impl DeliveryStats {
+    pub(crate) fn record_delivery(&self, bytes: usize) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        self.delivered.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_delivery.store(now, Ordering::Relaxed);
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+        self.changed.store(true, Ordering::Relaxed);
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "delivered": self.delivered.load(Ordering::Relaxed),
+            "bytes": self.bytes.load(Ordering::Relaxed),
+            "failures": self.failures.load(Ordering::Relaxed),
+            "last_delivery": self.last_delivery.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Adds the counters saved by a previous run.
+    fn restore(&self, saved: &Value) {
+        let get = |field: &str| saved[field].as_u64().unwrap_or(0);
+        self.delivered
+            .fetch_add(get("delivered"), Ordering::Relaxed);
+        self.bytes.fetch_add(get("bytes"), Ordering::Relaxed);
+        self.failures.fetch_add(get("failures"), Ordering::Relaxed);
+        self.last_delivery
+            .fetch_max(get("last_delivery"), Ordering::Relaxed);
+    }
+
+    /// Returns a line of text with all counters.
+    pub(crate) fn describe(&self) -> String {
+        let last_delivery = match self.last_delivery.load(Ordering::Relaxed) {
+            0 => "never".to_string(),
+            secs => rfc5322_date(secs),
+        };
+        format!(
+            "delivered={} bytes={} failures={} last_delivery={}",
+            self.delivered.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed),
+            self.failures.load(Ordering::Relaxed),
+            last_delivery
+        )
+    }
+}
+
+/// The file in the state directory, that keeps the delivery stats of all mappings across restarts.
+///
+/// It contains a JSON object with the stats of every mapping by its name. Stats of mappings, that were removed from the
+/// config, are kept, so they are not lost by a temporary mistake in the config.
+pub(crate) struct StatsFile {
+    path: PathBuf,
+    mappings: BTreeMap<String, Arc<Mapping>>,
+    /// The saved stats of mappings, that are not configured anymore.
+    orphaned: Map<String, Value>,
+}
+
+impl StatsFile {
+    /// Reads the stats saved at `path`, if it exists, and adds them to the given mappings.
+    pub(crate) fn load<'a>(
+        path: impl Into<PathBuf>,
+        mappings: impl Iterator<Item = &'a Arc<Mapping>>,
+    ) -> Result<Self, Error> {
+        let path = path.into();
+        let mappings: BTreeMap<String, Arc<Mapping>> = mappings
+            .map(|mapping| (mapping.name.clone(), mapping.clone()))
+            .collect();
+        let mut orphaned = Map::new();
+        match fs::read(&path) {
+            Ok(content) => {
+                let saved: Map<String, Value> = serde_json::from_slice(&content).map_err(|e| {
+                    Error::config(format!("Invalid stats file {}: {}", path.display(), e))
+                })?;
+                for (name, stats) in saved {
+                    match mappings.get(&name) {
+                        Some(mapping) => mapping.stats.restore(&stats),
+                        None => {
+                            orphaned.insert(name, stats);
+                        }
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(StatsFile {
+            path,
+            mappings,
+            orphaned,
+        })
+    }
+
+    /// Writes the stats of all mappings, if they changed since the last write.
+    pub(crate) fn save(&self) -> Result<(), Error> {
+        let mut changed = false;
+        for mapping in self.mappings.values() {
+            changed |= mapping.stats.changed.swap(false, Ordering::Relaxed);
+        }
+        if !changed {
+            return Ok(());
+        }
+        let mut all = self.orphaned.clone();
+        for (name, mapping) in self.mappings.iter() {
+            all.insert(name.clone(), mapping.stats.to_json());
+        }
+        // Replace the file atomically, so a crash while writing does not lose the previous stats:
+        let tmp_path = self.path.with_extension("tmp");
+        let _writing = state_lock::writing();
+        fs::write(&tmp_path, Value::Object(all).to_string())?;
+        fs::rename(&tmp_path, &self.path)?;
+        debug!("Saved delivery stats to {}.", self.path.display());
+        Ok(())
+    }
+
+    /// Saves the stats periodically forever.
+    pub(crate) async fn run(&self) {
+        let mut ticker = interval(SAVE_INTERVAL);
+        loop {
+            ticker.tick().await;
+            if let Err(e) = self.save() {
+                error!("Could not save delivery stats: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::maildest::FileDestination;
+
+    #[test]
+    fn test_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("stats.json");
+        let new_mapping = || {
+            Arc::new(Mapping::new(
+                "files",
+                Box::new(FileDestination::new(dir.path()).unwrap()),
+            ))
+        };
+        fs::write(&path, r#"{"removed":{"delivered":7}}"#).unwrap();
+
+        let mapping = new_mapping();
+        let stats_file = StatsFile::load(&path, [mapping.clone()].iter()).unwrap();
+        mapping.stats.record_delivery(100);
+        mapping.stats.record_delivery(20);
+        mapping.stats.record_failure();
+        stats_file.save().unwrap();
+
+        let mapping = new_mapping();
+        let _stats_file = StatsFile::load(&path, [mapping.clone()].iter()).unwrap();
+        let description = mapping.stats.describe();
+        assert!(description.starts_with("delivered=2 bytes=120 failures=1 last_delivery="));
+        assert!(!description.ends_with("never"));
+        let saved: Value = serde_json::from_slice(&fs::read(&path).unwrap()).unwrap();
+        assert_eq!(saved["removed"]["delivered"], 7);
+
+        fs::write(&path, "{").unwrap();
+        assert!(StatsFile::load(&path, [mapping].iter()).is_err());
+    }
+}
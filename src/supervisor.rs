@@ -0,0 +1,70 @@
+use log::{error, info};
+use tokio::task::JoinHandle;
+
+use std::time::{Duration, Instant};
+
+use crate::report::report_error;
+
+/// The longest delay between two restarts of a crashing task.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Runs the task returned by `start` and starts it again whenever it panics, until it finishes normally.
+///
+/// Restarts are delayed by `backoff`, which doubles with every panic up to `MAX_BACKOFF`. If a task ran for longer than
+/// `MAX_BACKOFF` before panicking, the delay starts at `backoff` again.
+pub(crate) async fn supervise<F>(name: &str, mut start: F, backoff: Duration)
+where
+    F: FnMut() -> JoinHandle<()>,
+{
+    let mut delay = backoff;
+    loop {
+        let started = Instant::now();
+        match start().await {
+            Ok(()) => return,
+            Err(e) if e.is_cancelled() => {
+                error!("The task {} was cancelled.", name);
+                return;
+            }
+            Err(_) => {
+                if started.elapsed() > MAX_BACKOFF {
+                    delay = backoff;
+                }
+                report_error!(
+                    "The task {} panicked, restarting it in {} ms.",
+                    name,
+                    delay.as_millis()
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_BACKOFF);
+                info!("Restarting task {}...", name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_restart_after_panic() {
+        let starts = Arc::new(AtomicUsize::new(0));
+        let counter = starts.clone();
+        supervise(
+            "test",
+            move || {
+                let count = counter.fetch_add(1, Ordering::SeqCst);
+                tokio::spawn(async move {
+                    if count < 2 {
+                        panic!("Crash number {}.", count + 1);
+                    }
+                })
+            },
+            Duration::from_millis(1),
+        )
+        .await;
+        assert_eq!(starts.load(Ordering::SeqCst), 3);
+    }
+}
@@ -0,0 +1,181 @@
+//! Tenants: independent users of one server, each owning a set of recipient domains.
+//!
+//! The mappings and destinations of a tenant are loaded from its own config file. Recipients in the domains of a tenant
+//! are only ever routed to the mappings of this tenant, so tenants don't see each other's emails, and the emails
+//! accepted for a tenant can be limited per minute.
+
+use log::warn;
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::error::SmtpErrorCode;
+use crate::shared_state::SharedState;
+use crate::Error;
+
+/// The window, in which the emails of a tenant are counted for its rate limit.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+pub(crate) struct Tenant {
+    pub(crate) name: String,
+    /// The recipient domains of the tenant in lower case.
+    pub(crate) domains: Vec<String>,
+    /// The file with the mappings and destinations of the tenant.
+    pub(crate) config_file: PathBuf,
+    /// The directory, that relative paths in the config file of the tenant are resolved against.
+    pub(crate) state_dir: Option<PathBuf>,
+    /// The base directory of the default file destinations of the tenant.
+    pub(crate) default_path: Option<PathBuf>,
+    /// The maximum number of emails accepted for the tenant per minute.
+    max_emails_per_minute: Option<u32>,
+    /// The start of the current window and the number of emails accepted in it.
+    window: Mutex<(Instant, u32)>,
+    /// The state shared with other servers, that the emails are counted in instead of `window`, if it is available.
+    shared_state: Option<Arc<SharedState>>,
+}
+
+impl Tenant {
+    pub(crate) fn new(
+        name: impl Into<String>,
+        domains: Vec<String>,
+        config_file: impl Into<PathBuf>,
+    ) -> Self {
+        Tenant {
+            name: name.into(),
+            domains: domains.iter().map(|domain| domain.to_lowercase()).collect(),
+            config_file: config_file.into(),
+            state_dir: None,
+            default_path: None,
+            max_emails_per_minute: None,
+            window: Mutex::new((Instant::now(), 0)),
+            shared_state: None,
+        }
+    }
+
+    pub(crate) fn set_state_dir(&mut self, state_dir: impl Into<PathBuf>) {
+        self.state_dir = Some(state_dir.into());
+    }
+
+    pub(crate) fn set_max_emails_per_minute(&mut self, max: u32) {
+        self.max_emails_per_minute = Some(max);
+    }
+
+    pub(crate) fn max_emails_per_minute(&self) -> Option<u32> {
+        self.max_emails_per_minute
+    }
+
+    /// Counts the emails of the tenant in the given shared state, so the rate limit applies to all servers together.
+    pub(crate) fn set_shared_state(&mut self, shared_state: Arc<SharedState>) {
+        self.shared_state = Some(shared_state);
+    }
+
+    /// Returns true, if the domain of `address` (an address or an address pattern) belongs to this tenant.
+    pub(crate) fn owns(&self, address: &str) -> bool {
+        match address.rsplit_once('@') {
+            Some((_, domain)) => self.domains.contains(&domain.to_lowercase()),
+            None => false,
+        }
+    }
+
+    /// Returns `path` or, if it is relative, `path` within the state directory of the tenant.
+    pub(crate) fn resolve_path(&self, path: impl AsRef<Path>) -> PathBuf {
+        match self.state_dir {
+            Some(ref state_dir) => state_dir.join(path),
+            None => path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Counts an accepted email against the rate limit of the tenant.
+    ///
+    /// Returns a temporary error without counting the email, if the limit of the current minute is reached, so the
+    /// sender retries later.
+    ///
+    /// With shared state, the emails accepted by all servers are counted. If the shared state is not available, they
+    /// are counted by this server alone, so a failure of Redis does not stop the delivery.
+    pub(crate) async fn count_email(&self) -> Result<(), Error> {
+        let max = match self.max_emails_per_minute {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        if let Some(ref shared_state) = self.shared_state {
+            match shared_state
+                .count(&format!("rate:{}", self.name), max, RATE_WINDOW)
+                .await
+            {
+                Ok(true) => return Ok(()),
+                Ok(false) => return Err(self.rate_limited(max)),
+                Err(e) => warn!(
+                    "Counting email of tenant '{}' locally, because shared state is not available: {}",
+                    self.name, e
+                ),
+            }
+        }
+        let mut window = self.window.lock().expect("Rate limit window is poisoned.");
+        if window.0.elapsed() >= RATE_WINDOW {
+            *window = (Instant::now(), 0);
+        }
+        if window.1 >= max {
+            return Err(self.rate_limited(max));
+        }
+        window.1 += 1;
+        Ok(())
+    }
+
+    fn rate_limited(&self, max: u32) -> Error {
+        Error::smtp(
+            SmtpErrorCode::RateLimited,
+            format!(
+                "Tenant '{}' exceeded its limit of {} emails per minute.",
+                self.name, max
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::RedisClient;
+
+    #[test]
+    fn test_owns() {
+        let tenant = Tenant::new("acme", vec!["Acme.example".to_string()], "acme.toml");
+        assert!(tenant.owns("alerts@acme.example"));
+        assert!(tenant.owns("*@ACME.example"));
+        assert!(!tenant.owns("alerts@example.com"));
+        assert!(!tenant.owns("*"));
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let mut tenant = Tenant::new("acme", vec![], "acme.toml");
+        assert!(tenant.count_email().await.is_ok());
+        tenant.set_max_emails_per_minute(2);
+        assert!(tenant.count_email().await.is_ok());
+        assert!(tenant.count_email().await.is_ok());
+        let e = tenant.count_email().await.unwrap_err();
+        assert_eq!(e.code(), "smtp.rate_limited");
+        assert!(e.is_temporary());
+
+        // The next window starts with no emails:
+        tenant.window.lock().unwrap().0 -= RATE_WINDOW;
+        assert!(tenant.count_email().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_shared_state() {
+        // Nothing listens on the port of a closed listener:
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let client = RedisClient::new(format!("redis://127.0.0.1:{}", port).parse().unwrap());
+        let mut tenant = Tenant::new("acme", vec![], "acme.toml");
+        tenant.set_max_emails_per_minute(1);
+        tenant.set_shared_state(Arc::new(SharedState::new(client, "kutsche:")));
+        assert!(tenant.count_email().await.is_ok());
+        assert!(tenant.count_email().await.is_err());
+    }
+}
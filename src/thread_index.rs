@@ -0,0 +1,205 @@
+use serde_json::{json, Value};
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::email::Email;
+use crate::state_lock;
+use crate::Error;
+
+/// The conversation, that a delivered email belongs to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ThreadEntry {
+    /// The Message-ID of the first email of the conversation, which groups the emails of a conversation.
+    pub(crate) thread: String,
+    /// What the destination knows the first delivered email of the conversation by, e.g. the ID of its event in a
+    /// Matrix room.
+    pub(crate) reference: String,
+}
+
+/// A persistent index of the conversations of delivered emails, that are correlated by their In-Reply-To and
+/// References headers.
+///
+/// Every delivery is appended to the file as one line of JSON with the fields "message_id", "thread" and "reference",
+/// so the index survives restarts.
+pub(crate) struct ThreadIndex {
+    state: Mutex<(File, HashMap<String, ThreadEntry>)>,
+}
+
+impl ThreadIndex {
+    pub(crate) fn open(path: &Path) -> Result<Self, Error> {
+        let mut entries = HashMap::new();
+        if path.is_file() {
+            for line in BufReader::new(File::open(path)?).lines() {
+                let entry: Value = match serde_json::from_str(&line?) {
+                    Ok(entry) => entry,
+                    // Skip lines, that were cut off, e.g. by a full disk:
+                    Err(_) => continue,
+                };
+                if let (Some(message_id), Some(thread), Some(reference)) = (
+                    entry["message_id"].as_str(),
+                    entry["thread"].as_str(),
+                    entry["reference"].as_str(),
+                ) {
+                    entries.insert(
+                        message_id.to_string(),
+                        ThreadEntry {
+                            thread: thread.to_string(),
+                            reference: reference.to_string(),
+                        },
+                    );
+                }
+            }
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(ThreadIndex {
+            state: Mutex::new((file, entries)),
+        })
+    }
+
+    /// Returns the conversation of the most recent earlier email, that `email` refers to, or None, if none of them was
+    /// delivered before.
+    pub(crate) fn find(&self, email: &Email<'_>) -> Option<ThreadEntry> {
+        let state = self.state.lock().expect("Thread index is poisoned.");
+        email
+            .thread_references()
+            .iter()
+            .find_map(|id| state.1.get(id).cloned())
+    }
+
+    /// Records, that the email with the given Message-ID was delivered as part of the given conversation.
+    pub(crate) fn record(&self, message_id: &str, entry: ThreadEntry) -> Result<(), Error> {
+        let mut line = json!({
+            "message_id": message_id,
+            "thread": entry.thread,
+            "reference": entry.reference,
+        })
+        .to_string();
+        line.push('\n');
+        let _writing = state_lock::writing();
+        let mut state = self.state.lock().expect("Thread index is poisoned.");
+        state.0.write_all(line.as_bytes())?;
+        state.0.flush()?;
+        state.1.insert(message_id.to_string(), entry);
+        Ok(())
+    }
+
+    /// Removes the entries of the emails with the given Message-IDs and of the emails in conversations started by them,
+    /// whose references point to them, and rewrites the file with the remaining entries. Returns the number of removed
+    /// entries.
+    pub(crate) fn forget(&self, message_ids: &[String]) -> Result<usize, Error> {
+        let _writing = state_lock::writing();
+        let mut state = self.state.lock().expect("Thread index is poisoned.");
+        let (ref mut file, ref mut entries) = *state;
+        let before = entries.len();
+        entries.retain(|message_id, entry| {
+            !message_ids.contains(message_id) && !message_ids.contains(&entry.thread)
+        });
+        let removed = before - entries.len();
+        if removed > 0 {
+            let mut content = String::new();
+            for (message_id, entry) in entries.iter() {
+                content.push_str(
+                    &json!({
+                        "message_id": message_id,
+                        "thread": entry.thread,
+                        "reference": entry.reference,
+                    })
+                    .to_string(),
+                );
+                content.push('\n');
+            }
+            // The file is opened for appending, so the content is written to its start after truncating it:
+            file.set_len(0)?;
+            file.write_all(content.as_bytes())?;
+            file.flush()?;
+        }
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REPLY: &[u8] = b"Message-ID: <c@example.com>\r\n\
+In-Reply-To: <b@example.com>\r\n\
+References: <a@example.com> <b@example.com>\r\n\
+\r\n\
+Hi\r\n";
+
+    #[test]
+    fn test_find_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("threads.jsonl");
+        let index = ThreadIndex::open(&path).unwrap();
+        let reply = Email::parse(REPLY).unwrap();
+        assert_eq!(index.find(&reply), None);
+
+        let entry = ThreadEntry {
+            thread: "a@example.com".to_string(),
+            reference: "$root".to_string(),
+        };
+        index.record("a@example.com", entry.clone()).unwrap();
+        assert_eq!(index.find(&reply), Some(entry.clone()));
+        drop(index);
+
+        // The index is restored from the file:
+        let index = ThreadIndex::open(&path).unwrap();
+        assert_eq!(index.find(&reply), Some(entry));
+    }
+
+    #[test]
+    fn test_most_recent_reference_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        let index = ThreadIndex::open(&dir.path().join("threads.jsonl")).unwrap();
+        for (id, thread) in [("a@example.com", "a"), ("b@example.com", "b")] {
+            index
+                .record(
+                    id,
+                    ThreadEntry {
+                        thread: thread.to_string(),
+                        reference: format!("${}", thread),
+                    },
+                )
+                .unwrap();
+        }
+
+        let entry = index.find(&Email::parse(REPLY).unwrap()).unwrap();
+        assert_eq!(entry.thread, "b");
+    }
+
+    #[test]
+    fn test_forget() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("threads.jsonl");
+        let index = ThreadIndex::open(&path).unwrap();
+        for (id, thread) in [
+            ("a@example.com", "a@example.com"),
+            ("b@example.com", "a@example.com"),
+            ("x@example.com", "x@example.com"),
+        ] {
+            index
+                .record(
+                    id,
+                    ThreadEntry {
+                        thread: thread.to_string(),
+                        reference: format!("${}", id),
+                    },
+                )
+                .unwrap();
+        }
+
+        // The reply to the forgotten email is removed with it:
+        assert_eq!(index.forget(&["a@example.com".to_string()]).unwrap(), 2);
+        assert_eq!(index.find(&Email::parse(REPLY).unwrap()), None);
+        assert_eq!(index.forget(&["a@example.com".to_string()]).unwrap(), 0);
+        drop(index);
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 1);
+        assert!(content.contains("x@example.com"));
+    }
+}
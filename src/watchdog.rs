@@ -0,0 +1,89 @@
+use log::{error, info, warn};
+use tokio::time::interval;
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// The time between two checks of the free space.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Monitors the free space of the volumes emails are written to.
+///
+/// While one of the volumes has less free space than the configured minimum, the SMTP servers reject new emails with
+/// 452 (insufficient system storage), so senders retry later instead of us losing emails on a full disk.
+pub(crate) struct DiskWatchdog {
+    paths: Vec<PathBuf>,
+    min_free: u64,
+    low: AtomicBool,
+}
+
+impl DiskWatchdog {
+    /// Creates a watchdog, that checks the volumes of all given paths for at least `min_free` bytes of free space.
+    pub(crate) fn new(paths: Vec<PathBuf>, min_free: u64) -> Self {
+        DiskWatchdog {
+            paths,
+            min_free,
+            low: AtomicBool::new(false),
+        }
+    }
+
+    /// Returns true, if one of the volumes had too little free space at the last check.
+    pub(crate) fn is_low(&self) -> bool {
+        self.low.load(Ordering::Relaxed)
+    }
+
+    /// Checks the free space of all volumes once and updates the state returned by `is_low()`.
+    pub(crate) fn check(&self) {
+        let mut low = false;
+        for path in self.paths.iter() {
+            match fs2::available_space(path) {
+                Ok(free) if free < self.min_free => {
+                    warn!(
+                        "Only {} bytes free on the volume of {} (minimum is {}).",
+                        free,
+                        path.display(),
+                        self.min_free
+                    );
+                    low = true;
+                }
+                Ok(_) => {}
+                Err(e) => error!("Could not get free space of {}: {}", path.display(), e),
+            }
+        }
+
+        let was_low = self.low.swap(low, Ordering::Relaxed);
+        if low && !was_low {
+            error!("Insufficient storage, rejecting new emails until space is freed.");
+        } else if !low && was_low {
+            info!("Enough storage is available again, accepting new emails.");
+        }
+    }
+
+    /// Checks the free space periodically forever.
+    pub(crate) async fn run(&self) {
+        let mut ticker = interval(CHECK_INTERVAL);
+        loop {
+            ticker.tick().await;
+            self.check();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_threshold() {
+        let dir = std::env::temp_dir();
+
+        let watchdog = DiskWatchdog::new(vec![dir.clone()], u64::MAX);
+        watchdog.check();
+        assert!(watchdog.is_low());
+
+        let watchdog = DiskWatchdog::new(vec![dir], 0);
+        watchdog.check();
+        assert!(!watchdog.is_low());
+    }
+}